@@ -0,0 +1,124 @@
+//! Shared against sharded counters under contention.
+//!
+//! Every thread does what a request does to the stats: bump a counter and a
+//! count in a map keyed by path, first on state all threads share, as the
+//! proxy had it, then on the per-worker shards of `proxy_rs::sharded`.
+//! Then what a request does to the circuit breakers, write stalls and usage
+//! meter of the proxy, which should scale as the sharded counters do.
+//!
+//! ```text
+//! cargo run --release --example contention [threads] [ops per thread]
+//! ```
+//!
+//! Sharding only pays off with more than one core; with one, both take
+//! about the same time.
+
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use pingora::protocols::l4::socket::SocketAddr;
+use proxy_rs::billing::{Account, RequestUsage, UsageMeter};
+use proxy_rs::circuit::{CircuitBreakers, CircuitConfig};
+use proxy_rs::sharded::{Counter, Sharded, shards};
+use proxy_rs::stalls::WriteStalls;
+
+const PATHS: [&str; 4] = ["/", "/users/:id", "/images/*", "/api/orders"];
+
+/// Run `op` `ops` times on each of `threads` threads, the time they took.
+fn run(threads: usize, ops: usize, op: impl Fn(usize) + Sync) -> Duration {
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for i in 0..ops {
+                    op(i);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn report(name: &str, threads: usize, ops: usize, took: Duration) {
+    let total = (threads * ops) as f64;
+    println!(
+        "{name:<24} {:>8.1} ns/op {:>8.1} Mops/s",
+        took.as_nanos() as f64 / ops as f64,
+        total / took.as_secs_f64() / 1e6
+    );
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let threads = args
+        .next()
+        .map_or_else(shards, |t| t.parse().expect("threads"));
+    let ops: usize = args.next().map_or(2_000_000, |n| n.parse().expect("ops"));
+    println!("{threads} threads, {ops} ops each, {} shards", shards());
+
+    let counter = AtomicU64::new(0);
+    let took = run(threads, ops, |_| {
+        counter.fetch_add(1, Ordering::Relaxed);
+    });
+    black_box(counter.load(Ordering::Relaxed));
+    report("shared counter", threads, ops, took);
+
+    let counter = Counter::default();
+    let took = run(threads, ops, |_| counter.add(1));
+    black_box(counter.sum());
+    report("sharded counter", threads, ops, took);
+
+    let map: Mutex<HashMap<&str, u64>> = Mutex::default();
+    let took = run(threads, ops, |i| {
+        *map.lock()
+            .unwrap()
+            .entry(PATHS[i % PATHS.len()])
+            .or_default() += 1;
+    });
+    black_box(map.lock().unwrap().len());
+    report("shared map", threads, ops, took);
+
+    let map: Sharded<HashMap<&str, u64>> = Sharded::default();
+    let took = run(threads, ops, |i| {
+        *map.local().entry(PATHS[i % PATHS.len()]).or_default() += 1;
+    });
+    let mut total = 0;
+    map.each(|shard| total += shard.values().sum::<u64>());
+    black_box(total);
+    report("sharded map", threads, ops, took);
+
+    let circuits = CircuitBreakers::new(CircuitConfig::default());
+    let upstreams: Vec<SocketAddr> = (1..=4)
+        .map(|i| SocketAddr::Inet(format!("10.0.0.{i}:80").parse().unwrap()))
+        .collect();
+    let took = run(threads, ops, |i| {
+        let upstream = &upstreams[i % upstreams.len()];
+        if circuits.allows(upstream) {
+            circuits.sending(upstream);
+            circuits.succeeded(upstream);
+        }
+    });
+    report("circuit breakers", threads, ops, took);
+
+    let stalls = WriteStalls::default();
+    let took = run(threads, ops, |i| {
+        let stall = Duration::from_micros(i as u64 % 5000);
+        stalls.observe(PATHS[i % PATHS.len()], Some(stall), Some(stall));
+    });
+    black_box(stalls.to_json());
+    report("write stalls", threads, ops, took);
+
+    let usage = UsageMeter::default();
+    let took = run(threads, ops, |i| {
+        let account = Account {
+            tenant: Some(PATHS[i % PATHS.len()].to_string()),
+            consumer: None,
+        };
+        usage.record(account, RequestUsage::default());
+    });
+    black_box(usage.to_json());
+    report("usage meter", threads, ops, took);
+}
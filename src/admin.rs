@@ -0,0 +1,1044 @@
+//! Admin API, served on its own listener.
+//!
+//! - `GET /admin/upstreams`: the upstreams of the `default` cluster, their
+//!   health and whether they were added at runtime, and those removed
+//! - `POST /admin/upstreams`: add the upstream `{"addr": "ip:port",
+//!   "weight": n}` in the body to the `default` cluster, see
+//!   [`crate::runtime_upstreams`]
+//! - `DELETE /admin/upstreams/{addr}`: remove `addr` from the `default`
+//!   cluster
+//! - `GET /admin/drains`: the draining upstreams
+//! - `POST /admin/upstreams/{addr}/drain`: start draining `addr`
+//! - `DELETE /admin/upstreams/{addr}/drain`: stop draining `addr`
+//! - `GET /admin/ring/{cluster}[?key=]`: the consistent hashing ring of a
+//!   cluster, laid out and hashed as its route has it, and where `key` maps
+//!   to; 409 for clusters hashed by another algorithm than the ring
+//! - `GET /admin/certs`: days to expiry of the watched certificates
+//! - `GET /admin/stalls`: histograms of the time requests waited on slow
+//!   clients and slow upstreams to take their writes, by route
+//! - `GET /admin/paths`: requests by route and normalized path, with their
+//!   status classes, see [`crate::labels`]
+//! - `GET /admin/h2-fallback`: upstreams sent HTTP/1.1 after failing h2, and
+//!   the count of such downgrades
+//! - `GET /admin/in-flight`: requests queued for and in flight on every
+//!   upstream, its latency estimate, how often full ones were passed over,
+//!   and the cap
+//! - `GET /admin/no-upstream`: requests that found no usable upstream, by
+//!   cluster and how they were answered
+//! - `GET /admin/config`: the live routes version, the last known good one
+//!   and the watch of a reload
+//! - `POST /admin/config/rollback`: go back to the last known good routes
+//! - `POST /admin/config/plan`: what reloading the candidate config file in
+//!   the body, as YAML, would change, see [`crate::plan`]
+//! - `POST /admin/explain`: how the request described in the body would be
+//!   routed, filtered and sent upstream, see [`crate::explain`]
+//! - `GET /admin/budgets`: the budgets of the routes that have one, what is
+//!   held on them and the optional filters skipped for going over
+//! - `GET /admin/scans`: the bodies the scanners of routes found clean,
+//!   cleaned and blocked, and could not scan, see [`crate::scan`]
+//! - `GET /admin/content-types`: the responses the content-type guards of
+//!   routes checked and found not of their declared type, see
+//!   [`crate::content_sniff`]
+//! - `GET /admin/openapi`: the requests the OpenAPI specs of routes passed
+//!   and refused, by operation, see [`crate::openapi`]
+//! - `GET /admin/connections[?client=&route=&protocol=&min_age=&limit=]`: the
+//!   open client connections, oldest first; `client` matches part of the
+//!   address, `min_age` is in seconds, at most `limit` (100) are listed
+//! - `DELETE /admin/connections/{id}`: close a client connection
+//! - `GET /admin/debug`: tokio task counts and queue depths of the services,
+//!   the queues of requests and connections, memory and allocator stats,
+//!   open file descriptors, and allocations per request since the previous
+//!   call
+//! - `GET /admin/debug/profile[?seconds=]`: CPU time of the process's threads
+//!   and busy share of the services over `seconds` (5, at most 30), see
+//!   [`crate::diagnostics`]
+//! - `GET /admin/synthetic`: runs, failures and latencies of the synthetic
+//!   checks, see [`crate::synthetic`]
+//! - `GET /admin/cache`: sizes of the cache's segments, and hits, misses and
+//!   admissions by object size class, for the shared store and each
+//!   partition
+//! - `DELETE /admin/cache/partitions/{name}`: drop every object of a cache
+//!   partition, `default` for the shared store
+//! - `GET /admin/anomaly`: requests scored, by score and by signal, see
+//!   [`crate::anomaly`]
+//! - `GET /admin/ready`: `200` once the instance is ready for rotation,
+//!   `503` with what it still waits for until then, see [`crate::readiness`]
+//! - `GET /admin/flags`: the feature flags, and how many requests each was
+//!   on and off for, see [`crate::flags`]
+//! - `GET /admin/usage`: the usage of every account so far in the current
+//!   billing period, see [`crate::billing`]
+//! - `GET /admin/egress`: connections and requests the egress policy allowed
+//!   and denied, and the denials by source and destination, see
+//!   [`crate::egress`]
+//! - `GET /admin/circuits`: the open circuits of upstreams, and whether this
+//!   replica or a peer opened them, see [`crate::circuit`]
+//! - `GET /admin/region`: the region that should get traffic and the health
+//!   of both it was decided on, for DNS automation, see [`crate::region`]
+//! - `GET /admin/connect-race`: the connect races of upstream addresses and
+//!   their success rates, see [`crate::connect_race`]
+//!
+//! Clusters are named after the route that owns them; the upstreams of routes
+//! without their own are the `default` cluster.
+//!
+//! Requests are not authenticated, so the listener should only be reachable
+//! by operators, e.g. by binding it to loopback.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use http::{Method, Response, StatusCode, header};
+use pingora::apps::http_app::ServeHttp;
+use pingora::lb::{LoadBalancer, selection::RoundRobin};
+use pingora::protocols::http::ServerSession;
+use pingora::protocols::l4::socket::SocketAddr as PeerAddr;
+use serde_json::{Value, json};
+
+use crate::anomaly::AnomalyScorer;
+use crate::balancing::{Balancing, Latencies};
+use crate::billing::UsageMeter;
+use crate::cache::MemoryCache;
+use crate::certs::CertMonitor;
+use crate::circuit::CircuitBreakers;
+use crate::config::Config;
+use crate::connect_race::ConnectRace;
+use crate::connections::Connections;
+use crate::consistent_hash::{Bucket, Continuum, HashFunction, Layout};
+use crate::diagnostics::{MAX_PROFILE, Runtimes};
+use crate::drain::{DrainRegistry, DrainSource};
+use crate::egress::EgressPolicy;
+use crate::explain::Explainer;
+use crate::flags::FeatureFlags;
+use crate::h2_fallback::H2Fallback;
+use crate::hash_select::Algorithm;
+use crate::in_flight::InFlight;
+use crate::labels::PathStats;
+use crate::no_upstream::NoUpstreamCounts;
+use crate::plan::Planner;
+use crate::readiness::Readiness;
+use crate::region::RegionFailover;
+use crate::rollback::RouterVersions;
+use crate::route::SharedRouter;
+use crate::runtime_upstreams::RuntimeUpstreams;
+use crate::stalls::WriteStalls;
+use crate::synthetic::SyntheticProber;
+
+/// Largest candidate configuration accepted for a plan.
+const MAX_PLAN_BODY: usize = 1 << 20;
+/// Largest upstream accepted to be added.
+const MAX_UPSTREAM_BODY: usize = 4 << 10;
+/// Largest request description accepted to be explained.
+const MAX_EXPLAIN_BODY: usize = 64 << 10;
+
+pub struct Admin {
+    upstreams: Arc<LoadBalancer<RoundRobin>>,
+    router: Arc<SharedRouter>,
+    drain: Arc<DrainRegistry>,
+    h2_fallback: Arc<H2Fallback>,
+    in_flight: Arc<InFlight>,
+    latencies: Arc<Latencies>,
+    balancing: Balancing,
+    no_upstream: Arc<NoUpstreamCounts>,
+    stalls: Arc<WriteStalls>,
+    paths: Arc<PathStats>,
+    connections: Arc<Connections>,
+    certs: Option<Arc<CertMonitor>>,
+    versions: Option<Arc<RouterVersions>>,
+    planner: Option<Arc<Planner>>,
+    synthetic: Option<Arc<SyntheticProber>>,
+    anomaly: Option<Arc<AnomalyScorer>>,
+    readiness: Option<Arc<Readiness>>,
+    flags: Option<Arc<FeatureFlags>>,
+    usage: Option<Arc<UsageMeter>>,
+    egress: Option<Arc<EgressPolicy>>,
+    region: Option<Arc<RegionFailover>>,
+    circuits: Option<Arc<CircuitBreakers>>,
+    connect_race: Option<Arc<ConnectRace>>,
+    runtime_upstreams: Option<Arc<RuntimeUpstreams>>,
+    cache: Option<Arc<MemoryCache>>,
+    runtimes: Arc<Runtimes>,
+}
+
+impl Admin {
+    pub fn new(upstreams: Arc<LoadBalancer<RoundRobin>>) -> Self {
+        Admin {
+            upstreams,
+            router: Arc::default(),
+            drain: Arc::default(),
+            h2_fallback: Arc::default(),
+            in_flight: Arc::default(),
+            latencies: Arc::default(),
+            balancing: Balancing::default(),
+            no_upstream: Arc::default(),
+            stalls: Arc::default(),
+            paths: Arc::default(),
+            connections: Arc::default(),
+            certs: None,
+            versions: None,
+            planner: None,
+            synthetic: None,
+            anomaly: None,
+            readiness: None,
+            flags: None,
+            usage: None,
+            egress: None,
+            region: None,
+            circuits: None,
+            connect_race: None,
+            runtime_upstreams: None,
+            cache: None,
+            runtimes: Arc::default(),
+        }
+    }
+
+    pub fn with_router(mut self, router: Arc<SharedRouter>) -> Self {
+        self.router = router;
+        self
+    }
+
+    pub fn with_drain(mut self, drain: Arc<DrainRegistry>) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    pub fn with_h2_fallback(mut self, fallback: Arc<H2Fallback>) -> Self {
+        self.h2_fallback = fallback;
+        self
+    }
+
+    pub fn with_in_flight(mut self, in_flight: Arc<InFlight>) -> Self {
+        self.in_flight = in_flight;
+        self
+    }
+
+    pub fn with_latencies(mut self, latencies: Arc<Latencies>) -> Self {
+        self.latencies = latencies;
+        self
+    }
+
+    /// How the proxy picks upstreams of routes without a balancing of their
+    /// own, for explaining requests.
+    pub fn with_balancing(mut self, balancing: Balancing) -> Self {
+        self.balancing = balancing;
+        self
+    }
+
+    pub fn with_no_upstream_counts(mut self, counts: Arc<NoUpstreamCounts>) -> Self {
+        self.no_upstream = counts;
+        self
+    }
+
+    pub fn with_write_stalls(mut self, stalls: Arc<WriteStalls>) -> Self {
+        self.stalls = stalls;
+        self
+    }
+
+    pub fn with_path_stats(mut self, paths: Arc<PathStats>) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    pub fn with_connections(mut self, connections: Arc<Connections>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    pub fn with_router_versions(mut self, versions: Arc<RouterVersions>) -> Self {
+        self.versions = Some(versions);
+        self
+    }
+
+    /// Plan candidate config files with `planner`, against the router
+    /// versions.
+    pub fn with_planner(mut self, planner: Arc<Planner>) -> Self {
+        self.planner = Some(planner);
+        self
+    }
+
+    pub fn with_certs(mut self, certs: Arc<CertMonitor>) -> Self {
+        self.certs = Some(certs);
+        self
+    }
+
+    /// Report the runtimes in `runtimes`, which the admin service joins.
+    pub fn with_runtimes(mut self, runtimes: Arc<Runtimes>) -> Self {
+        self.runtimes = runtimes;
+        self
+    }
+
+    pub fn with_synthetic(mut self, prober: Arc<SyntheticProber>) -> Self {
+        self.synthetic = Some(prober);
+        self
+    }
+
+    pub fn with_anomaly(mut self, scorer: Arc<AnomalyScorer>) -> Self {
+        self.anomaly = Some(scorer);
+        self
+    }
+
+    pub fn with_readiness(mut self, readiness: Arc<Readiness>) -> Self {
+        self.readiness = Some(readiness);
+        self
+    }
+
+    pub fn with_flags(mut self, flags: Arc<FeatureFlags>) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    pub fn with_usage(mut self, meter: Arc<UsageMeter>) -> Self {
+        self.usage = Some(meter);
+        self
+    }
+
+    pub fn with_egress(mut self, policy: Arc<EgressPolicy>) -> Self {
+        self.egress = Some(policy);
+        self
+    }
+
+    pub fn with_region_failover(mut self, failover: Arc<RegionFailover>) -> Self {
+        self.region = Some(failover);
+        self
+    }
+
+    pub fn with_circuit_breakers(mut self, circuits: Arc<CircuitBreakers>) -> Self {
+        self.circuits = Some(circuits);
+        self
+    }
+
+    pub fn with_connect_race(mut self, race: Arc<ConnectRace>) -> Self {
+        self.connect_race = Some(race);
+        self
+    }
+
+    pub fn with_runtime_upstreams(mut self, upstreams: Arc<RuntimeUpstreams>) -> Self {
+        self.runtime_upstreams = Some(upstreams);
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Arc<MemoryCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    fn certs(&self) -> Response<Vec<u8>> {
+        let Some(certs) = &self.certs else {
+            return error(StatusCode::NOT_FOUND, "certificates are not monitored");
+        };
+        let threshold = certs.threshold_days();
+        let status: Vec<Value> = certs
+            .status()
+            .into_iter()
+            .map(|cert| {
+                let checked = cert.checked.duration_since(UNIX_EPOCH).unwrap_or_default();
+                json!({
+                    "name": cert.name,
+                    "subject": cert.subject,
+                    "days_to_expiry": cert.error.is_none().then_some(cert.days_left),
+                    "expiring": cert.error.is_none() && cert.days_left < threshold,
+                    "checked": checked.as_secs(),
+                    "error": cert.error,
+                })
+            })
+            .collect();
+        reply(
+            StatusCode::OK,
+            json!({ "threshold_days": threshold, "certificates": status }),
+        )
+    }
+
+    fn drains(&self) -> Response<Vec<u8>> {
+        let drains: Vec<Value> = self
+            .drain
+            .list()
+            .into_iter()
+            .map(|(addr, elapsed, source)| {
+                json!({
+                    "addr": addr.to_string(),
+                    "draining_ms": elapsed.as_millis() as u64,
+                    "source": match source {
+                        DrainSource::Admin => "admin",
+                        DrainSource::Discovery => "discovery",
+                    },
+                })
+            })
+            .collect();
+        let grace_ms = self.drain.grace().as_millis() as u64;
+        reply(
+            StatusCode::OK,
+            json!({ "grace_ms": grace_ms, "draining": drains }),
+        )
+    }
+
+    fn h2_fallback(&self) -> Response<Vec<u8>> {
+        let downgraded: Vec<Value> = self
+            .h2_fallback
+            .list()
+            .into_iter()
+            .map(|(addr, left)| {
+                json!({
+                    "addr": addr.to_string(),
+                    "cooldown_left_ms": left.as_millis() as u64,
+                })
+            })
+            .collect();
+        reply(
+            StatusCode::OK,
+            json!({
+                "cooldown_ms": self.h2_fallback.cooldown().as_millis() as u64,
+                "downgrades_total": self.h2_fallback.downgrades(),
+                "downgraded": downgraded,
+            }),
+        )
+    }
+
+    fn budgets(&self) -> Response<Vec<u8>> {
+        let routes: serde_json::Map<String, Value> = self
+            .router
+            .load()
+            .routes()
+            .iter()
+            .filter_map(|route| Some((route.name.clone(), route.budget.as_ref()?.to_json())))
+            .collect();
+        reply(StatusCode::OK, json!({ "routes": routes }))
+    }
+
+    fn scans(&self) -> Response<Vec<u8>> {
+        let routes: serde_json::Map<String, Value> = self
+            .router
+            .load()
+            .routes()
+            .iter()
+            .filter_map(|route| Some((route.name.clone(), route.scan.as_ref()?.to_json())))
+            .collect();
+        reply(StatusCode::OK, json!({ "routes": routes }))
+    }
+
+    fn content_types(&self) -> Response<Vec<u8>> {
+        let routes: serde_json::Map<String, Value> = self
+            .router
+            .load()
+            .routes()
+            .iter()
+            .filter_map(|route| {
+                let guard = route.content_type_guard.as_ref()?;
+                Some((route.name.clone(), guard.to_json()))
+            })
+            .collect();
+        reply(StatusCode::OK, json!({ "routes": routes }))
+    }
+
+    fn openapi(&self) -> Response<Vec<u8>> {
+        let routes: serde_json::Map<String, Value> = self
+            .router
+            .load()
+            .routes()
+            .iter()
+            .filter_map(|route| Some((route.name.clone(), route.openapi.as_ref()?.to_json())))
+            .collect();
+        reply(StatusCode::OK, json!({ "routes": routes }))
+    }
+
+    fn config(&self) -> Response<Vec<u8>> {
+        match &self.versions {
+            Some(versions) => reply(StatusCode::OK, versions.to_json()),
+            None => error(StatusCode::NOT_FOUND, "routes are not versioned"),
+        }
+    }
+
+    fn rollback(&self) -> Response<Vec<u8>> {
+        let Some(versions) = &self.versions else {
+            return error(StatusCode::NOT_FOUND, "routes are not versioned");
+        };
+        match versions.rollback() {
+            Ok(version) => reply(StatusCode::OK, json!({ "version": version })),
+            Err(e) => error(StatusCode::CONFLICT, e),
+        }
+    }
+
+    async fn plan(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let (Some(planner), Some(versions)) = (&self.planner, &self.versions) else {
+            return error(StatusCode::NOT_FOUND, "no config file to plan against");
+        };
+        let body = match read_body(http_session, MAX_PLAN_BODY, "candidate").await {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+        let candidate = match std::str::from_utf8(&body) {
+            Ok(content) => Config::parse(content),
+            Err(_) => Err("not UTF-8".to_string()),
+        };
+        match candidate {
+            Ok(candidate) => {
+                let router = self.router.load();
+                let plan = planner
+                    .plan(&router, versions, &self.upstreams, &candidate)
+                    .await;
+                reply(StatusCode::OK, plan)
+            }
+            Err(e) => error(StatusCode::BAD_REQUEST, &format!("candidate: {e}")),
+        }
+    }
+
+    async fn explain(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let description =
+            match read_json(http_session, MAX_EXPLAIN_BODY, "request description").await {
+                Ok(description) => description,
+                Err(response) => return response,
+            };
+        let router = self.router.load();
+        let explainer = Explainer {
+            router: &router,
+            upstreams: &self.upstreams,
+            balancing: self.balancing,
+            region: self.region.as_deref(),
+            flags: self.flags.as_deref(),
+            egress: self.egress.as_deref(),
+            drain: &self.drain,
+            circuits: self.circuits.as_deref(),
+            in_flight: &self.in_flight,
+        };
+        match explainer.explain(&description) {
+            Ok(explanation) => reply(StatusCode::OK, explanation),
+            Err(e) => error(StatusCode::BAD_REQUEST, &e),
+        }
+    }
+
+    fn list_upstreams(&self) -> Response<Vec<u8>> {
+        let backends = self.upstreams.backends();
+        let runtime = self.runtime_upstreams.as_deref();
+        let upstreams: Vec<Value> = backends
+            .get_backend()
+            .iter()
+            .map(|b| {
+                json!({
+                    "addr": b.addr.to_string(),
+                    "weight": b.weight,
+                    "healthy": backends.ready(b),
+                    "added": runtime.is_some_and(|r| r.is_added(&b.addr)),
+                })
+            })
+            .collect();
+        let removed: Vec<String> = runtime
+            .map(|r| r.removed().iter().map(|a| a.to_string()).collect())
+            .unwrap_or_default();
+        reply(
+            StatusCode::OK,
+            json!({ "upstreams": upstreams, "removed": removed }),
+        )
+    }
+
+    async fn add_upstream(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let Some(runtime) = &self.runtime_upstreams else {
+            return error(
+                StatusCode::NOT_FOUND,
+                "upstreams are not managed at runtime",
+            );
+        };
+        let upstream = match read_json(http_session, MAX_UPSTREAM_BODY, "upstream").await {
+            Ok(upstream) => upstream,
+            Err(response) => return response,
+        };
+        let Some(addr) = upstream["addr"]
+            .as_str()
+            .and_then(|a| a.parse::<SocketAddr>().ok())
+        else {
+            return error(StatusCode::BAD_REQUEST, "invalid upstream address");
+        };
+        let weight = match &upstream["weight"] {
+            Value::Null => 1,
+            weight => match weight.as_u64().filter(|w| *w > 0) {
+                Some(weight) => weight as usize,
+                None => return error(StatusCode::BAD_REQUEST, "weight is not a positive integer"),
+            },
+        };
+        let new = runtime.add(addr, weight);
+        if let Err(e) = self.upstreams.update().await {
+            return error(StatusCode::BAD_GATEWAY, &format!("discovery failed: {e}"));
+        }
+        let status = if new {
+            StatusCode::CREATED
+        } else {
+            StatusCode::OK
+        };
+        reply(
+            status,
+            json!({ "addr": addr.to_string(), "weight": weight }),
+        )
+    }
+
+    async fn remove_upstream(&self, addr: &str) -> Response<Vec<u8>> {
+        let Some(runtime) = &self.runtime_upstreams else {
+            return error(
+                StatusCode::NOT_FOUND,
+                "upstreams are not managed at runtime",
+            );
+        };
+        let Ok(addr) = addr.parse::<SocketAddr>() else {
+            return error(StatusCode::BAD_REQUEST, "invalid upstream address");
+        };
+        let peer = PeerAddr::Inet(addr);
+        let known = self
+            .upstreams
+            .backends()
+            .get_backend()
+            .iter()
+            .any(|b| b.addr == peer);
+        if !known {
+            return error(StatusCode::NOT_FOUND, "no such upstream");
+        }
+        runtime.remove(addr);
+        if let Err(e) = self.upstreams.update().await {
+            return error(StatusCode::BAD_GATEWAY, &format!("discovery failed: {e}"));
+        }
+        reply(
+            StatusCode::OK,
+            json!({ "addr": addr.to_string(), "removed": true }),
+        )
+    }
+
+    fn in_flight(&self) -> Response<Vec<u8>> {
+        let upstreams: Vec<Value> = self
+            .in_flight
+            .list()
+            .into_iter()
+            .map(|load| {
+                json!({
+                    "addr": load.addr.to_string(),
+                    "queued": load.queued,
+                    "in_flight": load.in_flight,
+                    "latency_ms": self.latencies.estimate(&load.addr).as_secs_f64() * 1000.0,
+                    "skipped_full": load.skipped,
+                })
+            })
+            .collect();
+        reply(
+            StatusCode::OK,
+            json!({
+                "cap": self.in_flight.cap(),
+                "saturated_total": self.in_flight.saturated(),
+                "upstreams": upstreams,
+            }),
+        )
+    }
+
+    fn debug(&self) -> Response<Vec<u8>> {
+        let upstreams = self.in_flight.list();
+        let connections = self.connections.list();
+        let mut body = self.runtimes.to_json();
+        body["queues"] = json!({
+            "upstream_queued": upstreams.iter().map(|u| u.queued).sum::<usize>(),
+            "upstream_in_flight": upstreams.iter().map(|u| u.in_flight).sum::<usize>(),
+            "client_connections": connections.len(),
+            "client_requests": connections.iter().map(|c| c.in_flight()).sum::<usize>(),
+        });
+        reply(StatusCode::OK, body)
+    }
+
+    async fn profile(&self, query: &str) -> Response<Vec<u8>> {
+        let seconds = match query_param(query, "seconds").map(|v| v.parse::<f64>()) {
+            None => 5.0,
+            Some(Ok(secs)) if secs > 0.0 && secs <= MAX_PROFILE.as_secs_f64() => secs,
+            Some(_) => {
+                return error(
+                    StatusCode::BAD_REQUEST,
+                    "seconds is not a number of seconds up to 30",
+                );
+            }
+        };
+        match self
+            .runtimes
+            .profile(Duration::from_secs_f64(seconds))
+            .await
+        {
+            Some(profile) => reply(StatusCode::OK, profile),
+            None => error(StatusCode::CONFLICT, "a profile is already being taken"),
+        }
+    }
+
+    fn connections(&self, query: &str) -> Response<Vec<u8>> {
+        let client = query_param(query, "client");
+        let route = query_param(query, "route");
+        let protocol = query_param(query, "protocol");
+        let min_age = match query_param(query, "min_age").map(|v| v.parse::<f64>()) {
+            None => Duration::ZERO,
+            Some(Ok(secs)) if secs >= 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+            Some(_) => {
+                return error(
+                    StatusCode::BAD_REQUEST,
+                    "min_age is not a number of seconds",
+                );
+            }
+        };
+        let limit = match query_param(query, "limit").map(|v| v.parse::<usize>()) {
+            None => 100,
+            Some(Ok(limit)) => limit,
+            Some(Err(_)) => return error(StatusCode::BAD_REQUEST, "limit is not a number"),
+        };
+        let open = self.connections.list();
+        let matching: Vec<Value> = open
+            .iter()
+            .filter(|c| {
+                client
+                    .as_deref()
+                    .is_none_or(|client| c.client.to_string().contains(client))
+                    && protocol.as_deref().is_none_or(|p| c.protocol == p)
+                    && (route.is_none() || c.route() == route)
+                    && c.age() >= min_age
+            })
+            .map(|c| {
+                json!({
+                    "id": c.id,
+                    "client": c.client.to_string(),
+                    "protocol": c.protocol,
+                    "tls": c.tls,
+                    "age_ms": c.age().as_millis() as u64,
+                    "requests": c.requests(),
+                    "in_flight": c.in_flight(),
+                    "route": c.route(),
+                })
+            })
+            .collect();
+        let matched = matching.len();
+        let listed: Vec<Value> = matching.into_iter().take(limit).collect();
+        reply(
+            StatusCode::OK,
+            json!({ "open": open.len(), "matched": matched, "connections": listed }),
+        )
+    }
+
+    fn ring(&self, cluster: &str, key: Option<&str>) -> Response<Vec<u8>> {
+        let (upstreams, layout, hasher) = if cluster == "default" {
+            (
+                self.upstreams.clone(),
+                Layout::default(),
+                HashFunction::default(),
+            )
+        } else {
+            let route = self.router.load().route(cluster);
+            let hashing = route.as_ref().and_then(|r| r.hash_selection.as_ref());
+            if let Some(hashing) = hashing.filter(|h| h.algorithm() != Algorithm::Ring) {
+                let algorithm = hashing.algorithm().as_str();
+                return error(
+                    StatusCode::CONFLICT,
+                    &format!("cluster is hashed by {algorithm}, not on a ring"),
+                );
+            }
+            let layout = hashing.map(|h| h.layout()).unwrap_or_default();
+            let hasher = hashing.map(|h| h.hasher()).unwrap_or_default();
+            match route.and_then(|r| r.upstreams.clone()) {
+                Some(upstreams) => (upstreams, layout, hasher),
+                None => return error(StatusCode::NOT_FOUND, "no such cluster"),
+            }
+        };
+        let buckets: Vec<Bucket> = upstreams
+            .backends()
+            .get_backend()
+            .iter()
+            .filter_map(Bucket::from_backend)
+            .collect();
+        let continuum = Continuum::with_layout(&buckets, layout, hasher);
+        let ranges = continuum.ranges();
+
+        let mut owned: HashMap<SocketAddr, u64> = HashMap::new();
+        for (from, to, node) in &ranges {
+            *owned.entry(*node).or_default() += u64::from(to.wrapping_sub(*from)) + 1;
+        }
+        let nodes: Vec<Value> = buckets
+            .iter()
+            .map(|b| {
+                let owned = owned.get(&b.node).copied().unwrap_or_default();
+                json!({
+                    "addr": b.node.to_string(),
+                    "weight": b.weight,
+                    "share_percent": owned as f64 * 100.0 / (1u64 << 32) as f64,
+                })
+            })
+            .collect();
+        let ranges: Vec<Value> = ranges
+            .iter()
+            .map(|(from, to, node)| json!({ "from": from, "to": to, "node": node.to_string() }))
+            .collect();
+
+        let mut body = json!({
+            "cluster": cluster,
+            "layout": layout.as_str(),
+            "hash_function": hasher.as_str(),
+            "nodes": nodes,
+            "ranges": ranges,
+        });
+        if let Some(key) = key {
+            // the node first, then the ones tried after it
+            let chain: Vec<String> = continuum
+                .nodes(key.as_bytes(), buckets.len())
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            body["key"] = json!({
+                "key": key,
+                "hash": continuum.hash(key.as_bytes()),
+                "node": chain.first(),
+                "fallbacks": chain.get(1..).unwrap_or_default(),
+            });
+        }
+        reply(StatusCode::OK, body)
+    }
+
+    fn upstream_drain(&self, method: &Method, addr: &str) -> Response<Vec<u8>> {
+        let Ok(addr) = addr.parse::<std::net::SocketAddr>() else {
+            return error(StatusCode::BAD_REQUEST, "invalid upstream address");
+        };
+        let addr = PeerAddr::Inet(addr);
+        match *method {
+            Method::POST => {
+                self.drain.drain(addr.clone(), DrainSource::Admin);
+                reply(
+                    StatusCode::OK,
+                    json!({ "addr": addr.to_string(), "draining": true }),
+                )
+            }
+            Method::DELETE if self.drain.undrain(&addr) => reply(
+                StatusCode::OK,
+                json!({ "addr": addr.to_string(), "draining": false }),
+            ),
+            Method::DELETE => error(StatusCode::NOT_FOUND, "upstream is not draining"),
+            _ => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        }
+    }
+}
+
+#[async_trait]
+impl ServeHttp for Admin {
+    async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        self.runtimes.observe("admin");
+        let req = http_session.req_header();
+        let method = req.method.clone();
+        let path = req.uri.path().to_string();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["admin", "certs"] if method == Method::GET => self.certs(),
+            ["admin", "certs"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "drains"] if method == Method::GET => self.drains(),
+            ["admin", "drains"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "stalls"] if method == Method::GET => {
+                reply(StatusCode::OK, self.stalls.to_json())
+            }
+            ["admin", "stalls"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "paths"] if method == Method::GET => {
+                reply(StatusCode::OK, self.paths.to_json())
+            }
+            ["admin", "paths"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "h2-fallback"] if method == Method::GET => self.h2_fallback(),
+            ["admin", "h2-fallback"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "in-flight"] if method == Method::GET => self.in_flight(),
+            ["admin", "in-flight"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "no-upstream"] if method == Method::GET => {
+                reply(StatusCode::OK, self.no_upstream.to_json())
+            }
+            ["admin", "no-upstream"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "config"] if method == Method::GET => self.config(),
+            ["admin", "config"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "config", "plan"] if method == Method::POST => self.plan(http_session).await,
+            ["admin", "config", "plan"] => {
+                error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            ["admin", "config", "rollback"] if method == Method::POST => self.rollback(),
+            ["admin", "config", "rollback"] => {
+                error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            ["admin", "explain"] if method == Method::POST => self.explain(http_session).await,
+            ["admin", "explain"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "budgets"] if method == Method::GET => self.budgets(),
+            ["admin", "budgets"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "scans"] if method == Method::GET => self.scans(),
+            ["admin", "scans"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "content-types"] if method == Method::GET => self.content_types(),
+            ["admin", "content-types"] => {
+                error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            ["admin", "openapi"] if method == Method::GET => self.openapi(),
+            ["admin", "openapi"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "connections"] if method == Method::GET => {
+                self.connections(req.uri.query().unwrap_or_default())
+            }
+            ["admin", "connections"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "connections", id] if method == Method::DELETE => match id.parse() {
+                Ok(id) if self.connections.close(id) => {
+                    reply(StatusCode::OK, json!({ "id": id, "closed": true }))
+                }
+                _ => error(StatusCode::NOT_FOUND, "no such connection"),
+            },
+            ["admin", "connections", _] => {
+                error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            ["admin", "upstreams"] if method == Method::GET => self.list_upstreams(),
+            ["admin", "upstreams"] if method == Method::POST => {
+                self.add_upstream(http_session).await
+            }
+            ["admin", "upstreams"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "upstreams", addr] if method == Method::DELETE => {
+                self.remove_upstream(addr).await
+            }
+            ["admin", "upstreams", _] => {
+                error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            ["admin", "upstreams", addr, "drain"] => self.upstream_drain(&method, addr),
+            ["admin", "ring", cluster] if method == Method::GET => {
+                let key = query_param(req.uri.query().unwrap_or_default(), "key");
+                self.ring(cluster, key.as_deref())
+            }
+            ["admin", "ring", _] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "debug"] if method == Method::GET => self.debug(),
+            ["admin", "debug"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "debug", "profile"] if method == Method::GET => {
+                self.profile(req.uri.query().unwrap_or_default()).await
+            }
+            ["admin", "debug", "profile"] => {
+                error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            ["admin", "synthetic"] if method == Method::GET => match &self.synthetic {
+                Some(prober) => reply(StatusCode::OK, prober.to_json()),
+                None => error(StatusCode::NOT_FOUND, "no synthetic checks"),
+            },
+            ["admin", "synthetic"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "cache"] if method == Method::GET => match &self.cache {
+                Some(cache) => reply(StatusCode::OK, cache.to_json()),
+                None => error(StatusCode::NOT_FOUND, "no cache"),
+            },
+            ["admin", "cache"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "cache", "partitions", name] if method == Method::DELETE => {
+                match self.cache.as_ref().and_then(|cache| cache.purge(name)) {
+                    Some(purged) => reply(
+                        StatusCode::OK,
+                        json!({ "partition": name, "purged": purged }),
+                    ),
+                    None => error(StatusCode::NOT_FOUND, "no such cache partition"),
+                }
+            }
+            ["admin", "cache", "partitions", _] => {
+                error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            ["admin", "anomaly"] if method == Method::GET => match &self.anomaly {
+                Some(scorer) => reply(StatusCode::OK, scorer.to_json()),
+                None => error(StatusCode::NOT_FOUND, "requests are not scored"),
+            },
+            ["admin", "anomaly"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "ready"] if method == Method::GET => match &self.readiness {
+                Some(readiness) if readiness.is_ready() => {
+                    reply(StatusCode::OK, readiness.to_json())
+                }
+                Some(readiness) => reply(StatusCode::SERVICE_UNAVAILABLE, readiness.to_json()),
+                None => error(StatusCode::NOT_FOUND, "no readiness checks"),
+            },
+            ["admin", "ready"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "flags"] if method == Method::GET => match &self.flags {
+                Some(flags) => reply(StatusCode::OK, flags.to_json()),
+                None => error(StatusCode::NOT_FOUND, "no feature flags"),
+            },
+            ["admin", "flags"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "usage"] if method == Method::GET => match &self.usage {
+                Some(meter) => reply(StatusCode::OK, meter.to_json()),
+                None => error(StatusCode::NOT_FOUND, "usage is not accounted"),
+            },
+            ["admin", "usage"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "egress"] if method == Method::GET => match &self.egress {
+                Some(policy) => reply(StatusCode::OK, policy.to_json()),
+                None => error(StatusCode::NOT_FOUND, "no egress policy"),
+            },
+            ["admin", "egress"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "circuits"] if method == Method::GET => match &self.circuits {
+                Some(circuits) => reply(StatusCode::OK, circuits.to_json()),
+                None => error(StatusCode::NOT_FOUND, "no circuit breakers"),
+            },
+            ["admin", "circuits"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "region"] if method == Method::GET => match &self.region {
+                Some(failover) => reply(StatusCode::OK, failover.to_json()),
+                None => error(StatusCode::NOT_FOUND, "no secondary region"),
+            },
+            ["admin", "region"] => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            ["admin", "connect-race"] if method == Method::GET => match &self.connect_race {
+                Some(race) => reply(StatusCode::OK, race.to_json()),
+                None => error(StatusCode::NOT_FOUND, "connects are not raced"),
+            },
+            ["admin", "connect-race"] => {
+                error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            _ => error(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+/// The percent-decoded value of `name` in `query`.
+pub(crate) fn query_param(query: &str, name: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(n, v)| (n == name).then_some(v))?;
+    let mut out = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b => out.push(b),
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn reply(status: StatusCode, body: Value) -> Response<Vec<u8>> {
+    let body = body.to_string().into_bytes();
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Vec<u8>> {
+    reply(status, json!({ "error": message }))
+}
+
+/// The JSON request body, at most `max` bytes; the error response otherwise.
+async fn read_json(
+    http_session: &mut ServerSession,
+    max: usize,
+    what: &str,
+) -> Result<Value, Response<Vec<u8>>> {
+    let body = read_body(http_session, max, what).await?;
+    serde_json::from_slice(&body)
+        .map_err(|_| error(StatusCode::BAD_REQUEST, &format!("{what} is not JSON")))
+}
+
+async fn read_body(
+    http_session: &mut ServerSession,
+    max: usize,
+    what: &str,
+) -> Result<Vec<u8>, Response<Vec<u8>>> {
+    let mut body = Vec::new();
+    loop {
+        match http_session.read_request_body().await {
+            Ok(Some(chunk)) if body.len() + chunk.len() <= max => body.extend_from_slice(&chunk),
+            Ok(Some(_)) => {
+                return Err(error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    &format!("{what} too large"),
+                ));
+            }
+            Ok(None) => break,
+            Err(_) => {
+                return Err(error(
+                    StatusCode::BAD_REQUEST,
+                    &format!("reading the {what} failed"),
+                ));
+            }
+        }
+    }
+    Ok(body)
+}
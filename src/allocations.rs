@@ -0,0 +1,146 @@
+//! Counts of heap allocations, to see what a request costs the allocator.
+//!
+//! [`Counting`] wraps the system allocator and counts the allocations of
+//! every thread, on slots of their own so the count does not contend. The
+//! binary installs it as the global allocator; without it nothing is
+//! counted. The counts cover the whole process, admin API and background
+//! services included, so allocations per request are an upper bound, close
+//! to the truth while the proxy is busy. [`PerRequest`] reports them between
+//! one read and the next, with the requests the proxy started meanwhile.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use serde_json::{Value, json};
+
+use crate::ctx;
+
+const SLOTS: usize = 64;
+
+#[repr(align(128))]
+struct Slot {
+    count: AtomicU64,
+    bytes: AtomicU64,
+}
+
+static COUNTS: [Slot; SLOTS] = [const {
+    Slot {
+        count: AtomicU64::new(0),
+        bytes: AtomicU64::new(0),
+    }
+}; SLOTS];
+
+/// The slot of the current thread. Not [`crate::sharded`]'s worker number:
+/// every thread allocates, and numbering them all would spread the workers
+/// over the shards unevenly.
+fn slot() -> &'static Slot {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SLOT: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+    let n = SLOT.with(|slot| match slot.get() {
+        Some(n) => n,
+        None => {
+            let n = NEXT.fetch_add(1, Ordering::Relaxed) % SLOTS;
+            slot.set(Some(n));
+            n
+        }
+    });
+    &COUNTS[n]
+}
+
+fn count(size: usize) {
+    let slot = slot();
+    slot.count.fetch_add(1, Ordering::Relaxed);
+    slot.bytes.fetch_add(size as u64, Ordering::Relaxed);
+}
+
+/// The system allocator, counting allocations. A `realloc` counts as one.
+pub struct Counting;
+
+// SAFETY: every call goes to the system allocator unchanged
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+/// Allocations so far and their bytes, zero without [`Counting`].
+pub fn allocated() -> (u64, u64) {
+    COUNTS.iter().fold((0, 0), |(count, bytes), slot| {
+        (
+            count + slot.count.load(Ordering::Relaxed),
+            bytes + slot.bytes.load(Ordering::Relaxed),
+        )
+    })
+}
+
+#[derive(Clone, Copy, Default)]
+struct Snapshot {
+    allocations: u64,
+    bytes: u64,
+    requests: u64,
+}
+
+impl Snapshot {
+    fn now() -> Self {
+        let (allocations, bytes) = allocated();
+        Snapshot {
+            allocations,
+            bytes,
+            requests: ctx::recycling().requests(),
+        }
+    }
+}
+
+/// Allocations per request since the previous read.
+#[derive(Default)]
+pub struct PerRequest {
+    last: Mutex<Snapshot>,
+}
+
+impl PerRequest {
+    /// The counts, `null` when allocations are not counted.
+    pub fn to_json(&self) -> Value {
+        let now = Snapshot::now();
+        if now.allocations == 0 {
+            return Value::Null;
+        }
+        let last = std::mem::replace(&mut *self.last.lock().unwrap(), now);
+        let requests = now.requests - last.requests;
+        let per_request = |n: u64| (requests > 0).then(|| n as f64 / requests as f64);
+        let recycling = ctx::recycling();
+        json!({
+            "allocations": now.allocations,
+            "bytes": now.bytes,
+            "requests": now.requests,
+            "since_last_read": {
+                "requests": requests,
+                "allocations": now.allocations - last.allocations,
+                "per_request": per_request(now.allocations - last.allocations),
+                "bytes_per_request": per_request(now.bytes - last.bytes),
+            },
+            "contexts": {
+                "recycled": recycling.recycled(),
+                "fresh": recycling.requests() - recycling.recycled(),
+            },
+        })
+    }
+}
@@ -0,0 +1,350 @@
+//! Request fingerprints and anomaly scores.
+//!
+//! Scripted clients give themselves away in how they build requests rather
+//! than in any one value: the order and casing of their header names, missing
+//! headers every browser sends, an `Accept-Language` no browser would, a
+//! browser `User-Agent` over a TLS connection without ALPN. The
+//! [`AnomalyScorer`] fingerprints every request by the names of its headers,
+//! as sent, and adds up the weights of the signals it shows into a score from
+//! 0 to 100. A fingerprint sent many times a second from one address is a
+//! signal too: behind a shared address it singles out the script from the
+//! people next to it, who send other fingerprints.
+//!
+//! A route can refuse requests scoring over its
+//! [`crate::route::Route::max_anomaly_score`], and the GraphQL rate limits
+//! take [`RequestFingerprint::rate_multiplier`] requests from their buckets for
+//! each one. The score is logged and sent upstream in [`SCORE_HEADER`], with
+//! the fingerprint in [`FINGERPRINT_HEADER`], for rules of the upstream's own.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use http::{Version, header};
+use pingora::http::RequestHeader;
+use serde_json::{Value, json};
+
+use crate::fingerprint::{self, TlsFingerprint};
+
+/// Upstream header with the request's fingerprint.
+pub const FINGERPRINT_HEADER: &str = "x-request-fingerprint";
+/// Upstream header with the request's anomaly score.
+pub const SCORE_HEADER: &str = "x-anomaly-score";
+
+/// Highest anomaly score.
+pub const MAX_SCORE: u32 = 100;
+
+/// Address and fingerprint pairs counted at once before stale ones are dropped.
+const MAX_TRACKED: usize = 10_000;
+
+/// User agents of HTTP libraries and tools rather than browsers.
+const TOOLS: &[&str] = &[
+    "curl",
+    "wget",
+    "python",
+    "go-http-client",
+    "java/",
+    "okhttp",
+    "libwww",
+    "scrapy",
+    "aiohttp",
+    "axios",
+    "node-fetch",
+    "httpclient",
+    "headless",
+];
+
+#[derive(Clone, Debug)]
+pub struct AnomalyConfig {
+    /// Requests a second with one fingerprint from one address before they
+    /// count as a burst.
+    pub burst_per_sec: u32,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig { burst_per_sec: 20 }
+    }
+}
+
+/// What a request shows, and the weight of each in its score.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Signal {
+    NoUserAgent,
+    ToolUserAgent,
+    NoAccept,
+    NoAcceptEncoding,
+    /// a browser `User-Agent` without `Accept-Language`
+    NoAcceptLanguage,
+    MalformedAcceptLanguage,
+    /// both lowercase and capitalized header names over HTTP/1
+    MixedHeaderCase,
+    /// a browser `User-Agent` over HTTP/1 whose `Host` is not its first header
+    HostNotFirst,
+    /// a browser `User-Agent` over TLS without ALPN
+    NoAlpn,
+    /// the fingerprint sent over [`AnomalyConfig::burst_per_sec`]
+    FingerprintBurst,
+}
+
+impl Signal {
+    pub fn weight(self) -> u32 {
+        match self {
+            Signal::NoUserAgent => 30,
+            Signal::ToolUserAgent => 40,
+            Signal::NoAccept => 15,
+            Signal::NoAcceptEncoding => 10,
+            Signal::NoAcceptLanguage => 20,
+            Signal::MalformedAcceptLanguage => 15,
+            Signal::MixedHeaderCase => 20,
+            Signal::HostNotFirst => 10,
+            Signal::NoAlpn => 30,
+            Signal::FingerprintBurst => 30,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Signal::NoUserAgent => "no-user-agent",
+            Signal::ToolUserAgent => "tool-user-agent",
+            Signal::NoAccept => "no-accept",
+            Signal::NoAcceptEncoding => "no-accept-encoding",
+            Signal::NoAcceptLanguage => "no-accept-language",
+            Signal::MalformedAcceptLanguage => "malformed-accept-language",
+            Signal::MixedHeaderCase => "mixed-header-case",
+            Signal::HostNotFirst => "host-not-first",
+            Signal::NoAlpn => "no-alpn",
+            Signal::FingerprintBurst => "fingerprint-burst",
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestFingerprint {
+    /// Hash of the method, version and header names in order and case.
+    pub hash: String,
+    pub signals: Vec<Signal>,
+    /// The sum of the signals' weights, at most [`MAX_SCORE`].
+    pub score: u32,
+}
+
+impl RequestFingerprint {
+    /// How many requests the request counts as for rate limits: 1 at score
+    /// 0, up to 3 at [`MAX_SCORE`].
+    pub fn rate_multiplier(&self) -> f64 {
+        1.0 + 2.0 * self.score as f64 / MAX_SCORE as f64
+    }
+}
+
+impl fmt::Display for RequestFingerprint {
+    /// `45(no-accept-language,host-not-first)`, for the log.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.score)?;
+        if !self.signals.is_empty() {
+            let signals: Vec<&str> = self.signals.iter().map(|s| s.as_str()).collect();
+            write!(f, "({})", signals.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+struct Window {
+    started: Instant,
+    requests: u32,
+}
+
+pub struct AnomalyScorer {
+    config: AnomalyConfig,
+    windows: Mutex<HashMap<(IpAddr, String), Window>>,
+    scored: Mutex<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    requests: u64,
+    /// requests by score, in tens
+    scores: [u64; 11],
+    signals: BTreeMap<Signal, u64>,
+}
+
+impl AnomalyScorer {
+    pub fn new(config: AnomalyConfig) -> Self {
+        AnomalyScorer {
+            config,
+            windows: Mutex::default(),
+            scored: Mutex::default(),
+        }
+    }
+
+    /// Fingerprint and score `req` from `client`, whose TLS connection has
+    /// `tls` for its fingerprint.
+    pub fn score(
+        &self,
+        req: &RequestHeader,
+        client: Option<IpAddr>,
+        tls: Option<&TlsFingerprint>,
+    ) -> RequestFingerprint {
+        let names: Vec<String> = if req.has_case() {
+            req.case_header_iter()
+                .map(|(name, _)| String::from_utf8_lossy(name.as_slice()).into_owned())
+                .collect()
+        } else {
+            req.headers.keys().map(|name| name.to_string()).collect()
+        };
+        let hash = fingerprint::short_hash(&format!(
+            "{} {:?} {}",
+            req.method,
+            req.version,
+            names.join(",")
+        ));
+
+        let mut signals = Vec::new();
+        let header = |name| {
+            req.headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+        };
+        let browser = match header(header::USER_AGENT) {
+            None | Some("") => {
+                signals.push(Signal::NoUserAgent);
+                false
+            }
+            Some(agent) => {
+                let agent = agent.to_ascii_lowercase();
+                if TOOLS.iter().any(|tool| agent.contains(tool)) {
+                    signals.push(Signal::ToolUserAgent);
+                }
+                agent.starts_with("mozilla/")
+            }
+        };
+        if header(header::ACCEPT).is_none() {
+            signals.push(Signal::NoAccept);
+        }
+        if header(header::ACCEPT_ENCODING).is_none() {
+            signals.push(Signal::NoAcceptEncoding);
+        }
+        match header(header::ACCEPT_LANGUAGE) {
+            None if browser => signals.push(Signal::NoAcceptLanguage),
+            Some(languages) if !well_formed_languages(languages) => {
+                signals.push(Signal::MalformedAcceptLanguage)
+            }
+            _ => {}
+        }
+        if req.version < Version::HTTP_2 && req.has_case() {
+            let lowercase = |name: &String| !name.bytes().any(|b| b.is_ascii_uppercase());
+            if names.iter().any(lowercase) && !names.iter().all(lowercase) {
+                signals.push(Signal::MixedHeaderCase);
+            }
+            if browser
+                && names
+                    .first()
+                    .is_some_and(|n| !n.eq_ignore_ascii_case("host"))
+            {
+                signals.push(Signal::HostNotFirst);
+            }
+        }
+        if browser && tls.is_some_and(|tls| !tls.offers_alpn()) {
+            signals.push(Signal::NoAlpn);
+        }
+        if let Some(client) = client
+            && self.burst(client, &hash)
+        {
+            signals.push(Signal::FingerprintBurst);
+        }
+
+        let score = signals
+            .iter()
+            .map(|s| s.weight())
+            .sum::<u32>()
+            .min(MAX_SCORE);
+        let mut scored = self.scored.lock().unwrap();
+        scored.requests += 1;
+        scored.scores[score as usize / 10] += 1;
+        for signal in &signals {
+            *scored.signals.entry(*signal).or_default() += 1;
+        }
+        drop(scored);
+        RequestFingerprint {
+            hash,
+            signals,
+            score,
+        }
+    }
+
+    /// Count a request with `hash` from `client`, `true` if it makes a burst.
+    fn burst(&self, client: IpAddr, hash: &str) -> bool {
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut windows = self.windows.lock().unwrap();
+        let key = (client, hash.to_string());
+        if windows.len() >= MAX_TRACKED && !windows.contains_key(&key) {
+            windows.retain(|_, w| now.duration_since(w.started) < second);
+        }
+        let window = windows.entry(key).or_insert(Window {
+            started: now,
+            requests: 0,
+        });
+        if now.duration_since(window.started) >= second {
+            window.started = now;
+            window.requests = 0;
+        }
+        window.requests += 1;
+        window.requests > self.config.burst_per_sec
+    }
+
+    pub fn to_json(&self) -> Value {
+        let scored = self.scored.lock().unwrap();
+        let scores: BTreeMap<String, u64> = scored
+            .scores
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(tens, count)| (format!("{}", tens * 10), *count))
+            .collect();
+        let signals: BTreeMap<&str, u64> = scored
+            .signals
+            .iter()
+            .map(|(signal, count)| (signal.as_str(), *count))
+            .collect();
+        json!({
+            "requests": scored.requests,
+            "scores": scores,
+            "signals": signals,
+            "tracked": self.windows.lock().unwrap().len(),
+            "burst_per_sec": self.config.burst_per_sec,
+        })
+    }
+}
+
+/// Whether `languages` reads as browsers write it, `en-US,en;q=0.9`.
+fn well_formed_languages(languages: &str) -> bool {
+    !languages.is_empty()
+        && languages.split(',').all(|item| {
+            let mut parts = item.trim().split(';');
+            let tag = parts.next().unwrap_or_default().trim();
+            let tag_ok = tag == "*"
+                || (!tag.is_empty()
+                    && tag.len() <= 35
+                    && tag.split('-').all(|sub| {
+                        (1..=8).contains(&sub.len())
+                            && sub.bytes().all(|b| b.is_ascii_alphanumeric())
+                    }));
+            let quality_ok = parts.all(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| (0.0..=1.0).contains(&q))
+            });
+            tag_ok && quality_ok
+        })
+}
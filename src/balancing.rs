@@ -0,0 +1,449 @@
+//! How an upstream is picked among the usable ones of a cluster.
+//!
+//! With [`Balancing::RoundRobin`], the load balancer's own selection, an
+//! upstream of weight `w` gets `w` turns in a row, and once one is passed
+//! over the others are tried without regard to their weights.
+//! [`Balancing::WeightedRoundRobin`] spreads the turns of every upstream over
+//! the cycle, as nginx's smooth weighted round robin does: next to one of
+//! weight 1, an upstream of weight 3 gets three of every four requests, but
+//! never all four. An upstream passed over, unhealthy or full, hands its
+//! turn on to the next one. [`Balancing::LeastConnections`] picks the
+//! upstream with the fewest requests queued on or in flight to it, as
+//! [`InFlight`] counts them from the pick in `upstream_peer` until the
+//! request is logged, relative to its weight: one of weight 2 holds twice
+//! the requests of one of weight 1. Upstreams tied for the fewest take
+//! turns.
+//!
+//! [`Balancing::PeakEwma`] weighs those requests by how long the upstream
+//! takes to answer, its [`Latencies`]: the cost of an upstream is its latency
+//! estimate times one more than its requests, and the cheapest is picked. The
+//! estimate follows a moving average of the time from sending a request to
+//! its response header, but jumps to any slower response at once, so a
+//! backend that degrades loses its share on the first slow response rather
+//! than after many. A failed attempt counts as a response after
+//! [`FAILURE_PENALTY`]. Without responses the estimate decays, and an
+//! upstream passed over for being slow gets tried again. One never heard
+//! from counts as answering in [`DEFAULT_LATENCY`]. Estimates are read and
+//! updated without locks; of responses of an upstream that arrive together,
+//! only one may make it into the estimate.
+//!
+//! [`Balancing::PowerOfTwoChoices`] draws two upstreams at random and picks
+//! the one with fewer requests for its weight, by the same counts as
+//! least connections. It comes close to least connections while looking at
+//! two upstreams instead of all, and, as every proxy draws its own pairs,
+//! replicas that count only their own requests do not all pile onto the one
+//! upstream each of them sees as least loaded. When neither of the two may
+//! take the request, the least loaded of all that may is picked.
+//!
+//! Weights come from discovery: the weights of the upstreams file, those
+//! given through the admin API, and load feedback.
+
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use pingora::lb::selection::{BackendIter, BackendSelection, RoundRobin};
+use pingora::lb::{Backend, LoadBalancer};
+use pingora::protocols::l4::socket::SocketAddr;
+
+use crate::in_flight::InFlight;
+
+/// Longest cycle of turns; larger weights are scaled down to fit.
+const MAX_CYCLE: u64 = 1 << 16;
+
+/// Time over which a latency estimate mostly forgets what it saw.
+pub const DECAY: Duration = Duration::from_secs(10);
+
+/// Latency of an upstream not heard from yet.
+pub const DEFAULT_LATENCY: Duration = Duration::from_millis(30);
+
+/// Latency a failed attempt counts as.
+pub const FAILURE_PENALTY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Balancing {
+    #[default]
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastConnections,
+    PeakEwma,
+    PowerOfTwoChoices,
+}
+
+impl Balancing {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Balancing::RoundRobin => "round-robin",
+            Balancing::WeightedRoundRobin => "weighted-round-robin",
+            Balancing::LeastConnections => "least-connections",
+            Balancing::PeakEwma => "peak-ewma",
+            Balancing::PowerOfTwoChoices => "power-of-two-choices",
+        }
+    }
+}
+
+impl std::str::FromStr for Balancing {
+    type Err = String;
+
+    /// `round-robin`, `weighted-round-robin`, `least-connections`,
+    /// `peak-ewma` or `power-of-two-choices`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "round-robin" => Ok(Balancing::RoundRobin),
+            "weighted-round-robin" => Ok(Balancing::WeightedRoundRobin),
+            "least-connections" => Ok(Balancing::LeastConnections),
+            "peak-ewma" => Ok(Balancing::PeakEwma),
+            "power-of-two-choices" => Ok(Balancing::PowerOfTwoChoices),
+            _ => Err(format!("unknown balancing {s}")),
+        }
+    }
+}
+
+/// Smooth weighted round robin over a set of backends.
+pub struct SmoothWeighted {
+    backends: Box<[Backend]>,
+    /// one cycle of turns, as indexes into `backends`
+    order: Box<[u32]>,
+    next: AtomicUsize,
+}
+
+impl BackendSelection for SmoothWeighted {
+    type Iter = SmoothIter;
+    type Config = ();
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        let backends: Box<[Backend]> = backends.iter().cloned().collect();
+        let mut weights: Vec<u64> = backends.iter().map(|b| b.weight.max(1) as u64).collect();
+        let divisor = weights.iter().copied().reduce(gcd).unwrap_or(1);
+        weights.iter_mut().for_each(|w| *w /= divisor);
+        let total: u64 = weights.iter().sum();
+        if total > MAX_CYCLE {
+            weights
+                .iter_mut()
+                .for_each(|w| *w = (*w * MAX_CYCLE / total).max(1));
+        }
+
+        // every turn each backend gains its weight and the one furthest
+        // ahead takes the turn, giving back the total
+        let total: i64 = weights.iter().sum::<u64>() as i64;
+        let mut current = vec![0i64; weights.len()];
+        let mut order = Vec::with_capacity(total as usize);
+        for _ in 0..total {
+            let mut best = 0;
+            for (i, weight) in weights.iter().enumerate() {
+                current[i] += *weight as i64;
+                if current[i] > current[best] {
+                    best = i;
+                }
+            }
+            current[best] -= total;
+            order.push(best as u32);
+        }
+        SmoothWeighted {
+            backends,
+            order: order.into_boxed_slice(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn iter(self: &Arc<Self>, _key: &[u8]) -> SmoothIter {
+        let first = match self.order.len() {
+            0 => 0,
+            len => self.order[self.next.fetch_add(1, Ordering::Relaxed) % len] as usize,
+        };
+        SmoothIter {
+            selection: self.clone(),
+            first,
+            tried: 0,
+        }
+    }
+}
+
+/// A random number from a generator of the current thread, seeded at random.
+fn random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u8));
+    }
+    // SplitMix64
+    STATE.with(|state| {
+        let mut z = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state.set(z);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    })
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// The backend whose turn it is, then every other one once.
+pub struct SmoothIter {
+    selection: Arc<SmoothWeighted>,
+    first: usize,
+    tried: usize,
+}
+
+impl BackendIter for SmoothIter {
+    fn next(&mut self) -> Option<&Backend> {
+        let backends = &self.selection.backends;
+        if self.tried == backends.len() {
+            return None;
+        }
+        let backend = &backends[(self.first + self.tried) % backends.len()];
+        self.tried += 1;
+        Some(backend)
+    }
+}
+
+/// The latency estimate of an upstream, in seconds, as of `at`, in
+/// nanoseconds since its [`Latencies`] started.
+struct Estimate {
+    latency: AtomicU64,
+    at: AtomicU64,
+}
+
+impl Estimate {
+    fn latency(&self) -> f64 {
+        f64::from_bits(self.latency.load(Ordering::Relaxed))
+    }
+
+    /// How much of the estimate is left at `now`.
+    fn weight(&self, now: u64) -> f64 {
+        let age = now.saturating_sub(self.at.load(Ordering::Relaxed));
+        (-Duration::from_nanos(age).as_secs_f64() / DECAY.as_secs_f64()).exp()
+    }
+
+    /// The estimate decayed to `now`.
+    fn decayed(&self, now: u64) -> f64 {
+        self.latency() * self.weight(now)
+    }
+
+    fn set(&self, latency: f64, at: u64) {
+        self.latency.store(latency.to_bits(), Ordering::Relaxed);
+        self.at.store(at, Ordering::Relaxed);
+    }
+}
+
+/// Peak EWMA latency estimates of the upstreams of all clusters, by address.
+pub struct Latencies {
+    started: Instant,
+    /// replaced when an upstream is first heard from
+    estimates: ArcSwap<HashMap<SocketAddr, Arc<Estimate>>>,
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Latencies {
+            started: Instant::now(),
+            estimates: ArcSwap::default(),
+        }
+    }
+}
+
+impl Latencies {
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64
+    }
+
+    /// Record a response of `addr` after `latency`.
+    pub fn observe(&self, addr: &SocketAddr, latency: Duration) {
+        let now = self.now();
+        let sample = latency.as_secs_f64();
+        if let Some(estimate) = self.estimates.load().get(addr) {
+            let w = estimate.weight(now);
+            let latency = estimate.latency();
+            let latency = if sample > latency * w {
+                sample
+            } else {
+                // the older the estimate, the more the sample counts
+                latency * w + sample * (1.0 - w)
+            };
+            estimate.set(latency, now);
+            return;
+        }
+        self.estimates.rcu(|estimates| {
+            let mut estimates = HashMap::clone(estimates);
+            estimates.entry(addr.clone()).or_insert_with(|| {
+                Arc::new(Estimate {
+                    latency: AtomicU64::new(sample.to_bits()),
+                    at: AtomicU64::new(now),
+                })
+            });
+            estimates
+        });
+    }
+
+    /// An attempt on `addr` failed.
+    pub fn failed(&self, addr: &SocketAddr) {
+        self.observe(addr, FAILURE_PENALTY);
+    }
+
+    /// The latency estimate of `addr` now.
+    pub fn estimate(&self, addr: &SocketAddr) -> Duration {
+        self.estimates
+            .load()
+            .get(addr)
+            .map_or(DEFAULT_LATENCY, |estimate| {
+                Duration::from_secs_f64(estimate.decayed(self.now()))
+            })
+    }
+}
+
+/// A set of backends with its selection.
+type Selection = (Arc<BTreeSet<Backend>>, Arc<SmoothWeighted>);
+
+/// Picks the upstreams of clusters by a [`Balancing`].
+pub struct Balancer {
+    balancing: Balancing,
+    /// the selection of each set of backends seen lately, read without a
+    /// lock
+    weighted: ArcSwap<Vec<Selection>>,
+    /// where the search for the cheapest starts, so ties take turns
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(balancing: Balancing) -> Self {
+        Balancer {
+            balancing,
+            weighted: ArcSwap::default(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn balancing(&self) -> Balancing {
+        self.balancing
+    }
+
+    /// The first upstream of `upstreams` to `accept`, given whether it is
+    /// healthy, in the order of the balancing, with `in_flight` the requests
+    /// of every upstream and `latencies` their latency estimates.
+    pub(crate) fn select(
+        &self,
+        upstreams: &LoadBalancer<RoundRobin>,
+        in_flight: &InFlight,
+        latencies: &Latencies,
+        accept: impl Fn(&Backend, bool) -> bool,
+    ) -> Option<Backend> {
+        match self.balancing {
+            // hash doesn't matter
+            Balancing::RoundRobin => upstreams.select_with(b"", 256, accept),
+            Balancing::WeightedRoundRobin => {
+                let backends = upstreams.backends();
+                let mut iter = self.weighted(backends.get_backend()).iter(b"");
+                while let Some(backend) = iter.next() {
+                    if accept(backend, backends.ready(backend)) {
+                        return Some(backend.clone());
+                    }
+                }
+                None
+            }
+            Balancing::LeastConnections => self.cheapest(upstreams, accept, |backend| {
+                in_flight.load(&backend.addr) as f64
+            }),
+            Balancing::PeakEwma => self.cheapest(upstreams, accept, |backend| {
+                let latency = latencies.estimate(&backend.addr).as_secs_f64();
+                latency * (in_flight.load(&backend.addr) + 1) as f64
+            }),
+            Balancing::PowerOfTwoChoices => {
+                let load = |backend: &Backend| in_flight.load(&backend.addr) as f64;
+                self.two_choices(upstreams, &accept, load)
+                    .or_else(|| self.cheapest(upstreams, accept, load))
+            }
+        }
+    }
+
+    /// Of two upstreams drawn at random, the one to `accept` of the least
+    /// `cost` for its weight; `None` when neither may be.
+    fn two_choices(
+        &self,
+        upstreams: &LoadBalancer<RoundRobin>,
+        accept: impl Fn(&Backend, bool) -> bool,
+        cost: impl Fn(&Backend) -> f64,
+    ) -> Option<Backend> {
+        let backends = upstreams.backends();
+        let set = backends.get_backend();
+        let (first, second) = match set.len() {
+            0 => return None,
+            1 => (0, None),
+            n => {
+                let first = (random() % n as u64) as usize;
+                // one of the others
+                let second = (random() % (n as u64 - 1)) as usize;
+                (first, Some(second + usize::from(second >= first)))
+            }
+        };
+        let drawn = [Some(first), second];
+        drawn
+            .into_iter()
+            .flatten()
+            .filter_map(|i| set.iter().nth(i))
+            .filter(|backend| accept(backend, backends.ready(backend)))
+            .map(|backend| (backend, cost(backend) / backend.weight.max(1) as f64))
+            .reduce(|a, b| if b.1 < a.1 { b } else { a })
+            .map(|(backend, _)| backend.clone())
+    }
+
+    /// The upstream to `accept` of the least `cost` for its weight; the
+    /// search starts at the next upstream each time, so ties take turns.
+    fn cheapest(
+        &self,
+        upstreams: &LoadBalancer<RoundRobin>,
+        accept: impl Fn(&Backend, bool) -> bool,
+        cost: impl Fn(&Backend) -> f64,
+    ) -> Option<Backend> {
+        let backends = upstreams.backends();
+        let set = backends.get_backend();
+        if set.is_empty() {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % set.len();
+        let mut cheapest: Option<(&Backend, f64)> = None;
+        for backend in set.iter().cycle().skip(start).take(set.len()) {
+            let cost = cost(backend) / backend.weight.max(1) as f64;
+            if cheapest.is_none_or(|(_, least)| cost < least)
+                && accept(backend, backends.ready(backend))
+            {
+                cheapest = Some((backend, cost));
+            }
+        }
+        cheapest.map(|(backend, _)| backend.clone())
+    }
+
+    /// The selection of `backends`, built on first use.
+    fn weighted(&self, backends: Arc<BTreeSet<Backend>>) -> Arc<SmoothWeighted> {
+        let find = |sets: &[Selection]| {
+            sets.iter()
+                .find(|(set, _)| Arc::ptr_eq(set, &backends))
+                .map(|(_, selection)| selection.clone())
+        };
+        if let Some(selection) = find(&self.weighted.load()) {
+            return selection;
+        }
+        let selection = Arc::new(SmoothWeighted::build(&backends));
+        let mut built = selection.clone();
+        self.weighted.rcu(|sets| {
+            // another request may have built it first
+            if let Some(selection) = find(sets) {
+                built = selection;
+                return sets.clone();
+            }
+            built = selection.clone();
+            // sets only held here are no longer any cluster's
+            let mut sets: Vec<Selection> = sets
+                .iter()
+                .filter(|(set, _)| Arc::strong_count(set) > 1)
+                .cloned()
+                .collect();
+            sets.push((backends.clone(), selection.clone()));
+            Arc::new(sets)
+        });
+        built
+    }
+}
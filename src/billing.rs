@@ -0,0 +1,294 @@
+//! Usage accounting for usage-based billing.
+//!
+//! [`UsageMeter`] adds every request to the usage of its account: the tenant
+//! of its route and the consumer authentication established, either of which
+//! may be missing. It counts requests, request and response body bytes, the
+//! time spent waiting on upstreams from the first attempt to the end of the
+//! response, and cache hits and misses.
+//!
+//! [`UsageExporter`] closes a period every interval and exports one record
+//! per account that had requests in it, as JSON lines:
+//!
+//! ```text
+//! {"period_start":1767225600,"period_end":1767225660,"tenant":"acme","consumer":null,
+//!  "requests":120,"bytes_in":0,"bytes_out":5242880,"upstream_ms":4180,"cache_hits":80,"cache_misses":40}
+//! ```
+//!
+//! to a file they are appended to, or in the body of a `POST` to an HTTP
+//! endpoint. Records the sink did not take are exported again with the next
+//! period's, up to [`MAX_PENDING`] of them; a period closes once more on
+//! shutdown, so a restart only loses what could not be sent.
+//!
+//! Every worker adds requests to usage of its own, see [`crate::sharded`],
+//! which closing a period and reading the usage add up. A request recorded
+//! while a period closes may land in the next period.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{Uri, header};
+use log::{info, warn};
+use pingora::connectors::http::Connector;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde_json::{Value, json};
+
+use crate::sharded::Sharded;
+use crate::subrequest;
+
+/// Records kept for a sink that does not take them, the oldest are
+/// dropped past this.
+pub const MAX_PENDING: usize = 100_000;
+
+/// Who a request is billed to.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Account {
+    pub tenant: Option<String>,
+    pub consumer: Option<String>,
+}
+
+/// One request as billed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestUsage {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub upstream_time: Duration,
+    /// `Some(true)` when served from the cache, `Some(false)` when looked up
+    /// and not, `None` when not cacheable.
+    pub cache_hit: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Usage {
+    requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    upstream_time: Duration,
+    cache_hits: u64,
+    cache_misses: u64,
+}
+
+impl Usage {
+    fn add(&mut self, request: &RequestUsage) {
+        self.requests += 1;
+        self.bytes_in += request.bytes_in;
+        self.bytes_out += request.bytes_out;
+        self.upstream_time += request.upstream_time;
+        match request.cache_hit {
+            Some(true) => self.cache_hits += 1,
+            Some(false) => self.cache_misses += 1,
+            None => {}
+        }
+    }
+
+    fn merge(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.upstream_time += other.upstream_time;
+        self.cache_hits += other.cache_hits;
+        self.cache_misses += other.cache_misses;
+    }
+
+    fn to_json(self, account: &Account) -> Value {
+        json!({
+            "tenant": account.tenant,
+            "consumer": account.consumer,
+            "requests": self.requests,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "upstream_ms": self.upstream_time.as_millis() as u64,
+            "cache_hits": self.cache_hits,
+            "cache_misses": self.cache_misses,
+        })
+    }
+}
+
+/// Usage by account over the current period.
+pub struct UsageMeter {
+    /// when the current period started
+    start: Mutex<SystemTime>,
+    usage: Sharded<BTreeMap<Account, Usage>>,
+}
+
+impl Default for UsageMeter {
+    fn default() -> Self {
+        UsageMeter {
+            start: Mutex::new(SystemTime::now()),
+            usage: Sharded::default(),
+        }
+    }
+}
+
+impl UsageMeter {
+    pub fn record(&self, account: Account, request: RequestUsage) {
+        self.usage.local().entry(account).or_default().add(&request);
+    }
+
+    /// End the current period, with its records.
+    fn close(&self) -> Vec<Value> {
+        let end = SystemTime::now();
+        let start = std::mem::replace(&mut *self.start.lock().unwrap(), end);
+        let mut usage: BTreeMap<Account, Usage> = BTreeMap::new();
+        self.usage.each(|shard| {
+            for (account, shard) in std::mem::take(shard) {
+                usage.entry(account).or_default().merge(&shard);
+            }
+        });
+        usage
+            .iter()
+            .map(|(account, usage)| {
+                let mut record = usage.to_json(account);
+                record["period_start"] = unix(start).into();
+                record["period_end"] = unix(end).into();
+                record
+            })
+            .collect()
+    }
+
+    /// The usage of the current period so far.
+    pub fn to_json(&self) -> Value {
+        let start = *self.start.lock().unwrap();
+        let mut usage: BTreeMap<Account, Usage> = BTreeMap::new();
+        self.usage.each(|shard| {
+            for (account, shard) in shard.iter() {
+                usage.entry(account.clone()).or_default().merge(shard);
+            }
+        });
+        let accounts: Vec<Value> = usage
+            .iter()
+            .map(|(account, usage)| usage.to_json(account))
+            .collect();
+        json!({ "period_start": unix(start), "accounts": accounts })
+    }
+}
+
+fn unix(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Where usage records go.
+#[derive(Clone, Debug)]
+pub enum UsageSink {
+    /// Appended to this file.
+    JsonLines(PathBuf),
+    /// `POST`ed to this URL as `application/x-ndjson`, with `token` as the
+    /// bearer token.
+    Http { url: Uri, token: Option<String> },
+}
+
+pub struct UsageExporter {
+    meter: Arc<UsageMeter>,
+    sink: UsageSink,
+    interval: Duration,
+    /// records the sink did not take yet
+    pending: Mutex<Vec<Value>>,
+    connector: Connector,
+}
+
+impl UsageExporter {
+    /// Close a period of `meter` every `interval` and export it to `sink`.
+    pub fn new(meter: Arc<UsageMeter>, sink: UsageSink, interval: Duration) -> Self {
+        UsageExporter {
+            meter,
+            sink,
+            interval,
+            pending: Mutex::default(),
+            connector: Connector::new(None),
+        }
+    }
+
+    async fn export(&self) {
+        let records = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.extend(self.meter.close());
+            std::mem::take(&mut *pending)
+        };
+        if records.is_empty() {
+            return;
+        }
+        let mut body = String::new();
+        for record in &records {
+            body.push_str(&record.to_string());
+            body.push('\n');
+        }
+        let sent = match &self.sink {
+            UsageSink::JsonLines(path) => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || {
+                    std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&path)
+                        .and_then(|mut file| file.write_all(body.as_bytes()))
+                        .map_err(|e| format!("{}: {e}", path.display()))
+                })
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()))
+            }
+            UsageSink::Http { url, token } => self.post(url, token.as_deref(), body).await,
+        };
+        match sent {
+            Ok(()) => info!("exported {} usage records", records.len()),
+            Err(e) => {
+                warn!(
+                    "could not export {} usage records, keeping them: {e}",
+                    records.len()
+                );
+                let mut pending = self.pending.lock().unwrap();
+                let mut kept = records;
+                kept.append(&mut pending);
+                let over = kept.len().saturating_sub(MAX_PENDING);
+                if over > 0 {
+                    warn!("dropping the {over} oldest usage records");
+                    kept.drain(..over);
+                }
+                *pending = kept;
+            }
+        }
+    }
+
+    async fn post(&self, url: &Uri, token: Option<&str>, body: String) -> Result<(), String> {
+        let timeout = Duration::from_secs(30);
+        let (peer, mut req) = subrequest::for_url("POST", url, timeout)?;
+        let length = body.len().to_string();
+        let mut headers = vec![
+            (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (header::CONTENT_LENGTH, length),
+        ];
+        if let Some(token) = token {
+            headers.push((header::AUTHORIZATION, format!("Bearer {token}")));
+        }
+        for (name, value) in headers {
+            req.insert_header(name, value).map_err(|e| e.to_string())?;
+        }
+        let sent = subrequest::send(&self.connector, &peer, req, Bytes::from(body), 1 << 16);
+        match tokio::time::timeout(timeout, sent).await {
+            Err(_) => Err("timed out".to_string()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Ok(Ok(Some(fetched))) if fetched.header.status.is_success() => Ok(()),
+            Ok(Ok(Some(fetched))) => Err(format!("status {}", fetched.header.status)),
+            Ok(Ok(None)) => Err("response body too large".to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for UsageExporter {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    self.export().await;
+                    return;
+                }
+                _ = tokio::time::sleep(self.interval) => self.export().await,
+            }
+        }
+    }
+}
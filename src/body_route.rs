@@ -0,0 +1,149 @@
+//! Routing on what a request carries rather than where it goes.
+//!
+//! A route with [`BodyRouting`] hands its requests over to other routes, by
+//! name, by the first rule to match:
+//!
+//! - a gRPC method, from the request path `/package.Service/Method`; a
+//!   pattern `package.Service/*` matches all methods of the service,
+//! - a JSON field of the request body, by JSON pointer (`/order/region`),
+//!   compared with the field's string value or, for other types, its JSON
+//!   text (`true`, `42`),
+//! - a SOAP action, from the `SOAPAction` header or the `action` parameter
+//!   of the `Content-Type`,
+//! - the operation element of an XML body, by local name: the first element
+//!   of the `Body` of a SOAP envelope, the root element of other documents.
+//!
+//! The body is only inspected when its `Content-Length` is known and at most
+//! `max_body`, at most 64 KiB: the body read is replayed to the upstream from
+//! the buffer the proxy keeps for retries, which holds no more. Requests
+//! whose body cannot be inspected or parsed go to the fallback route when a
+//! JSON or XML rule is reached; requests matching no rule stay on the route.
+
+use http::header;
+use pingora::http::RequestHeader;
+use serde_json::Value;
+
+use crate::xml;
+
+/// The most body kept for replaying to the upstream.
+pub const MAX_BODY: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub enum BodyMatch {
+    GrpcMethod(String),
+    JsonField { pointer: String, value: String },
+    SoapAction(String),
+    XmlOperation(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct BodyRule {
+    pub matcher: BodyMatch,
+    /// Name of the route taking over matching requests.
+    pub route: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct BodyRouting {
+    rules: Vec<BodyRule>,
+    fallback: Option<String>,
+    max_body: usize,
+}
+
+impl BodyRouting {
+    pub fn new(rules: Vec<BodyRule>) -> Self {
+        BodyRouting {
+            rules,
+            fallback: None,
+            max_body: MAX_BODY,
+        }
+    }
+
+    /// Route taking over requests whose body could not be parsed; without
+    /// one they stay on the route.
+    pub fn with_fallback(mut self, route: impl Into<String>) -> Self {
+        self.fallback = Some(route.into());
+        self
+    }
+
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body.min(MAX_BODY);
+        self
+    }
+
+    /// Names of the routes requests may be handed to.
+    pub(crate) fn targets(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .map(|r| r.route.as_str())
+            .chain(self.fallback.as_deref())
+    }
+
+    /// Whether to read the body of `req` before calling [`Self::route_for`].
+    pub(crate) fn wants_body(&self, req: &RequestHeader) -> bool {
+        let body_rules = self.rules.iter().any(|r| {
+            matches!(
+                r.matcher,
+                BodyMatch::JsonField { .. } | BodyMatch::XmlOperation(_)
+            )
+        });
+        body_rules && content_length(req).is_some_and(|len| len <= self.max_body)
+    }
+
+    /// The route to hand `req` over to; `body` is `None` when it was not read.
+    pub(crate) fn route_for(&self, req: &RequestHeader, body: Option<&[u8]>) -> Option<&str> {
+        let mut json = None;
+        let mut operation = None;
+        for rule in &self.rules {
+            let matched = match &rule.matcher {
+                BodyMatch::GrpcMethod(pattern) => grpc_matches(pattern, req.uri.path()),
+                BodyMatch::JsonField { pointer, value } => {
+                    let parsed = json.get_or_insert_with(|| {
+                        body.and_then(|b| serde_json::from_slice::<Value>(b).ok())
+                    });
+                    let Some(parsed) = parsed else {
+                        return self.fallback.as_deref();
+                    };
+                    parsed.pointer(pointer).is_some_and(|field| match field {
+                        Value::String(s) => s == value,
+                        other => serde_json::from_str::<Value>(value).is_ok_and(|v| v == *other),
+                    })
+                }
+                BodyMatch::SoapAction(action) => xml::soap_action(req) == Some(action.as_str()),
+                BodyMatch::XmlOperation(name) => {
+                    let parsed = operation.get_or_insert_with(|| body.and_then(xml::operation));
+                    let Some(parsed) = parsed else {
+                        return self.fallback.as_deref();
+                    };
+                    parsed == name
+                }
+            };
+            if matched {
+                return Some(&rule.route);
+            }
+        }
+        None
+    }
+}
+
+fn content_length(req: &RequestHeader) -> Option<usize> {
+    req.headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Whether the gRPC request path `/package.Service/Method` matches `pattern`.
+fn grpc_matches(pattern: &str, path: &str) -> bool {
+    let Some(method) = path.strip_prefix('/') else {
+        return false;
+    };
+    match pattern.strip_suffix("/*") {
+        Some(service) => method
+            .strip_prefix(service)
+            .is_some_and(|rest| rest.starts_with('/') && !rest[1..].contains('/')),
+        None => method == pattern,
+    }
+}
@@ -0,0 +1,127 @@
+//! Soft per-route budgets for optional filters.
+//!
+//! Some filters are optional: the request is answered correctly without
+//! them, only less well. Image transforms can fall back to the original
+//! image and a cache fill can let the response pass uncached. A route's
+//! [`Budget`] caps the bytes such filters hold buffered at once and the
+//! CPU-heavy operations they start per second. A filter that would go over is
+//! skipped for the request, and the skip is counted by filter for the admin
+//! API. Filters the answer depends on, like signing or the XML and GraphQL
+//! guards, are never skipped.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{Value, json};
+
+/// The limits of a route; `None` is unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct RouteBudget {
+    /// Bytes optional filters of the route may hold buffered at once.
+    pub max_buffered: Option<usize>,
+    /// CPU-heavy operations, e.g. image transforms, started per second.
+    pub max_heavy_per_sec: Option<u32>,
+}
+
+struct Window {
+    started: Instant,
+    ops: u32,
+}
+
+pub struct Budget {
+    limits: RouteBudget,
+    buffered: Arc<AtomicUsize>,
+    window: Mutex<Window>,
+    over_buffered: AtomicU64,
+    over_heavy: AtomicU64,
+    /// skips by filter
+    skipped: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Bytes buffered under a budget, given back when dropped.
+pub(crate) struct BufferLease {
+    buffered: Arc<AtomicUsize>,
+    bytes: usize,
+}
+
+impl Drop for BufferLease {
+    fn drop(&mut self) {
+        self.buffered.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+impl Budget {
+    pub fn new(limits: RouteBudget) -> Self {
+        Budget {
+            limits,
+            buffered: Arc::default(),
+            window: Mutex::new(Window {
+                started: Instant::now(),
+                ops: 0,
+            }),
+            over_buffered: AtomicU64::new(0),
+            over_heavy: AtomicU64::new(0),
+            skipped: Mutex::default(),
+        }
+    }
+
+    fn skip(&self, filter: &'static str) {
+        *self.skipped.lock().unwrap().entry(filter).or_default() += 1;
+    }
+
+    /// Let `filter` buffer `bytes`, or `None` if that goes over the budget
+    /// and the filter is to be skipped.
+    pub(crate) fn buffer(&self, filter: &'static str, bytes: usize) -> Option<BufferLease> {
+        let held = self.buffered.fetch_add(bytes, Ordering::Relaxed);
+        if self
+            .limits
+            .max_buffered
+            .is_some_and(|max| held + bytes > max)
+        {
+            self.buffered.fetch_sub(bytes, Ordering::Relaxed);
+            self.over_buffered.fetch_add(1, Ordering::Relaxed);
+            self.skip(filter);
+            return None;
+        }
+        Some(BufferLease {
+            buffered: self.buffered.clone(),
+            bytes,
+        })
+    }
+
+    /// Let `filter` start a CPU-heavy operation, `false` if that goes over
+    /// the budget and the filter is to be skipped.
+    pub(crate) fn heavy(&self, filter: &'static str) -> bool {
+        let Some(max) = self.limits.max_heavy_per_sec else {
+            return true;
+        };
+        let mut window = self.window.lock().unwrap();
+        if window.started.elapsed() >= Duration::from_secs(1) {
+            window.started = Instant::now();
+            window.ops = 0;
+        }
+        if window.ops < max {
+            window.ops += 1;
+            return true;
+        }
+        drop(window);
+        self.over_heavy.fetch_add(1, Ordering::Relaxed);
+        self.skip(filter);
+        false
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "max_buffered": self.limits.max_buffered,
+            "buffered": self.buffered.load(Ordering::Relaxed),
+            "max_heavy_per_sec": self.limits.max_heavy_per_sec,
+            "exceeded": {
+                "buffered": self.over_buffered.load(Ordering::Relaxed),
+                "heavy": self.over_heavy.load(Ordering::Relaxed),
+            },
+            "skipped": *self.skipped.lock().unwrap(),
+        })
+    }
+}
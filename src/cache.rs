@@ -0,0 +1,1061 @@
+//! In-memory response cache.
+//!
+//! Successful `GET` responses are kept in memory with a byte budget and
+//! least-recently-used eviction. Cached objects can answer `Range` requests
+//! locally. With slicing enabled, a range miss fetches only the fixed-size,
+//! aligned slice of the object that contains the requested range and caches
+//! that slice on its own, so large media objects never have to be fetched
+//! whole. Expired objects stay until evicted, to be served stale when no
+//! upstream is up, and are revalidated when requested again: if they have an
+//! `ETag` or `Last-Modified`, the upstream is asked with `If-None-Match` or
+//! `If-Modified-Since`, and a `304` refreshes the stored header and serves the
+//! stored body instead of downloading it again.
+//!
+//! What is stored is decided by a size-aware W-TinyLFU admission policy, see
+//! `Store`, so objects fetched once do not push out the popular ones.
+//!
+//! Routes can have a partition of their own, see
+//! [`MemoryCache::with_partition`]: a store with its own byte quota, which
+//! the traffic of other routes cannot evict from, and which can be purged on
+//! its own. The partition of a route is its tenant's, see
+//! [`Route::cache_partition`], so the routes of a tenant share one.
+//!
+//! A `HEAD` is answered from the cached `GET` of the same resource, without
+//! asking the upstream. CORS preflights, `OPTIONS` requests with an `Origin`
+//! and an `Access-Control-Request-Method`, are cached by route, origin and the
+//! method and headers they ask for, for their `Access-Control-Max-Age` up to
+//! [`CacheConfig::preflight_ttl`].
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use http::{HeaderName, HeaderValue, Method, StatusCode, header};
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde_json::{Value, json};
+
+use crate::range::{self, ByteRange, RangeSpec};
+use crate::route::Route;
+use crate::subrequest::Fetched;
+
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// Total bytes of bodies kept in memory.
+    pub capacity: usize,
+    /// Objects (or slices) larger than this are never stored.
+    pub max_object_size: usize,
+    /// TTL for responses that carry no explicit freshness; zero disables
+    /// caching of such responses.
+    pub default_ttl: Duration,
+    /// Fetch and cache range misses in aligned slices of this many bytes.
+    pub slice_size: Option<u64>,
+    /// Longest a CORS preflight response is kept; zero disables caching
+    /// them.
+    pub preflight_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            capacity: 256 * 1024 * 1024,
+            max_object_size: 8 * 1024 * 1024,
+            default_ttl: Duration::ZERO,
+            slice_size: None,
+            preflight_ttl: Duration::ZERO,
+        }
+    }
+}
+
+/// A stored response. Slices keep the upstream `206` header, whose
+/// `Content-Range` tells which part of the object the body holds.
+pub struct CachedObject {
+    pub header: ResponseHeader,
+    pub body: Bytes,
+    pub stored_at: Instant,
+    pub ttl: Duration,
+}
+
+impl CachedObject {
+    pub fn is_fresh(&self) -> bool {
+        self.stored_at.elapsed() < self.ttl
+    }
+
+    /// The conditional headers revalidating the object, empty if it has no
+    /// validator.
+    fn conditionals(&self) -> Vec<(HeaderName, HeaderValue)> {
+        let headers = &self.header.headers;
+        let mut conditionals = Vec::new();
+        if let Some(etag) = headers.get(header::ETAG) {
+            conditionals.push((header::IF_NONE_MATCH, etag.clone()));
+        }
+        if let Some(modified) = headers.get(header::LAST_MODIFIED) {
+            conditionals.push((header::IF_MODIFIED_SINCE, modified.clone()));
+        }
+        conditionals
+    }
+
+    /// The header stored after a `304` with `not_modified`, whose fields
+    /// replace the stored ones but for the framing ones (RFC 9111, 4.3.4).
+    fn refreshed(&self, not_modified: &ResponseHeader) -> Result<ResponseHeader> {
+        let mut header = self.header.clone();
+        for name in not_modified.headers.keys() {
+            if matches!(
+                *name,
+                header::CONTENT_LENGTH | header::TRANSFER_ENCODING | header::CONTENT_ENCODING
+            ) {
+                continue;
+            }
+            header.remove_header(name);
+            for value in not_modified.headers.get_all(name) {
+                header.append_header(name.clone(), value.clone())?;
+            }
+        }
+        Ok(header)
+    }
+
+    /// The byte range of the object held in `body` and the full object size.
+    fn extent(&self) -> Option<(ByteRange, u64)> {
+        if self.header.status == StatusCode::PARTIAL_CONTENT {
+            let value = self.header.headers.get(header::CONTENT_RANGE)?;
+            let (range, total) = range::parse_content_range(value.as_bytes())?;
+            return Some((range, total?));
+        }
+        let len = self.body.len() as u64;
+        (len > 0).then_some((
+            ByteRange {
+                start: 0,
+                end: len - 1,
+            },
+            len,
+        ))
+    }
+
+    /// Build the response for a request that asked for `spec`, or for the
+    /// whole object when `spec` is `None`.
+    fn respond(&self, spec: Option<RangeSpec>) -> Result<(ResponseHeader, Bytes)> {
+        let mut header = self.header.clone();
+        header.insert_header(header::AGE, self.stored_at.elapsed().as_secs().to_string())?;
+        // the stored header may describe a chunked upstream body
+        header.remove_header(&header::TRANSFER_ENCODING);
+        // preflights may be `204`s, which have no length
+        if header.status != StatusCode::NO_CONTENT {
+            header.insert_header(header::CONTENT_LENGTH, self.body.len().to_string())?;
+        }
+
+        let Some(spec) = spec else {
+            return Ok((header, self.body.clone()));
+        };
+        let Some((extent, total)) = self.extent() else {
+            return Ok((header, self.body.clone()));
+        };
+        match spec.resolve(total) {
+            Some(want) => {
+                // a slice only answers ranges it fully covers, see `lookup`
+                let want = want.intersect(&extent).unwrap_or(extent);
+                let from = (want.start - extent.start) as usize;
+                let body = self.body.slice(from..from + want.len() as usize);
+                header.set_status(StatusCode::PARTIAL_CONTENT)?;
+                header.insert_header(header::CONTENT_RANGE, range::content_range(want, total))?;
+                header.insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+                Ok((header, body))
+            }
+            None => {
+                let mut header = ResponseHeader::build(StatusCode::RANGE_NOT_SATISFIABLE, Some(2))?;
+                header.insert_header(header::CONTENT_RANGE, range::unsatisfied_range(total))?;
+                header.insert_header(header::CONTENT_LENGTH, "0")?;
+                Ok((header, Bytes::new()))
+            }
+        }
+    }
+}
+
+/// Largest object of each size class, for the hit ratios.
+const SIZE_CLASSES: [(&str, usize); 4] = [
+    ("16KiB", 16 << 10),
+    ("256KiB", 256 << 10),
+    ("4MiB", 4 << 20),
+    ("larger", usize::MAX),
+];
+
+fn size_class(len: usize) -> usize {
+    SIZE_CLASSES
+        .iter()
+        .position(|(_, max)| len <= *max)
+        .unwrap_or(SIZE_CLASSES.len() - 1)
+}
+
+/// Share of the capacity given to the admission window.
+const WINDOW_SHARE: usize = 100;
+
+/// Approximate access counts of recent keys: a count-min sketch of 4-bit
+/// counters, halved every `10 * width` accesses so old popularity fades.
+struct Sketch {
+    rows: [Vec<u8>; 4],
+    mask: usize,
+    accesses: usize,
+    hasher: RandomState,
+}
+
+impl Sketch {
+    fn new(capacity: usize) -> Self {
+        // about one counter per 4 KiB of capacity
+        let width = (capacity / 4096).clamp(1024, 1 << 20).next_power_of_two();
+        Sketch {
+            rows: std::array::from_fn(|_| vec![0; width]),
+            mask: width - 1,
+            accesses: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn slots(&self, key: &str) -> [usize; 4] {
+        let hash = self.hasher.hash_one(key);
+        std::array::from_fn(|row| {
+            // a distinct odd multiplier per row spreads the one hash
+            let mixed = hash.wrapping_mul(0x9e37_79b9_7f4a_7c15 | ((row as u64) << 1 | 1));
+            (mixed >> 32) as usize & self.mask
+        })
+    }
+
+    fn record(&mut self, key: &str) {
+        for (row, slot) in self.slots(key).into_iter().enumerate() {
+            let counter = &mut self.rows[row][slot];
+            *counter = (*counter + 1).min(15);
+        }
+        self.accesses += 1;
+        if self.accesses >= 10 * (self.mask + 1) {
+            self.accesses = 0;
+            for counter in self.rows.iter_mut().flatten() {
+                *counter /= 2;
+            }
+        }
+    }
+
+    fn estimate(&self, key: &str) -> u32 {
+        self.slots(key)
+            .into_iter()
+            .enumerate()
+            .map(|(row, slot)| self.rows[row][slot] as u32)
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct ClassStats {
+    hits: u64,
+    misses: u64,
+    /// stale objects the upstream said were still current
+    revalidated: u64,
+    admitted: u64,
+    rejected: u64,
+}
+
+struct Entry {
+    object: Arc<CachedObject>,
+    tick: u64,
+    /// whether it made it past the window into the main segment
+    main: bool,
+}
+
+/// Objects are stored W-TinyLFU style: new ones go into a small window
+/// segment in recency order, and are let into the main segment when they
+/// leave it only if the sketch counts them more popular than everything they
+/// would evict there, together. A large object seen once so loses to the
+/// small hot objects it would push out.
+struct Store {
+    entries: HashMap<String, Entry>,
+    /// recency order of the segments: tick -> key
+    window: BTreeMap<u64, String>,
+    main: BTreeMap<u64, String>,
+    window_size: usize,
+    main_size: usize,
+    window_capacity: usize,
+    main_capacity: usize,
+    next_tick: u64,
+    sketch: Sketch,
+    classes: [ClassStats; SIZE_CLASSES.len()],
+}
+
+impl Store {
+    fn new(capacity: usize) -> Self {
+        let window_capacity = capacity / WINDOW_SHARE;
+        Store {
+            entries: HashMap::new(),
+            window: BTreeMap::new(),
+            main: BTreeMap::new(),
+            window_size: 0,
+            main_size: 0,
+            window_capacity,
+            main_capacity: capacity - window_capacity,
+            next_tick: 0,
+            sketch: Sketch::new(capacity),
+            classes: [ClassStats::default(); SIZE_CLASSES.len()],
+        }
+    }
+
+    /// Count an access to `key` and, if stored, make it the most recent of
+    /// its segment.
+    fn touch(&mut self, key: &str) -> Option<Arc<CachedObject>> {
+        self.sketch.record(key);
+        let tick = self.next_tick;
+        let entry = self.entries.get_mut(key)?;
+        let segment = if entry.main {
+            &mut self.main
+        } else {
+            &mut self.window
+        };
+        segment.remove(&entry.tick);
+        entry.tick = tick;
+        segment.insert(tick, key.to_string());
+        self.next_tick += 1;
+        Some(entry.object.clone())
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            let len = entry.object.body.len();
+            if entry.main {
+                self.main.remove(&entry.tick);
+                self.main_size -= len;
+            } else {
+                self.window.remove(&entry.tick);
+                self.window_size -= len;
+            }
+        }
+    }
+
+    /// Replace the object of `key` with `object`, of the same size, where it
+    /// is; `false` if it was evicted.
+    fn refresh(&mut self, key: &str, object: CachedObject) -> bool {
+        let Some(entry) = self
+            .entries
+            .get_mut(key)
+            .filter(|e| e.object.body.len() == object.body.len())
+        else {
+            return false;
+        };
+        self.classes[size_class(object.body.len())].revalidated += 1;
+        entry.object = Arc::new(object);
+        true
+    }
+
+    fn insert(&mut self, key: String, object: CachedObject) {
+        self.remove(&key);
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.window_size += object.body.len();
+        self.window.insert(tick, key.clone());
+        let object = Arc::new(object);
+        let entry = Entry {
+            object,
+            tick,
+            main: false,
+        };
+        self.entries.insert(key, entry);
+        while self.window_size > self.window_capacity {
+            let Some((_, oldest)) = self.window.pop_first() else {
+                break;
+            };
+            self.admit(oldest);
+        }
+    }
+
+    /// Move `key`, just out of the window, into the main segment if it is
+    /// worth its victims there, or drop it.
+    fn admit(&mut self, key: String) {
+        let Some(mut entry) = self.entries.remove(&key) else {
+            return;
+        };
+        let len = entry.object.body.len();
+        self.window_size -= len;
+        let class = size_class(len);
+        if len > self.main_capacity {
+            self.classes[class].rejected += 1;
+            return;
+        }
+        let mut victims = Vec::new();
+        let mut freed = 0;
+        let mut victims_frequency = 0;
+        for (tick, victim) in &self.main {
+            if self.main_size - freed + len <= self.main_capacity {
+                break;
+            }
+            freed += self.entries[victim].object.body.len();
+            victims_frequency += self.sketch.estimate(victim);
+            victims.push(*tick);
+        }
+        if !victims.is_empty() && self.sketch.estimate(&key) <= victims_frequency {
+            self.classes[class].rejected += 1;
+            return;
+        }
+        self.classes[class].admitted += 1;
+        for tick in victims {
+            if let Some(victim) = self.main.remove(&tick)
+                && let Some(evicted) = self.entries.remove(&victim)
+            {
+                self.main_size -= evicted.object.body.len();
+            }
+        }
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        entry.tick = tick;
+        entry.main = true;
+        self.main_size += len;
+        self.main.insert(tick, key.clone());
+        self.entries.insert(key, entry);
+    }
+
+    /// Drop every object, how many there were. Access frequencies and
+    /// stats are kept.
+    fn clear(&mut self) -> usize {
+        let objects = self.entries.len();
+        self.entries.clear();
+        self.window.clear();
+        self.main.clear();
+        self.window_size = 0;
+        self.main_size = 0;
+        objects
+    }
+
+    /// Sizes of the segments and hit ratios by object size class.
+    fn to_json(&self) -> Value {
+        let classes: Vec<Value> = SIZE_CLASSES
+            .iter()
+            .zip(&self.classes)
+            .map(|((name, _), stats)| {
+                let lookups = stats.hits + stats.misses;
+                json!({
+                    "class": name,
+                    "hits": stats.hits,
+                    "misses": stats.misses,
+                    "revalidated": stats.revalidated,
+                    "hit_ratio": (lookups > 0).then(|| stats.hits as f64 / lookups as f64),
+                    "admitted": stats.admitted,
+                    "rejected": stats.rejected,
+                })
+            })
+            .collect();
+        json!({
+            "objects": self.entries.len(),
+            "window": { "size": self.window_size, "capacity": self.window_capacity },
+            "main": { "size": self.main_size, "capacity": self.main_capacity },
+            "classes": classes,
+        })
+    }
+}
+
+pub struct MemoryCache {
+    config: CacheConfig,
+    /// shared by everything without a partition
+    store: Mutex<Store>,
+    partitions: HashMap<String, Mutex<Store>>,
+}
+
+/// Outcome of looking a request up in the cache.
+pub enum Lookup {
+    /// Answer the client with this response.
+    Hit(ResponseHeader, Bytes),
+    /// Proxy the request and fill the cache from the response.
+    Miss(CacheFill),
+    /// Ask the upstream whether the expired object is still current.
+    Revalidate(Revalidation),
+    /// Proxy the request without touching the cache.
+    Bypass,
+}
+
+/// How the cache took part in answering a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    /// Served past its expiry, the upstreams being unavailable.
+    Stale,
+    /// Expired, and asked the upstream about.
+    Revalidated,
+    Bypass,
+}
+
+impl CacheStatus {
+    /// The value of `X-Cache`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Revalidated => "REVALIDATED",
+            CacheStatus::Bypass => "BYPASS",
+        }
+    }
+}
+
+impl MemoryCache {
+    pub fn new(config: CacheConfig) -> Self {
+        MemoryCache {
+            store: Mutex::new(Store::new(config.capacity)),
+            partitions: HashMap::new(),
+            config,
+        }
+    }
+
+    /// Give `partition` its own `capacity` bytes, apart from the shared
+    /// store and from the other partitions.
+    pub fn with_partition(mut self, partition: impl Into<String>, capacity: usize) -> Self {
+        self.partitions
+            .insert(partition.into(), Mutex::new(Store::new(capacity)));
+        self
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    /// The store of `partition`, the shared one if it has none.
+    fn store(&self, partition: Option<&str>) -> &Mutex<Store> {
+        partition
+            .and_then(|p| self.partitions.get(p))
+            .unwrap_or(&self.store)
+    }
+
+    pub fn get(&self, partition: Option<&str>, key: &str) -> Option<Arc<CachedObject>> {
+        let mut store = self.store(partition).lock().unwrap();
+        let object = store.touch(key).filter(|o| o.is_fresh())?;
+        store.classes[size_class(object.body.len())].hits += 1;
+        Some(object)
+    }
+
+    /// The whole cached response for `req` on `route`, even if it expired up
+    /// to `max_stale` ago.
+    pub fn lookup_stale(
+        &self,
+        req: &RequestHeader,
+        route: Option<&Route>,
+        max_stale: Duration,
+    ) -> Result<Option<(ResponseHeader, Bytes)>> {
+        if req.method != Method::GET {
+            return Ok(None);
+        }
+        let store = self.store(route.map(Route::cache_partition));
+        let Some(object) = store.lock().unwrap().touch(&cache_key(req)) else {
+            return Ok(None);
+        };
+        if object.stored_at.elapsed() >= object.ttl + max_stale {
+            return Ok(None);
+        }
+        object.respond(None).map(Some)
+    }
+
+    /// Store `object`, fetched after a miss, if the admission policy lets it.
+    pub fn put(&self, partition: Option<&str>, key: String, object: CachedObject) {
+        let mut store = self.store(partition).lock().unwrap();
+        let class = &mut store.classes[size_class(object.body.len())];
+        class.misses += 1;
+        if object.body.len() > self.config.max_object_size {
+            class.rejected += 1;
+            return;
+        }
+        store.insert(key, object);
+    }
+
+    /// Objects stored, in every partition.
+    pub fn objects(&self) -> usize {
+        let partitions: usize = self
+            .partitions
+            .values()
+            .map(|store| store.lock().unwrap().entries.len())
+            .sum();
+        self.store.lock().unwrap().entries.len() + partitions
+    }
+
+    /// Drop every object of `partition`, `default` being the shared store;
+    /// how many there were, `None` if there is no such partition.
+    pub fn purge(&self, partition: &str) -> Option<usize> {
+        let store = match partition {
+            "default" => &self.store,
+            name => self.partitions.get(name)?,
+        };
+        Some(store.lock().unwrap().clear())
+    }
+
+    /// The shared store and the partitions, see [`Store::to_json`].
+    pub fn to_json(&self) -> Value {
+        let partitions: BTreeMap<&str, Value> = self
+            .partitions
+            .iter()
+            .map(|(name, store)| (name.as_str(), store.lock().unwrap().to_json()))
+            .collect();
+        let mut json = self.store.lock().unwrap().to_json();
+        json["partitions"] = json!(partitions);
+        json
+    }
+
+    /// Look up `req`, matched to `route`.
+    pub fn lookup(&self, req: &RequestHeader, route: Option<&Route>) -> Result<Lookup> {
+        let partition = route.map(Route::cache_partition);
+        match req.method {
+            Method::GET => {}
+            Method::HEAD => return self.lookup_head(req, partition),
+            Method::OPTIONS => return self.lookup_preflight(req, route),
+            _ => return Ok(Lookup::Bypass),
+        }
+        let key = cache_key(req);
+        let spec = req
+            .headers
+            .get(header::RANGE)
+            .and_then(|v| range::parse_range(v.as_bytes()));
+
+        if let Some(object) = self.get(partition, &key) {
+            let spec = spec.filter(|_| if_range_matches(req, &object));
+            let (header, body) = object.respond(spec)?;
+            return Ok(Lookup::Hit(header, body));
+        }
+
+        let Some(spec) = spec else {
+            if let Some(stale) = self.revalidatable(partition, &key) {
+                return Ok(Lookup::Revalidate(Revalidation {
+                    key,
+                    partition: partition.map(str::to_string),
+                    stale,
+                }));
+            }
+            return Ok(Lookup::Miss(CacheFill::new(partition, key, None)));
+        };
+        // only bounded ranges that stay inside one slice are sliced, anything
+        // else is passed through to the upstream untouched
+        let (Some(size), Some(want)) = (self.config.slice_size, spec.bounded()) else {
+            return Ok(Lookup::Bypass);
+        };
+        if size == 0 || want.start / size != want.end / size {
+            return Ok(Lookup::Bypass);
+        }
+        let slice_start = want.start / size * size;
+        let slice = ByteRange {
+            start: slice_start,
+            end: slice_start + size - 1,
+        };
+        let slice_key = format!("{key}|slice={}", want.start / size);
+        if let Some(object) = self.get(partition, &slice_key) {
+            let (header, body) = object.respond(Some(spec))?;
+            return Ok(Lookup::Hit(header, body));
+        }
+        Ok(Lookup::Miss(CacheFill::new(
+            partition,
+            slice_key,
+            Some(SliceFill {
+                full_key: key,
+                slice,
+                want,
+                offset: slice_start,
+                held: Bytes::new(),
+            }),
+        )))
+    }
+
+    /// The expired object of `key` if it can be revalidated.
+    fn revalidatable(&self, partition: Option<&str>, key: &str) -> Option<Arc<CachedObject>> {
+        let store = self.store(partition).lock().unwrap();
+        let object = &store.entries.get(key)?.object;
+        (object.header.status == StatusCode::OK && !object.conditionals().is_empty())
+            .then(|| object.clone())
+    }
+
+    /// The header of the cached `GET` for a `HEAD`; misses are proxied as
+    /// they are, a `HEAD` response has no body to store.
+    fn lookup_head(&self, req: &RequestHeader, partition: Option<&str>) -> Result<Lookup> {
+        let Some(object) = self.get(partition, &cache_key(req)) else {
+            return Ok(Lookup::Bypass);
+        };
+        let (header, _) = object.respond(None)?;
+        Ok(Lookup::Hit(header, Bytes::new()))
+    }
+
+    fn lookup_preflight(&self, req: &RequestHeader, route: Option<&Route>) -> Result<Lookup> {
+        if self.config.preflight_ttl.is_zero() {
+            return Ok(Lookup::Bypass);
+        }
+        let partition = route.map(Route::cache_partition);
+        let Some(key) = preflight_key(req, route.map_or("", |r| r.name.as_str())) else {
+            return Ok(Lookup::Bypass);
+        };
+        if let Some(object) = self.get(partition, &key) {
+            let (header, body) = object.respond(None)?;
+            return Ok(Lookup::Hit(header, body));
+        }
+        let mut fill = CacheFill::new(partition, key, None);
+        fill.preflight = true;
+        Ok(Lookup::Miss(fill))
+    }
+}
+
+/// Cache key of a CORS preflight on `route`, `None` if `req` is not one.
+fn preflight_key(req: &RequestHeader, route: &str) -> Option<String> {
+    let value = |name| {
+        req.headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+    };
+    let origin = value(header::ORIGIN)?;
+    let method = value(header::ACCESS_CONTROL_REQUEST_METHOD)?;
+    // the same headers asked for in another order get the same answer
+    let mut headers: Vec<String> = value(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .unwrap_or_default()
+        .split(',')
+        .map(|h| h.trim().to_ascii_lowercase())
+        .filter(|h| !h.is_empty())
+        .collect();
+    headers.sort();
+    let host = cache_key(req);
+    let host = host.split('/').next().unwrap_or_default();
+    Some(format!(
+        "preflight|{route}|{host}|{origin}|{method}|{}",
+        headers.join(",")
+    ))
+}
+
+/// How long a preflight response may be kept, from `Access-Control-Max-Age`
+/// up to `max`; `None` if it must not be stored.
+fn preflight_ttl(resp: &ResponseHeader, max: Duration) -> Option<Duration> {
+    if !resp.status.is_success() || resp.headers.contains_key(header::SET_COOKIE) {
+        return None;
+    }
+    let max_age: u64 = resp
+        .headers
+        .get(header::ACCESS_CONTROL_MAX_AGE)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    let ttl = Duration::from_secs(max_age).min(max);
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+/// Cache key of a request: host plus path and query.
+pub fn cache_key(req: &RequestHeader) -> String {
+    let host = req
+        .headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri.host())
+        .unwrap_or_default();
+    let path = req.uri.path_and_query().map_or("/", |p| p.as_str());
+    format!("{host}{path}")
+}
+
+/// `If-Range` only keeps the `Range` if it names the cached validator.
+fn if_range_matches(req: &RequestHeader, object: &CachedObject) -> bool {
+    let Some(if_range) = req.headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    [header::ETAG, header::LAST_MODIFIED]
+        .iter()
+        .any(|name| object.header.headers.get(name) == Some(if_range))
+}
+
+/// Freshness lifetime of a response, or `None` if it must not be stored.
+pub(crate) fn response_ttl(resp: &ResponseHeader, default_ttl: Duration) -> Option<Duration> {
+    if resp.headers.contains_key(header::SET_COOKIE) {
+        return None;
+    }
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for value in resp.headers.get_all(header::CACHE_CONTROL) {
+        let Ok(value) = value.to_str() else {
+            return None;
+        };
+        for directive in value.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+            match directive.split_once('=') {
+                Some(("max-age", secs)) => max_age = secs.trim_matches('"').parse().ok(),
+                Some(("s-maxage", secs)) => s_maxage = secs.trim_matches('"').parse().ok(),
+                None if matches!(directive.as_str(), "no-store" | "private" | "no-cache") => {
+                    return None;
+                }
+                _ => {}
+            }
+        }
+    }
+    let ttl = s_maxage
+        .or(max_age)
+        .map_or(default_ttl, Duration::from_secs);
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+/// An expired object being revalidated; the proxy sends the request itself,
+/// as a `304` carries no body to answer the client with.
+pub struct Revalidation {
+    key: String,
+    partition: Option<String>,
+    stale: Arc<CachedObject>,
+}
+
+impl Revalidation {
+    /// `req` made conditional on the stored object. The client's own
+    /// conditions are left out, they are answered by the stored object.
+    pub fn request(&self, req: &RequestHeader) -> Result<RequestHeader> {
+        let mut req = req.clone();
+        req.remove_header(&header::IF_NONE_MATCH);
+        req.remove_header(&header::IF_MODIFIED_SINCE);
+        for (name, value) in self.stale.conditionals() {
+            req.insert_header(name, value)?;
+        }
+        Ok(req)
+    }
+
+    /// Give up on revalidating, e.g. when the new object is too large to
+    /// buffer, and proxy the request as a miss.
+    pub fn into_fill(self) -> CacheFill {
+        CacheFill::new(self.partition.as_deref(), self.key, None)
+    }
+
+    /// The response to the client for the upstream's answer: the stored
+    /// object, refreshed, for a `304`, the answer itself otherwise, stored
+    /// in its place if it can be.
+    pub fn complete(
+        self,
+        cache: &MemoryCache,
+        fetched: Fetched,
+    ) -> Result<(ResponseHeader, Bytes)> {
+        let Fetched { header, body } = fetched;
+        if header.status == StatusCode::NOT_MODIFIED {
+            let header = self.stale.refreshed(&header)?;
+            let fresh = CachedObject {
+                ttl: response_ttl(&header, cache.config().default_ttl).unwrap_or_default(),
+                header,
+                body: self.stale.body.clone(),
+                stored_at: Instant::now(),
+            };
+            let response = fresh.respond(None)?;
+            let store = cache.store(self.partition.as_deref());
+            store.lock().unwrap().refresh(&self.key, fresh);
+            return Ok(response);
+        }
+        let object = CachedObject {
+            ttl: response_ttl(&header, cache.config().default_ttl).unwrap_or_default(),
+            header,
+            body,
+            stored_at: Instant::now(),
+        };
+        let response = object.respond(None)?;
+        if object.header.status == StatusCode::OK && !object.ttl.is_zero() {
+            cache.put(self.partition.as_deref(), self.key, object);
+        }
+        Ok(response)
+    }
+}
+
+/// Slice-specific state of a fill: which part of the object is fetched and
+/// which part of it the client asked for.
+struct SliceFill {
+    full_key: String,
+    slice: ByteRange,
+    want: ByteRange,
+    /// object offset of the next body byte
+    offset: u64,
+    /// last byte of `want`, released when the slice is complete
+    held: Bytes,
+}
+
+/// Per-request state of a response being written into the cache.
+pub struct CacheFill {
+    partition: Option<String>,
+    key: String,
+    slice: Option<SliceFill>,
+    header: Option<ResponseHeader>,
+    ttl: Duration,
+    body: BytesMut,
+    storable: bool,
+    /// whether the request is a CORS preflight
+    preflight: bool,
+}
+
+impl CacheFill {
+    fn new(partition: Option<&str>, key: String, slice: Option<SliceFill>) -> Self {
+        CacheFill {
+            partition: partition.map(str::to_string),
+            key,
+            slice,
+            header: None,
+            ttl: Duration::ZERO,
+            body: BytesMut::new(),
+            storable: false,
+            preflight: false,
+        }
+    }
+
+    /// Ask the upstream for the slice instead of the client's range.
+    pub fn upstream_request_filter(&self, upstream_request: &mut RequestHeader) -> Result<()> {
+        if let Some(slice) = &self.slice {
+            let value = format!("bytes={}-{}", slice.slice.start, slice.slice.end);
+            upstream_request.insert_header(header::RANGE, value)?;
+            upstream_request.remove_header(&header::IF_RANGE);
+        }
+        Ok(())
+    }
+
+    /// Decide whether the upstream response can be stored, and fix up the
+    /// header sent downstream when a slice was fetched on the client's behalf.
+    pub fn response_filter(
+        &mut self,
+        cache: &MemoryCache,
+        resp: &mut ResponseHeader,
+    ) -> Result<()> {
+        let upstream_header = resp.clone();
+        if self.preflight {
+            // browsers ignore preflight bodies, the header is all there is
+            if let Some(ttl) = preflight_ttl(&upstream_header, cache.config().preflight_ttl) {
+                let object = CachedObject {
+                    header: upstream_header,
+                    body: Bytes::new(),
+                    stored_at: Instant::now(),
+                    ttl,
+                };
+                cache.put(
+                    self.partition.as_deref(),
+                    std::mem::take(&mut self.key),
+                    object,
+                );
+            }
+            return Ok(());
+        }
+        match (self.slice.take(), resp.status) {
+            (None, StatusCode::OK) => {}
+            (None, _) => return Ok(()),
+            (Some(slice), StatusCode::OK) => {
+                // origin ignored the slice range and sent everything: store it
+                // as the full object, the client gets a valid 200
+                self.key = slice.full_key;
+            }
+            (Some(mut slice), StatusCode::PARTIAL_CONTENT) => {
+                let Some((got, Some(total))) = resp
+                    .headers
+                    .get(header::CONTENT_RANGE)
+                    .and_then(|v| range::parse_content_range(v.as_bytes()))
+                    .filter(|(got, _)| got.start == slice.slice.start)
+                else {
+                    // not the slice we asked for, relay it as is
+                    return Ok(());
+                };
+                resp.remove_header(&header::TRANSFER_ENCODING);
+                match slice.want.intersect(&got) {
+                    Some(want) => {
+                        slice.want = want;
+                        resp.insert_header(
+                            header::CONTENT_RANGE,
+                            range::content_range(want, total),
+                        )?;
+                        resp.insert_header(header::CONTENT_LENGTH, want.len().to_string())?;
+                    }
+                    None => {
+                        // the object ends before the requested range starts
+                        slice.want = ByteRange { start: 1, end: 0 };
+                        resp.set_status(StatusCode::RANGE_NOT_SATISFIABLE)?;
+                        resp.insert_header(header::CONTENT_RANGE, range::unsatisfied_range(total))?;
+                        resp.insert_header(header::CONTENT_LENGTH, "0")?;
+                    }
+                }
+                self.slice = Some(slice);
+            }
+            (Some(_), _) => return Ok(()),
+        }
+
+        let config = cache.config();
+        let Some(ttl) = response_ttl(&upstream_header, config.default_ttl) else {
+            return Ok(());
+        };
+        let too_large = upstream_header
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+            .is_some_and(|len| len > config.max_object_size);
+        if !too_large {
+            self.header = Some(upstream_header);
+            self.ttl = ttl;
+            self.storable = true;
+        }
+        Ok(())
+    }
+
+    /// Bytes the fill will buffer to store the response, the largest object
+    /// size when the upstream did not say; `None` if it is not stored.
+    pub fn buffer_size(&self, cache: &MemoryCache) -> Option<usize> {
+        let header = self.header.as_ref().filter(|_| self.storable)?;
+        let declared = header
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+        Some(declared.unwrap_or(cache.config().max_object_size))
+    }
+
+    /// Let the response through without storing it.
+    pub fn forgo(&mut self) {
+        self.storable = false;
+        self.header = None;
+    }
+
+    /// Collect the body for storage and, for slices, trim what the client sees
+    /// down to its requested range.
+    pub fn response_body_filter(
+        &mut self,
+        cache: &MemoryCache,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) {
+        if let Some(chunk) = body.as_ref() {
+            if self.storable {
+                self.body.extend_from_slice(chunk);
+                if self.body.len() > cache.config().max_object_size {
+                    self.storable = false;
+                    self.body = BytesMut::new();
+                }
+            }
+            if let Some(slice) = self.slice.as_mut().filter(|_| !chunk.is_empty()) {
+                let chunk_range = ByteRange {
+                    start: slice.offset,
+                    end: slice.offset + chunk.len() as u64 - 1,
+                };
+                slice.offset += chunk.len() as u64;
+                *body = chunk_range.intersect(&slice.want).map(|overlap| {
+                    let from = (overlap.start - chunk_range.start) as usize;
+                    let mut out = chunk.slice(from..from + overlap.len() as usize);
+                    // the downstream is done once its last byte is written,
+                    // which would abort the rest of the slice download
+                    if overlap.end == slice.want.end {
+                        slice.held = out.split_off(out.len() - 1);
+                    }
+                    out
+                });
+            }
+        }
+        if !end_of_stream {
+            return;
+        }
+        if let Some(slice) = self.slice.as_mut().filter(|s| !s.held.is_empty()) {
+            let held = std::mem::take(&mut slice.held);
+            *body = Some(match body.take() {
+                Some(chunk) if !chunk.is_empty() => [chunk, held].concat().into(),
+                _ => held,
+            });
+        }
+        if self.storable {
+            self.storable = false;
+            if let Some(header) = self.header.take() {
+                let object = CachedObject {
+                    header,
+                    body: std::mem::take(&mut self.body).freeze(),
+                    stored_at: Instant::now(),
+                    ttl: self.ttl,
+                };
+                cache.put(
+                    self.partition.as_deref(),
+                    std::mem::take(&mut self.key),
+                    object,
+                );
+            }
+        }
+    }
+}
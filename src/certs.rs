@@ -0,0 +1,200 @@
+//! Certificate expiry monitoring.
+//!
+//! A background service that checks the certificates the proxy serves, read
+//! from their PEM files, and the ones its TLS upstreams present, fetched with
+//! a handshake of its own. The days left on each are kept for the admin API,
+//! and every certificate closer to expiry than the threshold is logged and
+//! passed to the alert hook on each check.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use log::{info, warn};
+// pingora re-exports openssl without its time types
+use openssl::asn1::Asn1Time;
+use pingora::connectors::TransportConnector;
+use pingora::lb::{LoadBalancer, selection::RoundRobin};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::tls::x509::{X509, X509Ref};
+use pingora::upstreams::peer::HttpPeer;
+use pingora::{Error, ErrorType, OkOrErr, OrErr, Result};
+
+const CERT_ERROR: ErrorType = ErrorType::Custom("CertificateError");
+
+/// Where to find certificates to watch.
+#[derive(Clone)]
+pub enum CertSource {
+    /// A served certificate, the first one of a PEM file.
+    File { name: String, path: String },
+    /// The certificates presented by every upstream of a cluster.
+    Cluster {
+        name: String,
+        upstreams: Arc<LoadBalancer<RoundRobin>>,
+        sni: String,
+    },
+}
+
+/// The last check of one certificate.
+#[derive(Clone, Debug)]
+pub struct CertStatus {
+    /// the source name, with the upstream address for clusters
+    pub name: String,
+    pub subject: String,
+    /// negative once expired
+    pub days_left: i32,
+    pub checked: SystemTime,
+    /// why the certificate could not be checked
+    pub error: Option<String>,
+}
+
+pub type AlertHook = Box<dyn Fn(&CertStatus) + Send + Sync>;
+
+pub struct CertMonitor {
+    sources: Vec<CertSource>,
+    /// certificates with fewer days left are alerted on
+    threshold_days: i32,
+    interval: Duration,
+    alert: Option<AlertHook>,
+    connector: TransportConnector,
+    status: RwLock<Vec<CertStatus>>,
+}
+
+impl CertMonitor {
+    pub fn new(sources: Vec<CertSource>, threshold_days: i32, interval: Duration) -> Self {
+        CertMonitor {
+            sources,
+            threshold_days,
+            interval,
+            alert: None,
+            connector: TransportConnector::new(None),
+            status: RwLock::default(),
+        }
+    }
+
+    /// Call `alert` for every certificate found below the threshold.
+    pub fn with_alert(mut self, alert: AlertHook) -> Self {
+        self.alert = Some(alert);
+        self
+    }
+
+    pub fn threshold_days(&self) -> i32 {
+        self.threshold_days
+    }
+
+    /// The results of the last check.
+    pub fn status(&self) -> Vec<CertStatus> {
+        self.status.read().unwrap().clone()
+    }
+
+    async fn check(&self) {
+        let mut status = Vec::new();
+        for source in &self.sources {
+            match source {
+                CertSource::File { name, path } => {
+                    status.push(result(name.clone(), read_pem(path)));
+                }
+                CertSource::Cluster {
+                    name,
+                    upstreams,
+                    sni,
+                } => {
+                    for backend in upstreams.backends().get_backend().iter() {
+                        let mut peer = HttpPeer::new(backend.clone(), true, sni.clone());
+                        // an expired certificate is what this is looking for
+                        peer.options.verify_cert = false;
+                        peer.options.verify_hostname = false;
+                        let cert = self.fetch(&peer).await;
+                        status.push(result(format!("{name} {}", backend.addr), cert));
+                    }
+                }
+            }
+        }
+
+        for cert in &status {
+            match &cert.error {
+                Some(e) => warn!("checking certificate {}: {e}", cert.name),
+                None if cert.days_left < self.threshold_days => {
+                    warn!(
+                        "certificate {} ({}) expires in {} days",
+                        cert.name, cert.subject, cert.days_left
+                    );
+                    if let Some(alert) = &self.alert {
+                        alert(cert);
+                    }
+                }
+                None => {}
+            }
+        }
+        *self.status.write().unwrap() = status;
+    }
+
+    async fn fetch(&self, peer: &HttpPeer) -> Result<X509> {
+        let stream = self.connector.new_stream(peer).await?;
+        let ssl = stream
+            .get_ssl()
+            .or_err(CERT_ERROR, "upstream connection is not TLS")?;
+        ssl.peer_certificate()
+            .or_err(CERT_ERROR, "upstream presented no certificate")
+    }
+}
+
+fn read_pem(path: &str) -> Result<X509> {
+    let pem = std::fs::read(path).or_err_with(CERT_ERROR, || format!("reading {path}"))?;
+    X509::from_pem(&pem).or_err_with(CERT_ERROR, || format!("parsing {path}"))
+}
+
+fn result(name: String, cert: Result<X509>) -> CertStatus {
+    let checked = SystemTime::now();
+    match cert.and_then(|cert| days_left(&cert).map(|days| (cert, days))) {
+        Ok((cert, days_left)) => CertStatus {
+            name,
+            subject: subject(&cert),
+            days_left,
+            checked,
+            error: None,
+        },
+        Err(e) => CertStatus {
+            name,
+            subject: String::new(),
+            days_left: 0,
+            checked,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+fn days_left(cert: &X509Ref) -> Result<i32> {
+    let now = Asn1Time::days_from_now(0).or_err(CERT_ERROR, "reading the time")?;
+    match now.diff(cert.not_after()) {
+        Ok(diff) => Ok(diff.days),
+        Err(e) => Error::e_because(CERT_ERROR, "comparing expiry", e),
+    }
+}
+
+fn subject(cert: &X509Ref) -> String {
+    cert.subject_name()
+        .entries()
+        .filter_map(|e| {
+            let key = e.object().nid().short_name().ok()?;
+            let value = e.data().to_string().ok()?;
+            Some(format!("{key}={value}"))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[async_trait]
+impl BackgroundService for CertMonitor {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        info!("watching {} certificate sources", self.sources.len());
+        loop {
+            self.check().await;
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(self.interval) => {}
+            }
+        }
+    }
+}
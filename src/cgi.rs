@@ -0,0 +1,412 @@
+//! Application servers speaking CGI-derived protocols.
+//!
+//! A route with a [`CgiGateway`] sends its requests to its cluster, picked
+//! with the usual balancing and health checks, in the protocol of the
+//! application server instead of HTTP:
+//!
+//! - FastCGI, for PHP-FPM pools and the like.
+//! - uwsgi and SCGI, for Python applications behind uWSGI or an SCGI server.
+//!
+//! Each request becomes a set of CGI parameters and its body; the CGI
+//! response of the script (`Status:` and header lines, or an HTTP status
+//! line, then the body) is streamed back to the client as it comes. FastCGI
+//! scripts are looked up under `root`: the path up to its first `.php`
+//! segment names the script, the rest is `PATH_INFO`; with a front controller
+//! every request goes to that one script. uwsgi and SCGI applications are
+//! mounted at the root, the whole path is their `PATH_INFO`. Request bodies
+//! need a `Content-Length`.
+
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use http::{StatusCode, header};
+use pingora::connectors::TransportConnector;
+use pingora::http::ResponseHeader;
+use pingora::lb::Backend;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::proxy::Session;
+use pingora::upstreams::peer::BasicPeer;
+use pingora::{Error, ErrorType, OrErr, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::route::request_host;
+use crate::s3::percent_decode;
+
+mod fastcgi;
+mod scgi;
+mod uwsgi;
+
+/// Largest CGI header block accepted from a script.
+const MAX_HEAD: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    FastCgi,
+    Uwsgi,
+    Scgi,
+}
+
+pub struct CgiGateway {
+    protocol: Protocol,
+    root: String,
+    index: String,
+    front_controller: Option<String>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    keepalive: Option<Duration>,
+    connector: TransportConnector,
+}
+
+impl CgiGateway {
+    /// Scripts under `root` on the application servers, `/var/www/html`;
+    /// only FastCGI has scripts of its own.
+    pub fn new(protocol: Protocol, root: impl Into<String>) -> Self {
+        CgiGateway {
+            protocol,
+            root: root.into().trim_end_matches('/').to_string(),
+            index: "index.php".to_string(),
+            front_controller: None,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(60),
+            keepalive: Some(Duration::from_secs(60)),
+            connector: TransportConnector::new(None),
+        }
+    }
+
+    /// Script run for paths ending in `/`.
+    pub fn with_index(mut self, index: impl Into<String>) -> Self {
+        self.index = index.into();
+        self
+    }
+
+    /// Run every request through one script, `/index.php`, with the request
+    /// path as `PATH_INFO`.
+    pub fn with_front_controller(mut self, script: impl Into<String>) -> Self {
+        self.front_controller = Some(script.into());
+        self
+    }
+
+    pub fn with_timeouts(mut self, connect: Duration, read: Duration) -> Self {
+        self.connect_timeout = connect;
+        self.read_timeout = read;
+        self
+    }
+
+    /// How long idle FastCGI connections are kept for later requests; `None`
+    /// closes each after its request, as uwsgi and SCGI always do.
+    pub fn with_keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.keepalive = idle;
+        self
+    }
+
+    /// Run the request of `session` on `upstream`, answering the client with
+    /// the script's response; `fix` adjusts the response header before it is
+    /// written.
+    pub(crate) async fn handle(
+        &self,
+        session: &mut Session,
+        upstream: &Backend,
+        fix: impl Fn(&mut ResponseHeader) -> Result<()>,
+    ) -> Result<()> {
+        let req = session.req_header();
+        let content_length = match req
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .map(|v| v.to_str().ok().and_then(|v| v.parse::<u64>().ok()))
+        {
+            Some(Some(len)) => len,
+            Some(None) => {
+                return Error::e_explain(ErrorType::HTTPStatus(400), "invalid Content-Length");
+            }
+            None if req.headers.contains_key(header::TRANSFER_ENCODING) => {
+                return Error::e_explain(
+                    ErrorType::HTTPStatus(411),
+                    "request body needs a Content-Length",
+                );
+            }
+            None => 0,
+        };
+        let params = self.params(session, content_length)?;
+
+        let mut peer = match &upstream.addr {
+            SocketAddr::Inet(addr) => BasicPeer::new(&addr.to_string()),
+            SocketAddr::Unix(addr) => match addr.as_pathname() {
+                Some(path) => BasicPeer::new_uds(path)?,
+                None => return Error::e_explain(ErrorType::InvalidHTTPHeader, "unnamed socket"),
+            },
+        };
+        peer.options.connection_timeout = Some(self.connect_timeout);
+        let mut relay = Relay::new(session.req_header().method == http::Method::HEAD, fix);
+        match self.protocol {
+            Protocol::FastCgi => {
+                fastcgi::exchange(self, &peer, params, session, &mut relay).await?;
+            }
+            Protocol::Uwsgi => {
+                let head = uwsgi::request_head(&params)?;
+                self.exchange(&peer, &head, session, &mut relay).await?;
+            }
+            Protocol::Scgi => {
+                let head = scgi::request_head(&params);
+                self.exchange(&peer, &head, session, &mut relay).await?;
+            }
+        }
+        relay.finish(session).await
+    }
+
+    /// Send `head` and the request body on a new connection, then relay what
+    /// comes back until the server closes it.
+    async fn exchange<F: Fn(&mut ResponseHeader) -> Result<()>>(
+        &self,
+        peer: &BasicPeer,
+        head: &[u8],
+        session: &mut Session,
+        relay: &mut Relay<F>,
+    ) -> Result<()> {
+        const UPSTREAM_ERROR: ErrorType = ErrorType::HTTPStatus(502);
+        let (mut stream, _) = self.connector.get_stream(peer).await?;
+        stream
+            .write_all(head)
+            .await
+            .or_err(UPSTREAM_ERROR, "sending request head")?;
+        while let Some(chunk) = session.read_request_body().await? {
+            stream
+                .write_all(&chunk)
+                .await
+                .or_err(UPSTREAM_ERROR, "sending request body")?;
+        }
+        stream
+            .flush()
+            .await
+            .or_err(UPSTREAM_ERROR, "sending request body")?;
+
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = tokio::time::timeout(self.read_timeout, stream.read(&mut buf)).await;
+            let Ok(read) = read else {
+                return Error::e_explain(ErrorType::HTTPStatus(504), "response timed out");
+            };
+            match read.or_err(UPSTREAM_ERROR, "reading response")? {
+                0 => return Ok(()),
+                n => relay.output(session, &buf[..n]).await?,
+            }
+        }
+    }
+
+    /// The CGI parameters of the request, as FastCGI and its relatives send
+    /// them.
+    fn params(&self, session: &Session, content_length: u64) -> Result<Vec<(String, String)>> {
+        let req = session.req_header();
+        let path = percent_decode(req.uri.path());
+        if path.split('/').any(|segment| segment == "..") || path.contains('\0') {
+            return Error::e_explain(ErrorType::HTTPStatus(400), "path leaves the document root");
+        }
+        let (script_name, path_info) = match self.protocol {
+            Protocol::FastCgi => self.script(&path),
+            Protocol::Uwsgi | Protocol::Scgi => (String::new(), path.clone()),
+        };
+        let query = req.uri.query().unwrap_or_default();
+        let request_uri = req.uri.path_and_query().map_or("/", |p| p.as_str());
+
+        let mut params: Vec<(String, String)> = vec![
+            ("GATEWAY_INTERFACE".into(), "CGI/1.1".into()),
+            ("SERVER_SOFTWARE".into(), "proxy-rs".into()),
+            ("SERVER_PROTOCOL".into(), format!("{:?}", req.version)),
+            ("REQUEST_METHOD".into(), req.method.to_string()),
+            ("REQUEST_URI".into(), request_uri.into()),
+            ("DOCUMENT_URI".into(), path.clone()),
+            ("QUERY_STRING".into(), query.into()),
+            ("SCRIPT_NAME".into(), script_name.clone()),
+            ("PATH_INFO".into(), path_info),
+            (
+                "SERVER_NAME".into(),
+                request_host(req).unwrap_or_default().into(),
+            ),
+        ];
+        if self.protocol == Protocol::FastCgi {
+            params.push(("DOCUMENT_ROOT".into(), self.root.clone()));
+            params.push((
+                "SCRIPT_FILENAME".into(),
+                format!("{}{script_name}", self.root),
+            ));
+        }
+        if content_length > 0 {
+            params.push(("CONTENT_LENGTH".into(), content_length.to_string()));
+        }
+        if let Some(content_type) = req.headers.get(header::CONTENT_TYPE)
+            && let Ok(content_type) = content_type.to_str()
+        {
+            params.push(("CONTENT_TYPE".into(), content_type.into()));
+        }
+        if let Some(client) = session.client_addr().and_then(|a| a.as_inet()) {
+            params.push(("REMOTE_ADDR".into(), client.ip().to_canonical().to_string()));
+            params.push(("REMOTE_PORT".into(), client.port().to_string()));
+        }
+        if let Some(server) = session.server_addr().and_then(|a| a.as_inet()) {
+            params.push(("SERVER_ADDR".into(), server.ip().to_string()));
+            params.push(("SERVER_PORT".into(), server.port().to_string()));
+        }
+        if session.digest().is_some_and(|d| d.ssl_digest.is_some()) {
+            params.push(("HTTPS".into(), "on".into()));
+            params.push(("REQUEST_SCHEME".into(), "https".into()));
+        } else {
+            params.push(("REQUEST_SCHEME".into(), "http".into()));
+        }
+        for (name, value) in &req.headers {
+            // `Proxy` would become HTTP_PROXY, read by many scripts as their
+            // outgoing proxy ("httpoxy")
+            if name == header::CONTENT_TYPE || name == header::CONTENT_LENGTH || name == "proxy" {
+                continue;
+            }
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            let name = format!(
+                "HTTP_{}",
+                name.as_str().to_ascii_uppercase().replace('-', "_")
+            );
+            match params.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, joined)) => {
+                    joined.push_str(", ");
+                    joined.push_str(value);
+                }
+                None => params.push((name, value.into())),
+            }
+        }
+        Ok(params)
+    }
+
+    /// `SCRIPT_NAME` and `PATH_INFO` of the decoded request `path`.
+    fn script(&self, path: &str) -> (String, String) {
+        if let Some(script) = &self.front_controller {
+            return (script.clone(), path.to_string());
+        }
+        let mut end = 0;
+        while let Some(at) = path[end..].find(".php") {
+            end += at + 4;
+            if end == path.len() || path.as_bytes()[end] == b'/' {
+                return (path[..end].to_string(), path[end..].to_string());
+            }
+        }
+        if path.ends_with('/') {
+            (format!("{path}{}", self.index), String::new())
+        } else {
+            (path.to_string(), String::new())
+        }
+    }
+}
+
+/// Turns the CGI output of a script into the response to the client.
+pub(crate) struct Relay<F> {
+    head: BytesMut,
+    header_sent: bool,
+    head_request: bool,
+    fix: F,
+}
+
+impl<F: Fn(&mut ResponseHeader) -> Result<()>> Relay<F> {
+    fn new(head_request: bool, fix: F) -> Self {
+        Relay {
+            head: BytesMut::new(),
+            header_sent: false,
+            head_request,
+            fix,
+        }
+    }
+
+    /// Pass on more output of the script.
+    pub(crate) async fn output(&mut self, session: &mut Session, data: &[u8]) -> Result<()> {
+        if self.header_sent {
+            if !self.head_request && !data.is_empty() {
+                session
+                    .write_response_body(Some(Bytes::copy_from_slice(data)), false)
+                    .await?;
+            }
+            return Ok(());
+        }
+        self.head.extend_from_slice(data);
+        let end = find(&self.head, b"\r\n\r\n")
+            .map(|at| at + 4)
+            .or_else(|| find(&self.head, b"\n\n").map(|at| at + 2));
+        let Some(end) = end else {
+            if self.head.len() > MAX_HEAD {
+                return Error::e_explain(ErrorType::HTTPStatus(502), "script headers too large");
+            }
+            return Ok(());
+        };
+        let head = self.head.split_to(end);
+        let mut header = parse_head(&head)?;
+        (self.fix)(&mut header)?;
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        self.header_sent = true;
+        let body = std::mem::take(&mut self.head);
+        Box::pin(self.output(session, &body)).await
+    }
+
+    async fn finish(self, session: &mut Session) -> Result<()> {
+        if !self.header_sent {
+            return Error::e_explain(
+                ErrorType::HTTPStatus(502),
+                "script ended before its headers",
+            );
+        }
+        session.write_response_body(None, true).await
+    }
+}
+
+/// The response header of the CGI header block `head`.
+fn parse_head(head: &[u8]) -> Result<ResponseHeader> {
+    let text = std::str::from_utf8(head)
+        .or_else(|_| Error::e_explain(ErrorType::HTTPStatus(502), "script headers not UTF-8"))?;
+    let mut status = None;
+    let mut fields = Vec::new();
+    let mut lines = text.lines().peekable();
+    // uwsgi applications answer with a status line, as in HTTP
+    if let Some(status_line) = lines.next_if(|l| l.starts_with("HTTP/")) {
+        status = status_line
+            .split_ascii_whitespace()
+            .nth(1)
+            .and_then(|code| StatusCode::from_bytes(code.as_bytes()).ok());
+        if status.is_none() {
+            return Error::e_explain(ErrorType::HTTPStatus(502), "malformed status line");
+        }
+    }
+    for line in lines.filter(|l| !l.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            return Error::e_explain(ErrorType::HTTPStatus(502), "malformed script header");
+        };
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("status") {
+            status = value
+                .get(..3)
+                .and_then(|code| StatusCode::from_bytes(code.as_bytes()).ok());
+            continue;
+        }
+        // framing is the proxy's business
+        if ["connection", "keep-alive", "transfer-encoding"]
+            .iter()
+            .any(|hop| name.eq_ignore_ascii_case(hop))
+        {
+            continue;
+        }
+        fields.push((name.to_string(), value.to_string()));
+    }
+    let redirect = fields
+        .iter()
+        .any(|(n, _)| n.eq_ignore_ascii_case("location"));
+    let status = status.unwrap_or(if redirect {
+        StatusCode::FOUND
+    } else {
+        StatusCode::OK
+    });
+    let mut header = ResponseHeader::build(status, Some(fields.len()))?;
+    for (name, value) in fields {
+        header.append_header(name, value)?;
+    }
+    Ok(header)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
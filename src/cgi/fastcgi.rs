@@ -0,0 +1,163 @@
+//! The FastCGI record protocol, as a responder client.
+//!
+//! Connections are kept open between requests (`FCGI_KEEP_CONN`) and pooled;
+//! requests are not multiplexed over one connection, which PHP-FPM does not
+//! support.
+
+use log::warn;
+use pingora::proxy::Session;
+use pingora::upstreams::peer::{BasicPeer, Peer};
+use pingora::{Error, ErrorType, OrErr, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{CgiGateway, Relay};
+
+const VERSION: u8 = 1;
+
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+
+/// One request per connection at a time, so always the same id.
+const REQUEST_ID: u16 = 1;
+
+const MAX_CONTENT: usize = u16::MAX as usize;
+
+const UPSTREAM_ERROR: ErrorType = ErrorType::HTTPStatus(502);
+
+pub(super) async fn exchange<F>(
+    gateway: &CgiGateway,
+    peer: &BasicPeer,
+    params: Vec<(String, String)>,
+    session: &mut Session,
+    relay: &mut Relay<F>,
+) -> Result<()>
+where
+    F: Fn(&mut pingora::http::ResponseHeader) -> Result<()>,
+{
+    let (mut stream, _reused) = gateway.connector.get_stream(peer).await?;
+    let keep = gateway.keepalive.is_some();
+
+    let mut out = Vec::new();
+    let flags = if keep { KEEP_CONN } else { 0 };
+    let [role_hi, role_lo] = RESPONDER.to_be_bytes();
+    record(
+        &mut out,
+        BEGIN_REQUEST,
+        &[role_hi, role_lo, flags, 0, 0, 0, 0, 0],
+    );
+    let mut encoded = Vec::new();
+    for (name, value) in &params {
+        encode_length(&mut encoded, name.len());
+        encode_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    for chunk in encoded.chunks(MAX_CONTENT) {
+        record(&mut out, PARAMS, chunk);
+    }
+    record(&mut out, PARAMS, &[]);
+    stream
+        .write_all(&out)
+        .await
+        .or_err(UPSTREAM_ERROR, "sending FastCGI params")?;
+
+    while let Some(chunk) = session.read_request_body().await? {
+        for piece in chunk.chunks(MAX_CONTENT) {
+            out.clear();
+            record(&mut out, STDIN, piece);
+            stream
+                .write_all(&out)
+                .await
+                .or_err(UPSTREAM_ERROR, "sending FastCGI stdin")?;
+        }
+    }
+    out.clear();
+    record(&mut out, STDIN, &[]);
+    stream
+        .write_all(&out)
+        .await
+        .or_err(UPSTREAM_ERROR, "sending FastCGI stdin")?;
+    stream
+        .flush()
+        .await
+        .or_err(UPSTREAM_ERROR, "sending FastCGI stdin")?;
+
+    let protocol_status = loop {
+        let read = tokio::time::timeout(gateway.read_timeout, read_record(&mut stream)).await;
+        let Ok(read) = read else {
+            return Error::e_explain(ErrorType::HTTPStatus(504), "FastCGI response timed out");
+        };
+        let (kind, id, content) = read?;
+        if id != REQUEST_ID {
+            continue;
+        }
+        match kind {
+            STDOUT => relay.output(session, &content).await?,
+            STDERR => warn!(
+                "FastCGI stderr from {}: {}",
+                peer.address(),
+                String::from_utf8_lossy(&content).trim_end()
+            ),
+            END_REQUEST => break content.get(4).copied().unwrap_or(u8::MAX),
+            _ => {}
+        }
+    };
+    if protocol_status != 0 {
+        return Error::e_explain(
+            UPSTREAM_ERROR,
+            format!("FastCGI request refused, protocol status {protocol_status}"),
+        );
+    }
+    if keep {
+        gateway
+            .connector
+            .release_stream(stream, peer.reuse_hash(), gateway.keepalive);
+    }
+    Ok(())
+}
+
+fn record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let padding = (8 - content.len() % 8) % 8;
+    out.extend_from_slice(&[VERSION, kind]);
+    out.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[padding as u8, 0]);
+    out.extend_from_slice(content);
+    out.extend_from_slice(&[0; 8][..padding]);
+}
+
+fn encode_length(out: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// The type, request id and content of the next record.
+async fn read_record<S: AsyncReadExt + Unpin>(stream: &mut S) -> Result<(u8, u16, Vec<u8>)> {
+    let mut head = [0; 8];
+    stream
+        .read_exact(&mut head)
+        .await
+        .or_err(UPSTREAM_ERROR, "reading FastCGI record")?;
+    if head[0] != VERSION {
+        return Error::e_explain(UPSTREAM_ERROR, "not a FastCGI record");
+    }
+    let id = u16::from_be_bytes([head[2], head[3]]);
+    let len = u16::from_be_bytes([head[4], head[5]]) as usize;
+    let mut content = vec![0; len + head[6] as usize];
+    stream
+        .read_exact(&mut content)
+        .await
+        .or_err(UPSTREAM_ERROR, "reading FastCGI record")?;
+    content.truncate(len);
+    Ok((head[1], id, content))
+}
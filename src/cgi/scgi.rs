@@ -0,0 +1,31 @@
+//! The SCGI protocol: the CGI variables as a netstring, then the request body.
+//!
+//! The response is CGI output and ends when the server closes the connection.
+
+/// The netstring of a request with `params`; `CONTENT_LENGTH` has to come
+/// first, even for requests without a body.
+pub(super) fn request_head(params: &[(String, String)]) -> Vec<u8> {
+    let content_length = params
+        .iter()
+        .find(|(name, _)| name == "CONTENT_LENGTH")
+        .map_or("0", |(_, value)| value.as_str());
+    let mut headers = Vec::new();
+    for (name, value) in [("CONTENT_LENGTH", content_length), ("SCGI", "1")]
+        .into_iter()
+        .chain(
+            params
+                .iter()
+                .filter(|(name, _)| name != "CONTENT_LENGTH")
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        )
+    {
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    }
+    let mut head = format!("{}:", headers.len()).into_bytes();
+    head.extend_from_slice(&headers);
+    head.push(b',');
+    head
+}
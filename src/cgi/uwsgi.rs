@@ -0,0 +1,29 @@
+//! The uwsgi protocol: a packet of CGI variables, then the request body.
+//!
+//! Only the WSGI request modifier (0) is sent; the response is HTTP, a status
+//! line and headers, and ends when uWSGI closes the connection.
+
+use pingora::{Error, ErrorType, Result};
+
+/// The packet header and variables of a request with `params`.
+pub(super) fn request_head(params: &[(String, String)]) -> Result<Vec<u8>> {
+    let mut vars = Vec::new();
+    for (name, value) in params {
+        for field in [name, value] {
+            let Ok(len) = u16::try_from(field.len()) else {
+                return Error::e_explain(ErrorType::HTTPStatus(431), "uwsgi variable too long");
+            };
+            vars.extend_from_slice(&len.to_le_bytes());
+            vars.extend_from_slice(field.as_bytes());
+        }
+    }
+    let Ok(size) = u16::try_from(vars.len()) else {
+        return Error::e_explain(ErrorType::HTTPStatus(431), "uwsgi variables too large");
+    };
+    let mut head = Vec::with_capacity(4 + vars.len());
+    head.push(0);
+    head.extend_from_slice(&size.to_le_bytes());
+    head.push(0);
+    head.extend_from_slice(&vars);
+    Ok(head)
+}
@@ -0,0 +1,329 @@
+//! Circuit breakers on upstreams, shared between replicas.
+//!
+//! An upstream that fails `failures` requests in a row, by refusing the
+//! connection, breaking it, or answering `502`, `503` or `504`, is ejected:
+//! its circuit opens and it gets no requests for `open_for`. Then one request
+//! may try it again; a success closes the circuit, a failure opens it anew.
+//!
+//! Replicas in front of the same upstreams would each learn of a failure on
+//! their own, each sending their share of requests into it. With a
+//! [`CircuitSync`] the circuits a replica opens are sent to its peers over
+//! UDP, which open them for the time left, within a round trip instead of
+//! after `failures` requests of their own. Messages are signed with a key all
+//! replicas share, and ones older than [`MAX_MESSAGE_AGE`] are dropped, so
+//! nobody else can eject upstreams. Peers only ever open circuits; closing
+//! one is each replica's own decision.
+//!
+//! Requests only read a snapshot of the upstreams with a circuit, which the
+//! failures that open circuits replace; the lock of the circuits is taken to
+//! change them, by failures, and by the first success or try of an upstream
+//! that has one.
+
+use std::collections::HashMap;
+use std::net::SocketAddr as InetAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::{debug, info, warn};
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use ring::hmac;
+use serde_json::{Value, json};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+
+/// Messages sent longer ago are dropped, replays included.
+pub const MAX_MESSAGE_AGE: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitConfig {
+    /// Failures in a row that open a circuit.
+    pub failures: u32,
+    /// How long an open circuit keeps the upstream from requests.
+    pub open_for: Duration,
+}
+
+impl Default for CircuitConfig {
+    fn default() -> Self {
+        CircuitConfig {
+            failures: 5,
+            open_for: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Circuit {
+    failures: u32,
+    open_until: Option<Instant>,
+    /// The request let through to try the upstream again is out.
+    trying: bool,
+    /// The peer that opened it, `None` when this replica did.
+    opened_by: Option<InetAddr>,
+}
+
+/// The circuits of all upstreams, by address.
+pub struct CircuitBreakers {
+    config: CircuitConfig,
+    circuits: Mutex<HashMap<SocketAddr, Circuit>>,
+    /// until when the circuit of each upstream in `circuits` is open, read
+    /// without the lock
+    open: ArcSwap<HashMap<SocketAddr, Option<Instant>>>,
+    /// circuits opened here and not sent to the peers yet
+    outbox: Mutex<Vec<(InetAddr, Duration)>>,
+    opened: Notify,
+    opens: AtomicU64,
+    peer_opens: AtomicU64,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: CircuitConfig) -> Self {
+        CircuitBreakers {
+            config,
+            circuits: Mutex::default(),
+            open: ArcSwap::default(),
+            outbox: Mutex::default(),
+            opened: Notify::new(),
+            opens: AtomicU64::new(0),
+            peer_opens: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether `addr` may get a request: its circuit is closed, or due to be
+    /// tried again.
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        self.open_until(addr)
+            .is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn open_until(&self, addr: &SocketAddr) -> Option<Instant> {
+        self.open.load().get(addr).copied().flatten()
+    }
+
+    /// Replace the snapshot of the circuits with `circuits`.
+    fn publish(&self, circuits: &HashMap<SocketAddr, Circuit>) {
+        let open = circuits
+            .iter()
+            .map(|(addr, circuit)| (addr.clone(), circuit.open_until))
+            .collect();
+        self.open.store(Arc::new(open));
+    }
+
+    /// A request is sent to `addr`. If its circuit is due to be tried, this
+    /// is the try, and the circuit stays open for others until it is over,
+    /// or for another `open_for` should it never be.
+    pub fn sending(&self, addr: &SocketAddr) {
+        let now = Instant::now();
+        if self.open_until(addr).is_none_or(|until| now < until) {
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(addr)
+            && circuit.open_until.is_some_and(|until| now >= until)
+        {
+            circuit.open_until = Some(now + self.config.open_for);
+            circuit.trying = true;
+            self.publish(&circuits);
+        }
+    }
+
+    pub fn succeeded(&self, addr: &SocketAddr) {
+        if !self.open.load().contains_key(addr) {
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.remove(addr) {
+            self.publish(&circuits);
+            if circuit.open_until.is_some() {
+                info!("circuit of upstream {addr} closed");
+            }
+        }
+    }
+
+    pub fn failed(&self, addr: &SocketAddr) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let known = circuits.contains_key(addr);
+        let circuit = circuits.entry(addr.clone()).or_default();
+        circuit.failures += 1;
+        // a failed try opens it again right away
+        if circuit.failures < self.config.failures && !circuit.trying {
+            if !known {
+                self.publish(&circuits);
+            }
+            return;
+        }
+        warn!(
+            "upstream {addr} failed {} requests in a row, opening its circuit for {:?}",
+            circuit.failures, self.config.open_for
+        );
+        *circuit = Circuit {
+            failures: 0,
+            open_until: Some(Instant::now() + self.config.open_for),
+            trying: false,
+            opened_by: None,
+        };
+        self.publish(&circuits);
+        self.opens.fetch_add(1, Ordering::Relaxed);
+        if let Some(addr) = addr.as_inet() {
+            self.outbox
+                .lock()
+                .unwrap()
+                .push((*addr, self.config.open_for));
+            self.opened.notify_one();
+        }
+    }
+
+    /// Open the circuit of `addr` for `open_for`, as `peer` did.
+    fn open_from_peer(&self, addr: InetAddr, open_for: Duration, peer: InetAddr) {
+        let until = Instant::now() + open_for.min(self.config.open_for);
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(SocketAddr::Inet(addr)).or_default();
+        if circuit.open_until.is_some_and(|open| open >= until) {
+            return;
+        }
+        info!("circuit of upstream {addr} opened by replica {peer} for {open_for:?}");
+        *circuit = Circuit {
+            failures: 0,
+            open_until: Some(until),
+            trying: false,
+            opened_by: Some(peer),
+        };
+        self.publish(&circuits);
+        self.peer_opens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn take_outbox(&self) -> Vec<(InetAddr, Duration)> {
+        std::mem::take(&mut *self.outbox.lock().unwrap())
+    }
+
+    pub fn to_json(&self) -> Value {
+        let now = Instant::now();
+        let open: Vec<Value> = self
+            .circuits
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(addr, circuit)| {
+                let until = circuit.open_until?;
+                Some(json!({
+                    "upstream": addr.to_string(),
+                    "open_for_secs": until.saturating_duration_since(now).as_secs_f64(),
+                    "trying": circuit.trying,
+                    "opened_by": circuit.opened_by.map_or("local".to_string(), |p| p.to_string()),
+                }))
+            })
+            .collect();
+        json!({
+            "failures": self.config.failures,
+            "open_secs": self.config.open_for.as_secs(),
+            "opened": self.opens.load(Ordering::Relaxed),
+            "opened_by_peers": self.peer_opens.load(Ordering::Relaxed),
+            "open": open,
+        })
+    }
+}
+
+/// Sends the circuits opened here to the peer replicas, and opens those they
+/// send.
+pub struct CircuitSync {
+    breakers: Arc<CircuitBreakers>,
+    bind: InetAddr,
+    peers: Vec<InetAddr>,
+    key: hmac::Key,
+}
+
+impl CircuitSync {
+    /// Listen on `bind` for `peers`, which listen on the addresses given,
+    /// signing with `secret`.
+    pub fn new(
+        breakers: Arc<CircuitBreakers>,
+        bind: InetAddr,
+        peers: Vec<InetAddr>,
+        secret: &[u8],
+    ) -> Self {
+        CircuitSync {
+            breakers,
+            bind,
+            peers,
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    /// `open <upstream> <millis open> <unix millis sent> <signature>`
+    fn message(&self, addr: InetAddr, open_for: Duration) -> String {
+        let signed = format!("open {addr} {} {}", open_for.as_millis(), unix_millis());
+        let sig = hmac::sign(&self.key, signed.as_bytes());
+        format!("{signed} {}", STANDARD.encode(sig))
+    }
+
+    /// The upstream and how long to open its circuit, from a message that is
+    /// signed and recent.
+    fn parse(&self, message: &[u8]) -> Option<(InetAddr, Duration)> {
+        let message = std::str::from_utf8(message).ok()?;
+        let (signed, sig) = message.rsplit_once(' ')?;
+        let sig = STANDARD.decode(sig).ok()?;
+        hmac::verify(&self.key, signed.as_bytes(), &sig).ok()?;
+        let fields: Vec<&str> = signed.split(' ').collect();
+        let ["open", addr, open_for, sent] = fields[..] else {
+            return None;
+        };
+        let age = unix_millis().saturating_sub(sent.parse().ok()?);
+        if u128::from(age) > MAX_MESSAGE_AGE.as_millis() {
+            return None;
+        }
+        Some((
+            addr.parse().ok()?,
+            Duration::from_millis(open_for.parse().ok()?),
+        ))
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[async_trait]
+impl BackgroundService for CircuitSync {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let socket = match UdpSocket::bind(self.bind).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("circuit sync cannot listen on {}: {e}", self.bind);
+                return;
+            }
+        };
+        let mut buf = [0; 512];
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = self.breakers.opened.notified() => {
+                    for (addr, open_for) in self.breakers.take_outbox() {
+                        let message = self.message(addr, open_for);
+                        for peer in &self.peers {
+                            if let Err(e) = socket.send_to(message.as_bytes(), peer).await {
+                                debug!("sending circuit of {addr} to replica {peer}: {e}");
+                            }
+                        }
+                    }
+                }
+                received = socket.recv_from(&mut buf) => {
+                    let Ok((len, from)) = received else {
+                        continue;
+                    };
+                    match self.parse(&buf[..len]) {
+                        Some((addr, open_for)) => self.breakers.open_from_peer(addr, open_for, from),
+                        None => warn!("dropped a circuit message from {from} that is not signed or too old"),
+                    }
+                }
+            }
+        }
+    }
+}
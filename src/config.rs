@@ -0,0 +1,1535 @@
+//! Listeners, upstream pools and the routes that depend on the site from a
+//! configuration file.
+//!
+//! The file is YAML, or JSON, which is YAML too:
+//!
+//! ```yaml
+//! listeners:
+//!   - "[::]:6188"
+//!   - addr: 0.0.0.0:8080
+//!     http10:
+//!       default_host: www.example.com
+//!     h2:
+//!       h2c: true
+//!       ping_interval: 30
+//!       max_requests: 1000
+//!     keepalive:
+//!       max_requests: 10000
+//!       idle_timeout: 75
+//!       max_age: 900
+//!     diagnostics: [127.0.0.0/8, "::1", 10.0.0.0/8]
+//!   - addr: 0.0.0.0:443
+//!     sniff:
+//!       tls:
+//!         cert: /etc/proxy-rs/cert.pem
+//!         key: /etc/proxy-rs/key.pem
+//!       tcp_upstream: 10.0.0.9:22
+//! admin: 127.0.0.1:6190
+//! strict_hosts: [www.example.com, "*.example.org"]
+//! geo_rates:
+//!   - asn: 16509
+//!     multiplier: 4
+//!   - country: KP
+//!     multiplier: 10
+//! templates: /etc/proxy-rs/templates
+//! routes_snapshot: /var/lib/proxy-rs/routes.json
+//! readiness:
+//!   min_cached_objects: 100
+//!   min_upstream_connections: 8
+//! pools:
+//!   # the default upstreams, of requests no other pool's route matches
+//!   - name: default
+//!     sni: one.one.one.one
+//!     upstreams:
+//!       - 1.1.1.1:443
+//!       - addr: 1.0.0.1:443
+//!         weight: 2
+//!   - name: api
+//!     path_prefix: /api/
+//!     tls: false
+//!     host: api.internal
+//!     upstreams: [10.0.0.7:8080, "app-2.internal:8080"]
+//!     max_in_flight: 512
+//!   - name: php
+//!     tls: false
+//!     upstreams: [10.0.0.8:9000]
+//! routes:
+//!   - name: images
+//!     path_prefix: /images/
+//!     path_templates: ["/images/:size/*"]
+//!     image: {}
+//!     budget:
+//!       max_buffered: 67108864
+//!       max_heavy_per_sec: 200
+//!     no_upstream:
+//!       serve_stale: 86400
+//!     early_data: true
+//!     cache_bytes: 67108864
+//!   - name: user-content
+//!     path_prefix: /uploads/
+//!     content_type_guard: block
+//!   - name: assets
+//!     path_prefix: /assets/
+//!     hash_selection:
+//!       key: uri
+//!   - name: orders
+//!     path_prefix: /orders/
+//!     host: shop.example.com
+//!     pool: api
+//!     sticky:
+//!       ttl: 600
+//!       on_drain: repin
+//!     idempotency:
+//!       ttl: 86400
+//!     body_routing:
+//!       rules:
+//!         - json_field: /kind
+//!           value: refund
+//!           route: refunds
+//!   - name: refunds
+//!     path_prefix: /refunds/
+//!     pool: api
+//!   - name: blog
+//!     path_prefix: /blog/
+//!     pool: php
+//!     cgi:
+//!       protocol: fastcgi
+//!       root: /var/www/html
+//!       front_controller: /index.php
+//!   - name: files
+//!     path_prefix: /files/
+//!     s3:
+//!       bucket:
+//!         name: site-files
+//!         region: eu-west-1
+//!         addr: 52.218.0.1:443
+//!         host: s3.eu-west-1.amazonaws.com
+//! doh:
+//!   path: /dns-query
+//!   upstreams:
+//!     - https: 1.1.1.1:443
+//!       sni: cloudflare-dns.com
+//!     - do53: 1.0.0.1:53
+//! maintenance:
+//!   - route: images
+//!     cron: "0 3 * * SUN"
+//!     minutes: 30
+//!     timezone: Europe/Berlin
+//! egress:
+//!   ports: [80]
+//! egress_policy:
+//!   - network: 127.0.0.0/8
+//!     networks: [10.0.0.0/8, "fd00::/8"]
+//!     ports: [80]
+//!   - user: ci
+//!     domains: [github.com, "*.githubusercontent.com"]
+//!     ports: [443]
+//! forward:
+//!   addr: 0.0.0.0:3128
+//!   tls:
+//!     cert: /etc/proxy-rs/cert.pem
+//!     key: /etc/proxy-rs/key.pem
+//!   connect_timeout: 10
+//!   users:
+//!     - name: ci
+//!       password: hunter2
+//!       domains: [github.com, "*.githubusercontent.com"]
+//!       ports: [443]
+//!       bandwidth: 1048576
+//! synthetic:
+//!   - name: front page
+//!     path: /
+//!     host: www.example.com
+//! feature_flags:
+//!   stickiness: [{cookie: session}, {header: x-user-id}, client_ip]
+//!   flags:
+//!     - name: new-image-pipeline
+//!       rollout: 10
+//! ```
+//!
+//! A listener is an address, or an `addr` with settings of its own:
+//! `ipv6_only` sets `IPV6_V6ONLY`, and `[::]` listeners accept IPv4 clients
+//! too unless it is `true`. With `http10`, HTTP/1.0 requests are answered
+//! as HTTP/1.0 clients expect, those without a `Host` sent to its
+//! `default_host`, see [`crate::http10`]; without it they are passed on as
+//! they are. `h2` tunes HTTP/2: `h2c` accepts it without TLS, with prior
+//! knowledge, only when `true`; at most `max_concurrent_streams` streams,
+//! 100 without it, with the `initial_stream_window`,
+//! `initial_connection_window` and `max_frame_size` of the h2 stack unless
+//! given; idle clients are sent a PING every `ping_interval`, never without
+//! it, and dropped when they do not answer within `ping_timeout`, 20s; and
+//! connections get a GOAWAY after `max_requests`, no cap without it, see
+//! [`crate::h2_server`]. `keepalive` closes client connections after
+//! `max_requests`, once idle for `idle_timeout` or once `max_age` old, over
+//! HTTP/1 and HTTP/2; without them connections are reused for as long as
+//! clients keep them, HTTP/1 ones idle for at most 60s, see
+//! [`crate::keepalive`]. Responses to clients in the `diagnostics`
+//! networks, loopback ones without it, are annotated with how the proxy
+//! answered them, see [`crate::debug_headers`]. With `sniff`, the port
+//! also terminates TLS with the `cert` and `key` of its `tls`, and relays
+//! connections that are neither HTTP nor TLS to its `tcp_upstream`, those
+//! silent for its `timeout`, 1s, too, see [`crate::sniff`]; TLS and TCP
+//! connections are refused when it has no `tls` or `tcp_upstream`. The
+//! first listener is the one the proxy's own checks go through. Durations
+//! are in seconds.
+//!
+//! The admin API listens on `admin`, 127.0.0.1:6190 without it, see
+//! [`crate::admin`].
+//!
+//! With `strict_hosts`, only those hosts and the hosts routes name are
+//! served, others get a 421, see [`crate::strict_host`]; without it any
+//! `Host` is.
+//!
+//! With `--geo-db`, requests of clients in the `country` or of the `asn` of
+//! a `geo_rates` rule count as `multiplier` requests against rate limits,
+//! see [`crate::geo`]; the first rule a client matches applies. Without
+//! rules every client counts as one.
+//!
+//! Pages the proxy answers with itself are read from the `templates`
+//! directory where it has them, see [`crate::template`]; without it the
+//! built-in ones are used.
+//!
+//! The routes last known good are written to `routes_snapshot` as JSON, see
+//! [`crate::rollback`]; without it they are only kept in memory.
+//!
+//! `readiness` holds the proxy out of rotation after startup until the
+//! cache holds `min_cached_objects` and it opened `min_upstream_connections`,
+//! see [`crate::readiness`]; without them it is ready once the startup
+//! probe is done.
+//!
+//! The pool named `default` holds the default upstreams. Every other pool
+//! has its own cluster, and a route of its name for the requests under its
+//! `path_prefix` if it has one. Upstreams are `host:port` with a weight of 1, or an
+//! `addr` with a `weight`; names are resolved again on every discovery
+//! refresh, see [`crate::discovery::FileDiscovery`].
+//! A pool talks TLS with the `sni` unless `tls` is `false`, and sends its
+//! `host` as the `Host` of requests, the `sni` when it has none. A pool
+//! without either passes the client's `Host` on. An upstream of a pool with
+//! `max_in_flight` requests queued or in flight gets no more until some are
+//! done, see [`crate::in_flight`]; without it upstreams take any number.
+//!
+//! Each of the `routes` is a route of its `name` for the requests under its
+//! `path_prefix`, and of its `host` and matching its `path_pattern` where
+//! it has them, sent to the cluster of its `pool`, the default upstreams
+//! without one, see [`crate::route`]. Besides `tenant`, `redirect`,
+//! `path_templates`, `early_hints`, `early_data`, `h2c`,
+//! `blocked_fingerprints`, `max_anomaly_score` and the `content_type_guard`,
+//! `correct`, `block` or `log`, a route has the features it has a section
+//! of, the defaults of a feature for the settings the section leaves out:
+//!
+//! - `image`: the transforms of [`crate::image`], offering `avif` and `webp`
+//!   unless `false`, of originals up to `max_input_bytes` and
+//!   `max_input_dimension`, to at most `max_output_width` and
+//!   `max_output_height`, keeping results for `result_ttl` in
+//!   `result_cache_bytes`.
+//! - `budget`: `max_buffered` bytes and `max_heavy_per_sec`, see
+//!   [`crate::budget`].
+//! - `hash_selection`: upstreams picked by the `uri`, the default `key`, or
+//!   by a `header` or `cookie` of the name, on a ring of the
+//!   `hash_function`, `crc32`, and `layout`, `nginx`, or by another
+//!   `algorithm`, `ring`, `maglev` or `rendezvous`, see
+//!   [`crate::hash_select`].
+//! - `sticky`: the `cookie`, `ttl` and `on_drain` policy, `honor` or
+//!   `repin`, of [`crate::sticky`].
+//! - `fan_out`: reads from `replicas` nodes of a ring of the `hash_function`
+//!   and `layout`, of responses up to `max_body`, 1 MiB, see
+//!   [`crate::replica`].
+//! - `subsets`: of `size` upstreams per client, see [`crate::subset`].
+//! - `stream`: the `idle_timeout` and `sse_keepalive` of [`crate::stream`].
+//! - `signing`: with the `secret` of `key_id`, of bodies up to `max_body`,
+//!   see [`crate::signing`].
+//! - `idempotency`: responses kept for `ttl`, of bodies up to `max_body`,
+//!   at most `max_entries`, see [`crate::idempotency`].
+//! - `body_routing`: `rules` handing requests over to their `route` by a
+//!   `grpc_method`, a `json_field` pointer of a `value`, a `soap_action` or
+//!   an `xml_operation`, to the `fallback` when the body cannot be read, of
+//!   bodies up to `max_body`, see [`crate::body_route`].
+//! - `graphql`: `max_depth`, `max_complexity`, `max_body`, and per-second
+//!   `rate_limits` by operation with a `default_rate_limit` of the others,
+//!   see [`crate::graphql`].
+//! - `xml_guard`: `max_body`, `max_depth` and `max_attributes`, see
+//!   [`crate::xml`].
+//! - `upload`: to a `bucket` under `prefix`, in parts of `part_size`, at
+//!   most `max_size` and `max_files`, within `timeout`, see
+//!   [`crate::upload`].
+//! - `s3`: objects of a `bucket` under `prefix`, `index` for paths ending in
+//!   `/`, see [`crate::s3`].
+//! - `cgi`: the `protocol`, `fastcgi`, `uwsgi` or `scgi`, with scripts under
+//!   `root`, the `index` or `front_controller` script, `connect_timeout`,
+//!   `read_timeout` and `keepalive`, `false` for none, see [`crate::cgi`].
+//!
+//! Without a usable upstream a route answers with the `unavailable` page, or
+//! as its `no_upstream` says: from the cache for responses expired at most
+//! `serve_stale` ago, or from the cluster of the `fallback` pool, see
+//! [`crate::no_upstream`]. A route with `cache_bytes` has a cache partition
+//! of as many bytes, its tenant's when it has one, see [`crate::cache`].
+//!
+//! A bucket has a `name`, a `region`, the `addr` and `host` of its
+//! endpoint, `tls` unless `false`, and `path_style` addressing when `true`.
+//! Its `credentials` are read from the `environment` without any, come from
+//! the EC2 `instance_role`, the first one or the `instance_role` of a
+//! mapping, or are the `access_key_id`, `secret_access_key` and
+//! `session_token` of a mapping.
+//!
+//! With `doh`, a DoH gateway answers under its `path`, forwarding to its
+//! `upstreams` in order: DoH servers at an `https` address, asked for the
+//! `sni` at their own `path`, `/dns-query` without one, or nameservers at
+//! a `do53` address. Without it there is no DoH route.
+//!
+//! The maintenance page takes over the `route` of each `maintenance` entry
+//! for `minutes` from the times of its `cron`, read in its `timezone`, UTC
+//! without one, see [`crate::schedule`].
+//!
+//! With `egress`, as a sidecar, a route sends the requests of connections
+//! redirected to the proxy on to where they were headed, if that is in one
+//! of its `networks` on one of its `ports`, any when a list is left out, see
+//! [`crate::original_dst`]. Without it redirected connections are routed as
+//! any other.
+//!
+//! With `egress_policy`, redirected connections and requests of the forward
+//! proxy may only reach what one of its rules allows, see [`crate::egress`].
+//! A rule applies to the forward proxy `user`, or to the clients in its
+//! `network`, or to anyone with neither, and allows its `domains` and
+//! `networks` on its `ports`, any port when it has none. Without it they
+//! may reach anything.
+//!
+//! With `forward`, a forward proxy takes `CONNECT` tunnels on its `addr`,
+//! over TLS with the `cert` and `key` of its `tls`, see [`crate::forward`].
+//! Its `users` authenticate with their `name` and `password` and may reach
+//! their `domains` on their `ports`, any when a list is left out, at up to
+//! `bandwidth` bytes per second over all their tunnels, without a cap when
+//! it is left out. Destinations not answering within `connect_timeout`,
+//! 10s, are given up on. Without it there is no forward proxy.
+//!
+//! The `synthetic` checks are sent through the first listener every 30
+//! seconds, each a `GET` of its `path` with its `host` as the `Host`, the
+//! listener address without one, reported as hitting its `route`, `default`
+//! unless it names another, and passing on a 2xx or 3xx that contains its
+//! `expect_body` if it has one, see [`crate::synthetic`].
+//!
+//! With `feature_flags`, each of its `flags` is evaluated for every request
+//! and the upstream told the outcome, see [`crate::flags`]: a flag of its
+//! `name` is on for `rollout` percent of identities, all without one, and
+//! for none while `enabled` is `false`; with a `reroute` it sends requests
+//! of the route named `from` to the one named `to` while on. Identities are
+//! the first of the `stickiness` sources a request has, a `cookie` or
+//! `header` of the name or the `client_ip`, the client address without any.
+//! Without it, or `--unleash-url`, no flags are evaluated.
+//!
+//! Upstreams of the pools are read again with the file on `SIGHUP`, see
+//! [`crate::discovery::HangupReload`], and the routes are reloaded from it,
+//! those of pools and of the `routes`, `doh`, `egress` and `maintenance`
+//! sections, and rolled back if they answer with too many errors, see
+//! [`crate::rollback`]. Adding a pool, listeners and the other settings are
+//! only read at startup.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::debug_headers::DiagnosticHeaders;
+use crate::discovery::split_host_port;
+use crate::doh::DohUpstream;
+use crate::egress::{EgressRule, Source};
+use crate::family::Network;
+use crate::flags::{Flag, Stickiness};
+use crate::forward::{ForwardConfig, ForwardUser, UserPolicy};
+use crate::geo::GeoRule;
+use crate::h2_server::H2Settings;
+use crate::http10::Http10Compat;
+use crate::keepalive::Keepalive;
+use crate::listener::ListenerConfig;
+use crate::no_upstream::NoUpstream;
+use crate::readiness::ReadinessConfig;
+use crate::route::Route;
+use crate::schedule::Schedule;
+use crate::sniff::SniffConfig;
+use crate::synthetic::SyntheticCheck;
+use crate::transparent::DstMatch;
+
+mod route;
+
+/// Name of the pool of the default upstreams.
+pub const DEFAULT_POOL: &str = "default";
+
+/// Address of the admin API, of configs that name none.
+pub const DEFAULT_ADMIN: &str = "127.0.0.1:6190";
+
+/// Networks of the clients that get diagnostic headers, of listeners that
+/// name none.
+const DIAGNOSTICS_NETWORKS: [&str; 2] = ["127.0.0.0/8", "::1"];
+
+#[derive(Clone, Default)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    /// Address of the admin API; `None` for [`DEFAULT_ADMIN`].
+    pub admin: Option<Listen>,
+    /// Hosts served besides those of the routes; empty serves any.
+    pub strict_hosts: Vec<String>,
+    /// Rate limit multipliers by where clients come from, first match wins.
+    pub geo_rates: Vec<GeoRule>,
+    /// Directory of the page overrides; `None` for the built-in pages.
+    pub templates: Option<PathBuf>,
+    /// File the last known good routes are written to; `None` writes none.
+    pub routes_snapshot: Option<PathBuf>,
+    pub readiness: ReadinessConfig,
+    pub pools: Vec<Pool>,
+    pub routes: Vec<RouteConfig>,
+    pub doh: Option<Doh>,
+    pub maintenance: Vec<Maintenance>,
+    pub egress: Option<Egress>,
+    /// Rules of the egress policy; `None` allows everything.
+    pub egress_policy: Option<Vec<EgressRule>>,
+    pub forward: Option<Forward>,
+    pub synthetic: Vec<SyntheticCheck>,
+    /// `None` evaluates no feature flags.
+    pub feature_flags: Option<FeatureFlagsConfig>,
+}
+
+/// An address to accept connections on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listen {
+    pub addr: String,
+    /// `IPV6_V6ONLY`; `None` accepts IPv4 clients on `[::]` too.
+    pub ipv6_only: Option<bool>,
+}
+
+impl Listen {
+    /// `addr`, dual stack if it is the IPv6 unspecified address.
+    pub fn new(addr: impl Into<String>) -> Self {
+        let addr = addr.into();
+        let ipv6_only = addr.starts_with("[::]:").then_some(false);
+        Listen { addr, ipv6_only }
+    }
+}
+
+impl From<Listen> for ListenerConfig {
+    fn from(listen: Listen) -> Self {
+        ListenerConfig {
+            ipv6_only: listen.ipv6_only,
+            diagnostics: DiagnosticHeaders::new(DIAGNOSTICS_NETWORKS).ok(),
+            ..ListenerConfig::new(listen.addr)
+        }
+    }
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    /// `host:port`, with IPv6 literals in brackets.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        split_host_port(s).map_err(|_| format!("bad address {s}"))?;
+        Ok(Listen::new(s))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pool {
+    pub name: String,
+    /// Prefix of the pool's route; `None` for the default pool and pools
+    /// only routes of the `routes` section send to.
+    pub path_prefix: Option<String>,
+    pub peer: UpstreamPeer,
+    pub upstreams: Vec<Upstream>,
+    /// Requests each upstream may have queued or in flight; `None` for no
+    /// cap.
+    pub max_in_flight: Option<usize>,
+}
+
+/// A route of the `routes` section.
+#[derive(Clone)]
+pub struct RouteConfig {
+    /// The route, sending to the default upstreams.
+    pub route: Route,
+    /// Name of the pool whose cluster the route sends to instead.
+    pub pool: Option<String>,
+    /// The answer when no upstream of the cluster is usable.
+    pub no_upstream: NoUpstreamConfig,
+    /// Bytes of the cache partition of the route, see
+    /// [`Route::cache_partition`]; `None` caches in the shared store.
+    pub cache_bytes: Option<usize>,
+}
+
+/// The answer of a route of the `routes` section without a usable upstream.
+#[derive(Clone)]
+pub enum NoUpstreamConfig {
+    Answer(NoUpstream),
+    /// Send to the cluster of the pool of this name instead.
+    Fallback(String),
+}
+
+/// An upstream of a pool, resolved if it is a name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstream {
+    pub host: String,
+    pub port: u16,
+    pub weight: usize,
+}
+
+/// The DoH gateway of the site.
+#[derive(Clone, Debug)]
+pub struct Doh {
+    /// Prefix of the gateway's route.
+    pub path: String,
+    /// Resolvers to forward to, tried in order.
+    pub upstreams: Vec<DohUpstream>,
+}
+
+/// Windows the maintenance page answers for a route in.
+#[derive(Clone)]
+pub struct Maintenance {
+    /// Name of the route taken over.
+    pub route: String,
+    pub schedule: Arc<Schedule>,
+}
+
+/// The route of redirected connections.
+#[derive(Clone, Debug)]
+pub struct Egress {
+    /// Original destinations sent on to.
+    pub dst: DstMatch,
+}
+
+/// The forward proxy of the site.
+#[derive(Clone, Debug)]
+pub struct Forward {
+    pub listen: Listen,
+    pub config: ForwardConfig,
+}
+
+/// The flags evaluated for every request.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureFlagsConfig {
+    pub flags: Vec<Flag>,
+    /// Where identities come from, first found first; the client address
+    /// without any.
+    pub stickiness: Vec<Stickiness>,
+}
+
+/// How the upstreams of a cluster are spoken to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamPeer {
+    pub tls: bool,
+    /// Server name of the TLS handshake.
+    pub sni: String,
+    /// `Host` of requests sent upstream; `None` keeps the client's.
+    pub host: Option<String>,
+}
+
+impl UpstreamPeer {
+    /// TLS with `sni`, which requests are sent with as their `Host`.
+    pub fn tls(sni: impl Into<String>) -> Self {
+        let sni = sni.into();
+        UpstreamPeer {
+            tls: true,
+            host: Some(sni.clone()),
+            sni,
+        }
+    }
+
+    /// Plain HTTP, with `host` as the `Host` of requests if there is one.
+    pub fn plain(host: Option<String>) -> Self {
+        UpstreamPeer {
+            tls: false,
+            sni: String::new(),
+            host,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration in `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let invalid = |e: String| format!("config {}: {e}", path.display());
+        let content = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        Config::parse(&content).map_err(invalid)
+    }
+
+    /// Read the configuration in `content`, YAML as in a config file.
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let value: Value = serde_norway::from_str(content).map_err(|e| e.to_string())?;
+        Config::from_value(&value)
+    }
+
+    /// The pool named `name`.
+    pub fn pool(&self, name: &str) -> Option<&Pool> {
+        self.pools.iter().find(|p| p.name == name)
+    }
+
+    fn from_value(value: &Value) -> Result<Self, String> {
+        if !value.is_object() {
+            return Err("not a mapping".to_string());
+        }
+        let listeners = list(value, "listeners")?
+            .iter()
+            .enumerate()
+            .map(|(i, listen)| listener(listen).map_err(|e| format!("listener {}: {e}", i + 1)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let admin = string(value, "admin")?
+            .map(|addr| addr.parse().map_err(|e| format!("admin: {e}")))
+            .transpose()?;
+        let strict_hosts = list(value, "strict_hosts")?
+            .iter()
+            .map(|host| match host {
+                Value::String(host) => Ok(host.clone()),
+                _ => Err(format!("strict host {host} is not a string")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let geo_rates = list(value, "geo_rates")?
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| geo_rule(rule).map_err(|e| format!("geo rate {}: {e}", i + 1)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let templates = string(value, "templates")?.map(PathBuf::from);
+        let routes_snapshot = string(value, "routes_snapshot")?.map(PathBuf::from);
+        let readiness = &value["readiness"];
+        let readiness = ReadinessConfig {
+            min_cached_objects: count(readiness, "min_cached_objects")
+                .map_err(|e| format!("readiness: {e}"))?,
+            min_upstream_connections: count(readiness, "min_upstream_connections")
+                .map_err(|e| format!("readiness: {e}"))?,
+        };
+        let mut pools: Vec<Pool> = Vec::new();
+        for (i, pool) in list(value, "pools")?.iter().enumerate() {
+            let pool = upstream_pool(pool).map_err(|e| format!("pool {}: {e}", i + 1))?;
+            if pools.iter().any(|p| p.name == pool.name) {
+                return Err(format!("pool {} twice", pool.name));
+            }
+            pools.push(pool);
+        }
+        let routes = list(value, "routes")?
+            .iter()
+            .enumerate()
+            .map(|(i, r)| route::route(r).map_err(|e| format!("route {}: {e}", i + 1)))
+            .collect::<Result<Vec<_>, _>>()?;
+        for route in &routes {
+            let fallback = match &route.no_upstream {
+                NoUpstreamConfig::Fallback(pool) => Some(pool),
+                NoUpstreamConfig::Answer(_) => None,
+            };
+            for pool in route.pool.iter().chain(fallback) {
+                if pool != DEFAULT_POOL && !pools.iter().any(|p| &p.name == pool) {
+                    return Err(format!("route {}: unknown pool {pool}", route.route.name));
+                }
+            }
+        }
+        let doh = match &value["doh"] {
+            Value::Null => None,
+            doh => Some(doh_gateway(doh).map_err(|e| format!("doh: {e}"))?),
+        };
+        let maintenance = list(value, "maintenance")?
+            .iter()
+            .enumerate()
+            .map(|(i, window)| {
+                maintenance(window).map_err(|e| format!("maintenance {}: {e}", i + 1))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let egress = match &value["egress"] {
+            Value::Null => None,
+            egress => Some(egress_route(egress).map_err(|e| format!("egress: {e}"))?),
+        };
+        let egress_policy = match &value["egress_policy"] {
+            Value::Null => None,
+            _ => Some(
+                list(value, "egress_policy")?
+                    .iter()
+                    .enumerate()
+                    .map(|(i, rule)| {
+                        egress_rule(rule).map_err(|e| format!("egress rule {}: {e}", i + 1))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+        };
+        let forward = match &value["forward"] {
+            Value::Null => None,
+            forward => Some(forward_proxy(forward).map_err(|e| format!("forward: {e}"))?),
+        };
+        let synthetic = list(value, "synthetic")?
+            .iter()
+            .enumerate()
+            .map(|(i, check)| {
+                synthetic_check(check).map_err(|e| format!("synthetic check {}: {e}", i + 1))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let feature_flags = match &value["feature_flags"] {
+            Value::Null => None,
+            flags => Some(feature_flags(flags).map_err(|e| format!("feature flags: {e}"))?),
+        };
+        Ok(Config {
+            listeners,
+            admin,
+            strict_hosts,
+            geo_rates,
+            templates,
+            routes_snapshot,
+            readiness,
+            pools,
+            routes,
+            doh,
+            maintenance,
+            egress,
+            egress_policy,
+            forward,
+            synthetic,
+            feature_flags,
+        })
+    }
+}
+
+/// The list under `key`, empty without one.
+fn list<'a>(value: &'a Value, key: &str) -> Result<&'a [Value], String> {
+    match &value[key] {
+        Value::Null => Ok(&[]),
+        Value::Array(items) => Ok(items),
+        _ => Err(format!("{key} is not a list")),
+    }
+}
+
+fn string<'a>(value: &'a Value, key: &str) -> Result<Option<&'a str>, String> {
+    match &value[key] {
+        Value::Null => Ok(None),
+        Value::String(s) => Ok(Some(s)),
+        _ => Err(format!("{key} is not a string")),
+    }
+}
+
+fn boolean(value: &Value, key: &str) -> Result<Option<bool>, String> {
+    match &value[key] {
+        Value::Null => Ok(None),
+        Value::Bool(b) => Ok(Some(*b)),
+        _ => Err(format!("{key} is not true or false")),
+    }
+}
+
+fn count<T: TryFrom<u64>>(value: &Value, key: &str) -> Result<Option<T>, String> {
+    match &value[key] {
+        Value::Null => Ok(None),
+        n => n
+            .as_u64()
+            .and_then(|n| T::try_from(n).ok())
+            .map(Some)
+            .ok_or_else(|| format!("{key} is not a count")),
+    }
+}
+
+/// The duration under `key`, in seconds.
+fn seconds(value: &Value, key: &str) -> Result<Option<Duration>, String> {
+    match &value[key] {
+        Value::Null => Ok(None),
+        s => s
+            .as_f64()
+            .and_then(|s| Duration::try_from_secs_f64(s).ok())
+            .map(Some)
+            .ok_or_else(|| format!("{key} is not a number of seconds")),
+    }
+}
+
+fn listener(value: &Value) -> Result<ListenerConfig, String> {
+    let addr = match value {
+        Value::String(addr) => addr.as_str(),
+        _ => string(value, "addr")?.ok_or("without addr")?,
+    };
+    let mut listener = ListenerConfig::from(addr.parse::<Listen>()?);
+    if let Some(ipv6_only) = boolean(value, "ipv6_only")? {
+        listener.ipv6_only = Some(ipv6_only);
+    }
+    listener.http10 = match &value["http10"] {
+        Value::Null => None,
+        http10 => {
+            let host = string(http10, "default_host")
+                .map_err(|e| format!("http10: {e}"))?
+                .ok_or("http10 without default_host")?;
+            Some(Http10Compat::new(host))
+        }
+    };
+    listener.h2 = h2_settings(&value["h2"]).map_err(|e| format!("h2: {e}"))?;
+    let keepalive = &value["keepalive"];
+    let context = |e: String| format!("keepalive: {e}");
+    listener.keepalive = Keepalive {
+        max_requests: count(keepalive, "max_requests").map_err(context)?,
+        idle_timeout: seconds(keepalive, "idle_timeout").map_err(context)?,
+        max_age: seconds(keepalive, "max_age").map_err(context)?,
+    };
+    if let Value::Array(_) = &value["diagnostics"] {
+        let networks = strings(value, "diagnostics")?;
+        listener.diagnostics =
+            Some(DiagnosticHeaders::new(networks).map_err(|e| format!("diagnostics: {e}"))?);
+    }
+    listener.sniff = match &value["sniff"] {
+        Value::Null => None,
+        sniff => Some(sniffing(sniff).map_err(|e| format!("sniff: {e}"))?),
+    };
+    Ok(listener)
+}
+
+fn sniffing(value: &Value) -> Result<SniffConfig, String> {
+    let defaults = SniffConfig::default();
+    let tls = certificate(value)?;
+    let tcp_upstream = string(value, "tcp_upstream")?;
+    if let Some(upstream) = tcp_upstream {
+        upstream
+            .parse::<std::net::SocketAddr>()
+            .map_err(|_| format!("bad tcp_upstream {upstream}"))?;
+    }
+    Ok(SniffConfig {
+        tls,
+        tcp_upstream: tcp_upstream.map(str::to_string),
+        sniff_timeout: seconds(value, "timeout")?.unwrap_or(defaults.sniff_timeout),
+    })
+}
+
+/// The `cert` and `key` paths of the `tls` section, if there is one.
+fn certificate(value: &Value) -> Result<Option<(String, String)>, String> {
+    match &value["tls"] {
+        Value::Null => Ok(None),
+        tls => {
+            let cert = string(tls, "cert")?.ok_or("tls without cert")?;
+            let key = string(tls, "key")?.ok_or("tls without key")?;
+            Ok(Some((cert.to_string(), key.to_string())))
+        }
+    }
+}
+
+/// HTTP/2 settings, the defaults of [`H2Settings`] for those left out.
+fn h2_settings(value: &Value) -> Result<H2Settings, String> {
+    let defaults = H2Settings::default();
+    Ok(H2Settings {
+        h2c: boolean(value, "h2c")?.unwrap_or(defaults.h2c),
+        max_concurrent_streams: count(value, "max_concurrent_streams")?
+            .unwrap_or(defaults.max_concurrent_streams),
+        initial_stream_window: count(value, "initial_stream_window")?,
+        initial_connection_window: count(value, "initial_connection_window")?,
+        max_frame_size: count(value, "max_frame_size")?,
+        ping_interval: seconds(value, "ping_interval")?,
+        ping_timeout: seconds(value, "ping_timeout")?.unwrap_or(defaults.ping_timeout),
+        max_requests: count(value, "max_requests")?,
+    })
+}
+
+fn geo_rule(value: &Value) -> Result<GeoRule, String> {
+    let multiplier = value["multiplier"]
+        .as_f64()
+        .filter(|m| *m > 0.0)
+        .ok_or("without a positive multiplier")?;
+    match (string(value, "country")?, count(value, "asn")?) {
+        (Some(country), None) => Ok(GeoRule::country(country, multiplier)),
+        (None, Some(asn)) => Ok(GeoRule::asn(asn, multiplier)),
+        _ => Err("a rule is either of a country or of an asn".to_string()),
+    }
+}
+
+fn upstream_pool(value: &Value) -> Result<Pool, String> {
+    let name = string(value, "name")?.ok_or("without name")?;
+    let context = |e: String| format!("{name}: {e}");
+    let path_prefix = string(value, "path_prefix").map_err(context)?;
+    match (name, path_prefix) {
+        (DEFAULT_POOL, Some(_)) => {
+            return Err(context("the default pool has no path_prefix".to_string()));
+        }
+        (_, None) => {}
+        (_, Some(prefix)) if !prefix.starts_with('/') => {
+            return Err(context(format!(
+                "path_prefix {prefix} does not start with /"
+            )));
+        }
+        (_, Some(_)) => {}
+    }
+
+    let sni = string(value, "sni").map_err(context)?;
+    let host = string(value, "host").map_err(context)?.map(str::to_string);
+    let peer = match boolean(value, "tls").map_err(context)? {
+        Some(false) => UpstreamPeer::plain(host),
+        _ => {
+            let sni = sni.ok_or_else(|| context("TLS without sni".to_string()))?;
+            UpstreamPeer {
+                host: host.or_else(|| Some(sni.to_string())),
+                ..UpstreamPeer::tls(sni)
+            }
+        }
+    };
+
+    let upstreams = list(value, "upstreams")
+        .map_err(context)?
+        .iter()
+        .map(|upstream| {
+            let addr = match upstream {
+                Value::String(addr) => addr.as_str(),
+                _ => string(upstream, "addr")
+                    .map_err(context)?
+                    .ok_or_else(|| context("upstream without addr".to_string()))?,
+            };
+            let (host, port) =
+                split_host_port(addr).map_err(|_| context(format!("bad upstream {addr}")))?;
+            let weight = match &upstream["weight"] {
+                Value::Null => 1,
+                weight => weight
+                    .as_u64()
+                    .filter(|w| *w > 0)
+                    .and_then(|w| usize::try_from(w).ok())
+                    .ok_or_else(|| context(format!("bad weight {weight} of {addr}")))?,
+            };
+            Ok(Upstream { host, port, weight })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if upstreams.is_empty() {
+        return Err(context("no upstreams".to_string()));
+    }
+    let max_in_flight = match count(value, "max_in_flight").map_err(context)? {
+        Some(0) => return Err(context("max_in_flight of 0".to_string())),
+        cap => cap,
+    };
+    Ok(Pool {
+        name: name.to_string(),
+        path_prefix: path_prefix.map(str::to_string),
+        peer,
+        upstreams,
+        max_in_flight,
+    })
+}
+
+fn doh_gateway(value: &Value) -> Result<Doh, String> {
+    let path = string(value, "path")?.ok_or("without path")?;
+    if !path.starts_with('/') {
+        return Err(format!("path {path} does not start with /"));
+    }
+    let upstreams = list(value, "upstreams")?
+        .iter()
+        .map(|upstream| {
+            let addr = |addr: &str| addr.parse().map_err(|_| format!("bad address {addr}"));
+            match (string(upstream, "https")?, string(upstream, "do53")?) {
+                (Some(https), None) => Ok(DohUpstream::Https {
+                    addr: addr(https)?,
+                    sni: string(upstream, "sni")?
+                        .ok_or_else(|| format!("{https} without sni"))?
+                        .to_string(),
+                    path: string(upstream, "path")?
+                        .unwrap_or("/dns-query")
+                        .to_string(),
+                }),
+                (None, Some(do53)) => Ok(DohUpstream::Do53(addr(do53)?)),
+                _ => Err("an upstream is either https or do53".to_string()),
+            }
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if upstreams.is_empty() {
+        return Err("no upstreams".to_string());
+    }
+    Ok(Doh {
+        path: path.to_string(),
+        upstreams,
+    })
+}
+
+fn maintenance(value: &Value) -> Result<Maintenance, String> {
+    let route = string(value, "route")?.ok_or("without route")?;
+    let cron = string(value, "cron")?.ok_or("without cron")?;
+    let minutes: u64 = count(value, "minutes")?.ok_or("without minutes")?;
+    let tz = string(value, "timezone")?.unwrap_or("UTC");
+    let duration = Duration::from_secs(60 * minutes);
+    Ok(Maintenance {
+        route: route.to_string(),
+        schedule: Arc::new(Schedule::new(cron, duration, tz)?),
+    })
+}
+
+fn egress_route(value: &Value) -> Result<Egress, String> {
+    Ok(Egress {
+        dst: DstMatch::networks(strings(value, "networks")?)?.with_ports(ports(value)?),
+    })
+}
+
+fn egress_rule(value: &Value) -> Result<EgressRule, String> {
+    let source = match (string(value, "user")?, string(value, "network")?) {
+        (Some(user), None) => Source::User(user.to_string()),
+        (None, Some(network)) => Source::Network(Network::parse(network)?),
+        (None, None) => Source::Any,
+        (Some(_), Some(_)) => return Err("a rule is either of a user or of a network".to_string()),
+    };
+    Ok(EgressRule::new(source)
+        .with_domains(strings(value, "domains")?)
+        .with_networks(strings(value, "networks")?)?
+        .with_ports(ports(value)?))
+}
+
+fn forward_proxy(value: &Value) -> Result<Forward, String> {
+    let defaults = ForwardConfig::default();
+    let listen = string(value, "addr")?.ok_or("without addr")?.parse()?;
+    let mut users = HashMap::new();
+    for (i, user) in list(value, "users")?.iter().enumerate() {
+        let (name, user) = forward_user(user).map_err(|e| format!("user {}: {e}", i + 1))?;
+        if users.contains_key(&name) {
+            return Err(format!("user {name} twice"));
+        }
+        users.insert(name, user);
+    }
+    if users.is_empty() {
+        return Err("without users".to_string());
+    }
+    Ok(Forward {
+        listen,
+        config: ForwardConfig {
+            tls: certificate(value)?,
+            users,
+            connect_timeout: seconds(value, "connect_timeout")?.unwrap_or(defaults.connect_timeout),
+        },
+    })
+}
+
+fn forward_user(value: &Value) -> Result<(String, ForwardUser), String> {
+    let name = string(value, "name")?.ok_or("without name")?;
+    let password = string(value, "password")?.ok_or_else(|| format!("{name}: without password"))?;
+    let policy = UserPolicy {
+        allowed_domains: strings(value, "domains")
+            .map_err(|e| format!("{name}: {e}"))?
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        allowed_ports: ports(value).map_err(|e| format!("{name}: {e}"))?,
+        bandwidth: count(value, "bandwidth").map_err(|e| format!("{name}: {e}"))?,
+    };
+    let user = ForwardUser {
+        password: password.to_string(),
+        policy,
+    };
+    Ok((name.to_string(), user))
+}
+
+/// The strings of the list under `key`.
+fn strings<'a>(value: &'a Value, key: &str) -> Result<Vec<&'a str>, String> {
+    list(value, key)?
+        .iter()
+        .map(|s| s.as_str().ok_or(format!("{key}: {s} is not a string")))
+        .collect()
+}
+
+/// The ports of the list under `ports`.
+fn ports(value: &Value) -> Result<Vec<u16>, String> {
+    list(value, "ports")?
+        .iter()
+        .map(|port| {
+            port.as_u64()
+                .and_then(|p| u16::try_from(p).ok())
+                .ok_or(format!("bad port {port}"))
+        })
+        .collect()
+}
+
+fn synthetic_check(value: &Value) -> Result<SyntheticCheck, String> {
+    let name = string(value, "name")?.ok_or("without name")?;
+    let path = string(value, "path")?.ok_or("without path")?;
+    let route = string(value, "route")?.unwrap_or(DEFAULT_POOL);
+    let mut check = SyntheticCheck::get(name, route, path);
+    if let Some(host) = string(value, "host")? {
+        check = check.with_host(host);
+    }
+    if let Some(contains) = string(value, "expect_body")? {
+        check = check.expect_body(contains);
+    }
+    Ok(check)
+}
+
+fn feature_flags(value: &Value) -> Result<FeatureFlagsConfig, String> {
+    let mut flags: Vec<Flag> = Vec::new();
+    for (i, flag) in list(value, "flags")?.iter().enumerate() {
+        let flag = feature_flag(flag).map_err(|e| format!("flag {}: {e}", i + 1))?;
+        if flags.iter().any(|f| f.name == flag.name) {
+            return Err(format!("flag {} twice", flag.name));
+        }
+        flags.push(flag);
+    }
+    let stickiness = list(value, "stickiness")?
+        .iter()
+        .map(|source| match source {
+            Value::String(s) if s == "client_ip" => Ok(Stickiness::ClientIp),
+            _ => match (string(source, "cookie")?, string(source, "header")?) {
+                (Some(cookie), None) => Ok(Stickiness::Cookie(cookie.to_string())),
+                (None, Some(header)) => Ok(Stickiness::Header(header.to_string())),
+                _ => Err(format!(
+                    "stickiness {source} is neither client_ip, a cookie nor a header"
+                )),
+            },
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(FeatureFlagsConfig { flags, stickiness })
+}
+
+fn feature_flag(value: &Value) -> Result<Flag, String> {
+    let name = string(value, "name")?.ok_or("without name")?;
+    let rollout = match &value["rollout"] {
+        Value::Null => 100.0,
+        rollout => rollout
+            .as_f64()
+            .filter(|r| (0.0..=100.0).contains(r))
+            .ok_or_else(|| format!("{name}: rollout {rollout} is not a percentage"))?,
+    };
+    let mut flag = Flag::new(name, rollout);
+    if let Some(enabled) = boolean(value, "enabled")? {
+        flag.enabled = enabled;
+    }
+    match &value["reroute"] {
+        Value::Null => {}
+        reroute => {
+            let from =
+                string(reroute, "from")?.ok_or_else(|| format!("{name}: reroute without from"))?;
+            let to = string(reroute, "to")?.ok_or_else(|| format!("{name}: reroute without to"))?;
+            flag = flag.with_reroute(from, to);
+        }
+    }
+    Ok(flag)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pingora::http::RequestHeader;
+
+    use super::{Config, Listen, NoUpstreamConfig};
+    use crate::consistent_hash::{HashFunction, Layout};
+    use crate::flags::Stickiness;
+    use crate::hash_select::{Algorithm, HashKey};
+    use crate::no_upstream::NoUpstream;
+    use crate::sticky::DrainPolicy;
+
+    fn parse(yaml: &str) -> Result<Config, String> {
+        Config::from_value(&serde_norway::from_str(yaml).expect("YAML"))
+    }
+
+    const POOLS: &str = "
+pools:
+  - name: default
+    tls: false
+    upstreams: [127.0.0.1:8001]
+  - name: php
+    tls: false
+    upstreams: [127.0.0.1:9000]
+    max_in_flight: 32
+";
+
+    #[test]
+    fn routes_with_their_features() {
+        let config = parse(&format!(
+            "{POOLS}
+routes:
+  - name: orders
+    path_prefix: /orders/
+    host: shop.example.com
+    path_pattern: ^/orders/[0-9]+$
+    tenant: shop
+    early_hints: [\"</app.css>; rel=preload; as=style\"]
+    blocked_fingerprints: [t13d1516h2_8daaf6152771_02713d6af862]
+    max_anomaly_score: 40
+    sticky:
+      cookie: shop
+      ttl: 600
+      on_drain: repin
+    idempotency:
+      ttl: 86400
+      max_body: 65536
+    body_routing:
+      rules:
+        - json_field: /kind
+          value: refund
+          route: refunds
+      fallback: orders
+  - name: refunds
+    path_prefix: /refunds/
+    pool: php
+    h2c: true
+    stream:
+      idle_timeout: 30
+  - name: site
+    path_prefix: /
+    pool: default
+    cgi:
+      protocol: fastcgi
+      root: /var/www/html
+      front_controller: /index.php
+      keepalive: false
+    fan_out:
+      replicas: 2
+      hash_function: ketama
+      layout: ketama
+"
+        ))
+        .expect("config");
+        let [orders, refunds, site] = &config.routes[..] else {
+            panic!("{} routes", config.routes.len());
+        };
+
+        let route = &orders.route;
+        assert_eq!(orders.pool, None);
+        assert_eq!(route.path_prefix, "/orders/");
+        assert_eq!(route.host.as_deref(), Some("shop.example.com"));
+        assert_eq!(route.path_pattern.as_deref(), Some("^/orders/[0-9]+$"));
+        assert_eq!(route.tenant.as_deref(), Some("shop"));
+        assert_eq!(route.early_hints.len(), 1);
+        assert_eq!(route.blocked_fingerprints.len(), 1);
+        assert_eq!(route.max_anomaly_score, Some(40));
+        let sticky = route.sticky.as_ref().expect("sticky");
+        assert_eq!(sticky.cookie, "shop");
+        assert_eq!(sticky.ttl, Duration::from_secs(600));
+        assert_eq!(sticky.on_drain, DrainPolicy::Repin);
+        assert_eq!(
+            route.idempotency.as_ref().expect("idempotency").max_body(),
+            65536
+        );
+        let routing = route.body_routing.as_ref().expect("body routing");
+        let req = RequestHeader::build("POST", b"/orders/7", None).expect("request");
+        assert_eq!(
+            routing.route_for(&req, Some(br#"{"kind":"refund"}"#)),
+            Some("refunds")
+        );
+        assert_eq!(routing.route_for(&req, Some(b"not json")), Some("orders"));
+
+        assert_eq!(refunds.pool.as_deref(), Some("php"));
+        assert!(refunds.route.h2c);
+        let stream = refunds.route.stream.as_ref().expect("stream");
+        assert_eq!(stream.idle_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(stream.sse_keepalive, None);
+
+        // the default pool is the default upstreams
+        assert_eq!(site.pool, None);
+        assert!(site.route.cgi.is_some());
+        assert_eq!(
+            site.route.fan_out.as_ref().expect("fan-out").max_body(),
+            1024 * 1024
+        );
+        assert!(site.route.signing.is_none() && site.route.s3.is_none());
+    }
+
+    #[test]
+    fn routes_of_static_content() {
+        let config = parse(
+            "
+routes:
+  - name: images
+    path_prefix: /images/
+    path_templates: [\"/images/:size/*\"]
+    image:
+      avif: false
+      max_output_width: 2048
+    budget:
+      max_buffered: 67108864
+      max_heavy_per_sec: 200
+    no_upstream:
+      serve_stale: 86400
+    early_data: true
+    cache_bytes: 67108864
+  - name: user-content
+    path_prefix: /uploads/
+    content_type_guard: block
+    no_upstream:
+      fallback: default
+  - name: assets
+    path_prefix: /assets/
+    hash_selection:
+      cookie: session
+      hash_function: ketama
+      layout: ketama
+",
+        )
+        .expect("config");
+        let [images, user_content, assets] = &config.routes[..] else {
+            panic!("{} routes", config.routes.len());
+        };
+
+        let route = &images.route;
+        assert_eq!(route.path_templates, ["/images/:size/*"]);
+        let options = route.image.as_ref().expect("image").options();
+        assert!(!options.avif && options.webp);
+        assert_eq!(options.max_output_width, 2048);
+        assert_eq!(options.max_output_height, 4096);
+        let budget = route.budget.as_ref().expect("budget").to_json();
+        assert_eq!(budget["max_buffered"], 64 * 1024 * 1024);
+        assert_eq!(budget["max_heavy_per_sec"], 200);
+        assert!(route.early_data);
+        assert!(matches!(
+            images.no_upstream,
+            NoUpstreamConfig::Answer(NoUpstream::ServeStale { max_stale })
+                if max_stale == Duration::from_secs(86400)
+        ));
+        assert_eq!(images.cache_bytes, Some(64 * 1024 * 1024));
+
+        assert!(user_content.route.content_type_guard.is_some());
+        assert!(
+            matches!(&user_content.no_upstream, NoUpstreamConfig::Fallback(pool) if pool == "default")
+        );
+        assert_eq!(user_content.cache_bytes, None);
+
+        let hashing = assets
+            .route
+            .hash_selection
+            .as_ref()
+            .expect("hash selection");
+        assert_eq!(
+            hashing.key_source(),
+            &HashKey::Cookie("session".to_string())
+        );
+        assert_eq!(hashing.hasher(), HashFunction::Ketama);
+        assert_eq!(hashing.layout(), Layout::Ketama);
+        assert!(matches!(
+            assets.no_upstream,
+            NoUpstreamConfig::Answer(NoUpstream::Unavailable)
+        ));
+    }
+
+    #[test]
+    fn route_buckets() {
+        let config = parse(
+            "
+routes:
+  - name: files
+    path_prefix: /files/
+    s3:
+      prefix: public/
+      bucket:
+        name: assets
+        region: eu-west-1
+        addr: 127.0.0.1:9000
+        host: s3.local
+        tls: false
+        path_style: true
+        credentials:
+          access_key_id: AKIA
+          secret_access_key: secret
+  - name: uploads
+    path_prefix: /uploads/
+    upload:
+      max_size: 1048576
+      bucket:
+        name: uploads
+        region: eu-west-1
+        addr: 127.0.0.1:9000
+        host: s3.local
+        credentials: instance_role
+",
+        )
+        .expect("config");
+        let files = config.routes[0].route.s3.as_ref().expect("s3");
+        assert_eq!(files.bucket().object_host(), "s3.local");
+        assert!(!files.bucket().tls);
+        let uploads = config.routes[1].route.upload.as_ref().expect("upload");
+        assert_eq!(uploads.bucket().object_host(), "uploads.s3.local");
+        assert!(uploads.bucket().tls);
+    }
+
+    #[test]
+    fn feature_flags() {
+        let config = parse(
+            "
+feature_flags:
+  stickiness: [{cookie: session}, {header: x-user-id}, client_ip]
+  flags:
+    - name: New-Image-Pipeline
+      rollout: 10
+    - name: checkout-v2
+      enabled: false
+      reroute: {from: checkout, to: checkout-v2}
+",
+        )
+        .expect("config");
+        let flags = config.feature_flags.expect("feature flags");
+        assert_eq!(
+            flags.stickiness,
+            [
+                Stickiness::Cookie("session".to_string()),
+                Stickiness::Header("x-user-id".to_string()),
+                Stickiness::ClientIp,
+            ]
+        );
+        let [pipeline, checkout] = &flags.flags[..] else {
+            panic!("{} flags", flags.flags.len());
+        };
+        assert_eq!(pipeline.name, "new-image-pipeline");
+        assert_eq!(pipeline.rollout, 10.0);
+        assert!(pipeline.enabled && pipeline.reroute.is_none());
+        assert_eq!(checkout.rollout, 100.0);
+        assert!(!checkout.enabled);
+        assert_eq!(
+            checkout.reroute,
+            Some(("checkout".to_string(), "checkout-v2".to_string()))
+        );
+
+        assert!(parse("pools: []").expect("config").feature_flags.is_none());
+        for (flags, error) in [
+            ("flags: [{rollout: 5}]", "flag 1: without name"),
+            ("flags: [{name: a, rollout: 101}]", "a: rollout 101"),
+            ("flags: [{name: a}, {name: A}]", "flag a twice"),
+            ("stickiness: [session]", "stickiness \"session\""),
+        ] {
+            match parse(&format!("feature_flags:\n  {flags}\n")) {
+                Ok(_) => panic!("{flags} parsed"),
+                Err(e) => assert!(e.contains(error), "{flags}: {e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn forward_proxy() {
+        let config = parse(
+            "
+forward:
+  addr: \"[::]:3128\"
+  tls: {cert: /etc/proxy-rs/cert.pem, key: /etc/proxy-rs/key.pem}
+  users:
+    - name: ci
+      password: hunter2
+      domains: [github.com, \"*.githubusercontent.com\"]
+      ports: [443]
+      bandwidth: 1048576
+    - name: ops
+      password: swordfish
+",
+        )
+        .expect("config");
+        let forward = config.forward.expect("forward proxy");
+        assert_eq!(forward.listen, Listen::new("[::]:3128"));
+        assert_eq!(
+            forward.config.tls,
+            Some((
+                "/etc/proxy-rs/cert.pem".to_string(),
+                "/etc/proxy-rs/key.pem".to_string()
+            ))
+        );
+        assert_eq!(forward.config.connect_timeout, Duration::from_secs(10));
+        let ci = &forward.config.users["ci"];
+        assert_eq!(ci.password, "hunter2");
+        assert_eq!(
+            ci.policy.allowed_domains,
+            ["github.com", "*.githubusercontent.com"]
+        );
+        assert_eq!(ci.policy.allowed_ports, [443]);
+        assert_eq!(ci.policy.bandwidth, Some(1048576));
+        let ops = &forward.config.users["ops"].policy;
+        assert!(ops.allowed_domains.is_empty() && ops.allowed_ports.is_empty());
+        assert_eq!(ops.bandwidth, None);
+
+        assert!(parse("pools: []").expect("config").forward.is_none());
+        for (forward, error) in [
+            ("users: [{name: a, password: b}]", "forward: without addr"),
+            ("addr: 0.0.0.0:3128", "forward: without users"),
+            (
+                "addr: 0.0.0.0:3128\n  users: [{name: a}]",
+                "user 1: a: without password",
+            ),
+            (
+                "addr: 0.0.0.0:3128\n  users: [{name: a, password: b}, {name: a, password: c}]",
+                "user a twice",
+            ),
+            (
+                "addr: 0.0.0.0:3128\n  users: [{name: a, password: b, ports: [http]}]",
+                "a: bad port",
+            ),
+        ] {
+            match parse(&format!("forward:\n  {forward}\n")) {
+                Ok(_) => panic!("{forward} parsed"),
+                Err(e) => assert!(e.contains(error), "{forward}: {e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn hash_selection_algorithms() {
+        for algorithm in [Algorithm::Ring, Algorithm::Maglev, Algorithm::Rendezvous] {
+            let config = parse(&format!(
+                "routes:\n  - name: x\n    path_prefix: /\n    hash_selection: {{algorithm: {}}}\n",
+                algorithm.as_str()
+            ))
+            .expect("config");
+            let hashing = config.routes[0].route.hash_selection.as_ref();
+            assert_eq!(hashing.expect("hash selection").algorithm(), algorithm);
+        }
+        let config = parse("routes:\n  - name: x\n    path_prefix: /\n    hash_selection: {}\n");
+        let hashing = config.expect("config").routes[0]
+            .route
+            .hash_selection
+            .clone();
+        assert_eq!(
+            hashing.expect("hash selection").algorithm(),
+            Algorithm::Ring
+        );
+    }
+
+    #[test]
+    fn pools_without_a_prefix() {
+        let config = parse(POOLS).expect("config");
+        assert_eq!(config.pool("php").expect("pool").path_prefix, None);
+    }
+
+    #[test]
+    fn bad_routes() {
+        for (route, error) in [
+            ("path_prefix: /x/", "route 1: without name"),
+            ("name: x", "route 1: x: without path_prefix"),
+            ("name: x\n    path_prefix: x", "does not start with /"),
+            (
+                "name: x\n    path_prefix: /\n    pool: nope",
+                "unknown pool nope",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    sticky: {on_drain: never}",
+                "x: sticky: on_drain never",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    sticky: yes",
+                "sticky is not a mapping",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    fan_out: {replicas: 2, layout: ring}",
+                "unknown ring layout",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    no_upstream: {fallback: nope}",
+                "unknown pool nope",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    no_upstream: {serve_stale: 1, fallback: php}",
+                "no_upstream: either serve_stale or fallback",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    content_type_guard: sniff",
+                "content_type_guard sniff",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    hash_selection: {header: a, cookie: b}",
+                "hash_selection: requests are hashed by",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    hash_selection: {algorithm: jump}",
+                "unknown hashing algorithm jump",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    idempotency: {max_body: 10}",
+                "idempotency: without ttl",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    cgi: {protocol: fastcgi}",
+                "fastcgi without root",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    graphql: {rate_limits: {GetUser: 0}}",
+                "rate limit of GetUser",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    body_routing:\n      rules:\n        - {grpc_method: a.B/C, soap_action: c, route: y}",
+                "body_routing: rule 1: a rule matches one of",
+            ),
+            (
+                "name: x\n    path_prefix: /\n    s3: {bucket: {name: b, region: r, addr: s3.local, host: s3.local}}",
+                "bad addr s3.local",
+            ),
+        ] {
+            let yaml = format!("{POOLS}\nroutes:\n  - {route}\n");
+            match parse(&yaml) {
+                Ok(_) => panic!("{route} parsed"),
+                Err(e) => assert!(e.contains(error), "{route}: {e}"),
+            }
+        }
+    }
+}
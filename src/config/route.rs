@@ -0,0 +1,461 @@
+//! Routes of the `routes` section of the configuration file, see
+//! [`crate::config`].
+//!
+//! Each entry is a [`Route`] of the settings it names, every other field
+//! left as [`Route::new`] has it. Features are sections of their own, taken
+//! as a mapping of their settings; the defaults of the feature apply to
+//! those left out.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use super::{
+    DEFAULT_POOL, NoUpstreamConfig, RouteConfig, boolean, count, list, seconds, string, strings,
+};
+use crate::body_route::{BodyMatch, BodyRouting, BodyRule};
+use crate::budget::{Budget, RouteBudget};
+use crate::cgi::{CgiGateway, Protocol};
+use crate::content_sniff::{ContentTypeGuard, Mismatch};
+use crate::graphql::GraphQl;
+use crate::hash_select::{HashKey, HashSelection};
+use crate::idempotency::Idempotency;
+use crate::image::{ImageOptimizer, ImageOptions};
+use crate::no_upstream::NoUpstream;
+use crate::replica::FanOut;
+use crate::route::Route;
+use crate::s3::{Bucket, CredentialProvider, Credentials, S3Origin};
+use crate::signing::ResponseSigner;
+use crate::sticky::{DrainPolicy, StickySessions};
+use crate::stream::StreamConfig;
+use crate::subset::ClientSubsets;
+use crate::upload::UploadTarget;
+use crate::xml::XmlGuard;
+
+/// Largest response a fan-out route buffers, of those that name none.
+const FAN_OUT_MAX_BODY: usize = 1024 * 1024;
+
+pub(super) fn route(value: &Value) -> Result<RouteConfig, String> {
+    let name = string(value, "name")?.ok_or("without name")?;
+    let context = |e: String| format!("{name}: {e}");
+    settings(name, value).map_err(context)
+}
+
+fn settings(name: &str, value: &Value) -> Result<RouteConfig, String> {
+    let prefix = string(value, "path_prefix")?.ok_or("without path_prefix")?;
+    if !prefix.starts_with('/') {
+        return Err(format!("path_prefix {prefix} does not start with /"));
+    }
+    let mut route = Route::new(name, prefix);
+    route.host = string(value, "host")?.map(str::to_string);
+    route.path_pattern = string(value, "path_pattern")?.map(str::to_string);
+    route.tenant = string(value, "tenant")?.map(str::to_string);
+    route.redirect = string(value, "redirect")?.map(str::to_string);
+    route.path_templates = owned(strings(value, "path_templates")?);
+    route.early_hints = owned(strings(value, "early_hints")?);
+    route.early_data = boolean(value, "early_data")?.unwrap_or_default();
+    route.h2c = boolean(value, "h2c")?.unwrap_or_default();
+    route.blocked_fingerprints = owned(strings(value, "blocked_fingerprints")?);
+    route.max_anomaly_score = count(value, "max_anomaly_score")?;
+    route.image = section(value, "image", image)?.map(Arc::new);
+    route.budget = section(value, "budget", |v| {
+        Ok(Budget::new(RouteBudget {
+            max_buffered: count(v, "max_buffered")?,
+            max_heavy_per_sec: count(v, "max_heavy_per_sec")?,
+        }))
+    })?
+    .map(Arc::new);
+    route.content_type_guard = match string(value, "content_type_guard")? {
+        None => None,
+        Some(mismatch) => Some(Arc::new(ContentTypeGuard::new(match mismatch {
+            "correct" => Mismatch::Correct,
+            "block" => Mismatch::Block,
+            "log" => Mismatch::Log,
+            _ => {
+                return Err(format!(
+                    "content_type_guard {mismatch} is neither correct, block nor log"
+                ));
+            }
+        }))),
+    };
+    route.hash_selection = section(value, "hash_selection", hash_selection)?.map(Arc::new);
+    route.sticky = section(value, "sticky", sticky)?;
+    route.fan_out = section(value, "fan_out", fan_out)?.map(Arc::new);
+    route.subsets = section(value, "subsets", |v| {
+        let size = count(v, "size")?.filter(|n| *n > 0).ok_or("without size")?;
+        Ok(ClientSubsets::new(size))
+    })?
+    .map(Arc::new);
+    route.stream = section(value, "stream", |v| {
+        Ok(StreamConfig {
+            idle_timeout: seconds(v, "idle_timeout")?,
+            sse_keepalive: seconds(v, "sse_keepalive")?,
+        })
+    })?;
+    route.signing = section(value, "signing", signing)?.map(Arc::new);
+    route.idempotency = section(value, "idempotency", idempotency)?.map(Arc::new);
+    route.body_routing = section(value, "body_routing", body_routing)?.map(Arc::new);
+    route.graphql = section(value, "graphql", graphql)?.map(Arc::new);
+    route.xml_guard = section(value, "xml_guard", xml_guard)?.map(Arc::new);
+    route.upload = section(value, "upload", upload)?.map(Arc::new);
+    route.s3 = section(value, "s3", s3_origin)?.map(Arc::new);
+    route.cgi = section(value, "cgi", cgi)?.map(Arc::new);
+    let no_upstream = match &value["no_upstream"] {
+        Value::Null => NoUpstreamConfig::Answer(NoUpstream::Unavailable),
+        Value::String(answer) if answer == "unavailable" => {
+            NoUpstreamConfig::Answer(NoUpstream::Unavailable)
+        }
+        Value::Object(_) => {
+            no_upstream(&value["no_upstream"]).map_err(|e| format!("no_upstream: {e}"))?
+        }
+        other => {
+            return Err(format!(
+                "no_upstream {other} is neither unavailable nor a mapping"
+            ));
+        }
+    };
+    let pool = string(value, "pool")?
+        .filter(|pool| *pool != DEFAULT_POOL)
+        .map(str::to_string);
+    Ok(RouteConfig {
+        route,
+        pool,
+        no_upstream,
+        cache_bytes: count(value, "cache_bytes")?,
+    })
+}
+
+fn no_upstream(value: &Value) -> Result<NoUpstreamConfig, String> {
+    match (seconds(value, "serve_stale")?, string(value, "fallback")?) {
+        (Some(max_stale), None) => Ok(NoUpstreamConfig::Answer(NoUpstream::ServeStale {
+            max_stale,
+        })),
+        (None, Some(pool)) => Ok(NoUpstreamConfig::Fallback(pool.to_string())),
+        _ => Err("either serve_stale or fallback".to_string()),
+    }
+}
+
+fn image(value: &Value) -> Result<ImageOptimizer, String> {
+    let defaults = ImageOptions::default();
+    Ok(ImageOptimizer::new(ImageOptions {
+        avif: boolean(value, "avif")?.unwrap_or(defaults.avif),
+        webp: boolean(value, "webp")?.unwrap_or(defaults.webp),
+        max_input_bytes: count(value, "max_input_bytes")?.unwrap_or(defaults.max_input_bytes),
+        max_input_dimension: count(value, "max_input_dimension")?
+            .unwrap_or(defaults.max_input_dimension),
+        max_output_width: count(value, "max_output_width")?.unwrap_or(defaults.max_output_width),
+        max_output_height: count(value, "max_output_height")?.unwrap_or(defaults.max_output_height),
+        result_ttl: seconds(value, "result_ttl")?.unwrap_or(defaults.result_ttl),
+        result_cache_bytes: count(value, "result_cache_bytes")?
+            .unwrap_or(defaults.result_cache_bytes),
+    }))
+}
+
+fn hash_selection(value: &Value) -> Result<HashSelection, String> {
+    let key = match (
+        string(value, "key")?,
+        string(value, "header")?,
+        string(value, "cookie")?,
+    ) {
+        (None | Some("uri"), None, None) => HashKey::Uri,
+        (None, Some(header), None) => HashKey::Header(header.to_string()),
+        (None, None, Some(cookie)) => HashKey::Cookie(cookie.to_string()),
+        (Some(key), None, None) => return Err(format!("unknown key {key}")),
+        _ => return Err("requests are hashed by the uri, a header or a cookie".to_string()),
+    };
+    let mut selection = HashSelection::new(key);
+    if let Some(hasher) = string(value, "hash_function")? {
+        selection = selection.with_hasher(hasher.parse()?);
+    }
+    if let Some(layout) = string(value, "layout")? {
+        selection = selection.with_layout(layout.parse()?);
+    }
+    if let Some(algorithm) = string(value, "algorithm")? {
+        selection = selection.with_algorithm(algorithm.parse()?);
+    }
+    Ok(selection)
+}
+
+/// The setting under `key` as `read` makes it, `None` without one.
+fn section<T>(
+    value: &Value,
+    key: &str,
+    read: impl FnOnce(&Value) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    match &value[key] {
+        Value::Null => Ok(None),
+        Value::Object(_) => read(&value[key])
+            .map(Some)
+            .map_err(|e| format!("{key}: {e}")),
+        _ => Err(format!("{key} is not a mapping")),
+    }
+}
+
+fn owned(strings: Vec<&str>) -> Vec<String> {
+    strings.into_iter().map(str::to_string).collect()
+}
+
+fn sticky(value: &Value) -> Result<StickySessions, String> {
+    let defaults = StickySessions::default();
+    let on_drain = match string(value, "on_drain")? {
+        None | Some("honor") => DrainPolicy::HonorUntilExpiry,
+        Some("repin") => DrainPolicy::Repin,
+        Some(policy) => return Err(format!("on_drain {policy} is neither honor nor repin")),
+    };
+    Ok(StickySessions {
+        cookie: string(value, "cookie")?
+            .map(str::to_string)
+            .unwrap_or(defaults.cookie),
+        ttl: seconds(value, "ttl")?.unwrap_or(defaults.ttl),
+        on_drain,
+    })
+}
+
+fn fan_out(value: &Value) -> Result<FanOut, String> {
+    let replicas = count(value, "replicas")?
+        .filter(|n| *n > 0)
+        .ok_or("without replicas")?;
+    let max_body = count(value, "max_body")?.unwrap_or(FAN_OUT_MAX_BODY);
+    let mut fan_out = FanOut::new(replicas, max_body);
+    if let Some(hasher) = string(value, "hash_function")? {
+        fan_out = fan_out.with_hasher(hasher.parse()?);
+    }
+    if let Some(layout) = string(value, "layout")? {
+        fan_out = fan_out.with_layout(layout.parse()?);
+    }
+    Ok(fan_out)
+}
+
+fn signing(value: &Value) -> Result<ResponseSigner, String> {
+    let key_id = string(value, "key_id")?.ok_or("without key_id")?;
+    let secret = string(value, "secret")?.ok_or("without secret")?;
+    let mut signer = ResponseSigner::new(key_id, secret.as_bytes());
+    if let Some(max_body) = count(value, "max_body")? {
+        signer = signer.with_max_body(max_body);
+    }
+    Ok(signer)
+}
+
+fn idempotency(value: &Value) -> Result<Idempotency, String> {
+    let mut idempotency = Idempotency::new(seconds(value, "ttl")?.ok_or("without ttl")?);
+    if let Some(max_body) = count(value, "max_body")? {
+        idempotency = idempotency.with_max_body(max_body);
+    }
+    if let Some(max_entries) = count(value, "max_entries")? {
+        idempotency = idempotency.with_max_entries(max_entries);
+    }
+    Ok(idempotency)
+}
+
+fn body_routing(value: &Value) -> Result<BodyRouting, String> {
+    let rules = list(value, "rules")?
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| body_rule(rule).map_err(|e| format!("rule {}: {e}", i + 1)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut routing = BodyRouting::new(rules);
+    if let Some(fallback) = string(value, "fallback")? {
+        routing = routing.with_fallback(fallback);
+    }
+    if let Some(max_body) = count(value, "max_body")? {
+        routing = routing.with_max_body(max_body);
+    }
+    Ok(routing)
+}
+
+fn body_rule(value: &Value) -> Result<BodyRule, String> {
+    let route = string(value, "route")?.ok_or("without route")?;
+    let matchers = [
+        string(value, "grpc_method")?.map(|m| BodyMatch::GrpcMethod(m.to_string())),
+        string(value, "json_field")?
+            .map(|pointer| {
+                let value = string(value, "value")?.ok_or("json_field without value")?;
+                Ok::<_, String>(BodyMatch::JsonField {
+                    pointer: pointer.to_string(),
+                    value: value.to_string(),
+                })
+            })
+            .transpose()?,
+        string(value, "soap_action")?.map(|a| BodyMatch::SoapAction(a.to_string())),
+        string(value, "xml_operation")?.map(|o| BodyMatch::XmlOperation(o.to_string())),
+    ];
+    let mut matchers = matchers.into_iter().flatten();
+    match (matchers.next(), matchers.next()) {
+        (Some(matcher), None) => Ok(BodyRule {
+            matcher,
+            route: route.to_string(),
+        }),
+        _ => Err(
+            "a rule matches one of grpc_method, json_field, soap_action or xml_operation"
+                .to_string(),
+        ),
+    }
+}
+
+fn graphql(value: &Value) -> Result<GraphQl, String> {
+    let mut graphql = GraphQl::new();
+    if let Some(max_depth) = count(value, "max_depth")? {
+        graphql = graphql.with_max_depth(max_depth);
+    }
+    if let Some(max_complexity) = count(value, "max_complexity")? {
+        graphql = graphql.with_max_complexity(max_complexity);
+    }
+    if let Some(max_body) = count(value, "max_body")? {
+        graphql = graphql.with_max_body(max_body);
+    }
+    match &value["rate_limits"] {
+        Value::Null => {}
+        Value::Object(limits) => {
+            for (operation, limit) in limits {
+                graphql = graphql.with_rate_limit(operation, rate(limit, operation)?);
+            }
+        }
+        _ => return Err("rate_limits is not a mapping".to_string()),
+    }
+    match &value["default_rate_limit"] {
+        Value::Null => {}
+        limit => graphql = graphql.with_default_rate_limit(rate(limit, "default_rate_limit")?),
+    }
+    Ok(graphql)
+}
+
+/// A positive number of requests per second.
+fn rate(value: &Value, what: &str) -> Result<f64, String> {
+    value
+        .as_f64()
+        .filter(|r| *r > 0.0)
+        .ok_or_else(|| format!("rate limit of {what} is not a positive number"))
+}
+
+fn xml_guard(value: &Value) -> Result<XmlGuard, String> {
+    let mut guard = XmlGuard::new();
+    if let Some(max_body) = count(value, "max_body")? {
+        guard = guard.with_max_body(max_body);
+    }
+    if let Some(max_depth) = count(value, "max_depth")? {
+        guard = guard.with_max_depth(max_depth);
+    }
+    if let Some(max_attributes) = count(value, "max_attributes")? {
+        guard = guard.with_max_attributes(max_attributes);
+    }
+    Ok(guard)
+}
+
+fn upload(value: &Value) -> Result<UploadTarget, String> {
+    let bucket = section(value, "bucket", bucket)?.ok_or("without bucket")?;
+    let mut target = UploadTarget::new(bucket);
+    if let Some(prefix) = string(value, "prefix")? {
+        target = target.with_prefix(prefix);
+    }
+    if let Some(part_size) = count(value, "part_size")? {
+        target = target.with_part_size(part_size);
+    }
+    if let Some(max_size) = count(value, "max_size")? {
+        target = target.with_max_size(max_size);
+    }
+    if let Some(max_files) = count(value, "max_files")? {
+        target = target.with_max_files(max_files);
+    }
+    if let Some(timeout) = seconds(value, "timeout")? {
+        target = target.with_timeout(timeout);
+    }
+    Ok(target)
+}
+
+fn s3_origin(value: &Value) -> Result<S3Origin, String> {
+    let bucket = section(value, "bucket", bucket)?.ok_or("without bucket")?;
+    let mut origin = S3Origin::new(bucket);
+    if let Some(prefix) = string(value, "prefix")? {
+        origin = origin.with_prefix(prefix);
+    }
+    if let Some(index) = string(value, "index")? {
+        origin = origin.with_index(index);
+    }
+    Ok(origin)
+}
+
+fn bucket(value: &Value) -> Result<Bucket, String> {
+    let name = string(value, "name")?.ok_or("without name")?;
+    let region = string(value, "region")?.ok_or("without region")?;
+    let addr = string(value, "addr")?.ok_or("without addr")?;
+    let host = string(value, "host")?.ok_or("without host")?;
+    let credentials = match &value["credentials"] {
+        Value::Null => CredentialProvider::environment(),
+        Value::String(source) if source == "environment" => CredentialProvider::environment(),
+        Value::String(source) if source == "instance_role" => {
+            CredentialProvider::instance_role(None)
+        }
+        Value::Object(_) => {
+            credentials(&value["credentials"]).map_err(|e| format!("credentials: {e}"))?
+        }
+        other => {
+            return Err(format!(
+                "credentials {other} are neither environment, instance_role nor a mapping"
+            ));
+        }
+    };
+    Ok(Bucket {
+        name: name.to_string(),
+        region: region.to_string(),
+        addr: addr.parse().map_err(|_| format!("bad addr {addr}"))?,
+        host: host.to_string(),
+        tls: boolean(value, "tls")?.unwrap_or(true),
+        path_style: boolean(value, "path_style")?.unwrap_or_default(),
+        credentials,
+    })
+}
+
+fn credentials(value: &Value) -> Result<Arc<CredentialProvider>, String> {
+    if let Some(role) = string(value, "instance_role")? {
+        return Ok(CredentialProvider::instance_role(Some(role.to_string())));
+    }
+    let access_key_id = string(value, "access_key_id")?.ok_or("without access_key_id")?;
+    let secret_access_key =
+        string(value, "secret_access_key")?.ok_or("without secret_access_key")?;
+    Ok(CredentialProvider::fixed(Credentials {
+        access_key_id: access_key_id.to_string(),
+        secret_access_key: secret_access_key.to_string(),
+        session_token: string(value, "session_token")?.map(str::to_string),
+    }))
+}
+
+fn cgi(value: &Value) -> Result<CgiGateway, String> {
+    let protocol = match string(value, "protocol")?.ok_or("without protocol")? {
+        "fastcgi" => Protocol::FastCgi,
+        "uwsgi" => Protocol::Uwsgi,
+        "scgi" => Protocol::Scgi,
+        protocol => {
+            return Err(format!(
+                "protocol {protocol} is neither fastcgi, uwsgi nor scgi"
+            ));
+        }
+    };
+    // uwsgi and SCGI applications have no scripts to look up
+    let root = match (protocol, string(value, "root")?) {
+        (_, Some(root)) => root,
+        (Protocol::FastCgi, None) => return Err("fastcgi without root".to_string()),
+        (_, None) => "/",
+    };
+    let mut gateway = CgiGateway::new(protocol, root);
+    if let Some(index) = string(value, "index")? {
+        gateway = gateway.with_index(index);
+    }
+    if let Some(script) = string(value, "front_controller")? {
+        gateway = gateway.with_front_controller(script);
+    }
+    let connect = seconds(value, "connect_timeout")?;
+    let read = seconds(value, "read_timeout")?;
+    if connect.is_some() || read.is_some() {
+        gateway = gateway.with_timeouts(
+            connect.unwrap_or(Duration::from_secs(5)),
+            read.unwrap_or(Duration::from_secs(60)),
+        );
+    }
+    match &value["keepalive"] {
+        Value::Null => {}
+        Value::Bool(false) => gateway = gateway.with_keepalive(None),
+        _ => gateway = gateway.with_keepalive(seconds(value, "keepalive")?),
+    }
+    Ok(gateway)
+}
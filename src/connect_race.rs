@@ -0,0 +1,231 @@
+//! Connect racing between the addresses of an upstream.
+//!
+//! An upstream given by name often has several addresses, and discovery
+//! makes a backend of each, tagged with the [`UpstreamName`] it came from.
+//! When a new connection is opened to one of them, a [`ConnectRace`] also
+//! tries another address of the same upstream should the first not have
+//! connected after `stagger`, and takes whichever connects first, as Happy
+//! Eyeballs does for address families (RFC 8305). An address that is slow or
+//! down then costs `stagger` rather than the connect timeout.
+//!
+//! Every address has a success rate, a moving average of its attempts that
+//! connected, failed, or were started first and lost. Of the two addresses of
+//! a race, the better one goes first, so one that keeps failing is only tried
+//! as the backup until it does better.
+//!
+//! The request is accounted to the address that won. Connections reused from
+//! the pool are not raced, and neither are those of sticky sessions and
+//! client subsets, which are tied to one address.
+//!
+//! [`UpstreamName`]: crate::discovery::UpstreamName
+
+use std::collections::HashMap;
+use std::net::SocketAddr as InetAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::connectors::L4Connect;
+use pingora::lb::Backend;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::protocols::l4::stream::Stream;
+use pingora::{Error, ErrorType, Result};
+use serde_json::{Value, json};
+use tokio::net::TcpStream;
+
+/// Weight of the latest attempt in an address's success rate.
+const SMOOTHING: f64 = 0.2;
+
+#[derive(Debug)]
+pub struct ConnectRace {
+    stagger: Duration,
+    /// success rates, addresses without attempts are at 1
+    scores: Mutex<HashMap<InetAddr, f64>>,
+    races: AtomicU64,
+    backup_wins: AtomicU64,
+}
+
+impl ConnectRace {
+    /// Try a second address when the first did not connect within `stagger`.
+    pub fn new(stagger: Duration) -> Self {
+        ConnectRace {
+            stagger,
+            scores: Mutex::default(),
+            races: AtomicU64::new(0),
+            backup_wins: AtomicU64::new(0),
+        }
+    }
+
+    fn score(&self, addr: &InetAddr) -> f64 {
+        self.scores
+            .lock()
+            .unwrap()
+            .get(addr)
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    fn record(&self, addr: InetAddr, connected: bool) {
+        let outcome = if connected { 1.0 } else { 0.0 };
+        let mut scores = self.scores.lock().unwrap();
+        let score = scores.entry(addr).or_insert(1.0);
+        *score += SMOOTHING * (outcome - *score);
+    }
+
+    /// Race a connection to `selected` against the best of its `siblings`,
+    /// the other usable addresses of its upstream; `None` without any.
+    pub(crate) fn racer(
+        self: &Arc<Self>,
+        selected: &Backend,
+        siblings: Vec<Backend>,
+        timeout: Option<Duration>,
+    ) -> Option<Arc<Racer>> {
+        selected.addr.as_inet()?;
+        let backup = siblings
+            .into_iter()
+            .filter(|b| b.addr.as_inet().is_some())
+            .max_by(|a, b| self.score_of(a).total_cmp(&self.score_of(b)))?;
+        // the selected address goes first unless it did worse
+        let (first, second) = match self.score_of(&backup) > self.score_of(selected) {
+            true => (backup, selected.clone()),
+            false => (selected.clone(), backup),
+        };
+        Some(Arc::new(Racer {
+            race: self.clone(),
+            first,
+            second,
+            timeout,
+            won: Mutex::new(None),
+        }))
+    }
+
+    fn score_of(&self, backend: &Backend) -> f64 {
+        backend.addr.as_inet().map_or(0.0, |addr| self.score(addr))
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut scores: Vec<(InetAddr, f64)> = self
+            .scores
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, score)| (*addr, *score))
+            .collect();
+        scores.sort_by_key(|s| s.0);
+        let scores: Vec<Value> = scores
+            .into_iter()
+            .map(|(addr, score)| json!({ "upstream": addr.to_string(), "success_rate": score }))
+            .collect();
+        json!({
+            "stagger_ms": self.stagger.as_millis() as u64,
+            "races": self.races.load(Ordering::Relaxed),
+            "backup_wins": self.backup_wins.load(Ordering::Relaxed),
+            "addresses": scores,
+        })
+    }
+}
+
+/// The race of one new connection, given to the connector in place of its
+/// own connect.
+#[derive(Debug)]
+pub(crate) struct Racer {
+    race: Arc<ConnectRace>,
+    first: Backend,
+    second: Backend,
+    timeout: Option<Duration>,
+    won: Mutex<Option<Backend>>,
+}
+
+impl Racer {
+    /// The address that connected, once one did.
+    pub(crate) fn winner(&self) -> Option<Backend> {
+        self.won.lock().unwrap().clone()
+    }
+
+    /// Score the attempt on `backend` by how it ended.
+    fn settle(&self, backend: &Backend, attempt: std::io::Result<TcpStream>) -> Result<TcpStream> {
+        let addr = inet(backend);
+        self.race.record(addr, attempt.is_ok());
+        match attempt {
+            Ok(stream) => {
+                *self.won.lock().unwrap() = Some(backend.clone());
+                Ok(stream)
+            }
+            Err(e) => Error::e_because(ErrorType::ConnectError, format!("connecting to {addr}"), e),
+        }
+    }
+
+    async fn run(&self) -> Result<TcpStream> {
+        let first = TcpStream::connect(inet(&self.first));
+        tokio::pin!(first);
+        tokio::select! {
+            attempt = &mut first => {
+                if attempt.is_ok() {
+                    return self.settle(&self.first, attempt);
+                }
+                // failed within the stagger, the backup goes right away
+                let _ = self.settle(&self.first, attempt);
+                let attempt = TcpStream::connect(inet(&self.second)).await;
+                return self.settle(&self.second, attempt);
+            }
+            _ = tokio::time::sleep(self.race.stagger) => {}
+        }
+
+        self.race.races.fetch_add(1, Ordering::Relaxed);
+        let second = TcpStream::connect(inet(&self.second));
+        tokio::pin!(second);
+        let won = tokio::select! {
+            attempt = &mut first => match attempt {
+                Ok(_) => self.settle(&self.first, attempt),
+                Err(_) => {
+                    let _ = self.settle(&self.first, attempt);
+                    let attempt = second.await;
+                    self.settle(&self.second, attempt)
+                }
+            },
+            attempt = &mut second => match attempt {
+                Ok(_) => {
+                    // started first and lost
+                    self.race.record(inet(&self.first), false);
+                    self.settle(&self.second, attempt)
+                }
+                Err(_) => {
+                    let _ = self.settle(&self.second, attempt);
+                    let attempt = first.await;
+                    self.settle(&self.first, attempt)
+                }
+            },
+        };
+        if won.is_ok() && self.winner().is_some_and(|w| w.addr == self.second.addr) {
+            self.race.backup_wins.fetch_add(1, Ordering::Relaxed);
+        }
+        won
+    }
+}
+
+fn inet(backend: &Backend) -> InetAddr {
+    *backend.addr.as_inet().expect("raced backends are inet")
+}
+
+#[async_trait]
+impl L4Connect for Racer {
+    async fn connect(&self, _addr: &SocketAddr) -> Result<Stream> {
+        let stream = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, self.run()).await {
+                Ok(stream) => stream?,
+                Err(_) => {
+                    return Error::e_explain(
+                        ErrorType::ConnectTimedout,
+                        format!(
+                            "timeout {timeout:?} connecting to {} or {}",
+                            self.first.addr, self.second.addr
+                        ),
+                    );
+                }
+            },
+            None => self.run().await?,
+        };
+        Ok(stream.into())
+    }
+}
@@ -0,0 +1,152 @@
+//! The open downstream connections, for the admin API.
+//!
+//! Connections are registered by the [`crate::h2_server::H2Server`] wrapping
+//! the proxy service, which also closes them on request; the proxy keeps
+//! their in-flight requests and latest route up to date. A connection is
+//! known by its client address, unique among open TCP connections.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use pingora::protocols::l4::socket::SocketAddr;
+use tokio::sync::Notify;
+
+pub struct Connection {
+    pub id: u64,
+    pub client: SocketAddr,
+    /// `http/1`, `h2` or `h2c`.
+    pub protocol: &'static str,
+    pub tls: bool,
+    opened: Instant,
+    requests: AtomicU64,
+    in_flight: AtomicUsize,
+    route: Mutex<Option<String>>,
+    close: Notify,
+}
+
+impl Connection {
+    pub fn age(&self) -> Duration {
+        self.opened.elapsed()
+    }
+
+    /// Requests started on the connection.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Requests being served, at most one for HTTP/1.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Route of the latest request.
+    pub fn route(&self) -> Option<String> {
+        self.route.lock().unwrap().clone()
+    }
+
+    /// Resolves once the connection is asked to close.
+    pub(crate) async fn closed(&self) {
+        self.close.notified().await
+    }
+}
+
+#[derive(Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    open: RwLock<HashMap<SocketAddr, Arc<Connection>>>,
+}
+
+/// Unregisters its connection when dropped.
+pub(crate) struct Registration<'a> {
+    connections: &'a Connections,
+    pub(crate) connection: Arc<Connection>,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.write().unwrap();
+        if open
+            .get(&self.connection.client)
+            .is_some_and(|c| c.id == self.connection.id)
+        {
+            open.remove(&self.connection.client);
+        }
+    }
+}
+
+impl Connections {
+    pub(crate) fn register(
+        &self,
+        client: SocketAddr,
+        protocol: &'static str,
+        tls: bool,
+    ) -> Registration<'_> {
+        let connection = Arc::new(Connection {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            client: client.clone(),
+            protocol,
+            tls,
+            opened: Instant::now(),
+            requests: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            route: Mutex::new(None),
+            close: Notify::new(),
+        });
+        self.open
+            .write()
+            .unwrap()
+            .insert(client, connection.clone());
+        Registration {
+            connections: self,
+            connection,
+        }
+    }
+
+    fn get(&self, client: &SocketAddr) -> Option<Arc<Connection>> {
+        self.open.read().unwrap().get(client).cloned()
+    }
+
+    /// Count a request on the connection of `client`, returned if it is
+    /// registered.
+    pub(crate) fn request_started(&self, client: &SocketAddr) -> Option<Arc<Connection>> {
+        let connection = self.get(client)?;
+        connection.requests.fetch_add(1, Ordering::Relaxed);
+        connection.in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(connection)
+    }
+
+    pub(crate) fn request_routed(&self, client: &SocketAddr, route: &str) {
+        if let Some(connection) = self.get(client) {
+            *connection.route.lock().unwrap() = Some(route.to_string());
+        }
+    }
+
+    pub(crate) fn request_finished(&self, client: &SocketAddr) {
+        if let Some(connection) = self.get(client) {
+            // saturating, requests may have started before the registration
+            let _ = connection
+                .in_flight
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+        }
+    }
+
+    /// The open connections, oldest first.
+    pub fn list(&self) -> Vec<Arc<Connection>> {
+        let mut list: Vec<_> = self.open.read().unwrap().values().cloned().collect();
+        list.sort_by_key(|c| c.opened);
+        list
+    }
+
+    /// Close connection `id`, cutting its requests short; `false` if it is
+    /// not open.
+    pub fn close(&self, id: u64) -> bool {
+        let open = self.open.read().unwrap();
+        let Some(connection) = open.values().find(|c| c.id == id) else {
+            return false;
+        };
+        connection.close.notify_one();
+        true
+    }
+}
@@ -0,0 +1,1010 @@
+//! Consistent hashing ring.
+//!
+//! Every node gets 160 points per unit of weight on a ring of 32 bit hashes,
+//! and a key maps to the node of the first point at or after the hash of the
+//! key. Points are derived the way nginx's `hash ... consistent` derives them,
+//! so with the default [`Crc32`] keys land on the same nodes as behind an
+//! nginx with the same upstreams. Another [`Hasher`] spreads them over other
+//! nodes: [`XxHash32`] and [`Fnv1a`] for speed, [`Ketama`] for the MD5 hash
+//! of libmemcached's ketama; [`HashFunction`] picks one of them at runtime.
+//!
+//! [`Layout::Ketama`] lays the points out as libmemcached's weighted ketama
+//! does instead, for memcached clusters whose clients already shard keys
+//! with it: 160 points per node times its share of the total weight and the
+//! number of nodes, four from every MD5 digest of `"<ip>:<port>-<n>"`, or
+//! `"<ip>-<n>"` on the default memcached port. With [`Ketama`] hashing the
+//! keys, as [`Continuum::ketama`] has it, keys map to the nodes those
+//! clients send them to.
+//!
+//! Membership changes one node at a time: [`Continuum::add_bucket`] and
+//! [`Continuum::remove_bucket`] splice the points of that node in or out of
+//! the sorted ring, leaving the others unhashed, and tell the share of keys
+//! the change moved. A ketama layout is laid out again, since the points of
+//! every node there depend on the number of nodes and their total weight.
+//!
+//! A lookup also gives the [`PoolHint`] of the node, which keys the pools
+//! connections to it are reused from, see [`crate::upstream_tcp`].
+
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher as _};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use arc_swap::ArcSwapOption;
+use openssl::hash::MessageDigest;
+use pingora::lb::{Backend, LoadBalancer, selection::RoundRobin};
+use pingora::protocols::l4::socket::SocketAddr as PeerAddr;
+
+/// Points per unit of weight.
+const POINT_MULTIPLE: u32 = 160;
+
+/// Points per node of a ketama ring of equal weights.
+const KETAMA_POINTS: u32 = 160;
+
+/// The port libmemcached leaves out of the names of nodes.
+const MEMCACHED_PORT: u16 = 11211;
+
+/// Hashes keys and points onto the ring.
+pub trait Hasher {
+    fn hash(&self, data: &[u8]) -> u32;
+}
+
+/// CRC-32 (IEEE), as nginx hashes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Crc32;
+
+impl Hasher for Crc32 {
+    fn hash(&self, data: &[u8]) -> u32 {
+        crc32fast::hash(data)
+    }
+}
+
+/// 32 bit xxHash, seeded with 0.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XxHash32;
+
+impl XxHash32 {
+    const PRIME1: u32 = 0x9e37_79b1;
+    const PRIME2: u32 = 0x85eb_ca77;
+    const PRIME3: u32 = 0xc2b2_ae3d;
+    const PRIME4: u32 = 0x27d4_eb2f;
+    const PRIME5: u32 = 0x1656_67b1;
+
+    fn round(acc: u32, lane: &[u8]) -> u32 {
+        let lane = u32::from_le_bytes(lane.try_into().unwrap());
+        acc.wrapping_add(lane.wrapping_mul(Self::PRIME2))
+            .rotate_left(13)
+            .wrapping_mul(Self::PRIME1)
+    }
+}
+
+impl Hasher for XxHash32 {
+    fn hash(&self, data: &[u8]) -> u32 {
+        let stripes = data.chunks_exact(16);
+        let rest = stripes.remainder();
+        let mut hash = if data.len() >= 16 {
+            let mut acc = [
+                Self::PRIME1.wrapping_add(Self::PRIME2),
+                Self::PRIME2,
+                0,
+                0u32.wrapping_sub(Self::PRIME1),
+            ];
+            for stripe in stripes {
+                for (acc, lane) in acc.iter_mut().zip(stripe.chunks_exact(4)) {
+                    *acc = Self::round(*acc, lane);
+                }
+            }
+            acc[0]
+                .rotate_left(1)
+                .wrapping_add(acc[1].rotate_left(7))
+                .wrapping_add(acc[2].rotate_left(12))
+                .wrapping_add(acc[3].rotate_left(18))
+        } else {
+            Self::PRIME5
+        };
+        hash = hash.wrapping_add(data.len() as u32);
+
+        let words = rest.chunks_exact(4);
+        let bytes = words.remainder();
+        for word in words {
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            hash = hash
+                .wrapping_add(word.wrapping_mul(Self::PRIME3))
+                .rotate_left(17)
+                .wrapping_mul(Self::PRIME4);
+        }
+        for &byte in bytes {
+            hash = hash
+                .wrapping_add(u32::from(byte).wrapping_mul(Self::PRIME5))
+                .rotate_left(11)
+                .wrapping_mul(Self::PRIME1);
+        }
+
+        hash ^= hash >> 15;
+        hash = hash.wrapping_mul(Self::PRIME2);
+        hash ^= hash >> 13;
+        hash = hash.wrapping_mul(Self::PRIME3);
+        hash ^ (hash >> 16)
+    }
+}
+
+/// 32 bit FNV-1a.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fnv1a;
+
+impl Hasher for Fnv1a {
+    fn hash(&self, data: &[u8]) -> u32 {
+        data.iter().fold(0x811c_9dc5, |hash, &byte| {
+            (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+        })
+    }
+}
+
+/// The first four bytes of the MD5 digest, little endian, as libmemcached's
+/// ketama hashes keys.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ketama;
+
+impl Hasher for Ketama {
+    fn hash(&self, data: &[u8]) -> u32 {
+        u32::from_le_bytes(md5(data)[..4].try_into().unwrap())
+    }
+}
+
+fn md5(data: &[u8]) -> [u8; 16] {
+    let digest = openssl::hash::hash(MessageDigest::md5(), data).expect("MD5 digest");
+    digest[..].try_into().unwrap()
+}
+
+/// One of the hashers, picked at runtime.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashFunction {
+    #[default]
+    Crc32,
+    XxHash32,
+    Fnv1a,
+    Ketama,
+}
+
+impl HashFunction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashFunction::Crc32 => "crc32",
+            HashFunction::XxHash32 => "xxhash32",
+            HashFunction::Fnv1a => "fnv1a",
+            HashFunction::Ketama => "ketama",
+        }
+    }
+}
+
+impl FromStr for HashFunction {
+    type Err = String;
+
+    /// `crc32`, `xxhash32`, `fnv1a` or `ketama`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crc32" => Ok(HashFunction::Crc32),
+            "xxhash32" => Ok(HashFunction::XxHash32),
+            "fnv1a" => Ok(HashFunction::Fnv1a),
+            "ketama" => Ok(HashFunction::Ketama),
+            _ => Err(format!("unknown hash function {s:?}")),
+        }
+    }
+}
+
+impl Hasher for HashFunction {
+    fn hash(&self, data: &[u8]) -> u32 {
+        match self {
+            HashFunction::Crc32 => Crc32.hash(data),
+            HashFunction::XxHash32 => XxHash32.hash(data),
+            HashFunction::Fnv1a => Fnv1a.hash(data),
+            HashFunction::Ketama => Ketama.hash(data),
+        }
+    }
+}
+
+/// How the points of the nodes are laid out on the ring.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// As nginx's `hash ... consistent`, points hashed by the ring's hasher.
+    #[default]
+    Nginx,
+    /// As libmemcached's weighted ketama, points from MD5 digests whatever
+    /// the hasher of the keys.
+    Ketama,
+}
+
+impl Layout {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Layout::Nginx => "nginx",
+            Layout::Ketama => "ketama",
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    /// `nginx` or `ketama`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nginx" => Ok(Layout::Nginx),
+            "ketama" => Ok(Layout::Ketama),
+            _ => Err(format!("unknown ring layout {s:?}")),
+        }
+    }
+}
+
+/// A node on the ring and its weight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bucket {
+    pub node: SocketAddr,
+    pub weight: u32,
+}
+
+impl Bucket {
+    pub fn new(node: SocketAddr, weight: u32) -> Self {
+        assert!(weight != 0, "bucket weight must be at least 1");
+        Bucket { node, weight }
+    }
+
+    /// The bucket of a network backend; `None` for other kinds of backends,
+    /// which have no place on the ring.
+    pub fn from_backend(backend: &Backend) -> Option<Self> {
+        match backend.addr {
+            PeerAddr::Inet(addr) => {
+                let weight = u32::try_from(backend.weight).unwrap_or(u32::MAX).max(1);
+                Some(Bucket::new(addr, weight))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Identifies the connection pools of a node: the same for the node on
+/// every ring, so rebuilding a ring keeps its pooled connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PoolHint(u64);
+
+impl PoolHint {
+    pub fn of(node: &SocketAddr) -> Self {
+        let mut hasher = DefaultHasher::new();
+        node.hash(&mut hasher);
+        PoolHint(hasher.finish())
+    }
+}
+
+/// Where a key maps to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lookup {
+    pub node: SocketAddr,
+    pub pool: PoolHint,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Point {
+    hash: u32,
+    /// index into `Continuum::nodes`
+    node: u32,
+}
+
+/// What adding or removing a bucket did to a ring.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Churn {
+    /// points put on the ring
+    pub added: usize,
+    /// points taken off it
+    pub removed: usize,
+    /// whether every point was laid out again rather than spliced
+    pub rebuilt: bool,
+    /// share of the ring, from 0 to 1, whose keys map to another node now
+    pub moved: f64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Continuum<H = Crc32> {
+    /// sorted by hash, then by node; of the points sharing a hash, the one
+    /// of the node listed first owns it
+    ring: Vec<Point>,
+    nodes: Vec<SocketAddr>,
+    /// by node index
+    weights: Vec<u32>,
+    /// by node index, hashed once rather than on every lookup
+    pools: Vec<PoolHint>,
+    layout: Layout,
+    hasher: H,
+}
+
+impl Continuum {
+    /// The ring of `buckets` hashed with [`Crc32`], as nginx lays it out.
+    pub fn new(buckets: &[Bucket]) -> Self {
+        Continuum::with_hasher(buckets, Crc32)
+    }
+}
+
+impl Continuum<Ketama> {
+    /// The ring of `buckets` as libmemcached's weighted ketama lays it out
+    /// and hashes keys onto it.
+    pub fn ketama(buckets: &[Bucket]) -> Self {
+        Continuum::with_layout(buckets, Layout::Ketama, Ketama)
+    }
+}
+
+/// Points of `bucket` as nginx derives them, hashed by `hasher`.
+fn nginx_points(bucket: &Bucket, node: u32, hasher: &impl Hasher, ring: &mut Vec<Point>) {
+    // nginx hashes "<host>\0<port>" followed by the previous point, the host
+    // being the server's address up to its last colon, so IPv6 bracketed
+    let mut name = Vec::with_capacity(1 + 39 + 1 + 1 + 5 + 4);
+    match bucket.node {
+        SocketAddr::V4(node) => write!(name, "{}\0{}", node.ip(), node.port()),
+        SocketAddr::V6(node) => write!(name, "[{}]\0{}", node.ip(), node.port()),
+    }
+    .unwrap();
+    let prefix = name.len();
+
+    let mut prev_hash: u32 = 0;
+    for _ in 0..bucket.weight * POINT_MULTIPLE {
+        name.truncate(prefix);
+        name.extend_from_slice(&prev_hash.to_le_bytes());
+        let hash = hasher.hash(&name);
+        ring.push(Point { hash, node });
+        prev_hash = hash;
+    }
+}
+
+/// Points of `bucket` as libmemcached derives them, on a ring of `nodes`
+/// with weights summing to `total_weight`.
+fn ketama_points(
+    bucket: &Bucket,
+    node: u32,
+    nodes: usize,
+    total_weight: u64,
+    ring: &mut Vec<Point>,
+) {
+    // in single precision and rounded down to four, as libmemcached has it
+    let share = bucket.weight as f32 / total_weight as f32;
+    let digests = (f64::from(share * KETAMA_POINTS as f32 / 4.0 * nodes as f32) + 1e-10).floor();
+    let mut name = Vec::with_capacity(39 + 1 + 5 + 1 + 10);
+    for n in 0..digests as u32 {
+        name.clear();
+        match bucket.node.port() {
+            MEMCACHED_PORT => write!(name, "{}-{n}", bucket.node.ip()),
+            port => write!(name, "{}:{port}-{n}", bucket.node.ip()),
+        }
+        .unwrap();
+        let digest = md5(&name);
+        for point in digest.chunks_exact(4) {
+            let hash = u32::from_le_bytes(point.try_into().unwrap());
+            ring.push(Point { hash, node });
+        }
+    }
+}
+
+impl<H: Hasher> Continuum<H> {
+    /// The ring of `buckets` with points and keys hashed by `hasher`.
+    pub fn with_hasher(buckets: &[Bucket], hasher: H) -> Self {
+        Continuum::with_layout(buckets, Layout::Nginx, hasher)
+    }
+
+    /// The ring of `buckets` laid out by `layout`, with keys hashed by
+    /// `hasher`, and points too on an nginx layout.
+    pub fn with_layout(buckets: &[Bucket], layout: Layout, hasher: H) -> Self {
+        let mut continuum = Continuum {
+            ring: Vec::new(),
+            nodes: buckets.iter().map(|b| b.node).collect(),
+            weights: buckets.iter().map(|b| b.weight).collect(),
+            pools: buckets.iter().map(|b| PoolHint::of(&b.node)).collect(),
+            layout,
+            hasher,
+        };
+        continuum.lay_out();
+        continuum
+    }
+
+    /// Puts the points of every node on the ring.
+    fn lay_out(&mut self) {
+        let total_weight: u64 = self.weights.iter().map(|&w| u64::from(w)).sum();
+        let points = match self.layout {
+            Layout::Nginx => total_weight * u64::from(POINT_MULTIPLE),
+            Layout::Ketama => self.nodes.len() as u64 * u64::from(KETAMA_POINTS),
+        };
+        let mut ring = Vec::with_capacity(points as usize);
+        for node in 0..self.nodes.len() {
+            let bucket = self.bucket(node);
+            match self.layout {
+                Layout::Nginx => nginx_points(&bucket, node as u32, &self.hasher, &mut ring),
+                Layout::Ketama => ketama_points(
+                    &bucket,
+                    node as u32,
+                    self.nodes.len(),
+                    total_weight,
+                    &mut ring,
+                ),
+            }
+        }
+        ring.sort_unstable();
+        self.ring = ring;
+    }
+
+    fn bucket(&self, node: usize) -> Bucket {
+        Bucket {
+            node: self.nodes[node],
+            weight: self.weights[node],
+        }
+    }
+
+    /// The number of points on the ring.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+
+    /// Where `key` is on the ring.
+    pub fn hash(&self, key: &[u8]) -> u32 {
+        self.hasher.hash(key)
+    }
+
+    /// Index of the first point at or after the hash of `key`.
+    pub fn node_idx(&self, key: &[u8]) -> usize {
+        self.point_idx(self.hash(key))
+    }
+
+    fn point_idx(&self, hash: u32) -> usize {
+        // the first of the points sharing a hash owns it
+        match self.ring.partition_point(|p| p.hash < hash) {
+            // past the last point wraps around to the first
+            i if i == self.ring.len() => 0,
+            i => i,
+        }
+    }
+
+    /// The node `key` maps to, `None` on an empty ring.
+    pub fn node(&self, key: &[u8]) -> Option<SocketAddr> {
+        self.lookup(key).map(|l| l.node)
+    }
+
+    /// The node `key` maps to with its pool, `None` on an empty ring.
+    pub fn lookup(&self, key: &[u8]) -> Option<Lookup> {
+        self.ring
+            .get(self.node_idx(key))
+            .map(|p| self.lookup_of(p.node))
+    }
+
+    fn lookup_of(&self, node: u32) -> Lookup {
+        Lookup {
+            node: self.nodes[node as usize],
+            pool: self.pools[node as usize],
+        }
+    }
+
+    /// The nodes of the points from `key` on, around the ring and again, for
+    /// falling back when the node of `key` cannot be used. Nodes repeat; see
+    /// [`Continuum::nodes`] for distinct ones.
+    pub fn node_iter(&self, key: &[u8]) -> NodeIterator<'_, H> {
+        NodeIterator {
+            idx: self.node_idx(key),
+            continuum: self,
+        }
+    }
+
+    /// The node of the point at `idx`, moving `idx` on to the next point.
+    pub fn get_addr(&self, idx: &mut usize) -> Option<&SocketAddr> {
+        let point = self.ring.get(*idx)?;
+        *idx = (*idx + 1) % self.ring.len();
+        Some(&self.nodes[point.node as usize])
+    }
+
+    /// The first `n` distinct nodes from `key` on, in ring order: where the
+    /// replicas of `key` live. Fewer if the ring has fewer nodes.
+    pub fn nodes(&self, key: &[u8], n: usize) -> Vec<SocketAddr> {
+        self.lookups(key, n).iter().map(|l| l.node).collect()
+    }
+
+    /// [`Continuum::nodes`] with their pools.
+    pub fn lookups(&self, key: &[u8], n: usize) -> Vec<Lookup> {
+        let n = n.min(self.nodes.len());
+        let mut found: Vec<Lookup> = Vec::with_capacity(n);
+        if self.ring.is_empty() {
+            return found;
+        }
+        // one lap visits every node
+        let start = self.node_idx(key);
+        for i in 0..self.ring.len() {
+            if found.len() == n {
+                break;
+            }
+            let node = self.ring[(start + i) % self.ring.len()].node;
+            if !found.iter().any(|l| l.node == self.nodes[node as usize]) {
+                found.push(self.lookup_of(node));
+            }
+        }
+        found
+    }
+
+    /// Share of the ring, from 0 to 1, whose keys map to another node in
+    /// `other`: the keys a change from `self` to `other` moves.
+    pub fn moved(&self, other: &Continuum<H>) -> f64 {
+        match (self.ring.is_empty(), other.ring.is_empty()) {
+            (true, true) => return 0.0,
+            (true, false) | (false, true) => return 1.0,
+            (false, false) => {}
+        }
+        let owner = |c: &Continuum<H>, hash: u32| c.nodes[c.ring[c.point_idx(hash)].node as usize];
+        let mut bounds: Vec<u32> = self
+            .ring
+            .iter()
+            .chain(&*other.ring)
+            .map(|p| p.hash)
+            .collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+        // every stretch up to a point has one node in each ring, the one of
+        // the point closing it
+        let mut moved: u64 = 0;
+        let mut prev = *bounds.last().unwrap();
+        for &bound in &bounds {
+            if owner(self, bound) != owner(other, bound) {
+                moved += u64::from(bound.wrapping_sub(prev));
+            }
+            prev = bound;
+        }
+        if bounds.len() == 1 {
+            // a single point owns the whole ring
+            moved = if owner(self, prev) == owner(other, prev) {
+                0
+            } else {
+                1 << 32
+            };
+        }
+        moved as f64 / (1u64 << 32) as f64
+    }
+
+    /// The stretches of the ring owned by one node, in ring order, as
+    /// inclusive `(from, to, node)` hash ranges. The one range with `from`
+    /// above `to` wraps around past `u32::MAX`.
+    pub fn ranges(&self) -> Vec<(u32, u32, SocketAddr)> {
+        let mut ranges: Vec<(u32, u32, SocketAddr)> = Vec::new();
+        let Some(last) = self.ring.last() else {
+            return ranges;
+        };
+        // a point owns the hashes after the previous point up to its own
+        let mut from = last.hash.wrapping_add(1);
+        for (i, point) in self.ring.iter().enumerate() {
+            if i > 0 && self.ring[i - 1].hash == point.hash {
+                continue;
+            }
+            let node = self.nodes[point.node as usize];
+            match ranges.last_mut() {
+                Some(range) if range.2 == node => range.1 = point.hash,
+                _ => ranges.push((from, point.hash, node)),
+            }
+            from = point.hash.wrapping_add(1);
+        }
+        // the keys past the last point go to the first one
+        if ranges.len() > 1 && ranges[0].2 == ranges[ranges.len() - 1].2 {
+            let (_, to, _) = ranges.remove(0);
+            ranges.last_mut().unwrap().1 = to;
+        }
+        ranges
+    }
+
+    /// Share of the ring, from 0 to 1, whose keys map to the node at `node`.
+    fn share(&self, node: u32) -> f64 {
+        let Some(last) = self.ring.last() else {
+            return 0.0;
+        };
+        let mut owned: u64 = 0;
+        let mut owners = 0;
+        let mut prev = last.hash;
+        for (i, point) in self.ring.iter().enumerate() {
+            if i > 0 && self.ring[i - 1].hash == point.hash {
+                continue;
+            }
+            if point.node == node {
+                owned += u64::from(point.hash.wrapping_sub(prev));
+            }
+            owners += 1;
+            prev = point.hash;
+        }
+        if owners == 1 {
+            // a single point owns the whole ring
+            owned = if self.ring[0].node == node {
+                1 << 32
+            } else {
+                0
+            };
+        }
+        owned as f64 / (1u64 << 32) as f64
+    }
+}
+
+impl<H: Hasher + Clone> Continuum<H> {
+    /// Puts `bucket` on the ring, its node listed after the others, splicing
+    /// its points in among theirs. A node on the ring already takes the
+    /// weight of `bucket`, keeping its place in the list.
+    pub fn add_bucket(&mut self, bucket: Bucket) -> Churn {
+        let before = (self.layout == Layout::Ketama).then(|| self.clone());
+        let node = match self.nodes.iter().position(|n| *n == bucket.node) {
+            Some(node) => {
+                self.weights[node] = bucket.weight;
+                node as u32
+            }
+            None => {
+                self.push(bucket);
+                (self.nodes.len() - 1) as u32
+            }
+        };
+        if let Some(before) = before {
+            return self.rebuilt(&before);
+        }
+
+        let share = self.share(node);
+        let removed = self.splice_out(node);
+        let added = self.splice_in(node);
+        // the nginx points of a weight are the first ones of any heavier
+        // weight, so keys only move to the node or only away from it
+        Churn {
+            added,
+            removed,
+            rebuilt: false,
+            moved: (self.share(node) - share).abs(),
+        }
+    }
+
+    /// Takes `node` and its points off the ring, `None` if it is not on it.
+    pub fn remove_bucket(&mut self, node: &SocketAddr) -> Option<Churn> {
+        let listed = self.nodes.iter().position(|n| n == node)?;
+        if self.layout == Layout::Ketama {
+            let before = self.clone();
+            self.unlist(listed);
+            return Some(self.rebuilt(&before));
+        }
+
+        // consistent hashing only moves the keys of the node removed
+        let moved = self.share(listed as u32);
+        let removed = self.splice_out(listed as u32);
+        self.unlist(listed);
+        for point in &mut self.ring {
+            if point.node > listed as u32 {
+                point.node -= 1;
+            }
+        }
+        Some(Churn {
+            added: 0,
+            removed,
+            rebuilt: false,
+            moved,
+        })
+    }
+
+    fn push(&mut self, bucket: Bucket) {
+        self.nodes.push(bucket.node);
+        self.weights.push(bucket.weight);
+        self.pools.push(PoolHint::of(&bucket.node));
+    }
+
+    fn unlist(&mut self, node: usize) {
+        self.nodes.remove(node);
+        self.weights.remove(node);
+        self.pools.remove(node);
+    }
+
+    /// Lays every point out again after a change from `before`.
+    fn rebuilt(&mut self, before: &Continuum<H>) -> Churn {
+        self.lay_out();
+        Churn {
+            added: self.ring.len(),
+            removed: before.ring.len(),
+            rebuilt: true,
+            moved: before.moved(self),
+        }
+    }
+
+    /// Merges the nginx points of `node` into the ring, how many.
+    fn splice_in(&mut self, node: u32) -> usize {
+        let mut points =
+            Vec::with_capacity((self.weights[node as usize] * POINT_MULTIPLE) as usize);
+        nginx_points(&self.bucket(node as usize), node, &self.hasher, &mut points);
+        points.sort_unstable();
+        let added = points.len();
+
+        let mut ring = Vec::with_capacity(self.ring.len() + added);
+        let mut points = points.into_iter().peekable();
+        for point in std::mem::take(&mut self.ring) {
+            while let Some(new) = points.next_if(|p| *p < point) {
+                ring.push(new);
+            }
+            ring.push(point);
+        }
+        ring.extend(points);
+        self.ring = ring;
+        added
+    }
+
+    /// Drops the points of `node` from the ring, how many.
+    fn splice_out(&mut self, node: u32) -> usize {
+        let before = self.ring.len();
+        self.ring.retain(|p| p.node != node);
+        before - self.ring.len()
+    }
+}
+
+/// Endless walk around the ring, see [`Continuum::node_iter`].
+pub struct NodeIterator<'a, H = Crc32> {
+    idx: usize,
+    continuum: &'a Continuum<H>,
+}
+
+impl<'a, H: Hasher> Iterator for NodeIterator<'a, H> {
+    type Item = &'a SocketAddr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.continuum.get_addr(&mut self.idx)
+    }
+}
+
+/// The continuum of a cluster's backends, updated when they change. Requests
+/// read it without a lock, see [`crate::route::SharedRouter`].
+#[derive(Default)]
+pub struct ClusterRing {
+    ring: ArcSwapOption<BackendRing>,
+    layout: Layout,
+    hasher: HashFunction,
+}
+
+/// The continuum of one set of backends.
+pub struct BackendRing {
+    backends: Arc<BTreeSet<Backend>>,
+    pub continuum: Continuum<HashFunction>,
+    by_addr: HashMap<SocketAddr, Backend>,
+}
+
+impl BackendRing {
+    /// The backend of a node.
+    pub fn backend(&self, node: &SocketAddr) -> Option<&Backend> {
+        self.by_addr.get(node)
+    }
+}
+
+impl ClusterRing {
+    /// Rings laid out by `layout`, hashed by `hasher`.
+    pub fn new(layout: Layout, hasher: HashFunction) -> Self {
+        ClusterRing {
+            ring: ArcSwapOption::empty(),
+            layout,
+            hasher,
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn hasher(&self) -> HashFunction {
+        self.hasher
+    }
+
+    /// The ring of the cluster's current backends.
+    pub fn get(&self, upstreams: &LoadBalancer<RoundRobin>) -> Arc<BackendRing> {
+        let backends = upstreams.backends().get_backend();
+        let old = self.ring.load_full();
+        if let Some(ring) = &old
+            && Arc::ptr_eq(&ring.backends, &backends)
+        {
+            return ring.clone();
+        }
+
+        let mut buckets = Vec::with_capacity(backends.len());
+        let mut by_addr = HashMap::with_capacity(backends.len());
+        for backend in backends.iter() {
+            if let Some(bucket) = Bucket::from_backend(backend) {
+                buckets.push(bucket);
+                by_addr.insert(bucket.node, backend.clone());
+            }
+        }
+        let continuum = match &old {
+            // backends come and go a few at a time: splice them in and out
+            // rather than hash every point again; a ketama layout is laid
+            // out again on any change anyway
+            Some(old) if self.layout == Layout::Nginx => {
+                let mut continuum = old.continuum.clone();
+                for node in old.by_addr.keys() {
+                    if !by_addr.contains_key(node) {
+                        continuum.remove_bucket(node);
+                    }
+                }
+                for bucket in &buckets {
+                    let was = old.backend(&bucket.node).and_then(Bucket::from_backend);
+                    if was != Some(*bucket) {
+                        continuum.add_bucket(*bucket);
+                    }
+                }
+                continuum
+            }
+            _ => Continuum::with_layout(&buckets, self.layout, self.hasher),
+        };
+        let ring = Arc::new(BackendRing {
+            continuum,
+            backends,
+            by_addr,
+        });
+        self.ring.store(Some(ring.clone()));
+        ring
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first points of `server 10.0.0.1:8080;` and of
+    /// `server [2001:db8::1]:8080;` in an nginx `hash ... consistent`
+    /// upstream, as `ngx_http_upstream_init_chash` derives them.
+    const NGINX_POINTS: [(&str, [u32; 4]); 2] = [
+        (
+            "10.0.0.1:8080",
+            [0xf1ea5a8f, 0x4bd08e68, 0x84e5eeee, 0xadead1be],
+        ),
+        (
+            "[2001:db8::1]:8080",
+            [0x65e4862c, 0x6d7252a7, 0xf46a5bdf, 0x5ef85b9a],
+        ),
+    ];
+
+    /// Keys, their MD5 hash and the server libmemcached's weighted ketama
+    /// sends them to among `10.0.1.1:11211` of weight 1, `10.0.1.2:11211` of
+    /// weight 2 and `10.0.1.3:11212` of weight 1, as `update_continuum` and
+    /// `dispatch_host` have it.
+    const KETAMA_KEYS: [(&str, u32, usize); 7] = [
+        ("foo", 3675831724, 0),
+        ("bar", 421377335, 2),
+        ("user:42", 417323606, 1),
+        ("session:abc", 226189362, 1),
+        ("a", 3111502092, 2),
+        ("b", 4267699090, 1),
+        ("c", 4027091530, 0),
+    ];
+
+    fn bucket(node: &str, weight: u32) -> Bucket {
+        Bucket::new(node.parse().expect("address"), weight)
+    }
+
+    #[test]
+    fn points_of_nginx() {
+        for (node, expected) in NGINX_POINTS {
+            let mut points = Vec::new();
+            nginx_points(&bucket(node, 1), 0, &Crc32, &mut points);
+            assert_eq!(points.len(), 160);
+            let hashes: Vec<u32> = points.iter().take(4).map(|p| p.hash).collect();
+            assert_eq!(hashes, expected, "{node}");
+        }
+
+        // and the upstreams nginx sends these keys to
+        let continuum =
+            Continuum::new(&[bucket(NGINX_POINTS[0].0, 1), bucket(NGINX_POINTS[1].0, 1)]);
+        for (key, node) in [
+            ("/", 1),
+            ("/index.html", 0),
+            ("user:42", 1),
+            ("a", 0),
+            ("b", 1),
+            ("e", 0),
+        ] {
+            let expected: SocketAddr = NGINX_POINTS[node].0.parse().expect("address");
+            assert_eq!(continuum.node(key.as_bytes()), Some(expected), "{key}");
+        }
+    }
+
+    #[test]
+    fn keys_of_libmemcached() {
+        let buckets = [
+            bucket("10.0.1.1:11211", 1),
+            bucket("10.0.1.2:11211", 2),
+            bucket("10.0.1.3:11212", 1),
+        ];
+        let continuum = Continuum::ketama(&buckets);
+        // 160 points a node times its share of the weight and the nodes
+        for (node, points) in [(0, 120), (1, 240), (2, 120)] {
+            let on_ring = continuum.ring.iter().filter(|p| p.node == node).count();
+            assert_eq!(on_ring, points, "{}", buckets[node as usize].node);
+        }
+        for (key, hash, node) in KETAMA_KEYS {
+            assert_eq!(continuum.hash(key.as_bytes()), hash, "{key}");
+            assert_eq!(
+                continuum.node(key.as_bytes()),
+                Some(buckets[node].node),
+                "{key}"
+            );
+        }
+    }
+
+    /// Panics unless `spliced` is the ring `rebuilt` is, point for point.
+    fn assert_same<H: Hasher>(spliced: &Continuum<H>, rebuilt: &Continuum<H>) {
+        assert_eq!(spliced.nodes, rebuilt.nodes);
+        assert_eq!(spliced.weights, rebuilt.weights);
+        assert_eq!(spliced.pools, rebuilt.pools);
+        assert!(spliced.ring == rebuilt.ring, "points differ");
+    }
+
+    #[test]
+    fn splicing_buckets_is_rebuilding() {
+        let buckets = [
+            bucket("10.0.0.1:80", 1),
+            bucket("10.0.0.2:80", 2),
+            bucket("10.0.0.3:80", 1),
+            bucket("[2001:db8::1]:80", 3),
+        ];
+        let before = Continuum::new(&buckets[..3]);
+
+        let mut added = before.clone();
+        let churn = added.add_bucket(buckets[3]);
+        assert_same(&added, &Continuum::new(&buckets));
+        assert_eq!((churn.added, churn.removed, churn.rebuilt), (480, 0, false));
+        assert!((churn.moved - before.moved(&added)).abs() < 1e-9);
+        assert!(churn.moved > 0.0 && churn.moved < 1.0);
+
+        let mut reweighted = added.clone();
+        let churn = reweighted.add_bucket(bucket("10.0.0.2:80", 1));
+        let weights = [buckets[0], bucket("10.0.0.2:80", 1), buckets[2], buckets[3]];
+        assert_same(&reweighted, &Continuum::new(&weights));
+        assert_eq!(
+            (churn.added, churn.removed, churn.rebuilt),
+            (160, 320, false)
+        );
+        assert!((churn.moved - added.moved(&reweighted)).abs() < 1e-9);
+
+        // of a node listed in the middle, the ones after it moving up
+        let mut removed = added.clone();
+        let churn = removed
+            .remove_bucket(&buckets[1].node)
+            .expect("on the ring");
+        let rest = [buckets[0], buckets[2], buckets[3]];
+        assert_same(&removed, &Continuum::new(&rest));
+        assert_eq!((churn.added, churn.removed, churn.rebuilt), (0, 320, false));
+        assert!((churn.moved - added.moved(&removed)).abs() < 1e-9);
+        assert_eq!(removed.remove_bucket(&buckets[1].node), None);
+
+        let mut emptied = Continuum::new(&buckets[..1]);
+        let churn = emptied
+            .remove_bucket(&buckets[0].node)
+            .expect("on the ring");
+        assert!(emptied.is_empty());
+        assert_eq!(churn.moved, 1.0);
+    }
+
+    #[test]
+    fn splicing_ketama_buckets_lays_them_out_again() {
+        let buckets = [
+            bucket("10.0.1.1:11211", 1),
+            bucket("10.0.1.2:11211", 2),
+            bucket("10.0.1.3:11212", 1),
+        ];
+        let before = Continuum::ketama(&buckets[..2]);
+
+        let mut added = before.clone();
+        let churn = added.add_bucket(buckets[2]);
+        assert_same(&added, &Continuum::ketama(&buckets));
+        assert_eq!(
+            (churn.added, churn.removed, churn.rebuilt),
+            (added.len(), before.len(), true)
+        );
+        assert!((churn.moved - before.moved(&added)).abs() < 1e-9);
+
+        let mut removed = added.clone();
+        let churn = removed
+            .remove_bucket(&buckets[0].node)
+            .expect("on the ring");
+        assert_same(&removed, &Continuum::ketama(&buckets[1..]));
+        assert_eq!(
+            (churn.added, churn.removed, churn.rebuilt),
+            (removed.len(), added.len(), true)
+        );
+        assert!((churn.moved - added.moved(&removed)).abs() < 1e-9);
+    }
+}
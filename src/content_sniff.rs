@@ -0,0 +1,301 @@
+//! Content-type confusion guard.
+//!
+//! A response whose `Content-Type` does not say what its body is can be
+//! rendered as something else: an "image" uploaded by a user that is really
+//! HTML runs its scripts in the site's origin when a browser sniffs it. On a
+//! route with a [`ContentTypeGuard`] the proxy reads the start of each
+//! response body before sending the header, sniffs it for the signatures of
+//! HTML, XML, SVG, PDF, images, media, archives and WebAssembly, and when the
+//! declared type is not one the content can be served as:
+//!
+//! - [`Mismatch::Correct`] replaces `Content-Type`, with the sniffed type, or
+//!   `application/octet-stream` for HTML, XML and SVG so that correcting
+//!   never makes a browser render content it would not have,
+//! - [`Mismatch::Block`] answers `502` instead,
+//! - [`Mismatch::Log`] only logs it.
+//!
+//! Mismatches are logged in every case, and checked responses get
+//! `X-Content-Type-Options: nosniff`. Content the signatures do not know,
+//! such as plain text, JSON or scripts, is never a mismatch, and neither is
+//! `application/octet-stream`, which makes no claim. Encoded bodies and
+//! partial content are not sniffed.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use http::{Method, StatusCode, header};
+use log::warn;
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde_json::{Value, json};
+
+/// Bytes of the body sniffed, as browsers do.
+pub const SNIFF_LEN: usize = 512;
+
+/// What to do with a response whose content is not its declared type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mismatch {
+    #[default]
+    Correct,
+    Block,
+    Log,
+}
+
+/// A type of content sniffing recognises.
+struct Sniffed {
+    mime: &'static str,
+    /// Whether browsers run scripts in it.
+    active: bool,
+    /// Declared types the content can be served as, besides its own; a
+    /// trailing `*` matches any suffix.
+    compatible: &'static [&'static str],
+}
+
+const HTML: Sniffed = Sniffed {
+    mime: "text/html",
+    active: true,
+    compatible: &["application/xhtml+xml"],
+};
+const XML: Sniffed = Sniffed {
+    mime: "text/xml",
+    active: true,
+    compatible: &["application/xml", "application/*+xml", "image/svg+xml"],
+};
+const SVG: Sniffed = Sniffed {
+    mime: "image/svg+xml",
+    active: true,
+    compatible: &["text/xml", "application/xml"],
+};
+
+/// Binary signatures, by offset.
+const SIGNATURES: &[(usize, &[u8], Sniffed)] = &[
+    (0, b"%PDF-", passive("application/pdf", &[])),
+    (0, b"GIF87a", passive("image/gif", &[])),
+    (0, b"GIF89a", passive("image/gif", &[])),
+    (
+        0,
+        b"\x89PNG\r\n\x1a\n",
+        passive("image/png", &["image/apng"]),
+    ),
+    (
+        0,
+        b"\xff\xd8\xff",
+        passive("image/jpeg", &["image/jpg", "image/pjpeg"]),
+    ),
+    (
+        0,
+        b"BM",
+        passive("image/bmp", &["image/x-bmp", "image/x-ms-bmp"]),
+    ),
+    (
+        0,
+        b"\0\0\x01\0",
+        passive("image/x-icon", &["image/vnd.microsoft.icon"]),
+    ),
+    (
+        0,
+        b"\x1a\x45\xdf\xa3",
+        passive("video/webm", &["audio/webm", "video/x-matroska"]),
+    ),
+    (
+        0,
+        b"OggS\0",
+        passive("application/ogg", &["audio/ogg", "video/ogg"]),
+    ),
+    (0, b"ID3", passive("audio/mpeg", &["audio/mp3"])),
+    (0, b"fLaC", passive("audio/flac", &["audio/x-flac"])),
+    (
+        0,
+        b"PK\x03\x04",
+        passive("application/zip", &["application/*"]),
+    ),
+    (
+        0,
+        b"\x1f\x8b\x08",
+        passive("application/gzip", &["application/x-gzip"]),
+    ),
+    (0, b"\0asm", passive("application/wasm", &[])),
+    // the brand of ISO media files follows
+    (
+        4,
+        b"ftyp",
+        passive(
+            "video/mp4",
+            &["video/*", "audio/*", "image/avif", "image/hei*"],
+        ),
+    ),
+];
+
+/// RIFF files, by the format after their size.
+const RIFF_FORMATS: &[(&[u8], Sniffed)] = &[
+    (b"WEBP", passive("image/webp", &[])),
+    (
+        b"WAVE",
+        passive("audio/wav", &["audio/wave", "audio/x-wav"]),
+    ),
+    (b"AVI ", passive("video/x-msvideo", &["video/avi"])),
+];
+
+const fn passive(mime: &'static str, compatible: &'static [&'static str]) -> Sniffed {
+    Sniffed {
+        mime,
+        active: false,
+        compatible,
+    }
+}
+
+/// The tags an HTML document starts with, after any whitespace, each followed
+/// by a space or `>`, as browsers sniff them.
+const HTML_STARTS: &[&[u8]] = &[
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<script",
+    b"<iframe",
+    b"<h1",
+    b"<div",
+    b"<font",
+    b"<table",
+    b"<a",
+    b"<style",
+    b"<title",
+    b"<b",
+    b"<body",
+    b"<br",
+    b"<p",
+    b"<!--",
+];
+
+/// What content sniffing takes `start`, the start of a body, for.
+fn sniff(start: &[u8]) -> Option<&'static Sniffed> {
+    let text = start.trim_ascii_start();
+    let lower = text.to_ascii_lowercase();
+    for tag in HTML_STARTS {
+        if lower.starts_with(tag) && matches!(lower.get(tag.len()), Some(b' ' | b'>')) {
+            return Some(&HTML);
+        }
+    }
+    if lower.starts_with(b"<?xml") || lower.starts_with(b"<svg") {
+        let svg = lower.windows(4).any(|w| w == b"<svg");
+        return Some(if svg { &SVG } else { &XML });
+    }
+    if start.starts_with(b"RIFF") && start.len() >= 12 {
+        return RIFF_FORMATS
+            .iter()
+            .find(|(format, _)| &start[8..12] == *format)
+            .map(|(_, sniffed)| sniffed);
+    }
+    SIGNATURES
+        .iter()
+        .find(|(at, signature, _)| start.get(*at..).is_some_and(|s| s.starts_with(signature)))
+        .map(|(_, _, sniffed)| sniffed)
+}
+
+/// Whether `declared`, a lowercase type without parameters, names `pattern`.
+fn names(pattern: &str, declared: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => declared.starts_with(prefix),
+        None => declared == pattern,
+    }
+}
+
+/// How a checked response goes on.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Checked {
+    Pass,
+    Block,
+}
+
+pub struct ContentTypeGuard {
+    mismatch: Mismatch,
+    checked: AtomicU64,
+    mismatched: AtomicU64,
+}
+
+impl ContentTypeGuard {
+    pub fn new(mismatch: Mismatch) -> Self {
+        ContentTypeGuard {
+            mismatch,
+            checked: AtomicU64::new(0),
+            mismatched: AtomicU64::new(0),
+        }
+    }
+
+    /// Check `resp`, to `req`, against `start`, the first [`SNIFF_LEN`] bytes
+    /// of its body or all of a shorter one, correcting its `Content-Type` if
+    /// need be.
+    pub(crate) fn check(
+        &self,
+        req: &RequestHeader,
+        resp: &mut ResponseHeader,
+        start: &[u8],
+    ) -> Result<Checked> {
+        let encoded = resp
+            .headers
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|v| v.as_bytes() != b"identity");
+        if start.is_empty()
+            || encoded
+            || req.method == Method::HEAD
+            || resp.status == StatusCode::PARTIAL_CONTENT
+        {
+            return Ok(Checked::Pass);
+        }
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        resp.insert_header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")?;
+        let Some(sniffed) = sniff(start) else {
+            return Ok(Checked::Pass);
+        };
+        let declared = resp
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                v.split(';')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_ascii_lowercase()
+            });
+        let fits = declared.as_deref().is_some_and(|declared| {
+            declared == sniffed.mime
+                || declared == "application/octet-stream"
+                || sniffed.compatible.iter().any(|p| names(p, declared))
+        });
+        if fits {
+            return Ok(Checked::Pass);
+        }
+        self.mismatched.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "{} {} declared {} but looks like {}, {}",
+            req.method,
+            req.uri,
+            declared.as_deref().unwrap_or("no content type"),
+            sniffed.mime,
+            match self.mismatch {
+                Mismatch::Correct => "corrected",
+                Mismatch::Block => "blocked",
+                Mismatch::Log => "passed",
+            }
+        );
+        match self.mismatch {
+            Mismatch::Correct => {
+                let mime = match sniffed.active {
+                    true => "application/octet-stream",
+                    false => sniffed.mime,
+                };
+                resp.insert_header(header::CONTENT_TYPE, mime)?;
+                Ok(Checked::Pass)
+            }
+            Mismatch::Block => Ok(Checked::Block),
+            Mismatch::Log => Ok(Checked::Pass),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "mismatch": format!("{:?}", self.mismatch).to_lowercase(),
+            "checked": self.checked.load(Ordering::Relaxed),
+            "mismatched": self.mismatched.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -0,0 +1,413 @@
+//! Per-request state shared by all proxy phases.
+//!
+//! Every hook gets the same [`ProxyCtx`]; features keep their state here
+//! instead of re-deriving it from the session in each phase. Fields are set by
+//! the phase that knows them and read through accessors everywhere else.
+//!
+//! A context lives in its request's task, so the context itself costs no
+//! allocation of its own; the strings and lists it fills do. A finished
+//! context leaves those buffers to its worker thread, and the next context
+//! the thread creates takes them over, cleared, with the room they grew.
+//! Each thread keeps at most [`MAX_SPARES`], and no buffer that grew past
+//! [`MAX_SPARE_LEN`], so a burst of large requests does not stay resident.
+//! [`recycling`] counts the contexts that found buffers to take over.
+
+use std::cell::RefCell;
+use std::fmt::{self, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use pingora::lb::Backend;
+
+use crate::anomaly::RequestFingerprint;
+use crate::budget::BufferLease;
+use crate::cache::{CacheFill, CacheStatus};
+use crate::connect_race::Racer;
+use crate::fingerprint::TlsFingerprint;
+use crate::flags::Evaluation;
+use crate::geo::GeoMatch;
+use crate::har::Capture;
+use crate::in_flight::Lease;
+use crate::route::Route;
+use crate::sharded::Counter;
+
+/// Most sets of buffers a worker thread keeps for its next contexts.
+pub const MAX_SPARES: usize = 256;
+
+/// Longest buffer kept, in bytes or entries.
+pub const MAX_SPARE_LEN: usize = 1024;
+
+/// The buffers of a finished context, cleared.
+#[derive(Default)]
+struct Spare {
+    request_id: String,
+    flags: Vec<Evaluation>,
+    scratch: String,
+}
+
+thread_local! {
+    static SPARES: RefCell<Vec<Spare>> = const { RefCell::new(Vec::new()) };
+}
+
+/// How many contexts were created, and how many of them took over the
+/// buffers of a finished one.
+#[derive(Default)]
+pub struct Recycling {
+    requests: Counter,
+    recycled: Counter,
+}
+
+impl Recycling {
+    pub fn requests(&self) -> u64 {
+        self.requests.sum()
+    }
+
+    pub fn recycled(&self) -> u64 {
+        self.recycled.sum()
+    }
+}
+
+pub fn recycling() -> &'static Recycling {
+    static RECYCLING: LazyLock<Recycling> = LazyLock::new(Recycling::default);
+    &RECYCLING
+}
+
+/// Points in a request's life, recorded at most once each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mark {
+    /// The request header was read.
+    Received,
+    /// The request filters let the request through to an upstream.
+    Filtered,
+    /// An upstream connection was established, new or reused.
+    Connected,
+    /// The upstream response header arrived.
+    UpstreamResponse,
+}
+
+impl Mark {
+    const COUNT: usize = 4;
+}
+
+/// Phase durations of the latest upstream attempt. A phase is `None` when it
+/// did not happen, e.g. no connect on a reused connection, or has not yet.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UpstreamTiming {
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+    /// From the connection being ready to the response header arriving.
+    pub ttfb: Option<Duration>,
+}
+
+impl UpstreamTiming {
+    /// Write a `Server-Timing` value with the recorded phases and `total` to
+    /// `out`.
+    pub fn server_timing(&self, total: Duration, out: &mut String) {
+        let phases = [
+            ("dns", self.dns),
+            ("connect", self.connect),
+            ("tls", self.tls),
+            ("ttfb", self.ttfb),
+            ("total", Some(total)),
+        ];
+        for (name, d) in phases {
+            let Some(d) = d else { continue };
+            if !out.is_empty() {
+                out.push_str(", ");
+            }
+            let _ = write!(out, "{name};dur={:.3}", d.as_secs_f64() * 1000.0);
+        }
+    }
+}
+
+pub struct ProxyCtx {
+    request_id: String,
+    started: Instant,
+    marks: [Option<Instant>; Mark::COUNT],
+    route: Option<Arc<Route>>,
+    upstream: Option<Backend>,
+    retries: usize,
+    attempt_started: Option<Instant>,
+    attempt_connected: Option<Instant>,
+    timing: UpstreamTiming,
+    consumer: Option<String>,
+    fingerprint: Option<Arc<TlsFingerprint>>,
+    anomaly: Option<RequestFingerprint>,
+    geo: Option<GeoMatch>,
+    flags: Vec<Evaluation>,
+    pub(crate) cache_fill: Option<CacheFill>,
+    /// `Set-Cookie` (re)pinning the session, sent with the response
+    pub(crate) sticky_cookie: Option<String>,
+    /// GraphQL operations of the request, `query:GetUser`, for the log
+    pub(crate) operation: Option<String>,
+    /// Whether the attempt's upstream connection came from the pool
+    pub(crate) upstream_reused: bool,
+    /// Whether the attempt's upstream speaks HTTP/1.x, whose writes are timed
+    pub(crate) upstream_http1: bool,
+    /// Write pending time of the client connection when the request came
+    pub(crate) downstream_write_pending: Option<Duration>,
+    /// The attempt's count on its upstream's in-flight requests
+    pub(crate) upstream_lease: Option<Lease>,
+    /// The race of the attempt's connect, when its upstream has other
+    /// addresses
+    pub(crate) connect_racer: Option<Arc<Racer>>,
+    /// The request's HAR archive, if it is sampled
+    pub(crate) har: Option<Capture>,
+    /// The cache fill's bytes on the route's budget
+    pub(crate) fill_lease: Option<BufferLease>,
+    /// Whether the request came in TLS early data and is handled anyway
+    pub(crate) early_data: bool,
+    /// How the cache took part, `None` if the request was not looked up
+    pub(crate) cache_status: Option<CacheStatus>,
+    /// Whether the response gets the diagnostic headers
+    pub(crate) diagnostics: bool,
+    /// Where the connection was headed before it was redirected to the proxy
+    pub(crate) original_dst: Option<SocketAddr>,
+    /// Room for a header value being built, kept with the other buffers
+    scratch: String,
+}
+
+impl Default for ProxyCtx {
+    /// A context with the buffers of a finished one of this thread, if any.
+    fn default() -> Self {
+        let spare = SPARES.with_borrow_mut(Vec::pop);
+        let recycling = recycling();
+        recycling.requests.add(1);
+        if spare.is_some() {
+            recycling.recycled.add(1);
+        }
+        let spare = spare.unwrap_or_default();
+        ProxyCtx {
+            request_id: spare.request_id,
+            started: Instant::now(),
+            marks: [None; Mark::COUNT],
+            route: None,
+            upstream: None,
+            retries: 0,
+            attempt_started: None,
+            attempt_connected: None,
+            timing: UpstreamTiming::default(),
+            consumer: None,
+            fingerprint: None,
+            anomaly: None,
+            geo: None,
+            flags: spare.flags,
+            cache_fill: None,
+            sticky_cookie: None,
+            operation: None,
+            upstream_reused: false,
+            upstream_http1: false,
+            downstream_write_pending: None,
+            upstream_lease: None,
+            connect_racer: None,
+            har: None,
+            fill_lease: None,
+            early_data: false,
+            cache_status: None,
+            diagnostics: false,
+            original_dst: None,
+            scratch: spare.scratch,
+        }
+    }
+}
+
+impl Drop for ProxyCtx {
+    fn drop(&mut self) {
+        let mut spare = Spare {
+            request_id: std::mem::take(&mut self.request_id),
+            flags: std::mem::take(&mut self.flags),
+            scratch: std::mem::take(&mut self.scratch),
+        };
+        if spare.request_id.capacity() > MAX_SPARE_LEN
+            || spare.flags.capacity() > MAX_SPARE_LEN
+            || spare.scratch.capacity() > MAX_SPARE_LEN
+        {
+            return;
+        }
+        spare.request_id.clear();
+        spare.flags.clear();
+        spare.scratch.clear();
+        // the thread may be exiting, its spares already gone
+        let _ = SPARES.try_with(|spares| {
+            let mut spares = spares.borrow_mut();
+            if spares.len() < MAX_SPARES {
+                spares.push(spare);
+            }
+        });
+    }
+}
+
+impl ProxyCtx {
+    /// The client's `X-Request-Id` if usable, a generated one otherwise.
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub fn set_request_id(&mut self, id: impl fmt::Display) {
+        self.request_id.clear();
+        let _ = write!(self.request_id, "{id}");
+    }
+
+    /// The matched route, `None` until routing ran or when nothing matched.
+    pub fn route(&self) -> Option<&Arc<Route>> {
+        self.route.as_ref()
+    }
+
+    /// Name of the matched route, empty without one.
+    pub fn route_name(&self) -> &str {
+        self.route.as_ref().map_or("", |r| r.name.as_str())
+    }
+
+    pub fn set_route(&mut self, route: Option<Arc<Route>>) {
+        self.route = route;
+    }
+
+    /// The upstream of the latest attempt.
+    pub fn upstream(&self) -> Option<&Backend> {
+        self.upstream.as_ref()
+    }
+
+    /// Record the upstream picked for an attempt, which starts the attempt's
+    /// timing; every pick after the first counts as a retry.
+    pub fn set_upstream(&mut self, upstream: Backend) {
+        if self.upstream.is_some() {
+            self.retries += 1;
+        }
+        self.upstream = Some(upstream);
+        self.attempt_started = Some(Instant::now());
+        self.attempt_connected = None;
+        self.upstream_reused = false;
+        self.upstream_http1 = false;
+        self.upstream_lease = None;
+        self.connect_racer = None;
+        self.timing = UpstreamTiming::default();
+    }
+
+    /// The attempt connected to `upstream`, another address of the upstream
+    /// it was sent to.
+    pub(crate) fn raced_to(&mut self, upstream: Backend) {
+        self.upstream = Some(upstream);
+    }
+
+    /// Record the attempt's connection as ready, with the durations of its
+    /// TCP and TLS handshakes if it was newly established.
+    pub fn upstream_connected(&mut self, connect: Option<Duration>, tls: Option<Duration>) {
+        self.attempt_connected = Some(Instant::now());
+        self.timing.connect = connect;
+        self.timing.tls = tls;
+    }
+
+    /// Record the arrival of the attempt's first response header.
+    pub fn upstream_responded(&mut self) {
+        if self.timing.ttfb.is_none()
+            && let Some(ready) = self.attempt_connected.or(self.attempt_started)
+        {
+            self.timing.ttfb = Some(ready.elapsed());
+        }
+    }
+
+    /// Time since the latest upstream attempt started.
+    pub fn attempt_elapsed(&self) -> Option<Duration> {
+        self.attempt_started.map(|started| started.elapsed())
+    }
+
+    /// Phase durations of the latest upstream attempt.
+    pub fn timing(&self) -> &UpstreamTiming {
+        &self.timing
+    }
+
+    pub fn timing_mut(&mut self) -> &mut UpstreamTiming {
+        &mut self.timing
+    }
+
+    /// How many times the request was sent to another upstream attempt.
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    /// Who sent the request, as established by authentication.
+    pub fn consumer(&self) -> Option<&str> {
+        self.consumer.as_deref()
+    }
+
+    pub fn set_consumer(&mut self, consumer: impl Into<String>) {
+        self.consumer = Some(consumer.into());
+    }
+
+    /// The JA3 and JA4 fingerprints of the client's TLS connection, `None`
+    /// over plain HTTP or when the ClientHello could not be read.
+    pub fn fingerprint(&self) -> Option<&TlsFingerprint> {
+        self.fingerprint.as_deref()
+    }
+
+    pub fn set_fingerprint(&mut self, fingerprint: Option<Arc<TlsFingerprint>>) {
+        self.fingerprint = fingerprint;
+    }
+
+    /// The request's fingerprint and anomaly score, `None` when requests are
+    /// not scored.
+    pub fn anomaly(&self) -> Option<&RequestFingerprint> {
+        self.anomaly.as_ref()
+    }
+
+    pub fn set_anomaly(&mut self, anomaly: RequestFingerprint) {
+        self.anomaly = Some(anomaly);
+    }
+
+    /// The country or ASN rule the client matched, see [`crate::geo`].
+    pub fn geo(&self) -> Option<&GeoMatch> {
+        self.geo.as_ref()
+    }
+
+    pub fn set_geo(&mut self, geo: Option<GeoMatch>) {
+        self.geo = geo;
+    }
+
+    /// The feature flags evaluated for the request, see [`crate::flags`].
+    pub fn flags(&self) -> &[Evaluation] {
+        &self.flags
+    }
+
+    /// The flags to evaluate into, see [`crate::flags::FeatureFlags::evaluate`].
+    pub fn flags_mut(&mut self) -> &mut Vec<Evaluation> {
+        &mut self.flags
+    }
+
+    /// How many requests the request counts as for rate limits, going by its
+    /// anomaly score and where it comes from.
+    pub fn rate_cost(&self) -> f64 {
+        self.anomaly.as_ref().map_or(1.0, |a| a.rate_multiplier())
+            * self.geo.as_ref().map_or(1.0, |g| g.multiplier)
+    }
+
+    /// Record `mark` now, unless it was recorded before.
+    pub fn mark(&mut self, mark: Mark) {
+        self.marks[mark as usize].get_or_insert_with(Instant::now);
+    }
+
+    /// When `mark` was recorded.
+    pub fn marked(&self, mark: Mark) -> Option<Instant> {
+        self.marks[mark as usize]
+    }
+
+    /// Time from the context's creation to `mark`.
+    pub fn since_start(&self, mark: Mark) -> Option<Duration> {
+        self.marked(mark).map(|at| at - self.started)
+    }
+
+    /// Time since the context was created.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The `Server-Timing` value of the latest upstream attempt, built in the
+    /// context's kept buffer.
+    pub fn server_timing(&mut self) -> &str {
+        let total = self.elapsed();
+        self.scratch.clear();
+        self.timing.server_timing(total, &mut self.scratch);
+        &self.scratch
+    }
+}
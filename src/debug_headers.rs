@@ -0,0 +1,84 @@
+//! Diagnostic response headers.
+//!
+//! Requests from the listed internal networks, or carrying the debug token,
+//! get responses annotated with how the proxy answered them, so support
+//! engineers can tell from a browser's developer tools why a page is stale or
+//! slow:
+//!
+//! ```text
+//! X-Cache: HIT | MISS | STALE | REVALIDATED | BYPASS
+//! X-Upstream: 10.0.3.7:8080
+//! X-Retry-Count: 1
+//! ```
+//!
+//! Other clients get none of them, and the token header never reaches the
+//! upstream.
+
+use std::net::IpAddr;
+
+use pingora::http::{RequestHeader, ResponseHeader};
+
+use crate::ctx::ProxyCtx;
+use crate::family::Network;
+
+pub const CACHE_HEADER: &str = "x-cache";
+pub const UPSTREAM_HEADER: &str = "x-upstream";
+pub const RETRY_HEADER: &str = "x-retry-count";
+/// Request header carrying the debug token.
+pub const TOKEN_HEADER: &str = "x-debug-token";
+
+#[derive(Clone, Debug, Default)]
+pub struct DiagnosticHeaders {
+    networks: Vec<Network>,
+    token: Option<String>,
+}
+
+impl DiagnosticHeaders {
+    /// Annotate the responses to clients in `networks`.
+    pub fn new<S: AsRef<str>>(networks: impl IntoIterator<Item = S>) -> Result<Self, String> {
+        let networks = networks
+            .into_iter()
+            .map(|n| Network::parse(n.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(DiagnosticHeaders {
+            networks,
+            token: None,
+        })
+    }
+
+    /// Also annotate the responses to requests with `token` in
+    /// [`TOKEN_HEADER`], from anywhere.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Whether the response to `req` from `client` is annotated.
+    pub fn allows(&self, req: &RequestHeader, client: Option<IpAddr>) -> bool {
+        if client.is_some_and(|ip| self.networks.iter().any(|n| n.contains(ip))) {
+            return true;
+        }
+        let Some(token) = &self.token else {
+            return false;
+        };
+        req.headers
+            .get(TOKEN_HEADER)
+            .is_some_and(|sent| same(sent.as_bytes(), token.as_bytes()))
+    }
+
+    /// Add the diagnostics of the request of `ctx` to `resp`.
+    pub(crate) fn annotate(resp: &mut ResponseHeader, ctx: &ProxyCtx) -> pingora::Result<()> {
+        if let Some(status) = ctx.cache_status {
+            resp.insert_header(CACHE_HEADER, status.as_str())?;
+        }
+        if let Some(upstream) = ctx.upstream() {
+            resp.insert_header(UPSTREAM_HEADER, upstream.addr.to_string())?;
+        }
+        resp.insert_header(RETRY_HEADER, ctx.retries().to_string())
+    }
+}
+
+/// Compares in a time that does not tell how much of the token was right.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,258 @@
+//! Process diagnostics for the admin API.
+//!
+//! Every pingora service runs on its own tokio runtime. [`Runtimes`] learns
+//! them from the services themselves, which call [`Runtimes::observe`] as they
+//! handle requests, and reports their task counts, scheduler queue depths and
+//! busy time. Memory figures and open file descriptors come from the kernel
+//! and, on glibc, from the allocator; allocations per request between reads come from
+//! [`crate::allocations`]. A CPU profile is taken by sampling the CPU time of
+//! every thread of the process over a bounded duration; threads are named
+//! after their service, so the profile shows which service the time goes to.
+//! There is no stack sampling: that needs a profiler build, this works on any
+//! binary.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use serde_json::{Value, json};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use crate::allocations::PerRequest;
+
+/// Longest CPU profile taken.
+pub const MAX_PROFILE: Duration = Duration::from_secs(30);
+
+thread_local! {
+    /// whether the runtime of this thread is known, each thread runs one
+    static OBSERVED: Cell<bool> = const { Cell::new(false) };
+}
+
+struct Runtime {
+    service: String,
+    handle: Handle,
+}
+
+/// The runtimes of the services, and whether a profile is being taken.
+#[derive(Default)]
+pub struct Runtimes {
+    runtimes: RwLock<Vec<Runtime>>,
+    profiling: AtomicBool,
+    allocations: PerRequest,
+}
+
+impl Runtimes {
+    /// Learn the runtime of the calling thread as one of `service`'s; cheap
+    /// once the thread is known.
+    pub fn observe(&self, service: &str) {
+        if OBSERVED.get() {
+            return;
+        }
+        OBSERVED.set(true);
+        let Ok(handle) = Handle::try_current() else {
+            return;
+        };
+        let mut runtimes = self.runtimes.write().unwrap();
+        if runtimes.iter().all(|r| r.handle.id() != handle.id()) {
+            runtimes.push(Runtime {
+                service: service.to_string(),
+                handle,
+            });
+        }
+    }
+
+    /// Busy time of every worker of every runtime, by runtime and worker.
+    fn busy(&self) -> Vec<Vec<Duration>> {
+        self.runtimes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|r| {
+                let metrics = r.handle.metrics();
+                (0..metrics.num_workers())
+                    .map(|w| metrics.worker_total_busy_duration(w))
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let runtimes: Vec<Value> = self
+            .runtimes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|r| {
+                let metrics = r.handle.metrics();
+                let busy: Duration = (0..metrics.num_workers())
+                    .map(|w| metrics.worker_total_busy_duration(w))
+                    .sum();
+                json!({
+                    "service": r.service,
+                    "id": r.handle.id().to_string(),
+                    "flavor": match r.handle.runtime_flavor() {
+                        RuntimeFlavor::CurrentThread => "current_thread",
+                        _ => "multi_thread",
+                    },
+                    "workers": metrics.num_workers(),
+                    "alive_tasks": metrics.num_alive_tasks(),
+                    "global_queue_depth": metrics.global_queue_depth(),
+                    "busy_ms": busy.as_millis() as u64,
+                })
+            })
+            .collect();
+        json!({
+            "runtimes": runtimes,
+            "memory": memory(),
+            "allocator": allocator(),
+            "open_fds": open_fds(),
+            "allocations": self.allocations.to_json(),
+        })
+    }
+
+    /// The CPU time of the process's threads over `duration`, by thread name,
+    /// and the busy share of every runtime; `None` while another profile is
+    /// taken.
+    pub async fn profile(&self, duration: Duration) -> Option<Value> {
+        if self.profiling.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        let duration = duration.min(MAX_PROFILE);
+        let started = Instant::now();
+        let threads_before = thread_cpu();
+        let busy_before = self.busy();
+        tokio::time::sleep(duration).await;
+        let threads_after = thread_cpu();
+        let busy_after = self.busy();
+        let elapsed = started.elapsed();
+        self.profiling.store(false, Ordering::Relaxed);
+
+        // threads started during the profile count from zero
+        let mut by_name: BTreeMap<String, (Duration, usize)> = BTreeMap::new();
+        for (tid, (name, after)) in &threads_after {
+            let before = threads_before.get(tid).map_or(Duration::ZERO, |(_, b)| *b);
+            let entry = by_name.entry(name.clone()).or_default();
+            entry.0 += after.saturating_sub(before);
+            entry.1 += 1;
+        }
+        let mut threads: Vec<(String, Duration, usize)> = by_name
+            .into_iter()
+            .map(|(name, (cpu, count))| (name, cpu, count))
+            .collect();
+        threads.sort_by_key(|t| std::cmp::Reverse(t.1));
+        let total: Duration = threads.iter().map(|t| t.1).sum();
+        let share = |cpu: Duration| cpu.as_secs_f64() / elapsed.as_secs_f64();
+        let threads: Vec<Value> = threads
+            .into_iter()
+            .map(|(name, cpu, count)| {
+                json!({
+                    "name": name,
+                    "threads": count,
+                    "cpu_ms": cpu.as_secs_f64() * 1000.0,
+                    "cores": share(cpu),
+                })
+            })
+            .collect();
+
+        let runtimes = self.runtimes.read().unwrap();
+        let runtimes: Vec<Value> = runtimes
+            .iter()
+            .zip(busy_before.iter().zip(&busy_after))
+            .map(|(r, (before, after))| {
+                let busy: Duration = after
+                    .iter()
+                    .zip(before)
+                    .map(|(a, b)| a.saturating_sub(*b))
+                    .sum();
+                let workers = after.len().max(1) as f64;
+                json!({
+                    "service": r.service,
+                    "busy_ratio": share(busy) / workers,
+                })
+            })
+            .collect();
+        Some(json!({
+            "duration_ms": elapsed.as_millis() as u64,
+            "cpu_ms": total.as_secs_f64() * 1000.0,
+            "cores": share(total),
+            "threads": threads,
+            "runtimes": runtimes,
+        }))
+    }
+}
+
+/// Memory of the process as the kernel counts it, in bytes.
+fn memory() -> Value {
+    let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+        return Value::Null;
+    };
+    let kib = |field: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+            .and_then(|v| v.trim().strip_suffix("kB")?.trim().parse::<u64>().ok())
+            .map(|kib| kib * 1024)
+    };
+    json!({
+        "rss": kib("VmRSS"),
+        "rss_peak": kib("VmHWM"),
+        "data": kib("VmData"),
+        "threads": status
+            .lines()
+            .find_map(|line| line.strip_prefix("Threads:"))
+            .and_then(|v| v.trim().parse::<u64>().ok()),
+    })
+}
+
+/// File descriptors the process has open, sockets included.
+fn open_fds() -> Option<usize> {
+    std::fs::read_dir("/proc/self/fd")
+        .ok()
+        .map(|fds| fds.count())
+}
+
+/// Heap figures of the glibc allocator, in bytes.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn allocator() -> Value {
+    // SAFETY: mallinfo2 only reads the allocator's counters
+    let info = unsafe { libc::mallinfo2() };
+    json!({
+        "name": "glibc",
+        "arena": info.arena,
+        "mmapped": info.hblkhd,
+        "in_use": info.uordblks,
+        "free": info.fordblks,
+        "releasable": info.keepcost,
+    })
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn allocator() -> Value {
+    Value::Null
+}
+
+/// CPU time of every thread of the process, with its name, by thread id.
+fn thread_cpu() -> BTreeMap<u64, (String, Duration)> {
+    let Ok(tasks) = std::fs::read_dir("/proc/self/task") else {
+        return BTreeMap::new();
+    };
+    // SAFETY: sysconf has no preconditions
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+    tasks
+        .filter_map(|task| {
+            let task = task.ok()?;
+            let tid = task.file_name().to_str()?.parse().ok()?;
+            let stat = std::fs::read_to_string(task.path().join("stat")).ok()?;
+            // `tid (name) state ...`, the name may hold spaces and parens
+            let (head, rest) = stat.rsplit_once(") ")?;
+            let name = head.split_once(" (")?.1.to_string();
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            let utime: u64 = fields.get(11)?.parse().ok()?;
+            let stime: u64 = fields.get(12)?.parse().ok()?;
+            let cpu = Duration::from_secs_f64((utime + stime) as f64 / ticks);
+            Some((tid, (name, cpu)))
+        })
+        .collect()
+}
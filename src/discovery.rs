@@ -0,0 +1,179 @@
+//! Service discovery backends for the upstream clusters.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use http::Extensions;
+use log::warn;
+use pingora::lb::Backend;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::{Error, ErrorType, Result};
+
+use crate::dns::Resolver;
+
+mod docker;
+#[cfg(feature = "aws")]
+mod ec2;
+mod file;
+
+pub use docker::{DockerConfig, DockerWatcher};
+#[cfg(feature = "aws")]
+pub use ec2::{Ec2Discovery, Ec2Selector};
+pub use file::{BuildRoutes, ConfigFile, FileDiscovery, HangupReload};
+
+/// The upstream a backend is one address of, `host:port` as it was given.
+/// Backends of one upstream are interchangeable, see [`crate::connect_race`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamName(pub Arc<str>);
+
+impl UpstreamName {
+    pub(crate) fn of(host: &str, port: u16) -> Self {
+        UpstreamName(Arc::from(format!("{host}:{port}")))
+    }
+}
+
+/// Tag `backend` with the upstream it is an address of.
+pub(crate) fn tagged(mut backend: Backend, name: &UpstreamName) -> Backend {
+    backend.ext.insert(name.clone());
+    backend
+}
+
+/// Upstreams given as `host:port`, resolved through the caching [`Resolver`]
+/// on every discovery refresh. Every address of a name becomes a backend.
+pub struct DnsDiscovery {
+    resolver: Arc<Resolver>,
+    targets: Vec<(String, u16)>,
+}
+
+impl DnsDiscovery {
+    pub fn new<T: AsRef<str>>(
+        resolver: Arc<Resolver>,
+        targets: impl IntoIterator<Item = T>,
+    ) -> Result<Box<Self>> {
+        let targets = targets
+            .into_iter()
+            .map(|t| split_host_port(t.as_ref()))
+            .collect::<Result<_>>()?;
+        Ok(Box::new(DnsDiscovery { resolver, targets }))
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for DnsDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let mut backends = BTreeSet::new();
+        let mut last_error = None;
+        for (host, port) in &self.targets {
+            let name = UpstreamName::of(host, *port);
+            match self.resolver.lookup_ip(host).await {
+                Ok(addrs) => backends.extend(addrs.iter().map(|ip| {
+                    let backend = Backend {
+                        addr: SocketAddr::Inet((*ip, *port).into()),
+                        weight: 1,
+                        ext: Extensions::new(),
+                    };
+                    tagged(backend, &name)
+                })),
+                Err(e) => {
+                    warn!("discovery of {host}:{port} failed: {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        // with nothing resolved keep the previous backends rather than none
+        match last_error {
+            Some(e) if backends.is_empty() => Err(e),
+            _ => Ok((backends, HashMap::new())),
+        }
+    }
+}
+
+/// Upstreams published as DNS `SRV` records, as Consul DNS and Kubernetes
+/// headless services do.
+///
+/// Each record's target is resolved to its addresses, which become backends
+/// with the record's port and weight. Only the lowest priority that has any
+/// addresses is used; higher priorities are fallbacks for when it is empty.
+/// Records are re-read once their TTL expired, and since the load balancer
+/// only swaps backends that changed, unchanged ones keep their health state.
+pub struct SrvDiscovery {
+    resolver: Arc<Resolver>,
+    names: Vec<String>,
+}
+
+impl SrvDiscovery {
+    pub fn new<T: Into<String>>(
+        resolver: Arc<Resolver>,
+        names: impl IntoIterator<Item = T>,
+    ) -> Box<Self> {
+        let names = names.into_iter().map(Into::into).collect();
+        Box::new(SrvDiscovery { resolver, names })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for SrvDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let mut by_priority: BTreeMap<u16, BTreeSet<Backend>> = BTreeMap::new();
+        let mut last_error = None;
+        for name in &self.names {
+            let records = match self.resolver.lookup_srv(name).await {
+                Ok(records) => records,
+                Err(e) => {
+                    warn!("SRV discovery of {name} failed: {e}");
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            // a target of "." means the service is decidedly not available
+            for srv in records.iter().filter(|r| !r.target.is_empty()) {
+                let addrs = match self.resolver.lookup_ip(&srv.target).await {
+                    Ok(addrs) => addrs,
+                    Err(e) => {
+                        warn!("resolving SRV target {} of {name} failed: {e}", srv.target);
+                        last_error = Some(e);
+                        continue;
+                    }
+                };
+                let name = UpstreamName::of(&srv.target, srv.port);
+                let group = by_priority.entry(srv.priority).or_default();
+                group.extend(addrs.iter().map(|ip| {
+                    let backend = Backend {
+                        addr: SocketAddr::Inet((*ip, srv.port).into()),
+                        // weight 0 records are still picked, just rarely
+                        weight: usize::from(srv.weight.max(1)),
+                        ext: Extensions::new(),
+                    };
+                    tagged(backend, &name)
+                }));
+            }
+        }
+        match (by_priority.into_values().next(), last_error) {
+            (Some(backends), _) => Ok((backends, HashMap::new())),
+            (None, Some(e)) => Err(e),
+            (None, None) => Ok((BTreeSet::new(), HashMap::new())),
+        }
+    }
+}
+
+/// Split `host:port`, with IPv6 literals in brackets.
+pub(crate) fn split_host_port(target: &str) -> Result<(String, u16)> {
+    let invalid = || {
+        Error::explain(
+            ErrorType::InternalError,
+            format!("invalid upstream {target}"),
+        )
+    };
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    let port = port.parse().map_err(|_| invalid())?;
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port))
+}
@@ -0,0 +1,304 @@
+//! Routes and clusters from the labels of local Docker containers.
+//!
+//! Running containers opt in with labels, shown here with the default
+//! `proxy-rs` prefix:
+//!
+//! - `proxy-rs.route`: path prefix to route to the container, e.g. `/app/`
+//! - `proxy-rs.host`: host to route, optional
+//! - `proxy-rs.port`: container port to send to, defaults to the first exposed
+//! - `proxy-rs.network`: network whose address to use, defaults to the first
+//! - `proxy-rs.name`: route name, defaults to `docker:<host><prefix>`
+//! - `proxy-rs.gateway`: API gateway presets of the route, e.g. `api,cors`,
+//!   see [`crate::gateway`]
+//! - `proxy-rs.cors-origins`: comma separated origins the `cors` preset
+//!   allows, any without
+//!
+//! Containers with the same host and prefix form one cluster, with the
+//! presets of the container whose route name comes first. The routes are
+//! rebuilt on every container lifecycle event read from the Docker socket,
+//! and reloaded as the [`RouteSource::Docker`] routes of the
+//! [`RouterVersions`], next to the configured ones.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{info, warn};
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::lb::LoadBalancer;
+use pingora::server::ShutdownWatch;
+use pingora::services::ServiceReadyNotifier;
+use pingora::services::background::BackgroundService;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::{Error, ErrorType, OrErr, Result};
+use serde_json::Value;
+
+use crate::gateway::{Cors, Gateway};
+use crate::rollback::{RouteSource, RouterVersions};
+use crate::route::Route;
+use crate::subrequest;
+
+const DOCKER_ERROR: ErrorType = ErrorType::Custom("DockerError");
+
+/// Container listings larger than this are refused.
+const MAX_LISTING: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct DockerConfig {
+    /// Path of the Docker API socket.
+    pub socket: String,
+    /// Prefix of the labels read, without the trailing dot.
+    pub label_prefix: String,
+    /// Pause before reconnecting after the event stream broke.
+    pub retry: Duration,
+}
+
+impl Default for DockerConfig {
+    fn default() -> Self {
+        DockerConfig {
+            socket: "/var/run/docker.sock".to_string(),
+            label_prefix: "proxy-rs".to_string(),
+            retry: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Background service keeping the Docker routes of [`RouterVersions`] up
+/// to date.
+pub struct DockerWatcher {
+    config: DockerConfig,
+    versions: Arc<RouterVersions>,
+    connector: Connector,
+}
+
+impl DockerWatcher {
+    pub fn new(config: DockerConfig, versions: Arc<RouterVersions>) -> Self {
+        DockerWatcher {
+            config,
+            versions,
+            connector: Connector::new(None),
+        }
+    }
+
+    fn label(&self, name: &str) -> String {
+        format!("{}.{name}", self.config.label_prefix)
+    }
+
+    fn peer(&self) -> Result<HttpPeer> {
+        HttpPeer::new_uds(&self.config.socket, false, String::new())
+    }
+
+    fn request(&self, path: &str, filters: &Value) -> Result<RequestHeader> {
+        let uri = format!("{path}?filters={}", percent_encode(&filters.to_string()));
+        let mut req = RequestHeader::build("GET", uri.as_bytes(), None)?;
+        // the daemon rejects HTTP/1.1 requests without a Host
+        req.insert_header("Host", "docker")?;
+        Ok(req)
+    }
+
+    /// List the labelled containers and install their routes.
+    async fn sync(&self) -> Result<()> {
+        let filters = serde_json::json!({ "label": [self.label("route")] });
+        let req = self.request("/containers/json", &filters)?;
+        let Some(listing) =
+            subrequest::fetch(&self.connector, &self.peer()?, req, MAX_LISTING).await?
+        else {
+            return Error::e_explain(DOCKER_ERROR, "container listing too large");
+        };
+        if listing.header.status != 200 {
+            return Error::e_explain(
+                DOCKER_ERROR,
+                format!("listing containers: {}", listing.header.status),
+            );
+        }
+        let containers: Vec<Value> =
+            serde_json::from_slice(&listing.body).or_err(DOCKER_ERROR, "parsing containers")?;
+
+        type Cluster = (String, Option<Arc<Gateway>>, Vec<String>);
+        let mut clusters: BTreeMap<(Option<String>, String), Cluster> = BTreeMap::new();
+        for container in &containers {
+            let Some(target) = self.target(container) else {
+                continue;
+            };
+            let (name, gateway, addrs) = clusters
+                .entry((target.host.clone(), target.prefix.clone()))
+                .or_insert_with(|| (target.name.clone(), target.gateway.clone(), Vec::new()));
+            if target.name < *name {
+                // stable across listings whatever the container order
+                *name = target.name.clone();
+                *gateway = target.gateway.clone();
+            }
+            addrs.push(target.addr);
+        }
+
+        let mut routes = Vec::new();
+        for ((host, prefix), (name, gateway, addrs)) in clusters {
+            let upstreams = LoadBalancer::try_from_iter(&addrs)
+                .or_err(DOCKER_ERROR, "building docker cluster")?;
+            info!("docker route {name}: {addrs:?}");
+            let mut route = Route::new(name, prefix);
+            route.host = host;
+            route.upstreams = Some(Arc::new(upstreams));
+            route.gateway = gateway;
+            routes.push(route);
+        }
+        self.versions
+            .reload(RouteSource::Docker, routes)
+            .or_err(DOCKER_ERROR, "installing docker routes")?;
+        Ok(())
+    }
+
+    /// What a container's labels ask for, `None` if it cannot be routed.
+    fn target(&self, container: &Value) -> Option<Target> {
+        let labels = &container["Labels"];
+        let label = |name: &str| labels[self.label(name)].as_str();
+        let id = container["Id"].as_str().unwrap_or_default();
+
+        let prefix = label("route")?.to_string();
+        let host = label("host").map(str::to_string);
+        let port = match label("port") {
+            Some(port) => port.parse::<u16>().ok(),
+            None => container["Ports"]
+                .as_array()?
+                .iter()
+                .find_map(|p| p["PrivatePort"].as_u64())
+                .and_then(|p| u16::try_from(p).ok()),
+        };
+        let Some(port) = port else {
+            warn!("docker container {id} has no usable port");
+            return None;
+        };
+
+        let networks = container["NetworkSettings"]["Networks"].as_object()?;
+        let ip = match label("network") {
+            Some(network) => networks.get(network)?["IPAddress"].as_str(),
+            None => networks
+                .values()
+                .filter_map(|n| n["IPAddress"].as_str())
+                .find(|ip| !ip.is_empty()),
+        };
+        let Some(ip) = ip.filter(|ip| !ip.is_empty()) else {
+            warn!("docker container {id} has no address");
+            return None;
+        };
+        let addr = if ip.contains(':') {
+            format!("[{ip}]:{port}")
+        } else {
+            format!("{ip}:{port}")
+        };
+
+        let gateway = match label("gateway").map(str::parse::<Gateway>) {
+            None => None,
+            Some(Ok(gateway)) => Some(gateway),
+            Some(Err(e)) => {
+                // routed without them, the service would be exposed as it
+                // did not ask to be
+                warn!("docker container {id}: {e}");
+                return None;
+            }
+        };
+        let gateway = match (gateway, label("cors-origins")) {
+            (Some(gateway), Some(origins)) if gateway.cors().is_some() => {
+                let origins = origins.split(',').map(str::trim).filter(|o| !o.is_empty());
+                Some(gateway.with_cors(Cors::origins(origins)))
+            }
+            (gateway, _) => gateway,
+        };
+
+        let name = label("name").map_or_else(
+            || format!("docker:{}{prefix}", host.as_deref().unwrap_or_default()),
+            str::to_string,
+        );
+        Some(Target {
+            name,
+            host,
+            prefix,
+            addr,
+            gateway: gateway.map(Arc::new),
+        })
+    }
+
+    /// Follow the event stream, resyncing on every container event, until the
+    /// stream ends.
+    async fn watch(&self) -> Result<()> {
+        let filters = serde_json::json!({
+            "type": ["container"],
+            "event": ["start", "stop", "die", "destroy", "pause", "unpause"],
+        });
+        let req = self.request("/events", &filters)?;
+        let (mut session, _) = self.connector.get_http_session(&self.peer()?).await?;
+        session.write_request_header(Box::new(req)).await?;
+        session.finish_request_body().await?;
+        session.read_response_header().await?;
+        // events before the subscription are covered by this listing
+        self.sync().await?;
+        while let Some(event) = session.read_response_body().await? {
+            if !event.iter().all(u8::is_ascii_whitespace) {
+                self.sync().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Target {
+    name: String,
+    host: Option<String>,
+    prefix: String,
+    addr: String,
+    gateway: Option<Arc<Gateway>>,
+}
+
+#[async_trait]
+impl BackgroundService for DockerWatcher {
+    async fn start_with_ready_notifier(
+        &self,
+        shutdown: ShutdownWatch,
+        ready_notifier: ServiceReadyNotifier,
+    ) {
+        // dependents start with the routes of the containers already running
+        if let Err(e) = self.sync().await {
+            warn!("docker discovery: {e}");
+        }
+        ready_notifier.notify_ready();
+        self.follow(shutdown).await
+    }
+
+    async fn start(&self, shutdown: ShutdownWatch) {
+        self.follow(shutdown).await
+    }
+}
+
+impl DockerWatcher {
+    /// Watch events until shutdown, reconnecting whenever the stream breaks.
+    async fn follow(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                res = self.watch() => match res {
+                    Ok(()) => warn!("docker event stream ended"),
+                    Err(e) => warn!("docker discovery: {e}"),
+                },
+            }
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(self.config.retry) => {}
+            }
+        }
+    }
+}
+
+/// Percent-encode everything but unreserved characters, for a query value.
+fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() * 3);
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
@@ -0,0 +1,194 @@
+//! EC2 instance discovery through the AWS API.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use aws_sdk_autoscaling::types::LifecycleState;
+use aws_sdk_ec2::error::DisplayErrorContext;
+use aws_sdk_ec2::types::Filter;
+use http::Extensions;
+use pingora::lb::Backend;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::{Error, ErrorType, Result};
+use tokio::sync::OnceCell;
+
+use crate::drain::Draining;
+
+const AWS_ERROR: ErrorType = ErrorType::Custom("AWSError");
+
+/// Which instances make up the cluster.
+#[derive(Clone, Debug)]
+pub enum Ec2Selector {
+    /// The in-service instances of an Auto Scaling Group. Instances on their
+    /// way out of the group are kept as [`Draining`] backends.
+    AutoScalingGroup(String),
+    /// Instances carrying all of these `(key, value)` tags.
+    Tags(Vec<(String, String)>),
+}
+
+struct Clients {
+    ec2: aws_sdk_ec2::Client,
+    autoscaling: aws_sdk_autoscaling::Client,
+}
+
+/// Running EC2 instances, addressed by private IP and a fixed port.
+///
+/// Credentials and region come from the standard AWS environment (variables,
+/// profile, instance role). The API is queried at most once per `refresh`,
+/// in between the previous answer is served.
+pub struct Ec2Discovery {
+    selector: Ec2Selector,
+    port: u16,
+    refresh: Duration,
+    clients: OnceCell<Clients>,
+    last: Mutex<Option<(Instant, BTreeSet<Backend>)>>,
+}
+
+impl Ec2Discovery {
+    pub fn new(selector: Ec2Selector, port: u16, refresh: Duration) -> Box<Self> {
+        Box::new(Ec2Discovery {
+            selector,
+            port,
+            refresh,
+            clients: OnceCell::new(),
+            last: Mutex::new(None),
+        })
+    }
+
+    async fn clients(&self) -> &Clients {
+        self.clients
+            .get_or_init(|| async {
+                let config = aws_config::load_from_env().await;
+                Clients {
+                    ec2: aws_sdk_ec2::Client::new(&config),
+                    autoscaling: aws_sdk_autoscaling::Client::new(&config),
+                }
+            })
+            .await
+    }
+
+    /// The private IPs of the cluster, with whether each instance is leaving.
+    async fn private_ips(&self) -> Result<Vec<(IpAddr, bool)>> {
+        let clients = self.clients().await;
+        let mut request = clients
+            .ec2
+            .describe_instances()
+            .filters(filter("instance-state-name", "running"));
+        let mut leaving = HashSet::new();
+        match &self.selector {
+            Ec2Selector::AutoScalingGroup(name) => {
+                let instances = self.group_instances(clients, name).await?;
+                if instances.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let mut ids = Vec::with_capacity(instances.len());
+                for (id, is_leaving) in instances {
+                    if is_leaving {
+                        leaving.insert(id.clone());
+                    }
+                    ids.push(id);
+                }
+                request = request.set_instance_ids(Some(ids));
+            }
+            Ec2Selector::Tags(tags) => {
+                for (key, value) in tags {
+                    request = request.filters(filter(&format!("tag:{key}"), value));
+                }
+            }
+        }
+
+        let mut ips = Vec::new();
+        let mut next_token = None;
+        loop {
+            let page = request
+                .clone()
+                .set_next_token(next_token)
+                .send()
+                .await
+                .map_err(|e| aws_error("describing instances", e))?;
+            let instances = page.reservations().iter().flat_map(|r| r.instances());
+            ips.extend(instances.filter_map(|i| {
+                let ip = i.private_ip_address()?.parse::<IpAddr>().ok()?;
+                let is_leaving = i.instance_id().is_some_and(|id| leaving.contains(id));
+                Some((ip, is_leaving))
+            }));
+            next_token = page.next_token().map(str::to_string);
+            if next_token.is_none() {
+                return Ok(ips);
+            }
+        }
+    }
+
+    /// The instance IDs of the group that are in service or leaving it, with
+    /// whether they are leaving.
+    async fn group_instances(&self, clients: &Clients, name: &str) -> Result<Vec<(String, bool)>> {
+        let groups = clients
+            .autoscaling
+            .describe_auto_scaling_groups()
+            .auto_scaling_group_names(name)
+            .send()
+            .await
+            .map_err(|e| aws_error("describing auto scaling group", e))?;
+        let Some(group) = groups.auto_scaling_groups().first() else {
+            return Error::e_explain(AWS_ERROR, format!("no auto scaling group {name}"));
+        };
+        Ok(group
+            .instances()
+            .iter()
+            .filter_map(|i| {
+                let is_leaving = match i.lifecycle_state()? {
+                    LifecycleState::InService => false,
+                    LifecycleState::Terminating
+                    | LifecycleState::TerminatingWait
+                    | LifecycleState::Detaching
+                    | LifecycleState::EnteringStandby => true,
+                    _ => return None,
+                };
+                Some((i.instance_id()?.to_string(), is_leaving))
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for Ec2Discovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        if let Some((at, backends)) = &*self.last.lock().unwrap()
+            && at.elapsed() < self.refresh
+        {
+            return Ok((backends.clone(), HashMap::new()));
+        }
+
+        let backends: BTreeSet<_> = self
+            .private_ips()
+            .await?
+            .into_iter()
+            .map(|(ip, is_leaving)| {
+                let mut ext = Extensions::new();
+                if is_leaving {
+                    ext.insert(Draining);
+                }
+                Backend {
+                    addr: SocketAddr::Inet((ip, self.port).into()),
+                    weight: 1,
+                    ext,
+                }
+            })
+            .collect();
+        *self.last.lock().unwrap() = Some((Instant::now(), backends.clone()));
+        Ok((backends, HashMap::new()))
+    }
+}
+
+fn filter(name: &str, value: &str) -> Filter {
+    Filter::builder().name(name).values(value).build()
+}
+
+fn aws_error(context: &'static str, e: impl std::error::Error) -> Box<Error> {
+    // the plain Display of SDK errors leaves out the cause
+    Error::because(AWS_ERROR, context, DisplayErrorContext(e).to_string())
+}
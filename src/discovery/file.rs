@@ -0,0 +1,240 @@
+//! Upstreams of the pools of the configuration file, see [`crate::config`],
+//! reloaded on `SIGHUP`.
+//!
+//! Names are resolved through the caching [`Resolver`] on every discovery
+//! refresh, as with [`super::DnsDiscovery`], and every address of a name
+//! becomes a backend with the weight the upstream has in the file. The file
+//! is only read again on `SIGHUP`, when [`HangupReload`] parses it, has
+//! the load balancers of the pools discover at once, and reloads the routes
+//! built from it. A load balancer swaps
+//! its backends and their selection ring in one step, so requests in flight
+//! finish on the connections they have, upstreams kept keep their health,
+//! and rings built from the backends, as those of
+//! [`crate::replica::FanOut`], follow on their next use. A file that does
+//! not parse, or lacks a pool the proxy has a cluster of, is refused and the
+//! previous upstreams stay.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use http::Extensions;
+use log::{info, warn};
+use pingora::Result;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::{Backend, LoadBalancer, selection::RoundRobin};
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use tokio::signal::unix::{SignalKind, signal};
+
+use super::{UpstreamName, tagged};
+use crate::config::{Config, Upstream};
+use crate::dns::Resolver;
+use crate::rollback::{RouteSource, RouterVersions};
+use crate::route::Route;
+
+/// The routes of a configuration, or why it has none.
+pub type BuildRoutes = Arc<dyn Fn(&Config) -> Result<Vec<Route>, String> + Send + Sync>;
+
+/// The configuration file as last read.
+pub struct ConfigFile {
+    path: PathBuf,
+    config: RwLock<Arc<Config>>,
+}
+
+impl ConfigFile {
+    /// `config`, as read from `path`.
+    pub fn new(path: impl Into<PathBuf>, config: Config) -> Arc<Self> {
+        Arc::new(ConfigFile {
+            path: path.into(),
+            config: RwLock::new(Arc::new(config)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Read the file again, keeping the configuration as it is if it does
+    /// not parse or drops a pool. Whether the upstreams of a pool changed.
+    pub fn reload(&self) -> Result<bool, String> {
+        let config = Config::load(&self.path)?;
+        let mut current = self.config.write().unwrap();
+        if let Some(pool) = current
+            .pools
+            .iter()
+            .find(|p| config.pool(&p.name).is_none())
+        {
+            return Err(format!(
+                "config {}: no pool {}, removing a pool takes a restart",
+                self.path.display(),
+                pool.name
+            ));
+        }
+        let changed = current.pools.iter().any(|p| {
+            config
+                .pool(&p.name)
+                .is_some_and(|n| n.upstreams != p.upstreams)
+        });
+        *current = Arc::new(config);
+        Ok(changed)
+    }
+
+    fn upstreams(&self, pool: &str) -> Vec<Upstream> {
+        let config = self.config();
+        config
+            .pool(pool)
+            .map(|p| p.upstreams.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// The weighted upstreams of a pool of a [`ConfigFile`], resolved.
+pub struct FileDiscovery {
+    resolver: Arc<Resolver>,
+    file: Arc<ConfigFile>,
+    pool: String,
+}
+
+impl FileDiscovery {
+    pub fn new(resolver: Arc<Resolver>, file: Arc<ConfigFile>, pool: &str) -> Box<Self> {
+        Box::new(FileDiscovery {
+            resolver,
+            file,
+            pool: pool.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for FileDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let mut backends = BTreeSet::new();
+        let mut last_error = None;
+        for upstream in self.file.upstreams(&self.pool) {
+            let name = UpstreamName::of(&upstream.host, upstream.port);
+            match self.resolver.lookup_ip(&upstream.host).await {
+                Ok(addrs) => backends.extend(addrs.iter().map(|ip| {
+                    let backend = Backend {
+                        addr: SocketAddr::Inet((*ip, upstream.port).into()),
+                        weight: upstream.weight,
+                        ext: Extensions::new(),
+                    };
+                    tagged(backend, &name)
+                })),
+                Err(e) => {
+                    warn!(
+                        "discovery of {}:{} failed: {e}",
+                        upstream.host, upstream.port
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        // with nothing resolved keep the previous backends rather than none
+        match last_error {
+            Some(e) if backends.is_empty() => Err(e),
+            _ => Ok((backends, HashMap::new())),
+        }
+    }
+}
+
+/// Background service reloading a [`ConfigFile`] on `SIGHUP` and updating
+/// the clusters of its pools, and the routes built from it.
+pub struct HangupReload {
+    file: Arc<ConfigFile>,
+    /// by pool name
+    clusters: Vec<(String, Arc<LoadBalancer<RoundRobin>>)>,
+    routes: Option<(Arc<RouterVersions>, BuildRoutes)>,
+}
+
+impl HangupReload {
+    pub fn new(
+        file: Arc<ConfigFile>,
+        clusters: Vec<(String, Arc<LoadBalancer<RoundRobin>>)>,
+    ) -> Self {
+        HangupReload {
+            file,
+            clusters,
+            routes: None,
+        }
+    }
+
+    /// Reload the [`RouteSource::Config`] routes of `versions` as `build`
+    /// makes them from the file read again.
+    pub fn with_routes(mut self, versions: Arc<RouterVersions>, build: BuildRoutes) -> Self {
+        self.routes = Some((versions, build));
+        self
+    }
+
+    async fn reload(&self) {
+        let changed = match self.file.reload() {
+            Ok(changed) => changed,
+            Err(e) => {
+                warn!("not reloading the upstreams: {e}");
+                return;
+            }
+        };
+        if let Some((versions, build)) = &self.routes {
+            let config = self.file.config();
+            match build(&config).and_then(|routes| versions.reload(RouteSource::Config, routes)) {
+                Ok(version) => info!(
+                    "routes of {} loaded as version {version}",
+                    self.file.path().display()
+                ),
+                Err(e) => warn!("not reloading the routes: {e}"),
+            }
+        }
+        if !changed {
+            info!("upstreams of {} unchanged", self.file.path().display());
+            return;
+        }
+        for (pool, cluster) in &self.clusters {
+            let before = cluster.backends().get_backend();
+            if let Err(e) = cluster.update().await {
+                warn!("discovery of the reloaded upstreams of pool {pool} failed: {e}");
+                continue;
+            }
+            let after = cluster.backends().get_backend();
+            let added: Vec<String> = after
+                .difference(&before)
+                .map(|b| b.addr.to_string())
+                .collect();
+            let removed: Vec<String> = before
+                .difference(&after)
+                .map(|b| b.addr.to_string())
+                .collect();
+            info!(
+                "reloaded pool {pool}: {} upstreams, added [{}], removed [{}]",
+                after.len(),
+                added.join(", "),
+                removed.join(", ")
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for HangupReload {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("cannot reload the upstreams on SIGHUP: {e}");
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = hangups.recv() => self.reload().await,
+            }
+        }
+    }
+}
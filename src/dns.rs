@@ -0,0 +1,219 @@
+//! Caching DNS resolver for hostname upstreams.
+//!
+//! Answers are cached for their record TTL, clamped to a configured range, so
+//! discovery refreshes do not hit the nameservers more often than the zone
+//! asks for. Names that do not exist are cached too, for the negative TTL.
+//! When a refresh fails for other reasons (timeouts, `SERVFAIL`) the expired
+//! answer keeps being served for up to `max_stale`, so a nameserver outage does
+//! not empty the upstream clusters.
+//!
+//! Address (`A`/`AAAA`) and `SRV` lookups are cached separately.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig as HickoryConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::{ResolveError, TokioResolver};
+use log::warn;
+use pingora::{Error, ErrorType, OrErr, Result};
+
+const DNS_ERROR: ErrorType = ErrorType::Custom("DNSError");
+
+#[derive(Clone, Debug)]
+pub struct ResolverConfig {
+    /// Nameservers to query over UDP/TCP; empty uses the system configuration.
+    pub nameservers: Vec<SocketAddr>,
+    /// Per-query timeout.
+    pub timeout: Duration,
+    /// Record TTLs are clamped to `min_ttl..=max_ttl`.
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    /// How long a name found not to exist is remembered.
+    pub negative_ttl: Duration,
+    /// How long past its TTL an answer is still served while refreshes fail.
+    pub max_stale: Duration,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            nameservers: Vec::new(),
+            timeout: Duration::from_secs(2),
+            min_ttl: Duration::from_secs(1),
+            max_ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(30),
+            max_stale: Duration::from_secs(300),
+        }
+    }
+}
+
+/// One `SRV` record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// Host name to resolve for the addresses, without the trailing dot.
+    pub target: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Ip,
+    Srv,
+}
+
+#[derive(Clone)]
+enum Answer {
+    Ips(Arc<[IpAddr]>),
+    Srv(Arc<[SrvTarget]>),
+    NotFound,
+}
+
+struct Entry {
+    answer: Answer,
+    expires: Instant,
+}
+
+pub struct Resolver {
+    inner: TokioResolver,
+    config: ResolverConfig,
+    cache: Mutex<HashMap<(Kind, String), Entry>>,
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Result<Self> {
+        let provider = TokioConnectionProvider::default();
+        let mut builder = if config.nameservers.is_empty() {
+            TokioResolver::builder(provider).or_err(DNS_ERROR, "reading system DNS config")?
+        } else {
+            let mut servers = NameServerConfigGroup::new();
+            for addr in &config.nameservers {
+                servers.merge(NameServerConfigGroup::from_ips_clear(
+                    &[addr.ip()],
+                    addr.port(),
+                    true,
+                ));
+            }
+            let hickory = HickoryConfig::from_parts(None, Vec::new(), servers);
+            TokioResolver::builder_with_config(hickory, provider)
+        };
+        let options = builder.options_mut();
+        options.timeout = config.timeout;
+        // caching is done here, with stale answers on top
+        options.cache_size = 0;
+
+        Ok(Resolver {
+            inner: builder.build(),
+            config,
+            cache: Mutex::default(),
+        })
+    }
+
+    pub fn config(&self) -> &ResolverConfig {
+        &self.config
+    }
+
+    /// The addresses of `name`; IP literals are returned as they are.
+    pub async fn lookup_ip(&self, name: &str) -> Result<Arc<[IpAddr]>> {
+        if let Ok(ip) = name.parse::<IpAddr>() {
+            return Ok(Arc::new([ip]));
+        }
+        let answer = self
+            .lookup(Kind::Ip, name, async {
+                let lookup = self.inner.lookup_ip(name).await?;
+                let addrs = lookup.iter().collect();
+                Ok((Answer::Ips(addrs), lookup.valid_until()))
+            })
+            .await?;
+        match answer {
+            Answer::Ips(addrs) => Ok(addrs),
+            _ => not_found(name),
+        }
+    }
+
+    /// The `SRV` records of `name`, e.g. `_http._tcp.web.service.consul`.
+    pub async fn lookup_srv(&self, name: &str) -> Result<Arc<[SrvTarget]>> {
+        let answer = self
+            .lookup(Kind::Srv, name, async {
+                let lookup = self.inner.srv_lookup(name).await?;
+                let records = lookup
+                    .iter()
+                    .map(|srv| SrvTarget {
+                        priority: srv.priority(),
+                        weight: srv.weight(),
+                        port: srv.port(),
+                        target: srv.target().to_ascii().trim_end_matches('.').to_string(),
+                    })
+                    .collect();
+                Ok((Answer::Srv(records), lookup.as_lookup().valid_until()))
+            })
+            .await?;
+        match answer {
+            Answer::Srv(records) => Ok(records),
+            _ => not_found(name),
+        }
+    }
+
+    /// Serve `name` from the cache, or run `query` and cache its answer.
+    async fn lookup(
+        &self,
+        kind: Kind,
+        name: &str,
+        query: impl Future<Output = std::result::Result<(Answer, Instant), ResolveError>>,
+    ) -> Result<Answer> {
+        let now = Instant::now();
+        let cached = self.cached(kind, name);
+        if let Some((answer, expires)) = &cached
+            && *expires > now
+        {
+            return Ok(answer.clone());
+        }
+
+        match query.await {
+            Ok((answer, valid_until)) => {
+                let ttl = valid_until
+                    .saturating_duration_since(now)
+                    .clamp(self.config.min_ttl, self.config.max_ttl);
+                self.store(kind, name, answer.clone(), now + ttl);
+                Ok(answer)
+            }
+            Err(e) if e.is_nx_domain() || e.is_no_records_found() => {
+                self.store(kind, name, Answer::NotFound, now + self.config.negative_ttl);
+                Ok(Answer::NotFound)
+            }
+            Err(e) => match cached {
+                Some((answer, expires))
+                    if !matches!(answer, Answer::NotFound)
+                        && expires + self.config.max_stale > now =>
+                {
+                    warn!("resolving {name} failed, serving stale answer: {e}");
+                    Ok(answer)
+                }
+                _ => Error::e_because(DNS_ERROR, format!("resolving {name}"), e),
+            },
+        }
+    }
+
+    fn cached(&self, kind: Kind, name: &str) -> Option<(Answer, Instant)> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(&(kind, name.to_string()))
+            .map(|e| (e.answer.clone(), e.expires))
+    }
+
+    fn store(&self, kind: Kind, name: &str, answer: Answer, expires: Instant) {
+        let mut cache = self.cache.lock().unwrap();
+        let stale_until = |e: &Entry| e.expires + self.config.max_stale;
+        cache.retain(|_, e| stale_until(e) > Instant::now());
+        cache.insert((kind, name.to_string()), Entry { answer, expires });
+    }
+}
+
+fn not_found<T>(name: &str) -> Result<T> {
+    Error::e_explain(DNS_ERROR, format!("{name} does not exist"))
+}
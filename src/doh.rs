@@ -0,0 +1,412 @@
+//! DNS-over-HTTPS gateway.
+//!
+//! A route with a [`DohGateway`] answers RFC 8484 queries itself instead of
+//! proxying them: `GET` with the query base64url-encoded in the `dns`
+//! parameter, or `POST` with an `application/dns-message` body. Queries are
+//! forwarded to the configured resolvers in order until one answers, over
+//! plain DNS (UDP, then TCP for truncated answers) or DoH.
+//!
+//! Answers are cached for their smallest record TTL, clamped to
+//! `min_ttl..=max_ttl`, and served from the cache with their TTLs counted
+//! down. Failures (`SERVFAIL`, `REFUSED`, truncated answers) are not cached.
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use bytes::{Bytes, BytesMut};
+use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::{RData, Record};
+use http::{Method, StatusCode, header};
+use log::debug;
+use pingora::connectors::TransportConnector;
+use pingora::connectors::http::Connector;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::proxy::Session;
+use pingora::upstreams::peer::{BasicPeer, HttpPeer};
+use pingora::{Error, ErrorType, OrErr, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+use crate::subrequest;
+
+const DOH_ERROR: ErrorType = ErrorType::Custom("DoHError");
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Largest DNS message, the limit of the TCP length prefix.
+const MAX_MESSAGE: usize = 65_535;
+
+#[derive(Clone, Debug)]
+pub enum DohUpstream {
+    /// A nameserver speaking plain DNS.
+    Do53(SocketAddr),
+    /// Another DoH server, e.g. `1.1.1.1:443` as `cloudflare-dns.com` at
+    /// `/dns-query`.
+    Https {
+        addr: SocketAddr,
+        sni: String,
+        path: String,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct DohConfig {
+    /// Resolvers to forward to, tried in order.
+    pub upstreams: Vec<DohUpstream>,
+    /// Per-resolver timeout.
+    pub timeout: Duration,
+    /// Answer TTLs are clamped to `min_ttl..=max_ttl` for caching.
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    /// Answers cached at most; beyond this the expired ones are dropped, and
+    /// everything if that is not enough.
+    pub cache_size: usize,
+}
+
+impl Default for DohConfig {
+    fn default() -> Self {
+        DohConfig {
+            upstreams: Vec::new(),
+            timeout: Duration::from_secs(2),
+            min_ttl: Duration::from_secs(1),
+            max_ttl: Duration::from_secs(3600),
+            cache_size: 10_000,
+        }
+    }
+}
+
+/// What a cached answer answers: the question, and the flags changing which
+/// records come back.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    name: String,
+    record_type: u16,
+    class: u16,
+    dnssec_ok: bool,
+    checking_disabled: bool,
+}
+
+impl Key {
+    fn of(query: &Message) -> Option<Key> {
+        let [question] = query.queries() else {
+            return None;
+        };
+        Some(Key {
+            name: question.name().to_lowercase().to_ascii(),
+            record_type: question.query_type().into(),
+            class: question.query_class().into(),
+            dnssec_ok: query
+                .extensions()
+                .as_ref()
+                .is_some_and(|e| e.flags().dnssec_ok),
+            checking_disabled: query.checking_disabled(),
+        })
+    }
+}
+
+struct Entry {
+    answer: Message,
+    stored: Instant,
+    ttl: Duration,
+}
+
+pub struct DohGateway {
+    config: DohConfig,
+    http: Connector,
+    tcp: TransportConnector,
+    cache: Mutex<HashMap<Key, Entry>>,
+}
+
+impl DohGateway {
+    pub fn new(config: DohConfig) -> Self {
+        DohGateway {
+            config,
+            http: Connector::new(None),
+            tcp: TransportConnector::new(None),
+            cache: Mutex::default(),
+        }
+    }
+
+    /// Answer the DoH request of `session`.
+    pub(crate) async fn handle(&self, session: &mut Session) -> Result<(ResponseHeader, Bytes)> {
+        let query = match read_query(session).await? {
+            Ok(query) => query,
+            Err(status) => return reply(status, Bytes::new(), None),
+        };
+        let parsed = Message::from_vec(&query).ok();
+        let Some((parsed, key)) = parsed
+            .filter(|m| m.message_type() == MessageType::Query)
+            .and_then(|m| Key::of(&m).map(|key| (m, key)))
+        else {
+            return reply(StatusCode::BAD_REQUEST, Bytes::new(), None);
+        };
+
+        if let Some((answer, ttl)) = self.cached(&key, parsed.id()) {
+            return reply(StatusCode::OK, answer, Some(ttl));
+        }
+        let Some(answer) = self.forward(&query).await else {
+            return reply(StatusCode::BAD_GATEWAY, Bytes::new(), None);
+        };
+        let Ok(mut message) = Message::from_vec(&answer) else {
+            return reply(StatusCode::BAD_GATEWAY, Bytes::new(), None);
+        };
+        message.set_id(parsed.id());
+        let answer = message.to_vec().or_err(DOH_ERROR, "encoding answer")?;
+        let ttl = self.store(key, message);
+        reply(StatusCode::OK, Bytes::from(answer), ttl)
+    }
+
+    /// A cached answer for `key` with TTLs counted down, and how long it
+    /// stays valid.
+    fn cached(&self, key: &Key, id: u16) -> Option<(Bytes, Duration)> {
+        let cache = self.cache.lock().unwrap();
+        let entry = cache.get(key)?;
+        let age = entry.stored.elapsed();
+        let remaining = entry.ttl.checked_sub(age).filter(|r| !r.is_zero())?;
+        let mut answer = entry.answer.clone();
+        drop(cache);
+
+        let age = age.as_secs() as u32;
+        answer.set_id(id);
+        let count_down = |records: &mut Vec<Record>| {
+            for record in records {
+                record.set_ttl(record.ttl().saturating_sub(age));
+            }
+        };
+        count_down(answer.answers_mut());
+        count_down(answer.name_servers_mut());
+        count_down(answer.additionals_mut());
+        let encoded = answer.to_vec().ok()?;
+        Some((Bytes::from(encoded), remaining))
+    }
+
+    /// Cache `answer` if it can be, returning for how long.
+    fn store(&self, key: Key, answer: Message) -> Option<Duration> {
+        let cacheable = !answer.truncated()
+            && matches!(
+                answer.response_code(),
+                ResponseCode::NoError | ResponseCode::NXDomain
+            );
+        let ttl = answer
+            .answers()
+            .iter()
+            .chain(answer.name_servers())
+            .map(record_ttl)
+            .min()
+            .filter(|_| cacheable)?;
+        let ttl = Duration::from_secs(ttl.into()).clamp(self.config.min_ttl, self.config.max_ttl);
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.config.cache_size {
+            cache.retain(|_, e| e.stored.elapsed() < e.ttl);
+            if cache.len() >= self.config.cache_size {
+                cache.clear();
+            }
+        }
+        let stored = Instant::now();
+        cache.insert(
+            key,
+            Entry {
+                answer,
+                stored,
+                ttl,
+            },
+        );
+        Some(ttl)
+    }
+
+    /// The raw answer of the first resolver to give one.
+    async fn forward(&self, query: &[u8]) -> Option<Vec<u8>> {
+        for upstream in &self.config.upstreams {
+            let answer = match upstream {
+                DohUpstream::Do53(addr) => self.do53(*addr, query).await,
+                DohUpstream::Https { addr, sni, path } => self.https(*addr, sni, path, query).await,
+            };
+            match answer {
+                Ok(answer) => return Some(answer),
+                Err(e) => debug!("forwarding DNS query to {upstream:?}: {e}"),
+            }
+        }
+        None
+    }
+
+    async fn do53(&self, addr: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+        // the query gets an id of its own, clients of DoH usually send 0
+        let id = RandomState::new().hash_one(Instant::now()) as u16;
+        let mut query = query.to_vec();
+        query[..2].copy_from_slice(&id.to_be_bytes());
+
+        let answer = self.with_timeout(udp_exchange(addr, &query, id)).await?;
+        if answer.get(2).is_some_and(|flags| flags & 0x02 != 0) {
+            // truncated, the whole answer needs TCP
+            return self.with_timeout(self.tcp_exchange(addr, &query)).await;
+        }
+        Ok(answer)
+    }
+
+    async fn tcp_exchange(&self, addr: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+        let peer = BasicPeer::new(&addr.to_string());
+        let mut stream = self.tcp.new_stream(&peer).await?;
+        let len = (query.len() as u16).to_be_bytes();
+        stream
+            .write_all(&[&len[..], query].concat())
+            .await
+            .or_err(DOH_ERROR, "sending query over TCP")?;
+        stream
+            .flush()
+            .await
+            .or_err(DOH_ERROR, "sending query over TCP")?;
+        let len = stream
+            .read_u16()
+            .await
+            .or_err(DOH_ERROR, "reading answer over TCP")?;
+        let mut answer = vec![0; len.into()];
+        stream
+            .read_exact(&mut answer)
+            .await
+            .or_err(DOH_ERROR, "reading answer over TCP")?;
+        Ok(answer)
+    }
+
+    async fn https(
+        &self,
+        addr: SocketAddr,
+        sni: &str,
+        path: &str,
+        query: &[u8],
+    ) -> Result<Vec<u8>> {
+        // an id of 0 lets upstream caches share answers
+        let mut query = query.to_vec();
+        query[..2].copy_from_slice(&[0, 0]);
+        let uri = format!("{path}?dns={}", URL_SAFE_NO_PAD.encode(&query));
+        let mut req = RequestHeader::build(Method::GET, uri.as_bytes(), None)?;
+        req.insert_header(header::HOST, sni)?;
+        req.insert_header(header::ACCEPT, DNS_MESSAGE)?;
+
+        let mut peer = HttpPeer::new(addr, true, sni.to_string());
+        peer.options.total_connection_timeout = Some(self.config.timeout);
+        peer.options.read_timeout = Some(self.config.timeout);
+        let fetched = subrequest::fetch(&self.http, &peer, req, MAX_MESSAGE).await?;
+        match fetched {
+            Some(fetched) if fetched.header.status == StatusCode::OK => Ok(fetched.body.to_vec()),
+            Some(fetched) => Error::e_explain(
+                DOH_ERROR,
+                format!("resolver answered {}", fetched.header.status),
+            ),
+            None => Error::e_explain(DOH_ERROR, "answer too large"),
+        }
+    }
+
+    async fn with_timeout<T>(&self, exchange: impl Future<Output = Result<T>>) -> Result<T> {
+        match tokio::time::timeout(self.config.timeout, exchange).await {
+            Ok(res) => res,
+            Err(_) => Error::e_explain(ErrorType::ReadTimedout, "waiting for DNS answer"),
+        }
+    }
+}
+
+/// The TTL of `record`; for the `SOA` of a negative answer, the negative TTL
+/// it gives.
+fn record_ttl(record: &Record) -> u32 {
+    match record.data() {
+        RData::SOA(soa) => record.ttl().min(soa.minimum()),
+        _ => record.ttl(),
+    }
+}
+
+async fn udp_exchange(addr: SocketAddr, query: &[u8], id: u16) -> Result<Vec<u8>> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind)
+        .await
+        .or_err(DOH_ERROR, "binding UDP socket")?;
+    socket
+        .connect(addr)
+        .await
+        .or_err(DOH_ERROR, "connecting UDP socket")?;
+    socket
+        .send(query)
+        .await
+        .or_err(DOH_ERROR, "sending query over UDP")?;
+    let mut buf = vec![0; MAX_MESSAGE];
+    loop {
+        let len = socket
+            .recv(&mut buf)
+            .await
+            .or_err(DOH_ERROR, "reading answer over UDP")?;
+        // anything else is a late answer to an earlier query, or spoofed
+        if len >= 12 && buf[..2] == id.to_be_bytes() {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+/// The DNS query of a DoH request, or the status to refuse it with.
+async fn read_query(session: &mut Session) -> Result<std::result::Result<Vec<u8>, StatusCode>> {
+    let req = session.req_header();
+    let query = match req.method {
+        Method::GET => {
+            let dns = req
+                .uri
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("dns="));
+            match dns.and_then(|q| URL_SAFE_NO_PAD.decode(q.trim_end_matches('=')).ok()) {
+                Some(query) => query,
+                None => return Ok(Err(StatusCode::BAD_REQUEST)),
+            }
+        }
+        Method::POST => {
+            let content_type = req.headers.get(header::CONTENT_TYPE);
+            if content_type.and_then(|v| v.to_str().ok()) != Some(DNS_MESSAGE) {
+                return Ok(Err(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+            let mut body = BytesMut::new();
+            while let Some(chunk) = session.read_request_body().await? {
+                body.extend_from_slice(&chunk);
+                if body.len() > MAX_MESSAGE {
+                    return Ok(Err(StatusCode::PAYLOAD_TOO_LARGE));
+                }
+            }
+            body.to_vec()
+        }
+        _ => return Ok(Err(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+    // shorter than a DNS header
+    if query.len() < 12 {
+        return Ok(Err(StatusCode::BAD_REQUEST));
+    }
+    Ok(Ok(query))
+}
+
+fn reply(
+    status: StatusCode,
+    body: Bytes,
+    ttl: Option<Duration>,
+) -> Result<(ResponseHeader, Bytes)> {
+    let mut header = ResponseHeader::build(status, Some(4))?;
+    if status == StatusCode::OK {
+        header.insert_header(header::CONTENT_TYPE, DNS_MESSAGE)?;
+        let cache_control = match ttl {
+            Some(ttl) => format!("max-age={}", ttl.as_secs()),
+            None => "no-store".to_string(),
+        };
+        header.insert_header(header::CACHE_CONTROL, cache_control)?;
+    }
+    if status == StatusCode::METHOD_NOT_ALLOWED {
+        header.insert_header(header::ALLOW, "GET, POST")?;
+    }
+    header.insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+    Ok((header, body))
+}
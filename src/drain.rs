@@ -0,0 +1,156 @@
+//! Draining upstreams.
+//!
+//! A draining upstream gets no new requests, while requests already sent to it
+//! finish normally. Once its grace period is over it is left out of the
+//! cluster altogether. Upstreams are put into draining through the admin API
+//! or by their discovery, which marks them with [`Draining`] in
+//! [`Backend::ext`]; the latter are released again when discovery stops
+//! marking them.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use log::info;
+use pingora::Result;
+use pingora::lb::Backend;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::protocols::l4::socket::SocketAddr;
+
+/// Discovery metadata asking for a backend to be drained.
+#[derive(Clone, Copy, Debug)]
+pub struct Draining;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrainSource {
+    Admin,
+    Discovery,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Drain {
+    since: Instant,
+    source: DrainSource,
+}
+
+/// The draining upstreams of all clusters, by address. Requests read them
+/// without a lock, drains replace them.
+pub struct DrainRegistry {
+    grace: Duration,
+    draining: ArcSwap<HashMap<SocketAddr, Drain>>,
+}
+
+impl Default for DrainRegistry {
+    fn default() -> Self {
+        DrainRegistry::new(Duration::from_secs(30))
+    }
+}
+
+impl DrainRegistry {
+    pub fn new(grace: Duration) -> Self {
+        DrainRegistry {
+            grace,
+            draining: ArcSwap::default(),
+        }
+    }
+
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    /// Start draining `addr`; a drain already in progress keeps its start.
+    pub fn drain(&self, addr: SocketAddr, source: DrainSource) {
+        let since = Instant::now();
+        let previous = self.draining.rcu(|draining| {
+            let mut draining = HashMap::clone(draining);
+            draining
+                .entry(addr.clone())
+                .or_insert(Drain { since, source });
+            draining
+        });
+        if !previous.contains_key(&addr) {
+            info!("draining upstream {addr}");
+        }
+    }
+
+    /// Stop draining `addr`; `false` if it was not draining.
+    pub fn undrain(&self, addr: &SocketAddr) -> bool {
+        let previous = self.draining.rcu(|draining| {
+            let mut draining = HashMap::clone(draining);
+            draining.remove(addr);
+            draining
+        });
+        let removed = previous.contains_key(addr);
+        if removed {
+            info!("upstream {addr} no longer draining");
+        }
+        removed
+    }
+
+    pub fn is_draining(&self, addr: &SocketAddr) -> bool {
+        self.draining.load().contains_key(addr)
+    }
+
+    /// Whether the grace period of a draining `addr` is over.
+    fn is_drained(&self, addr: &SocketAddr) -> bool {
+        self.draining
+            .load()
+            .get(addr)
+            .is_some_and(|d| d.since.elapsed() >= self.grace)
+    }
+
+    /// The draining upstreams with how long they have been draining.
+    pub fn list(&self) -> Vec<(SocketAddr, Duration, DrainSource)> {
+        let draining = self.draining.load();
+        let mut list: Vec<_> = draining
+            .iter()
+            .map(|(addr, d)| (addr.clone(), d.since.elapsed(), d.source))
+            .collect();
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+        list
+    }
+
+    /// Apply discovery metadata: drain marked backends, release the ones no
+    /// longer marked that discovery drained before.
+    fn sync_discovered(&self, backends: &BTreeSet<Backend>) {
+        for backend in backends {
+            if backend.ext.get::<Draining>().is_some() {
+                self.drain(backend.addr.clone(), DrainSource::Discovery);
+            } else if self.source(&backend.addr) == Some(DrainSource::Discovery) {
+                self.undrain(&backend.addr);
+            }
+        }
+    }
+
+    fn source(&self, addr: &SocketAddr) -> Option<DrainSource> {
+        self.draining.load().get(addr).map(|d| d.source)
+    }
+}
+
+/// Wraps a discovery to leave out backends whose drain grace is over, and to
+/// pick up drains requested through discovery metadata.
+pub struct DrainingDiscovery {
+    inner: Box<dyn ServiceDiscovery + Send + Sync>,
+    registry: Arc<DrainRegistry>,
+}
+
+impl DrainingDiscovery {
+    pub fn new(
+        inner: Box<dyn ServiceDiscovery + Send + Sync>,
+        registry: Arc<DrainRegistry>,
+    ) -> Box<Self> {
+        Box::new(DrainingDiscovery { inner, registry })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for DrainingDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let (mut backends, enablement) = self.inner.discover().await?;
+        self.registry.sync_discovered(&backends);
+        backends.retain(|b| !self.registry.is_drained(&b.addr));
+        Ok((backends, enablement))
+    }
+}
@@ -0,0 +1,53 @@
+//! TLS 1.3 early data (0-RTT).
+//!
+//! Early data can be replayed by anyone who captured it, so a request sent in
+//! it must be safe to handle twice. The TLS terminator in front of the proxy
+//! marks such requests with `Early-Data: 1` (RFC 8470). The proxy handles them
+//! only if their method is idempotent and their route opts in with
+//! [`crate::route::Route::early_data`]. It forwards the header so the upstream
+//! can make its own call, and flags the upstream's response with
+//! [`REPLAY_RISK_HEADER`]; the proxy's own answers, like cache hits, have no
+//! side effects a replay could repeat. Any other early request is answered
+//! `425 Too Early`, which has the client retry once the handshake is complete.
+//!
+//! The proxy's own terminator, see [`crate::sniff`], completes the handshake
+//! before reading requests, so nothing it accepts is early data.
+
+use http::HeaderName;
+use pingora::http::RequestHeader;
+
+use crate::route::Route;
+
+/// Marks requests that arrived in early data, set by the TLS terminator.
+pub const EARLY_DATA_HEADER: HeaderName = HeaderName::from_static("early-data");
+
+/// Sent with responses to early requests: they may have been replayed.
+pub const REPLAY_RISK_HEADER: &str = "x-replay-risk";
+
+/// The value of [`REPLAY_RISK_HEADER`].
+pub const REPLAY_RISK: &str = "early-data";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EarlyData {
+    /// Sent after the handshake, nothing to decide.
+    No,
+    /// Early, and safe to handle on `route`.
+    Accept,
+    /// Early, and must wait for the handshake: answer 425.
+    TooEarly,
+}
+
+/// How to treat `req`, matched to `route`.
+pub fn decide(req: &RequestHeader, route: Option<&Route>) -> EarlyData {
+    let early = req
+        .headers
+        .get(&EARLY_DATA_HEADER)
+        .is_some_and(|v| v.as_bytes() == b"1");
+    if !early {
+        EarlyData::No
+    } else if req.method.is_idempotent() && route.is_some_and(|r| r.early_data) {
+        EarlyData::Accept
+    } else {
+        EarlyData::TooEarly
+    }
+}
@@ -0,0 +1,212 @@
+//! Egress policy: where each source may connect to, out of the forward
+//! proxy and `original_dst` routes.
+//!
+//! Everything is denied but what a rule allows. A rule names a source, the
+//! forward proxy user or consumer, or the network of the client's address,
+//! and the destinations it may reach: domains, `*.example.com` for the
+//! subdomains of `example.com`, networks of addresses, and ports. A
+//! destination is allowed when its host is one of the domains or an address
+//! in one of the networks, on one of the ports; an empty list of ports
+//! allows any.
+//!
+//! Networks match destinations given as addresses, `CONNECT 10.0.3.7:443`,
+//! and domains the names of `CONNECT`. Requests of `original_dst` routes are
+//! checked by the address they were headed to, never their `Host`, which the
+//! application chooses: networks are the rules that bind a sidecar's
+//! application.
+//!
+//! Denied connections and requests are logged and counted by source and
+//! destination, see [`EgressPolicy::to_json`].
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::warn;
+use serde_json::{Value, json};
+
+use crate::family::Network;
+
+/// Sources and destinations denials are counted for, the least denied are
+/// forgotten past this.
+const MAX_VIOLATIONS: usize = 1024;
+
+/// Who a rule applies to.
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// The forward proxy user, or the consumer authentication established.
+    User(String),
+    /// Clients with an address in the network.
+    Network(Network),
+    Any,
+}
+
+/// Who is connecting out.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Identity<'a> {
+    pub user: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+}
+
+impl Identity<'_> {
+    /// How violations are counted and logged: the user if known, the address
+    /// otherwise.
+    fn label(&self) -> String {
+        match (self.user, self.ip) {
+            (Some(user), _) => user.to_string(),
+            (None, Some(ip)) => ip.to_string(),
+            (None, None) => "-".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EgressRule {
+    pub source: Source,
+    pub domains: Vec<String>,
+    pub networks: Vec<Network>,
+    pub ports: Vec<u16>,
+}
+
+impl EgressRule {
+    /// Nothing allowed yet to `source`.
+    pub fn new(source: Source) -> Self {
+        EgressRule {
+            source,
+            domains: Vec::new(),
+            networks: Vec::new(),
+            ports: Vec::new(),
+        }
+    }
+
+    pub fn with_domains<S: Into<String>>(mut self, domains: impl IntoIterator<Item = S>) -> Self {
+        self.domains.extend(domains.into_iter().map(Into::into));
+        self
+    }
+
+    /// Allow the networks, as `10.0.0.0/8`.
+    pub fn with_networks<S: AsRef<str>>(
+        mut self,
+        networks: impl IntoIterator<Item = S>,
+    ) -> Result<Self, String> {
+        for network in networks {
+            self.networks.push(Network::parse(network.as_ref())?);
+        }
+        Ok(self)
+    }
+
+    pub fn with_ports(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+        self.ports.extend(ports);
+        self
+    }
+
+    fn applies_to(&self, identity: &Identity) -> bool {
+        match &self.source {
+            Source::User(user) => identity.user == Some(user.as_str()),
+            Source::Network(network) => identity.ip.is_some_and(|ip| network.contains(ip)),
+            Source::Any => true,
+        }
+    }
+
+    fn allows(&self, host: &str, port: u16) -> bool {
+        let host_ok = match host.parse::<IpAddr>() {
+            Ok(ip) => self.networks.iter().any(|n| n.contains(ip)),
+            Err(_) => self.domains.iter().any(|d| domain_matches(d, host)),
+        };
+        host_ok && (self.ports.is_empty() || self.ports.contains(&port))
+    }
+}
+
+/// Whether `host` is `allowed`, or under it when `allowed` is `*.parent`.
+pub(crate) fn domain_matches(allowed: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let allowed = allowed.trim_end_matches('.').to_ascii_lowercase();
+    match allowed.strip_prefix("*.") {
+        Some(parent) => host
+            .strip_suffix(parent)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => host == allowed,
+    }
+}
+
+pub struct EgressPolicy {
+    rules: Vec<EgressRule>,
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    /// denials by source and `host:port`
+    violations: Mutex<BTreeMap<(String, String), u64>>,
+}
+
+impl EgressPolicy {
+    pub fn new(rules: Vec<EgressRule>) -> Self {
+        EgressPolicy {
+            rules,
+            allowed: AtomicU64::new(0),
+            denied: AtomicU64::new(0),
+            violations: Mutex::default(),
+        }
+    }
+
+    /// Whether `identity` may connect to `host`, a domain or an address, on
+    /// `port`, without counting or logging it.
+    pub fn allows(&self, identity: &Identity, host: &str, port: u16) -> bool {
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        self.rules
+            .iter()
+            .any(|rule| rule.applies_to(identity) && rule.allows(host, port))
+    }
+
+    /// Whether `identity` may connect to `host`, a domain or an address, on
+    /// `port`; denials are logged and counted.
+    pub fn check(&self, identity: &Identity, host: &str, port: u16) -> bool {
+        if self.allows(identity, host, port) {
+            self.allowed.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        self.denied.fetch_add(1, Ordering::Relaxed);
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        let destination = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{ip}]:{port}"),
+            _ => format!("{host}:{port}"),
+        };
+        let key = (identity.label(), destination);
+        warn!("egress policy denied {} to {}", key.0, key.1);
+        let mut violations = self.violations.lock().unwrap();
+        if violations.len() >= MAX_VIOLATIONS && !violations.contains_key(&key) {
+            let least = violations
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, _)| key.clone());
+            if let Some(least) = least {
+                violations.remove(&least);
+            }
+        }
+        *violations.entry(key).or_default() += 1;
+        false
+    }
+
+    pub fn to_json(&self) -> Value {
+        let violations: Vec<Value> = self
+            .violations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((source, destination), count)| {
+                json!({ "source": source, "destination": destination, "denied": count })
+            })
+            .collect();
+        json!({
+            "rules": self.rules.len(),
+            "allowed": self.allowed.load(Ordering::Relaxed),
+            "denied": self.denied.load(Ordering::Relaxed),
+            "violations": violations,
+        })
+    }
+}
@@ -0,0 +1,38 @@
+//! `Expect: 100-continue` handling.
+//!
+//! A client sending `Expect: 100-continue` holds back the request body until
+//! it sees an interim `100 Continue`. Who produces that interim response
+//! decides how early a large upload starts flowing, and whether it can still be
+//! rejected before any body byte is sent.
+
+use http::header;
+use pingora::http::RequestHeader;
+
+/// Who answers `Expect: 100-continue`, configured per listener.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExpectContinue {
+    /// Pass `Expect` to the upstream and relay its `100 Continue`.
+    #[default]
+    Forward,
+    /// Answer `100 Continue` as soon as the request header is read.
+    RespondLocally,
+    /// Answer `100 Continue` only once the request filters (auth, rate
+    /// limiting, ...) have accepted the request, so rejected uploads are
+    /// answered before the client sends the body.
+    AfterFilters,
+}
+
+impl ExpectContinue {
+    /// Whether the proxy itself answers, in which case `Expect` must not be
+    /// forwarded or the upstream would emit a second `100 Continue`.
+    pub fn is_local(self) -> bool {
+        self != ExpectContinue::Forward
+    }
+}
+
+/// Whether the request asks for a `100 Continue` before sending its body.
+pub fn expects_continue(req: &RequestHeader) -> bool {
+    req.headers
+        .get(header::EXPECT)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
@@ -0,0 +1,468 @@
+//! Explanations of what the proxy does with a request.
+//!
+//! [`Explainer::explain`] traces the decisions the proxy would make for a
+//! described request, without sending it anywhere or counting it in any
+//! stats: the route it matches, the filters of the route in the order the
+//! proxy applies them, and, unless one of them answers, the cluster and the
+//! upstream. Upstreams picked by the request itself, by consistent hashing
+//! or a sticky session's cookie, are named, as the same request gets the
+//! same one while the cluster stays as it is; for those picked by balancing
+//! the candidates are listed instead.
+//!
+//! Descriptions are JSON:
+//!
+//! ```json
+//! {
+//!   "method": "GET",
+//!   "host": "shop.example.com",
+//!   "path": "/assets/app.css?v=3",
+//!   "headers": {"cookie": "session=abc", "accept": ["text/css", "*/*"]},
+//!   "client_ip": "203.0.113.7",
+//!   "consumer": "team-a",
+//!   "dst": "10.0.0.9:80"
+//! }
+//! ```
+//!
+//! Only `path` is required, `method` defaults to `GET`. `consumer` stands
+//! for who authentication established, `dst` for where a connection the
+//! proxy intercepted was headed. Filters deciding on more than the
+//! description are listed as not evaluated: on the body, the TLS handshake
+//! or the client's earlier requests. So is the cache, which the request may
+//! or may not hit.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use http::{Method, StatusCode, header};
+use pingora::http::RequestHeader;
+use pingora::lb::{Backend, LoadBalancer, selection::RoundRobin};
+use serde_json::{Value, json};
+
+use crate::balancing::Balancing;
+use crate::circuit::CircuitBreakers;
+use crate::consistent_hash::Hasher;
+use crate::drain::DrainRegistry;
+use crate::egress::{EgressPolicy, Identity};
+use crate::flags::{self, FeatureFlags};
+use crate::gateway::Cors;
+use crate::idempotency::Idempotency;
+use crate::in_flight::InFlight;
+use crate::region::{self, RegionFailover};
+use crate::route::{Route, Router, request_host};
+use crate::stream;
+
+/// A described request.
+struct Description {
+    req: RequestHeader,
+    client_ip: Option<IpAddr>,
+    consumer: Option<String>,
+    dst: Option<SocketAddr>,
+}
+
+fn describe(value: &Value) -> Result<Description, String> {
+    let string = |field: &str| match value.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.as_str())),
+        Some(_) => Err(format!("{field} must be a string")),
+    };
+    let path = string("path")?.ok_or("request without path")?;
+    if !path.starts_with('/') {
+        return Err(format!("path {path} does not start with /"));
+    }
+    let method = string("method")?.unwrap_or("GET");
+    let mut req = RequestHeader::build(method.to_ascii_uppercase().as_str(), path.as_bytes(), None)
+        .map_err(|_| format!("bad request line {method} {path}"))?;
+    if let Some(headers) = value.get("headers").filter(|h| !h.is_null()) {
+        let headers = headers.as_object().ok_or("headers must be an object")?;
+        for (name, values) in headers {
+            let values = match values {
+                Value::String(v) => vec![v.as_str()],
+                Value::Array(values) => values
+                    .iter()
+                    .map(|v| {
+                        v.as_str()
+                            .ok_or(format!("values of header {name} must be strings"))
+                    })
+                    .collect::<Result<_, _>>()?,
+                _ => return Err(format!("header {name} must be a string or a list")),
+            };
+            for v in values {
+                req.append_header(name.to_ascii_lowercase(), v)
+                    .map_err(|_| format!("bad header {name}: {v}"))?;
+            }
+        }
+    }
+    if let Some(host) = string("host")? {
+        req.insert_header(header::HOST, host)
+            .map_err(|_| format!("bad host {host}"))?;
+    }
+    let client_ip = string("client_ip")?
+        .map(|ip| ip.parse().map_err(|_| format!("bad client_ip {ip}")))
+        .transpose()?;
+    let dst = string("dst")?
+        .map(|dst| dst.parse().map_err(|_| format!("bad dst {dst}")))
+        .transpose()?;
+    Ok(Description {
+        req,
+        client_ip,
+        consumer: string("consumer")?.map(str::to_string),
+        dst,
+    })
+}
+
+fn filter(name: &str, outcome: &str) -> Value {
+    json!({ "filter": name, "outcome": outcome })
+}
+
+fn not_evaluated(name: &str, why: &str) -> Value {
+    json!({ "filter": name, "outcome": "not evaluated", "why": why })
+}
+
+/// The answer of the proxy itself, by `by`; `None` status for answers that
+/// depend on more than the request line and headers.
+fn answer(by: &str, status: Option<StatusCode>) -> Value {
+    json!({ "by": by, "status": status.map(|s| s.as_u16()) })
+}
+
+fn route_json(route: &Route) -> Value {
+    json!({
+        "name": route.name,
+        "host": route.host,
+        "path_prefix": route.path_prefix,
+        "path_pattern": route.path_pattern,
+    })
+}
+
+/// What the proxy decides on, borrowed from the live state.
+pub struct Explainer<'a> {
+    pub router: &'a Router,
+    pub upstreams: &'a LoadBalancer<RoundRobin>,
+    /// How upstreams of routes without a balancing of their own are picked.
+    pub balancing: Balancing,
+    pub region: Option<&'a RegionFailover>,
+    pub flags: Option<&'a FeatureFlags>,
+    pub egress: Option<&'a EgressPolicy>,
+    pub drain: &'a DrainRegistry,
+    pub circuits: Option<&'a CircuitBreakers>,
+    pub in_flight: &'a InFlight,
+}
+
+impl Explainer<'_> {
+    /// The trace of the request `description` describes, see the module
+    /// docs; `Err` if it cannot be read.
+    pub fn explain(&self, description: &Value) -> Result<Value, String> {
+        let request = describe(description)?;
+        let redirected = request.dst.is_some();
+        let mut route = self
+            .router
+            .match_request(&request.req, request.dst, redirected);
+        let matched = route.as_ref().map(|r| r.name.clone());
+        let mut filters = Vec::new();
+        let answer = self.filters(&request, &mut route, &mut filters);
+        let (cluster, upstream) = match answer {
+            Some(_) => (Value::Null, Value::Null),
+            None => self.upstream(&request, route.as_deref()),
+        };
+        Ok(json!({
+            "request": {
+                "method": request.req.method.as_str(),
+                "host": request_host(&request.req),
+                "path": request.req.uri.to_string(),
+                "client_ip": request.client_ip.map(|ip| ip.to_string()),
+                "consumer": request.consumer,
+                "dst": request.dst.map(|dst| dst.to_string()),
+            },
+            "matched": matched,
+            "route": route.as_deref().map(route_json),
+            "filters": filters,
+            "answer": answer,
+            "cluster": cluster,
+            "upstream": upstream,
+        }))
+    }
+
+    /// The filters the request goes through until the one that answers it,
+    /// if one does, with its answer.
+    fn filters(
+        &self,
+        request: &Description,
+        route: &mut Option<Arc<Route>>,
+        filters: &mut Vec<Value>,
+    ) -> Option<Value> {
+        let req = &request.req;
+        if let Some(egress) = self.egress
+            && let Some(dst) = request.dst
+            && route.as_ref().is_some_and(|r| r.original_dst_cluster)
+        {
+            let identity = Identity {
+                user: request.consumer.as_deref(),
+                ip: request.client_ip,
+            };
+            if !egress.allows(&identity, &dst.ip().to_string(), dst.port()) {
+                filters.push(filter("egress", "denied"));
+                return Some(answer("egress", Some(StatusCode::FORBIDDEN)));
+            }
+            filters.push(filter("egress", "allowed"));
+        }
+        if let Some(flags) = self.flags {
+            let evaluations = flags.evaluations(req, request.client_ip);
+            let mut trace = filter("flags", "evaluated");
+            trace["flags"] = evaluations
+                .iter()
+                .map(|e| json!({ "flag": e.flag.name, "on": e.on }))
+                .collect();
+            let to = route
+                .as_ref()
+                .and_then(|r| flags::reroute(&evaluations, &r.name));
+            if let Some(to) = to {
+                trace["to"] = json!(to);
+                match self.router.route(to) {
+                    Some(to) => {
+                        trace["outcome"] = json!("rerouted");
+                        *route = Some(to);
+                    }
+                    None => trace["outcome"] = json!("reroutes to a route that does not exist"),
+                }
+            }
+            filters.push(trace);
+        }
+        let route = route.clone()?;
+
+        if route.body_routing.is_some() {
+            filters.push(not_evaluated(
+                "body_routing",
+                "hands requests over by their body",
+            ));
+        }
+        if !route.blocked_fingerprints.is_empty() {
+            filters.push(not_evaluated("fingerprints", "takes the TLS handshake"));
+        }
+        if route.max_anomaly_score.is_some() {
+            filters.push(not_evaluated(
+                "anomaly",
+                "scores the client's earlier requests",
+            ));
+        }
+        if let Some(gateway) = &route.gateway {
+            if let Some(cors) = gateway.cors()
+                && Cors::is_preflight(req)
+            {
+                return Some(match cors.preflight(req) {
+                    Ok(Some(_)) => {
+                        filters.push(filter("cors", "answers"));
+                        answer("cors", Some(StatusCode::NO_CONTENT))
+                    }
+                    _ => {
+                        filters.push(filter("cors", "denied"));
+                        answer("cors", Some(StatusCode::FORBIDDEN))
+                    }
+                });
+            }
+            let mut trace = filter("gateway", "applies");
+            trace["presets"] = json!(gateway.presets());
+            let path = req.uri.path_and_query().map_or("/", |p| p.as_str());
+            if let Some(path) = gateway.upstream_path(&route.path_prefix, path) {
+                trace["upstream_path"] = json!(path);
+            }
+            filters.push(trace);
+        }
+        if route.maintenance {
+            filters.push(filter("maintenance", "answers"));
+            return Some(answer("maintenance", Some(StatusCode::SERVICE_UNAVAILABLE)));
+        }
+        if let Some(location) = &route.redirect {
+            filters.push(filter("redirect", "answers"));
+            let mut answer = answer("redirect", Some(StatusCode::FOUND));
+            answer["location"] = json!(location);
+            return Some(answer);
+        }
+        if route.xml_guard.is_some() {
+            filters.push(not_evaluated("xml_guard", "checks the body"));
+        }
+        if route.graphql.is_some() {
+            filters.push(not_evaluated("graphql", "checks the query in the body"));
+        }
+        if let Some(api) = &route.openapi {
+            let checked = api.check(req).and_then(|checked| {
+                let wants_body = api.wants_body(&checked, req)?;
+                Ok((checked, wants_body))
+            });
+            match checked {
+                Ok((checked, wants_body)) => {
+                    let mut trace = filter("openapi", "passes");
+                    trace["operation"] = json!(api.operation_id(&checked));
+                    filters.push(trace);
+                    if wants_body {
+                        filters.push(not_evaluated("openapi_body", "checks the body"));
+                    }
+                }
+                Err(refusal) => {
+                    let mut trace = filter("openapi", "denied");
+                    trace["operation"] = json!(refusal.operation(api));
+                    filters.push(trace);
+                    return Some(answer("openapi", Some(refusal.status())));
+                }
+            }
+        }
+        if route.doh.is_some() {
+            filters.push(filter("doh", "answers"));
+            return Some(answer("doh", None));
+        }
+        if route.s3.is_some() && !matches!(req.method, Method::GET | Method::HEAD) {
+            filters.push(filter("s3", "answers"));
+            return Some(answer("s3", Some(StatusCode::METHOD_NOT_ALLOWED)));
+        }
+        if let Some(upload) = &route.upload {
+            filters.push(filter("upload", "answers"));
+            return Some(answer("upload", upload.refusal(req)));
+        }
+
+        // the first of these to apply handles the exchange with the cluster
+        let handler = if route.cgi.is_some() {
+            Some("cgi")
+        } else if route.quarantine.is_some() {
+            Some("quarantine")
+        } else if route.scan.is_some() {
+            Some("scan")
+        } else if route.signing.is_some() {
+            Some("signing")
+        } else if route.content_type_guard.is_some() {
+            Some("content_type_guard")
+        } else if route.idempotency.is_some() && Idempotency::key(req).is_some() {
+            Some("idempotency")
+        } else if route.fan_out.is_some() && req.method == Method::GET {
+            Some("fan_out")
+        } else if route.image.as_ref().is_some_and(|i| i.plan(req).is_some()) {
+            Some("image")
+        } else if route
+            .stream
+            .as_ref()
+            .is_some_and(|s| s.sse_keepalive.is_some())
+            && req.method == Method::GET
+            && stream::wants_events(req)
+        {
+            Some("stream")
+        } else {
+            None
+        };
+        if let Some(handler) = handler {
+            filters.push(filter(handler, "handles"));
+        }
+        None
+    }
+
+    fn usable(&self, route: Option<&Route>, backend: &Backend, healthy: bool) -> bool {
+        healthy
+            && !self.drain.is_draining(&backend.addr)
+            && self.circuits.is_none_or(|c| c.allows(&backend.addr))
+            && self
+                .in_flight
+                .has_room(&backend.addr, self.in_flight.cap_of(route))
+    }
+
+    /// The cluster of the request, with the upstream picked of it.
+    fn upstream(&self, request: &Description, route: Option<&Route>) -> (Value, Value) {
+        let req = &request.req;
+        if let Some(origin) = route.and_then(|r| r.s3.as_ref()) {
+            let bucket = origin.bucket();
+            let upstream = json!({
+                "selection": "s3",
+                "bucket": bucket.name,
+                "upstream": bucket.addr.to_string(),
+            });
+            return (Value::Null, upstream);
+        }
+        if route.is_some_and(|r| r.original_dst_cluster) {
+            let upstream = json!({
+                "selection": "original_dst",
+                "upstream": request.dst.map(|dst| dst.to_string()),
+            });
+            return (Value::Null, upstream);
+        }
+
+        let cluster = route
+            .filter(|r| r.upstreams.is_some())
+            .map_or("default", |r| &r.name);
+        let mut cluster = json!({ "name": cluster });
+        if let Some(region) = self.region {
+            cluster["region"] = json!(region.active());
+        }
+        let upstreams = region::cluster(self.region, route, self.upstreams);
+        let backends = upstreams.backends();
+
+        if let Some(sticky) = route.and_then(|r| r.sticky.as_ref()) {
+            let pinned = sticky.pin(req).and_then(|pin| {
+                backends
+                    .get_backend()
+                    .iter()
+                    .find(|b| pin.is_for(b))
+                    .filter(|b| backends.ready(b))
+                    .cloned()
+            });
+            if let Some(upstream) = pinned
+                && self
+                    .in_flight
+                    .has_room(&upstream.addr, self.in_flight.cap_of(route))
+            {
+                let upstream = json!({
+                    "selection": "sticky",
+                    "upstream": upstream.addr.to_string(),
+                    "draining": self.drain.is_draining(&upstream.addr),
+                });
+                return (cluster, upstream);
+            }
+        } else if let Some(hashing) = route.and_then(|r| r.hash_selection.as_ref())
+            && let Some(key) = hashing.key(req)
+        {
+            let owner = hashing.select(upstreams, &key, |_, _| true);
+            let picked =
+                hashing.select(upstreams, &key, |b, healthy| self.usable(route, b, healthy));
+            if let Some((upstream, _)) = picked {
+                let upstream = json!({
+                    "selection": "hash",
+                    "key": String::from_utf8_lossy(&key),
+                    "algorithm": hashing.algorithm().as_str(),
+                    "layout": hashing.layout().as_str(),
+                    "hash_function": hashing.hasher().as_str(),
+                    "hash": hashing.hasher().hash(&key),
+                    "upstream": upstream.addr.to_string(),
+                    "fallback": owner.is_some_and(|(owner, _)| owner.addr != upstream.addr),
+                });
+                return (cluster, upstream);
+            }
+        }
+
+        let balancing = route
+            .and_then(|r| r.balancing.as_ref())
+            .map_or(self.balancing, |b| b.balancing());
+        let client = match (&request.consumer, request.client_ip) {
+            (Some(consumer), _) => consumer.clone(),
+            (None, Some(ip)) => ip.to_string(),
+            (None, None) => String::new(),
+        };
+        let subset = route
+            .and_then(|r| r.subsets.as_ref())
+            .map(|s| s.get(backends, &client));
+        let candidates: Vec<String> = backends
+            .get_backend()
+            .iter()
+            .filter(|b| subset.as_ref().is_none_or(|s| s.contains(&b.addr)))
+            .filter(|b| self.usable(route, b, backends.ready(b)))
+            .map(|b| b.addr.to_string())
+            .collect();
+        let mut upstream = json!({
+            "selection": balancing.as_str(),
+            "upstream": null,
+            "candidates": candidates,
+        });
+        if route.is_some_and(|r| r.sticky.is_some()) {
+            upstream["sticky"] = json!("pinned anew to the upstream picked");
+        }
+        if let Some(subset) = subset {
+            let mut members: Vec<String> = subset.iter().map(ToString::to_string).collect();
+            members.sort();
+            upstream["subset"] = json!(members);
+        }
+        (cluster, upstream)
+    }
+}
@@ -0,0 +1,226 @@
+//! IPv4 and IPv6: dual-stack listeners and the families of upstreams.
+//!
+//! A listener bound to `[::]` with `IPV6_V6ONLY` off takes both families on
+//! one socket, IPv4 clients showing up as `::ffff:a.b.c.d`; [`client_ip`]
+//! gives them back as IPv4, so limits, geo rules and logs see one address per
+//! client whichever way it connected.
+//!
+//! [`FamilyDiscovery`] wraps a discovery to pick the family of upstreams with
+//! both A and AAAA records by a [`FamilyPreference`], and, on IPv6-only
+//! hosts, to reach IPv4-only upstreams through a NAT64 gateway by
+//! synthesizing their addresses in its [`Nat64`] prefix (RFC 6052).
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use async_trait::async_trait;
+use pingora::Result;
+use pingora::lb::Backend;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::proxy::Session;
+
+/// The client's address, IPv4 clients of a dual-stack listener as IPv4.
+pub fn client_ip(session: &Session) -> Option<IpAddr> {
+    session
+        .client_addr()
+        .and_then(|a| a.as_inet())
+        .map(|a| a.ip().to_canonical())
+}
+
+/// An IP network, `10.0.0.0/8` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    /// `text` as `10.0.0.0/8`, a bare address is a network of one.
+    pub fn parse(text: &str) -> std::result::Result<Network, String> {
+        let (addr, prefix) = text.split_once('/').unwrap_or((text, ""));
+        let addr: IpAddr = addr.parse().map_err(|e| format!("network {text}: {e}"))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            bits
+        } else {
+            prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= bits)
+                .ok_or_else(|| format!("network {text}: bad prefix length"))?
+        };
+        Ok(Network { addr, prefix })
+    }
+
+    /// Whether `ip` is in the network; IPv4-mapped IPv6 addresses count as
+    /// IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        let (network, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => (u32::from(n) as u128, u32::from(a) as u128, 32),
+            (IpAddr::V6(n), IpAddr::V6(a)) => (u128::from(n), u128::from(a), 128),
+            _ => return false,
+        };
+        let host_bits = bits - self.prefix;
+        host_bits == bits || (network ^ ip) >> host_bits == 0
+    }
+}
+
+/// Which upstream addresses of a cluster are used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FamilyPreference {
+    #[default]
+    Any,
+    /// Only the IPv4 addresses while there are any.
+    PreferV4,
+    /// Only the IPv6 addresses while there are any.
+    PreferV6,
+    V4Only,
+    V6Only,
+}
+
+impl std::str::FromStr for FamilyPreference {
+    type Err = String;
+
+    /// `any`, `prefer-v4`, `prefer-v6`, `v4` or `v6`.
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "any" => Ok(FamilyPreference::Any),
+            "prefer-v4" => Ok(FamilyPreference::PreferV4),
+            "prefer-v6" => Ok(FamilyPreference::PreferV6),
+            "v4" => Ok(FamilyPreference::V4Only),
+            "v6" => Ok(FamilyPreference::V6Only),
+            _ => Err(format!("unknown address family preference {s}")),
+        }
+    }
+}
+
+impl FamilyPreference {
+    /// Whether `ip` is of the preferred family, or of the only one.
+    fn preferred(self, ip: IpAddr) -> bool {
+        match self {
+            FamilyPreference::Any => true,
+            FamilyPreference::PreferV4 | FamilyPreference::V4Only => ip.is_ipv4(),
+            FamilyPreference::PreferV6 | FamilyPreference::V6Only => ip.is_ipv6(),
+        }
+    }
+
+    fn is_strict(self) -> bool {
+        matches!(self, FamilyPreference::V4Only | FamilyPreference::V6Only)
+    }
+}
+
+/// A NAT64 prefix of 96 bits, the IPv4 address filling the last 32.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Nat64 {
+    prefix: Ipv6Addr,
+}
+
+impl Default for Nat64 {
+    /// The well-known prefix, `64:ff9b::/96`.
+    fn default() -> Self {
+        Nat64 {
+            prefix: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+        }
+    }
+}
+
+impl Nat64 {
+    /// The prefix `prefix`, as `2001:db8:64::/96`.
+    pub fn new(prefix: &str) -> std::result::Result<Self, String> {
+        let (addr, len) = prefix
+            .split_once('/')
+            .ok_or_else(|| format!("NAT64 prefix {prefix} has no length"))?;
+        if len != "96" {
+            return Err(format!("NAT64 prefix {prefix} is not a /96"));
+        }
+        let addr: Ipv6Addr = addr
+            .parse()
+            .map_err(|e| format!("NAT64 prefix {prefix}: {e}"))?;
+        if u128::from(addr) as u32 != 0 {
+            return Err(format!("NAT64 prefix {prefix} has host bits set"));
+        }
+        Ok(Nat64 { prefix: addr })
+    }
+
+    /// The IPv6 address `v4` is reached at through the gateway.
+    pub fn synthesize(&self, v4: Ipv4Addr) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.prefix) | u128::from(u32::from(v4)))
+    }
+}
+
+/// Wraps a discovery to keep the upstreams of the preferred family, after
+/// mapping IPv4 ones into the NAT64 prefix if there is one.
+pub struct FamilyDiscovery {
+    inner: Box<dyn ServiceDiscovery + Send + Sync>,
+    preference: FamilyPreference,
+    nat64: Option<Nat64>,
+}
+
+impl FamilyDiscovery {
+    pub fn new(
+        inner: Box<dyn ServiceDiscovery + Send + Sync>,
+        preference: FamilyPreference,
+        nat64: Option<Nat64>,
+    ) -> Box<Self> {
+        Box::new(FamilyDiscovery {
+            inner,
+            preference,
+            nat64,
+        })
+    }
+}
+
+/// The key of `backend` in the enablement of a discovery, as pingora has it.
+fn hash_key(backend: &Backend) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    backend.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn ip_of(backend: &Backend) -> Option<IpAddr> {
+    backend.addr.as_inet().map(|a| a.ip())
+}
+
+#[async_trait]
+impl ServiceDiscovery for FamilyDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let (backends, mut enablement) = self.inner.discover().await?;
+        let backends: BTreeSet<Backend> = match self.nat64 {
+            None => backends,
+            Some(nat64) => backends
+                .into_iter()
+                .map(|mut backend| {
+                    if let Some(inet) = backend.addr.as_inet()
+                        && let IpAddr::V4(v4) = inet.ip()
+                    {
+                        let enabled = enablement.remove(&hash_key(&backend));
+                        let v6 = (nat64.synthesize(v4), inet.port());
+                        backend.addr = SocketAddr::Inet(v6.into());
+                        if let Some(enabled) = enabled {
+                            enablement.insert(hash_key(&backend), enabled);
+                        }
+                    }
+                    backend
+                })
+                .collect(),
+        };
+        let any_preferred = backends
+            .iter()
+            .any(|b| ip_of(b).is_some_and(|ip| self.preference.preferred(ip)));
+        if !any_preferred && !self.preference.is_strict() {
+            return Ok((backends, enablement));
+        }
+        let backends = backends
+            .into_iter()
+            .filter(|b| ip_of(b).is_none_or(|ip| self.preference.preferred(ip)))
+            .collect();
+        Ok((backends, enablement))
+    }
+}
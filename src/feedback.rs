@@ -0,0 +1,227 @@
+//! Upstream weights adjusted to the load the upstreams report.
+//!
+//! Every response tells how loaded its upstream is, either explicitly in a
+//! load header (a fraction, `0.7`, or a percentage, `70%`) or implicitly
+//! through its time to first byte, averaged per upstream as an EWMA. On each
+//! discovery refresh the upstreams get weights in proportion to their spare
+//! capacity relative to the others, kept within `min_factor..=max_factor` of
+//! the discovered weight and moved by at most `step` per refresh so the
+//! cluster does not oscillate.
+//!
+//! The load balancer tracks health per weight, so a reweighted upstream is
+//! healthy until its next check. Only upstreams that answer requests get
+//! feedback, so in practice those are the healthy ones anyway.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::debug;
+use pingora::Result;
+use pingora::http::ResponseHeader;
+use pingora::lb::Backend;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::protocols::l4::socket::SocketAddr;
+
+#[derive(Clone, Debug)]
+pub struct FeedbackConfig {
+    /// Response header with the upstream's load; `None` only uses latency.
+    pub load_header: Option<String>,
+    /// Weight of the newest sample in the averages, `0.0..=1.0`.
+    pub alpha: f64,
+    /// Bounds of the effective weight, as factors of the discovered one.
+    pub min_factor: f64,
+    pub max_factor: f64,
+    /// Largest change of the factor per refresh.
+    pub step: f64,
+    /// Weights are integers; a factor of 1 is this many times the discovered
+    /// weight, to leave room for fractions.
+    pub scale: usize,
+}
+
+impl Default for FeedbackConfig {
+    fn default() -> Self {
+        FeedbackConfig {
+            load_header: Some("X-Upstream-Load".to_string()),
+            alpha: 0.2,
+            min_factor: 0.25,
+            max_factor: 2.0,
+            step: 0.25,
+            scale: 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Stats {
+    /// seconds
+    latency: Option<f64>,
+    /// fraction of capacity in use
+    load: Option<f64>,
+    factor: f64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            latency: None,
+            load: None,
+            factor: 1.0,
+        }
+    }
+}
+
+impl Stats {
+    /// Spare capacity, comparable among upstreams of the same `uses_load`.
+    fn capacity(&self) -> Option<f64> {
+        match (self.load, self.latency) {
+            (Some(load), _) => Some((1.0 - load).max(0.05)),
+            (None, Some(latency)) => Some(1.0 / latency.max(0.001)),
+            (None, None) => None,
+        }
+    }
+
+    fn uses_load(&self) -> bool {
+        self.load.is_some()
+    }
+}
+
+pub struct LoadFeedback {
+    config: FeedbackConfig,
+    stats: Mutex<HashMap<SocketAddr, Stats>>,
+}
+
+impl LoadFeedback {
+    pub fn new(config: FeedbackConfig) -> Self {
+        LoadFeedback {
+            config,
+            stats: Mutex::default(),
+        }
+    }
+
+    /// Take in what a response of `upstream` says about its load.
+    pub(crate) fn observe(
+        &self,
+        upstream: &SocketAddr,
+        header: &ResponseHeader,
+        ttfb: Option<Duration>,
+    ) {
+        let load = self
+            .config
+            .load_header
+            .as_ref()
+            .and_then(|name| header.headers.get(name.as_str()))
+            .and_then(|v| parse_load(v.to_str().ok()?));
+        let alpha = self.config.alpha;
+        let ewma = |avg: Option<f64>, sample: f64| {
+            Some(avg.map_or(sample, |avg| avg + alpha * (sample - avg)))
+        };
+
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(upstream.clone()).or_default();
+        if let Some(load) = load {
+            stats.load = ewma(stats.load, load);
+        }
+        if let Some(ttfb) = ttfb {
+            stats.latency = ewma(stats.latency, ttfb.as_secs_f64());
+        }
+    }
+
+    /// The weight an upstream at `addr` discovered with `weight` has now,
+    /// without moving its factor.
+    pub fn weight(&self, addr: &SocketAddr, weight: usize) -> usize {
+        let factor = self
+            .stats
+            .lock()
+            .unwrap()
+            .get(addr)
+            .map_or(1.0, |stats| stats.factor);
+        (((weight * self.config.scale) as f64 * factor).round() as usize).max(1)
+    }
+
+    /// Reweight `backends` by their feedback, forgetting upstreams that left.
+    fn reweight(&self, backends: BTreeSet<Backend>) -> BTreeSet<Backend> {
+        let mut stats = self.stats.lock().unwrap();
+        stats.retain(|addr, _| backends.iter().any(|b| b.addr == *addr));
+
+        // upstreams reporting load are compared among themselves, the others
+        // by latency
+        let mean = |uses_load: bool| {
+            let capacities: Vec<f64> = stats
+                .values()
+                .filter(|s| s.uses_load() == uses_load)
+                .filter_map(Stats::capacity)
+                .collect();
+            (!capacities.is_empty())
+                .then(|| capacities.iter().sum::<f64>() / capacities.len() as f64)
+        };
+        let (mean_load, mean_latency) = (mean(true), mean(false));
+
+        let config = &self.config;
+        backends
+            .into_iter()
+            .map(|mut backend| {
+                let factor = match stats.get_mut(&backend.addr) {
+                    Some(stats) => {
+                        let mean = if stats.uses_load() {
+                            mean_load
+                        } else {
+                            mean_latency
+                        };
+                        if let (Some(capacity), Some(mean)) = (stats.capacity(), mean) {
+                            let target =
+                                (capacity / mean).clamp(config.min_factor, config.max_factor);
+                            let change = (target - stats.factor).clamp(-config.step, config.step);
+                            stats.factor += change;
+                        }
+                        stats.factor
+                    }
+                    None => 1.0,
+                };
+                let weight = (backend.weight * config.scale) as f64 * factor;
+                if factor != 1.0 {
+                    debug!("upstream {} weight factor {factor:.2}", backend.addr);
+                }
+                backend.weight = (weight.round() as usize).max(1);
+                backend
+            })
+            .collect()
+    }
+}
+
+/// A load of `0.7` or `70%`, as a fraction.
+fn parse_load(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let load = match value.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().ok()? / 100.0,
+        None => value.parse::<f64>().ok()?,
+    };
+    load.is_finite().then(|| load.clamp(0.0, 1.0))
+}
+
+/// Wraps a discovery to weight its backends by their load feedback.
+///
+/// Enablement reported by the wrapped discovery refers to the backends as it
+/// weighted them, so it should not report any.
+pub struct FeedbackDiscovery {
+    inner: Box<dyn ServiceDiscovery + Send + Sync>,
+    feedback: Arc<LoadFeedback>,
+}
+
+impl FeedbackDiscovery {
+    pub fn new(
+        inner: Box<dyn ServiceDiscovery + Send + Sync>,
+        feedback: Arc<LoadFeedback>,
+    ) -> Box<Self> {
+        Box::new(FeedbackDiscovery { inner, feedback })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for FeedbackDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let (backends, enablement) = self.inner.discover().await?;
+        Ok((self.feedback.reweight(backends), enablement))
+    }
+}
@@ -0,0 +1,261 @@
+//! JA3 and JA4 fingerprints of TLS clients.
+//!
+//! The TLS listener, see [`crate::sniff`], peeks at the ClientHello before
+//! the handshake and attaches the [`TlsFingerprint`] of the connection to its
+//! TLS digest, where every request of the connection finds it. The proxy
+//! checks it against the route's blocked fingerprints, sends it upstream in
+//! [`JA3_HEADER`] and [`JA4_HEADER`], replacing whatever the client sent, and
+//! logs it.
+//!
+//! JA3 is the MD5 of `version,ciphers,extensions,groups,point formats` in
+//! decimal; JA4 is the `t13d1516h2_<ciphers>_<extensions>` form with sorted,
+//! hashed cipher and extension lists. GREASE values are left out of both.
+
+use std::any::Any;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use openssl::hash::{MessageDigest, hash};
+use pingora::listeners::TlsAccept;
+use pingora::protocols::tls::TlsRef;
+
+/// Upstream header with the JA3 hash of the client.
+pub const JA3_HEADER: &str = "x-ja3";
+/// Upstream header with the JA4 fingerprint of the client.
+pub const JA4_HEADER: &str = "x-ja4";
+
+const SERVER_NAME: u16 = 0x0000;
+const SUPPORTED_GROUPS: u16 = 0x000a;
+const POINT_FORMATS: u16 = 0x000b;
+const SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const ALPN: u16 = 0x0010;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+
+#[derive(Clone, Debug)]
+pub struct TlsFingerprint {
+    /// The JA3 string, before hashing.
+    pub ja3: String,
+    pub ja3_hash: String,
+    pub ja4: String,
+}
+
+impl TlsFingerprint {
+    /// Whether `rule`, a JA3 hash or a JA4 fingerprint, is this one.
+    pub fn matches(&self, rule: &str) -> bool {
+        rule.eq_ignore_ascii_case(&self.ja3_hash) || rule == self.ja4
+    }
+
+    /// Whether the client offered ALPN protocols, as every browser does.
+    pub fn offers_alpn(&self) -> bool {
+        // the first and last characters of the first protocol, `00` for none
+        self.ja4.get(8..10).is_some_and(|alpn| alpn != "00")
+    }
+}
+
+/// Reads the big-endian fields of a ClientHello.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// A block prefixed with its length in `len_bytes` bytes.
+    fn block(&mut self, len_bytes: usize) -> Option<Reader<'a>> {
+        let len = match len_bytes {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => {
+                let b = self.take(3)?;
+                u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize
+            }
+        };
+        Some(Reader {
+            data: self.take(len)?,
+        })
+    }
+
+    fn u16s(mut self) -> Vec<u16> {
+        let mut values = Vec::new();
+        while let Some(v) = self.u16() {
+            values.push(v);
+        }
+        values
+    }
+}
+
+fn grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The first 12 hex digits of the SHA-256 of `text`, zeros if it is empty.
+pub(crate) fn short_hash(text: &str) -> String {
+    if text.is_empty() {
+        return "000000000000".to_string();
+    }
+    hash(MessageDigest::sha256(), text.as_bytes()).map_or_else(
+        |_| "000000000000".to_string(),
+        |d| hex(&d)[..12].to_string(),
+    )
+}
+
+fn join<T: ToString>(values: &[T], separator: &str) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Length of the TLS record starting `peeked`, header included, if it is a
+/// handshake record.
+pub(crate) fn record_len(peeked: &[u8]) -> Option<usize> {
+    (peeked.len() >= 5 && peeked[0] == 0x16)
+        .then(|| 5 + u16::from_be_bytes([peeked[3], peeked[4]]) as usize)
+}
+
+/// The fingerprint of the ClientHello in the TLS `record`, `None` if it is
+/// not one or is cut short.
+pub fn from_client_hello(record: &[u8]) -> Option<TlsFingerprint> {
+    let mut record = Reader { data: record };
+    if record.u8()? != 0x16 {
+        return None;
+    }
+    record.take(2)?;
+    let mut handshake = record.block(2)?;
+    if handshake.u8()? != 0x01 {
+        return None;
+    }
+    let mut hello = handshake.block(3)?;
+    let legacy_version = hello.u16()?;
+    hello.take(32)?;
+    hello.block(1)?;
+    let ciphers: Vec<u16> = hello
+        .block(2)?
+        .u16s()
+        .into_iter()
+        .filter(|c| !grease(*c))
+        .collect();
+    hello.block(1)?;
+
+    let mut extensions = Vec::new();
+    let (mut groups, mut formats, mut signatures, mut versions) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let mut alpn: Option<Vec<u8>> = None;
+    if let Some(mut list) = hello.block(2) {
+        while let Some(kind) = list.u16() {
+            let mut data = list.block(2)?;
+            if grease(kind) {
+                continue;
+            }
+            extensions.push(kind);
+            match kind {
+                SUPPORTED_GROUPS => groups = data.block(2)?.u16s(),
+                POINT_FORMATS => formats = data.block(1)?.data.to_vec(),
+                SIGNATURE_ALGORITHMS => signatures = data.block(2)?.u16s(),
+                SUPPORTED_VERSIONS => versions = data.block(1)?.u16s(),
+                ALPN => alpn = data.block(2)?.block(1).map(|p| p.data.to_vec()),
+                _ => {}
+            }
+        }
+    }
+    groups.retain(|g| !grease(*g));
+    signatures.retain(|s| !grease(*s));
+
+    let ja3 = format!(
+        "{legacy_version},{},{},{},{}",
+        join(&ciphers, "-"),
+        join(&extensions, "-"),
+        join(&groups, "-"),
+        join(&formats, "-"),
+    );
+    let ja3_hash = hash(MessageDigest::md5(), ja3.as_bytes()).map_or(String::new(), |d| hex(&d));
+
+    let version = versions
+        .into_iter()
+        .filter(|v| !grease(*v))
+        .max()
+        .unwrap_or(legacy_version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        _ => "00",
+    };
+    let sni = if extensions.contains(&SERVER_NAME) {
+        'd'
+    } else {
+        'i'
+    };
+    let alpn = match alpn.as_deref() {
+        Some([first, .., last])
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() =>
+        {
+            format!("{}{}", *first as char, *last as char)
+        }
+        Some([one]) if one.is_ascii_alphanumeric() => format!("{0}{0}", *one as char),
+        Some([first, .., last]) => format!("{:x}{:x}", first >> 4, last & 0x0f),
+        Some([one]) => format!("{:x}{:x}", one >> 4, one & 0x0f),
+        _ => "00".to_string(),
+    };
+    let mut sorted_ciphers: Vec<String> = ciphers.iter().map(|c| format!("{c:04x}")).collect();
+    sorted_ciphers.sort();
+    let mut sorted_extensions: Vec<String> = extensions
+        .iter()
+        .filter(|e| !matches!(**e, SERVER_NAME | ALPN))
+        .map(|e| format!("{e:04x}"))
+        .collect();
+    sorted_extensions.sort();
+    let mut extension_text = sorted_extensions.join(",");
+    if !signatures.is_empty() {
+        let signatures: Vec<String> = signatures.iter().map(|s| format!("{s:04x}")).collect();
+        extension_text = format!("{extension_text}_{}", signatures.join(","));
+    }
+    let ja4 = format!(
+        "t{version}{sni}{:02}{:02}{alpn}_{}_{}",
+        ciphers.len().min(99),
+        extensions.len().min(99),
+        short_hash(&sorted_ciphers.join(",")),
+        short_hash(&extension_text),
+    );
+
+    Some(TlsFingerprint { ja3, ja3_hash, ja4 })
+}
+
+/// Attaches the fingerprint taken before the handshake to the connection's
+/// TLS digest.
+pub(crate) struct AttachFingerprint(pub Option<Arc<TlsFingerprint>>);
+
+#[async_trait]
+impl TlsAccept for AttachFingerprint {
+    async fn handshake_complete_callback(
+        &self,
+        _ssl: &TlsRef,
+    ) -> Option<Arc<dyn Any + Send + Sync>> {
+        // the digest hands out references, an `Arc` in it is cheap to keep
+        self.0
+            .clone()
+            .map(|fingerprint| Arc::new(fingerprint) as Arc<dyn Any + Send + Sync>)
+    }
+}
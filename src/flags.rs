@@ -0,0 +1,419 @@
+//! Percentage-based feature flags, evaluated at the proxy.
+//!
+//! Every request gets each [`Flag`] evaluated once, and the upstream gets the
+//! outcome as `x-feature-{name}: on` or `off`, so no service behind the proxy
+//! needs a flag SDK, and all of them agree on who is in a rollout. A flag can
+//! also move requests to another route while it is on, for a new version of
+//! a service rolled out to a share of users.
+//!
+//! Bucketing is sticky: a flag is on for an identity when the crc32 of the
+//! flag's name and the identity falls within the rollout, so a user keeps
+//! their side of a rollout across requests and as it grows, and different
+//! flags split users independently. The identity is the first of the
+//! [`Stickiness`] sources the request has, the client address otherwise;
+//! requests with none share one bucket.
+//!
+//! The flags are configured, or polled from an Unleash server by
+//! [`FlagPoller`]; polled flags keep the reroutes of configured ones of the
+//! same name, since Unleash knows nothing of routes.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use http::{HeaderName, Uri, header};
+use log::{info, warn};
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde_json::{Value, json};
+
+use crate::subrequest;
+
+/// Prefix of the headers carrying evaluations upstream.
+pub const HEADER_PREFIX: &str = "x-feature-";
+
+/// Buckets of a rollout, for hundredths of a percent.
+const BUCKETS: u32 = 10_000;
+
+/// Largest flag list read from a provider.
+const MAX_BODY: usize = 4 << 20;
+
+#[derive(Clone, Debug)]
+pub struct Flag {
+    /// Lowercase; the header is [`HEADER_PREFIX`] followed by it.
+    pub name: String,
+    pub enabled: bool,
+    /// Share of identities the flag is on for, in percent.
+    pub rollout: f64,
+    /// While the flag is on, requests matching the route named first go to
+    /// the route named second.
+    pub reroute: Option<(String, String)>,
+}
+
+impl Flag {
+    /// An enabled flag on for `rollout` percent of identities.
+    pub fn new(name: impl Into<String>, rollout: f64) -> Self {
+        Flag {
+            name: name.into().to_ascii_lowercase(),
+            enabled: true,
+            rollout: rollout.clamp(0.0, 100.0),
+            reroute: None,
+        }
+    }
+
+    /// Send requests routed to `from` to `to` while the flag is on.
+    pub fn with_reroute(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.reroute = Some((from.into(), to.into()));
+        self
+    }
+
+    fn is_on_for(&self, identity: &str) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(self.name.as_bytes());
+        hasher.update(b":");
+        hasher.update(identity.as_bytes());
+        f64::from(hasher.finalize() % BUCKETS) < self.rollout * f64::from(BUCKETS) / 100.0
+    }
+}
+
+/// Where the identity a request is bucketed by comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stickiness {
+    Cookie(String),
+    Header(String),
+    ClientIp,
+}
+
+/// A flag's outcome for a request.
+#[derive(Clone, Debug)]
+pub struct Evaluation {
+    pub flag: Arc<Flag>,
+    pub on: bool,
+}
+
+/// The route requests matching `route` go to instead, by the first flag of
+/// `evaluations` that is on and reroutes `route`.
+pub fn reroute<'a>(evaluations: &'a [Evaluation], route: &str) -> Option<&'a str> {
+    evaluations.iter().find_map(|e| match &e.flag.reroute {
+        Some((from, to)) if e.on && from == route => Some(to.as_str()),
+        _ => None,
+    })
+}
+
+pub struct FeatureFlags {
+    /// the flags as configured, whose reroutes polled flags keep
+    configured: Vec<Flag>,
+    /// read without a lock by every request
+    flags: ArcSwap<Vec<Arc<Flag>>>,
+    stickiness: Vec<Stickiness>,
+    /// requests evaluated on and off, by flag
+    counts: Mutex<BTreeMap<String, [u64; 2]>>,
+}
+
+impl FeatureFlags {
+    /// Evaluate `flags` by the identity of the first of `stickiness` the
+    /// request has.
+    pub fn new(flags: Vec<Flag>, stickiness: Vec<Stickiness>) -> Self {
+        let configured = flags_with_valid_names(flags);
+        let flags = configured.iter().cloned().map(Arc::new).collect();
+        FeatureFlags {
+            configured,
+            flags: ArcSwap::from_pointee(flags),
+            stickiness,
+            counts: Mutex::default(),
+        }
+    }
+
+    /// Replace the flags with `flags`, keeping the reroutes of the
+    /// configured ones they do not set.
+    pub fn update(&self, flags: Vec<Flag>) {
+        let flags: Vec<Arc<Flag>> = flags_with_valid_names(flags)
+            .into_iter()
+            .map(|mut flag| {
+                if flag.reroute.is_none() {
+                    flag.reroute = self
+                        .configured
+                        .iter()
+                        .find(|c| c.name == flag.name)
+                        .and_then(|c| c.reroute.clone());
+                }
+                Arc::new(flag)
+            })
+            .collect();
+        self.flags.store(Arc::new(flags));
+    }
+
+    /// The identity `req` is bucketed by.
+    fn identity(&self, req: &RequestHeader, client: Option<IpAddr>) -> String {
+        for source in &self.stickiness {
+            let identity = match source {
+                Stickiness::Cookie(name) => req
+                    .headers
+                    .get_all(header::COOKIE)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .flat_map(|v| v.split(';'))
+                    .filter_map(|c| c.trim().split_once('='))
+                    .find_map(|(n, value)| (n == name).then_some(value)),
+                Stickiness::Header(name) => {
+                    req.headers.get(name.as_str()).and_then(|v| v.to_str().ok())
+                }
+                Stickiness::ClientIp => break,
+            };
+            if let Some(identity) = identity.filter(|i| !i.is_empty()) {
+                return identity.to_string();
+            }
+        }
+        client.map_or_else(String::new, |ip| ip.to_string())
+    }
+
+    /// Evaluate every flag for `req`, from `client`, into `evaluations`.
+    pub fn evaluate(
+        &self,
+        req: &RequestHeader,
+        client: Option<IpAddr>,
+        evaluations: &mut Vec<Evaluation>,
+    ) {
+        self.evaluate_uncounted(req, client, evaluations);
+        if evaluations.is_empty() {
+            return;
+        }
+        let mut counts = self.counts.lock().unwrap();
+        for evaluation in evaluations.iter() {
+            let name = &evaluation.flag.name;
+            if !counts.contains_key(name) {
+                counts.insert(name.clone(), [0; 2]);
+            }
+            counts.get_mut(name).unwrap()[usize::from(evaluation.on)] += 1;
+        }
+    }
+
+    /// Every flag evaluated for `req`, from `client`, without counting the
+    /// evaluations in [`FeatureFlags::to_json`].
+    pub fn evaluations(&self, req: &RequestHeader, client: Option<IpAddr>) -> Vec<Evaluation> {
+        let mut evaluations = Vec::new();
+        self.evaluate_uncounted(req, client, &mut evaluations);
+        evaluations
+    }
+
+    fn evaluate_uncounted(
+        &self,
+        req: &RequestHeader,
+        client: Option<IpAddr>,
+        evaluations: &mut Vec<Evaluation>,
+    ) {
+        let flags = self.flags.load();
+        if flags.is_empty() {
+            return;
+        }
+        let identity = self.identity(req, client);
+        evaluations.extend(flags.iter().map(|flag| Evaluation {
+            on: flag.is_on_for(&identity),
+            flag: flag.clone(),
+        }));
+    }
+
+    pub fn to_json(&self) -> Value {
+        let counts = self.counts.lock().unwrap();
+        let flags: Vec<Value> = self
+            .flags
+            .load()
+            .iter()
+            .map(|flag| {
+                let [off, on] = counts.get(&flag.name).copied().unwrap_or_default();
+                json!({
+                    "name": flag.name,
+                    "enabled": flag.enabled,
+                    "rollout": flag.rollout,
+                    "reroute": flag.reroute.as_ref().map(|(from, to)| json!({ "from": from, "to": to })),
+                    "on": on,
+                    "off": off,
+                })
+            })
+            .collect();
+        let stickiness: Vec<String> = self
+            .stickiness
+            .iter()
+            .map(|s| match s {
+                Stickiness::Cookie(name) => format!("cookie:{name}"),
+                Stickiness::Header(name) => format!("header:{name}"),
+                Stickiness::ClientIp => "client_ip".to_string(),
+            })
+            .collect();
+        json!({ "stickiness": stickiness, "flags": flags })
+    }
+}
+
+/// `flags` without those whose name cannot be in a header name.
+fn flags_with_valid_names(flags: Vec<Flag>) -> Vec<Flag> {
+    flags
+        .into_iter()
+        .map(|mut flag| {
+            flag.name.make_ascii_lowercase();
+            flag
+        })
+        .filter(|flag| {
+            let valid = HeaderName::try_from(format!("{HEADER_PREFIX}{}", flag.name)).is_ok();
+            if !valid {
+                warn!(
+                    "ignoring feature flag {:?}, not a valid header name",
+                    flag.name
+                );
+            }
+            valid
+        })
+        .collect()
+}
+
+/// The flags of an Unleash client API response, `GET /api/client/features`.
+///
+/// A flag's rollout is the largest of its strategies': 100% for `default`,
+/// the `rollout` of `flexibleRollout` and the `percentage` of the
+/// `gradualRollout*` ones. Flags with no strategy are on for everyone, those
+/// with only strategies the proxy cannot evaluate, like user ids lists, for
+/// no one.
+pub fn from_unleash(features: &Value) -> Result<Vec<Flag>, String> {
+    let features = features
+        .get("features")
+        .and_then(Value::as_array)
+        .ok_or("no features array")?;
+    let percent = |v: Option<&Value>| match v {
+        Some(Value::String(s)) => s.parse::<f64>().ok(),
+        Some(v) => v.as_f64(),
+        None => None,
+    };
+    features
+        .iter()
+        .map(|feature| {
+            let name = feature
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or("feature without a name")?;
+            let strategies = feature
+                .get("strategies")
+                .and_then(Value::as_array)
+                .map_or(&[][..], Vec::as_slice);
+            let rollout = if strategies.is_empty() {
+                100.0
+            } else {
+                strategies
+                    .iter()
+                    .filter_map(|s| {
+                        let params = s.get("parameters");
+                        match s.get("name").and_then(Value::as_str)? {
+                            "default" => Some(100.0),
+                            "flexibleRollout" => percent(params?.get("rollout")),
+                            n if n.starts_with("gradualRollout") => {
+                                percent(params?.get("percentage"))
+                            }
+                            _ => None,
+                        }
+                    })
+                    .fold(0.0, f64::max)
+            };
+            let mut flag = Flag::new(name, rollout);
+            flag.enabled = feature
+                .get("enabled")
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+            Ok(flag)
+        })
+        .collect()
+}
+
+/// Polls an Unleash server for the flags.
+pub struct FlagPoller {
+    flags: Arc<FeatureFlags>,
+    url: Uri,
+    token: Option<String>,
+    interval: Duration,
+    connector: Connector,
+}
+
+impl FlagPoller {
+    /// Poll `url`, the client features endpoint of an Unleash server, every
+    /// `interval`, with `token` as its API token.
+    pub fn new(
+        flags: Arc<FeatureFlags>,
+        url: &str,
+        token: Option<String>,
+        interval: Duration,
+    ) -> Result<Self, String> {
+        let url: Uri = url
+            .parse()
+            .map_err(|e| format!("flag provider {url}: {e}"))?;
+        subrequest::for_url("GET", &url, Duration::ZERO)
+            .map_err(|e| format!("flag provider {e}"))?;
+        Ok(FlagPoller {
+            flags,
+            url,
+            token,
+            interval,
+            connector: Connector::new(None),
+        })
+    }
+
+    async fn poll(&self) -> Result<usize, String> {
+        let timeout = Duration::from_secs(10);
+        let (peer, mut req) = subrequest::for_url("GET", &self.url, timeout)?;
+        req.insert_header(header::ACCEPT, "application/json")
+            .map_err(|e| e.to_string())?;
+        if let Some(token) = &self.token {
+            req.insert_header(header::AUTHORIZATION, token.as_str())
+                .map_err(|e| e.to_string())?;
+        }
+        let fetched = subrequest::fetch(&self.connector, &peer, req, MAX_BODY);
+        let fetched = match tokio::time::timeout(timeout, fetched).await {
+            Err(_) => return Err("timed out".to_string()),
+            Ok(Err(e)) => return Err(e.to_string()),
+            Ok(Ok(None)) => return Err("response body too large".to_string()),
+            Ok(Ok(Some(fetched))) => fetched,
+        };
+        if !fetched.header.status.is_success() {
+            return Err(format!("status {}", fetched.header.status));
+        }
+        let features: Value = serde_json::from_slice(&fetched.body).map_err(|e| e.to_string())?;
+        let flags = from_unleash(&features)?;
+        let count = flags.len();
+        self.flags.update(flags);
+        Ok(count)
+    }
+}
+
+#[async_trait]
+impl BackgroundService for FlagPoller {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut failing = false;
+        loop {
+            match self.poll().await {
+                Ok(flags) => {
+                    if failing {
+                        info!("feature flags from {} again, {flags} of them", self.url);
+                    }
+                    failing = false;
+                }
+                Err(e) => {
+                    if !failing {
+                        warn!(
+                            "could not poll feature flags from {}, keeping the last ones: {e}",
+                            self.url
+                        );
+                    }
+                    failing = true;
+                }
+            }
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(self.interval) => {}
+            }
+        }
+    }
+}
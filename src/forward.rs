@@ -0,0 +1,407 @@
+//! Forward proxy mode, to use the proxy as an egress gateway.
+//!
+//! Clients open tunnels with `CONNECT host:port`, over TLS when the listener
+//! has a certificate, and authenticate with `Proxy-Authorization: Basic`.
+//! Each user has a policy: the domains and ports they may reach, and a
+//! bandwidth cap shared by all their tunnels. Tunnels are answered with
+//!
+//! - 407 without valid credentials,
+//! - 403 to destinations outside the user's policy, or the
+//!   [`EgressPolicy`] of the proxy when it has one,
+//! - 405 for any other method,
+//! - 502 or 504 when the destination cannot be reached,
+//!
+//! and are relayed as they are once established.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::{debug, info, warn};
+use pingora::apps::ServerApp;
+use pingora::connectors::TransportConnector;
+use pingora::protocols::Stream;
+use pingora::protocols::l4::stream::Stream as L4Stream;
+use pingora::protocols::tls::server::handshake;
+use pingora::server::ShutdownWatch;
+use pingora::tls::ssl::SslAcceptor;
+use pingora::upstreams::peer::{BasicPeer, Peer};
+use pingora::{Error, ErrorType, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::dns::Resolver;
+use crate::egress::{self, EgressPolicy, Identity};
+use crate::sniff::acceptor;
+
+/// Longest request head accepted before a tunnel is refused.
+const MAX_HEAD: usize = 16 * 1024;
+
+const RELAY_BUF: usize = 16 * 1024;
+
+#[derive(Clone, Debug, Default)]
+pub struct UserPolicy {
+    /// Domains the user may connect to; `*.example.com` matches the
+    /// subdomains of `example.com`. Empty allows any.
+    pub allowed_domains: Vec<String>,
+    /// Ports the user may connect to. Empty allows any.
+    pub allowed_ports: Vec<u16>,
+    /// Bytes per second, both directions and all tunnels of the user
+    /// together; `None` is unlimited.
+    pub bandwidth: Option<u64>,
+}
+
+impl UserPolicy {
+    fn allows(&self, host: &str, port: u16) -> bool {
+        let domain_ok = self.allowed_domains.is_empty()
+            || self
+                .allowed_domains
+                .iter()
+                .any(|allowed| egress::domain_matches(allowed, host));
+        domain_ok && (self.allowed_ports.is_empty() || self.allowed_ports.contains(&port))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ForwardUser {
+    pub password: String,
+    pub policy: UserPolicy,
+}
+
+#[derive(Clone, Debug)]
+pub struct ForwardConfig {
+    /// Certificate and key paths (PEM) of the client-facing side; without
+    /// them clients connect in plain text.
+    pub tls: Option<(String, String)>,
+    pub users: HashMap<String, ForwardUser>,
+    pub connect_timeout: Duration,
+}
+
+impl Default for ForwardConfig {
+    fn default() -> Self {
+        ForwardConfig {
+            tls: None,
+            users: HashMap::new(),
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A token bucket refilled at `rate` bytes per second, holding a second's
+/// worth. Taking more than it holds leaves a debt the next takers wait out.
+struct Bandwidth {
+    rate: f64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Bandwidth {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Bandwidth {
+            rate,
+            bucket: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    async fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.1).as_secs_f64() * self.rate;
+            bucket.0 = (bucket.0 + refill).min(self.rate) - bytes as f64;
+            bucket.1 = now;
+            (bucket.0 < 0.0).then(|| Duration::from_secs_f64(-bucket.0 / self.rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+struct User {
+    password: String,
+    policy: UserPolicy,
+    bandwidth: Option<Arc<Bandwidth>>,
+}
+
+/// A [`ServerApp`] serving `CONNECT` tunnels to authenticated users.
+pub struct ForwardProxy {
+    tls: Option<SslAcceptor>,
+    users: HashMap<String, User>,
+    resolver: Arc<Resolver>,
+    connector: TransportConnector,
+    connect_timeout: Duration,
+    egress: Option<Arc<EgressPolicy>>,
+}
+
+impl ForwardProxy {
+    pub fn new(config: ForwardConfig, resolver: Arc<Resolver>) -> Result<Self> {
+        let tls = match &config.tls {
+            Some((cert, key)) => Some(acceptor(cert, key, b"\x08http/1.1")?),
+            None => None,
+        };
+        let users = config
+            .users
+            .into_iter()
+            .map(|(name, user)| {
+                let bandwidth = user.policy.bandwidth.map(|b| Arc::new(Bandwidth::new(b)));
+                let user = User {
+                    password: user.password,
+                    policy: user.policy,
+                    bandwidth,
+                };
+                (name, user)
+            })
+            .collect();
+        Ok(ForwardProxy {
+            tls,
+            users,
+            resolver,
+            connector: TransportConnector::new(None),
+            connect_timeout: config.connect_timeout,
+            egress: None,
+        })
+    }
+
+    /// Also hold every tunnel to `policy`.
+    pub fn with_egress(mut self, policy: Arc<EgressPolicy>) -> Self {
+        self.egress = Some(policy);
+        self
+    }
+
+    /// The user named by Basic `credentials`, if the password matches.
+    fn authenticate(&self, credentials: Option<&str>) -> Option<(&str, &User)> {
+        let encoded = credentials?.trim().strip_prefix("Basic ")?;
+        let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+        let (name, password) = decoded.split_once(':')?;
+        let (name, user) = self.users.get_key_value(name)?;
+        (user.password == password).then_some((name.as_str(), user))
+    }
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Stream> {
+        let ips = match host.parse::<IpAddr>() {
+            Ok(ip) => Arc::from([ip]),
+            Err(_) => self.resolver.lookup_ip(host).await?,
+        };
+        let mut last_error = None;
+        for ip in ips.iter() {
+            let mut peer = BasicPeer::new(&SocketAddr::new(*ip, port).to_string());
+            peer.options.connection_timeout = Some(self.connect_timeout);
+            match self.connector.new_stream(&peer).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("connecting {} for {host}: {e}", peer.address());
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e),
+            None => Error::e_explain(ErrorType::ConnectNoRoute, "no addresses"),
+        }
+    }
+
+    /// Serve one tunnel request on `downstream`.
+    async fn tunnel(&self, mut downstream: Stream) {
+        let (head, rest) = match read_head(&mut downstream).await {
+            Ok(Some(head)) => head,
+            Ok(None) => return reply(&mut downstream, "400 Bad Request", "").await,
+            Err(e) => return debug!("reading tunnel request: {e}"),
+        };
+        if head.method != "CONNECT" {
+            return reply(
+                &mut downstream,
+                "405 Method Not Allowed",
+                "Allow: CONNECT\r\n",
+            )
+            .await;
+        }
+        let Some((name, user)) = self.authenticate(head.authorization.as_deref()) else {
+            let challenge = "Proxy-Authenticate: Basic realm=\"proxy-rs\"\r\n";
+            return reply(
+                &mut downstream,
+                "407 Proxy Authentication Required",
+                challenge,
+            )
+            .await;
+        };
+        let Some((host, port)) = split_authority(&head.target) else {
+            return reply(&mut downstream, "400 Bad Request", "").await;
+        };
+        if !user.policy.allows(host, port) {
+            info!("{name} denied CONNECT {host}:{port}");
+            return reply(&mut downstream, "403 Forbidden", "").await;
+        }
+        if let Some(egress) = &self.egress {
+            let identity = Identity {
+                user: Some(name),
+                ip: downstream
+                    .get_socket_digest()
+                    .and_then(|d| d.peer_addr()?.as_inet().map(|a| a.ip().to_canonical())),
+            };
+            if !egress.check(&identity, host, port) {
+                return reply(&mut downstream, "403 Forbidden", "").await;
+            }
+        }
+
+        let mut upstream = match self.connect(host, port).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                warn!("{name} CONNECT {host}:{port}: {e}");
+                let status = match e.etype() {
+                    ErrorType::ConnectTimedout => "504 Gateway Timeout",
+                    _ => "502 Bad Gateway",
+                };
+                return reply(&mut downstream, status, "").await;
+            }
+        };
+        let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+        if let Err(e) = send(&mut downstream, established).await {
+            return debug!("answering tunnel request: {e}");
+        }
+        // bytes the client sent right after its request belong to the tunnel
+        if !rest.is_empty()
+            && let Err(e) = send(&mut upstream, &rest).await
+        {
+            return debug!("relaying to {host}:{port}: {e}");
+        }
+
+        let relayed = match &user.bandwidth {
+            Some(bandwidth) => relay_limited(downstream, upstream, bandwidth).await,
+            None => tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await,
+        };
+        match relayed {
+            Ok((sent, received)) => {
+                info!("{name} CONNECT {host}:{port} sent={sent} received={received}")
+            }
+            Err(e) => debug!("{name} CONNECT {host}:{port} relay ended: {e}"),
+        }
+    }
+}
+
+struct Head {
+    method: String,
+    target: String,
+    authorization: Option<String>,
+}
+
+/// Read a request head, returning it with the bytes read past its end;
+/// `None` when what was read cannot be one.
+async fn read_head(stream: &mut Stream) -> std::io::Result<Option<(Head, Vec<u8>)>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(len)) => {
+                let authorization = req
+                    .headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case("Proxy-Authorization"))
+                    .and_then(|h| std::str::from_utf8(h.value).ok())
+                    .map(str::to_string);
+                let head = Head {
+                    method: req.method.unwrap_or_default().to_string(),
+                    target: req.path.unwrap_or_default().to_string(),
+                    authorization,
+                };
+                return Ok(Some((head, buf.split_off(len))));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_HEAD => {}
+            _ => return Ok(None),
+        }
+    }
+}
+
+/// The host and port of a `CONNECT` target, `host:port` or `[v6]:port`.
+fn split_authority(target: &str) -> Option<(&str, u16)> {
+    let (host, port) = target.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.strip_suffix(']')?,
+        None => host,
+    };
+    Some((host, port.parse().ok().filter(|p| *p != 0)?))
+}
+
+async fn reply(stream: &mut Stream, status: &str, headers: &str) {
+    let response =
+        format!("HTTP/1.1 {status}\r\n{headers}Content-Length: 0\r\nConnection: close\r\n\r\n");
+    if let Err(e) = send(stream, response.as_bytes()).await {
+        debug!("answering tunnel request: {e}");
+    }
+}
+
+/// Write all of `data`, through the buffering of the stream.
+async fn send(stream: &mut Stream, data: &[u8]) -> std::io::Result<()> {
+    stream.write_all(data).await?;
+    stream.flush().await
+}
+
+/// Relay both directions of a tunnel within `bandwidth`, returning the bytes
+/// sent and received by the client.
+async fn relay_limited(
+    downstream: Stream,
+    upstream: Stream,
+    bandwidth: &Bandwidth,
+) -> std::io::Result<(u64, u64)> {
+    let (down_read, down_write) = tokio::io::split(downstream);
+    let (up_read, up_write) = tokio::io::split(upstream);
+    tokio::try_join!(
+        copy_limited(down_read, up_write, bandwidth),
+        copy_limited(up_read, down_write, bandwidth),
+    )
+}
+
+async fn copy_limited(
+    mut from: impl AsyncRead + Unpin,
+    mut to: impl AsyncWrite + Unpin,
+    bandwidth: &Bandwidth,
+) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; RELAY_BUF];
+    let mut copied = 0;
+    loop {
+        let n = from.read(&mut buf).await?;
+        if n == 0 {
+            to.shutdown().await?;
+            return Ok(copied);
+        }
+        bandwidth.take(n).await;
+        to.write_all(&buf[..n]).await?;
+        to.flush().await?;
+        copied += n as u64;
+    }
+}
+
+#[async_trait]
+impl ServerApp for ForwardProxy {
+    async fn process_new(
+        self: &Arc<Self>,
+        session: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let Some(acceptor) = &self.tls else {
+            self.tunnel(session).await;
+            return None;
+        };
+        // the handshake needs the TCP stream itself
+        let Ok(tcp) = session.into_any().downcast::<L4Stream>() else {
+            debug!("TLS on a non-TCP stream, closing connection");
+            return None;
+        };
+        match handshake(acceptor, *tcp).await {
+            Ok(tls) => self.tunnel(Box::new(tls)).await,
+            Err(e) => debug!("TLS handshake failed: {e}"),
+        }
+        None
+    }
+}
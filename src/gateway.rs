@@ -0,0 +1,410 @@
+//! API gateway presets.
+//!
+//! A [`Gateway`] on a route bundles what services behind an API gateway
+//! expect of it, so a service is onboarded with one setting rather than a
+//! filter at a time. The presets compose, and [`Gateway::from_str`] takes
+//! them as a comma separated list, as the `proxy-rs.gateway` label of
+//! [`crate::discovery::docker`] does:
+//!
+//! - `strip-prefix`: the route's path prefix is taken off the path sent
+//!   upstream, `/api/v1/users` of a route at `/api/v1/` going as `/users`,
+//!   and sent in `X-Forwarded-Prefix` instead
+//! - `auth-context`: who the request is from goes upstream in
+//!   `X-Request-Id`, `X-Consumer` and `X-Client-Ip`, and with a client
+//!   certificate in `X-Client-Cert-Organization` and `X-Client-Cert-Serial`;
+//!   whatever the client sent in them is dropped
+//! - `error-envelope`: the proxy's own error, maintenance and unavailable
+//!   answers are JSON whatever the client accepts, in one envelope:
+//!   `{"error": {"code": "unavailable", "message": "Service Unavailable",
+//!   "request_id": "...", "route": "api", "status": 503}}`
+//! - `cors`: the proxy answers CORS preflights itself and gives responses to
+//!   allowed origins the CORS headers, see [`Cors`]; any origin is allowed
+//!   unless [`Gateway::with_cors`] says otherwise
+//! - `api`: `strip-prefix`, `auth-context` and `error-envelope`
+//!
+//! Prefixes and the auth context apply to the requests proxied upstream;
+//! those a handler of the route sends itself, fan-out or image originals
+//! say, go as they are.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use http::{HeaderValue, Method, StatusCode, header};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::{ErrorType, OrErr, Result};
+use serde_json::json;
+
+use crate::template::Page;
+
+pub const FORWARDED_PREFIX_HEADER: &str = "x-forwarded-prefix";
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const CONSUMER_HEADER: &str = "x-consumer";
+pub const CLIENT_IP_HEADER: &str = "x-client-ip";
+pub const CERT_ORGANIZATION_HEADER: &str = "x-client-cert-organization";
+pub const CERT_SERIAL_HEADER: &str = "x-client-cert-serial";
+
+/// The headers of the auth context, dropped from what clients send.
+const AUTH_CONTEXT_HEADERS: [&str; 5] = [
+    REQUEST_ID_HEADER,
+    CONSUMER_HEADER,
+    CLIENT_IP_HEADER,
+    CERT_ORGANIZATION_HEADER,
+    CERT_SERIAL_HEADER,
+];
+
+/// The presets of a route, see the module docs.
+#[derive(Clone, Debug, Default)]
+pub struct Gateway {
+    strip_prefix: bool,
+    auth_context: bool,
+    error_envelope: bool,
+    cors: Option<Cors>,
+}
+
+/// Who a request is from, as the auth context sends it upstream.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AuthContext<'a> {
+    pub request_id: &'a str,
+    pub consumer: Option<&'a str>,
+    pub client_ip: Option<IpAddr>,
+    pub cert_organization: Option<&'a str>,
+    pub cert_serial: Option<&'a str>,
+}
+
+impl Gateway {
+    pub fn new() -> Self {
+        Gateway::default()
+    }
+
+    /// `strip-prefix`, `auth-context` and `error-envelope`.
+    pub fn api() -> Self {
+        Gateway::new()
+            .with_strip_prefix()
+            .with_auth_context()
+            .with_error_envelope()
+    }
+
+    pub fn with_strip_prefix(mut self) -> Self {
+        self.strip_prefix = true;
+        self
+    }
+
+    pub fn with_auth_context(mut self) -> Self {
+        self.auth_context = true;
+        self
+    }
+
+    pub fn with_error_envelope(mut self) -> Self {
+        self.error_envelope = true;
+        self
+    }
+
+    pub fn with_cors(mut self, cors: Cors) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    pub fn cors(&self) -> Option<&Cors> {
+        self.cors.as_ref()
+    }
+
+    pub fn error_envelope(&self) -> bool {
+        self.error_envelope
+    }
+
+    /// The names of the presets on, in the order of the module docs.
+    pub fn presets(&self) -> Vec<&'static str> {
+        [
+            (self.strip_prefix, "strip-prefix"),
+            (self.auth_context, "auth-context"),
+            (self.error_envelope, "error-envelope"),
+            (self.cors.is_some(), "cors"),
+        ]
+        .into_iter()
+        .filter_map(|(on, name)| on.then_some(name))
+        .collect()
+    }
+
+    /// The path and query `path` is sent upstream with on a route at
+    /// `prefix`, `None` if it is sent as it is.
+    pub fn upstream_path(&self, prefix: &str, path: &str) -> Option<String> {
+        if !self.strip_prefix {
+            return None;
+        }
+        let rest = path.strip_prefix(prefix)?;
+        Some(format!("/{}", rest.trim_start_matches('/')))
+    }
+
+    /// Prepare `req` of a route at `prefix` to go upstream.
+    pub fn upstream_request(
+        &self,
+        prefix: &str,
+        req: &mut RequestHeader,
+        context: &AuthContext,
+    ) -> Result<()> {
+        let path = req.uri.path_and_query().map_or("/", |p| p.as_str());
+        if let Some(path) = self.upstream_path(prefix, path) {
+            let uri = path
+                .parse()
+                .or_err(ErrorType::HTTPStatus(400), "path without the route prefix")?;
+            req.set_uri(uri);
+            let prefix = prefix.trim_end_matches('/');
+            req.insert_header(
+                FORWARDED_PREFIX_HEADER,
+                if prefix.is_empty() { "/" } else { prefix },
+            )?;
+        }
+        if self.auth_context {
+            for name in AUTH_CONTEXT_HEADERS {
+                req.remove_header(name);
+            }
+            req.insert_header(REQUEST_ID_HEADER, context.request_id)?;
+            if let Some(consumer) = context.consumer {
+                req.insert_header(CONSUMER_HEADER, consumer)?;
+            }
+            if let Some(ip) = context.client_ip {
+                req.insert_header(CLIENT_IP_HEADER, ip.to_string())?;
+            }
+            if let Some(organization) = context.cert_organization {
+                req.insert_header(CERT_ORGANIZATION_HEADER, organization)?;
+            }
+            if let Some(serial) = context.cert_serial {
+                req.insert_header(CERT_SERIAL_HEADER, serial)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Gateway {
+    type Err = String;
+
+    /// Comma separated presets, e.g. `api,cors`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut gateway = Gateway::new();
+        for preset in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            gateway = match preset {
+                "strip-prefix" => gateway.with_strip_prefix(),
+                "auth-context" => gateway.with_auth_context(),
+                "error-envelope" => gateway.with_error_envelope(),
+                "cors" => gateway.with_cors(Cors::any()),
+                "api" => gateway
+                    .with_strip_prefix()
+                    .with_auth_context()
+                    .with_error_envelope(),
+                _ => return Err(format!("unknown gateway preset {preset:?}")),
+            };
+        }
+        Ok(gateway)
+    }
+}
+
+/// The error envelope of one of the proxy's own pages.
+pub fn envelope(status: StatusCode, page: Page, request_id: &str, route: &str) -> String {
+    let message = status.canonical_reason().unwrap_or_default();
+    let code = match page {
+        Page::Maintenance => "maintenance".to_string(),
+        Page::Unavailable => "unavailable".to_string(),
+        Page::Error | Page::Redirect => message.to_ascii_lowercase().replace([' ', '-'], "_"),
+    };
+    let mut body = json!({
+        "error": {
+            "status": status.as_u16(),
+            "code": code,
+            "message": message,
+            "request_id": request_id,
+            "route": route,
+        }
+    })
+    .to_string();
+    body.push('\n');
+    body
+}
+
+/// Cross-origin access to a route, answered by the proxy rather than by its
+/// upstreams, whose own `Access-Control-*` headers are replaced.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    /// lowercase; `None` allows any origin
+    origins: Option<Vec<String>>,
+    methods: Vec<Method>,
+    /// lowercase request headers allowed; empty allows those asked for
+    headers: Vec<String>,
+    expose: Vec<String>,
+    credentials: bool,
+    max_age: Duration,
+}
+
+impl Cors {
+    /// Any origin, the usual methods and any request header, without
+    /// credentials; preflights are kept for 10 minutes.
+    pub fn any() -> Self {
+        Cors {
+            origins: None,
+            methods: vec![
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ],
+            headers: Vec::new(),
+            expose: Vec::new(),
+            credentials: false,
+            max_age: Duration::from_secs(10 * 60),
+        }
+    }
+
+    /// [`Cors::any`] for `origins` only, e.g. `https://app.example.com`.
+    pub fn origins<S: AsRef<str>>(origins: impl IntoIterator<Item = S>) -> Self {
+        Cors {
+            origins: Some(
+                origins
+                    .into_iter()
+                    .map(|o| o.as_ref().trim_end_matches('/').to_ascii_lowercase())
+                    .collect(),
+            ),
+            ..Cors::any()
+        }
+    }
+
+    pub fn with_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Allow only these request headers rather than any asked for.
+    pub fn with_headers<S: AsRef<str>>(mut self, headers: impl IntoIterator<Item = S>) -> Self {
+        self.headers = headers
+            .into_iter()
+            .map(|h| h.as_ref().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Response headers scripts may read besides the safelisted ones.
+    pub fn with_expose<S: AsRef<str>>(mut self, headers: impl IntoIterator<Item = S>) -> Self {
+        self.expose = headers
+            .into_iter()
+            .map(|h| h.as_ref().to_string())
+            .collect();
+        self
+    }
+
+    /// Let requests carry cookies and credentials; origins are then named
+    /// back rather than allowed with `*`.
+    pub fn with_credentials(mut self) -> Self {
+        self.credentials = true;
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Whether `req` is a CORS preflight.
+    pub fn is_preflight(req: &RequestHeader) -> bool {
+        req.method == Method::OPTIONS
+            && req.headers.contains_key(header::ORIGIN)
+            && req
+                .headers
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        self.origins.as_ref().is_none_or(|origins| {
+            let origin = origin.trim_end_matches('/');
+            origins.iter().any(|o| o.eq_ignore_ascii_case(origin))
+        })
+    }
+
+    /// The answer to the preflight `req`, `None` if it asks for an origin,
+    /// method or header not allowed.
+    pub fn preflight(&self, req: &RequestHeader) -> Result<Option<ResponseHeader>> {
+        let value = |name| {
+            req.headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+        };
+        let Some(origin) = value(header::ORIGIN).filter(|o| self.allows(o)) else {
+            return Ok(None);
+        };
+        let method = value(header::ACCESS_CONTROL_REQUEST_METHOD).unwrap_or_default();
+        if !self.methods.iter().any(|m| m.as_str() == method) {
+            return Ok(None);
+        }
+        let asked = value(header::ACCESS_CONTROL_REQUEST_HEADERS).unwrap_or_default();
+        let asked: Vec<&str> = asked
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .collect();
+        if !self.headers.is_empty()
+            && !asked
+                .iter()
+                .all(|h| self.headers.iter().any(|a| a.eq_ignore_ascii_case(h)))
+        {
+            return Ok(None);
+        }
+
+        let mut resp = ResponseHeader::build(StatusCode::NO_CONTENT, None)?;
+        self.allow_origin(origin, &mut resp)?;
+        let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+        resp.insert_header(header::ACCESS_CONTROL_ALLOW_METHODS, methods.join(", "))?;
+        if !asked.is_empty() {
+            resp.insert_header(header::ACCESS_CONTROL_ALLOW_HEADERS, asked.join(", "))?;
+        }
+        resp.insert_header(
+            header::ACCESS_CONTROL_MAX_AGE,
+            self.max_age.as_secs().to_string(),
+        )?;
+        resp.insert_header(header::CONTENT_LENGTH, "0")?;
+        Ok(Some(resp))
+    }
+
+    /// Give `resp` to `req` the CORS headers of its origin, if allowed.
+    pub fn annotate(&self, req: &RequestHeader, resp: &mut ResponseHeader) -> Result<()> {
+        for name in [
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        ] {
+            resp.remove_header(&name);
+        }
+        let origin = req
+            .headers
+            .get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim);
+        let Some(origin) = origin.filter(|o| self.allows(o)) else {
+            return Ok(());
+        };
+        self.allow_origin(origin, resp)?;
+        if !self.expose.is_empty() {
+            resp.insert_header(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                self.expose.join(", "),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn allow_origin(&self, origin: &str, resp: &mut ResponseHeader) -> Result<()> {
+        if self.origins.is_none() && !self.credentials {
+            resp.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")?;
+            return Ok(());
+        }
+        // the answer depends on the origin, caches must keep them apart
+        let origin = HeaderValue::from_str(origin).or_err(ErrorType::HTTPStatus(400), "origin")?;
+        resp.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)?;
+        resp.append_header(header::VARY, "Origin")?;
+        if self.credentials {
+            resp.insert_header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+//! Country and ASN rate limit multipliers.
+//!
+//! A [`GeoDb`] maps client addresses to their country and autonomous system,
+//! read from a CSV of `network,country,asn` lines such as
+//!
+//! ```text
+//! # network, ISO country code, AS number, AS organization (ignored)
+//! 1.0.0.0/24,AU,13335,Cloudflare
+//! 2001:db8::/32,,64496
+//! ```
+//!
+//! which is what GeoLite2 and iptoasn exports reduce to; networks must not
+//! overlap. [`GeoRates`] picks the first of its [`GeoRule`]s matching the
+//! client, and the rate limits take the rule's multiplier times the requests
+//! from their buckets: a multiplier of 4 for hosting-provider ASNs gives them
+//! a quarter of the limit, one of 0.5 for a home country twice the limit. The
+//! dimension that matched is logged.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    /// ISO 3166 code, upper case.
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// What a rule matches clients by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Dimension {
+    Country(String),
+    Asn(u32),
+}
+
+impl Dimension {
+    fn matches(&self, info: &GeoInfo) -> bool {
+        match self {
+            Dimension::Country(code) => info
+                .country
+                .as_deref()
+                .is_some_and(|c| c.eq_ignore_ascii_case(code)),
+            Dimension::Asn(asn) => info.asn == Some(*asn),
+        }
+    }
+}
+
+impl fmt::Display for Dimension {
+    /// `country:DE` or `asn:16509`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dimension::Country(code) => write!(f, "country:{code}"),
+            Dimension::Asn(asn) => write!(f, "asn:{asn}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GeoRule {
+    pub dimension: Dimension,
+    /// Requests a matching request counts as for rate limits.
+    pub multiplier: f64,
+}
+
+impl GeoRule {
+    pub fn country(code: impl Into<String>, multiplier: f64) -> Self {
+        GeoRule {
+            dimension: Dimension::Country(code.into()),
+            multiplier,
+        }
+    }
+
+    pub fn asn(asn: u32, multiplier: f64) -> Self {
+        GeoRule {
+            dimension: Dimension::Asn(asn),
+            multiplier,
+        }
+    }
+}
+
+/// The rule a client matched.
+#[derive(Clone, Debug)]
+pub struct GeoMatch {
+    pub dimension: Dimension,
+    pub multiplier: f64,
+}
+
+/// Networks sorted by first address, with the index of their info.
+#[derive(Default)]
+pub struct GeoDb {
+    v4: Vec<(u32, u32, usize)>,
+    v6: Vec<(u128, u128, usize)>,
+    infos: Vec<GeoInfo>,
+}
+
+/// The first and last address of `network`, `10.0.0.0/8`.
+fn range<const BITS: u32>(first: u128, prefix: &str) -> Result<(u128, u128), String> {
+    let prefix: u32 = prefix
+        .parse()
+        .ok()
+        .filter(|p| *p <= BITS)
+        .ok_or_else(|| format!("bad prefix length {prefix}"))?;
+    let host_bits = BITS - prefix;
+    let mask = if host_bits == 0 {
+        0
+    } else {
+        u128::MAX >> (128 - host_bits)
+    };
+    Ok((first & !mask, first | mask))
+}
+
+fn find<T: Copy + Ord>(networks: &[(T, T, usize)], addr: T) -> Option<usize> {
+    let at = networks.partition_point(|(first, _, _)| *first <= addr);
+    let (_, last, info) = networks.get(at.checked_sub(1)?)?;
+    (addr <= *last).then_some(*info)
+}
+
+impl GeoDb {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::from_csv(&text).map_err(std::io::Error::other)
+    }
+
+    /// The database of `text`; errors name the line.
+    pub fn from_csv(text: &str) -> Result<Self, String> {
+        let mut db = GeoDb::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = |e: String| format!("line {}: {e}", number + 1);
+            let mut fields = line.split(',').map(str::trim);
+            let network = fields.next().unwrap_or_default();
+            let country = fields.next().filter(|c| !c.is_empty());
+            let asn = match fields.next().filter(|a| !a.is_empty()) {
+                Some(asn) => Some(
+                    asn.trim_start_matches("AS")
+                        .parse()
+                        .map_err(|_| bad(format!("bad AS number {asn}")))?,
+                ),
+                None => None,
+            };
+            let (addr, prefix) = network
+                .split_once('/')
+                .ok_or_else(|| bad(format!("{network} is not a network")))?;
+            let addr: IpAddr = addr
+                .parse()
+                .map_err(|_| bad(format!("bad address {addr}")))?;
+            let info = db.infos.len();
+            db.infos.push(GeoInfo {
+                country: country.map(str::to_ascii_uppercase),
+                asn,
+            });
+            match addr {
+                IpAddr::V4(v4) => {
+                    let (first, last) = range::<32>(u32::from(v4).into(), prefix).map_err(bad)?;
+                    db.v4.push((first as u32, last as u32, info));
+                }
+                IpAddr::V6(v6) => {
+                    let (first, last) = range::<128>(u128::from(v6), prefix).map_err(bad)?;
+                    db.v6.push((first, last, info));
+                }
+            }
+        }
+        db.v4.sort_unstable();
+        db.v6.sort_unstable();
+        Ok(db)
+    }
+
+    pub fn lookup(&self, addr: IpAddr) -> Option<&GeoInfo> {
+        let info = match addr {
+            IpAddr::V4(v4) => find(&self.v4, u32::from(v4)),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => find(&self.v4, u32::from(v4)),
+                None => find(&self.v6, u128::from(v6)),
+            },
+        }?;
+        self.infos.get(info)
+    }
+
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct GeoRates {
+    db: GeoDb,
+    rules: Vec<GeoRule>,
+}
+
+impl GeoRates {
+    pub fn new(db: GeoDb, rules: Vec<GeoRule>) -> Self {
+        GeoRates { db, rules }
+    }
+
+    /// The first rule matching `client`, `None` if none does or the client
+    /// is not in the database.
+    pub fn classify(&self, client: IpAddr) -> Option<GeoMatch> {
+        let info = self.db.lookup(client)?;
+        self.rules
+            .iter()
+            .find(|rule| rule.dimension.matches(info))
+            .map(|rule| GeoMatch {
+                dimension: rule.dimension.clone(),
+                multiplier: rule.multiplier,
+            })
+    }
+}
@@ -0,0 +1,713 @@
+//! GraphQL-aware limits.
+//!
+//! On a route with [`GraphQl`] the proxy reads each request's operations,
+//! from the query string of a `GET` or from the body of a `POST`
+//! (`application/json`, batches included, or `application/graphql`), and
+//! refuses, with a GraphQL error document, operations that:
+//!
+//! - nest fields deeper than `max_depth`, fragments expanded,
+//! - select more than `max_complexity` fields in total,
+//! - exceed the rate limit of their operation name, in requests per second
+//!   across all clients,
+//! - cannot be parsed; what the proxy cannot read it cannot limit.
+//!
+//! The operation, `query:GetUser`, is logged with the request. Bodies must
+//! have a `Content-Length` of at most 64 KiB, the most the proxy keeps for
+//! replaying to the upstream. Requests by persisted query hash alone carry no
+//! query and are only rate limited.
+//!
+//! Workers take the tokens of rate limits of at least four requests a second
+//! per core from the shared bucket in leases, see [`crate::sharded`], and
+//! spend them without taking its lock. A lease is a quarter of the burst
+//! split between the shards, so up to a quarter of the burst may sit unspent
+//! in the shards of idle workers.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use bytes::Bytes;
+use http::{Method, StatusCode, header};
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde_json::{Value, json};
+
+use crate::admin::query_param;
+use crate::body_route::MAX_BODY;
+use crate::sharded::{Sharded, shards};
+
+/// Deepest nesting the parser follows, whatever the limits.
+const MAX_NESTING: usize = 128;
+
+/// Operation names tracked for rate limiting before idle ones are dropped.
+const MAX_TRACKED: usize = 10_000;
+
+pub struct GraphQl {
+    max_depth: usize,
+    max_complexity: usize,
+    max_body: usize,
+    rate_limits: HashMap<String, f64>,
+    default_rate_limit: Option<f64>,
+    /// by operation name, `""` for anonymous operations
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// tokens each shard took from `buckets` ahead of its requests
+    leases: Sharded<HashMap<String, f64>>,
+}
+
+struct Bucket {
+    /// requests per second
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn burst(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let refilled = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refilled).min(self.burst());
+        self.refilled = now;
+    }
+}
+
+/// A request refused before reaching the upstream.
+#[derive(Debug)]
+pub(crate) struct Refusal {
+    pub(crate) status: StatusCode,
+    pub(crate) message: String,
+}
+
+/// An operation of a request, displayed as `type:name`.
+#[derive(Debug)]
+pub(crate) struct Operation {
+    /// `query`, `mutation`, `subscription`, or `persisted` when only the
+    /// hash of a persisted query was sent
+    pub(crate) kind: &'static str,
+    pub(crate) name: Option<String>,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}:{name}", self.kind),
+            None => f.write_str(self.kind),
+        }
+    }
+}
+
+impl Default for GraphQl {
+    fn default() -> Self {
+        GraphQl {
+            max_depth: 15,
+            max_complexity: 1000,
+            max_body: MAX_BODY,
+            rate_limits: HashMap::new(),
+            default_rate_limit: None,
+            buckets: Mutex::default(),
+            leases: Sharded::default(),
+        }
+    }
+}
+
+impl GraphQl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deepest field nesting allowed, `{ user { name } }` being 2.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth.min(MAX_NESTING);
+        self
+    }
+
+    /// Most fields an operation may select, counted with fragments expanded.
+    pub fn with_max_complexity(mut self, max_complexity: usize) -> Self {
+        self.max_complexity = max_complexity;
+        self
+    }
+
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body.min(MAX_BODY);
+        self
+    }
+
+    /// Requests per second allowed for the operation `name`, in bursts of up
+    /// to as many.
+    pub fn with_rate_limit(mut self, name: impl Into<String>, per_second: f64) -> Self {
+        self.rate_limits.insert(name.into(), per_second);
+        self
+    }
+
+    /// Requests per second allowed for each operation without its own limit,
+    /// anonymous ones sharing one.
+    pub fn with_default_rate_limit(mut self, per_second: f64) -> Self {
+        self.default_rate_limit = Some(per_second);
+        self
+    }
+
+    /// Whether to read the body of `req` before calling [`Self::check`].
+    pub(crate) fn wants_body(&self, req: &RequestHeader) -> Result<bool, Refusal> {
+        if req.method == Method::GET {
+            return Ok(false);
+        }
+        let len = req
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+        match len {
+            Some(len) if len <= self.max_body => Ok(true),
+            Some(_) => Err(Refusal::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request body too large to inspect",
+            )),
+            None => Err(Refusal::new(
+                StatusCode::LENGTH_REQUIRED,
+                "request body needs a Content-Length",
+            )),
+        }
+    }
+
+    /// The operations of `req` if within the depth and complexity limits.
+    pub(crate) fn check(
+        &self,
+        req: &RequestHeader,
+        body: &[u8],
+    ) -> Result<Vec<Operation>, Refusal> {
+        let requests = requests(req, body)?;
+        let mut operations = Vec::with_capacity(requests.len());
+        for request in &requests {
+            let operation = match &request.query {
+                Some(query) => self.inspect(query, request.operation_name.as_deref())?,
+                None => Operation {
+                    kind: "persisted",
+                    name: request.operation_name.clone(),
+                },
+            };
+            operations.push(operation);
+        }
+        Ok(operations)
+    }
+
+    /// Take `cost` requests from the rate limit of each operation, more than
+    /// one for suspicious requests, see [`crate::anomaly`].
+    pub(crate) fn throttle(&self, operations: &[Operation], cost: f64) -> Result<(), Refusal> {
+        for operation in operations {
+            if !self.allow(operation.name.as_deref().unwrap_or_default(), cost) {
+                return Err(Refusal::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    format!("rate limit of operation {operation} exceeded"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The operation of `query` to run, checked against the depth and
+    /// complexity limits.
+    fn inspect(&self, query: &str, operation_name: Option<&str>) -> Result<Operation, Refusal> {
+        let document = Parser::new(query)?.document()?;
+        let mut candidates = document
+            .operations
+            .iter()
+            .filter(|op| operation_name.is_none() || op.name == operation_name);
+        let chosen = match (candidates.next(), candidates.next()) {
+            (Some(op), None) => op,
+            (None, _) => return Err(Refusal::bad_request("operation not found in query")),
+            (Some(_), Some(_)) => {
+                return Err(Refusal::bad_request("operationName required"));
+            }
+        };
+        let mut measure = Measure {
+            fragments: &document.fragments,
+            max_depth: self.max_depth,
+            max_complexity: self.max_complexity,
+            fields: 0,
+            spreading: Vec::new(),
+        };
+        measure.selections(&chosen.selections, 1)?;
+        Ok(Operation {
+            kind: chosen.kind,
+            name: chosen.name.map(str::to_string),
+        })
+    }
+
+    fn allow(&self, name: &str, cost: f64) -> bool {
+        let Some(rate) = self
+            .rate_limits
+            .get(name)
+            .copied()
+            .or(self.default_rate_limit)
+        else {
+            return true;
+        };
+        // never more than a full bucket, or the request could never pass
+        let cost = cost.min(rate.max(1.0));
+        let lease = rate.max(1.0) / (4 * shards()) as f64;
+        if lease < 1.0 {
+            return self.take(name, rate, cost, cost).is_some();
+        }
+        let mut leases = self.leases.local();
+        if let Some(held) = leases.get_mut(name)
+            && *held >= cost
+        {
+            *held -= cost;
+            return true;
+        }
+        let held = leases.get(name).copied().unwrap_or_default();
+        let Some(taken) = self.take(name, rate, cost - held + lease, cost - held) else {
+            return false;
+        };
+        if leases.len() >= MAX_TRACKED && !leases.contains_key(name) {
+            leases.clear();
+        }
+        leases.insert(name.to_string(), held + taken - cost);
+        true
+    }
+
+    /// Take `want` tokens from the shared bucket of `name`, or all it has if
+    /// that is at least `least`. The tokens taken, `None` if it has fewer.
+    fn take(&self, name: &str, rate: f64, want: f64, least: f64) -> Option<f64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(name) {
+            // drop the buckets that have filled up again, they are idle
+            buckets.retain(|_, b| {
+                b.refill(now);
+                b.tokens < b.burst()
+            });
+        }
+        let bucket = buckets.entry(name.to_string()).or_insert(Bucket {
+            rate,
+            tokens: rate.max(1.0),
+            refilled: now,
+        });
+        bucket.refill(now);
+        if bucket.tokens < least {
+            return None;
+        }
+        let taken = want.min(bucket.tokens);
+        bucket.tokens -= taken;
+        Some(taken)
+    }
+}
+
+impl Refusal {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Refusal {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    /// The refusal as a GraphQL response with an error.
+    pub(crate) fn response(&self) -> Result<(ResponseHeader, Bytes)> {
+        let body = Bytes::from(json!({ "errors": [{ "message": self.message }] }).to_string());
+        let mut header = ResponseHeader::build(self.status, Some(3))?;
+        header.insert_header(header::CONTENT_TYPE, "application/json")?;
+        header.insert_header(header::CONTENT_LENGTH, body.len())?;
+        header.insert_header(header::CACHE_CONTROL, "no-store")?;
+        Ok((header, body))
+    }
+}
+
+/// One GraphQL request, of the possibly several a batch holds.
+struct Request {
+    query: Option<String>,
+    operation_name: Option<String>,
+}
+
+fn requests(req: &RequestHeader, body: &[u8]) -> Result<Vec<Request>, Refusal> {
+    if req.method == Method::GET {
+        let query = req.uri.query().unwrap_or_default();
+        let request = Request {
+            query: query_param(query, "query"),
+            operation_name: query_param(query, "operationName").filter(|n| !n.is_empty()),
+        };
+        if request.query.is_none() && query_param(query, "extensions").is_none() {
+            return Err(Refusal::bad_request("missing query"));
+        }
+        return Ok(vec![request]);
+    }
+
+    let content_type = req
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    if essence.eq_ignore_ascii_case("application/graphql") {
+        let query =
+            std::str::from_utf8(body).map_err(|_| Refusal::bad_request("query is not UTF-8"))?;
+        return Ok(vec![Request {
+            query: Some(query.to_string()),
+            operation_name: None,
+        }]);
+    }
+
+    let parsed: Value =
+        serde_json::from_slice(body).map_err(|_| Refusal::bad_request("body is not JSON"))?;
+    let batch = match parsed {
+        Value::Array(batch) if !batch.is_empty() => batch,
+        Value::Array(_) => return Err(Refusal::bad_request("empty batch")),
+        single => vec![single],
+    };
+    batch
+        .iter()
+        .map(|request| {
+            let query = request.get("query").and_then(Value::as_str);
+            let persisted = request.pointer("/extensions/persistedQuery").is_some();
+            if query.is_none() && !persisted {
+                return Err(Refusal::bad_request("missing query"));
+            }
+            Ok(Request {
+                query: query.map(str::to_string),
+                operation_name: request
+                    .get("operationName")
+                    .and_then(Value::as_str)
+                    .filter(|n| !n.is_empty())
+                    .map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token<'a> {
+    Name(&'a str),
+    Punct(u8),
+    Spread,
+    /// a string or number literal
+    Literal,
+}
+
+struct Document<'a> {
+    operations: Vec<OperationDef<'a>>,
+    fragments: HashMap<&'a str, Vec<Selection<'a>>>,
+}
+
+struct OperationDef<'a> {
+    kind: &'static str,
+    name: Option<&'a str>,
+    selections: Vec<Selection<'a>>,
+}
+
+enum Selection<'a> {
+    Field(Vec<Selection<'a>>),
+    Spread(&'a str),
+    Inline(Vec<Selection<'a>>),
+}
+
+/// Just enough of a GraphQL parser to find operations and their selections;
+/// arguments, variables and directives are skipped over.
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Result<Self, Refusal> {
+        Ok(Parser {
+            tokens: tokenize(source).ok_or_else(|| Refusal::bad_request("query does not parse"))?,
+            pos: 0,
+        })
+    }
+
+    fn document(mut self) -> Result<Document<'a>, Refusal> {
+        self.parse_document()
+            .ok_or_else(|| Refusal::bad_request("query does not parse"))
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: Token<'a>) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn name(&mut self) -> Option<&'a str> {
+        match self.next()? {
+            Token::Name(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    fn parse_document(&mut self) -> Option<Document<'a>> {
+        let mut document = Document {
+            operations: Vec::new(),
+            fragments: HashMap::new(),
+        };
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Punct(b'{') => document.operations.push(OperationDef {
+                    kind: "query",
+                    name: None,
+                    selections: self.selection_set(0)?,
+                }),
+                Token::Name(kind @ ("query" | "mutation" | "subscription")) => {
+                    self.pos += 1;
+                    let name = match self.peek()? {
+                        Token::Name(name) => {
+                            self.pos += 1;
+                            Some(name)
+                        }
+                        _ => None,
+                    };
+                    if self.peek()? == Token::Punct(b'(') {
+                        self.skip_group()?;
+                    }
+                    self.directives()?;
+                    let kind = match kind {
+                        "query" => "query",
+                        "mutation" => "mutation",
+                        _ => "subscription",
+                    };
+                    document.operations.push(OperationDef {
+                        kind,
+                        name,
+                        selections: self.selection_set(0)?,
+                    });
+                }
+                Token::Name("fragment") => {
+                    self.pos += 1;
+                    let name = self.name()?;
+                    if self.name()? != "on" {
+                        return None;
+                    }
+                    self.name()?;
+                    self.directives()?;
+                    let selections = self.selection_set(0)?;
+                    if document.fragments.insert(name, selections).is_some() {
+                        return None;
+                    }
+                }
+                _ => return None,
+            }
+        }
+        (!document.operations.is_empty()).then_some(document)
+    }
+
+    fn selection_set(&mut self, nesting: usize) -> Option<Vec<Selection<'a>>> {
+        if nesting > MAX_NESTING || !self.eat(Token::Punct(b'{')) {
+            return None;
+        }
+        let mut selections = Vec::new();
+        while !self.eat(Token::Punct(b'}')) {
+            let selection = match self.next()? {
+                Token::Spread => match self.peek()? {
+                    Token::Name("on") => {
+                        self.pos += 1;
+                        self.name()?;
+                        self.directives()?;
+                        Selection::Inline(self.selection_set(nesting + 1)?)
+                    }
+                    Token::Name(fragment) => {
+                        self.pos += 1;
+                        self.directives()?;
+                        Selection::Spread(fragment)
+                    }
+                    _ => {
+                        self.directives()?;
+                        Selection::Inline(self.selection_set(nesting + 1)?)
+                    }
+                },
+                Token::Name(_) => {
+                    if self.eat(Token::Punct(b':')) {
+                        self.name()?;
+                    }
+                    if self.peek()? == Token::Punct(b'(') {
+                        self.skip_group()?;
+                    }
+                    self.directives()?;
+                    let selections = if self.peek()? == Token::Punct(b'{') {
+                        self.selection_set(nesting + 1)?
+                    } else {
+                        Vec::new()
+                    };
+                    Selection::Field(selections)
+                }
+                _ => return None,
+            };
+            selections.push(selection);
+        }
+        (!selections.is_empty()).then_some(selections)
+    }
+
+    fn directives(&mut self) -> Option<()> {
+        while self.eat(Token::Punct(b'@')) {
+            self.name()?;
+            if self.peek() == Some(Token::Punct(b'(')) {
+                self.skip_group()?;
+            }
+        }
+        Some(())
+    }
+
+    /// Skip a parenthesized group, arguments or variable definitions.
+    fn skip_group(&mut self) -> Option<()> {
+        let mut open = 0usize;
+        loop {
+            match self.next()? {
+                Token::Punct(b'(') => open += 1,
+                Token::Punct(b')') => {
+                    open -= 1;
+                    if open == 0 {
+                        return Some(());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Option<Vec<Token<'_>>> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            // commas are insignificant, like whitespace
+            b' ' | b'\t' | b'\n' | b'\r' | b',' => i += 1,
+            // the byte order mark
+            0xef if bytes[i..].starts_with(&[0xef, 0xbb, 0xbf]) => i += 3,
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' && bytes[i] != b'\r' {
+                    i += 1;
+                }
+            }
+            b'.' => {
+                if !bytes[i..].starts_with(b"...") {
+                    return None;
+                }
+                tokens.push(Token::Spread);
+                i += 3;
+            }
+            b'"' if bytes[i..].starts_with(b"\"\"\"") => {
+                i += 3;
+                loop {
+                    if i >= bytes.len() {
+                        return None;
+                    }
+                    if bytes[i..].starts_with(b"\\\"\"\"") {
+                        i += 4;
+                    } else if bytes[i..].starts_with(b"\"\"\"") {
+                        i += 3;
+                        break;
+                    } else {
+                        i += 1;
+                    }
+                }
+                tokens.push(Token::Literal);
+            }
+            b'"' => {
+                i += 1;
+                loop {
+                    match bytes.get(i)? {
+                        b'"' => break,
+                        b'\\' => i += 2,
+                        b'\n' | b'\r' => return None,
+                        _ => i += 1,
+                    }
+                }
+                i += 1;
+                tokens.push(Token::Literal);
+            }
+            b'-' | b'0'..=b'9' => {
+                i += 1;
+                while i < bytes.len()
+                    && matches!(bytes[i], b'0'..=b'9' | b'.' | b'e' | b'E' | b'+' | b'-')
+                {
+                    i += 1;
+                }
+                tokens.push(Token::Literal);
+            }
+            b'_' | b'a'..=b'z' | b'A'..=b'Z' => {
+                let start = i;
+                while i < bytes.len() && (bytes[i] == b'_' || bytes[i].is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                tokens.push(Token::Name(&source[start..i]));
+            }
+            punct @ (b'!' | b'$' | b'&' | b'(' | b')' | b':' | b'=' | b'@' | b'[' | b']' | b'{'
+            | b'|' | b'}') => {
+                tokens.push(Token::Punct(punct));
+                i += 1;
+            }
+            _ => return None,
+        }
+    }
+    Some(tokens)
+}
+
+/// Depth and field count of an operation, refusing it as soon as it is over
+/// a limit.
+struct Measure<'d, 'a> {
+    fragments: &'d HashMap<&'a str, Vec<Selection<'a>>>,
+    max_depth: usize,
+    max_complexity: usize,
+    fields: usize,
+    /// fragments being expanded, to catch cycles
+    spreading: Vec<&'a str>,
+}
+
+impl<'a> Measure<'_, 'a> {
+    fn selections(&mut self, selections: &[Selection<'a>], depth: usize) -> Result<(), Refusal> {
+        if depth > self.max_depth {
+            return Err(Refusal::bad_request(format!(
+                "query is deeper than {} levels",
+                self.max_depth
+            )));
+        }
+        for selection in selections {
+            match selection {
+                Selection::Field(children) => {
+                    self.fields += 1;
+                    if self.fields > self.max_complexity {
+                        return Err(Refusal::bad_request(format!(
+                            "query selects more than {} fields",
+                            self.max_complexity
+                        )));
+                    }
+                    if !children.is_empty() {
+                        self.selections(children, depth + 1)?;
+                    }
+                }
+                Selection::Inline(children) => self.selections(children, depth)?,
+                Selection::Spread(name) => {
+                    if self.spreading.contains(name) {
+                        return Err(Refusal::bad_request(format!(
+                            "fragment {name} spreads itself"
+                        )));
+                    }
+                    let fragment = self
+                        .fragments
+                        .get(name)
+                        .ok_or_else(|| Refusal::bad_request(format!("unknown fragment {name}")))?;
+                    self.spreading.push(name);
+                    self.selections(fragment, depth)?;
+                    self.spreading.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+}
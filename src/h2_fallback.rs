@@ -0,0 +1,147 @@
+//! Falling back from HTTP/2 to HTTP/1.1 for upstreams that keep failing h2.
+//!
+//! Upstreams offered h2 (h2c clusters, or TLS peers negotiating it with ALPN)
+//! may still get it wrong: GOAWAY right after every connection, a handshake
+//! whose SETTINGS never come, protocol errors. After `threshold` such
+//! failures in a row an upstream is downgraded, it is sent HTTP/1.1 until its
+//! cooldown is over and h2 gets another chance. A successful h2 response
+//! resets the count. Downgrades are logged and counted, the admin API lists
+//! them.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::{Error, ErrorType};
+
+#[derive(Default)]
+struct Node {
+    failures: u32,
+    downgraded_until: Option<Instant>,
+}
+
+/// h2 failures and downgrades of all clusters, by address.
+pub struct H2Fallback {
+    threshold: u32,
+    cooldown: Duration,
+    nodes: RwLock<HashMap<SocketAddr, Node>>,
+    downgrades: AtomicU64,
+}
+
+impl Default for H2Fallback {
+    fn default() -> Self {
+        H2Fallback::new(3, Duration::from_secs(300))
+    }
+}
+
+impl H2Fallback {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        H2Fallback {
+            threshold: threshold.max(1),
+            cooldown,
+            nodes: RwLock::default(),
+            downgrades: AtomicU64::new(0),
+        }
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    /// Downgrades since startup.
+    pub fn downgrades(&self) -> u64 {
+        self.downgrades.load(Ordering::Relaxed)
+    }
+
+    /// Whether `addr` is to be sent HTTP/1.1 only.
+    pub fn is_downgraded(&self, addr: &SocketAddr) -> bool {
+        self.nodes
+            .read()
+            .unwrap()
+            .get(addr)
+            .and_then(|n| n.downgraded_until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// Count an h2 failure of `addr`; `true` if this downgraded it.
+    pub fn failed(&self, addr: &SocketAddr) -> bool {
+        let mut nodes = self.nodes.write().unwrap();
+        let node = nodes.entry(addr.clone()).or_default();
+        if node
+            .downgraded_until
+            .is_some_and(|until| until > Instant::now())
+        {
+            return false;
+        }
+        node.downgraded_until = None;
+        node.failures += 1;
+        if node.failures < self.threshold {
+            return false;
+        }
+        warn!(
+            "{addr} failed h2 {} times in a row, using HTTP/1.1 for {}s",
+            node.failures,
+            self.cooldown.as_secs()
+        );
+        node.failures = 0;
+        node.downgraded_until = Some(Instant::now() + self.cooldown);
+        self.downgrades.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// An h2 response came from `addr`.
+    pub fn succeeded(&self, addr: &SocketAddr) {
+        // the common case, without the write lock
+        if !self.nodes.read().unwrap().contains_key(addr) {
+            return;
+        }
+        let mut nodes = self.nodes.write().unwrap();
+        if nodes
+            .get(addr)
+            .is_some_and(|n| n.downgraded_until.is_none())
+        {
+            nodes.remove(addr);
+        }
+    }
+
+    /// The downgraded upstreams, with the time left of their cooldown.
+    pub fn list(&self) -> Vec<(SocketAddr, Duration)> {
+        let now = Instant::now();
+        let mut nodes = self.nodes.write().unwrap();
+        // forget cooldowns that are over on the way
+        nodes.retain(|_, n| n.failures > 0 || n.downgraded_until.is_some_and(|u| u > now));
+        let mut list: Vec<_> = nodes
+            .iter()
+            .filter_map(|(addr, n)| {
+                Some((
+                    addr.clone(),
+                    n.downgraded_until?.checked_duration_since(now)?,
+                ))
+            })
+            .collect();
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+        list
+    }
+}
+
+/// Whether `peer` may speak h2.
+pub(crate) fn offers_h2(peer: &HttpPeer) -> bool {
+    peer.options.alpn.get_max_http_version() == 2
+}
+
+/// Whether `e` counts as an h2 failure; timeouts and closes only on `fresh`
+/// connections, where they stand for the server's SETTINGS never coming.
+pub(crate) fn is_h2_failure(e: &Error, fresh: bool) -> bool {
+    match e.etype() {
+        ErrorType::H2Error
+        | ErrorType::InvalidH2
+        | ErrorType::H2Downgrade
+        | ErrorType::HandshakeError => true,
+        ErrorType::ReadTimedout | ErrorType::ConnectionClosed => fresh,
+        _ => false,
+    }
+}
@@ -0,0 +1,309 @@
+//! Downstream HTTP/2 with tunable settings.
+//!
+//! [`H2Server`] wraps the proxy service to accept HTTP/2 connections itself,
+//! with the [`H2Settings`] of the listener: the SETTINGS advertised to
+//! clients, PINGs that close connections whose client stopped answering, and
+//! a cap on requests per connection after which a GOAWAY asks the client to
+//! move to a new one. The [`Keepalive`] of the listener sends a GOAWAY too,
+//! to connections past its caps or idle for its timeout. HTTP/1.x
+//! connections are handed to the service as they are. Every connection is registered in [`Connections`] while open, and
+//! dropped when asked to close. With the PROXY protocol on, the header
+//! starting each connection is read first, see [`crate::original_dst`].
+
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use pingora::apps::{HttpServerApp, ServerApp};
+use pingora::protocols::http::ServerSession;
+use pingora::protocols::http::v2::server::{self, H2Accept, HttpSession, default_h2_options};
+use pingora::protocols::{ALPN, Digest, Stream};
+use pingora::server::ShutdownWatch;
+use tokio::time::{Instant, sleep_until};
+
+use crate::connections::Connections;
+use crate::keepalive::Keepalive;
+use crate::original_dst;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// HTTP/2 settings of a listener; `None`s keep the defaults of the h2 stack.
+#[derive(Clone, Debug)]
+pub struct H2Settings {
+    /// Accept HTTP/2 without TLS, with prior knowledge.
+    pub h2c: bool,
+    pub max_concurrent_streams: u32,
+    pub initial_stream_window: Option<u32>,
+    pub initial_connection_window: Option<u32>,
+    pub max_frame_size: Option<u32>,
+    /// PING idle clients this often, `None` to never.
+    pub ping_interval: Option<Duration>,
+    /// Close the connection when a PING is not answered within this.
+    pub ping_timeout: Duration,
+    /// Requests after which a connection is sent GOAWAY, to recycle it;
+    /// the lower of this and [`Keepalive::max_requests`] applies.
+    pub max_requests: Option<u64>,
+}
+
+impl Default for H2Settings {
+    fn default() -> Self {
+        H2Settings {
+            h2c: false,
+            max_concurrent_streams: 100,
+            initial_stream_window: None,
+            initial_connection_window: None,
+            max_frame_size: None,
+            ping_interval: None,
+            ping_timeout: Duration::from_secs(20),
+            max_requests: None,
+        }
+    }
+}
+
+impl H2Settings {
+    fn options(&self) -> server::H2Options {
+        let mut options = default_h2_options();
+        options.max_concurrent_streams(self.max_concurrent_streams);
+        if let Some(window) = self.initial_stream_window {
+            options.initial_window_size(window);
+        }
+        if let Some(window) = self.initial_connection_window {
+            options.initial_connection_window_size(window);
+        }
+        if let Some(size) = self.max_frame_size {
+            options.max_frame_size(size);
+        }
+        options
+    }
+}
+
+pub struct H2Server<A> {
+    app: Arc<A>,
+    settings: H2Settings,
+    keepalive: Keepalive,
+    connections: Arc<Connections>,
+    proxy_protocol: bool,
+}
+
+impl<A> H2Server<A> {
+    pub fn new(app: A, settings: H2Settings) -> Self {
+        H2Server {
+            app: Arc::new(app),
+            settings,
+            keepalive: Keepalive::default(),
+            connections: Arc::default(),
+            proxy_protocol: false,
+        }
+    }
+
+    /// Recycle h2 connections by `keepalive`, the listener's; HTTP/1 ones
+    /// are recycled by the proxy.
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Register the open connections in `connections`.
+    pub fn with_connections(mut self, connections: Arc<Connections>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Read a PROXY protocol header at the start of every connection, and
+    /// close those without one.
+    pub fn with_proxy_protocol(mut self) -> Self {
+        self.proxy_protocol = true;
+        self
+    }
+
+    /// Whether `stream` carries HTTP/2, negotiated or with prior knowledge.
+    async fn is_h2(&self, stream: &mut Stream) -> Option<bool> {
+        if stream.get_ssl_digest().is_some() {
+            return Some(matches!(stream.selected_alpn_proto(), Some(ALPN::H2)));
+        }
+        if !self.settings.h2c {
+            return Some(false);
+        }
+        let mut buf = [0; PREFACE.len()];
+        let peeked = stream
+            .try_peek(&mut buf)
+            .await
+            .map_err(|e| debug!("read error while peeking for the h2c preface: {e}"))
+            .ok()?;
+        Some(peeked && buf == PREFACE)
+    }
+}
+
+#[async_trait]
+impl<A> ServerApp for H2Server<A>
+where
+    A: HttpServerApp + Send + Sync + 'static,
+{
+    async fn process_new(
+        self: &Arc<Self>,
+        mut stream: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        if self.proxy_protocol {
+            let header = original_dst::accept_header(&mut stream);
+            match tokio::time::timeout(original_dst::HEADER_TIMEOUT, header).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    debug!("closing connection: {e}");
+                    return None;
+                }
+                Err(_) => {
+                    debug!("closing connection: no PROXY protocol header in time");
+                    return None;
+                }
+            }
+        }
+        let h2 = self.is_h2(&mut stream).await?;
+        let tls = stream.get_ssl_digest().is_some();
+        let protocol = match (h2, tls) {
+            (false, _) => "http/1",
+            (true, true) => "h2",
+            (true, false) => "h2c",
+        };
+        let registration = stream
+            .get_socket_digest()
+            .and_then(|d| d.peer_addr().cloned())
+            .map(|client| self.connections.register(client, protocol, tls));
+        let close = async {
+            match &registration {
+                Some(registration) => registration.connection.closed().await,
+                None => std::future::pending().await,
+            }
+        };
+        if !h2 {
+            return tokio::select! {
+                reused = self.app.process_new(stream, shutdown) => reused,
+                _ = close => None,
+            };
+        }
+        self.serve_h2(stream, shutdown, close).await
+    }
+
+    async fn cleanup(&self) {
+        self.app.http_cleanup().await;
+    }
+}
+
+impl<A> H2Server<A>
+where
+    A: HttpServerApp + Send + Sync + 'static,
+{
+    async fn serve_h2(
+        self: &Arc<Self>,
+        stream: Stream,
+        shutdown: &ShutdownWatch,
+        close: impl Future<Output = ()>,
+    ) -> Option<Stream> {
+        let digest = Arc::new(Digest {
+            ssl_digest: stream.get_ssl_digest(),
+            timing_digest: stream.get_timing_digest(),
+            proxy_digest: stream.get_proxy_digest(),
+            socket_digest: stream.get_socket_digest(),
+        });
+        let mut conn = match server::handshake(stream, Some(self.settings.options())).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!("h2 handshake with client failed: {e}");
+                return None;
+            }
+        };
+        let mut ping_pong = conn.ping_pong();
+        let ping_interval = self.settings.ping_interval;
+        let mut next_ping = ping_interval.map(|i| Instant::now() + i);
+        let mut pong_due: Option<Instant> = None;
+        let mut shutdown = shutdown.clone();
+        let mut closing = false;
+        let mut served = 0;
+        let max_requests = match (self.settings.max_requests, self.keepalive.max_requests) {
+            (Some(h2), Some(any)) => Some(h2.min(any)),
+            (h2, any) => h2.or(any),
+        };
+        let too_old = self.keepalive.max_age.map(|age| Instant::now() + age);
+        let idle_timeout = self.keepalive.idle_timeout;
+        let mut idle_until = idle_timeout.map(|idle| Instant::now() + idle);
+        let streams = Arc::new(AtomicUsize::new(0));
+        tokio::pin!(close);
+        loop {
+            tokio::select! {
+                biased;
+                _ = &mut close => return None,
+                _ = shutdown.changed(), if !closing => {
+                    conn.graceful_shutdown();
+                    closing = true;
+                }
+                _ = sleep_until(too_old.unwrap_or_else(Instant::now)),
+                    if too_old.is_some() && !closing =>
+                {
+                    debug!("h2 connection reached its maximum age, sending GOAWAY");
+                    conn.graceful_shutdown();
+                    closing = true;
+                }
+                _ = sleep_until(idle_until.unwrap_or_else(Instant::now)),
+                    if idle_until.is_some() && !closing =>
+                {
+                    if streams.load(Ordering::Relaxed) > 0 {
+                        idle_until = idle_timeout.map(|idle| Instant::now() + idle);
+                        continue;
+                    }
+                    debug!("h2 connection idle for too long, sending GOAWAY");
+                    conn.graceful_shutdown();
+                    closing = true;
+                }
+                _ = sleep_until(pong_due.unwrap_or_else(Instant::now)), if pong_due.is_some() => {
+                    warn!("h2 client did not answer a PING, closing its connection");
+                    return None;
+                }
+                pong = std::future::poll_fn(|cx| ping_pong.as_mut().unwrap().poll_pong(cx)),
+                    if pong_due.is_some() =>
+                {
+                    if pong.is_err() {
+                        return None;
+                    }
+                    pong_due = None;
+                    next_ping = ping_interval.map(|i| Instant::now() + i);
+                }
+                _ = sleep_until(next_ping.unwrap_or_else(Instant::now)),
+                    if next_ping.is_some() && pong_due.is_none() && ping_pong.is_some() =>
+                {
+                    let sent = ping_pong.as_mut().unwrap().send_ping(h2::Ping::opaque());
+                    if sent.is_err() {
+                        return None;
+                    }
+                    next_ping = None;
+                    pong_due = Some(Instant::now() + self.settings.ping_timeout);
+                }
+                accepted = HttpSession::from_h2_conn(&mut conn, digest.clone()) => {
+                    let session = match accepted {
+                        Ok(Some(H2Accept::Session(session))) => session,
+                        Ok(Some(H2Accept::Rejected)) => continue,
+                        // closed, or the client went away
+                        Ok(None) | Err(_) => return None,
+                    };
+                    let app = self.app.clone();
+                    let shutdown = shutdown.clone();
+                    let open = streams.clone();
+                    open.fetch_add(1, Ordering::Relaxed);
+                    tokio::spawn(async move {
+                        app.process_new_http(ServerSession::new_http2(session), &shutdown)
+                            .await;
+                        open.fetch_sub(1, Ordering::Relaxed);
+                    });
+                    served += 1;
+                    idle_until = idle_timeout.map(|idle| Instant::now() + idle);
+                    if !closing && max_requests.is_some_and(|max| served >= max) {
+                        conn.graceful_shutdown();
+                        closing = true;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,492 @@
+//! HAR archives of sampled traffic.
+//!
+//! [`HarRecorder`] writes requests picked by sampling and [`HarConfig`]'s
+//! filters to a directory, one HAR 1.2 file per request, for debugging issues
+//! that only reproduce through the proxy in the browser tools front-end teams
+//! already use. Archives are sanitized before they touch the disk: the values
+//! of credential headers, of all cookies and of sensitive query parameters are
+//! replaced, as are sensitive fields of JSON and form bodies. Other text
+//! bodies are kept up to `max_body` bytes; binary and compressed bodies are
+//! left out.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use http::{HeaderMap, header};
+use log::warn;
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde_json::{Value, json};
+
+use crate::ctx::UpstreamTiming;
+use crate::template;
+
+/// What replaces sanitized values.
+pub const REDACTED: &str = "REDACTED";
+
+#[derive(Clone, Debug)]
+pub struct HarConfig {
+    /// Share of the matching requests archived, `0.0..=1.0`.
+    pub sample_rate: f64,
+    /// Routes whose requests are archived, all if empty.
+    pub routes: Vec<String>,
+    /// Only archive requests whose path starts with this.
+    pub path_prefix: Option<String>,
+    /// Only archive responses with at least this status, e.g. 500 for errors.
+    pub min_status: Option<u16>,
+    /// Bytes of each body kept.
+    pub max_body: usize,
+    /// Archives written at most, `None` for no limit.
+    pub max_files: Option<u64>,
+    /// Headers whose values are redacted, lowercase.
+    pub redact_headers: Vec<String>,
+    /// Query parameters and body fields whose values are redacted, lowercase.
+    pub redact_fields: Vec<String>,
+}
+
+impl Default for HarConfig {
+    fn default() -> Self {
+        let list = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+        HarConfig {
+            sample_rate: 0.01,
+            routes: Vec::new(),
+            path_prefix: None,
+            min_status: None,
+            max_body: 64 * 1024,
+            max_files: Some(1000),
+            redact_headers: list(&[
+                "authorization",
+                "proxy-authorization",
+                "cookie",
+                "set-cookie",
+                "x-api-key",
+                "x-auth-token",
+                "x-csrf-token",
+            ]),
+            redact_fields: list(&[
+                "access_token",
+                "api_key",
+                "apikey",
+                "client_secret",
+                "code",
+                "key",
+                "password",
+                "refresh_token",
+                "secret",
+                "sig",
+                "signature",
+                "token",
+            ]),
+        }
+    }
+}
+
+/// A body as far as it is kept, with its full size.
+#[derive(Default)]
+struct Body {
+    data: Vec<u8>,
+    size: usize,
+}
+
+impl Body {
+    fn push(&mut self, chunk: &[u8], max: usize) {
+        self.size += chunk.len();
+        let room = max.saturating_sub(self.data.len());
+        self.data.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    }
+
+    fn truncated(&self) -> bool {
+        self.data.len() < self.size
+    }
+}
+
+/// Cookies of `name=value` pairs, with their values redacted.
+fn cookies<'a>(pairs: impl Iterator<Item = &'a str>) -> Vec<Value> {
+    pairs
+        .filter_map(|c| c.split_once('='))
+        .map(|(name, _)| json!({ "name": name.trim(), "value": REDACTED }))
+        .collect()
+}
+
+fn header_values(headers: &HeaderMap, name: header::HeaderName) -> Vec<&str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect()
+}
+
+/// An archived request, from its header to its response.
+pub(crate) struct Capture {
+    started: SystemTime,
+    request: RequestHeader,
+    https: bool,
+    max_body: usize,
+    request_body: Body,
+    response_body: Body,
+}
+
+impl Capture {
+    pub(crate) fn request_body(&mut self, chunk: &[u8]) {
+        self.request_body.push(chunk, self.max_body);
+    }
+
+    pub(crate) fn response_body(&mut self, chunk: &[u8]) {
+        self.response_body.push(chunk, self.max_body);
+    }
+}
+
+/// How a request went, for [`HarRecorder::finish`].
+pub(crate) struct Exchange<'a> {
+    pub response: Option<&'a ResponseHeader>,
+    pub request_id: &'a str,
+    pub route: &'a str,
+    pub upstream: Option<String>,
+    pub timing: &'a UpstreamTiming,
+    pub total: Duration,
+}
+
+pub struct HarRecorder {
+    dir: PathBuf,
+    config: HarConfig,
+    hasher: RandomState,
+    written: AtomicU64,
+}
+
+impl HarRecorder {
+    /// Archive to `dir`, which is created if missing.
+    pub fn new(dir: impl Into<PathBuf>, config: HarConfig) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(HarRecorder {
+            dir,
+            config,
+            hasher: RandomState::new(),
+            written: AtomicU64::new(0),
+        })
+    }
+
+    /// Archives written since startup.
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Start archiving `req` if it passes the filters and is sampled;
+    /// `buffered` is its body if already read.
+    pub(crate) fn start(
+        &self,
+        req: &RequestHeader,
+        route: &str,
+        request_id: &str,
+        https: bool,
+        buffered: Option<Bytes>,
+    ) -> Option<Capture> {
+        let config = &self.config;
+        if config.max_files.is_some_and(|max| self.written() >= max)
+            || !(config.routes.is_empty() || config.routes.iter().any(|r| r == route))
+            || config
+                .path_prefix
+                .as_ref()
+                .is_some_and(|prefix| !req.uri.path().starts_with(prefix.as_str()))
+        {
+            return None;
+        }
+        let draw = self.hasher.hash_one(request_id) as f64 / u64::MAX as f64;
+        if draw >= config.sample_rate {
+            return None;
+        }
+        let mut capture = Capture {
+            started: SystemTime::now(),
+            request: req.clone(),
+            https,
+            max_body: config.max_body,
+            request_body: Body::default(),
+            response_body: Body::default(),
+        };
+        if let Some(body) = buffered {
+            capture.request_body(&body);
+        }
+        Some(capture)
+    }
+
+    /// Write the archive of `capture`, unless its status is filtered out.
+    pub(crate) fn finish(&self, capture: Capture, exchange: Exchange) {
+        let status = exchange.response.map_or(0, |r| r.status.as_u16());
+        if self.config.min_status.is_some_and(|min| status < min) {
+            return;
+        }
+        let max = self.config.max_files;
+        if self
+            .written
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                max.is_none_or(|max| n < max).then_some(n + 1)
+            })
+            .is_err()
+        {
+            return;
+        }
+        let har = self.archive(&capture, &exchange);
+        let millis = capture
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let id: String = exchange
+            .request_id
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .collect();
+        let path = self.dir.join(format!("{millis}-{id}.har"));
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = std::fs::write(&path, har.to_string()) {
+                warn!("writing HAR archive {}: {e}", path.display());
+            }
+        });
+    }
+
+    fn archive(&self, capture: &Capture, exchange: &Exchange) -> Value {
+        let req = &capture.request;
+        let host = req
+            .headers
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri.host())
+            .unwrap_or("-");
+        let scheme = if capture.https { "https" } else { "http" };
+        let query: Vec<(String, String)> =
+            req.uri.query().map(|q| self.pairs(q)).unwrap_or_default();
+        let mut url = format!("{scheme}://{host}{}", req.uri.path());
+        if !query.is_empty() {
+            let joined: Vec<String> = query.iter().map(|(n, v)| format!("{n}={v}")).collect();
+            url = format!("{url}?{}", joined.join("&"));
+        }
+
+        let mut request = json!({
+            "method": req.method.as_str(),
+            "url": url,
+            "httpVersion": format!("{:?}", req.version),
+            "cookies": cookies(
+                header_values(&req.headers, header::COOKIE)
+                    .into_iter()
+                    .flat_map(|v| v.split(';')),
+            ),
+            "headers": self.headers(&req.headers),
+            "queryString": query
+                .iter()
+                .map(|(name, value)| json!({ "name": name, "value": value }))
+                .collect::<Vec<_>>(),
+            "headersSize": -1,
+            "bodySize": capture.request_body.size,
+        });
+        if capture.request_body.size > 0 {
+            let (mime, text, comment) = self.body(&req.headers, &capture.request_body);
+            request["postData"] = json!({ "mimeType": mime, "text": text.unwrap_or_default() });
+            if let Some(comment) = comment {
+                request["postData"]["comment"] = comment.into();
+            }
+        }
+
+        let response = match exchange.response {
+            Some(resp) => {
+                let (mime, text, comment) = self.body(&resp.headers, &capture.response_body);
+                let mut content = json!({
+                    "size": capture.response_body.size,
+                    "mimeType": mime,
+                });
+                if let Some(text) = text {
+                    content["text"] = text.into();
+                }
+                if let Some(comment) = comment {
+                    content["comment"] = comment.into();
+                }
+                json!({
+                    "status": resp.status.as_u16(),
+                    "statusText": resp.status.canonical_reason().unwrap_or(""),
+                    "httpVersion": format!("{:?}", resp.version),
+                    "cookies": cookies(
+                        header_values(&resp.headers, header::SET_COOKIE)
+                            .into_iter()
+                            .filter_map(|v| v.split(';').next()),
+                    ),
+                    "headers": self.headers(&resp.headers),
+                    "content": content,
+                    "redirectURL": resp
+                        .headers
+                        .get(header::LOCATION)
+                        .and_then(|l| l.to_str().ok())
+                        .unwrap_or(""),
+                    "headersSize": -1,
+                    "bodySize": capture.response_body.size,
+                })
+            }
+            None => json!({
+                "status": 0,
+                "statusText": "",
+                "httpVersion": "",
+                "cookies": [],
+                "headers": [],
+                "content": { "size": 0, "mimeType": "" },
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": -1,
+                "comment": "no response was sent",
+            }),
+        };
+
+        let ms = |d: Option<Duration>| d.map_or(-1.0, |d| d.as_secs_f64() * 1000.0);
+        let timing = exchange.timing;
+        let total = exchange.total.as_secs_f64() * 1000.0;
+        let connect = match (timing.connect, timing.tls) {
+            (None, None) => -1.0,
+            (connect, tls) => ms(Some(connect.unwrap_or_default() + tls.unwrap_or_default())),
+        };
+        let wait = ms(timing.ttfb).max(0.0);
+        let receive = (total - ms(timing.dns).max(0.0) - connect.max(0.0) - wait).max(0.0);
+        let mut entry = json!({
+            "startedDateTime": template::timestamp(capture.started),
+            "time": total,
+            "request": request,
+            "response": response,
+            "cache": {},
+            "timings": {
+                "dns": ms(timing.dns),
+                "connect": connect,
+                "ssl": ms(timing.tls),
+                "send": 0,
+                "wait": wait,
+                "receive": receive,
+            },
+            "comment": format!("request {} on route {}", exchange.request_id, exchange.route),
+        });
+        if let Some(upstream) = &exchange.upstream {
+            entry["serverIPAddress"] = upstream.as_str().into();
+        }
+        json!({
+            "log": {
+                "version": "1.2",
+                "creator": { "name": "proxy-rs", "version": env!("CARGO_PKG_VERSION") },
+                "entries": [entry],
+            }
+        })
+    }
+
+    fn headers(&self, headers: &HeaderMap) -> Vec<Value> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if self
+                    .config
+                    .redact_headers
+                    .iter()
+                    .any(|h| h == name.as_str())
+                {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                json!({ "name": name.as_str(), "value": value })
+            })
+            .collect()
+    }
+
+    fn redacted(&self, field: &str) -> bool {
+        let field = field.to_ascii_lowercase();
+        self.config.redact_fields.contains(&field)
+    }
+
+    /// The `name=value` pairs of a query or form body, sanitized.
+    fn pairs(&self, query: &str) -> Vec<(String, String)> {
+        query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let value = if self.redacted(name) { REDACTED } else { value };
+                (name.to_string(), value.to_string())
+            })
+            .collect()
+    }
+
+    fn redact_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (name, value) in fields {
+                    if self.redacted(name) {
+                        *value = REDACTED.into();
+                    } else {
+                        self.redact_json(value);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|v| self.redact_json(v)),
+            _ => {}
+        }
+    }
+
+    /// The mime type and sanitized text of a body, with why text is missing
+    /// or cut.
+    fn body(
+        &self,
+        headers: &HeaderMap,
+        body: &Body,
+    ) -> (String, Option<String>, Option<&'static str>) {
+        let mime = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        if body.size == 0 {
+            return (mime, None, None);
+        }
+        if headers
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|e| e.as_bytes() != b"identity")
+        {
+            return (mime, None, Some("compressed body left out"));
+        }
+        let essence = mime
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        let text = String::from_utf8_lossy(&body.data);
+        if essence == "application/json" || essence.ends_with("+json") {
+            if body.truncated() {
+                return (mime, None, Some("JSON body over the size limit left out"));
+            }
+            return match serde_json::from_str::<Value>(&text) {
+                Ok(mut value) => {
+                    self.redact_json(&mut value);
+                    (mime, Some(value.to_string()), None)
+                }
+                Err(_) => (mime, None, Some("unreadable JSON body left out")),
+            };
+        }
+        let cut = body.truncated().then_some("body cut at the size limit");
+        if essence == "application/x-www-form-urlencoded" {
+            let pairs: Vec<String> = self
+                .pairs(&text)
+                .iter()
+                .map(|(n, v)| format!("{n}={v}"))
+                .collect();
+            return (mime, Some(pairs.join("&")), cut);
+        }
+        if essence.starts_with("text/")
+            || essence.ends_with("+xml")
+            || [
+                "application/xml",
+                "application/javascript",
+                "application/graphql",
+            ]
+            .contains(&essence.as_str())
+        {
+            return (mime, Some(text.into_owned()), cut);
+        }
+        (mime, None, Some("binary body left out"))
+    }
+}
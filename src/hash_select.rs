@@ -0,0 +1,294 @@
+//! Consistent hashing selection of upstreams.
+//!
+//! A route with [`HashSelection`] sends each request to the node its key
+//! maps to on a [`Continuum`] of the cluster, so requests with one key keep
+//! landing on one upstream, as caches behind the proxy want, and only the
+//! keys of an upstream that joins or leaves move. When that node cannot take
+//! the request, because it is unhealthy, draining, its circuit is open or it
+//! is at its in-flight cap, the next distinct nodes around the ring are
+//! tried in turn. Requests without the key, and those finding no node, are
+//! selected round robin. Keys and points hash with crc32, as nginx's do,
+//! unless [`HashSelection::with_hasher`] picks another hash function, and
+//! [`HashSelection::with_layout`] can lay the ring out as libmemcached does.
+//!
+//! [`HashSelection::with_algorithm`] looks keys up in a [`Maglev`] table or
+//! by [`Rendezvous`] scores instead of on the ring, the next distinct nodes
+//! being those of the following slots or the next highest scores. Their
+//! nodes are laid out again whenever the cluster's backends change.
+//!
+//! [`Continuum`]: crate::consistent_hash::Continuum
+
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use arc_swap::ArcSwapOption;
+use http::header;
+use log::warn;
+use pingora::http::RequestHeader;
+use pingora::lb::{Backend, LoadBalancer, selection::RoundRobin};
+
+use crate::consistent_hash::{
+    Bucket, ClusterRing, Continuum, HashFunction, Layout, Lookup, PoolHint,
+};
+use crate::maglev::Maglev;
+use crate::rendezvous::Rendezvous;
+
+/// What a request is hashed by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HashKey {
+    /// The path and query.
+    Uri,
+    Header(String),
+    Cookie(String),
+}
+
+/// How keys are looked up among the nodes of a cluster.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// On a [`Continuum`].
+    #[default]
+    Ring,
+    /// In a [`Maglev`] table.
+    Maglev,
+    /// By [`Rendezvous`] scores.
+    Rendezvous,
+}
+
+impl Algorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Ring => "ring",
+            Algorithm::Maglev => "maglev",
+            Algorithm::Rendezvous => "rendezvous",
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    /// `ring`, `maglev` or `rendezvous`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ring" => Ok(Algorithm::Ring),
+            "maglev" => Ok(Algorithm::Maglev),
+            "rendezvous" => Ok(Algorithm::Rendezvous),
+            _ => Err(format!("unknown hashing algorithm {s}")),
+        }
+    }
+}
+
+pub struct HashSelection {
+    key: HashKey,
+    algorithm: Algorithm,
+    ring: ClusterRing,
+    /// the nodes of the cluster's backends for another algorithm than the
+    /// ring
+    table: ArcSwapOption<BackendTable>,
+}
+
+/// The nodes of one set of backends, laid out by [`Algorithm::Maglev`] or
+/// [`Algorithm::Rendezvous`].
+struct BackendTable {
+    backends: Arc<BTreeSet<Backend>>,
+    /// `None` when the nodes could not be laid out
+    nodes: Option<Nodes>,
+    by_addr: HashMap<SocketAddr, Backend>,
+}
+
+enum Nodes {
+    Maglev(Maglev<HashFunction>),
+    Rendezvous(Rendezvous<HashFunction>),
+}
+
+/// Where keys map to, whichever the algorithm.
+trait Lookups {
+    fn lookup(&self, key: &[u8]) -> Option<Lookup>;
+
+    /// The first `n` distinct nodes of `key`, in the order they are tried.
+    fn lookups(&self, key: &[u8], n: usize) -> Vec<Lookup>;
+}
+
+impl Lookups for Continuum<HashFunction> {
+    fn lookup(&self, key: &[u8]) -> Option<Lookup> {
+        Continuum::lookup(self, key)
+    }
+
+    fn lookups(&self, key: &[u8], n: usize) -> Vec<Lookup> {
+        Continuum::lookups(self, key, n)
+    }
+}
+
+impl Lookups for Maglev<HashFunction> {
+    fn lookup(&self, key: &[u8]) -> Option<Lookup> {
+        Maglev::lookup(self, key)
+    }
+
+    fn lookups(&self, key: &[u8], n: usize) -> Vec<Lookup> {
+        Maglev::lookups(self, key, n)
+    }
+}
+
+impl Lookups for Rendezvous<HashFunction> {
+    fn lookup(&self, key: &[u8]) -> Option<Lookup> {
+        Rendezvous::lookup(self, key)
+    }
+
+    fn lookups(&self, key: &[u8], n: usize) -> Vec<Lookup> {
+        Rendezvous::lookups(self, key, n)
+    }
+}
+
+impl HashSelection {
+    pub fn new(key: HashKey) -> Self {
+        HashSelection {
+            key,
+            algorithm: Algorithm::default(),
+            ring: ClusterRing::default(),
+            table: ArcSwapOption::empty(),
+        }
+    }
+
+    /// Look keys up by `algorithm` rather than on a ring.
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Hash keys, and the points of an nginx layout, by `hasher`.
+    pub fn with_hasher(mut self, hasher: HashFunction) -> Self {
+        self.ring = ClusterRing::new(self.ring.layout(), hasher);
+        self
+    }
+
+    /// Lay the cluster out by `layout`.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.ring = ClusterRing::new(layout, self.ring.hasher());
+        self
+    }
+
+    pub fn key_source(&self) -> &HashKey {
+        &self.key
+    }
+
+    pub fn hasher(&self) -> HashFunction {
+        self.ring.hasher()
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.ring.layout()
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// The key of `req`, `None` when it has none.
+    pub(crate) fn key<'a>(&self, req: &'a RequestHeader) -> Option<Cow<'a, [u8]>> {
+        let key = match &self.key {
+            HashKey::Uri => {
+                let uri = req.uri.path_and_query().map_or("/", |p| p.as_str());
+                return Some(Cow::Borrowed(uri.as_bytes()));
+            }
+            HashKey::Header(name) => req.headers.get(name.as_str()).map(|v| v.as_bytes()),
+            HashKey::Cookie(name) => req
+                .headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|c| c.trim().split_once('='))
+                .find_map(|(n, value)| (n == name).then_some(value.as_bytes())),
+        };
+        key.filter(|k| !k.is_empty()).map(Cow::Borrowed)
+    }
+
+    /// The node of `key` if it is `usable`, or else the first usable one of
+    /// the next distinct nodes, with its pool.
+    pub(crate) fn select(
+        &self,
+        upstreams: &LoadBalancer<RoundRobin>,
+        key: &[u8],
+        usable: impl Fn(&Backend, bool) -> bool,
+    ) -> Option<(Backend, PoolHint)> {
+        let backends = upstreams.backends();
+        let usable =
+            |backend: Option<&Backend>| backend.filter(|b| usable(b, backends.ready(b))).cloned();
+        if self.algorithm == Algorithm::Ring {
+            let ring = self.ring.get(upstreams);
+            return pick(&ring.continuum, key, |node| usable(ring.backend(node)));
+        }
+        let table = self.table(upstreams);
+        let backend = |node: &SocketAddr| usable(table.by_addr.get(node));
+        match table.nodes.as_ref()? {
+            Nodes::Maglev(maglev) => pick(maglev, key, backend),
+            Nodes::Rendezvous(rendezvous) => pick(rendezvous, key, backend),
+        }
+    }
+
+    /// The nodes of the cluster's current backends, by an algorithm other
+    /// than the ring.
+    fn table(&self, upstreams: &LoadBalancer<RoundRobin>) -> Arc<BackendTable> {
+        let backends = upstreams.backends().get_backend();
+        if let Some(table) = self.table.load_full()
+            && Arc::ptr_eq(&table.backends, &backends)
+        {
+            return table;
+        }
+
+        let mut buckets = Vec::with_capacity(backends.len());
+        let mut by_addr = HashMap::with_capacity(backends.len());
+        for backend in backends.iter() {
+            if let Some(bucket) = Bucket::from_backend(backend) {
+                buckets.push(bucket);
+                by_addr.insert(bucket.node, backend.clone());
+            }
+        }
+        let hasher = self.ring.hasher();
+        let nodes = match self.algorithm {
+            Algorithm::Maglev => {
+                let size = crate::maglev::DEFAULT_TABLE_SIZE;
+                match Maglev::with_hasher(&buckets, size, hasher) {
+                    Ok(maglev) => Some(Nodes::Maglev(maglev)),
+                    Err(e) => {
+                        warn!("selecting round robin: {e}");
+                        None
+                    }
+                }
+            }
+            Algorithm::Rendezvous => {
+                Some(Nodes::Rendezvous(Rendezvous::with_hasher(&buckets, hasher)))
+            }
+            // looked up on the cluster ring instead
+            Algorithm::Ring => None,
+        };
+        let table = Arc::new(BackendTable {
+            backends,
+            nodes,
+            by_addr,
+        });
+        self.table.store(Some(table.clone()));
+        table
+    }
+}
+
+/// The node of `key` in `nodes` if `usable` gives its backend, or else the
+/// first of the next distinct nodes it gives one of, with its pool.
+fn pick(
+    nodes: &impl Lookups,
+    key: &[u8],
+    usable: impl Fn(&SocketAddr) -> Option<Backend>,
+) -> Option<(Backend, PoolHint)> {
+    let first = nodes.lookup(key)?;
+    if let Some(backend) = usable(&first.node) {
+        return Some((backend, first.pool));
+    }
+    nodes
+        .lookups(key, usize::MAX)
+        .into_iter()
+        .skip(1)
+        .find_map(|l| Some((usable(&l.node)?, l.pool)))
+}
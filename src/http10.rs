@@ -0,0 +1,51 @@
+//! Compatibility handling for HTTP/1.0 clients.
+//!
+//! Legacy health checkers and embedded devices still speak HTTP/1.0: they may
+//! omit `Host`, cannot parse chunked bodies and expect the server to close the
+//! connection. When enabled on a listener, requests from such clients are
+//! pinned to a default virtual host and responses are re-framed so they are
+//! delimited by connection close instead of chunked encoding.
+
+use http::{Version, header};
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+
+/// Per-listener HTTP/1.0 compatibility settings.
+#[derive(Clone, Debug)]
+pub struct Http10Compat {
+    /// Host used for requests that arrive without a `Host` header.
+    pub default_host: String,
+}
+
+impl Http10Compat {
+    pub fn new(default_host: impl Into<String>) -> Self {
+        Http10Compat {
+            default_host: default_host.into(),
+        }
+    }
+
+    /// Whether the client spoke HTTP/1.0 (or older).
+    pub fn applies_to(req: &RequestHeader) -> bool {
+        req.version <= Version::HTTP_10
+    }
+
+    /// Fill in the default virtual host when the client sent no `Host`.
+    pub fn fix_request(&self, req: &mut RequestHeader) -> Result<()> {
+        if !req.headers.contains_key(header::HOST) {
+            req.insert_header(header::HOST, &self.default_host)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite a response so an HTTP/1.0 client can parse it.
+    ///
+    /// `Transfer-Encoding` is dropped so the body falls back to being
+    /// delimited by connection close when no `Content-Length` is known, and the
+    /// connection is always marked for close.
+    pub fn fix_response(&self, resp: &mut ResponseHeader) -> Result<()> {
+        resp.set_version(Version::HTTP_10);
+        resp.remove_header(&header::TRANSFER_ENCODING);
+        resp.insert_header(header::CONNECTION, "close")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+//! Request deduplication by `Idempotency-Key`.
+//!
+//! On a route with [`Idempotency`], a request carrying an `Idempotency-Key`
+//! header is buffered and sent upstream once; its response is kept for `ttl`
+//! and replayed, marked `Idempotent-Replayed: true`, to every later request
+//! of the same client with the same key. Duplicates arriving while the first
+//! request is still in flight wait for its response instead of reaching the
+//! upstream, so client retries cannot charge a payment twice.
+//!
+//! A key reused with a different method, path or body is refused with a 422.
+//! Server errors (5xx) are not kept, so the client can retry after a failure.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use log::warn;
+use pingora::http::{RequestHeader, ResponseHeader};
+use ring::digest;
+use tokio::sync::watch;
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+/// Longest key accepted; longer ones are not deduplicated.
+const MAX_KEY: usize = 255;
+
+pub struct Idempotency {
+    ttl: Duration,
+    max_body: usize,
+    max_entries: usize,
+    /// by client and key
+    slots: Mutex<HashMap<(String, String), Slot>>,
+}
+
+type Fingerprint = [u8; 32];
+
+enum Slot {
+    /// `done` changes, or closes, once the first request has finished.
+    InFlight {
+        fingerprint: Fingerprint,
+        done: watch::Receiver<()>,
+    },
+    Done(Arc<Stored>),
+}
+
+pub(crate) struct Stored {
+    fingerprint: Fingerprint,
+    pub(crate) header: ResponseHeader,
+    pub(crate) body: Bytes,
+    expires: Instant,
+}
+
+pub(crate) enum Begin<'a> {
+    /// The first request with the key: send it, then [`Lead::finish`].
+    Lead(Lead<'a>),
+    /// The first request is in flight; begin again once it is done.
+    Wait(watch::Receiver<()>),
+    Replay(Arc<Stored>),
+    /// The key was used for a different request.
+    Mismatch,
+}
+
+/// The right to send the request of a key. Dropped unfinished, the key is
+/// released so a waiting duplicate can send instead.
+pub(crate) struct Lead<'a> {
+    idempotency: &'a Idempotency,
+    key: (String, String),
+    fingerprint: Fingerprint,
+    done: watch::Sender<()>,
+    finished: bool,
+}
+
+impl Idempotency {
+    pub fn new(ttl: Duration) -> Self {
+        Idempotency {
+            ttl,
+            max_body: 1024 * 1024,
+            max_entries: 100_000,
+            slots: Mutex::default(),
+        }
+    }
+
+    /// Largest request and response body buffered; larger requests are
+    /// refused with a 413, larger responses with a 502.
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// Responses kept at most; when full, the ones expiring soonest go first.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    /// The idempotency key of `req`, if it has a usable one.
+    pub fn key(req: &RequestHeader) -> Option<&str> {
+        let key = req.headers.get(IDEMPOTENCY_KEY)?.to_str().ok()?.trim();
+        (!key.is_empty() && key.len() <= MAX_KEY).then_some(key)
+    }
+
+    pub(crate) fn begin(
+        &self,
+        client: &str,
+        key: &str,
+        req: &RequestHeader,
+        body: &[u8],
+    ) -> Begin<'_> {
+        let fingerprint = fingerprint(req, body);
+        let key = (client.to_string(), key.to_string());
+        let mut slots = self.slots.lock().unwrap();
+        match slots.get(&key) {
+            Some(Slot::InFlight { fingerprint: f, .. }) if *f != fingerprint => Begin::Mismatch,
+            Some(Slot::InFlight { done, .. }) => Begin::Wait(done.clone()),
+            Some(Slot::Done(stored)) if stored.expires > Instant::now() => {
+                if stored.fingerprint == fingerprint {
+                    Begin::Replay(stored.clone())
+                } else {
+                    Begin::Mismatch
+                }
+            }
+            _ => {
+                let (tx, rx) = watch::channel(());
+                slots.insert(
+                    key.clone(),
+                    Slot::InFlight {
+                        fingerprint,
+                        done: rx,
+                    },
+                );
+                Begin::Lead(Lead {
+                    idempotency: self,
+                    key,
+                    fingerprint,
+                    done: tx,
+                    finished: false,
+                })
+            }
+        }
+    }
+
+    fn store(&self, key: (String, String), stored: Stored) {
+        let mut slots = self.slots.lock().unwrap();
+        if slots.len() >= self.max_entries {
+            let now = Instant::now();
+            slots.retain(|_, slot| !matches!(slot, Slot::Done(s) if s.expires <= now));
+        }
+        if slots.len() >= self.max_entries {
+            let soonest = slots
+                .iter()
+                .filter_map(|(k, slot)| match slot {
+                    Slot::Done(s) => Some((s.expires, k)),
+                    Slot::InFlight { .. } => None,
+                })
+                .min()
+                .map(|(_, k)| k.clone());
+            match soonest {
+                Some(k) => {
+                    slots.remove(&k);
+                }
+                None => {
+                    warn!("idempotency store full of requests in flight");
+                    slots.remove(&key);
+                    return;
+                }
+            }
+        }
+        slots.insert(key, Slot::Done(Arc::new(stored)));
+    }
+}
+
+impl Lead<'_> {
+    /// Keep the response for duplicates, unless it is a server error.
+    pub(crate) fn finish(mut self, header: &ResponseHeader, body: &Bytes) {
+        self.finished = true;
+        let key = std::mem::take(&mut self.key);
+        if header.status.is_server_error() {
+            self.idempotency.slots.lock().unwrap().remove(&key);
+            return;
+        }
+        let stored = Stored {
+            fingerprint: self.fingerprint,
+            header: header.clone(),
+            body: body.clone(),
+            expires: Instant::now() + self.idempotency.ttl,
+        };
+        self.idempotency.store(key, stored);
+        // waiters are woken as `self` drops
+    }
+}
+
+impl Drop for Lead<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.idempotency.slots.lock().unwrap().remove(&self.key);
+        }
+        // without receivers left this is a no-op
+        let _ = self.done.send(());
+    }
+}
+
+fn fingerprint(req: &RequestHeader, body: &[u8]) -> Fingerprint {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(req.method.as_str().as_bytes());
+    ctx.update(b" ");
+    let path = req.uri.path_and_query().map_or("/", |p| p.as_str());
+    ctx.update(path.as_bytes());
+    ctx.update(b"\n");
+    ctx.update(body);
+    ctx.finish()
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digests are 32 bytes")
+}
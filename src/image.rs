@@ -0,0 +1,307 @@
+//! On-the-fly image optimization.
+//!
+//! For routes serving images, the original is fetched from the upstream,
+//! optionally resized (`?w=` / `?h=`, fitted into the box while keeping the
+//! aspect ratio) and re-encoded to the best format the client `Accept`s. The
+//! result is cached per source URL, target format and size. Originals that are
+//! larger than the input limit, not a decodable image or rejected by the decode
+//! limits are served unmodified.
+//!
+//! The codecs are only compiled in with the `image-opt` feature; without it
+//! every request is proxied as usual.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http::{StatusCode, header};
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+
+use crate::cache::{self, CacheConfig, CachedObject, MemoryCache};
+use crate::subrequest::Fetched;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Avif,
+    Webp,
+    /// Keep the source format, only resize.
+    Original,
+}
+
+impl OutputFormat {
+    fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Avif => "avif",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Original => "original",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ImageOptions {
+    /// Offer AVIF to clients accepting it; preferred over WebP.
+    pub avif: bool,
+    /// Offer WebP to clients accepting it.
+    pub webp: bool,
+    /// Originals with a larger body are passed through untouched.
+    pub max_input_bytes: usize,
+    /// Decoding is refused for sources wider or taller than this.
+    pub max_input_dimension: u32,
+    /// Requested sizes are clamped to this box.
+    pub max_output_width: u32,
+    pub max_output_height: u32,
+    /// How long transformed results are cached when the origin does not say.
+    pub result_ttl: Duration,
+    /// Byte budget of the transformed-result cache.
+    pub result_cache_bytes: usize,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            avif: true,
+            webp: true,
+            max_input_bytes: 10 * 1024 * 1024,
+            max_input_dimension: 8192,
+            max_output_width: 4096,
+            max_output_height: 4096,
+            result_ttl: Duration::from_secs(3600),
+            result_cache_bytes: 128 * 1024 * 1024,
+        }
+    }
+}
+
+/// What to do with one image request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transform {
+    pub format: OutputFormat,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+pub struct ImageOptimizer {
+    options: ImageOptions,
+    results: MemoryCache,
+}
+
+impl ImageOptimizer {
+    pub fn new(options: ImageOptions) -> Self {
+        let results = MemoryCache::new(CacheConfig {
+            capacity: options.result_cache_bytes,
+            max_object_size: options.max_input_bytes,
+            default_ttl: options.result_ttl,
+            slice_size: None,
+            preflight_ttl: Duration::ZERO,
+        });
+        ImageOptimizer { options, results }
+    }
+
+    pub fn options(&self) -> &ImageOptions {
+        &self.options
+    }
+
+    /// Decide the transform for a request, `None` to proxy it untouched.
+    pub fn plan(&self, req: &RequestHeader) -> Option<Transform> {
+        if !cfg!(feature = "image-opt") || req.method != http::Method::GET {
+            return None;
+        }
+        let accept = req
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let format = if self.options.avif && accepts(accept, "image/avif") {
+            OutputFormat::Avif
+        } else if self.options.webp && accepts(accept, "image/webp") {
+            OutputFormat::Webp
+        } else {
+            OutputFormat::Original
+        };
+
+        let (mut width, mut height) = (None, None);
+        for (name, value) in req
+            .uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+        {
+            match name {
+                "w" => width = value.parse::<u32>().ok().filter(|w| *w > 0),
+                "h" => height = value.parse::<u32>().ok().filter(|h| *h > 0),
+                _ => {}
+            }
+        }
+        let width = width.map(|w| w.min(self.options.max_output_width));
+        let height = height.map(|h| h.min(self.options.max_output_height));
+
+        if format == OutputFormat::Original && width.is_none() && height.is_none() {
+            return None;
+        }
+        Some(Transform {
+            format,
+            width,
+            height,
+        })
+    }
+
+    pub fn cache_key(req: &RequestHeader, t: &Transform) -> String {
+        format!(
+            "{}|image={}:{}x{}",
+            cache::cache_key(req),
+            t.format.name(),
+            t.width.unwrap_or(0),
+            t.height.unwrap_or(0)
+        )
+    }
+
+    pub fn cached(&self, key: &str) -> Option<(ResponseHeader, Bytes)> {
+        let object = self.results.get(None, key)?;
+        Some((object.header.clone(), object.body.clone()))
+    }
+
+    /// Transform a fetched original; the original itself is returned when it
+    /// cannot be transformed.
+    pub async fn apply(
+        &self,
+        key: String,
+        t: Transform,
+        original: Fetched,
+    ) -> (ResponseHeader, Bytes) {
+        let source_type = original
+            .header
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if original.header.status != StatusCode::OK || !is_transformable(&source_type) {
+            return (original.header, original.body);
+        }
+
+        let max_dimension = self.options.max_input_dimension;
+        let input = original.body.clone();
+        let encoded =
+            tokio::task::spawn_blocking(move || codec::transform(&input, t, max_dimension))
+                .await
+                .ok()
+                .flatten();
+        let Some((body, content_type)) = encoded else {
+            return (original.header, original.body);
+        };
+
+        let Ok(header) = transformed_header(&original.header, content_type, body.len()) else {
+            return (original.header, original.body);
+        };
+        let body = Bytes::from(body);
+        if let Some(ttl) = cache::response_ttl(&header, self.options.result_ttl) {
+            let object = CachedObject {
+                header: header.clone(),
+                body: body.clone(),
+                stored_at: Instant::now(),
+                ttl,
+            };
+            self.results.put(None, key, object);
+        }
+        (header, body)
+    }
+}
+
+fn transformed_header(
+    original: &ResponseHeader,
+    content_type: &str,
+    len: usize,
+) -> Result<ResponseHeader> {
+    let mut header = original.clone();
+    header.insert_header(header::CONTENT_TYPE, content_type)?;
+    header.insert_header(header::CONTENT_LENGTH, len.to_string())?;
+    header.insert_header(header::VARY, "Accept")?;
+    header.remove_header(&header::TRANSFER_ENCODING);
+    header.remove_header(&header::ACCEPT_RANGES);
+    // validators of the original do not describe the derived image
+    header.remove_header(&header::ETAG);
+    Ok(header)
+}
+
+/// Whether `accept` lists `mime` with a non-zero quality.
+fn accepts(accept: &str, mime: &str) -> bool {
+    accept.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        let matches = parts.next().is_some_and(|m| m.eq_ignore_ascii_case(mime));
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        matches && q > 0.0
+    })
+}
+
+fn is_transformable(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    matches!(
+        mime,
+        "image/jpeg" | "image/png" | "image/gif" | "image/webp"
+    )
+}
+
+#[cfg(feature = "image-opt")]
+mod codec {
+    use std::io::Cursor;
+
+    use image::imageops::FilterType;
+    use image::{ImageFormat, ImageReader, Limits};
+
+    use super::{OutputFormat, Transform};
+
+    pub fn transform(
+        input: &[u8],
+        t: Transform,
+        max_dimension: u32,
+    ) -> Option<(Vec<u8>, &'static str)> {
+        let mut reader = ImageReader::new(Cursor::new(input))
+            .with_guessed_format()
+            .ok()?;
+        let source_format = reader.format()?;
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(max_dimension);
+        limits.max_image_height = Some(max_dimension);
+        reader.limits(limits);
+        let mut img = reader.decode().ok()?;
+
+        if t.width.is_some() || t.height.is_some() {
+            let width = t.width.unwrap_or(u32::MAX).min(img.width());
+            let height = t.height.unwrap_or(u32::MAX).min(img.height());
+            // never upscale
+            if width < img.width() || height < img.height() {
+                img = img.resize(width, height, FilterType::Lanczos3);
+            }
+        }
+
+        let (format, content_type) = match t.format {
+            OutputFormat::Avif => (ImageFormat::Avif, "image/avif"),
+            OutputFormat::Webp => (ImageFormat::WebP, "image/webp"),
+            OutputFormat::Original => (source_format, source_format.to_mime_type()),
+        };
+        if format == ImageFormat::WebP {
+            // the WebP encoder only takes 8-bit RGB(A)
+            img = image::DynamicImage::ImageRgba8(img.to_rgba8());
+        }
+        let mut out = Cursor::new(Vec::new());
+        img.write_to(&mut out, format).ok()?;
+        Some((out.into_inner(), content_type))
+    }
+}
+
+#[cfg(not(feature = "image-opt"))]
+mod codec {
+    use super::Transform;
+
+    pub fn transform(
+        _input: &[u8],
+        _t: Transform,
+        _max_dimension: u32,
+    ) -> Option<(Vec<u8>, &'static str)> {
+        None
+    }
+}
@@ -0,0 +1,197 @@
+//! Requests in flight per upstream, with an optional hard cap.
+//!
+//! A proxied request is *queued* on its upstream from the moment it is picked
+//! until its connection is ready, then *in flight* until it is done. An
+//! upstream with the cap of its cluster in requests queued or in flight is
+//! full: selection passes over it to the next node, as it does for unhealthy
+//! ones, rather than piling more requests onto a backend already struggling
+//! to keep up. The default upstreams have the cap of the [`InFlight`], the
+//! cluster of a route the route's [`Route::max_in_flight`]. The counts are
+//! checked and taken separately, so requests picking the same upstream at the
+//! same moment may overshoot the cap by a few.
+//!
+//! The counts are sharded by worker, see [`crate::sharded`], and the cap is
+//! checked against their totals as a [`crate::sharded::ShardAggregator`] last
+//! refreshed them. Upstreams may then also overshoot the cap by what they got
+//! within the aggregation interval. Least-connections balancing compares the
+//! current counts instead, see [`crate::balancing`]. The upstreams are
+//! looked up in a snapshot, replaced when one gets its first request.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use pingora::protocols::l4::socket::SocketAddr;
+
+use crate::route::Route;
+use crate::sharded::{Aggregate, Counter, Gauge};
+
+#[derive(Default)]
+struct Node {
+    queued: Gauge,
+    in_flight: Gauge,
+    /// Times selection passed over the node for being full.
+    skipped: Counter,
+}
+
+impl Node {
+    /// Requests queued and in flight, as last aggregated.
+    fn load(&self) -> usize {
+        (self.queued.total() + self.in_flight.total()).max(0) as usize
+    }
+
+    /// Requests queued and in flight now.
+    fn current(&self) -> usize {
+        (self.queued.sum() + self.in_flight.sum()).max(0) as usize
+    }
+}
+
+/// Snapshot of an upstream's counts.
+pub struct UpstreamLoad {
+    pub addr: SocketAddr,
+    pub queued: usize,
+    pub in_flight: usize,
+    pub skipped: u64,
+}
+
+/// In-flight requests of all clusters, by address.
+#[derive(Default)]
+pub struct InFlight {
+    cap: Option<usize>,
+    nodes: ArcSwap<HashMap<SocketAddr, Arc<Node>>>,
+    /// Requests refused because every usable upstream was full.
+    saturated: Counter,
+}
+
+/// A request counted against its upstream, until dropped.
+pub(crate) struct Lease {
+    node: Arc<Node>,
+    connected: bool,
+}
+
+impl Lease {
+    /// The request's connection is ready, it moves from queued to in flight.
+    pub(crate) fn connected(&mut self) {
+        if !self.connected {
+            self.connected = true;
+            self.node.queued.add(-1);
+            self.node.in_flight.add(1);
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let count = if self.connected {
+            &self.node.in_flight
+        } else {
+            &self.node.queued
+        };
+        count.add(-1);
+    }
+}
+
+impl InFlight {
+    /// Cap each of the default upstreams at `cap` requests, `None` to only
+    /// count them.
+    pub fn new(cap: Option<usize>) -> Self {
+        InFlight {
+            cap: cap.map(|cap| cap.max(1)),
+            ..Default::default()
+        }
+    }
+
+    pub fn cap(&self) -> Option<usize> {
+        self.cap
+    }
+
+    /// Requests refused since startup for every upstream being full.
+    pub fn saturated(&self) -> u64 {
+        self.saturated.sum()
+    }
+
+    fn node(&self, addr: &SocketAddr) -> Arc<Node> {
+        if let Some(node) = self.nodes.load().get(addr) {
+            return node.clone();
+        }
+        let mut node = None;
+        self.nodes.rcu(|nodes| {
+            let mut nodes = HashMap::clone(nodes);
+            node = Some(nodes.entry(addr.clone()).or_default().clone());
+            nodes
+        });
+        node.unwrap()
+    }
+
+    /// The cap of each upstream of the cluster `route` sends to: the
+    /// route's own if it has a cluster, that of the default upstreams
+    /// otherwise.
+    pub fn cap_of(&self, route: Option<&Route>) -> Option<usize> {
+        match route {
+            Some(route) if route.upstreams.is_some() => route.max_in_flight.map(|cap| cap.max(1)),
+            _ => self.cap,
+        }
+    }
+
+    /// Whether `addr` may take another request under `cap`, see
+    /// [`Self::cap_of`]; a full upstream counts as skipped.
+    pub(crate) fn has_room(&self, addr: &SocketAddr, cap: Option<usize>) -> bool {
+        let Some(cap) = cap else {
+            return true;
+        };
+        let nodes = self.nodes.load();
+        let Some(node) = nodes.get(addr) else {
+            return true;
+        };
+        if node.load() < cap {
+            return true;
+        }
+        node.skipped.add(1);
+        false
+    }
+
+    /// Requests queued on and in flight to `addr` now.
+    pub(crate) fn load(&self, addr: &SocketAddr) -> usize {
+        self.nodes.load().get(addr).map_or(0, |node| node.current())
+    }
+
+    /// Count a request as queued on `addr`.
+    pub(crate) fn acquire(&self, addr: &SocketAddr) -> Lease {
+        let node = self.node(addr);
+        node.queued.add(1);
+        Lease {
+            node,
+            connected: false,
+        }
+    }
+
+    pub(crate) fn refused(&self) {
+        self.saturated.add(1);
+    }
+
+    /// The counts of all upstreams that got requests, by address.
+    pub fn list(&self) -> Vec<UpstreamLoad> {
+        let mut list: Vec<_> = self
+            .nodes
+            .load()
+            .iter()
+            .map(|(addr, node)| UpstreamLoad {
+                addr: addr.clone(),
+                queued: node.queued.sum().max(0) as usize,
+                in_flight: node.in_flight.sum().max(0) as usize,
+                skipped: node.skipped.sum(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.addr.cmp(&b.addr));
+        list
+    }
+}
+
+impl Aggregate for InFlight {
+    fn aggregate(&self) {
+        for node in self.nodes.load().values() {
+            node.queued.aggregate();
+            node.in_flight.aggregate();
+        }
+    }
+}
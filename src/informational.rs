@@ -0,0 +1,22 @@
+//! Upstream informational (`1xx`) responses.
+//!
+//! Interim responses such as `103 Early Hints` are relayed to the client ahead
+//! of the final response by default. Some client stacks fail on any interim
+//! response other than `100 Continue`, listeners serving those can suppress
+//! them. `101 Switching Protocols` is a final response and never suppressed, nor
+//! is the `100 Continue` a client asked for with `Expect: 100-continue`.
+//!
+//! HTTP/2 server push needs no handling here: upstream HTTP/2 connections are
+//! opened with `SETTINGS_ENABLE_PUSH` set to 0, so a push promise never reaches
+//! the proxy as a response.
+
+/// What to do with upstream `1xx` responses, configured per listener.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Informational {
+    /// Relay them to the client.
+    #[default]
+    Forward,
+    /// Drop them, the client only sees the final response. This includes the
+    /// proxy's own early hints.
+    Suppress,
+}
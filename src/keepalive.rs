@@ -0,0 +1,50 @@
+//! Downstream keepalive and connection recycling.
+//!
+//! Behind an L4 load balancer, a proxy only gets its share of new clients:
+//! long-lived connections stay where they were opened, so a proxy added to
+//! the pool, or one back from a restart, sees little traffic until they are
+//! closed. [`Keepalive`] caps how long a connection is reused, by requests
+//! and by age, and how long it may sit idle between requests. An HTTP/1
+//! connection past a cap gets `Connection: close` on its next response; an
+//! HTTP/2 one a GOAWAY, see [`crate::h2_server::H2Server`], letting its
+//! streams finish. The client reconnects, and the balancer places it anew.
+
+use std::time::Duration;
+
+use pingora::proxy::Session;
+
+/// Keepalive settings of a listener; `None`s keep the defaults of the
+/// HTTP stack, no caps and a 60s idle timeout for HTTP/1.
+#[derive(Clone, Debug, Default)]
+pub struct Keepalive {
+    /// Requests a connection serves before it is closed.
+    pub max_requests: Option<u64>,
+    /// Close connections idle between requests for this long. HTTP/1 has a
+    /// resolution of a second.
+    pub idle_timeout: Option<Duration>,
+    /// Close connections once they are this old, on their next response.
+    pub max_age: Option<Duration>,
+}
+
+impl Keepalive {
+    /// Whether a connection that served `requests`, this one included, and
+    /// was opened `age` ago is not to be reused.
+    pub fn recycle(&self, requests: u64, age: Duration) -> bool {
+        self.max_requests.is_some_and(|max| requests >= max)
+            || self.max_age.is_some_and(|max| age >= max)
+    }
+
+    /// Set the keepalive of the HTTP/1 `session` for the request making
+    /// `requests` on a connection `age` old.
+    pub(crate) fn apply(&self, session: &mut Session, requests: u64, age: Duration) {
+        // the client asked for the connection to be closed
+        if session.get_keepalive().is_none() {
+            return;
+        }
+        if self.recycle(requests, age) {
+            session.set_keepalive(None);
+        } else if let Some(idle) = self.idle_timeout {
+            session.set_keepalive(Some(idle.as_secs().max(1)));
+        }
+    }
+}
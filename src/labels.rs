@@ -0,0 +1,236 @@
+//! Bounded metric labels.
+//!
+//! A stat labelled with request paths has as many series as clients send
+//! distinct paths, and scanners send thousands. Paths are normalized before
+//! they become labels: the first of the route's
+//! [`crate::route::Route::path_templates`] that matches names the path, so
+//! `/users/123` counts as `/users/:id`, and in paths no template matches,
+//! segments that look like ids (numbers, UUIDs, long hex or token strings)
+//! are replaced with `:id`. [`BoundedLabels`] then caps the distinct values
+//! of a label; values past the cap are counted under [`OVERFLOW`].
+//!
+//! [`PathStats`] counts requests by route and normalized path, with status
+//! classes, for `GET /admin/paths`. Requests are counted in the shard of
+//! their worker, see [`crate::sharded`], and added to the bounded labels when
+//! they are aggregated or read.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use serde_json::{Value, json};
+
+use crate::route::Route;
+use crate::sharded::{Aggregate, Sharded};
+
+/// The label of the values past a [`BoundedLabels`] cap.
+pub const OVERFLOW: &str = "other";
+
+/// Replaces id-like path segments in paths matching no template.
+const ID: &str = ":id";
+
+/// Segments of a path kept before the rest is folded into `/*`.
+const MAX_SEGMENTS: usize = 8;
+
+/// Whether `template` matches `path`. Template segments are literals,
+/// `:name` matching any one segment, and a final `*` matching the rest.
+pub fn template_matches(template: &str, path: &str) -> bool {
+    let mut path = path.trim_start_matches('/').split('/');
+    for segment in template.trim_start_matches('/').split('/') {
+        if segment == "*" {
+            return true;
+        }
+        match path.next() {
+            Some(p) if segment.starts_with(':') && !p.is_empty() => {}
+            Some(p) if p == segment => {}
+            _ => return false,
+        }
+    }
+    path.next().is_none()
+}
+
+fn looks_like_id(segment: &str) -> bool {
+    let hex = |s: &str| s.bytes().all(|b| b.is_ascii_hexdigit());
+    let uuid = segment.len() == 36
+        && segment.split('-').map(str::len).eq([8, 4, 4, 4, 12])
+        && hex(&segment.replace('-', ""));
+    let token = segment.len() >= 20
+        && segment
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        && segment.bytes().any(|b| b.is_ascii_digit());
+    (!segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()))
+        || uuid
+        || (segment.len() >= 16 && hex(segment))
+        || token
+}
+
+/// The label of `path` on `route`: its first matching template, or the
+/// path with its ids replaced and at most [`MAX_SEGMENTS`] segments.
+pub fn normalize_path(route: Option<&Route>, path: &str) -> String {
+    if let Some(template) = route
+        .into_iter()
+        .flat_map(|r| &r.path_templates)
+        .find(|t| template_matches(t, path))
+    {
+        return template.clone();
+    }
+    let mut label = String::new();
+    for (i, segment) in path.trim_start_matches('/').split('/').enumerate() {
+        if i == MAX_SEGMENTS {
+            label.push_str("/*");
+            break;
+        }
+        label.push('/');
+        label.push_str(if looks_like_id(segment) { ID } else { segment });
+    }
+    label
+}
+
+/// Values of a label with at most `max` distinct ones.
+pub struct BoundedLabels<T> {
+    max: usize,
+    values: BTreeMap<String, T>,
+    /// observations that went to [`OVERFLOW`]
+    overflowed: u64,
+}
+
+impl<T: Default> BoundedLabels<T> {
+    pub fn new(max: usize) -> Self {
+        BoundedLabels {
+            max,
+            values: BTreeMap::new(),
+            overflowed: 0,
+        }
+    }
+
+    /// The value of `label`, or of [`OVERFLOW`] once there are `max`
+    /// others.
+    pub fn get_mut(&mut self, label: &str) -> &mut T {
+        self.get_mut_n(label, 1)
+    }
+
+    /// [`BoundedLabels::get_mut`] for `observations` at once.
+    pub fn get_mut_n(&mut self, label: &str, observations: u64) -> &mut T {
+        if !self.values.contains_key(label) {
+            let label = if self.values.len() < self.max {
+                label
+            } else {
+                self.overflowed += observations;
+                OVERFLOW
+            };
+            return self.values.entry(label.to_string()).or_default();
+        }
+        self.values.get_mut(label).unwrap()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &T)> {
+        self.values
+            .iter()
+            .map(|(label, value)| (label.as_str(), value))
+    }
+
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed
+    }
+}
+
+#[derive(Default)]
+struct PathCounts {
+    requests: u64,
+    /// by status class, 1xx to 5xx; 0 for no response
+    statuses: [u64; 6],
+}
+
+/// Requests by route and normalized path; requests without a route count
+/// as `default`.
+pub struct PathStats {
+    max_paths: usize,
+    routes: Mutex<BTreeMap<String, BoundedLabels<PathCounts>>>,
+    /// counts not yet added to `routes`, by route and path
+    pending: Sharded<HashMap<String, HashMap<String, PathCounts>>>,
+}
+
+impl Default for PathStats {
+    fn default() -> Self {
+        PathStats::new(100)
+    }
+}
+
+impl PathStats {
+    /// At most `max_paths` paths per route.
+    pub fn new(max_paths: usize) -> Self {
+        PathStats {
+            max_paths,
+            routes: Mutex::default(),
+            pending: Sharded::default(),
+        }
+    }
+
+    pub fn observe(&self, route: Option<&Route>, path: &str, status: u16) {
+        let path = normalize_path(route, path);
+        let name = route.map_or("default", |r| r.name.as_str());
+        let mut pending = self.pending.local();
+        if !pending.contains_key(name) {
+            pending.insert(name.to_string(), HashMap::new());
+        }
+        let counts = pending.get_mut(name).unwrap().entry(path).or_default();
+        counts.requests += 1;
+        counts.statuses[(status as usize / 100).min(5)] += 1;
+    }
+
+    pub fn to_json(&self) -> Value {
+        self.aggregate();
+        let routes: serde_json::Map<String, Value> = self
+            .routes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, paths)| {
+                let counts: serde_json::Map<String, Value> = paths
+                    .iter()
+                    .map(|(path, counts)| {
+                        let statuses: BTreeMap<String, u64> = counts
+                            .statuses
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, n)| **n > 0)
+                            .map(|(class, n)| {
+                                let class = if class == 0 {
+                                    "none".to_string()
+                                } else {
+                                    format!("{class}xx")
+                                };
+                                (class, *n)
+                            })
+                            .collect();
+                        let value = json!({ "requests": counts.requests, "statuses": statuses });
+                        (path.to_string(), value)
+                    })
+                    .collect();
+                let value = json!({ "paths": counts, "overflowed": paths.overflowed() });
+                (name.clone(), value)
+            })
+            .collect();
+        json!({ "max_paths": self.max_paths, "routes": routes })
+    }
+}
+
+impl Aggregate for PathStats {
+    fn aggregate(&self) {
+        let mut routes = self.routes.lock().unwrap();
+        self.pending.each(|pending| {
+            for (name, paths) in pending.drain() {
+                let labels = routes
+                    .entry(name)
+                    .or_insert_with(|| BoundedLabels::new(self.max_paths));
+                for (path, counts) in paths {
+                    let total = labels.get_mut_n(&path, counts.requests);
+                    total.requests += counts.requests;
+                    for (total, n) in total.statuses.iter_mut().zip(counts.statuses) {
+                        *total += n;
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,82 @@
+pub mod admin;
+pub mod allocations;
+pub mod anomaly;
+pub mod balancing;
+pub mod billing;
+pub mod body_route;
+pub mod budget;
+pub mod cache;
+pub mod certs;
+pub mod cgi;
+pub mod circuit;
+pub mod config;
+pub mod connect_race;
+pub mod connections;
+pub mod consistent_hash;
+pub mod content_sniff;
+pub mod ctx;
+pub mod debug_headers;
+pub mod diagnostics;
+pub mod discovery;
+pub mod dns;
+pub mod doh;
+pub mod drain;
+pub mod early_data;
+pub mod egress;
+pub mod expect;
+pub mod explain;
+pub mod family;
+pub mod feedback;
+pub mod fingerprint;
+pub mod flags;
+pub mod forward;
+pub mod gateway;
+pub mod geo;
+pub mod graphql;
+pub mod h2_fallback;
+pub mod h2_server;
+pub mod har;
+pub mod hash_select;
+pub mod http10;
+pub mod idempotency;
+pub mod image;
+pub mod in_flight;
+pub mod informational;
+pub mod keepalive;
+pub mod labels;
+pub mod listener;
+pub mod loadgen;
+pub mod maglev;
+pub mod no_upstream;
+pub mod openapi;
+pub mod original_dst;
+pub mod plan;
+pub mod proxy;
+pub mod quarantine;
+pub mod range;
+pub mod readiness;
+pub mod region;
+pub mod rendezvous;
+pub mod replica;
+pub mod rollback;
+pub mod route;
+pub mod runtime_upstreams;
+pub mod s3;
+pub mod scan;
+pub mod schedule;
+pub mod sharded;
+pub mod signing;
+pub mod sniff;
+pub mod stalls;
+pub mod startup;
+pub mod sticky;
+pub mod stream;
+pub mod strict_host;
+pub mod subrequest;
+pub mod subset;
+pub mod synthetic;
+pub mod template;
+pub mod transparent;
+pub mod upload;
+pub mod upstream_tcp;
+pub mod xml;
@@ -0,0 +1,86 @@
+//! Downstream listener settings.
+
+use pingora::listeners::TcpSocketOptions;
+
+use crate::debug_headers::DiagnosticHeaders;
+use crate::expect::ExpectContinue;
+use crate::h2_server::H2Settings;
+use crate::http10::Http10Compat;
+use crate::informational::Informational;
+use crate::keepalive::Keepalive;
+use crate::sniff::SniffConfig;
+use crate::strict_host::StrictHosts;
+
+/// Settings that apply to every request accepted on one listening address.
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    /// Address to bind, e.g. `0.0.0.0:6188`.
+    pub addr: String,
+    /// `IPV6_V6ONLY` of an IPv6 address; `Some(false)` on `[::]` accepts
+    /// IPv4 clients too. `None` keeps the system's default.
+    pub ipv6_only: Option<bool>,
+    /// HTTP/1.0 compatibility mode; `None` passes such requests through as-is.
+    pub http10: Option<Http10Compat>,
+    /// Who answers `Expect: 100-continue`.
+    pub expect_continue: ExpectContinue,
+    /// Whether upstream `1xx` responses reach the client.
+    pub informational: Informational,
+    /// Expose the upstream phase timings in a `Server-Timing` header.
+    pub server_timing: bool,
+    /// HTTP/2 settings, applied when the service is wrapped in an
+    /// [`crate::h2_server::H2Server`].
+    pub h2: H2Settings,
+    /// Caps on the reuse of client connections, over HTTP/1 and HTTP/2.
+    pub keepalive: Keepalive,
+    /// Serve only known hosts; `None` forwards any host.
+    pub strict_hosts: Option<StrictHosts>,
+    /// Who gets the diagnostic headers on responses; `None` for no one.
+    pub diagnostics: Option<DiagnosticHeaders>,
+    /// Accepts connections diverted by TPROXY, whose local address is their
+    /// original destination, see [`crate::transparent`].
+    pub transparent: bool,
+    /// Connections start with a PROXY protocol header, applied when the
+    /// service is wrapped in an [`crate::h2_server::H2Server`]; plain TCP
+    /// only.
+    pub proxy_protocol: bool,
+    /// Serve TLS and relay plain TCP on the same port as HTTP, applied when
+    /// the service is wrapped in a [`crate::sniff::Sniffer`]; `None` for
+    /// HTTP only.
+    pub sniff: Option<SniffConfig>,
+}
+
+impl ListenerConfig {
+    pub fn new(addr: impl Into<String>) -> Self {
+        ListenerConfig {
+            addr: addr.into(),
+            ipv6_only: None,
+            http10: None,
+            expect_continue: ExpectContinue::default(),
+            informational: Informational::default(),
+            server_timing: false,
+            h2: H2Settings::default(),
+            keepalive: Keepalive::default(),
+            strict_hosts: None,
+            diagnostics: None,
+            transparent: false,
+            proxy_protocol: false,
+            sniff: None,
+        }
+    }
+
+    /// A listener on `port` of every address, IPv4 and IPv6, on one socket.
+    pub fn dual_stack(port: u16) -> Self {
+        ListenerConfig {
+            ipv6_only: Some(false),
+            ..ListenerConfig::new(format!("[::]:{port}"))
+        }
+    }
+
+    /// Options of the listening socket, for
+    /// [`pingora::services::listening::Service::add_tcp_with_settings`].
+    pub fn socket_options(&self) -> TcpSocketOptions {
+        let mut options = TcpSocketOptions::default();
+        options.ipv6_only = self.ipv6_only;
+        options
+    }
+}
@@ -0,0 +1,456 @@
+//! Load generation for capacity tests, `proxy-rs loadgen`.
+//!
+//! Requests go out through pingora's HTTP connector, the one the proxy
+//! sends upstream with: connections are kept alive and reused, or spoken
+//! h2 to with [`LoadConfig::h2`]. `concurrency` tasks each send one request
+//! at a time, spread over `threads` threads with a runtime and connection
+//! pool each.
+//!
+//! Runs are deterministic: every task draws its requests from the mix with a
+//! generator seeded by [`LoadConfig::seed`] and its number, and with a
+//! request count each task sends its fixed share, so two runs with the same
+//! options send the same requests in the same order per task. With a
+//! [`LoadConfig::rate`] requests are sent on a fixed schedule, whether or not
+//! the previous ones are answered, and latency counts from when a request
+//! was due, so a stalled target shows in the percentiles instead of only
+//! slowing the run down.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http::{Uri, header};
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::upstreams::peer::HttpPeer;
+use serde_json::{Value, json};
+use tokio::time::MissedTickBehavior;
+
+use crate::subrequest;
+
+/// One kind of request of the mix, with its share.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MixEntry {
+    pub method: String,
+    pub path: String,
+    pub weight: u32,
+}
+
+impl std::str::FromStr for MixEntry {
+    type Err = String;
+
+    /// `METHOD PATH [WEIGHT]`, as `GET /users 3`; the weight defaults to 1.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut parts = s.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return Err(format!("request {s} is not METHOD PATH [WEIGHT]"));
+        };
+        if !path.starts_with('/') {
+            return Err(format!("path {path} does not start with /"));
+        }
+        let weight = match parts.next() {
+            Some(w) => w.parse().map_err(|_| format!("bad weight {w}"))?,
+            None => 1,
+        };
+        if weight == 0 || parts.next().is_some() {
+            return Err(format!("request {s} is not METHOD PATH [WEIGHT]"));
+        }
+        Ok(MixEntry {
+            method: method.to_ascii_uppercase(),
+            path: path.to_string(),
+            weight,
+        })
+    }
+}
+
+impl fmt::Display for MixEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.method, self.path)
+    }
+}
+
+/// When a run is over.
+#[derive(Clone, Copy, Debug)]
+pub enum Stop {
+    /// After this many requests in total.
+    Requests(u64),
+    After(Duration),
+}
+
+pub struct LoadConfig {
+    /// `http://` or `https://` URL of the target; its path is not used.
+    pub target: Uri,
+    pub mix: Vec<MixEntry>,
+    pub concurrency: usize,
+    pub threads: usize,
+    pub stop: Stop,
+    /// Requests per second of all tasks together, `None` for as fast as
+    /// the target answers.
+    pub rate: Option<f64>,
+    pub seed: u64,
+    pub h2: bool,
+    /// Open a connection per request instead of reusing them.
+    pub close: bool,
+    /// Bytes of body sent with requests whose method has one.
+    pub body_size: usize,
+    pub timeout: Duration,
+}
+
+/// SplitMix64, small and the same everywhere.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Picks entries of the mix by their weights.
+struct Mix<'a> {
+    entries: &'a [MixEntry],
+    total: u64,
+}
+
+impl<'a> Mix<'a> {
+    fn new(entries: &'a [MixEntry]) -> Self {
+        Mix {
+            entries,
+            total: entries.iter().map(|e| e.weight as u64).sum(),
+        }
+    }
+
+    /// The index of the entry for a draw of `rng`.
+    fn pick(&self, rng: &mut Rng) -> usize {
+        let mut draw = rng.next() % self.total;
+        for (i, entry) in self.entries.iter().enumerate() {
+            match draw.checked_sub(entry.weight as u64) {
+                Some(rest) => draw = rest,
+                None => return i,
+            }
+        }
+        self.entries.len() - 1
+    }
+}
+
+/// What one task saw.
+#[derive(Default)]
+struct Outcome {
+    /// latencies in microseconds, by mix entry
+    latencies: Vec<Vec<u32>>,
+    statuses: BTreeMap<u16, u64>,
+    errors: BTreeMap<&'static str, u64>,
+    bytes: u64,
+}
+
+/// The results of a run.
+pub struct Report {
+    pub mix: Vec<MixEntry>,
+    pub elapsed: Duration,
+    /// microseconds, sorted, by mix entry
+    latencies: Vec<Vec<u32>>,
+    pub statuses: BTreeMap<u16, u64>,
+    pub errors: BTreeMap<&'static str, u64>,
+    pub bytes: u64,
+}
+
+/// The percentiles reported.
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+fn percentile(sorted: &[u32], p: f64) -> Option<f64> {
+    let last = sorted.len().checked_sub(1)?;
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.saturating_sub(1).min(last)] as f64 / 1000.0)
+}
+
+fn summary(sorted: &[u32]) -> Value {
+    let mut latency = serde_json::Map::new();
+    for p in PERCENTILES {
+        latency.insert(format!("p{p}"), json!(percentile(sorted, p)));
+    }
+    latency.insert("max".into(), json!(percentile(sorted, 100.0)));
+    json!({ "requests": sorted.len(), "latency_ms": latency })
+}
+
+impl Report {
+    /// Requests answered, whatever their status.
+    pub fn answered(&self) -> usize {
+        self.latencies.iter().map(Vec::len).sum()
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    fn all(&self) -> Vec<u32> {
+        let mut all: Vec<u32> = self.latencies.concat();
+        all.sort_unstable();
+        all
+    }
+
+    pub fn to_json(&self) -> Value {
+        let by_request: Vec<Value> = self
+            .mix
+            .iter()
+            .zip(&self.latencies)
+            .map(|(entry, latencies)| {
+                let mut summary = summary(latencies);
+                summary["request"] = json!(entry.to_string());
+                summary
+            })
+            .collect();
+        let mut body = summary(&self.all());
+        body["elapsed_ms"] = json!(self.elapsed.as_millis() as u64);
+        body["rate"] = json!(self.answered() as f64 / self.elapsed.as_secs_f64());
+        body["bytes"] = json!(self.bytes);
+        body["statuses"] = json!(self.statuses);
+        body["errors"] = json!(self.errors);
+        body["by_request"] = json!(by_request);
+        body
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = |f: &mut fmt::Formatter<'_>, name: &str, sorted: &[u32]| {
+            write!(f, "{name:<24} {:>8}", sorted.len())?;
+            for p in PERCENTILES.into_iter().chain([100.0]) {
+                match percentile(sorted, p) {
+                    Some(ms) => write!(f, " {ms:>9.3}")?,
+                    None => write!(f, " {:>9}", "-")?,
+                }
+            }
+            writeln!(f)
+        };
+        writeln!(
+            f,
+            "{} answered, {} failed in {:.2}s, {:.1} req/s, {} bytes",
+            self.answered(),
+            self.failed(),
+            self.elapsed.as_secs_f64(),
+            self.answered() as f64 / self.elapsed.as_secs_f64(),
+            self.bytes
+        )?;
+        for (status, count) in &self.statuses {
+            writeln!(f, "status {status}: {count}")?;
+        }
+        for (error, count) in &self.errors {
+            writeln!(f, "error {error}: {count}")?;
+        }
+        writeln!(
+            f,
+            "{:<24} {:>8} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            "latency ms", "count", "p50", "p90", "p99", "p99.9", "max"
+        )?;
+        line(f, "all", &self.all())?;
+        if self.mix.len() > 1 {
+            for (entry, latencies) in self.mix.iter().zip(&self.latencies) {
+                line(f, &entry.to_string(), latencies)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The peer to send to, and the requests of the mix with their bodies.
+fn prepare(config: &LoadConfig) -> Result<(HttpPeer, Vec<(RequestHeader, Bytes)>), String> {
+    let (mut peer, base) = subrequest::for_url("GET", &config.target, config.timeout)?;
+    if config.h2 {
+        peer.options.set_http_version(2, 2);
+    }
+    peer.options.read_timeout = Some(config.timeout);
+    peer.options.write_timeout = Some(config.timeout);
+    let body = Bytes::from(vec![b'x'; config.body_size]);
+    let requests = config
+        .mix
+        .iter()
+        .map(|entry| {
+            let mut req = RequestHeader::build(entry.method.as_str(), entry.path.as_bytes(), None)
+                .map_err(|e| format!("request {entry}: {e}"))?;
+            if let Some(host) = base.headers.get(header::HOST) {
+                req.insert_header(header::HOST, host.clone())
+                    .map_err(|e| e.to_string())?;
+            }
+            let has_body = !matches!(entry.method.as_str(), "GET" | "HEAD" | "DELETE" | "OPTIONS");
+            let body = if has_body { body.clone() } else { Bytes::new() };
+            if has_body {
+                req.insert_header(header::CONTENT_LENGTH, body.len())
+                    .map_err(|e| e.to_string())?;
+            }
+            Ok((req, body))
+        })
+        .collect::<Result<_, String>>()?;
+    Ok((peer, requests))
+}
+
+/// Send `req` with `body` and read the whole response; its status and body
+/// size.
+async fn exchange(
+    connector: &Connector,
+    peer: &HttpPeer,
+    req: &RequestHeader,
+    body: &Bytes,
+    close: bool,
+) -> pingora::Result<(u16, u64)> {
+    let (mut session, _reused) = connector.get_http_session(peer).await?;
+    session.write_request_header(Box::new(req.clone())).await?;
+    if !body.is_empty() {
+        session.write_request_body(body.clone(), true).await?;
+    }
+    session.finish_request_body().await?;
+    session.read_response_header().await?;
+    let status = session
+        .response_header()
+        .expect("response header is read")
+        .status
+        .as_u16();
+    let mut bytes = 0;
+    while let Some(chunk) = session.read_response_body().await? {
+        bytes += chunk.len() as u64;
+    }
+    if !close {
+        connector.release_http_session(session, peer, None).await;
+    }
+    Ok((status, bytes))
+}
+
+/// Task `task` of the run, sending `quota` requests or until `deadline`.
+#[allow(clippy::too_many_arguments)]
+async fn task(
+    config: &LoadConfig,
+    connector: &Connector,
+    peer: &HttpPeer,
+    requests: &[(RequestHeader, Bytes)],
+    task: usize,
+    quota: Option<u64>,
+    deadline: Option<Instant>,
+) -> Outcome {
+    let mix = Mix::new(&config.mix);
+    let mut rng = Rng(config.seed ^ (task as u64).wrapping_mul(0x2545_f491_4f6c_dd1d));
+    let mut outcome = Outcome {
+        latencies: vec![Vec::new(); requests.len()],
+        ..Default::default()
+    };
+    let mut schedule = config.rate.map(|rate| {
+        let every = Duration::from_secs_f64(config.concurrency as f64 / rate);
+        // tasks start spread over one interval, not all at once
+        let offset = every.mul_f64(task as f64 / config.concurrency as f64);
+        let mut interval = tokio::time::interval_at((Instant::now() + offset).into(), every);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+        interval
+    });
+    let mut sent = 0;
+    while quota.is_none_or(|quota| sent < quota) && deadline.is_none_or(|d| Instant::now() < d) {
+        let due = match &mut schedule {
+            Some(schedule) => schedule.tick().await.into_std(),
+            None => Instant::now(),
+        };
+        if deadline.is_some_and(|d| due >= d) {
+            break;
+        }
+        let i = mix.pick(&mut rng);
+        let (req, body) = &requests[i];
+        sent += 1;
+        match exchange(connector, peer, req, body, config.close).await {
+            Ok((status, bytes)) => {
+                let micros = due.elapsed().as_micros().min(u32::MAX as u128) as u32;
+                outcome.latencies[i].push(micros);
+                *outcome.statuses.entry(status).or_default() += 1;
+                outcome.bytes += bytes;
+            }
+            Err(e) => *outcome.errors.entry(e.etype().as_str()).or_default() += 1,
+        }
+    }
+    outcome
+}
+
+/// The requests of task `task` of `tasks` for `total` in all.
+fn share(total: u64, tasks: usize, task: usize) -> u64 {
+    total / tasks as u64 + u64::from((task as u64) < total % tasks as u64)
+}
+
+/// Run the load of `config` against its target.
+pub fn run(config: &LoadConfig) -> Result<Report, String> {
+    if config.mix.is_empty() {
+        return Err("no requests to send".into());
+    }
+    if config
+        .rate
+        .is_some_and(|rate| !rate.is_finite() || rate <= 0.0)
+    {
+        return Err("rate is not above 0".into());
+    }
+    let (peer, requests) = prepare(config)?;
+    let concurrency = config.concurrency.max(1);
+    let threads = config.threads.clamp(1, concurrency);
+    let config = &LoadConfig {
+        concurrency,
+        threads,
+        target: config.target.clone(),
+        mix: config.mix.clone(),
+        ..*config
+    };
+    let started = Instant::now();
+    let deadline = match config.stop {
+        Stop::After(duration) => Some(started + duration),
+        Stop::Requests(_) => None,
+    };
+    let outcomes: Vec<Outcome> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|thread| {
+                let (peer, requests) = (&peer, &requests);
+                scope.spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .expect("runtime");
+                    let connector = Connector::new(None);
+                    runtime.block_on(async {
+                        // tasks go round robin over the threads
+                        let tasks = (thread..concurrency).step_by(threads).map(|n| {
+                            let quota = match config.stop {
+                                Stop::Requests(total) => Some(share(total, concurrency, n)),
+                                Stop::After(_) => None,
+                            };
+                            task(config, &connector, peer, requests, n, quota, deadline)
+                        });
+                        futures::future::join_all(tasks).await
+                    })
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("load thread"))
+            .collect()
+    });
+
+    let mut report = Report {
+        mix: config.mix.clone(),
+        elapsed: started.elapsed(),
+        latencies: vec![Vec::new(); config.mix.len()],
+        statuses: BTreeMap::new(),
+        errors: BTreeMap::new(),
+        bytes: 0,
+    };
+    for outcome in outcomes {
+        for (all, task) in report.latencies.iter_mut().zip(outcome.latencies) {
+            all.extend(task);
+        }
+        for (status, count) in outcome.statuses {
+            *report.statuses.entry(status).or_default() += count;
+        }
+        for (error, count) in outcome.errors {
+            *report.errors.entry(error).or_default() += count;
+        }
+        report.bytes += outcome.bytes;
+    }
+    for latencies in &mut report.latencies {
+        latencies.sort_unstable();
+    }
+    Ok(report)
+}
@@ -0,0 +1,328 @@
+//! Maglev hashing.
+//!
+//! The lookup table of Google's Maglev load balancer, an alternative to the
+//! [`Continuum`] ring: every node walks its own permutation of the slots of a
+//! table of prime size, taking the first free slot in turn with the others,
+//! until the table is full. A key maps to the node of the slot its hash
+//! falls in, one array read where the ring takes a binary search, and every
+//! node holds its share of the slots to within one, where the arcs of a ring
+//! of a few points per node vary widely. A change of nodes moves slightly
+//! more keys than on a ring: the slots of the node that left or joined, and
+//! a few more whose permutations collide with them.
+//!
+//! Nodes take turns in proportion to their weight. The offset and step of a
+//! node's permutation are the hashes of `"<ip>:<port>"` followed by a `0` and
+//! a `1` byte, with the [`Crc32`] of nginx's ring unless
+//! [`Maglev::with_hasher`] picks another hash function; keys are hashed by
+//! the same one.
+//!
+//! [`Continuum`]: crate::consistent_hash::Continuum
+
+use std::io::Write;
+use std::net::SocketAddr;
+
+use crate::consistent_hash::{Bucket, Crc32, Hasher, Lookup, PoolHint};
+
+/// Slots of a table unless said otherwise: a prime over a hundred times
+/// the nodes of any cluster, as Maglev wants.
+pub const DEFAULT_TABLE_SIZE: usize = 65537;
+
+/// No node holds the slot yet.
+const EMPTY: u32 = u32::MAX;
+
+#[derive(Clone, Debug, Default)]
+pub struct Maglev<H = Crc32> {
+    /// node index by slot; empty without nodes
+    table: Box<[u32]>,
+    nodes: Box<[SocketAddr]>,
+    /// by node index, hashed once rather than on every lookup
+    pools: Box<[PoolHint]>,
+    hasher: H,
+}
+
+impl Maglev {
+    /// The table of `buckets`, of [`DEFAULT_TABLE_SIZE`] slots, hashed with
+    /// [`Crc32`].
+    pub fn new(buckets: &[Bucket]) -> Result<Self, String> {
+        Maglev::with_hasher(buckets, DEFAULT_TABLE_SIZE, Crc32)
+    }
+}
+
+impl<H: Hasher> Maglev<H> {
+    /// The table of `buckets`, of `size` slots, with permutations and keys
+    /// hashed by `hasher`.
+    ///
+    /// Fails unless `size` is a prime, which every step of a permutation
+    /// must not divide, and at least the number of buckets, or if a bucket
+    /// has a weight of 0, which [`Bucket::new`] refuses too: a node that
+    /// takes no turns holds no slots.
+    pub fn with_hasher(buckets: &[Bucket], size: usize, hasher: H) -> Result<Self, String> {
+        if !is_prime(size) {
+            return Err(format!("maglev table size {size} is not a prime"));
+        }
+        if size < buckets.len() {
+            return Err(format!(
+                "maglev table of {size} slots for {} nodes",
+                buckets.len()
+            ));
+        }
+        if let Some(bucket) = buckets.iter().find(|b| b.weight == 0) {
+            return Err(format!("maglev bucket {} of weight 0", bucket.node));
+        }
+        let nodes: Box<[SocketAddr]> = buckets.iter().map(|b| b.node).collect();
+        let pools = nodes.iter().map(PoolHint::of).collect();
+        if buckets.is_empty() {
+            return Ok(Maglev {
+                table: Box::default(),
+                nodes,
+                pools,
+                hasher,
+            });
+        }
+
+        // (offset, step) of every node's permutation of the slots
+        let mut name = Vec::with_capacity(39 + 2 + 1 + 5 + 1);
+        let permutations: Vec<(u64, u64)> = buckets
+            .iter()
+            .map(|bucket| {
+                name.clear();
+                write!(name, "{}", bucket.node).unwrap();
+                let prefix = name.len();
+                name.push(0);
+                let offset = u64::from(hasher.hash(&name)) % size as u64;
+                name.truncate(prefix);
+                name.push(1);
+                let step = u64::from(hasher.hash(&name)) % (size as u64 - 1) + 1;
+                (offset, step)
+            })
+            .collect();
+
+        let mut table = vec![EMPTY; size];
+        let mut next = vec![0u64; buckets.len()];
+        // a node takes a turn for every max_weight of credit it has
+        let max_weight = buckets.iter().map(|b| b.weight).max().unwrap();
+        let mut credit = vec![0u32; buckets.len()];
+        let mut filled = 0;
+        'fill: loop {
+            for (node, bucket) in buckets.iter().enumerate() {
+                credit[node] += bucket.weight;
+                while credit[node] >= max_weight {
+                    credit[node] -= max_weight;
+                    let (offset, step) = permutations[node];
+                    // every permutation visits every slot, one is free
+                    let slot = loop {
+                        let slot = ((offset + next[node] * step) % size as u64) as usize;
+                        next[node] += 1;
+                        if table[slot] == EMPTY {
+                            break slot;
+                        }
+                    };
+                    table[slot] = node as u32;
+                    filled += 1;
+                    if filled == size {
+                        break 'fill;
+                    }
+                }
+            }
+        }
+
+        Ok(Maglev {
+            table: table.into_boxed_slice(),
+            nodes,
+            pools,
+            hasher,
+        })
+    }
+
+    /// The number of slots, none without nodes.
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// The slot `key` falls in.
+    pub fn slot(&self, key: &[u8]) -> usize {
+        self.hasher.hash(key) as usize % self.table.len().max(1)
+    }
+
+    /// The node `key` maps to, `None` on an empty table.
+    pub fn node(&self, key: &[u8]) -> Option<SocketAddr> {
+        self.lookup(key).map(|l| l.node)
+    }
+
+    /// The node `key` maps to with its pool, `None` on an empty table.
+    pub fn lookup(&self, key: &[u8]) -> Option<Lookup> {
+        self.table
+            .get(self.slot(key))
+            .map(|&node| self.lookup_of(node))
+    }
+
+    fn lookup_of(&self, node: u32) -> Lookup {
+        Lookup {
+            node: self.nodes[node as usize],
+            pool: self.pools[node as usize],
+        }
+    }
+
+    /// The nodes of the slots from the one of `key` on, around the table and
+    /// again, for falling back when the node of `key` cannot be used. Nodes
+    /// repeat; see [`Maglev::nodes`] for distinct ones.
+    pub fn node_iter(&self, key: &[u8]) -> NodeIterator<'_, H> {
+        NodeIterator {
+            slot: self.slot(key),
+            maglev: self,
+        }
+    }
+
+    /// The first `n` distinct nodes from the slot of `key` on, in table
+    /// order. Fewer if the table has fewer nodes.
+    pub fn nodes(&self, key: &[u8], n: usize) -> Vec<SocketAddr> {
+        self.lookups(key, n).iter().map(|l| l.node).collect()
+    }
+
+    /// [`Maglev::nodes`] with their pools.
+    pub fn lookups(&self, key: &[u8], n: usize) -> Vec<Lookup> {
+        let n = n.min(self.nodes.len());
+        let mut found: Vec<Lookup> = Vec::with_capacity(n);
+        if self.table.is_empty() {
+            return found;
+        }
+        let start = self.slot(key);
+        for i in 0..self.table.len() {
+            if found.len() == n {
+                break;
+            }
+            let node = self.table[(start + i) % self.table.len()];
+            if !found.iter().any(|l| l.node == self.nodes[node as usize]) {
+                found.push(self.lookup_of(node));
+            }
+        }
+        found
+    }
+
+    /// Slots held by each node, in the order of the buckets.
+    pub fn shares(&self) -> Vec<(SocketAddr, usize)> {
+        let mut slots = vec![0; self.nodes.len()];
+        for &node in &*self.table {
+            slots[node as usize] += 1;
+        }
+        self.nodes.iter().copied().zip(slots).collect()
+    }
+
+    /// Share of the slots, from 0 to 1, held by another node in `other`:
+    /// the keys a change from `self` to `other` moves. Tables of different
+    /// sizes share no slots, all keys move.
+    pub fn moved(&self, other: &Maglev<H>) -> f64 {
+        match (self.table.is_empty(), other.table.is_empty()) {
+            (true, true) => return 0.0,
+            (false, false) if self.table.len() == other.table.len() => {}
+            _ => return 1.0,
+        }
+        let moved = self
+            .table
+            .iter()
+            .zip(&*other.table)
+            .filter(|&(&a, &b)| self.nodes[a as usize] != other.nodes[b as usize])
+            .count();
+        moved as f64 / self.table.len() as f64
+    }
+}
+
+/// Endless walk around the table, see [`Maglev::node_iter`].
+pub struct NodeIterator<'a, H = Crc32> {
+    slot: usize,
+    maglev: &'a Maglev<H>,
+}
+
+impl<'a, H: Hasher> Iterator for NodeIterator<'a, H> {
+    type Item = &'a SocketAddr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = *self.maglev.table.get(self.slot)?;
+        self.slot = (self.slot + 1) % self.maglev.table.len();
+        Some(&self.maglev.nodes[node as usize])
+    }
+}
+
+fn is_prime(n: usize) -> bool {
+    n >= 2
+        && (2..)
+            .take_while(|d| d * d <= n)
+            .all(|d| !n.is_multiple_of(d))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buckets(weights: &[u32]) -> Vec<Bucket> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| Bucket::new(([10, 0, 0, i as u8 + 1], 80).into(), weight))
+            .collect()
+    }
+
+    #[test]
+    fn slots_are_shared_by_weight() {
+        let even = Maglev::new(&buckets(&[1; 5])).expect("table");
+        for (node, slots) in even.shares() {
+            assert!(
+                slots.abs_diff(DEFAULT_TABLE_SIZE / 5) <= 1,
+                "{node}: {slots}"
+            );
+        }
+
+        let weights = [1, 2, 3, 2];
+        let weighted = Maglev::new(&buckets(&weights)).expect("table");
+        let total: u32 = weights.iter().sum();
+        for ((node, slots), weight) in weighted.shares().into_iter().zip(weights) {
+            let expected = DEFAULT_TABLE_SIZE * weight as usize / total as usize;
+            assert!(
+                slots.abs_diff(expected) <= 3,
+                "{node}: {slots} for {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn removing_a_node_moves_few_other_keys() {
+        let all = buckets(&[1; 5]);
+        let before = Maglev::new(&all).expect("table");
+        let after = Maglev::new(&[&all[..2], &all[3..]].concat()).expect("table");
+        let gone = all[2].node;
+        // the slots of the node that left, and a few more
+        let moved = before.moved(&after);
+        assert!((0.2..0.22).contains(&moved), "{moved}");
+        let others = (0..DEFAULT_TABLE_SIZE)
+            .filter(|&slot| {
+                let node = before.nodes[before.table[slot] as usize];
+                node != gone && node != after.nodes[after.table[slot] as usize]
+            })
+            .count();
+        assert!(
+            others < DEFAULT_TABLE_SIZE / 50,
+            "{others} slots of staying nodes moved"
+        );
+    }
+
+    #[test]
+    fn tables_that_cannot_be_filled() {
+        let nodes = buckets(&[1, 1, 1]);
+        for (size, error) in [(4, "not a prime"), (2, "2 slots for 3 nodes")] {
+            match Maglev::with_hasher(&nodes, size, Crc32) {
+                Ok(_) => panic!("table of {size} slots"),
+                Err(e) => assert!(e.contains(error), "{size}: {e}"),
+            }
+        }
+        let weightless = [Bucket {
+            weight: 0,
+            ..nodes[0]
+        }];
+        assert!(Maglev::new(&weightless).is_err());
+        assert!(Maglev::new(&[]).expect("table").is_empty());
+    }
+}
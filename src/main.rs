@@ -1,3 +1,1049 @@
-fn main() {
-    println!("Hello, world!");
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use pingora::apps::ServerApp;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::{Backends, LoadBalancer, health_check, selection::RoundRobin};
+use pingora::listeners::TcpSocketOptions;
+use pingora::server::Server;
+use pingora::server::configuration::Opt;
+use pingora::services::ServiceWithDependents;
+use pingora::services::background::background_service;
+use pingora::services::listening::Service;
+
+use proxy_rs::admin::Admin;
+use proxy_rs::allocations::Counting;
+use proxy_rs::anomaly::{AnomalyConfig, AnomalyScorer};
+use proxy_rs::balancing::{Balancing, Latencies};
+use proxy_rs::billing::{UsageExporter, UsageMeter, UsageSink};
+use proxy_rs::cache::{CacheConfig, MemoryCache};
+use proxy_rs::certs::{CertMonitor, CertSource};
+use proxy_rs::circuit::{CircuitBreakers, CircuitConfig, CircuitSync};
+use proxy_rs::config::{
+    Config, DEFAULT_ADMIN, DEFAULT_POOL, Listen, NoUpstreamConfig, UpstreamPeer,
+};
+use proxy_rs::connect_race::ConnectRace;
+use proxy_rs::connections::Connections;
+use proxy_rs::diagnostics::Runtimes;
+use proxy_rs::discovery::{
+    BuildRoutes, ConfigFile, DnsDiscovery, DockerConfig, DockerWatcher, FileDiscovery, HangupReload,
+};
+use proxy_rs::dns::{Resolver, ResolverConfig};
+use proxy_rs::doh::{DohConfig, DohGateway};
+use proxy_rs::drain::{DrainRegistry, DrainingDiscovery};
+use proxy_rs::egress::EgressPolicy;
+use proxy_rs::expect::ExpectContinue;
+use proxy_rs::family::{FamilyDiscovery, FamilyPreference, Nat64};
+use proxy_rs::feedback::{FeedbackConfig, FeedbackDiscovery, LoadFeedback};
+use proxy_rs::flags::{FeatureFlags, FlagPoller};
+use proxy_rs::forward::ForwardProxy;
+use proxy_rs::gateway::{Cors, Gateway};
+use proxy_rs::geo::{GeoDb, GeoRates};
+use proxy_rs::h2_fallback::H2Fallback;
+use proxy_rs::h2_server::H2Server;
+use proxy_rs::har::{HarConfig, HarRecorder};
+use proxy_rs::in_flight::InFlight;
+use proxy_rs::labels::PathStats;
+use proxy_rs::listener::ListenerConfig;
+use proxy_rs::loadgen::{LoadConfig, MixEntry, Stop};
+use proxy_rs::no_upstream::{NoUpstream, NoUpstreamCounts};
+use proxy_rs::openapi::OpenApi;
+use proxy_rs::plan::Planner;
+use proxy_rs::proxy::LB;
+use proxy_rs::quarantine::{Quarantine, QuarantineScan};
+use proxy_rs::readiness::{Readiness, ReadinessConfig};
+use proxy_rs::region::RegionFailover;
+use proxy_rs::rollback::{ReloadWatch, RouterVersions};
+use proxy_rs::route::{Route, Router, SharedRouter};
+use proxy_rs::runtime_upstreams::{RuntimeDiscovery, RuntimeUpstreams};
+use proxy_rs::scan::{ContentScanner, Scanner};
+use proxy_rs::sharded::ShardAggregator;
+use proxy_rs::sniff::Sniffer;
+use proxy_rs::stalls::WriteStalls;
+use proxy_rs::startup::{ClusterProbe, StartupProbe};
+use proxy_rs::strict_host::StrictHosts;
+use proxy_rs::synthetic::SyntheticProber;
+use proxy_rs::template::Templates;
+use proxy_rs::transparent::TransparentListener;
+use proxy_rs::upstream_tcp::UpstreamTcp;
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+#[derive(Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(flatten)]
+    server: Opt,
+    /// Exit if a required cluster has no upstream up at startup.
+    #[clap(long)]
+    fail_fast: bool,
+    /// Write HAR archives of sampled requests to this directory.
+    #[clap(long)]
+    har_dir: Option<PathBuf>,
+    /// Share of requests archived with --har-dir.
+    #[clap(long, default_value_t = 0.01)]
+    har_sample_rate: f64,
+    /// CSV of networks with their country and ASN, for rate limits that
+    /// scale with where clients come from, by the geo_rates of --config.
+    #[clap(long)]
+    geo_db: Option<PathBuf>,
+    /// Client features endpoint of an Unleash server to poll the feature
+    /// flags from, instead of using the feature_flags of --config.
+    #[clap(long)]
+    unleash_url: Option<String>,
+    /// API token for --unleash-url.
+    #[clap(long)]
+    unleash_token: Option<String>,
+    /// Append the usage records of every minute to this file, as JSON lines.
+    #[clap(long)]
+    usage_log: Option<PathBuf>,
+    /// POST the usage records of every minute to this URL instead.
+    #[clap(long)]
+    usage_url: Option<String>,
+    /// Bearer token for --usage-url.
+    #[clap(long)]
+    usage_token: Option<String>,
+    /// Token that gets a request the diagnostic headers from outside the
+    /// diagnostics networks of its listener, in `X-Debug-Token`.
+    #[clap(long)]
+    debug_token: Option<String>,
+    /// YAML file of the listeners and upstream pools, each pool but the
+    /// default one with a route of its own; upstreams are read again on
+    /// SIGHUP.
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// Address to accept connections on, host:port, instead of [::]:6188
+    /// or the listeners of --config; repeat for more.
+    #[clap(long = "listen")]
+    listens: Vec<Listen>,
+    /// Address of the admin API, host:port, instead of the admin of
+    /// --config or 127.0.0.1:6190.
+    #[clap(long)]
+    admin: Option<Listen>,
+    /// File the last known good routes are written to as JSON, instead of
+    /// the routes_snapshot of --config; none are written without either.
+    #[clap(long)]
+    routes_snapshot: Option<PathBuf>,
+    /// A default upstream, host:port, instead of those of --config; repeat
+    /// for more.
+    #[clap(long = "upstream")]
+    upstreams: Vec<String>,
+    /// Server name of TLS to the default upstreams, sent as their Host too.
+    #[clap(long)]
+    tls_sni: Option<String>,
+    /// Serve only this host and the hosts routes name, instead of the
+    /// strict_hosts of --config, others get a 421; repeat for more.
+    #[clap(long = "strict-host")]
+    strict_hosts: Vec<String>,
+    /// Stay out of rotation until the cache holds this many objects,
+    /// instead of the readiness of --config.
+    #[clap(long)]
+    min_cached_objects: Option<usize>,
+    /// Stay out of rotation until this many upstream connections were
+    /// opened, instead of the readiness of --config.
+    #[clap(long)]
+    min_upstream_connections: Option<usize>,
+    /// Upstream addresses used: any, prefer-v4, prefer-v6, v4 or v6.
+    #[clap(long, default_value = "any")]
+    upstream_family: FamilyPreference,
+    /// How upstreams are picked: round-robin, weighted-round-robin to
+    /// spread requests by the weights of the upstreams file, or
+    /// least-connections for the one with the fewest requests in flight,
+    /// peak-ewma to also weigh them by how fast the upstream answers, or
+    /// power-of-two-choices for the less loaded of two drawn at random.
+    #[clap(long, default_value = "round-robin")]
+    balancing: Balancing,
+    /// NAT64 prefix IPv4 upstreams are reached through, on IPv6-only hosts,
+    /// e.g. 64:ff9b::/96.
+    #[clap(long)]
+    nat64_prefix: Option<String>,
+    /// Accept connections an iptables TPROXY rule diverts to the listener,
+    /// which takes CAP_NET_ADMIN.
+    #[clap(long)]
+    transparent: bool,
+    /// Connections start with a PROXY protocol header, from the L4 balancer
+    /// or an intercepting proxy in front.
+    #[clap(long)]
+    proxy_protocol: bool,
+    /// Path prefix of an API route behind the gateway presets, e.g.
+    /// /api/v1/: the prefix stripped, the auth context sent upstream, the
+    /// proxy's errors in a JSON envelope and CORS answered by the proxy.
+    #[clap(long)]
+    api_prefix: Option<String>,
+    /// Origins the API route allows cross-origin requests from, comma
+    /// separated; any origin without.
+    #[clap(long, value_delimiter = ',')]
+    cors_origins: Vec<String>,
+    /// OpenAPI 3 spec, in JSON, requests to the API route are checked
+    /// against; paths in it are below the API prefix.
+    #[clap(long, requires = "api_prefix")]
+    openapi_spec: Option<PathBuf>,
+    /// Scanner document uploads and downloads are checked by,
+    /// icap://host[:port]/service or an http(s) URL.
+    #[clap(long)]
+    scanner_url: Option<String>,
+    /// Directory attachments are held in until scanned, by the scanner of
+    /// `--scanner-url` or else clamdscan.
+    #[clap(long)]
+    quarantine_dir: Option<String>,
+    /// Region the proxy and its clusters are in.
+    #[clap(long, default_value = "local")]
+    region: String,
+    /// Region traffic fails over to when no local upstream is healthy.
+    #[clap(long)]
+    secondary_region: Option<String>,
+    /// Default upstreams of --secondary-region, comma separated.
+    #[clap(long, value_delimiter = ',')]
+    secondary_upstreams: Vec<String>,
+    /// Address the circuits other replicas open are received on, as
+    /// 0.0.0.0:6191.
+    #[clap(long)]
+    circuit_sync_bind: Option<String>,
+    /// Replicas the circuits opened here are sent to, comma separated.
+    #[clap(long, value_delimiter = ',')]
+    circuit_peers: Vec<String>,
+    /// Key circuit messages between replicas are signed with.
+    #[clap(long)]
+    circuit_sync_key: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Send a deterministic request mix to a target and report latency
+    /// percentiles, instead of running the proxy.
+    Loadgen(LoadgenArgs),
+}
+
+#[derive(clap::Args)]
+struct LoadgenArgs {
+    /// URL of the target, as http://127.0.0.1:6188.
+    target: http::Uri,
+    /// A request of the mix, `METHOD PATH [WEIGHT]`; repeat for more.
+    #[clap(long = "request", default_value = "GET /")]
+    requests: Vec<MixEntry>,
+    /// Requests sent at the same time.
+    #[clap(long, default_value_t = 16)]
+    concurrency: usize,
+    /// Threads sending them, each with its own connections.
+    #[clap(long, default_value_t = 1)]
+    threads: usize,
+    /// Requests to send in all.
+    #[clap(long, default_value_t = 10_000, conflicts_with = "duration")]
+    total: u64,
+    /// Send for this many seconds instead of a number of requests.
+    #[clap(long)]
+    duration: Option<f64>,
+    /// Requests per second to send at, whatever the latency.
+    #[clap(long)]
+    rate: Option<f64>,
+    /// Seed of the request mix; equal seeds send equal sequences.
+    #[clap(long, default_value_t = 1)]
+    seed: u64,
+    /// Speak h2 to the target.
+    #[clap(long)]
+    h2: bool,
+    /// Open a connection per request instead of keeping them alive.
+    #[clap(long)]
+    close: bool,
+    /// Bytes of body sent with POST, PUT and PATCH requests.
+    #[clap(long, default_value_t = 0)]
+    body_size: usize,
+    /// Seconds a connect, write or read may take.
+    #[clap(long, default_value_t = 10.0)]
+    timeout: f64,
+    /// Print the report as JSON.
+    #[clap(long)]
+    json: bool,
+}
+
+fn loadgen(args: LoadgenArgs) -> std::process::ExitCode {
+    let seconds = |s: f64, what: &str| {
+        Duration::try_from_secs_f64(s).unwrap_or_else(|_| {
+            eprintln!("loadgen: bad {what} {s}");
+            std::process::exit(2)
+        })
+    };
+    let config = LoadConfig {
+        target: args.target,
+        mix: args.requests,
+        concurrency: args.concurrency,
+        threads: args.threads,
+        stop: match args.duration {
+            Some(duration) => Stop::After(seconds(duration, "duration")),
+            None => Stop::Requests(args.total),
+        },
+        rate: args.rate,
+        seed: args.seed,
+        h2: args.h2,
+        close: args.close,
+        body_size: args.body_size,
+        timeout: seconds(args.timeout, "timeout"),
+    };
+    match proxy_rs::loadgen::run(&config) {
+        Ok(report) if args.json => println!("{}", report.to_json()),
+        Ok(report) => print!("{report}"),
+        Err(e) => {
+            eprintln!("loadgen: {e}");
+            return std::process::ExitCode::FAILURE;
+        }
+    }
+    std::process::ExitCode::SUCCESS
+}
+
+/// The value of `result`, or else its error reported after `what`, exiting.
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>, what: &str) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{what}: {e}");
+        std::process::exit(1)
+    })
+}
+
+/// The service of `app` listening on `addr`.
+fn listening<A: ServerApp + Send + Sync + 'static>(
+    name: String,
+    app: A,
+    addr: &str,
+    options: TcpSocketOptions,
+) -> Box<dyn ServiceWithDependents> {
+    let mut service = Service::new(name, app);
+    service.add_tcp_with_settings(addr, options);
+    Box::new(service)
+}
+
+// RUST_LOG=INFO cargo run
+/// The routes of `config`, with `cli`, those of the command line, on the
+/// `clusters` of its pools by name, `default` being the default upstreams.
+fn config_routes(
+    config: &Config,
+    cli: &[Route],
+    default: &Arc<LoadBalancer<RoundRobin>>,
+    clusters: &[(String, Arc<LoadBalancer<RoundRobin>>)],
+) -> Result<Vec<Route>, String> {
+    let cluster = |name: &str| {
+        clusters
+            .iter()
+            .find(|(pool, _)| pool == name)
+            .map(|(_, cluster)| cluster.clone())
+            .ok_or_else(|| format!("pool {name} has no cluster, adding a pool takes a restart"))
+    };
+    let mut routes = Vec::new();
+    // as a sidecar, outbound HTTP redirected to the proxy goes on to where
+    // it was headed
+    if let Some(sidecar) = &config.egress {
+        let mut egress = Route::new("egress", "/");
+        egress.original_dst = Some(sidecar.dst.clone());
+        egress.original_dst_cluster = true;
+        routes.push(egress);
+    }
+    if let Some(gateway) = &config.doh {
+        let mut doh = Route::new("doh", &gateway.path);
+        doh.doh = Some(Arc::new(DohGateway::new(DohConfig {
+            upstreams: gateway.upstreams.clone(),
+            ..Default::default()
+        })));
+        routes.push(doh);
+    }
+    routes.extend_from_slice(cli);
+    // pools with a prefix get a route of their name
+    for pool in &config.pools {
+        let Some(prefix) = &pool.path_prefix else {
+            continue;
+        };
+        let mut route = Route::new(pool.name.clone(), prefix.clone());
+        route.upstreams = Some(cluster(&pool.name)?);
+        route.peer = Some(Arc::new(pool.peer.clone()));
+        route.max_in_flight = pool.max_in_flight;
+        routes.push(route);
+    }
+    // the routes of the config file, on the clusters of their pools
+    for configured in &config.routes {
+        let mut route = configured.route.clone();
+        if let Some(name) = &configured.pool
+            && let Some(pool) = config.pool(name)
+        {
+            route.upstreams = Some(cluster(name)?);
+            route.peer = Some(Arc::new(pool.peer.clone()));
+            route.max_in_flight = pool.max_in_flight;
+        }
+        route.no_upstream = match &configured.no_upstream {
+            NoUpstreamConfig::Answer(answer) => answer.clone(),
+            NoUpstreamConfig::Fallback(name) if name == DEFAULT_POOL => {
+                NoUpstream::Fallback(default.clone())
+            }
+            NoUpstreamConfig::Fallback(name) => NoUpstream::Fallback(cluster(name)?),
+        };
+        routes.push(route);
+    }
+    // while a window is open the maintenance page takes over the route, as
+    // when its origins are patched
+    for window in &config.maintenance {
+        let Some(i) = routes.iter().position(|r| r.name == window.route) else {
+            return Err(format!("maintenance of unknown route {}", window.route));
+        };
+        let mut maintenance = routes[i].clone();
+        maintenance.name = format!("{}-maintenance", window.route);
+        maintenance.maintenance = true;
+        maintenance.schedule = Some(window.schedule.clone());
+        routes.insert(i + 1, maintenance);
+    }
+    Ok(routes)
+}
+
+fn main() -> std::process::ExitCode {
+    env_logger::init();
+
+    // read command line arguments
+    let args = Args::parse();
+    if let Some(Command::Loadgen(load)) = args.command {
+        return loadgen(load);
+    }
+    let mut my_server = or_exit(Server::new(Some(args.server)), "server");
+    my_server.bootstrap();
+
+    let resolver = or_exit(Resolver::new(ResolverConfig::default()), "resolver");
+    let resolver = Arc::new(resolver);
+
+    // upstreams leave the cluster 30s after they started draining
+    let drain = Arc::new(DrainRegistry::new(Duration::from_secs(30)));
+
+    let config_file = args
+        .config
+        .as_ref()
+        .map(|path| Config::load(path).map(|config| ConfigFile::new(path, config)))
+        .transpose();
+    let config_file = match config_file {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let config = config_file
+        .as_ref()
+        .map(|file| file.config())
+        .unwrap_or_default();
+    let default_pool = config.pool(DEFAULT_POOL);
+    // the default upstreams follow the file unless --upstream names them
+    let default_reloads = default_pool.is_some() && args.upstreams.is_empty();
+    // TLS to one.one.one.one unless --tls-sni or the default pool says
+    // otherwise
+    let default_peer = match (&args.tls_sni, default_pool) {
+        (Some(sni), _) => UpstreamPeer::tls(sni),
+        (None, Some(pool)) => pool.peer.clone(),
+        (None, None) => UpstreamPeer::tls("one.one.one.one"),
+    };
+    let discovery: Box<dyn ServiceDiscovery + Send + Sync> = match &config_file {
+        _ if !args.upstreams.is_empty() => {
+            match DnsDiscovery::new(resolver.clone(), &args.upstreams) {
+                Ok(discovery) => discovery,
+                Err(e) => {
+                    eprintln!("--upstream: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(file) if default_reloads => {
+            FileDiscovery::new(resolver.clone(), file.clone(), DEFAULT_POOL)
+        }
+        // 127.0.0.1:343" is just a bad server
+        _ => DnsDiscovery::new(
+            resolver.clone(),
+            ["1.1.1.1:443", "1.0.0.1:443", "127.0.0.1:343"],
+        )
+        .unwrap(),
+    };
+    let nat64 = args
+        .nat64_prefix
+        .map(|prefix| or_exit(Nat64::new(&prefix), "--nat64-prefix"));
+    let discovery = FamilyDiscovery::new(discovery, args.upstream_family, nat64);
+    // operators add and remove upstreams through the admin API
+    let runtime_upstreams = Arc::new(RuntimeUpstreams::default());
+    let discovery = RuntimeDiscovery::new(discovery, runtime_upstreams.clone());
+    // weights follow the load the upstreams report
+    let feedback = Arc::new(LoadFeedback::new(FeedbackConfig::default()));
+    let discovery = FeedbackDiscovery::new(discovery, feedback.clone());
+    let discovery = DrainingDiscovery::new(discovery, drain.clone());
+    let mut upstreams = LoadBalancer::from_backends(Backends::new(discovery));
+    // re-resolve hostnames, the resolver only queries once their TTL expired
+    upstreams.update_frequency = Some(Duration::from_secs(5));
+
+    // We add health check in the background so that the bad server is never selected.
+    let hc = health_check::TcpHealthCheck::new();
+    upstreams.set_health_check(hc);
+    upstreams.health_check_frequency = Some(Duration::from_secs(1));
+
+    let background = background_service("health check", upstreams);
+
+    let upstreams = background.task();
+
+    // routes with cache bytes of their own, a spike of them cannot push
+    // the rest out of the shared store
+    let cache = config.routes.iter().fold(
+        MemoryCache::new(CacheConfig {
+            slice_size: Some(1024 * 1024),
+            preflight_ttl: Duration::from_secs(10 * 60),
+            ..Default::default()
+        }),
+        |cache, configured| match configured.cache_bytes {
+            Some(bytes) => cache.with_partition(configured.route.cache_partition(), bytes),
+            None => cache,
+        },
+    );
+    let cache = Arc::new(cache);
+
+    // routes of the command line, next to those of the config file
+    let mut cli_routes = Vec::new();
+    if let Some(prefix) = &args.api_prefix {
+        let cors = if args.cors_origins.is_empty() {
+            Cors::any()
+        } else {
+            Cors::origins(&args.cors_origins)
+        };
+        let mut api = Route::new("api", prefix);
+        api.gateway = Some(Arc::new(Gateway::api().with_cors(cors)));
+        api.openapi = args.openapi_spec.as_ref().map(|path| {
+            let spec = or_exit(OpenApi::load(path), "--openapi-spec");
+            Arc::new(spec.with_base_path(prefix))
+        });
+        cli_routes.push(api);
+    }
+    // documents in and out are checked for malware, and not served while
+    // the scanner is down
+    let scanner = args.scanner_url.as_ref().map(|url| {
+        let uri = or_exit(url.parse(), &format!("scanner URL {url}"));
+        let scanner = if url.starts_with("icap://") {
+            Scanner::Icap(uri)
+        } else {
+            Scanner::Http(uri)
+        };
+        Arc::new(ContentScanner::new(scanner).with_max_body(20 * 1024 * 1024))
+    });
+    if let Some(scanner) = &scanner {
+        let mut documents = Route::new("documents", "/documents/");
+        documents.scan = Some(scanner.clone());
+        cli_routes.push(documents);
+    }
+    // attachments only reach the cluster once scanned clean, infected ones
+    // are kept for the security team
+    if let Some(dir) = &args.quarantine_dir {
+        let scan = match &scanner {
+            Some(scanner) => QuarantineScan::Scanner(scanner.clone()),
+            // exits 0 for clean files and 1 for infected ones
+            None => QuarantineScan::Command(vec![
+                "clamdscan".to_string(),
+                "--no-summary".to_string(),
+                "--fdpass".to_string(),
+            ]),
+        };
+        let quarantine = or_exit(
+            Quarantine::new(dir, scan),
+            &format!("quarantine directory {dir}"),
+        )
+        .with_max_size(20 * 1024 * 1024);
+        let mut attachments = Route::new("attachments", "/attachments/");
+        attachments.quarantine = Some(Arc::new(quarantine));
+        cli_routes.push(attachments);
+    }
+    // the other pools of the config file get a cluster each
+    let mut pools = Vec::new();
+    for (file, pool) in config_file
+        .iter()
+        .flat_map(|file| config.pools.iter().map(move |pool| (file, pool)))
+    {
+        if pool.name == DEFAULT_POOL {
+            continue;
+        }
+        let discovery = FileDiscovery::new(resolver.clone(), file.clone(), &pool.name);
+        let mut cluster = LoadBalancer::from_backends(Backends::new(discovery));
+        cluster.update_frequency = Some(Duration::from_secs(5));
+        cluster.set_health_check(health_check::TcpHealthCheck::new());
+        cluster.health_check_frequency = Some(Duration::from_secs(1));
+        let cluster = background_service(&format!("pool {} health check", pool.name), cluster);
+        pools.push((pool, cluster));
+    }
+    let clusters: Vec<(String, Arc<LoadBalancer<RoundRobin>>)> = pools
+        .iter()
+        .map(|(pool, cluster)| (pool.name.clone(), cluster.task()))
+        .collect();
+    let routes = match config_routes(&config, &cli_routes, &upstreams, &clusters) {
+        Ok(routes) => routes,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let router = Router::new(routes);
+    if let Err(e) = router.validate() {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    let router = Arc::new(SharedRouter::new(router));
+    // reloads answering over a fifth of their first minute with 5xx are undone
+    let versions = RouterVersions::new(router.clone(), ReloadWatch::default());
+    let versions = match args
+        .routes_snapshot
+        .or_else(|| config.routes_snapshot.clone())
+    {
+        Some(path) => versions.with_snapshot(path),
+        None => versions,
+    };
+    let versions = Arc::new(versions);
+    // swaps the upstreams of the file in, requests in flight keep theirs,
+    // and reloads the routes, candidates of the file are planned alike
+    let reload = config_file.map(|file| {
+        let default = default_reloads.then(|| (DEFAULT_POOL.to_string(), upstreams.clone()));
+        let reloaded: Vec<_> = default
+            .into_iter()
+            .chain(clusters.iter().cloned())
+            .collect();
+        let default = upstreams.clone();
+        let build: BuildRoutes = Arc::new(move |config: &Config| {
+            config_routes(config, &cli_routes, &default, &clusters)
+        });
+        let planner = Planner::new(resolver.clone(), reloaded.clone(), build.clone())
+            .with_feedback(feedback.clone());
+        let reload = HangupReload::new(file, reloaded).with_routes(versions.clone(), build);
+        (
+            background_service("config reload", reload),
+            Arc::new(planner),
+        )
+    });
+    let (reload, planner) = reload.unzip();
+
+    // routes for labelled containers, when running next to a Docker daemon
+    let docker_config = DockerConfig::default();
+    let docker = std::path::Path::new(&docker_config.socket)
+        .exists()
+        .then(|| {
+            let watcher = DockerWatcher::new(docker_config, versions.clone());
+            background_service("docker discovery", watcher)
+        });
+
+    // per-tenant page overrides, the built-in pages are used without them
+    let templates = match &config.templates {
+        Some(dir) => Templates::load(dir).unwrap_or_else(|e| {
+            eprintln!("templates {}: {e}", dir.display());
+            std::process::exit(1);
+        }),
+        None => Templates::default(),
+    };
+    let templates = Arc::new(templates);
+
+    // --listen beats the listeners of the config file, IPv4 and IPv6
+    // clients on one socket without either
+    let mut listeners: Vec<ListenerConfig> = if !args.listens.is_empty() {
+        args.listens
+            .iter()
+            .cloned()
+            .map(ListenerConfig::from)
+            .collect()
+    } else if !config.listeners.is_empty() {
+        config.listeners.clone()
+    } else {
+        vec![ListenerConfig::dual_stack(6188)]
+    };
+    // with strict hosts, hosts the routes do not name get a 421 rather than
+    // the default cluster
+    let strict_hosts = match &args.strict_hosts[..] {
+        [] => &config.strict_hosts[..],
+        hosts => hosts,
+    };
+    for listener in &mut listeners {
+        listener.expect_continue = ExpectContinue::AfterFilters;
+        if !strict_hosts.is_empty() {
+            listener.strict_hosts = Some(StrictHosts::new(strict_hosts));
+        }
+        // cache and upstream diagnostics for the networks of the listener,
+        // and for anyone with the debug token
+        if let Some(token) = &args.debug_token {
+            listener.diagnostics = Some(
+                listener
+                    .diagnostics
+                    .take()
+                    .unwrap_or_default()
+                    .with_token(token),
+            );
+        }
+        listener.proxy_protocol = args.proxy_protocol;
+    }
+    // TPROXY diverts connections to the first listener
+    listeners[0].transparent = args.transparent;
+
+    // upstreams failing h2 get HTTP/1.1 for five minutes
+    let h2_fallback = Arc::new(H2Fallback::new(3, Duration::from_secs(300)));
+    let stalls = Arc::new(WriteStalls::default());
+    // at most 200 paths a route, scans of random paths count as `other`
+    let paths = Arc::new(PathStats::new(200));
+    let connections = Arc::new(Connections::default());
+    let no_upstream = Arc::new(NoUpstreamCounts::default());
+    // a default upstream at the cap of its pool gets no more requests
+    let in_flight = Arc::new(InFlight::new(default_pool.and_then(|p| p.max_in_flight)));
+    let latencies = Arc::new(Latencies::default());
+    // counts are kept per worker, the cap checks their totals as of at most
+    // 5ms ago
+    let aggregator = ShardAggregator::new(Duration::from_millis(5))
+        .with(in_flight.clone())
+        .with(paths.clone());
+    let aggregator = background_service("shard aggregation", aggregator);
+    let runtimes = Arc::new(Runtimes::default());
+    let anomaly = Arc::new(AnomalyScorer::new(AnomalyConfig::default()));
+
+    // give the default cluster ten seconds to have an upstream accept connections
+    let probe = StartupProbe::new(
+        vec![ClusterProbe::new("default", upstreams.clone())],
+        Duration::from_secs(10),
+    )
+    .with_fail_fast(args.fail_fast);
+    let probe = background_service("startup probe", probe);
+
+    // rotation waits for the probe, and for a warm cache and pool if asked
+    let readiness = Arc::new(
+        Readiness::new(ReadinessConfig {
+            min_cached_objects: args
+                .min_cached_objects
+                .or(config.readiness.min_cached_objects),
+            min_upstream_connections: args
+                .min_upstream_connections
+                .or(config.readiness.min_upstream_connections),
+        })
+        .with_probe(probe.task())
+        .with_cache(cache.clone()),
+    );
+
+    // the feature flags of the config file, or those polled from Unleash;
+    // upstreams get no x-feature headers without either
+    let flags = (config.feature_flags.is_some() || args.unleash_url.is_some()).then(|| {
+        let configured = config.feature_flags.clone().unwrap_or_default();
+        Arc::new(FeatureFlags::new(configured.flags, configured.stickiness))
+    });
+    let flag_poller = args.unleash_url.zip(flags.clone()).map(|(url, flags)| {
+        let poller = FlagPoller::new(flags, &url, args.unleash_token, Duration::from_secs(15));
+        let poller = or_exit(poller, "--unleash-url");
+        background_service("feature flags", poller)
+    });
+
+    // what redirected connections and the forward proxy may reach, anything
+    // without a policy
+    let egress_policy = config
+        .egress_policy
+        .clone()
+        .map(|rules| Arc::new(EgressPolicy::new(rules)));
+    // CONNECT tunnels of the users of the config file, held to their
+    // policies and the egress policy
+    let forward = config.forward.clone().map(|forward| {
+        let mut proxy = match ForwardProxy::new(forward.config, resolver.clone()) {
+            Ok(proxy) => proxy,
+            Err(e) => {
+                eprintln!("forward proxy: {e}");
+                std::process::exit(1);
+            }
+        };
+        if let Some(policy) = &egress_policy {
+            proxy = proxy.with_egress(policy.clone());
+        }
+        let mut options = TcpSocketOptions::default();
+        options.ipv6_only = forward.listen.ipv6_only;
+        let name = format!("forward proxy {}", forward.listen.addr);
+        listening(name, proxy, &forward.listen.addr, options)
+    });
+
+    // usage by tenant and consumer, exported every minute for billing
+    let usage = Arc::new(UsageMeter::default());
+    let usage_sink = match (args.usage_url, args.usage_log) {
+        (Some(url), _) => Some(UsageSink::Http {
+            url: or_exit(url.parse(), &format!("usage URL {url}")),
+            token: args.usage_token,
+        }),
+        (None, Some(path)) => Some(UsageSink::JsonLines(path)),
+        (None, None) => None,
+    };
+    let usage_exporter = usage_sink.map(|sink| {
+        let exporter = UsageExporter::new(usage.clone(), sink, Duration::from_secs(60));
+        background_service("usage export", exporter)
+    });
+
+    let addr = listeners[0].addr.clone();
+    let transparent = args.transparent.then(|| {
+        let transparent = or_exit(TransparentListener::new(&addr), "--transparent");
+        background_service("transparent listener", transparent)
+    });
+    // with no local upstream healthy, everything goes to the default
+    // upstreams of the secondary region, and DNS follows through the admin
+    // API
+    let region = args.secondary_region.map(|secondary| {
+        let mut cluster = or_exit(
+            LoadBalancer::try_from_iter(&args.secondary_upstreams),
+            &format!("upstreams of region {secondary}"),
+        );
+        cluster.set_health_check(health_check::TcpHealthCheck::new());
+        cluster.health_check_frequency = Some(Duration::from_secs(1));
+        let cluster = background_service("secondary region health check", cluster);
+        let failover =
+            RegionFailover::new(&args.region, secondary, upstreams.clone(), router.clone())
+                .with_secondary_default(cluster.task());
+        (cluster, background_service("region failover", failover))
+    });
+    // an upstream failing five requests in a row is left alone for 30s, by
+    // every replica once one of them found out
+    let circuits = Arc::new(CircuitBreakers::new(CircuitConfig::default()));
+    let circuit_sync = args.circuit_sync_bind.map(|bind| {
+        let parse = |addr: &str| or_exit(addr.parse(), &format!("circuit sync address {addr}"));
+        let Some(key) = args.circuit_sync_key else {
+            eprintln!("--circuit-sync-bind needs --circuit-sync-key");
+            std::process::exit(1);
+        };
+        let peers = args.circuit_peers.iter().map(|p| parse(p)).collect();
+        let sync = CircuitSync::new(circuits.clone(), parse(&bind), peers, key.as_bytes());
+        background_service("circuit sync", sync)
+    });
+    // firewalls between here and the upstreams forget connections quiet
+    // for five minutes: probe them after a minute, and drop pooled ones
+    // before they would be forgotten
+    let upstream_tcp = UpstreamTcp {
+        keepalive: Some(UpstreamTcp::probe(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            5,
+        )),
+        pool_idle_timeout: Some(Duration::from_secs(4 * 60)),
+        half_close: true,
+        // fan-out reads take their connections from their worker's pools
+        partition_pools: true,
+    };
+
+    // an address slow to connect gets a second one racing it after 50ms
+    let connect_race = Arc::new(ConnectRace::new(Duration::from_millis(50)));
+
+    let har = args.har_dir.map(|dir| {
+        let config = HarConfig {
+            sample_rate: args.har_sample_rate,
+            ..Default::default()
+        };
+        let har = or_exit(
+            HarRecorder::new(&dir, config),
+            &format!("HAR directory {}", dir.display()),
+        );
+        Arc::new(har)
+    });
+    let geo_rates = args.geo_db.map(|path| {
+        let db = or_exit(
+            GeoDb::load(&path),
+            &format!("GeoIP database {}", path.display()),
+        );
+        Arc::new(GeoRates::new(db, config.geo_rates.clone()))
+    });
+
+    // a proxy service of their own settings for each listener, on the same
+    // shared state
+    let mut lbs = Vec::new();
+    for listener in listeners {
+        let addr = listener.addr.clone();
+        let socket_options = listener.socket_options();
+        let h2 = listener.h2.clone();
+        let keepalive = listener.keepalive.clone();
+        let proxy_protocol = listener.proxy_protocol;
+        let sniff = listener.sniff.clone();
+        if sniff.is_some() && proxy_protocol {
+            eprintln!("listener {addr}: sniffing does not go with --proxy-protocol");
+            std::process::exit(1);
+        }
+        let mut proxy = LB::new(upstreams.clone(), listener)
+            .with_cache(cache.clone())
+            .with_router(router.clone())
+            .with_router_versions(versions.clone())
+            .with_templates(templates.clone())
+            .with_drain(drain.clone())
+            .with_h2_fallback(h2_fallback.clone())
+            .with_write_stalls(stalls.clone())
+            .with_path_stats(paths.clone())
+            .with_connections(connections.clone())
+            .with_in_flight(in_flight.clone())
+            .with_latencies(latencies.clone())
+            .with_no_upstream_counts(no_upstream.clone())
+            .with_runtimes(runtimes.clone())
+            .with_anomaly(anomaly.clone())
+            .with_readiness(readiness.clone())
+            .with_usage(usage.clone())
+            .with_circuit_breakers(circuits.clone())
+            .with_upstream_tcp(upstream_tcp.clone())
+            .with_balancing(args.balancing)
+            .with_connect_race(connect_race.clone())
+            .with_feedback(feedback.clone())
+            .with_upstream_peer(default_peer.clone());
+        if let Some((_, failover)) = &region {
+            proxy = proxy.with_region_failover(failover.task());
+        }
+        if let Some(policy) = &egress_policy {
+            proxy = proxy.with_egress(policy.clone());
+        }
+        if let Some(har) = &har {
+            proxy = proxy.with_har(har.clone());
+        }
+        if let Some(rates) = &geo_rates {
+            proxy = proxy.with_geo_rates(rates.clone());
+        }
+        if let Some(flags) = &flags {
+            proxy = proxy.with_flags(flags.clone());
+        }
+        let proxy = pingora::proxy::http_proxy(&my_server.configuration, proxy);
+        let mut server = H2Server::new(proxy, h2)
+            .with_keepalive(keepalive)
+            .with_connections(connections.clone());
+        if proxy_protocol {
+            server = server.with_proxy_protocol();
+        }
+        let name = format!("proxy {addr}");
+        let lb = match sniff {
+            None => listening(name, server, &addr, socket_options),
+            // TLS and plain TCP on the port too
+            Some(sniff) => match Sniffer::new(server, sniff) {
+                Ok(sniffer) => listening(name, sniffer, &addr, socket_options),
+                Err(e) => {
+                    eprintln!("listener {addr}: {e}");
+                    std::process::exit(1);
+                }
+            },
+        };
+        lbs.push(lb);
+    }
+
+    // warn three weeks ahead when an upstream certificate is about to expire
+    let tls_clusters = std::iter::once((DEFAULT_POOL, &default_peer, upstreams.clone()))
+        .chain(
+            pools
+                .iter()
+                .map(|(pool, cluster)| (pool.name.as_str(), &pool.peer, cluster.task())),
+        )
+        .filter(|(_, peer, _)| peer.tls)
+        .map(|(name, peer, upstreams)| CertSource::Cluster {
+            name: name.to_string(),
+            upstreams,
+            sni: peer.sni.clone(),
+        });
+    let certs = CertMonitor::new(tls_clusters.collect(), 21, Duration::from_secs(6 * 60 * 60));
+    let certs = background_service("certificate expiry", certs);
+
+    // the checks of the config file through our own listener every 30s, as
+    // a client sees it
+    let port = addr.rsplit_once(':').map_or("6188", |(_, port)| port);
+    let synthetic = SyntheticProber::new(
+        format!("127.0.0.1:{port}"),
+        config.synthetic.clone(),
+        Duration::from_secs(30),
+    );
+    let synthetic = background_service("synthetic checks", synthetic);
+
+    let admin_app = Admin::new(upstreams)
+        .with_router(router)
+        .with_router_versions(versions)
+        .with_drain(drain)
+        .with_h2_fallback(h2_fallback)
+        .with_write_stalls(stalls)
+        .with_path_stats(paths)
+        .with_connections(connections)
+        .with_in_flight(in_flight)
+        .with_latencies(latencies)
+        .with_balancing(args.balancing)
+        .with_no_upstream_counts(no_upstream)
+        .with_runtimes(runtimes)
+        .with_anomaly(anomaly)
+        .with_readiness(readiness)
+        .with_usage(usage)
+        .with_circuit_breakers(circuits)
+        .with_connect_race(connect_race)
+        .with_runtime_upstreams(runtime_upstreams)
+        .with_cache(cache)
+        .with_certs(certs.task())
+        .with_synthetic(synthetic.task());
+    let admin_app = match &region {
+        Some((_, failover)) => admin_app.with_region_failover(failover.task()),
+        None => admin_app,
+    };
+    let admin_app = match egress_policy {
+        Some(policy) => admin_app.with_egress(policy),
+        None => admin_app,
+    };
+    let admin_app = match flags {
+        Some(flags) => admin_app.with_flags(flags),
+        None => admin_app,
+    };
+    let admin_app = match planner {
+        Some(planner) => admin_app.with_planner(planner),
+        None => admin_app,
+    };
+    let admin_addr = args
+        .admin
+        .or_else(|| config.admin.clone())
+        .unwrap_or_else(|| Listen::new(DEFAULT_ADMIN));
+    let mut options = TcpSocketOptions::default();
+    options.ipv6_only = admin_addr.ipv6_only;
+    let mut admin = Service::new("admin".to_string(), admin_app);
+    admin.add_tcp_with_settings(&admin_addr.addr, options);
+
+    let background = my_server.add_service(background);
+    // probe the upstreams of the first discovery
+    let probe = my_server.add_service(probe);
+    probe.add_dependency(&background);
+    // only accept traffic once the first discovery filled the cluster and
+    // the probe is done
+    let docker = docker.map(|docker| my_server.add_service(docker));
+    let lbs: Vec<_> = lbs
+        .into_iter()
+        .map(|lb| {
+            let lb = my_server.add_boxed_service(lb);
+            lb.add_dependency(&background);
+            lb.add_dependency(&probe);
+            if let Some(docker) = &docker {
+                lb.add_dependency(docker);
+            }
+            lb
+        })
+        .collect();
+    // checks after the first discovery, when there are upstreams to check
+    my_server.add_service(certs).add_dependency(&background);
+    // probes once the proxy takes traffic
+    my_server.add_service(synthetic).add_dependency(&lbs[0]);
+    my_server.add_service(aggregator);
+    if let Some(poller) = flag_poller {
+        my_server.add_service(poller);
+    }
+    if let Some(exporter) = usage_exporter {
+        my_server.add_service(exporter);
+    }
+    let mut clusters = vec![background.clone()];
+    for (_, cluster) in pools {
+        let cluster = my_server.add_service(cluster);
+        for lb in &lbs {
+            lb.add_dependency(&cluster);
+        }
+        clusters.push(cluster);
+    }
+    if let Some(reload) = reload {
+        let reload = my_server.add_service(reload);
+        for cluster in &clusters {
+            reload.add_dependency(cluster);
+        }
+    }
+    if let Some(sync) = circuit_sync {
+        my_server.add_service(sync);
+    }
+    if let Some((cluster, failover)) = region {
+        let cluster = my_server.add_service(cluster);
+        let failover = my_server.add_service(failover);
+        // decides once both regions have been checked
+        failover.add_dependency(&background);
+        failover.add_dependency(&cluster);
+    }
+    if let Some(transparent) = transparent {
+        my_server.add_service(transparent).add_dependency(&lbs[0]);
+    }
+    if let Some(forward) = forward {
+        my_server.add_boxed_service(forward);
+    }
+    my_server.add_service(admin);
+    my_server.run_forever();
 }
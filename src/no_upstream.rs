@@ -0,0 +1,83 @@
+//! Requests whose cluster has no usable upstream.
+//!
+//! When every upstream of a cluster is down or draining, the route's
+//! [`NoUpstream`] policy decides the answer: the `unavailable` page with a
+//! 503, the cached response even if expired, or another cluster altogether.
+//! Each such request is logged and counted by cluster and outcome.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pingora::ErrorType;
+use pingora::lb::{LoadBalancer, selection::RoundRobin};
+use serde_json::{Value, json};
+
+/// Error of requests that found no usable upstream.
+pub const NO_UPSTREAM: ErrorType = ErrorType::Custom("NoUsableUpstream");
+
+#[derive(Clone, Default)]
+pub enum NoUpstream {
+    /// Answer 503 with the `unavailable` page, see [`crate::template`].
+    #[default]
+    Unavailable,
+    /// Serve the cached response if it expired at most `max_stale` ago, the
+    /// `unavailable` page otherwise.
+    ServeStale { max_stale: Duration },
+    /// Send the request to this cluster instead, the `unavailable` page if it
+    /// has no usable upstream either.
+    Fallback(Arc<LoadBalancer<RoundRobin>>),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Outcome {
+    Unavailable,
+    Stale,
+    Fallback,
+}
+
+#[derive(Default)]
+struct Counts {
+    unavailable: u64,
+    stale: u64,
+    fallback: u64,
+}
+
+/// Requests without a usable upstream, by cluster.
+#[derive(Default)]
+pub struct NoUpstreamCounts {
+    clusters: Mutex<BTreeMap<String, Counts>>,
+}
+
+impl NoUpstreamCounts {
+    pub fn record(&self, cluster: &str, outcome: Outcome) {
+        let mut clusters = self.clusters.lock().unwrap();
+        if !clusters.contains_key(cluster) {
+            clusters.insert(cluster.to_string(), Counts::default());
+        }
+        let counts = clusters.get_mut(cluster).unwrap();
+        match outcome {
+            Outcome::Unavailable => counts.unavailable += 1,
+            Outcome::Stale => counts.stale += 1,
+            Outcome::Fallback => counts.fallback += 1,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let clusters: serde_json::Map<String, Value> = self
+            .clusters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, counts)| {
+                let value = json!({
+                    "unavailable": counts.unavailable,
+                    "stale": counts.stale,
+                    "fallback": counts.fallback,
+                });
+                (name.clone(), value)
+            })
+            .collect();
+        json!({ "clusters": clusters })
+    }
+}
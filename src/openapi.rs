@@ -0,0 +1,973 @@
+//! Request validation against an OpenAPI spec.
+//!
+//! On a route with an [`OpenApi`] every request is checked against the
+//! operations of an OpenAPI 3 spec, in JSON, before it is proxied. Refused
+//! are requests that:
+//!
+//! - are for a path the spec has no operation on (404), or for a method the
+//!   path has none for (405, with `Allow`),
+//! - lack a required path, query or header parameter, or give one a value
+//!   not of its schema (400),
+//! - lack a required body (400), send one of a media type the operation
+//!   does not take (415) or larger than `max_body`, at most 64 KiB (413),
+//!   or send JSON not of its schema (400).
+//!
+//! Refusals are JSON listing what is wrong, up to 16 violations:
+//! `{"error": {"code": "invalid_request", "message": "...", "operation":
+//! "getUser", "request_id": "...", "route": "api", "status": 400,
+//! "violations": [{"in": "query", "name": "limit", "message": "expected
+//! integer"}]}}`; violations of the body name the JSON pointer of the value.
+//!
+//! Paths are matched below the path of the spec's first server, or of
+//! [`OpenApi::with_base_path`], concrete paths before templated ones.
+//! `$ref`s within the spec are followed. Schemas are checked for `type`,
+//! `nullable`, `enum`, `const`, `required`, `properties`,
+//! `additionalProperties`, `items`, `allOf`, `anyOf`, `oneOf`, `not`,
+//! `pattern` and the bounds on lengths, sizes and values; `format` and other
+//! keywords are not checked.
+//!
+//! Requests passed and refused are counted by `operationId`, operations
+//! without one by `"<METHOD> <path>"`, for `GET /admin/openapi`.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{collections::HashMap, fs};
+
+use bytes::Bytes;
+use http::{Method, StatusCode, header};
+use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+use regex::Regex;
+use serde_json::{Map, Value, json};
+
+use crate::body_route::MAX_BODY;
+use crate::s3::percent_decode;
+
+/// Violations listed in a refusal at most.
+const MAX_VIOLATIONS: usize = 16;
+
+/// Deepest nesting of schemas and `$ref`s followed.
+const MAX_DEPTH: usize = 64;
+
+/// Keys under which something looking like a schema is only an example.
+const EXAMPLE_KEYS: [&str; 5] = ["example", "examples", "default", "const", "enum"];
+
+pub struct OpenApi {
+    /// the spec, for following `$ref`s
+    spec: Value,
+    /// without a trailing `/`
+    base_path: String,
+    operations: Vec<Operation>,
+    /// the compiled `pattern`s of the schemas
+    patterns: HashMap<String, Regex>,
+    max_body: usize,
+    unknown_paths: AtomicU64,
+    unknown_methods: AtomicU64,
+}
+
+struct Operation {
+    id: String,
+    method: Method,
+    path: String,
+    segments: Vec<Segment>,
+    parameters: Vec<Parameter>,
+    body: Option<Body>,
+    passed: AtomicU64,
+    /// by [`Code::index`] of the refusals naming an operation
+    refused: [AtomicU64; 3],
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Path,
+    Query,
+    Header,
+}
+
+impl Location {
+    fn as_str(self) -> &'static str {
+        match self {
+            Location::Path => "path",
+            Location::Query => "query",
+            Location::Header => "header",
+        }
+    }
+}
+
+struct Parameter {
+    name: String,
+    location: Location,
+    required: bool,
+    schema: Option<Value>,
+}
+
+struct Body {
+    required: bool,
+    /// lowercase media ranges and their schemas
+    content: Vec<(String, Option<Value>)>,
+}
+
+/// A request whose path, method and parameters passed, by the operation it
+/// is for.
+pub(crate) struct Checked {
+    operation: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Code {
+    UnknownPath,
+    MethodNotAllowed,
+    InvalidRequest,
+    BodyTooLarge,
+    UnsupportedMediaType,
+}
+
+impl Code {
+    fn as_str(self) -> &'static str {
+        match self {
+            Code::UnknownPath => "unknown_path",
+            Code::MethodNotAllowed => "method_not_allowed",
+            Code::InvalidRequest => "invalid_request",
+            Code::BodyTooLarge => "body_too_large",
+            Code::UnsupportedMediaType => "unsupported_media_type",
+        }
+    }
+
+    fn status(self) -> StatusCode {
+        match self {
+            Code::UnknownPath => StatusCode::NOT_FOUND,
+            Code::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Code::InvalidRequest => StatusCode::BAD_REQUEST,
+            Code::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Code::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        }
+    }
+
+    /// Index into [`Operation::refused`] of the codes of operations.
+    fn index(self) -> Option<usize> {
+        match self {
+            Code::InvalidRequest => Some(0),
+            Code::BodyTooLarge => Some(1),
+            Code::UnsupportedMediaType => Some(2),
+            Code::UnknownPath | Code::MethodNotAllowed => None,
+        }
+    }
+}
+
+const OPERATION_CODES: [Code; 3] = [
+    Code::InvalidRequest,
+    Code::BodyTooLarge,
+    Code::UnsupportedMediaType,
+];
+
+/// Why a request was refused.
+pub(crate) struct Refusal {
+    code: Code,
+    message: String,
+    operation: Option<usize>,
+    violations: Vec<Violation>,
+    /// the methods of the path, for a 405
+    allow: Option<String>,
+}
+
+struct Violation {
+    location: &'static str,
+    /// the parameter, or the JSON pointer into the body
+    name: String,
+    message: String,
+}
+
+impl OpenApi {
+    /// The operations of `spec`, an OpenAPI 3 document.
+    pub fn new(spec: Value) -> Result<Self, String> {
+        if !spec["openapi"]
+            .as_str()
+            .is_some_and(|v| v.starts_with("3."))
+        {
+            return Err("not an OpenAPI 3 spec".to_string());
+        }
+        let paths = spec["paths"]
+            .as_object()
+            .ok_or("OpenAPI spec without paths")?;
+        let mut patterns = HashMap::new();
+        compile_patterns(&spec, &mut patterns)?;
+        check_refs(&spec, &spec)?;
+
+        let mut operations = Vec::new();
+        for (path, item) in paths {
+            let item = follow(&spec, item).ok_or_else(|| format!("path {path}: bad $ref"))?;
+            let shared =
+                parameters(&spec, &item["parameters"]).map_err(|e| format!("path {path}: {e}"))?;
+            for (name, operation) in item.as_object().into_iter().flatten() {
+                let Ok(method) = name.to_ascii_uppercase().parse::<Method>() else {
+                    continue;
+                };
+                if !matches!(
+                    method,
+                    Method::GET
+                        | Method::PUT
+                        | Method::POST
+                        | Method::DELETE
+                        | Method::OPTIONS
+                        | Method::HEAD
+                        | Method::PATCH
+                        | Method::TRACE
+                ) {
+                    continue;
+                }
+                let context = |e: String| format!("{method} {path}: {e}");
+                let mut params = parameters(&spec, &operation["parameters"]).map_err(context)?;
+                // those of the operation override those of the path
+                for param in &shared {
+                    if !params
+                        .iter()
+                        .any(|p| p.name == param.name && p.location == param.location)
+                    {
+                        params.push(Parameter {
+                            name: param.name.clone(),
+                            location: param.location,
+                            required: param.required,
+                            schema: param.schema.clone(),
+                        });
+                    }
+                }
+                let body = request_body(&spec, &operation["requestBody"]).map_err(context)?;
+                operations.push(Operation {
+                    id: operation["operationId"]
+                        .as_str()
+                        .map_or_else(|| format!("{method} {path}"), str::to_string),
+                    method,
+                    path: path.clone(),
+                    segments: segments(path),
+                    parameters: params,
+                    body,
+                    passed: AtomicU64::new(0),
+                    refused: Default::default(),
+                });
+            }
+        }
+
+        let base_path = spec["servers"][0]["url"]
+            .as_str()
+            .map(|url| {
+                // a server URL may be absolute or only a path
+                let path = url.split_once("://").map_or(url, |(_, rest)| {
+                    rest.find('/').map_or("", |slash| &rest[slash..])
+                });
+                path.trim_end_matches('/').to_string()
+            })
+            .unwrap_or_default();
+        Ok(OpenApi {
+            spec,
+            base_path,
+            operations,
+            patterns,
+            max_body: MAX_BODY,
+            unknown_paths: AtomicU64::new(0),
+            unknown_methods: AtomicU64::new(0),
+        })
+    }
+
+    /// The operations of the JSON spec in the file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let spec = fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let spec = serde_json::from_slice(&spec).map_err(|e| format!("{}: {e}", path.display()))?;
+        OpenApi::new(spec).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// Match paths below `base_path` rather than below the path of the
+    /// spec's first server.
+    pub fn with_base_path(mut self, base_path: &str) -> Self {
+        self.base_path = base_path.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Refuse larger bodies, at most 64 KiB, the most the proxy keeps for
+    /// replaying to the upstream.
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body.min(MAX_BODY);
+        self
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    /// The operation of `req` if its path, method and parameters pass.
+    pub(crate) fn check(&self, req: &RequestHeader) -> Result<Checked, Refusal> {
+        let unknown = || Refusal::new(Code::UnknownPath, "no operation on this path", None);
+        let path = req
+            .uri
+            .path()
+            .strip_prefix(self.base_path.as_str())
+            .filter(|p| p.is_empty() || p.starts_with('/'))
+            .ok_or_else(unknown)?;
+        let parts: Vec<&str> = path
+            .trim_matches('/')
+            .split('/')
+            .filter(|p| !p.is_empty())
+            .collect();
+
+        // concrete segments beat templated ones, from the left
+        let specificity = |op: &Operation| -> Vec<bool> {
+            op.segments
+                .iter()
+                .map(|s| matches!(s, Segment::Param(_)))
+                .collect()
+        };
+        let best = self
+            .operations
+            .iter()
+            .filter(|op| matches(&op.segments, &parts))
+            .min_by_key(|op| specificity(op))
+            .ok_or_else(unknown)?;
+        let on_path = || {
+            self.operations
+                .iter()
+                .enumerate()
+                .filter(|(_, op)| op.path == best.path)
+        };
+        let found = on_path()
+            .find(|(_, op)| op.method == req.method)
+            .or_else(|| {
+                // a HEAD is a GET without the body
+                (req.method == Method::HEAD)
+                    .then(|| on_path().find(|(_, op)| op.method == Method::GET))
+                    .flatten()
+            });
+        let Some((index, operation)) = found else {
+            let allow: Vec<&str> = on_path().map(|(_, op)| op.method.as_str()).collect();
+            let mut refusal = Refusal::new(
+                Code::MethodNotAllowed,
+                format!("no {} operation on this path", req.method),
+                None,
+            );
+            refusal.allow = Some(allow.join(", "));
+            return Err(refusal);
+        };
+
+        let query: Vec<(String, String)> = req
+            .uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let decode = |s: &str| percent_decode(&s.replace('+', " "));
+                (decode(name), decode(value))
+            })
+            .collect();
+        let mut violations = Vec::new();
+        for param in &operation.parameters {
+            let value = match param.location {
+                Location::Path => operation
+                    .segments
+                    .iter()
+                    .zip(&parts)
+                    .find(|(s, _)| matches!(s, Segment::Param(name) if *name == param.name))
+                    .map(|(_, part)| percent_decode(part)),
+                Location::Query => query
+                    .iter()
+                    .find(|(name, _)| *name == param.name)
+                    .map(|(_, value)| value.clone()),
+                Location::Header => req
+                    .headers
+                    .get(param.name.as_str())
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string),
+            };
+            let mut problems = Vec::new();
+            match (value, &param.schema) {
+                (None, _) if param.required => {
+                    problems.push((String::new(), "required".to_string()));
+                }
+                (None, _) | (Some(_), None) => {}
+                (Some(value), Some(schema)) => {
+                    let value = self.typed(schema, &value);
+                    self.validate(schema, &value, "", 0, &mut problems);
+                }
+            }
+            violations.extend(problems.into_iter().map(|(_, message)| Violation {
+                location: param.location.as_str(),
+                name: param.name.clone(),
+                message,
+            }));
+        }
+        if !violations.is_empty() {
+            violations.truncate(MAX_VIOLATIONS);
+            let mut refusal = Refusal::new(
+                Code::InvalidRequest,
+                "parameters do not match the API",
+                Some(index),
+            );
+            refusal.violations = violations;
+            return Err(refusal);
+        }
+        Ok(Checked { operation: index })
+    }
+
+    /// Whether the operation of `checked` takes a body to check, and if so
+    /// whether `req` declares one over the limit.
+    pub(crate) fn wants_body(
+        &self,
+        checked: &Checked,
+        req: &RequestHeader,
+    ) -> Result<bool, Refusal> {
+        if self.operations[checked.operation].body.is_none() {
+            return Ok(false);
+        }
+        let len = req
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+        if len.is_some_and(|len| len > self.max_body) {
+            return Err(self.too_large(checked));
+        }
+        Ok(true)
+    }
+
+    /// The refusal of a body over the limit.
+    pub(crate) fn too_large(&self, checked: &Checked) -> Refusal {
+        Refusal::new(
+            Code::BodyTooLarge,
+            format!("request body over {} bytes", self.max_body),
+            Some(checked.operation),
+        )
+    }
+
+    /// Check `body`, of `req`, against the operation of `checked`.
+    pub(crate) fn check_body(
+        &self,
+        checked: &Checked,
+        req: &RequestHeader,
+        body: &[u8],
+    ) -> Result<(), Refusal> {
+        let operation = &self.operations[checked.operation];
+        let Some(spec) = &operation.body else {
+            return Ok(());
+        };
+        let invalid = |name: String, message: String| {
+            let mut refusal = Refusal::new(
+                Code::InvalidRequest,
+                "request body does not match the API",
+                Some(checked.operation),
+            );
+            refusal.violations.push(Violation {
+                location: "body",
+                name,
+                message,
+            });
+            refusal
+        };
+        if body.is_empty() {
+            return match spec.required {
+                true => Err(invalid(String::new(), "required".to_string())),
+                false => Ok(()),
+            };
+        }
+
+        let media = req
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        let Some((range, schema)) = spec.content.iter().find(|(range, _)| {
+            range == "*/*"
+                || *range == media
+                || range
+                    .strip_suffix("/*")
+                    .is_some_and(|kind| media.split('/').next() == Some(kind))
+        }) else {
+            let ranges: Vec<&str> = spec.content.iter().map(|(r, _)| r.as_str()).collect();
+            return Err(Refusal::new(
+                Code::UnsupportedMediaType,
+                format!("request body must be one of {}", ranges.join(", ")),
+                Some(checked.operation),
+            ));
+        };
+        // only JSON is read, other bodies are only held to the size limit
+        let json = media == "application/json" || media.ends_with("+json");
+        let (Some(schema), true) = (schema, json || range.ends_with("json")) else {
+            return Ok(());
+        };
+        let value: Value = serde_json::from_slice(body)
+            .map_err(|e| invalid(String::new(), format!("not JSON: {e}")))?;
+        let mut problems = Vec::new();
+        self.validate(schema, &value, "", 0, &mut problems);
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let mut refusal = invalid(String::new(), String::new());
+        refusal.violations = problems
+            .into_iter()
+            .take(MAX_VIOLATIONS)
+            .map(|(name, message)| Violation {
+                location: "body",
+                name,
+                message,
+            })
+            .collect();
+        Err(refusal)
+    }
+
+    /// The `operationId` of `checked`, or `"<METHOD> <path>"`.
+    pub(crate) fn operation_id(&self, checked: &Checked) -> &str {
+        &self.operations[checked.operation].id
+    }
+
+    pub(crate) fn passed(&self, checked: &Checked) {
+        self.operations[checked.operation]
+            .passed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn refused(&self, refusal: &Refusal) {
+        let counter = match (refusal.operation, refusal.code.index()) {
+            (Some(operation), Some(i)) => &self.operations[operation].refused[i],
+            _ if refusal.code == Code::MethodNotAllowed => &self.unknown_methods,
+            _ => &self.unknown_paths,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> Value {
+        let operations: Map<String, Value> = self
+            .operations
+            .iter()
+            .map(|op| {
+                let refused: Map<String, Value> = OPERATION_CODES
+                    .iter()
+                    .zip(&op.refused)
+                    .map(|(code, n)| (code.as_str().to_string(), json!(n.load(Ordering::Relaxed))))
+                    .collect();
+                let stats = json!({
+                    "method": op.method.as_str(),
+                    "path": op.path,
+                    "passed": op.passed.load(Ordering::Relaxed),
+                    "refused": refused,
+                });
+                (op.id.clone(), stats)
+            })
+            .collect();
+        json!({
+            "base_path": self.base_path,
+            "unknown_path": self.unknown_paths.load(Ordering::Relaxed),
+            "method_not_allowed": self.unknown_methods.load(Ordering::Relaxed),
+            "operations": operations,
+        })
+    }
+
+    /// `raw`, a parameter value, as the JSON value its schema has it, left
+    /// a string where it does not parse.
+    fn typed(&self, schema: &Value, raw: &str) -> Value {
+        let schema = follow(&self.spec, schema).unwrap_or(schema);
+        let string = || Value::String(raw.to_string());
+        match schema["type"].as_str() {
+            Some("integer") => raw.parse::<i64>().map_or_else(|_| string(), Value::from),
+            Some("number") => raw
+                .parse::<f64>()
+                .ok()
+                .and_then(|n| serde_json::Number::from_f64(n).map(Value::Number))
+                .unwrap_or_else(string),
+            Some("boolean") => match raw {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => string(),
+            },
+            // the form style of arrays: a,b,c
+            Some("array") => Value::Array(
+                raw.split(',')
+                    .map(|item| self.typed(&schema["items"], item))
+                    .collect(),
+            ),
+            _ => string(),
+        }
+    }
+
+    /// Add to `problems` where `value`, at `pointer`, is not of `schema`.
+    fn validate(
+        &self,
+        schema: &Value,
+        value: &Value,
+        pointer: &str,
+        depth: usize,
+        problems: &mut Vec<(String, String)>,
+    ) {
+        if problems.len() >= MAX_VIOLATIONS {
+            return;
+        }
+        if depth > MAX_DEPTH {
+            problems.push((pointer.to_string(), "schema nests too deep".to_string()));
+            return;
+        }
+        let Some(schema) = follow(&self.spec, schema).and_then(Value::as_object) else {
+            return;
+        };
+        let mut problem = |message: String| problems.push((pointer.to_string(), message));
+
+        if value.is_null() && schema.get("nullable") == Some(&Value::Bool(true)) {
+            return;
+        }
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::String(t) => vec![t.as_str()],
+                Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !types.is_empty() && !types.iter().any(|t| is_type(value, t)) {
+                problem(format!("expected {}", types.join(" or ")));
+                return;
+            }
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum")
+            && !allowed.contains(value)
+        {
+            problem("not one of the allowed values".to_string());
+        }
+        if let Some(constant) = schema.get("const")
+            && constant != value
+        {
+            problem(format!("must be {constant}"));
+        }
+
+        match value {
+            Value::String(s) => {
+                let len = s.chars().count() as u64;
+                if let Some(min) = schema.get("minLength").and_then(Value::as_u64)
+                    && len < min
+                {
+                    problem(format!("shorter than {min} characters"));
+                }
+                if let Some(max) = schema.get("maxLength").and_then(Value::as_u64)
+                    && len > max
+                {
+                    problem(format!("longer than {max} characters"));
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(Value::as_str)
+                    && let Some(regex) = self.patterns.get(pattern)
+                    && !regex.is_match(s)
+                {
+                    problem(format!("does not match {pattern}"));
+                }
+            }
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                let bound = |name| schema.get(name).and_then(Value::as_f64);
+                // OpenAPI 3.0 flags the bounds exclusive, 3.1 gives the bound
+                let exclusive = |name| schema.get(name) == Some(&Value::Bool(true));
+                if let Some(min) = bound("minimum") {
+                    if exclusive("exclusiveMinimum") && n <= min {
+                        problem(format!("must be over {min}"));
+                    } else if n < min {
+                        problem(format!("must be at least {min}"));
+                    }
+                }
+                if let Some(max) = bound("maximum") {
+                    if exclusive("exclusiveMaximum") && n >= max {
+                        problem(format!("must be under {max}"));
+                    } else if n > max {
+                        problem(format!("must be at most {max}"));
+                    }
+                }
+                if let Some(min) = bound("exclusiveMinimum")
+                    && n <= min
+                {
+                    problem(format!("must be over {min}"));
+                }
+                if let Some(max) = bound("exclusiveMaximum")
+                    && n >= max
+                {
+                    problem(format!("must be under {max}"));
+                }
+            }
+            Value::Array(items) => {
+                let len = items.len() as u64;
+                if let Some(min) = schema.get("minItems").and_then(Value::as_u64)
+                    && len < min
+                {
+                    problem(format!("fewer than {min} items"));
+                }
+                if let Some(max) = schema.get("maxItems").and_then(Value::as_u64)
+                    && len > max
+                {
+                    problem(format!("more than {max} items"));
+                }
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        let at = format!("{pointer}/{i}");
+                        self.validate(item_schema, item, &at, depth + 1, problems);
+                    }
+                }
+            }
+            Value::Object(fields) => {
+                for name in schema
+                    .get("required")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str)
+                {
+                    if !fields.contains_key(name) {
+                        let at = format!("{pointer}/{}", escape_pointer(name));
+                        problems.push((at, "required".to_string()));
+                    }
+                }
+                let properties = schema.get("properties").and_then(Value::as_object);
+                let additional = schema.get("additionalProperties");
+                for (name, field) in fields {
+                    let at = format!("{pointer}/{}", escape_pointer(name));
+                    match properties.and_then(|p| p.get(name)) {
+                        Some(property) => self.validate(property, field, &at, depth + 1, problems),
+                        None => match additional {
+                            Some(Value::Bool(false)) => {
+                                problems.push((at, "not allowed".to_string()));
+                            }
+                            Some(additional @ Value::Object(_)) => {
+                                self.validate(additional, field, &at, depth + 1, problems);
+                            }
+                            _ => {}
+                        },
+                    }
+                }
+            }
+            Value::Null | Value::Bool(_) => {}
+        }
+
+        for part in schema
+            .get("allOf")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            self.validate(part, value, pointer, depth + 1, problems);
+        }
+        let passes = |part: &Value| {
+            let mut found = Vec::new();
+            self.validate(part, value, pointer, depth + 1, &mut found);
+            found.is_empty()
+        };
+        if let Some(Value::Array(parts)) = schema.get("anyOf")
+            && !parts.iter().any(passes)
+        {
+            problems.push((pointer.to_string(), "matches none of anyOf".to_string()));
+        }
+        if let Some(Value::Array(parts)) = schema.get("oneOf") {
+            let matched = parts.iter().filter(|part| passes(part)).count();
+            if matched != 1 {
+                let message = format!("matches {matched} of oneOf, not one");
+                problems.push((pointer.to_string(), message));
+            }
+        }
+        if let Some(not) = schema.get("not")
+            && passes(not)
+        {
+            problems.push((pointer.to_string(), "matches what it must not".to_string()));
+        }
+    }
+}
+
+impl Refusal {
+    fn new(code: Code, message: impl Into<String>, operation: Option<usize>) -> Self {
+        Refusal {
+            code,
+            message: message.into(),
+            operation,
+            violations: Vec::new(),
+            allow: None,
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    /// The `operationId` of the operation refused, `None` if the request is
+    /// for none.
+    pub(crate) fn operation<'a>(&self, api: &'a OpenApi) -> Option<&'a str> {
+        self.operation.map(|i| api.operations[i].id.as_str())
+    }
+
+    /// The refusal as the JSON error of `api` for the request `request_id`
+    /// on `route`.
+    pub(crate) fn response(
+        &self,
+        api: &OpenApi,
+        request_id: &str,
+        route: &str,
+    ) -> Result<(ResponseHeader, Bytes)> {
+        let violations: Vec<Value> = self
+            .violations
+            .iter()
+            .map(|v| json!({ "in": v.location, "name": v.name, "message": v.message }))
+            .collect();
+        let mut body = json!({
+            "error": {
+                "status": self.status().as_u16(),
+                "code": self.code.as_str(),
+                "message": self.message,
+                "operation": self.operation(api),
+                "request_id": request_id,
+                "route": route,
+                "violations": violations,
+            }
+        })
+        .to_string();
+        body.push('\n');
+        let mut header = ResponseHeader::build(self.status(), Some(4))?;
+        header.insert_header(header::CONTENT_TYPE, "application/json")?;
+        header.insert_header(header::CONTENT_LENGTH, body.len())?;
+        header.insert_header(header::CACHE_CONTROL, "no-store")?;
+        if let Some(allow) = &self.allow {
+            header.insert_header(header::ALLOW, allow)?;
+        }
+        Ok((header, Bytes::from(body)))
+    }
+}
+
+fn segments(path: &str) -> Vec<Segment> {
+    path.trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(
+            |s| match s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Literal(s.to_string()),
+            },
+        )
+        .collect()
+}
+
+fn matches(segments: &[Segment], parts: &[&str]) -> bool {
+    segments.len() == parts.len()
+        && segments
+            .iter()
+            .zip(parts)
+            .all(|(segment, part)| match segment {
+                Segment::Literal(literal) => literal == part,
+                Segment::Param(_) => true,
+            })
+}
+
+fn is_type(value: &Value, t: &str) -> bool {
+    match t {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    }
+}
+
+/// `value`, or what its `$ref`s lead to within `spec`; `None` if one leads
+/// nowhere.
+fn follow<'a>(spec: &'a Value, mut value: &'a Value) -> Option<&'a Value> {
+    for _ in 0..MAX_DEPTH {
+        let Some(target) = value.get("$ref").and_then(Value::as_str) else {
+            return Some(value);
+        };
+        value = spec.pointer(target.strip_prefix('#')?)?;
+    }
+    None
+}
+
+/// Every `$ref` in `value` must lead somewhere within `spec`.
+fn check_refs(spec: &Value, value: &Value) -> Result<(), String> {
+    match value {
+        Value::Object(fields) => {
+            if let Some(target) = fields.get("$ref").and_then(Value::as_str)
+                && target
+                    .strip_prefix('#')
+                    .and_then(|pointer| spec.pointer(pointer))
+                    .is_none()
+            {
+                return Err(format!("$ref {target} does not lead into the spec"));
+            }
+            fields
+                .iter()
+                .filter(|(name, _)| !EXAMPLE_KEYS.contains(&name.as_str()))
+                .try_for_each(|(_, v)| check_refs(spec, v))
+        }
+        Value::Array(items) => items.iter().try_for_each(|v| check_refs(spec, v)),
+        _ => Ok(()),
+    }
+}
+
+fn compile_patterns(value: &Value, patterns: &mut HashMap<String, Regex>) -> Result<(), String> {
+    match value {
+        Value::Object(fields) => {
+            if let Some(pattern) = fields.get("pattern").and_then(Value::as_str)
+                && !patterns.contains_key(pattern)
+            {
+                let regex = Regex::new(pattern).map_err(|e| format!("pattern {pattern:?}: {e}"))?;
+                patterns.insert(pattern.to_string(), regex);
+            }
+            fields
+                .iter()
+                .filter(|(name, _)| !EXAMPLE_KEYS.contains(&name.as_str()))
+                .try_for_each(|(_, v)| compile_patterns(v, patterns))
+        }
+        Value::Array(items) => items.iter().try_for_each(|v| compile_patterns(v, patterns)),
+        _ => Ok(()),
+    }
+}
+
+fn parameters(spec: &Value, list: &Value) -> Result<Vec<Parameter>, String> {
+    let mut parameters = Vec::new();
+    for param in list.as_array().into_iter().flatten() {
+        let param = follow(spec, param).ok_or("bad parameter $ref")?;
+        let name = param["name"].as_str().ok_or("parameter without a name")?;
+        let location = match param["in"].as_str() {
+            Some("path") => Location::Path,
+            Some("query") => Location::Query,
+            Some("header") => Location::Header,
+            // cookies are not checked
+            Some("cookie") => continue,
+            _ => return Err(format!("parameter {name} is in no known place")),
+        };
+        parameters.push(Parameter {
+            // header names are case insensitive
+            name: match location {
+                Location::Header => name.to_ascii_lowercase(),
+                _ => name.to_string(),
+            },
+            location,
+            required: location == Location::Path || param["required"] == Value::Bool(true),
+            schema: param.get("schema").cloned(),
+        });
+    }
+    Ok(parameters)
+}
+
+fn request_body(spec: &Value, body: &Value) -> Result<Option<Body>, String> {
+    if body.is_null() {
+        return Ok(None);
+    }
+    let body = follow(spec, body).ok_or("bad requestBody $ref")?;
+    let content = body["content"]
+        .as_object()
+        .ok_or("requestBody without content")?
+        .iter()
+        .map(|(range, media)| (range.to_ascii_lowercase(), media.get("schema").cloned()))
+        .collect();
+    Ok(Some(Body {
+        required: body["required"] == Value::Bool(true),
+        content,
+    }))
+}
+
+/// `name` as a token of a JSON pointer.
+fn escape_pointer(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
@@ -0,0 +1,174 @@
+//! The original destination of connections, and routes forwarding to it.
+//!
+//! In a sidecar the proxy sits between an application and everything it
+//! talks to: iptables redirects the application's outbound connections to
+//! the proxy, which sends each request on to the address it was meant for,
+//! after its filters, limits and logging. A route with
+//! [`crate::route::Route::original_dst_cluster`] set is such an
+//! `original_dst` cluster; its upstream is, by the first that is known:
+//!
+//! - the destination of the PROXY protocol header, on listeners with
+//!   [`crate::listener::ListenerConfig::proxy_protocol`], where another
+//!   proxy or load balancer intercepted the connection
+//! - `SO_ORIGINAL_DST`, the address an iptables `REDIRECT` rewrote
+//! - the local address of connections on a transparent listener, see
+//!   [`crate::transparent`]
+//!
+//! A destination that is the proxy's own listener is none, such a request
+//! would loop. The PROXY protocol header also carries the client's address,
+//! which then stands in for that of the connection everywhere.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+
+use pingora::protocols::l4::socket::SocketAddr as L4Addr;
+use pingora::protocols::l4::stream::Stream as L4Stream;
+use pingora::protocols::{SocketDigest, Stream};
+use pingora::proxy::Session;
+use tokio::io::AsyncReadExt;
+
+/// How long a client has to send its PROXY protocol header.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header, `\r\n` included.
+const V1_MAX: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The original destination of the connection of `session`, `None` when
+/// it was not redirected to the proxy.
+pub fn destination(session: &Session, transparent: bool) -> Option<SocketAddr> {
+    let canonical = |a: &L4Addr| {
+        a.as_inet()
+            .map(|a| SocketAddr::new(a.ip().to_canonical(), a.port()))
+    };
+    let local = session.server_addr().and_then(canonical);
+    let digest = session.digest()?.socket_digest.as_ref()?;
+    digest
+        .original_dst()
+        .and_then(canonical)
+        .filter(|dst| Some(*dst) != local)
+        .or(local.filter(|_| transparent))
+}
+
+/// Read the PROXY protocol header, v1 or v2, that starts `stream`, and take
+/// the addresses in it as the connection's. A header without addresses, the
+/// `LOCAL` command of v2 or `UNKNOWN` of v1, leaves the connection as it is.
+pub async fn accept_header(stream: &mut Stream) -> Result<(), String> {
+    let mut start = [0; 12];
+    stream
+        .read_exact(&mut start[..V1_PREFIX.len()])
+        .await
+        .map_err(|e| e.to_string())?;
+    let addrs = if start.starts_with(V1_PREFIX) {
+        let mut line = start[..V1_PREFIX.len()].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX {
+                return Err("PROXY protocol v1 header too long".to_string());
+            }
+            line.push(stream.read_u8().await.map_err(|e| e.to_string())?);
+        }
+        parse_v1(&line)?
+    } else {
+        stream
+            .read_exact(&mut start[V1_PREFIX.len()..])
+            .await
+            .map_err(|e| e.to_string())?;
+        if start != V2_SIGNATURE {
+            return Err("no PROXY protocol header".to_string());
+        }
+        let mut head = [0; 4];
+        stream
+            .read_exact(&mut head)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut body = vec![0; u16::from_be_bytes([head[2], head[3]]) as usize];
+        stream
+            .read_exact(&mut body)
+            .await
+            .map_err(|e| e.to_string())?;
+        parse_v2(head[0], head[1], &body)?
+    };
+    let Some((client, dst)) = addrs else {
+        return Ok(());
+    };
+    let fd = (**stream)
+        .as_any()
+        .downcast_ref::<L4Stream>()
+        .ok_or("PROXY protocol over TLS is not supported")?
+        .as_raw_fd();
+    let digest = SocketDigest::from_raw_fd(fd);
+    let _ = digest.peer_addr.set(Some(L4Addr::Inet(client)));
+    let _ = digest.original_dst.set(Some(L4Addr::Inet(dst)));
+    (**stream).set_socket_digest(digest);
+    Ok(())
+}
+
+/// `PROXY TCP4 192.0.2.1 198.51.100.7 51234 80\r\n`, the client then the
+/// destination.
+fn parse_v1(line: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    let bad = || "bad PROXY protocol v1 header".to_string();
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| bad())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, dst, sport, dport] => {
+            let addr = |ip: &str, port: &str| -> Result<SocketAddr, String> {
+                let ip: IpAddr = ip.parse().map_err(|_| bad())?;
+                Ok(SocketAddr::new(ip, port.parse().map_err(|_| bad())?))
+            };
+            Ok(Some((addr(src, sport)?, addr(dst, dport)?)))
+        }
+        _ => Err(bad()),
+    }
+}
+
+/// The addresses of a v2 header, from its version and command byte, its
+/// family and protocol byte, and what follows.
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    body: &[u8],
+) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    if version_command >> 4 != 2 {
+        return Err("bad PROXY protocol version".to_string());
+    }
+    match version_command & 0x0f {
+        // LOCAL, health checks of the sender itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err("bad PROXY protocol command".to_string()),
+    }
+    let short = || "short PROXY protocol v2 header".to_string();
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match family {
+        // TCP over IPv4
+        0x11 => {
+            if body.len() < 12 {
+                return Err(short());
+            }
+            let ip = |at: usize| Ipv4Addr::new(body[at], body[at + 1], body[at + 2], body[at + 3]);
+            Ok(Some((
+                SocketAddr::new(ip(0).into(), port(8)),
+                SocketAddr::new(ip(4).into(), port(10)),
+            )))
+        }
+        // TCP over IPv6
+        0x21 => {
+            if body.len() < 36 {
+                return Err(short());
+            }
+            let ip = |at: usize| {
+                let bytes: [u8; 16] = body[at..at + 16].try_into().unwrap();
+                Ipv6Addr::from(bytes)
+            };
+            Ok(Some((
+                SocketAddr::new(ip(0).into(), port(32)),
+                SocketAddr::new(ip(16).into(), port(34)),
+            )))
+        }
+        // UDP, unix sockets and unspecified carry nothing to proxy to
+        _ => Ok(None),
+    }
+}
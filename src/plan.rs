@@ -0,0 +1,293 @@
+//! Previews of a configuration change.
+//!
+//! [`Planner::plan`] compares a candidate config file with the live routes
+//! and clusters without applying anything: the routes a `SIGHUP` reload of
+//! it would add, remove and change, whether the reload would be refused and
+//! why, and for every pool the upstreams joining and leaving its cluster and
+//! the share of its keys that would move to other upstreams.
+//!
+//! The candidate is read as the config file is, see [`crate::config`], and
+//! its routes are built as a reload builds them, next to the routes of the
+//! other sources, see [`crate::rollback`]. Upstreams are resolved as the
+//! discovery of the pools resolves them, and those that do not resolve are
+//! listed apart. Keys move as the cluster's route hashes them: on a ring, in
+//! a Maglev table or by rendezvous scores, laid out and hashed as the route
+//! has it, or on the default ring of a cluster without hashing; rendezvous
+//! moves are counted over a sample of keys.
+//!
+//! Only pools whose upstreams follow the file are compared; the default pool
+//! when its upstreams come from the command line is not. Weights of the
+//! default pool are those its load feedback would give them, see
+//! [`crate::feedback`].
+
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use pingora::lb::{LoadBalancer, selection::RoundRobin};
+use serde_json::{Value, json};
+
+use crate::config::{Config, DEFAULT_POOL, Pool};
+use crate::consistent_hash::{Bucket, Continuum};
+use crate::discovery::BuildRoutes;
+use crate::dns::Resolver;
+use crate::feedback::LoadFeedback;
+use crate::hash_select::{Algorithm, HashSelection};
+use crate::maglev::{self, Maglev};
+use crate::rendezvous::Rendezvous;
+use crate::rollback::{RouteSource, RouterVersions};
+use crate::route::{Route, Router};
+
+/// Keys rendezvous moves are counted over.
+const SAMPLE_KEYS: usize = 1 << 16;
+
+/// Plans candidates against the clusters of the pools of the config file.
+pub struct Planner {
+    resolver: Arc<Resolver>,
+    /// by pool name
+    clusters: Vec<(String, Arc<LoadBalancer<RoundRobin>>)>,
+    build: BuildRoutes,
+    feedback: Option<Arc<LoadFeedback>>,
+}
+
+impl Planner {
+    /// Plans against the live `clusters` of the pools by name, with routes as
+    /// `build` makes them and upstreams resolved by `resolver`.
+    pub fn new(
+        resolver: Arc<Resolver>,
+        clusters: Vec<(String, Arc<LoadBalancer<RoundRobin>>)>,
+        build: BuildRoutes,
+    ) -> Self {
+        Planner {
+            resolver,
+            clusters,
+            build,
+            feedback: None,
+        }
+    }
+
+    /// Weight the upstreams of the default pool by `feedback`.
+    pub fn with_feedback(mut self, feedback: Arc<LoadFeedback>) -> Self {
+        self.feedback = Some(feedback);
+        self
+    }
+
+    /// The plan of going from `live`, the router of `versions`, and `default`
+    /// as the default cluster, to `candidate`, see the module docs.
+    pub async fn plan(
+        &self,
+        live: &Router,
+        versions: &RouterVersions,
+        default: &Arc<LoadBalancer<RoundRobin>>,
+        candidate: &Config,
+    ) -> Value {
+        // what a reload of the file refuses
+        let dropped = self
+            .clusters
+            .iter()
+            .find(|(pool, _)| candidate.pool(pool).is_none())
+            .map(|(pool, _)| format!("no pool {pool}, removing a pool takes a restart"));
+        let (routes, problem) = match (self.build)(candidate) {
+            Ok(routes) => {
+                let router = versions.candidate(RouteSource::Config, routes);
+                (route_diff(live, &router), router.validate().err())
+            }
+            Err(e) => (Value::Null, Some(e)),
+        };
+        let problem = dropped.or(problem);
+
+        let names: BTreeSet<&str> = self
+            .clusters
+            .iter()
+            .map(|(pool, _)| pool.as_str())
+            .chain(candidate.pools.iter().map(|p| p.name.as_str()))
+            .filter(|&pool| pool != DEFAULT_POOL || self.cluster(pool).is_some())
+            .collect();
+        let mut clusters = Vec::new();
+        for name in names {
+            let cluster = self.cluster(name);
+            let old = cluster.map(|c| cluster_buckets(c)).unwrap_or_default();
+            let (mut new, unresolved) = match candidate.pool(name) {
+                Some(pool) => self.resolve(pool).await,
+                None => (Vec::new(), Vec::new()),
+            };
+            if let Some(feedback) = self.feedback.as_ref().filter(|_| name == DEFAULT_POOL) {
+                for bucket in &mut new {
+                    let addr = bucket.node.into();
+                    let weight = feedback.weight(&addr, bucket.weight as usize);
+                    bucket.weight = u32::try_from(weight).unwrap_or(u32::MAX);
+                }
+            }
+            let hashing = cluster.and_then(|c| hashing(live, default, c));
+            clusters.extend(cluster_diff(name, &old, &new, &unresolved, hashing));
+        }
+
+        json!({
+            "valid": problem.is_none(),
+            "problem": problem,
+            "routes": routes,
+            "clusters": clusters,
+        })
+    }
+
+    fn cluster(&self, pool: &str) -> Option<&Arc<LoadBalancer<RoundRobin>>> {
+        self.clusters
+            .iter()
+            .find(|(name, _)| name == pool)
+            .map(|(_, cluster)| cluster)
+    }
+
+    /// The buckets of the upstreams of `pool`, and the upstreams that did not
+    /// resolve.
+    async fn resolve(&self, pool: &Pool) -> (Vec<Bucket>, Vec<String>) {
+        let mut buckets: Vec<Bucket> = Vec::new();
+        let mut unresolved = Vec::new();
+        for upstream in &pool.upstreams {
+            match self.resolver.lookup_ip(&upstream.host).await {
+                Ok(addrs) => {
+                    let weight = u32::try_from(upstream.weight).unwrap_or(u32::MAX).max(1);
+                    for ip in addrs.iter() {
+                        let node = SocketAddr::new(*ip, upstream.port);
+                        if !buckets.iter().any(|b| b.node == node) {
+                            buckets.push(Bucket::new(node, weight));
+                        }
+                    }
+                }
+                Err(_) => unresolved.push(format!("{}:{}", upstream.host, upstream.port)),
+            }
+        }
+        (buckets, unresolved)
+    }
+}
+
+fn cluster_buckets(upstreams: &LoadBalancer<RoundRobin>) -> Vec<Bucket> {
+    upstreams
+        .backends()
+        .get_backend()
+        .iter()
+        .filter_map(Bucket::from_backend)
+        .collect()
+}
+
+/// The hashing of the routes of `live` on `cluster`, `default` being the
+/// cluster of routes without one of their own.
+fn hashing<'a>(
+    live: &'a Router,
+    default: &Arc<LoadBalancer<RoundRobin>>,
+    cluster: &Arc<LoadBalancer<RoundRobin>>,
+) -> Option<&'a HashSelection> {
+    live.routes()
+        .iter()
+        .filter(|r| Arc::ptr_eq(r.upstreams.as_ref().unwrap_or(default), cluster))
+        .find_map(|r| r.hash_selection.as_deref())
+}
+
+/// Share of the keys, from 0 to 1, that `hashing` maps to another node on
+/// `new` than on `old`; `None` when the nodes cannot be laid out.
+fn moved(old: &[Bucket], new: &[Bucket], hashing: Option<&HashSelection>) -> Option<f64> {
+    let hasher = hashing.map(|h| h.hasher()).unwrap_or_default();
+    match hashing.map(|h| h.algorithm()).unwrap_or_default() {
+        Algorithm::Ring => {
+            let layout = hashing.map(|h| h.layout()).unwrap_or_default();
+            let ring = |buckets| Continuum::with_layout(buckets, layout, hasher);
+            Some(ring(old).moved(&ring(new)))
+        }
+        Algorithm::Maglev => {
+            let table = |buckets| Maglev::with_hasher(buckets, maglev::DEFAULT_TABLE_SIZE, hasher);
+            Some(table(old).ok()?.moved(&table(new).ok()?))
+        }
+        Algorithm::Rendezvous => {
+            let (old, new) = (
+                Rendezvous::with_hasher(old, hasher),
+                Rendezvous::with_hasher(new, hasher),
+            );
+            let moved = (0..SAMPLE_KEYS)
+                .map(|i| i.to_string())
+                .filter(|key| old.node(key.as_bytes()) != new.node(key.as_bytes()))
+                .count();
+            Some(moved as f64 / SAMPLE_KEYS as f64)
+        }
+    }
+}
+
+/// The churn of a cluster going from `old` to `new`, `None` if unchanged.
+fn cluster_diff(
+    name: &str,
+    old: &[Bucket],
+    new: &[Bucket],
+    unresolved: &[String],
+    hashing: Option<&HashSelection>,
+) -> Option<Value> {
+    let old_set: BTreeSet<_> = old.iter().map(|b| (b.node, b.weight)).collect();
+    let new_set: BTreeSet<_> = new.iter().map(|b| (b.node, b.weight)).collect();
+    if old_set == new_set && unresolved.is_empty() {
+        return None;
+    }
+    let old_addrs: BTreeSet<_> = old.iter().map(|b| b.node).collect();
+    let new_addrs: BTreeSet<_> = new.iter().map(|b| b.node).collect();
+    let list = |addrs: Vec<&SocketAddr>| addrs.iter().map(ToString::to_string).collect::<Vec<_>>();
+    let reweighted: Vec<String> = new
+        .iter()
+        .filter(|b| old.iter().any(|o| o.node == b.node && o.weight != b.weight))
+        .map(|b| b.node.to_string())
+        .collect();
+    Some(json!({
+        "cluster": name,
+        "added": list(new_addrs.difference(&old_addrs).collect()),
+        "removed": list(old_addrs.difference(&new_addrs).collect()),
+        "reweighted": reweighted,
+        "unresolved": unresolved,
+        "algorithm": hashing.map(|h| h.algorithm()).unwrap_or_default().as_str(),
+        "layout": hashing.map(|h| h.layout()).unwrap_or_default().as_str(),
+        "hash_function": hashing.map(|h| h.hasher()).unwrap_or_default().as_str(),
+        "moved": moved(old, new, hashing),
+    }))
+}
+
+/// The routes added to, removed from and changed in `old` by `new`.
+fn route_diff(old: &Router, new: &Router) -> Value {
+    let names = |router: &Router| -> BTreeSet<String> {
+        router.routes().iter().map(|r| r.name.clone()).collect()
+    };
+    let (old_names, new_names) = (names(old), names(new));
+    let changed: Vec<Value> = new
+        .routes()
+        .iter()
+        .filter_map(|new| {
+            let changes = route_changes(&*old.route(&new.name)?, new);
+            (!changes.is_empty()).then(|| json!({ "name": new.name, "changes": changes }))
+        })
+        .collect();
+    json!({
+        "added": new_names.difference(&old_names).collect::<Vec<_>>(),
+        "removed": old_names.difference(&new_names).collect::<Vec<_>>(),
+        "changed": changed,
+    })
+}
+
+fn route_changes(old: &Route, new: &Route) -> Vec<&'static str> {
+    let mut changes = Vec::new();
+    if old.host != new.host {
+        changes.push("host");
+    }
+    if old.path_prefix != new.path_prefix {
+        changes.push("path_prefix");
+    }
+    if old.path_pattern != new.path_pattern {
+        changes.push("path_pattern");
+    }
+    let same_cluster = match (&old.upstreams, &new.upstreams) {
+        (Some(old), Some(new)) => Arc::ptr_eq(old, new),
+        (old, new) => old.is_none() && new.is_none(),
+    };
+    if !same_cluster {
+        changes.push("cluster");
+    }
+    if old.maintenance != new.maintenance {
+        changes.push("maintenance");
+    }
+    if old.redirect != new.redirect {
+        changes.push("redirect");
+    }
+    changes
+}
@@ -0,0 +1,2265 @@
+//! The load-balancing HTTP proxy.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use http::{Method, StatusCode, header};
+use log::{debug, error, info, warn};
+use pingora::connectors::http::Connector;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::lb::{Backend, LoadBalancer, selection::RoundRobin};
+use pingora::protocols::Digest;
+use pingora::proxy::{FailToProxy, ProxyHttp, Session};
+use pingora::upstreams::peer::HttpPeer;
+use pingora::{Error, ErrorSource, ErrorType, Result};
+
+use crate::anomaly::{AnomalyScorer, FINGERPRINT_HEADER, SCORE_HEADER};
+use crate::balancing::{Balancer, Balancing, Latencies};
+use crate::billing::{Account, RequestUsage, UsageMeter};
+use crate::body_route::{self, BodyRouting};
+use crate::cache::{CacheStatus, Lookup, MemoryCache, Revalidation};
+use crate::cgi::CgiGateway;
+use crate::circuit::CircuitBreakers;
+use crate::config::UpstreamPeer;
+use crate::connect_race::ConnectRace;
+use crate::connections::Connections;
+use crate::consistent_hash::PoolHint;
+use crate::content_sniff::{self, Checked, ContentTypeGuard};
+use crate::ctx::{Mark, ProxyCtx};
+use crate::debug_headers::{self, DiagnosticHeaders};
+use crate::diagnostics::Runtimes;
+use crate::discovery::UpstreamName;
+use crate::drain::DrainRegistry;
+use crate::early_data::{self, EarlyData, REPLAY_RISK, REPLAY_RISK_HEADER};
+use crate::egress::{EgressPolicy, Identity};
+use crate::expect::{self, ExpectContinue};
+use crate::family;
+use crate::feedback::LoadFeedback;
+use crate::fingerprint::{JA3_HEADER, JA4_HEADER, TlsFingerprint};
+use crate::flags::{self, Evaluation, FeatureFlags};
+use crate::gateway::{self, AuthContext, Cors, Gateway};
+use crate::geo::GeoRates;
+use crate::graphql::GraphQl;
+use crate::h2_fallback::{self, H2Fallback};
+use crate::har::{Exchange, HarRecorder};
+use crate::hash_select::HashSelection;
+use crate::http10::Http10Compat;
+use crate::idempotency::{Begin, Idempotency, REPLAYED_HEADER};
+use crate::image::{ImageOptimizer, Transform};
+use crate::in_flight::InFlight;
+use crate::informational::Informational;
+use crate::labels::PathStats;
+use crate::listener::ListenerConfig;
+use crate::no_upstream::{NO_UPSTREAM, NoUpstream, NoUpstreamCounts, Outcome};
+use crate::openapi::OpenApi;
+use crate::original_dst;
+use crate::quarantine::{self, Quarantine};
+use crate::readiness::Readiness;
+use crate::region::{self, RegionFailover};
+use crate::replica::FanOut;
+use crate::rollback::RouterVersions;
+use crate::route::{Route, SharedRouter};
+use crate::scan::{ContentScanner, Verdict};
+use crate::signing::ResponseSigner;
+use crate::stalls::WriteStalls;
+use crate::sticky::{DrainPolicy, StickySessions};
+use crate::stream::{self, StreamConfig};
+use crate::strict_host::HostCheck;
+use crate::subrequest;
+use crate::template::{self, Format, Page, Templates};
+use crate::upstream_tcp::{self, UpstreamTcp};
+use crate::xml::XmlGuard;
+
+/// Where a request would be sent, before it is counted anywhere.
+enum Pick {
+    Upstream(Backend),
+    /// An upstream of the route's fallback cluster, none of its own being
+    /// usable.
+    Fallback(Backend),
+    NoUpstream,
+    /// Every usable upstream is at its in-flight cap.
+    AtCap,
+}
+
+pub struct LB {
+    upstreams: Arc<LoadBalancer<RoundRobin>>,
+    listener: ListenerConfig,
+    cache: Option<Arc<MemoryCache>>,
+    router: Arc<SharedRouter>,
+    templates: Arc<Templates>,
+    drain: Arc<DrainRegistry>,
+    h2_fallback: Arc<H2Fallback>,
+    stalls: Arc<WriteStalls>,
+    paths: Arc<PathStats>,
+    connections: Arc<Connections>,
+    in_flight: Arc<InFlight>,
+    latencies: Arc<Latencies>,
+    no_upstream: Arc<NoUpstreamCounts>,
+    feedback: Option<Arc<LoadFeedback>>,
+    versions: Option<Arc<RouterVersions>>,
+    har: Option<Arc<HarRecorder>>,
+    anomaly: Option<Arc<AnomalyScorer>>,
+    geo: Option<Arc<GeoRates>>,
+    flags: Option<Arc<FeatureFlags>>,
+    usage: Option<Arc<UsageMeter>>,
+    egress: Option<Arc<EgressPolicy>>,
+    region: Option<Arc<RegionFailover>>,
+    circuits: Option<Arc<CircuitBreakers>>,
+    upstream_tcp: UpstreamTcp,
+    balancer: Arc<Balancer>,
+    connect_race: Option<Arc<ConnectRace>>,
+    readiness: Option<Arc<Readiness>>,
+    runtimes: Arc<Runtimes>,
+    /// for requests the proxy makes on its own, see [`subrequest`]
+    connector: Connector,
+    /// how the default upstreams are spoken to
+    peer: UpstreamPeer,
+}
+
+impl LB {
+    pub fn new(upstreams: Arc<LoadBalancer<RoundRobin>>, listener: ListenerConfig) -> Self {
+        LB {
+            upstreams,
+            listener,
+            cache: None,
+            router: Arc::default(),
+            templates: Arc::default(),
+            drain: Arc::default(),
+            h2_fallback: Arc::default(),
+            stalls: Arc::default(),
+            paths: Arc::default(),
+            connections: Arc::default(),
+            in_flight: Arc::default(),
+            latencies: Arc::default(),
+            no_upstream: Arc::default(),
+            feedback: None,
+            versions: None,
+            har: None,
+            anomaly: None,
+            geo: None,
+            flags: None,
+            usage: None,
+            egress: None,
+            region: None,
+            circuits: None,
+            upstream_tcp: UpstreamTcp::default(),
+            balancer: Arc::new(Balancer::new(Balancing::RoundRobin)),
+            connect_race: None,
+            readiness: None,
+            runtimes: Arc::default(),
+            connector: Connector::new(None),
+            peer: UpstreamPeer::tls("one.one.one.one"),
+        }
+    }
+
+    pub fn with_router(mut self, router: Arc<SharedRouter>) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// Report response statuses to `versions`, which rolls back reloads that
+    /// break requests; it should version the router of [`Self::with_router`].
+    pub fn with_router_versions(mut self, versions: Arc<RouterVersions>) -> Self {
+        self.versions = Some(versions);
+        self
+    }
+
+    /// Use `templates` for the error, maintenance and redirect pages.
+    pub fn with_templates(mut self, templates: Arc<Templates>) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    /// Send no new requests to the upstreams draining in `drain`.
+    pub fn with_drain(mut self, drain: Arc<DrainRegistry>) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    /// Track h2 failures and HTTP/1.1 fallbacks of upstreams in `fallback`.
+    pub fn with_h2_fallback(mut self, fallback: Arc<H2Fallback>) -> Self {
+        self.h2_fallback = fallback;
+        self
+    }
+
+    /// Record the write stalls of requests in `stalls`.
+    pub fn with_write_stalls(mut self, stalls: Arc<WriteStalls>) -> Self {
+        self.stalls = stalls;
+        self
+    }
+
+    /// Keep the requests and routes of the connections in `connections` up
+    /// to date.
+    pub fn with_connections(mut self, connections: Arc<Connections>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Count requests per upstream in `in_flight`, and pass over upstreams
+    /// at its cap.
+    pub fn with_in_flight(mut self, in_flight: Arc<InFlight>) -> Self {
+        self.in_flight = in_flight;
+        self
+    }
+
+    /// Keep the latency estimates of the upstreams in `latencies`, for
+    /// [`Balancing::PeakEwma`].
+    pub fn with_latencies(mut self, latencies: Arc<Latencies>) -> Self {
+        self.latencies = latencies;
+        self
+    }
+
+    /// Count requests by route and path in `paths`.
+    pub fn with_path_stats(mut self, paths: Arc<PathStats>) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    /// Count requests finding no usable upstream in `counts`.
+    pub fn with_no_upstream_counts(mut self, counts: Arc<NoUpstreamCounts>) -> Self {
+        self.no_upstream = counts;
+        self
+    }
+
+    /// Report the load of upstream responses to `feedback`.
+    pub fn with_feedback(mut self, feedback: Arc<LoadFeedback>) -> Self {
+        self.feedback = Some(feedback);
+        self
+    }
+
+    /// Speak to the default upstreams with `peer`'s TLS, SNI and `Host`,
+    /// rather than TLS to `one.one.one.one`.
+    pub fn with_upstream_peer(mut self, peer: UpstreamPeer) -> Self {
+        self.peer = peer;
+        self
+    }
+
+    /// Fingerprint and score every request with `scorer`.
+    pub fn with_anomaly(mut self, scorer: Arc<AnomalyScorer>) -> Self {
+        self.anomaly = Some(scorer);
+        self
+    }
+
+    /// Scale the rate limits of clients by their country or ASN with `rates`.
+    pub fn with_geo_rates(mut self, rates: Arc<GeoRates>) -> Self {
+        self.geo = Some(rates);
+        self
+    }
+
+    /// Evaluate `flags` for every request, tell the upstream the outcomes
+    /// and reroute by them.
+    pub fn with_flags(mut self, flags: Arc<FeatureFlags>) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Account the usage of every request in `meter`.
+    pub fn with_usage(mut self, meter: Arc<UsageMeter>) -> Self {
+        self.usage = Some(meter);
+        self
+    }
+
+    /// Hold the requests of `original_dst` routes to `policy`.
+    pub fn with_egress(mut self, policy: Arc<EgressPolicy>) -> Self {
+        self.egress = Some(policy);
+        self
+    }
+
+    /// Send to the secondary region's clusters while `failover` says so.
+    pub fn with_region_failover(mut self, failover: Arc<RegionFailover>) -> Self {
+        self.region = Some(failover);
+        self
+    }
+
+    /// Eject upstreams that keep failing, see [`crate::circuit`].
+    pub fn with_circuit_breakers(mut self, circuits: Arc<CircuitBreakers>) -> Self {
+        self.circuits = Some(circuits);
+        self
+    }
+
+    /// TCP settings of the connections to the default upstreams, and of
+    /// routes without their own, see [`crate::upstream_tcp`].
+    pub fn with_upstream_tcp(mut self, tcp: UpstreamTcp) -> Self {
+        self.upstream_tcp = tcp;
+        self
+    }
+
+    /// How the default upstreams, and those of routes without a balancing
+    /// of their own, are picked.
+    pub fn with_balancing(mut self, balancing: Balancing) -> Self {
+        self.balancer = Arc::new(Balancer::new(balancing));
+        self
+    }
+
+    /// Race new connections between the addresses of an upstream, see
+    /// [`crate::connect_race`].
+    pub fn with_connect_race(mut self, race: Arc<ConnectRace>) -> Self {
+        self.connect_race = Some(race);
+        self
+    }
+
+    /// Count the upstream connections opened for `readiness`.
+    pub fn with_readiness(mut self, readiness: Arc<Readiness>) -> Self {
+        self.readiness = Some(readiness);
+        self
+    }
+
+    /// Make the proxy's runtime known to `runtimes`, for diagnostics.
+    pub fn with_runtimes(mut self, runtimes: Arc<Runtimes>) -> Self {
+        self.runtimes = runtimes;
+        self
+    }
+
+    /// Archive sampled requests with `har`.
+    pub fn with_har(mut self, har: Arc<HarRecorder>) -> Self {
+        self.har = Some(har);
+        self
+    }
+
+    /// Serve and fill responses from `cache`, which may be shared with other
+    /// listeners.
+    pub fn with_cache(mut self, cache: Arc<MemoryCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    fn http10_compat(&self, session: &Session) -> Option<&Http10Compat> {
+        self.listener
+            .http10
+            .as_ref()
+            .filter(|_| Http10Compat::applies_to(session.req_header()))
+    }
+
+    /// The local `Expect: 100-continue` policy if this request is subject to it.
+    fn expect_continue(&self, session: &Session) -> Option<ExpectContinue> {
+        let policy = self.listener.expect_continue;
+        (policy.is_local() && expect::expects_continue(session.req_header())).then_some(policy)
+    }
+
+    /// The route's own cluster if it has one, the default otherwise; their
+    /// secondary region's while the region is failed over.
+    fn cluster<'a>(&'a self, route: Option<&'a Route>) -> &'a LoadBalancer<RoundRobin> {
+        region::cluster(self.region.as_deref(), route, &self.upstreams)
+    }
+
+    /// TCP settings of the connections to the cluster of `route`, the
+    /// proxy's own unless the route has its own.
+    fn upstream_tcp<'a>(&'a self, route: Option<&'a Route>) -> &'a UpstreamTcp {
+        route
+            .and_then(|r| r.upstream_tcp.as_ref())
+            .unwrap_or(&self.upstream_tcp)
+    }
+
+    fn balancer<'a>(&'a self, route: Option<&'a Route>) -> &'a Balancer {
+        route
+            .and_then(|r| r.balancing.as_deref())
+            .unwrap_or(&self.balancer)
+    }
+
+    /// Name of the cluster `cluster` picks, as the admin API has it.
+    fn cluster_name(route: Option<&Route>) -> &str {
+        route
+            .filter(|r| r.upstreams.is_some())
+            .map_or("default", |r| &r.name)
+    }
+
+    /// Who the request is from, for client subsets: the consumer when known,
+    /// the client address otherwise.
+    fn client_key(session: &Session, ctx: &ProxyCtx) -> String {
+        match ctx.consumer() {
+            Some(consumer) => consumer.to_string(),
+            None => family::client_ip(session)
+                .map(|ip| ip.to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Pick from the route's cluster, passing over draining upstreams. With
+    /// client subsets only the client's subset is used while any of it is up.
+    /// Without any usable upstream the route's [`NoUpstream`] policy applies:
+    /// its fallback cluster is picked from, or the request fails with
+    /// [`NO_UPSTREAM`] for `fail_to_proxy` to answer.
+    fn select_upstream(&self, route: Option<&Route>, client: &str) -> Result<Backend> {
+        let cluster = Self::cluster_name(route);
+        let upstream = match self.pick_upstream(route, client) {
+            Pick::Upstream(upstream) => {
+                info!("upstream peer is: {:?}", upstream);
+                upstream
+            }
+            Pick::Fallback(upstream) => {
+                warn!("no usable upstream in cluster {cluster}, sending to its fallback");
+                self.no_upstream.record(cluster, Outcome::Fallback);
+                upstream
+            }
+            Pick::NoUpstream => {
+                warn!("no usable upstream in cluster {cluster}");
+                return Error::e_explain(NO_UPSTREAM, "no usable upstream");
+            }
+            Pick::AtCap => {
+                self.in_flight.refused();
+                warn!("every upstream of cluster {cluster} is at its in-flight cap");
+                return Error::e_explain(
+                    ErrorType::HTTPStatus(503),
+                    "every upstream is at its in-flight cap",
+                );
+            }
+        };
+        self.sending(&upstream);
+        Ok(upstream)
+    }
+
+    /// The upstream [`Self::select_upstream`] would send to, without
+    /// counting the request against it.
+    fn pick_upstream(&self, route: Option<&Route>, client: &str) -> Pick {
+        let upstreams = self.cluster(route);
+        let usable = |backend: &Backend, healthy: bool| self.usable(backend, healthy);
+        let cap = self.in_flight.cap_of(route);
+        let with_room = |backend: &Backend, healthy: bool| {
+            usable(backend, healthy) && self.in_flight.has_room(&backend.addr, cap)
+        };
+        let subset = route
+            .and_then(|r| r.subsets.as_ref())
+            .map(|s| s.get(upstreams.backends(), client));
+        let balancer = self.balancer(route);
+        let in_subset = subset.and_then(|subset| {
+            balancer.select(
+                upstreams,
+                &self.in_flight,
+                &self.latencies,
+                |backend, healthy| subset.contains(&backend.addr) && with_room(backend, healthy),
+            )
+        });
+        let upstream = in_subset
+            .or_else(|| balancer.select(upstreams, &self.in_flight, &self.latencies, with_room));
+        if let Some(upstream) = upstream {
+            return Pick::Upstream(upstream);
+        }
+        if upstreams.select_with(b"", 256, usable).is_some() {
+            return Pick::AtCap;
+        }
+        let fallback = route.and_then(|r| match &r.no_upstream {
+            NoUpstream::Fallback(fallback) => {
+                balancer.select(fallback, &self.in_flight, &self.latencies, with_room)
+            }
+            _ => None,
+        });
+        fallback.map_or(Pick::NoUpstream, Pick::Fallback)
+    }
+
+    /// Whether `backend` may get requests, leaving its in-flight cap aside.
+    fn usable(&self, backend: &Backend, healthy: bool) -> bool {
+        healthy
+            && !self.drain.is_draining(&backend.addr)
+            && self
+                .circuits
+                .as_ref()
+                .is_none_or(|c| c.allows(&backend.addr))
+    }
+
+    /// Pick the upstream the key of `req` maps to, with its pool; `None`
+    /// without a key or a usable upstream for it.
+    fn select_hashed(
+        &self,
+        req: &RequestHeader,
+        route: &Route,
+        hashing: &HashSelection,
+    ) -> Option<(Backend, PoolHint)> {
+        let key = hashing.key(req)?;
+        let cap = self.in_flight.cap_of(Some(route));
+        let picked = hashing.select(self.cluster(Some(route)), &key, |backend, healthy| {
+            self.usable(backend, healthy) && self.in_flight.has_room(&backend.addr, cap)
+        });
+        if let Some((upstream, _)) = &picked {
+            info!("upstream peer is: {:?}", upstream);
+            self.sending(upstream);
+        }
+        picked
+    }
+
+    /// The other usable addresses of the upstream `backend` is one of.
+    fn siblings(&self, route: Option<&Route>, backend: &Backend) -> Vec<Backend> {
+        let Some(name) = backend.ext.get::<UpstreamName>() else {
+            return Vec::new();
+        };
+        let backends = self.cluster(route).backends();
+        let cap = self.in_flight.cap_of(route);
+        backends
+            .get_backend()
+            .iter()
+            .filter(|b| {
+                b.addr != backend.addr
+                    && b.ext.get::<UpstreamName>() == Some(name)
+                    && backends.ready(b)
+                    && !self.drain.is_draining(&b.addr)
+                    && self.in_flight.has_room(&b.addr, cap)
+                    && self.circuits.as_ref().is_none_or(|c| c.allows(&b.addr))
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn sending(&self, upstream: &Backend) {
+        if let Some(circuits) = &self.circuits {
+            circuits.sending(&upstream.addr);
+        }
+    }
+
+    /// Pick the upstream the session is pinned to, with the `Set-Cookie` to
+    /// send if the session is to be pinned anew.
+    fn select_sticky(
+        &self,
+        req: &RequestHeader,
+        route: &Route,
+        sticky: &StickySessions,
+        client: &str,
+    ) -> Result<(Backend, Option<String>)> {
+        let backends = self.cluster(Some(route)).backends();
+        let pinned = sticky.pin(req).and_then(|pin| {
+            backends
+                .get_backend()
+                .iter()
+                .find(|b| pin.is_for(b))
+                .filter(|b| backends.ready(b))
+                .cloned()
+        });
+        Ok(match pinned {
+            // served elsewhere for now, without moving the pin
+            Some(upstream)
+                if !self
+                    .in_flight
+                    .has_room(&upstream.addr, self.in_flight.cap_of(Some(route))) =>
+            {
+                (self.select_upstream(Some(route), client)?, None)
+            }
+            Some(upstream) if !self.drain.is_draining(&upstream.addr) => (upstream, None),
+            Some(upstream) => match sticky.on_drain {
+                DrainPolicy::HonorUntilExpiry => (upstream, None),
+                // the request is not sent to `next`, only the session is,
+                // once there is an upstream of the route's own to take it
+                DrainPolicy::Repin => match self.pick_upstream(Some(route), client) {
+                    Pick::Upstream(next) => (upstream, Some(sticky.set_cookie(&next))),
+                    _ => (upstream, None),
+                },
+            },
+            None => {
+                let upstream = self.select_upstream(Some(route), client)?;
+                let cookie = sticky.set_cookie(&upstream);
+                (upstream, Some(cookie))
+            }
+        })
+    }
+
+    /// How the cluster of `route` is spoken to.
+    fn cluster_peer<'a>(&'a self, route: Option<&'a Route>) -> &'a UpstreamPeer {
+        route.and_then(|r| r.peer.as_deref()).unwrap_or(&self.peer)
+    }
+
+    fn peer(&self, route: Option<&Route>, upstream: Backend) -> Box<HttpPeer> {
+        let peer = self.cluster_peer(route);
+        Box::new(HttpPeer::new(upstream, peer.tls, peer.sni.clone()))
+    }
+
+    fn set_upstream_host(&self, route: Option<&Route>, upstream_request: &mut RequestHeader) {
+        if let Some(host) = &self.cluster_peer(route).host {
+            upstream_request.insert_header("Host", host).unwrap();
+        }
+    }
+
+    /// Fetch the original image and answer with its transformed version.
+    async fn optimize_image(
+        &self,
+        session: &mut Session,
+        route: &Route,
+        image: &ImageOptimizer,
+        transform: Transform,
+        client: &str,
+    ) -> Result<bool> {
+        let key = ImageOptimizer::cache_key(session.req_header(), &transform);
+        if let Some((header, body)) = image.cached(&key) {
+            self.respond(session, header, body).await?;
+            return Ok(true);
+        }
+
+        let mut req = session.req_header().clone();
+        self.set_upstream_host(Some(route), &mut req);
+        for name in [
+            http::header::RANGE,
+            http::header::IF_RANGE,
+            http::header::IF_NONE_MATCH,
+            http::header::IF_MODIFIED_SINCE,
+            http::header::ACCEPT_ENCODING,
+            http::header::EXPECT,
+        ] {
+            req.remove_header(&name);
+        }
+        let max_input = image.options().max_input_bytes;
+        // over budget, the original is proxied untransformed
+        let _lease = match &route.budget {
+            Some(budget) => match budget
+                .heavy("image")
+                .then(|| budget.buffer("image", max_input))
+            {
+                Some(Some(lease)) => Some(lease),
+                _ => return Ok(false),
+            },
+            None => None,
+        };
+        let peer = self.peer(Some(route), self.select_upstream(Some(route), client)?);
+        let Some(original) = subrequest::fetch(&self.connector, &peer, req, max_input).await?
+        else {
+            // too large to transform, let the regular proxy path stream it
+            return Ok(false);
+        };
+        let (header, body) = image.apply(key, transform, original).await;
+        self.respond(session, header, body).await?;
+        Ok(true)
+    }
+
+    /// Send the request to all replicas of its URI that are up and answer with
+    /// the first successful response, or the last response if none was.
+    async fn fan_out(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        route: &Route,
+        fan_out: &FanOut,
+    ) -> Result<bool> {
+        let key = session.req_header().uri.to_string();
+        let upstreams = self.cluster(Some(route));
+        let replicas: Vec<_> = fan_out
+            .replicas(upstreams, key.as_bytes())
+            .into_iter()
+            .filter(|(b, _)| upstreams.backends().ready(b) && !self.drain.is_draining(&b.addr))
+            .collect();
+        if replicas.is_empty() {
+            return Ok(false);
+        }
+
+        let mut req = session.req_header().clone();
+        self.set_upstream_host(Some(route), &mut req);
+        req.remove_header(&header::EXPECT);
+        let tcp = self.upstream_tcp(Some(route));
+        let mut pending: FuturesUnordered<_> = replicas
+            .into_iter()
+            .map(|(upstream, pool)| {
+                let req = req.clone();
+                async move {
+                    let mut peer = self.peer(Some(route), upstream.clone());
+                    tcp.pool(&mut peer, pool);
+                    let fetched =
+                        subrequest::fetch(&self.connector, &peer, req, fan_out.max_body()).await;
+                    (upstream, fetched)
+                }
+            })
+            .collect();
+
+        let mut last = None;
+        while let Some((upstream, fetched)) = pending.next().await {
+            match fetched {
+                Ok(Some(fetched)) if fetched.header.status.is_success() => {
+                    last = Some((upstream, fetched));
+                    break;
+                }
+                Ok(Some(fetched)) => last = Some((upstream, fetched)),
+                // too large to buffer, left to the regular proxy path
+                Ok(None) => {}
+                Err(e) => warn!("replica {upstream:?} of {key} failed: {e}"),
+            }
+        }
+        drop(pending);
+        let Some((upstream, fetched)) = last else {
+            return Ok(false);
+        };
+        ctx.set_upstream(upstream);
+        let mut header = fetched.header;
+        header.remove_header(&header::TRANSFER_ENCODING);
+        header.insert_header(header::CONTENT_LENGTH, fetched.body.len())?;
+        self.respond(session, header, fetched.body).await?;
+        Ok(true)
+    }
+
+    /// Answer the `100 Continue` the client may be waiting for, before the
+    /// proxy reads the body itself rather than the upstream.
+    async fn send_continue(&self, session: &mut Session) -> Result<()> {
+        if expect::expects_continue(session.req_header()) {
+            if self.listener.expect_continue != ExpectContinue::RespondLocally {
+                session.write_continue_response().await?;
+            }
+            session.req_header_mut().remove_header(&header::EXPECT);
+        }
+        Ok(())
+    }
+
+    /// Read the whole request body, or `None` if it is over `max_body` bytes.
+    async fn buffer_request_body(
+        &self,
+        session: &mut Session,
+        max_body: usize,
+    ) -> Result<Option<Bytes>> {
+        // read before, e.g. for body routing, and kept for the upstream
+        if session.is_body_done() {
+            return Ok(Some(session.get_retry_buffer().unwrap_or_default()));
+        }
+        self.send_continue(session).await?;
+        let mut body = BytesMut::new();
+        while let Some(chunk) = session.read_request_body().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() > max_body {
+                return Ok(None);
+            }
+        }
+        Ok(Some(body.freeze()))
+    }
+
+    /// Hand the request over to the route its body or gRPC method selects.
+    async fn route_by_body(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        routing: &BodyRouting,
+    ) -> Result<()> {
+        let body = if routing.wants_body(session.req_header()) {
+            // the retry buffer keeps the body for the upstream
+            session.enable_retry_buffering();
+            self.buffer_request_body(session, body_route::MAX_BODY)
+                .await?
+        } else {
+            None
+        };
+        if let Some(name) = routing.route_for(session.req_header(), body.as_deref()) {
+            match self.router.load().route(name) {
+                Some(route) => ctx.set_route(Some(route)),
+                None => warn!("body routing to unknown route {name}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse request bodies the route's XML guard does not pass.
+    async fn check_xml(&self, session: &mut Session, guard: &XmlGuard) -> Result<()> {
+        if !guard.wants_body(session.req_header())? {
+            return Ok(());
+        }
+        // the retry buffer keeps the body for the upstream
+        session.enable_retry_buffering();
+        let body = self
+            .buffer_request_body(session, body_route::MAX_BODY)
+            .await?
+            .unwrap_or_default();
+        guard.check(&body)
+    }
+
+    /// Check a GraphQL request against the route's limits, answering it if
+    /// it is refused.
+    async fn check_graphql(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        graphql: &GraphQl,
+    ) -> Result<bool> {
+        let checked = match graphql.wants_body(session.req_header()) {
+            Ok(wants_body) => {
+                let body = if wants_body {
+                    // the retry buffer keeps the body for the upstream
+                    session.enable_retry_buffering();
+                    self.buffer_request_body(session, body_route::MAX_BODY)
+                        .await?
+                        .unwrap_or_default()
+                } else {
+                    Bytes::new()
+                };
+                graphql.check(session.req_header(), &body)
+            }
+            Err(refusal) => Err(refusal),
+        };
+        let checked = checked.and_then(|operations| {
+            let names: Vec<_> = operations.iter().map(ToString::to_string).collect();
+            ctx.operation = Some(names.join(","));
+            graphql.throttle(&operations, ctx.rate_cost())
+        });
+        match checked {
+            Ok(()) => Ok(false),
+            Err(refusal) => {
+                let (header, body) = refusal.response()?;
+                self.respond(session, header, body).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Check the request against the route's OpenAPI spec, answering it if
+    /// it is refused.
+    async fn check_openapi(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        api: &OpenApi,
+    ) -> Result<bool> {
+        let checked = match api.check(session.req_header()) {
+            Ok(checked) => {
+                ctx.operation = Some(api.operation_id(&checked).to_string());
+                match api.wants_body(&checked, session.req_header()) {
+                    Ok(true) => {
+                        // the retry buffer keeps the body for the upstream
+                        session.enable_retry_buffering();
+                        match self.buffer_request_body(session, api.max_body()).await? {
+                            Some(body) => api
+                                .check_body(&checked, session.req_header(), &body)
+                                .map(|()| checked),
+                            None => Err(api.too_large(&checked)),
+                        }
+                    }
+                    Ok(false) => Ok(checked),
+                    Err(refusal) => Err(refusal),
+                }
+            }
+            Err(refusal) => Err(refusal),
+        };
+        match checked {
+            Ok(checked) => {
+                api.passed(&checked);
+                Ok(false)
+            }
+            Err(refusal) => {
+                api.refused(&refusal);
+                if let Some(operation) = refusal.operation(api) {
+                    ctx.operation = Some(operation.to_string());
+                }
+                let (header, body) = refusal.response(api, ctx.request_id(), ctx.route_name())?;
+                self.respond(session, header, body).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Proxy the request buffered, answering with the response signed.
+    async fn sign_response(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        route: &Route,
+        signer: &ResponseSigner,
+    ) -> Result<()> {
+        let Some(body) = self.buffer_request_body(session, signer.max_body()).await? else {
+            return Error::e_explain(ErrorType::HTTPStatus(413), "request body too large to sign");
+        };
+        let (mut header, body) = self
+            .buffered_exchange(session, ctx, route, body, signer.max_body())
+            .await?;
+        signer.sign(session.req_header(), &mut header, &body)?;
+        self.respond(session, header, body).await
+    }
+
+    /// Proxy the request, holding the response header back until the start
+    /// of the body is sniffed for its content type.
+    async fn sniff_exchange(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        route: &Route,
+        guard: &ContentTypeGuard,
+    ) -> Result<()> {
+        let upstream = self.select_upstream(Some(route), &Self::client_key(session, ctx))?;
+        ctx.set_upstream(upstream.clone());
+        let peer = self.peer(Some(route), upstream);
+        self.send_continue(session).await?;
+        let mut req = session.req_header().clone();
+        self.set_upstream_host(Some(route), &mut req);
+        let (mut exchange, _reused) = self.connector.get_http_session(&*peer).await?;
+        exchange.write_request_header(Box::new(req)).await?;
+        while let Some(chunk) = session.read_request_body().await? {
+            exchange.write_request_body(chunk, false).await?;
+        }
+        exchange.finish_request_body().await?;
+        exchange.read_response_header().await?;
+        let mut header = exchange
+            .response_header()
+            .expect("response header is read")
+            .clone();
+
+        let mut start = BytesMut::new();
+        let mut done = false;
+        while start.len() < content_sniff::SNIFF_LEN {
+            match exchange.read_response_body().await? {
+                Some(chunk) => start.extend_from_slice(&chunk),
+                None => {
+                    done = true;
+                    break;
+                }
+            }
+        }
+        if guard.check(session.req_header(), &mut header, &start)? == Checked::Block {
+            let (header, body) =
+                self.synthesize(session, ctx, StatusCode::BAD_GATEWAY, Page::Error, &[])?;
+            return self.respond(session, header, body).await;
+        }
+        if done {
+            return self.respond(session, header, start.freeze()).await;
+        }
+        // kept responses of h2c upstreams still say HTTP/2, and may say
+        // nothing of their length
+        header.set_version(http::Version::HTTP_11);
+        if !header.headers.contains_key(header::CONTENT_LENGTH) {
+            header.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+        }
+        if let Some(compat) = self.http10_compat(session) {
+            compat.fix_response(&mut header)?;
+            session.set_keepalive(None);
+        }
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        session
+            .write_response_body(Some(start.freeze()), false)
+            .await?;
+        while let Some(chunk) = exchange.read_response_body().await? {
+            session.write_response_body(Some(chunk), false).await?;
+        }
+        session.write_response_body(None, true).await?;
+        self.connector
+            .release_http_session(exchange, &*peer, None)
+            .await;
+        Ok(())
+    }
+
+    /// Proxy an upgrade request, and the connection once upgraded, passing
+    /// half-closes on, see [`crate::upstream_tcp`].
+    async fn upgraded_exchange(&self, session: &mut Session, ctx: &mut ProxyCtx) -> Result<()> {
+        let mut peer = self.upstream_peer(session, ctx).await?;
+        // upgrades are HTTP/1 only
+        peer.options.set_http_version(1, 1);
+        let mut req = session.req_header().clone();
+        self.upstream_request_filter(session, &mut req, ctx).await?;
+        let (mut exchange, _reused) = self.connector.get_http_session(&*peer).await?;
+        exchange.write_request_header(Box::new(req)).await?;
+        let (sent, received) = upstream_tcp::relay_upgraded(session, &mut exchange).await?;
+        debug!(
+            "upgraded connection to {} closed, sent={sent} received={received}",
+            peer._address
+        );
+        Ok(())
+    }
+
+    /// Proxy the request buffered, with the scanner's verdict on its body and
+    /// on the response's.
+    async fn scan_exchange(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        route: &Route,
+        scanner: &ContentScanner,
+    ) -> Result<()> {
+        let Some(mut body) = self
+            .buffer_request_body(session, scanner.max_body())
+            .await?
+        else {
+            return Error::e_explain(ErrorType::HTTPStatus(413), "request body too large to scan");
+        };
+        if scanner.scans_requests() && !body.is_empty() {
+            match scanner.check_request(session.req_header(), &body).await {
+                Verdict::Clean => {}
+                Verdict::Cleaned(cleaned) => body = cleaned,
+                verdict => return self.refuse_scanned(session, ctx, verdict).await,
+            }
+        }
+        let (mut header, mut body) = self
+            .buffered_exchange(session, ctx, route, body, scanner.max_body())
+            .await?;
+        if scanner.scans_responses() && !body.is_empty() {
+            match scanner
+                .check_response(session.req_header(), &header, &body)
+                .await
+            {
+                Verdict::Clean => {}
+                Verdict::Cleaned(cleaned) => {
+                    header.insert_header(header::CONTENT_LENGTH, cleaned.len())?;
+                    body = cleaned;
+                }
+                verdict => return self.refuse_scanned(session, ctx, verdict).await,
+            }
+        }
+        self.respond(session, header, body).await
+    }
+
+    /// Proxy the request buffered once its body, held in quarantine, is
+    /// scanned clean.
+    async fn quarantine_exchange(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        route: &Route,
+        quarantine: &Quarantine,
+    ) -> Result<()> {
+        self.send_continue(session).await?;
+        let id = ctx.request_id().to_string();
+        let held = quarantine.hold(session, &id).await?;
+        let body = match held.verdict {
+            Verdict::Clean => held.body,
+            Verdict::Cleaned(cleaned) => cleaned,
+            Verdict::Blocked(_) => {
+                let (mut header, body) =
+                    self.synthesize(session, ctx, StatusCode::FORBIDDEN, Page::Error, &[])?;
+                header.insert_header(quarantine::REFERENCE_HEADER, held.reference)?;
+                return self.respond(session, header, body).await;
+            }
+            verdict => return self.refuse_scanned(session, ctx, verdict).await,
+        };
+        let (header, body) = self
+            .buffered_exchange(session, ctx, route, body, quarantine.max_size())
+            .await?;
+        self.respond(session, header, body).await
+    }
+
+    /// Answer `403` for a blocked body, `503` for one that could not be
+    /// scanned.
+    async fn refuse_scanned(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        verdict: Verdict,
+    ) -> Result<()> {
+        let status = match verdict {
+            Verdict::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::FORBIDDEN,
+        };
+        let (header, body) = self.synthesize(session, ctx, status, Page::Error, &[])?;
+        self.respond(session, header, body).await
+    }
+
+    /// Send the request with its buffered `body` upstream and buffer the
+    /// response, failing with a 502 if it is over `max_body` bytes.
+    async fn buffered_exchange(
+        &self,
+        session: &Session,
+        ctx: &mut ProxyCtx,
+        route: &Route,
+        body: Bytes,
+        max_body: usize,
+    ) -> Result<(ResponseHeader, Bytes)> {
+        let upstream = self.select_upstream(Some(route), &Self::client_key(session, ctx))?;
+        ctx.set_upstream(upstream.clone());
+        let peer = self.peer(Some(route), upstream);
+        let mut req = session.req_header().clone();
+        self.set_upstream_host(Some(route), &mut req);
+        req.remove_header(&header::EXPECT);
+        // the body may have been replaced, e.g. by a scanner's cleaned one
+        if req.headers.contains_key(header::CONTENT_LENGTH) {
+            req.insert_header(header::CONTENT_LENGTH, body.len())?;
+        }
+        let fetched = subrequest::send(&self.connector, &peer, req, body, max_body).await?;
+        let Some(fetched) = fetched else {
+            return Error::e_explain(ErrorType::HTTPStatus(502), "response too large to buffer");
+        };
+        let mut header = fetched.header;
+        header.remove_header(&header::TRANSFER_ENCODING);
+        header.insert_header(header::CONTENT_LENGTH, fetched.body.len())?;
+        Ok((header, fetched.body))
+    }
+
+    /// Ask an upstream whether the expired object of `revalidation` is still
+    /// current and answer with it or its replacement; `false` to proxy the
+    /// request as a cache miss instead.
+    async fn revalidate(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        cache: &MemoryCache,
+        revalidation: Revalidation,
+    ) -> Result<bool> {
+        let route = ctx.route().cloned();
+        let upstream = self.select_upstream(route.as_deref(), &Self::client_key(session, ctx))?;
+        ctx.set_upstream(upstream.clone());
+        let peer = self.peer(route.as_deref(), upstream);
+        let mut req = revalidation.request(session.req_header())?;
+        self.set_upstream_host(route.as_deref(), &mut req);
+        let max_body = cache.config().max_object_size;
+        let Some(fetched) = subrequest::fetch(&self.connector, &peer, req, max_body).await? else {
+            ctx.cache_fill = Some(revalidation.into_fill());
+            return Ok(false);
+        };
+        let (mut header, body) = revalidation.complete(cache, fetched)?;
+        ctx.cache_status = Some(CacheStatus::Revalidated);
+        if ctx.diagnostics {
+            DiagnosticHeaders::annotate(&mut header, ctx)?;
+        }
+        self.respond(session, header, body).await?;
+        Ok(true)
+    }
+
+    /// Send a request with an idempotency key upstream only if no request
+    /// with the key was, otherwise answer with the response to that one.
+    async fn deduplicate(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        route: &Route,
+        idempotency: &Idempotency,
+        key: &str,
+    ) -> Result<()> {
+        let Some(body) = self
+            .buffer_request_body(session, idempotency.max_body())
+            .await?
+        else {
+            return Error::e_explain(
+                ErrorType::HTTPStatus(413),
+                "request body too large to buffer",
+            );
+        };
+        let client = Self::client_key(session, ctx);
+        loop {
+            match idempotency.begin(&client, key, session.req_header(), &body) {
+                Begin::Lead(lead) => {
+                    let (header, body) = self
+                        .buffered_exchange(session, ctx, route, body, idempotency.max_body())
+                        .await?;
+                    lead.finish(&header, &body);
+                    return self.respond(session, header, body).await;
+                }
+                Begin::Wait(mut done) => {
+                    // woken by the first request finishing or giving up
+                    let _ = done.changed().await;
+                }
+                Begin::Replay(stored) => {
+                    let mut header = stored.header.clone();
+                    header.insert_header(REPLAYED_HEADER, "true")?;
+                    return self.respond(session, header, stored.body.clone()).await;
+                }
+                Begin::Mismatch => {
+                    return Error::e_explain(
+                        ErrorType::HTTPStatus(422),
+                        "idempotency key reused for a different request",
+                    );
+                }
+            }
+        }
+    }
+
+    /// Relay an event stream with keepalive comments, see [`stream`].
+    async fn relay_events(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        route: &Route,
+        config: &StreamConfig,
+    ) -> Result<()> {
+        let upstream = self.select_upstream(Some(route), &Self::client_key(session, ctx))?;
+        ctx.set_upstream(upstream.clone());
+        let peer = self.peer(Some(route), upstream);
+        let mut req = session.req_header().clone();
+        self.set_upstream_host(Some(route), &mut req);
+        req.remove_header(&header::EXPECT);
+        let compat = self.http10_compat(session).cloned();
+        if compat.is_some() {
+            session.set_keepalive(None);
+        }
+        stream::relay_events(
+            &self.connector,
+            &peer,
+            req,
+            session,
+            config,
+            |header| match &compat {
+                Some(compat) => compat.fix_response(header),
+                None => Ok(()),
+            },
+        )
+        .await
+    }
+
+    /// Run the request on an application server of the route's cluster.
+    async fn run_cgi(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        route: &Route,
+        cgi: &CgiGateway,
+    ) -> Result<()> {
+        let upstream = self.select_upstream(Some(route), &Self::client_key(session, ctx))?;
+        ctx.set_upstream(upstream.clone());
+        self.send_continue(session).await?;
+        let compat = self.http10_compat(session).cloned();
+        if compat.is_some() {
+            session.set_keepalive(None);
+        }
+        cgi.handle(session, &upstream, |header| match &compat {
+            Some(compat) => compat.fix_response(header),
+            None => Ok(()),
+        })
+        .await
+    }
+
+    /// Render one of the proxy's own pages for this request.
+    fn synthesize(
+        &self,
+        session: &Session,
+        ctx: &ProxyCtx,
+        status: StatusCode,
+        page: Page,
+        extra_vars: &[(&str, &str)],
+    ) -> Result<(ResponseHeader, Bytes)> {
+        let accept = session
+            .req_header()
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let gateway = ctx.route().and_then(|r| r.gateway.as_deref());
+        let enveloped = gateway.is_some_and(Gateway::error_envelope) && page != Page::Redirect;
+        let format = if enveloped {
+            Format::Json
+        } else {
+            Format::negotiate(accept)
+        };
+
+        let code = status.as_str();
+        let timestamp = template::timestamp(SystemTime::now());
+        let mut vars = vec![
+            ("status", code),
+            ("reason", status.canonical_reason().unwrap_or_default()),
+            ("request_id", ctx.request_id()),
+            ("timestamp", timestamp.as_str()),
+            ("route", ctx.route_name()),
+        ];
+        vars.extend_from_slice(extra_vars);
+        let tenant = ctx.route().and_then(|r| r.tenant.as_deref());
+        let body = if enveloped {
+            gateway::envelope(status, page, ctx.request_id(), ctx.route_name())
+        } else {
+            self.templates.render(tenant, page, format, &vars)
+        };
+
+        let mut header = ResponseHeader::build(status, None)?;
+        header.insert_header(header::CONTENT_TYPE, format.content_type())?;
+        header.insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+        header.insert_header(header::CACHE_CONTROL, "no-store")?;
+        // scripts of other origins can read why their request failed
+        if let Some(cors) = gateway.and_then(Gateway::cors) {
+            cors.annotate(session.req_header(), &mut header)?;
+        }
+        Ok((header, Bytes::from(body)))
+    }
+
+    /// Send the route's preload links ahead of the upstream response.
+    async fn early_hints(&self, session: &mut Session, route: &Route) -> Result<()> {
+        let req = session.req_header();
+        // 1xx responses must not be sent to HTTP/1.0 clients
+        if route.early_hints.is_empty()
+            || req.version < http::Version::HTTP_11
+            || req.method != http::Method::GET
+        {
+            return Ok(());
+        }
+        let mut hints = ResponseHeader::build(StatusCode::EARLY_HINTS, None)?;
+        for link in &route.early_hints {
+            hints.append_header(header::LINK, link)?;
+        }
+        session.write_response_header(Box::new(hints), false).await
+    }
+
+    /// Answer from the cache, stale or not, if the route's cluster has no
+    /// usable upstream and its policy allows.
+    async fn serve_stale(&self, session: &mut Session, ctx: &mut ProxyCtx) -> Option<FailToProxy> {
+        let route = ctx.route()?.clone();
+        let NoUpstream::ServeStale { max_stale } = route.no_upstream else {
+            return None;
+        };
+        let cached =
+            self.cache
+                .as_ref()?
+                .lookup_stale(session.req_header(), Some(&route), max_stale);
+        let (mut header, body) = cached.ok()??;
+        ctx.cache_status = Some(CacheStatus::Stale);
+        if ctx.diagnostics
+            && let Err(e) = DiagnosticHeaders::annotate(&mut header, ctx)
+        {
+            error!("failed to annotate stale response: {e}");
+        }
+        let code = header.status.as_u16();
+        if let Err(e) = self.respond(session, header, body).await {
+            error!("failed to send stale response to downstream: {e}");
+        }
+        self.no_upstream
+            .record(Self::cluster_name(Some(&route)), Outcome::Stale);
+        Some(FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        })
+    }
+
+    /// Write a response generated by the proxy itself.
+    async fn respond(
+        &self,
+        session: &mut Session,
+        mut header: ResponseHeader,
+        body: Bytes,
+    ) -> Result<()> {
+        // kept responses of h2c upstreams still say HTTP/2
+        header.set_version(http::Version::HTTP_11);
+        if let Some(compat) = self.http10_compat(session) {
+            compat.fix_response(&mut header)?;
+            session.set_keepalive(None);
+        }
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        session.write_response_body(Some(body), true).await
+    }
+}
+
+#[async_trait]
+impl ProxyHttp for LB {
+    type CTX = ProxyCtx;
+    fn new_ctx(&self) -> Self::CTX {
+        ProxyCtx::default()
+    }
+
+    async fn early_request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<()> {
+        ctx.mark(Mark::Received);
+        self.runtimes.observe("proxy");
+        let request_id = session
+            .req_header()
+            .headers
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= 128);
+        match request_id {
+            Some(id) => ctx.set_request_id(id),
+            None => ctx.set_request_id(generate_request_id()),
+        }
+        ctx.downstream_write_pending = session.stream().map(|s| s.get_write_pending_time());
+        ctx.set_fingerprint(
+            session
+                .digest()
+                .and_then(|d| {
+                    d.ssl_digest
+                        .as_ref()?
+                        .extension
+                        .get::<Arc<TlsFingerprint>>()
+                })
+                .cloned(),
+        );
+        let client = family::client_ip(session);
+        if let Some(diagnostics) = &self.listener.diagnostics {
+            ctx.diagnostics = diagnostics.allows(session.req_header(), client);
+        }
+        if let (Some(rates), Some(client)) = (&self.geo, client) {
+            ctx.set_geo(rates.classify(client));
+        }
+        if let Some(scorer) = &self.anomaly {
+            let scored = scorer.score(session.req_header(), client, ctx.fingerprint());
+            ctx.set_anomaly(scored);
+        }
+        if let Some(connection) = session
+            .client_addr()
+            .cloned()
+            .and_then(|client| self.connections.request_started(&client))
+        {
+            let keepalive = &self.listener.keepalive;
+            keepalive.apply(session, connection.requests(), connection.age());
+        }
+
+        if self.listener.informational == Informational::Suppress {
+            session.set_ignore_info_resp(true);
+        }
+
+        if let Some(policy) = self.expect_continue(session) {
+            // a response written before the client sends the body must not
+            // wait on draining a body that will never come
+            session.set_close_on_response_before_downstream_finish(true);
+            if policy == ExpectContinue::RespondLocally {
+                session.write_continue_response().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        if let Some(compat) = self.http10_compat(session) {
+            compat.fix_request(session.req_header_mut())?;
+            session.set_ignore_info_resp(true);
+        }
+
+        let router = self.router.load();
+        ctx.original_dst = original_dst::destination(session, self.listener.transparent);
+        let dst = ctx.original_dst.or_else(|| {
+            session
+                .server_addr()
+                .and_then(|a| a.as_inet())
+                .map(|a| SocketAddr::new(a.ip().to_canonical(), a.port()))
+        });
+        let redirected = ctx.original_dst.is_some();
+        ctx.set_route(router.match_request(session.req_header(), dst, redirected));
+        // requests on their way elsewhere name hosts the proxy does not serve
+        if let Some(strict) = &self.listener.strict_hosts
+            && !ctx.route().is_some_and(|r| r.original_dst_cluster)
+        {
+            let status = match strict.check(session.req_header(), &router) {
+                HostCheck::Allowed => None,
+                HostCheck::Invalid => Some(StatusCode::BAD_REQUEST),
+                HostCheck::Unknown => Some(StatusCode::MISDIRECTED_REQUEST),
+            };
+            if let Some(status) = status {
+                let (header, body) = self.synthesize(session, ctx, status, Page::Error, &[])?;
+                self.respond(session, header, body).await?;
+                return Ok(true);
+            }
+        }
+        if let Some(egress) = &self.egress
+            && let Some(dst) = ctx.original_dst
+            && ctx.route().is_some_and(|r| r.original_dst_cluster)
+        {
+            let identity = Identity {
+                user: ctx.consumer(),
+                ip: family::client_ip(session),
+            };
+            if !egress.check(&identity, &dst.ip().to_string(), dst.port()) {
+                let status = StatusCode::FORBIDDEN;
+                let (header, body) = self.synthesize(session, ctx, status, Page::Error, &[])?;
+                self.respond(session, header, body).await?;
+                return Ok(true);
+            }
+        }
+        if let Some(flags) = &self.flags {
+            let client = family::client_ip(session);
+            flags.evaluate(session.req_header(), client, ctx.flags_mut());
+            let to = ctx
+                .route()
+                .and_then(|route| flags::reroute(ctx.flags(), &route.name))
+                .map(str::to_string);
+            if let Some(to) = to {
+                match router.route(&to) {
+                    Some(route) => ctx.set_route(Some(route)),
+                    None => warn!("feature flags reroute to {to}, which is not a route"),
+                }
+            }
+        }
+        if let Some(route) = ctx.route().cloned()
+            && let Some(routing) = &route.body_routing
+        {
+            self.route_by_body(session, ctx, routing).await?;
+        }
+        if let Some(client) = session.client_addr()
+            && let Some(route) = ctx.route()
+        {
+            self.connections.request_routed(client, &route.name);
+        }
+        if let Some(har) = &self.har {
+            let https = session.digest().is_some_and(|d| d.ssl_digest.is_some());
+            let buffered = session
+                .is_body_done()
+                .then(|| session.get_retry_buffer())
+                .flatten();
+            ctx.har = har.start(
+                session.req_header(),
+                ctx.route_name(),
+                ctx.request_id(),
+                https,
+                buffered,
+            );
+        }
+
+        match early_data::decide(session.req_header(), ctx.route().map(|r| &**r)) {
+            EarlyData::No => {}
+            EarlyData::Accept => ctx.early_data = true,
+            EarlyData::TooEarly => {
+                let (header, body) =
+                    self.synthesize(session, ctx, StatusCode::TOO_EARLY, Page::Error, &[])?;
+                self.respond(session, header, body).await?;
+                return Ok(true);
+            }
+        }
+
+        if let (Some(route), Some(fingerprint)) = (ctx.route(), ctx.fingerprint())
+            && route
+                .blocked_fingerprints
+                .iter()
+                .any(|f| fingerprint.matches(f))
+        {
+            let (header, body) =
+                self.synthesize(session, ctx, StatusCode::FORBIDDEN, Page::Error, &[])?;
+            self.respond(session, header, body).await?;
+            return Ok(true);
+        }
+        if let (Some(route), Some(anomaly)) = (ctx.route(), ctx.anomaly())
+            && route
+                .max_anomaly_score
+                .is_some_and(|max| anomaly.score > max)
+        {
+            let (header, body) =
+                self.synthesize(session, ctx, StatusCode::FORBIDDEN, Page::Error, &[])?;
+            self.respond(session, header, body).await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned() {
+            // answered before anything else can, a browser only sends the
+            // request itself once its preflight is
+            if let Some(cors) = route.gateway.as_ref().and_then(|g| g.cors())
+                && Cors::is_preflight(session.req_header())
+            {
+                match cors.preflight(session.req_header())? {
+                    Some(header) => self.respond(session, header, Bytes::new()).await?,
+                    None => {
+                        let (header, body) =
+                            self.synthesize(session, ctx, StatusCode::FORBIDDEN, Page::Error, &[])?;
+                        self.respond(session, header, body).await?;
+                    }
+                }
+                return Ok(true);
+            }
+            if route.maintenance {
+                let (header, body) = self.synthesize(
+                    session,
+                    ctx,
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Page::Maintenance,
+                    &[],
+                )?;
+                self.respond(session, header, body).await?;
+                return Ok(true);
+            }
+            if let Some(location) = &route.redirect {
+                let (mut header, body) = self.synthesize(
+                    session,
+                    ctx,
+                    StatusCode::FOUND,
+                    Page::Redirect,
+                    &[("location", location)],
+                )?;
+                header.insert_header(header::LOCATION, location)?;
+                self.respond(session, header, body).await?;
+                return Ok(true);
+            }
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(guard) = &route.xml_guard
+        {
+            self.check_xml(session, guard).await?;
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(graphql) = &route.graphql
+            && self.check_graphql(session, ctx, graphql).await?
+        {
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(api) = &route.openapi
+            && self.check_openapi(session, ctx, api).await?
+        {
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(doh) = &route.doh
+        {
+            let (header, body) = doh.handle(session).await?;
+            self.respond(session, header, body).await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && route.s3.is_some()
+            && !matches!(session.req_header().method, Method::GET | Method::HEAD)
+        {
+            let (mut header, body) = self.synthesize(
+                session,
+                ctx,
+                StatusCode::METHOD_NOT_ALLOWED,
+                Page::Error,
+                &[],
+            )?;
+            header.insert_header(header::ALLOW, "GET, HEAD")?;
+            self.respond(session, header, body).await?;
+            return Ok(true);
+        }
+
+        let route = ctx.route().cloned();
+        if session.is_upgrade_req()
+            && session.as_http1().is_some()
+            && self.upstream_tcp(route.as_deref()).half_close
+            && !route
+                .as_deref()
+                .is_some_and(|r| r.original_dst_cluster || r.s3.is_some() || r.cgi.is_some())
+        {
+            self.upgraded_exchange(session, ctx).await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(upload) = &route.upload
+        {
+            if let Some(status) = upload.refusal(session.req_header()) {
+                let (mut header, body) = self.synthesize(session, ctx, status, Page::Error, &[])?;
+                if status == StatusCode::METHOD_NOT_ALLOWED {
+                    header.insert_header(header::ALLOW, "POST")?;
+                }
+                self.respond(session, header, body).await?;
+                return Ok(true);
+            }
+            self.send_continue(session).await?;
+            let (header, body) = upload.handle(session).await?;
+            self.respond(session, header, body).await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(cgi) = &route.cgi
+        {
+            self.run_cgi(session, ctx, &route, cgi).await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(quarantine) = &route.quarantine
+        {
+            self.quarantine_exchange(session, ctx, &route, quarantine)
+                .await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(scanner) = &route.scan
+        {
+            self.scan_exchange(session, ctx, &route, scanner).await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(signer) = &route.signing
+        {
+            self.sign_response(session, ctx, &route, signer).await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(guard) = &route.content_type_guard
+        {
+            self.sniff_exchange(session, ctx, &route, guard).await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(idempotency) = &route.idempotency
+            && let Some(key) = Idempotency::key(session.req_header()).map(str::to_string)
+        {
+            self.deduplicate(session, ctx, &route, idempotency, &key)
+                .await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(fan_out) = &route.fan_out
+            && session.req_header().method == Method::GET
+            && self.fan_out(session, ctx, &route, fan_out).await?
+        {
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(image) = &route.image
+            && let Some(transform) = image.plan(session.req_header())
+            && self
+                .optimize_image(
+                    session,
+                    &route,
+                    image,
+                    transform,
+                    &Self::client_key(session, ctx),
+                )
+                .await?
+        {
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route().cloned()
+            && let Some(stream) = &route.stream
+            && stream.sse_keepalive.is_some()
+            && session.req_header().method == Method::GET
+            && stream::wants_events(session.req_header())
+        {
+            self.relay_events(session, ctx, &route, stream).await?;
+            return Ok(true);
+        }
+
+        let streaming = ctx.route().is_some_and(|r| r.stream.is_some());
+        if let Some(cache) = &self.cache
+            && !streaming
+        {
+            let route = ctx.route().cloned();
+            match cache.lookup(session.req_header(), route.as_deref())? {
+                Lookup::Hit(mut header, body) => {
+                    ctx.cache_status = Some(CacheStatus::Hit);
+                    if ctx.diagnostics {
+                        DiagnosticHeaders::annotate(&mut header, ctx)?;
+                    }
+                    self.respond(session, header, body).await?;
+                    return Ok(true);
+                }
+                Lookup::Miss(fill) => {
+                    ctx.cache_status = Some(CacheStatus::Miss);
+                    ctx.cache_fill = Some(fill);
+                }
+                Lookup::Revalidate(revalidation) => {
+                    if self.revalidate(session, ctx, cache, revalidation).await? {
+                        return Ok(true);
+                    }
+                    ctx.cache_status = Some(CacheStatus::Miss);
+                }
+                Lookup::Bypass => ctx.cache_status = Some(CacheStatus::Bypass),
+            }
+        }
+
+        if let Some(route) = ctx.route().cloned() {
+            self.early_hints(session, &route).await?;
+        }
+
+        // keep this last: every filter above had its chance to reject
+        if self.expect_continue(session) == Some(ExpectContinue::AfterFilters) {
+            session.write_continue_response().await?;
+        }
+        ctx.mark(Mark::Filtered);
+        Ok(false)
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        let route = ctx.route().cloned();
+        let route = route.as_deref();
+        if let Some(origin) = route.and_then(|r| r.s3.as_ref()) {
+            let bucket = origin.bucket();
+            ctx.set_upstream(Backend::new(&bucket.addr.to_string())?);
+            return Ok(Box::new(bucket.peer()));
+        }
+        if route.is_some_and(|r| r.original_dst_cluster) {
+            let Some(dst) = ctx.original_dst else {
+                return Error::e_explain(
+                    ErrorType::HTTPStatus(502),
+                    "original_dst route on a connection that was not redirected",
+                );
+            };
+            let upstream = Backend::new(&dst.to_string())?;
+            ctx.set_upstream(upstream.clone());
+            ctx.upstream_lease = Some(self.in_flight.acquire(&upstream.addr));
+            return Ok(Box::new(HttpPeer::new(upstream, false, String::new())));
+        }
+        let client = Self::client_key(session, ctx);
+        let pinned = route.is_some_and(|r| {
+            r.sticky.is_some() || r.hash_selection.is_some() || r.subsets.is_some()
+        });
+        let mut pool = None;
+        let upstream = if let Some(route) = route
+            && let Some(sticky) = &route.sticky
+        {
+            let (upstream, cookie) =
+                self.select_sticky(session.req_header(), route, sticky, &client)?;
+            ctx.sticky_cookie = cookie;
+            upstream
+        } else if let Some(route) = route
+            && let Some(hashing) = &route.hash_selection
+            && let Some((upstream, hint)) = self.select_hashed(session.req_header(), route, hashing)
+        {
+            pool = Some(hint);
+            upstream
+        } else {
+            self.select_upstream(route, &client)?
+        };
+        ctx.set_upstream(upstream.clone());
+        ctx.upstream_lease = Some(self.in_flight.acquire(&upstream.addr));
+        let selected = upstream.clone();
+        let mut peer = if route.is_some_and(|r| r.h2c) {
+            let mut peer = HttpPeer::new(upstream, false, String::new());
+            peer.options.set_http_version(2, 2);
+            Box::new(peer)
+        } else {
+            self.peer(route, upstream)
+        };
+        if h2_fallback::offers_h2(&peer) && self.h2_fallback.is_downgraded(&peer._address) {
+            peer.options.set_http_version(1, 1);
+        }
+        if let Some(stream) = route.and_then(|r| r.stream.as_ref()) {
+            // no timeout on the whole response, only between its pieces
+            peer.options.read_timeout = stream.idle_timeout;
+        }
+        self.upstream_tcp(route).apply(&mut peer);
+        if let Some(pool) = pool {
+            self.upstream_tcp(route).pool(&mut peer, pool);
+        }
+        if let Some(race) = self.connect_race.as_ref().filter(|_| !pinned)
+            && let Some(racer) = race.racer(
+                &selected,
+                self.siblings(route, &selected),
+                peer.options.connection_timeout,
+            )
+        {
+            peer.options.custom_l4 = Some(racer.clone());
+            ctx.connect_racer = Some(racer);
+        }
+        Ok(peer)
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        reused: bool,
+        peer: &HttpPeer,
+        #[cfg(unix)] _fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        ctx.mark(Mark::Connected);
+        ctx.upstream_reused = reused;
+        if let Some(readiness) = &self.readiness
+            && !reused
+        {
+            readiness.upstream_connected();
+        }
+        ctx.upstream_http1 = !h2_fallback::offers_h2(peer);
+        if let Some(winner) = ctx.connect_racer.take().and_then(|r| r.winner())
+            && !reused
+            && ctx.upstream().is_some_and(|u| u.addr != winner.addr)
+        {
+            ctx.upstream_lease = Some(self.in_flight.acquire(&winner.addr));
+            ctx.raced_to(winner);
+        }
+        if let Some(lease) = &mut ctx.upstream_lease {
+            lease.connected();
+        }
+        // layer 0 is the TCP connection, layer 1 the TLS session on top of it
+        let layer = |i: usize| {
+            digest
+                .filter(|_| !reused)
+                .and_then(|d| d.timing_digest.get(i)?.as_ref()?.establishment_duration)
+        };
+        ctx.upstream_connected(layer(0), layer(1));
+        Ok(())
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if !ctx.route().is_some_and(|r| r.original_dst_cluster) {
+            self.set_upstream_host(ctx.route().map(|r| &**r), upstream_request);
+        }
+        if self.expect_continue(session).is_some() {
+            upstream_request.remove_header(&http::header::EXPECT);
+        }
+        upstream_request.remove_header(debug_headers::TOKEN_HEADER);
+        // only the proxy's own fingerprints, never the client's claims
+        upstream_request.remove_header(JA3_HEADER);
+        upstream_request.remove_header(JA4_HEADER);
+        if let Some(fingerprint) = ctx.fingerprint() {
+            upstream_request.insert_header(JA3_HEADER, &fingerprint.ja3_hash)?;
+            upstream_request.insert_header(JA4_HEADER, &fingerprint.ja4)?;
+        }
+        upstream_request.remove_header(FINGERPRINT_HEADER);
+        upstream_request.remove_header(SCORE_HEADER);
+        if let Some(anomaly) = ctx.anomaly() {
+            upstream_request.insert_header(FINGERPRINT_HEADER, &anomaly.hash)?;
+            upstream_request.insert_header(SCORE_HEADER, anomaly.score.to_string())?;
+        }
+        if self.flags.is_some() {
+            let claimed: Vec<_> = upstream_request
+                .headers
+                .keys()
+                .filter(|name| name.as_str().starts_with(flags::HEADER_PREFIX))
+                .cloned()
+                .collect();
+            for name in claimed {
+                upstream_request.remove_header(&name);
+            }
+        }
+        for evaluation in ctx.flags() {
+            let name = format!("{}{}", flags::HEADER_PREFIX, evaluation.flag.name);
+            let value = if evaluation.on { "on" } else { "off" };
+            upstream_request.insert_header(name, value)?;
+        }
+        if let Some(fill) = &ctx.cache_fill {
+            fill.upstream_request_filter(upstream_request)?;
+        }
+        if let Some(route) = ctx.route()
+            && let Some(gateway) = &route.gateway
+        {
+            let tls = session.digest().and_then(|d| d.ssl_digest.as_deref());
+            let context = AuthContext {
+                request_id: ctx.request_id(),
+                consumer: ctx.consumer(),
+                client_ip: family::client_ip(session),
+                cert_organization: tls.and_then(|t| t.organization.as_deref()),
+                cert_serial: tls.and_then(|t| t.serial_number.as_deref()),
+            };
+            gateway.upstream_request(&route.path_prefix, upstream_request, &context)?;
+        }
+        // signed last, over the request as it is sent
+        if let Some(route) = ctx.route()
+            && let Some(origin) = &route.s3
+        {
+            origin.prepare(&route.path_prefix, upstream_request).await?;
+        }
+        Ok(())
+    }
+
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let (Some(capture), Some(body)) = (&mut ctx.har, body) {
+            capture.request_body(body);
+        }
+        Ok(())
+    }
+
+    async fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        ctx.mark(Mark::UpstreamResponse);
+        ctx.upstream_responded();
+        // upstream 1xx are forwarded as they are, 103s included
+        if upstream_response.status.is_informational() {
+            return Ok(());
+        }
+        if upstream_response.version == http::Version::HTTP_2
+            && let Some(upstream) = ctx.upstream()
+        {
+            self.h2_fallback.succeeded(&upstream.addr);
+        }
+        if let (Some(upstream), Some(latency)) = (ctx.upstream(), ctx.attempt_elapsed()) {
+            self.latencies.observe(&upstream.addr, latency);
+        }
+        if let (Some(feedback), Some(upstream)) = (&self.feedback, ctx.upstream()) {
+            feedback.observe(&upstream.addr, upstream_response, ctx.timing().ttfb);
+        }
+        if let (Some(circuits), Some(upstream)) = (&self.circuits, ctx.upstream()) {
+            match upstream_response.status {
+                StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT => circuits.failed(&upstream.addr),
+                _ => circuits.succeeded(&upstream.addr),
+            }
+        }
+        let budget = ctx.route().and_then(|r| r.budget.clone());
+        if let (Some(cache), Some(fill)) = (&self.cache, &mut ctx.cache_fill) {
+            fill.response_filter(cache, upstream_response)?;
+            if let Some(budget) = budget
+                && let Some(size) = fill.buffer_size(cache)
+            {
+                ctx.fill_lease = budget.buffer("cache", size);
+                if ctx.fill_lease.is_none() {
+                    fill.forgo();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn upstream_response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<std::time::Duration>> {
+        if let (Some(cache), Some(fill)) = (&self.cache, &mut ctx.cache_fill) {
+            fill.response_body_filter(cache, body, end_of_stream);
+        }
+        Ok(None)
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(compat) = self.http10_compat(session) {
+            compat.fix_response(upstream_response)?;
+            session.set_keepalive(None);
+        }
+        if self.listener.server_timing && !upstream_response.status.is_informational() {
+            upstream_response.append_header("Server-Timing", ctx.server_timing())?;
+        }
+        if ctx.diagnostics && !upstream_response.status.is_informational() {
+            DiagnosticHeaders::annotate(upstream_response, ctx)?;
+        }
+        if !upstream_response.status.is_informational()
+            && ctx.route().is_some_and(|r| r.stream.is_some())
+        {
+            stream::disable_buffering(upstream_response)?;
+        }
+        if ctx.early_data && !upstream_response.status.is_informational() {
+            upstream_response.insert_header(REPLAY_RISK_HEADER, REPLAY_RISK)?;
+        }
+        if !upstream_response.status.is_informational()
+            && let Some(cookie) = ctx.sticky_cookie.take()
+        {
+            upstream_response.append_header(header::SET_COOKIE, cookie)?;
+        }
+        if !upstream_response.status.is_informational()
+            && let Some(cors) = ctx
+                .route()
+                .and_then(|r| r.gateway.as_deref())
+                .and_then(Gateway::cors)
+        {
+            cors.annotate(session.req_header(), upstream_response)?;
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<std::time::Duration>> {
+        if let (Some(capture), Some(body)) = (&mut ctx.har, body) {
+            capture.response_body(body);
+        }
+        Ok(None)
+    }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        peer: &HttpPeer,
+        _ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        if let Some(circuits) = &self.circuits {
+            circuits.failed(&peer._address);
+        }
+        self.latencies.failed(&peer._address);
+        if h2_fallback::offers_h2(peer)
+            && h2_fallback::is_h2_failure(&e, true)
+            && self.h2_fallback.failed(&peer._address)
+        {
+            // the retry goes out over HTTP/1.1
+            e.set_retry(true);
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        if let Some(circuits) = &self.circuits
+            && e.esource() == &ErrorSource::Upstream
+        {
+            circuits.failed(&peer._address);
+        }
+        if e.esource() == &ErrorSource::Upstream {
+            self.latencies.failed(&peer._address);
+        }
+        let downgraded = h2_fallback::offers_h2(peer)
+            && h2_fallback::is_h2_failure(&e, !ctx.upstream_reused)
+            && self.h2_fallback.failed(&peer._address);
+        // as the default implementation, but a downgrade retries over HTTP/1.1
+        let mut e = e.more_context(format!("Peer: {peer}"));
+        if !session.req_header().method.is_idempotent() || session.as_ref().retry_buffer_truncated()
+        {
+            e.set_retry(false);
+        } else if downgraded {
+            e.set_retry(true);
+        } else {
+            e.retry.decide_reuse(client_reused);
+        }
+        e
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
+        // the request is done with its upstream
+        ctx.upstream_lease = None;
+        let downstream_stall = ctx.downstream_write_pending.and_then(|before| {
+            let now = session.stream()?.get_write_pending_time();
+            Some(now.saturating_sub(before))
+        });
+        let upstream_stall = ctx
+            .upstream_http1
+            .then(|| session.upstream_write_pending_time());
+        self.stalls
+            .observe(ctx.route_name(), downstream_stall, upstream_stall);
+        if let Some(client) = session.client_addr() {
+            self.connections.request_finished(client);
+        }
+        let req = session.req_header();
+        let status = session.response_written().map_or(0, |r| r.status.as_u16());
+        self.paths
+            .observe(ctx.route().map(|r| &**r), req.uri.path(), status);
+        if let Some(versions) = &self.versions {
+            versions.observe(status);
+        }
+        if let Some(meter) = &self.usage {
+            let account = Account {
+                tenant: ctx.route().and_then(|r| r.tenant.clone()),
+                consumer: ctx.consumer().map(str::to_string),
+            };
+            let upstream_time = ctx
+                .upstream()
+                .and(ctx.marked(Mark::Filtered))
+                .map_or(Duration::ZERO, |filtered| filtered.elapsed());
+            let usage = RequestUsage {
+                bytes_in: session.body_bytes_read() as u64,
+                bytes_out: session.body_bytes_sent() as u64,
+                upstream_time,
+                cache_hit: ctx.cache_status.and_then(|status| match status {
+                    CacheStatus::Hit | CacheStatus::Stale => Some(true),
+                    CacheStatus::Miss | CacheStatus::Revalidated => Some(false),
+                    CacheStatus::Bypass => None,
+                }),
+            };
+            meter.record(account, usage);
+        }
+        if let (Some(har), Some(capture)) = (&self.har, ctx.har.take()) {
+            let exchange = Exchange {
+                response: session.response_written(),
+                request_id: ctx.request_id(),
+                route: ctx.route_name(),
+                upstream: ctx.upstream().map(|u| u.addr.to_string()),
+                timing: ctx.timing(),
+                total: ctx.elapsed(),
+            };
+            har.finish(capture, exchange);
+        }
+        let ms = |d: Option<std::time::Duration>| {
+            d.map_or("-".to_string(), |d| {
+                format!("{:.3}", d.as_secs_f64() * 1000.0)
+            })
+        };
+        let timing = ctx.timing();
+        info!(
+            "{} {} {} route={}{}{}{}{}{} upstream={} retries={} status={} dns={} connect={} tls={} ttfb={} {}ms{}",
+            ctx.request_id(),
+            req.method,
+            req.uri,
+            ctx.route_name(),
+            ctx.operation
+                .as_ref()
+                .map_or(String::new(), |op| format!(" op={op}")),
+            ctx.fingerprint().map_or(String::new(), |f| format!(
+                " ja3={} ja4={}",
+                f.ja3_hash, f.ja4
+            )),
+            ctx.anomaly()
+                .map_or(String::new(), |a| format!(" anomaly={a}")),
+            ctx.geo()
+                .map_or(String::new(), |g| format!(" geo={}", g.dimension)),
+            flags_on(ctx.flags()),
+            ctx.upstream()
+                .map_or("-".to_string(), |u| u.addr.to_string()),
+            ctx.retries(),
+            status,
+            ms(timing.dns),
+            ms(timing.connect),
+            ms(timing.tls),
+            ms(timing.ttfb),
+            ctx.elapsed().as_millis(),
+            e.map_or(String::new(), |e| format!(" error={e}")),
+        );
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy {
+        let no_upstream = e.etype() == &NO_UPSTREAM;
+        if no_upstream && let Some(served) = self.serve_stale(session, ctx).await {
+            return served;
+        }
+        // same status mapping as the default implementation
+        let code = match e.etype() {
+            _ if no_upstream => 503,
+            ErrorType::HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    // connection already dead
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+
+        let page = if no_upstream {
+            let route = ctx.route().map(|r| &**r);
+            self.no_upstream
+                .record(Self::cluster_name(route), Outcome::Unavailable);
+            Page::Unavailable
+        } else {
+            Page::Error
+        };
+        if let Ok(status) = StatusCode::from_u16(code) {
+            let written = async {
+                let (mut header, body) = self.synthesize(session, ctx, status, page, &[])?;
+                if let Some(compat) = self.http10_compat(session) {
+                    compat.fix_response(&mut header)?;
+                }
+                session.write_error_response(header, body).await
+            };
+            if let Err(e) = written.await {
+                error!("failed to send error response to downstream: {e}");
+            }
+        }
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+}
+
+/// An id for a request that arrived without one, unique across restarts as
+/// long as the clock does not go backwards.
+fn generate_request_id() -> impl std::fmt::Display {
+    static EPOCH: LazyLock<u64> = LazyLock::new(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    });
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::fmt::from_fn(move |f| write!(f, "{:x}-{:x}", *EPOCH, n))
+}
+
+/// ` flags=a,b` with the flags on for the request, for the access log.
+fn flags_on(evaluations: &[Evaluation]) -> String {
+    let on: Vec<&str> = evaluations
+        .iter()
+        .filter(|e| e.on)
+        .map(|e| e.flag.name.as_str())
+        .collect();
+    if on.is_empty() {
+        String::new()
+    } else {
+        format!(" flags={}", on.join(","))
+    }
+}
@@ -0,0 +1,260 @@
+//! Quarantine of uploads until they are scanned.
+//!
+//! On a route with a [`Quarantine`] the request body is written to a file in
+//! the quarantine directory, `<request id>.upload`, before anything else sees
+//! it. The file is then scanned, by a [`ContentScanner`] or a command
+//! run with the file's path as its last argument, and:
+//!
+//! - clean, the body goes upstream and the file is removed,
+//! - infected, the file stays in quarantine for inspection and the client
+//!   gets a `403` with the reference to it, the name of the file, in
+//!   [`REFERENCE_HEADER`]; the reason is written next to it, in
+//!   `<reference>.verdict`,
+//! - not scanned, because the scanner or the command failed, the file is
+//!   removed and the client gets a `503`.
+//!
+//! A command exits `0` for clean and `1` for infected; anything else, or
+//! running longer than the timeout, is a failure. The exchange is buffered,
+//! up to `max_size` each way, as with [`crate::scan`].
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use log::{info, warn};
+use pingora::http::RequestHeader;
+use pingora::proxy::Session;
+use pingora::{Error, ErrorType, Result};
+
+use crate::scan::{ContentScanner, Verdict};
+
+/// Response header with the reference of a quarantined upload.
+pub const REFERENCE_HEADER: &str = "x-quarantine-id";
+
+/// How a quarantined file is scanned.
+#[derive(Clone)]
+pub enum QuarantineScan {
+    /// Sent to a scanner, as the body of the request.
+    Scanner(Arc<ContentScanner>),
+    /// The program and its arguments, the file's path is appended.
+    Command(Vec<String>),
+}
+
+/// An upload written to quarantine and scanned.
+pub struct Held {
+    /// Names the upload's file in the quarantine directory, and is given to
+    /// the client when it is kept.
+    pub reference: String,
+    pub verdict: Verdict,
+    /// The body to send upstream, when it is clean.
+    pub body: Bytes,
+}
+
+pub struct Quarantine {
+    dir: PathBuf,
+    scan: QuarantineScan,
+    max_size: usize,
+    timeout: Duration,
+    /// for references of uploads whose request id is no file name
+    next: AtomicU64,
+}
+
+impl Quarantine {
+    /// Keep uploads of up to 100 MiB in `dir` until `scan` finds them clean.
+    pub fn new(dir: impl Into<PathBuf>, scan: QuarantineScan) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Quarantine {
+            dir,
+            scan,
+            max_size: 100 * 1024 * 1024,
+            timeout: Duration::from_secs(60),
+            next: AtomicU64::new(0),
+        })
+    }
+
+    /// Largest body accepted, larger ones are refused with a 413; also the
+    /// largest response buffered.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Time a command has to scan a file.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Write the body of the request of `session` to a quarantine file and
+    /// scan it. The file is named by `id`, the request id, unless it is not a
+    /// plain file name or already taken.
+    pub async fn hold(&self, session: &mut Session, id: &str) -> Result<Held> {
+        let (reference, path, mut file) = self.create(id).await?;
+        let mut size = 0;
+        while let Some(chunk) = session.read_request_body().await? {
+            size += chunk.len();
+            if size > self.max_size {
+                remove(&path).await;
+                return Error::e_explain(
+                    ErrorType::HTTPStatus(413),
+                    "request body too large to quarantine",
+                );
+            }
+            file = blocking(move || {
+                use std::io::Write;
+                file.write_all(&chunk).map(|()| file)
+            })
+            .await?;
+        }
+        drop(file);
+
+        let (verdict, body) = match &self.scan {
+            QuarantineScan::Scanner(scanner) => {
+                let body = read(&path).await?;
+                let verdict = scanner.check_request(session.req_header(), &body).await;
+                (verdict, body)
+            }
+            QuarantineScan::Command(command) => {
+                let verdict = self.run(command, &path, session.req_header()).await;
+                let body = match verdict {
+                    Verdict::Clean => read(&path).await?,
+                    _ => Bytes::new(),
+                };
+                (verdict, body)
+            }
+        };
+        match &verdict {
+            Verdict::Blocked(reason) => {
+                info!("quarantined upload {reference}: {reason}");
+                // the scanner's verdict is kept with the file
+                let note = path.with_extension("verdict");
+                let reason = reason.clone();
+                if let Err(e) = blocking(move || std::fs::write(note, reason)).await {
+                    warn!("could not note the verdict on upload {reference}: {e}");
+                }
+            }
+            _ => remove(&path).await,
+        }
+        Ok(Held {
+            reference,
+            verdict,
+            body,
+        })
+    }
+
+    /// A new quarantine file, by the name of `id` if it can be.
+    async fn create(&self, id: &str) -> Result<(String, PathBuf, File)> {
+        let plain = !id.is_empty()
+            && !id.starts_with('.')
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+        let mut reference = id.to_string();
+        if !plain {
+            reference = self.generated_reference();
+        }
+        loop {
+            let path = self.dir.join(format!("{reference}.upload"));
+            let created = tokio::task::spawn_blocking({
+                let path = path.clone();
+                move || File::create_new(path)
+            })
+            .await;
+            match created {
+                Ok(Ok(file)) => return Ok((reference, path, file)),
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    reference = self.generated_reference();
+                }
+                Ok(Err(e)) => {
+                    return Error::e_explain(ErrorType::InternalError, format!("quarantine: {e}"));
+                }
+                Err(e) => {
+                    return Error::e_explain(ErrorType::InternalError, format!("quarantine: {e}"));
+                }
+            }
+        }
+    }
+
+    fn generated_reference(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        format!("q{now:x}-{:x}", self.next.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Run `command` on the file at `path`, within the timeout.
+    async fn run(&self, command: &[String], path: &Path, req: &RequestHeader) -> Verdict {
+        let Some((program, args)) = command.split_first() else {
+            return Verdict::Unavailable;
+        };
+        let mut command = Command::new(program);
+        command.args(args).arg(path);
+        let deadline = Instant::now() + self.timeout;
+        let ran = tokio::task::spawn_blocking(move || {
+            let mut child = command.spawn()?;
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    return Ok(status.code());
+                }
+                if Instant::now() > deadline {
+                    child.kill()?;
+                    child.wait()?;
+                    return Err(std::io::Error::other("timed out"));
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        })
+        .await;
+        match ran {
+            Ok(Ok(Some(0))) => Verdict::Clean,
+            Ok(Ok(Some(1))) => Verdict::Blocked(format!("{program} found it infected")),
+            Ok(Ok(code)) => {
+                warn!(
+                    "{program} could not scan upload of {}: exit {code:?}",
+                    req.uri
+                );
+                Verdict::Unavailable
+            }
+            Ok(Err(e)) => {
+                warn!("{program} could not scan upload of {}: {e}", req.uri);
+                Verdict::Unavailable
+            }
+            Err(e) => {
+                warn!("{program} could not scan upload of {}: {e}", req.uri);
+                Verdict::Unavailable
+            }
+        }
+    }
+}
+
+/// Run file work off the runtime's threads.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+) -> Result<T> {
+    match tokio::task::spawn_blocking(work).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Error::e_explain(ErrorType::InternalError, format!("quarantine: {e}")),
+        Err(e) => Error::e_explain(ErrorType::InternalError, format!("quarantine: {e}")),
+    }
+}
+
+async fn read(path: &Path) -> Result<Bytes> {
+    let path = path.to_path_buf();
+    blocking(move || std::fs::read(path)).await.map(Bytes::from)
+}
+
+async fn remove(path: &Path) {
+    let path = path.to_path_buf();
+    if let Err(e) = blocking(move || std::fs::remove_file(path)).await {
+        warn!("could not remove a quarantined upload: {e}");
+    }
+}
@@ -0,0 +1,118 @@
+//! Parsing of `Range` / `Content-Range` byte ranges.
+//!
+//! Only single byte ranges are understood. Multi-range requests are treated
+//! as if no `Range` was sent, which RFC 9110 allows.
+
+use std::str;
+
+/// An inclusive, resolved byte range inside a representation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end < self.start
+    }
+
+    /// The overlap of two ranges, if any.
+    pub fn intersect(&self, other: &ByteRange) -> Option<ByteRange> {
+        let start = self.start.max(other.start);
+        let end = self.end.min(other.end);
+        (start <= end).then_some(ByteRange { start, end })
+    }
+}
+
+/// A `Range: bytes=...` request as written by the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangeSpec {
+    /// `bytes=start-` or `bytes=start-end`
+    FromTo(u64, Option<u64>),
+    /// `bytes=-n`: the last `n` bytes
+    Suffix(u64),
+}
+
+impl RangeSpec {
+    /// Resolve against a representation of `len` bytes; `None` means the
+    /// range is unsatisfiable.
+    pub fn resolve(&self, len: u64) -> Option<ByteRange> {
+        if len == 0 {
+            return None;
+        }
+        match *self {
+            RangeSpec::FromTo(start, end) => {
+                if start >= len {
+                    return None;
+                }
+                let end = end.map_or(len - 1, |e| e.min(len - 1));
+                Some(ByteRange { start, end })
+            }
+            RangeSpec::Suffix(0) => None,
+            RangeSpec::Suffix(n) => Some(ByteRange {
+                start: len.saturating_sub(n),
+                end: len - 1,
+            }),
+        }
+    }
+
+    /// The range if it is fully bounded, i.e. knowable without the length.
+    pub fn bounded(&self) -> Option<ByteRange> {
+        match *self {
+            RangeSpec::FromTo(start, Some(end)) => Some(ByteRange { start, end }),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a `Range` header value holding a single byte range.
+pub fn parse_range(value: &[u8]) -> Option<RangeSpec> {
+    let value = str::from_utf8(value).ok()?.trim();
+    let spec = value.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        return end.parse().ok().map(RangeSpec::Suffix);
+    }
+    let start = start.parse().ok()?;
+    if end.is_empty() {
+        return Some(RangeSpec::FromTo(start, None));
+    }
+    let end = end.parse().ok()?;
+    (end >= start).then_some(RangeSpec::FromTo(start, Some(end)))
+}
+
+/// Parse `Content-Range: bytes start-end/total`; `total` is `None` for `*`.
+pub fn parse_content_range(value: &[u8]) -> Option<(ByteRange, Option<u64>)> {
+    let value = str::from_utf8(value).ok()?.trim();
+    let rest = value.strip_prefix("bytes ")?;
+    let (range, total) = rest.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let range = ByteRange {
+        start: start.trim().parse().ok()?,
+        end: end.trim().parse().ok()?,
+    };
+    let total = match total.trim() {
+        "*" => None,
+        t => Some(t.parse().ok()?),
+    };
+    Some((range, total))
+}
+
+/// Format a `Content-Range` value for a satisfied range.
+pub fn content_range(range: ByteRange, total: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end, total)
+}
+
+/// Format the `Content-Range` value of a `416` response.
+pub fn unsatisfied_range(total: u64) -> String {
+    format!("bytes */{total}")
+}
@@ -0,0 +1,118 @@
+//! Readiness for rotation.
+//!
+//! The proxy accepts traffic once the [`crate::startup::StartupProbe`] is
+//! done, but taking a full share of it right away, during a scale-up, means
+//! every request of the first minutes misses the cache and waits on a new
+//! upstream connection. [`Readiness`] answers `GET /admin/ready`, the check
+//! of the load balancer putting instances into rotation, and can require
+//! more than the probe: a cache warmed to a number of objects, and a number
+//! of upstream connections opened, by warm-up traffic or a share of the
+//! real one. Once ready, the instance stays ready, so evictions and idle
+//! connections closing do not take it out of rotation again.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use serde_json::{Value, json};
+
+use crate::cache::MemoryCache;
+use crate::startup::StartupProbe;
+
+#[derive(Clone, Debug, Default)]
+pub struct ReadinessConfig {
+    /// Objects the cache must hold.
+    pub min_cached_objects: Option<usize>,
+    /// Upstream connections the proxy must have opened.
+    pub min_upstream_connections: Option<usize>,
+}
+
+pub struct Readiness {
+    config: ReadinessConfig,
+    probe: Option<Arc<StartupProbe>>,
+    cache: Option<Arc<MemoryCache>>,
+    upstream_connections: AtomicUsize,
+    ready: AtomicBool,
+}
+
+impl Readiness {
+    pub fn new(config: ReadinessConfig) -> Self {
+        Readiness {
+            config,
+            probe: None,
+            cache: None,
+            upstream_connections: AtomicUsize::new(0),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    /// Wait for `probe` to be done.
+    pub fn with_probe(mut self, probe: Arc<StartupProbe>) -> Self {
+        self.probe = Some(probe);
+        self
+    }
+
+    /// Count the objects of `cache` for [`ReadinessConfig::min_cached_objects`].
+    pub fn with_cache(mut self, cache: Arc<MemoryCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Count a new connection to an upstream.
+    pub(crate) fn upstream_connected(&self) {
+        self.upstream_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// What the instance still waits for, as `(condition, have, need)`.
+    fn waiting_for(&self) -> Vec<(&'static str, usize, usize)> {
+        let mut waiting = Vec::new();
+        if let Some(probe) = &self.probe
+            && !probe.done()
+        {
+            waiting.push(("startup_probe", 0, 1));
+        }
+        if let Some(need) = self.config.min_cached_objects {
+            let have = self.cache.as_ref().map_or(0, |c| c.objects());
+            if have < need {
+                waiting.push(("cached_objects", have, need));
+            }
+        }
+        if let Some(need) = self.config.min_upstream_connections {
+            let have = self.upstream_connections.load(Ordering::Relaxed);
+            if have < need {
+                waiting.push(("upstream_connections", have, need));
+            }
+        }
+        waiting
+    }
+
+    /// Whether the instance is ready, latching once it is.
+    pub fn is_ready(&self) -> bool {
+        if self.ready.load(Ordering::Relaxed) {
+            return true;
+        }
+        let ready = self.waiting_for().is_empty();
+        if ready {
+            self.ready.store(true, Ordering::Relaxed);
+        }
+        ready
+    }
+
+    pub fn to_json(&self) -> Value {
+        let ready = self.is_ready();
+        let waiting: Vec<Value> = if ready {
+            Vec::new()
+        } else {
+            self.waiting_for()
+                .into_iter()
+                .map(|(condition, have, need)| {
+                    json!({ "condition": condition, "have": have, "need": need })
+                })
+                .collect()
+        };
+        json!({
+            "ready": ready,
+            "waiting_for": waiting,
+            "upstream_connections": self.upstream_connections.load(Ordering::Relaxed),
+        })
+    }
+}
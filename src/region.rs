@@ -0,0 +1,271 @@
+//! Failover to a secondary region.
+//!
+//! Routes can have a cluster in a secondary region besides their own, see
+//! [`crate::route::Route::secondary_region`], and so can the default
+//! upstreams. [`RegionFailover`] watches the
+//! health of every local cluster, the default upstreams and those of the
+//! routes, together: when too few of their upstreams are healthy, none by
+//! default, and the secondary clusters have a healthy one, the region fails
+//! over and requests with a secondary cluster go there. It fails back once
+//! the local clusters have been healthy again for `fail_back_after`, so a
+//! flapping region is not left and rejoined every second.
+//!
+//! Clusters without a secondary one are not affected; the
+//! [`crate::no_upstream::NoUpstream`] policy of their routes still applies.
+//!
+//! DNS automation follows the proxy's view through `GET /admin/region`: the
+//! region that should get traffic, `active`, and the health it decided on,
+//! see [`RegionFailover::to_json`].
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use log::warn;
+use pingora::lb::{LoadBalancer, selection::RoundRobin};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde_json::{Value, json};
+
+use crate::route::{Route, SharedRouter};
+
+/// The cluster requests of `route` go to: its own, or the `default` one for
+/// routes without and requests without a route, or the one in the secondary
+/// region while `region` is failed over and there is one.
+pub fn cluster<'a>(
+    region: Option<&'a RegionFailover>,
+    route: Option<&'a Route>,
+    default: &'a LoadBalancer<RoundRobin>,
+) -> &'a LoadBalancer<RoundRobin> {
+    if let Some(region) = region.filter(|r| r.failed_over()) {
+        let secondary = match route {
+            Some(route) if route.upstreams.is_some() => route.secondary_region.as_deref(),
+            Some(route) => route
+                .secondary_region
+                .as_deref()
+                .or(region.secondary_default()),
+            None => region.secondary_default(),
+        };
+        if let Some(secondary) = secondary {
+            return secondary;
+        }
+    }
+    route
+        .and_then(|r| r.upstreams.as_deref())
+        .unwrap_or(default)
+}
+
+/// Healthy upstreams out of all, of a set of clusters.
+#[derive(Clone, Copy, Debug, Default)]
+struct Health {
+    healthy: usize,
+    total: usize,
+}
+
+impl Health {
+    fn of<'a>(clusters: impl IntoIterator<Item = &'a LoadBalancer<RoundRobin>>) -> Self {
+        let mut health = Health::default();
+        for cluster in clusters {
+            let backends = cluster.backends();
+            for backend in backends.get_backend().iter() {
+                health.total += 1;
+                if backends.ready(backend) {
+                    health.healthy += 1;
+                }
+            }
+        }
+        health
+    }
+
+    fn fraction(&self) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => self.healthy as f64 / total as f64,
+        }
+    }
+
+    fn to_json(self) -> Value {
+        json!({ "healthy": self.healthy, "total": self.total })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    local: Health,
+    secondary: Health,
+    /// Since when the region is failed over or back, in seconds since the
+    /// epoch.
+    since: u64,
+    /// Since when the local clusters are healthy again while failed over.
+    recovering: Option<Instant>,
+}
+
+pub struct RegionFailover {
+    local: String,
+    secondary: String,
+    default: Arc<LoadBalancer<RoundRobin>>,
+    secondary_default: Option<Arc<LoadBalancer<RoundRobin>>>,
+    router: Arc<SharedRouter>,
+    min_healthy: f64,
+    fail_back_after: Duration,
+    interval: Duration,
+    failed_over: AtomicBool,
+    failovers: AtomicU64,
+    state: Mutex<State>,
+}
+
+impl RegionFailover {
+    /// Fail over from region `local`, whose clusters are `default` and those
+    /// of the routes of `router`, to `secondary`.
+    pub fn new(
+        local: impl Into<String>,
+        secondary: impl Into<String>,
+        default: Arc<LoadBalancer<RoundRobin>>,
+        router: Arc<SharedRouter>,
+    ) -> Self {
+        RegionFailover {
+            local: local.into(),
+            secondary: secondary.into(),
+            default,
+            secondary_default: None,
+            router,
+            min_healthy: 0.0,
+            fail_back_after: Duration::from_secs(30),
+            interval: Duration::from_secs(1),
+            failed_over: AtomicBool::new(false),
+            failovers: AtomicU64::new(0),
+            state: Mutex::default(),
+        }
+    }
+
+    /// The default upstreams in the secondary region, for requests without
+    /// a route or on routes without a cluster of their own.
+    pub fn with_secondary_default(mut self, cluster: Arc<LoadBalancer<RoundRobin>>) -> Self {
+        self.secondary_default = Some(cluster);
+        self
+    }
+
+    /// Also fail over while under this share of the local upstreams is
+    /// healthy, as `0.25`; by default only when none is.
+    pub fn with_min_healthy(mut self, min_healthy: f64) -> Self {
+        self.min_healthy = min_healthy;
+        self
+    }
+
+    pub fn with_fail_back_after(mut self, fail_back_after: Duration) -> Self {
+        self.fail_back_after = fail_back_after;
+        self
+    }
+
+    /// Whether requests go to the secondary clusters.
+    pub fn failed_over(&self) -> bool {
+        self.failed_over.load(Ordering::Relaxed)
+    }
+
+    pub fn secondary_default(&self) -> Option<&LoadBalancer<RoundRobin>> {
+        self.secondary_default.as_deref()
+    }
+
+    /// The region that should get traffic.
+    pub fn active(&self) -> &str {
+        match self.failed_over() {
+            true => &self.secondary,
+            false => &self.local,
+        }
+    }
+
+    /// Look at the health of both regions and fail over or back.
+    fn update(&self) {
+        let router = self.router.load();
+        let mut seen = HashSet::new();
+        let mut distinct =
+            |cluster: &&Arc<LoadBalancer<RoundRobin>>| seen.insert(Arc::as_ptr(cluster));
+        let local = std::iter::once(&self.default)
+            .chain(router.routes().iter().filter_map(|r| r.upstreams.as_ref()))
+            .filter(&mut distinct)
+            .map(|c| &**c);
+        let local = Health::of(local);
+        let secondary = self
+            .secondary_default
+            .iter()
+            .chain(
+                router
+                    .routes()
+                    .iter()
+                    .filter_map(|r| r.secondary_region.as_ref()),
+            )
+            .filter(&mut distinct)
+            .map(|c| &**c);
+        let secondary = Health::of(secondary);
+
+        let unhealthy = local.healthy == 0 || local.fraction() < self.min_healthy;
+        let mut state = self.state.lock().unwrap();
+        state.local = local;
+        state.secondary = secondary;
+        if !self.failed_over() {
+            if unhealthy && secondary.healthy > 0 {
+                warn!(
+                    "region {} has {} of {} upstreams healthy, failing over to {}",
+                    self.local, local.healthy, local.total, self.secondary
+                );
+                self.failed_over.store(true, Ordering::Relaxed);
+                self.failovers.fetch_add(1, Ordering::Relaxed);
+                state.since = unix_time();
+                state.recovering = None;
+            }
+            return;
+        }
+        if unhealthy {
+            state.recovering = None;
+            return;
+        }
+        let recovering = *state.recovering.get_or_insert_with(Instant::now);
+        if recovering.elapsed() >= self.fail_back_after {
+            warn!(
+                "region {} has {} of {} upstreams healthy again, failing back from {}",
+                self.local, local.healthy, local.total, self.secondary
+            );
+            self.failed_over.store(false, Ordering::Relaxed);
+            state.since = unix_time();
+            state.recovering = None;
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        json!({
+            "local": self.local,
+            "secondary": self.secondary,
+            "active": self.active(),
+            "failed_over": self.failed_over(),
+            "since": state.since,
+            "failovers": self.failovers.load(Ordering::Relaxed),
+            "health": {
+                "local": state.local.to_json(),
+                "secondary": state.secondary.to_json(),
+            },
+        })
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[async_trait]
+impl BackgroundService for RegionFailover {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        self.state.lock().unwrap().since = unix_time();
+        loop {
+            self.update();
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(self.interval) => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Rendezvous hashing.
+//!
+//! Highest random weight hashing, an alternative to the [`Continuum`] ring
+//! for clusters of a few nodes: every node scores every key, and the key
+//! maps to the node scoring highest. There are no points to lay out or
+//! search, a lookup scores every node, so it suits small sets. A node that
+//! leaves moves only its own keys, each to the node that scored next on it,
+//! and a node that joins takes only the keys it scores highest on. Nodes
+//! hold shares of the keys in exact proportion to their weight, where a ring
+//! of a few points per node strays from it widely.
+//!
+//! A key is hashed once and its hash mixed with the hash of each node's
+//! `"<ip>:<port>"` into a score, weighted as `-weight / ln(x)` of the mix `x`
+//! taken as a fraction in (0, 1), which gives a node weighted twice as heavy
+//! twice the keys. Keys and nodes are hashed with the [`Crc32`] of nginx's
+//! ring unless [`Rendezvous::with_hasher`] picks another hash function.
+//!
+//! [`Continuum`]: crate::consistent_hash::Continuum
+
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+
+use crate::consistent_hash::{Bucket, Crc32, Hasher, Lookup, PoolHint};
+
+#[derive(Clone, Debug, Default)]
+pub struct Rendezvous<H = Crc32> {
+    nodes: Box<[SocketAddr]>,
+    weights: Box<[u32]>,
+    /// by node index, hashed once rather than on every lookup
+    seeds: Box<[u32]>,
+    pools: Box<[PoolHint]>,
+    hasher: H,
+}
+
+impl Rendezvous {
+    /// The selector of `buckets`, hashed with [`Crc32`].
+    pub fn new(buckets: &[Bucket]) -> Self {
+        Rendezvous::with_hasher(buckets, Crc32)
+    }
+}
+
+impl<H: Hasher> Rendezvous<H> {
+    /// The selector of `buckets`, with nodes and keys hashed by `hasher`.
+    pub fn with_hasher(buckets: &[Bucket], hasher: H) -> Self {
+        let nodes: Box<[SocketAddr]> = buckets.iter().map(|b| b.node).collect();
+        Rendezvous {
+            weights: buckets.iter().map(|b| b.weight).collect(),
+            seeds: nodes
+                .iter()
+                .map(|node| hasher.hash(node.to_string().as_bytes()))
+                .collect(),
+            pools: nodes.iter().map(PoolHint::of).collect(),
+            nodes,
+            hasher,
+        }
+    }
+
+    /// The number of nodes.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// What nodes score `key` by.
+    pub fn hash(&self, key: &[u8]) -> u32 {
+        self.hasher.hash(key)
+    }
+
+    /// The node `key` maps to, `None` without nodes.
+    pub fn node(&self, key: &[u8]) -> Option<SocketAddr> {
+        self.lookup(key).map(|l| l.node)
+    }
+
+    /// The node `key` maps to with its pool, `None` without nodes.
+    pub fn lookup(&self, key: &[u8]) -> Option<Lookup> {
+        let hash = self.hash(key);
+        (0..self.nodes.len())
+            .map(|node| (self.score(hash, node), node))
+            .max_by(|a, b| self.rank(*a, *b))
+            .map(|(_, node)| self.lookup_of(node))
+    }
+
+    fn lookup_of(&self, node: usize) -> Lookup {
+        Lookup {
+            node: self.nodes[node],
+            pool: self.pools[node],
+        }
+    }
+
+    /// The nodes from the highest scoring on `key` down, each once, for
+    /// falling back when the node of `key` cannot be used: the node a key
+    /// moves to when its node leaves is the next one.
+    pub fn node_iter(&self, key: &[u8]) -> NodeIterator<'_, H> {
+        NodeIterator {
+            order: self.order(key).into_iter(),
+            rendezvous: self,
+        }
+    }
+
+    /// The `n` highest scoring nodes on `key`, highest first. Fewer if there
+    /// are fewer nodes.
+    pub fn nodes(&self, key: &[u8], n: usize) -> Vec<SocketAddr> {
+        self.node_iter(key).take(n).copied().collect()
+    }
+
+    /// [`Rendezvous::nodes`] with their pools.
+    pub fn lookups(&self, key: &[u8], n: usize) -> Vec<Lookup> {
+        let mut order = self.order(key);
+        order.truncate(n);
+        order.into_iter().map(|node| self.lookup_of(node)).collect()
+    }
+
+    /// Node indices by descending score on `key`.
+    fn order(&self, key: &[u8]) -> Vec<usize> {
+        let hash = self.hash(key);
+        let mut scored: Vec<(f64, usize)> = (0..self.nodes.len())
+            .map(|node| (self.score(hash, node), node))
+            .collect();
+        scored.sort_unstable_by(|a, b| self.rank(*b, *a));
+        scored.into_iter().map(|(_, node)| node).collect()
+    }
+
+    /// The weighted score of `node` on a key hashing to `hash`.
+    fn score(&self, hash: u32, node: usize) -> f64 {
+        let mixed = mix(u64::from(hash) << 32 | u64::from(self.seeds[node]));
+        // the top 53 bits as a fraction strictly between 0 and 1
+        let x = ((mixed >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        -f64::from(self.weights[node]) / x.ln()
+    }
+
+    /// Orders scored nodes, equal scores by address so the winner does not
+    /// depend on the order of the buckets.
+    fn rank(&self, (a, i): (f64, usize), (b, j): (f64, usize)) -> Ordering {
+        a.total_cmp(&b)
+            .then_with(|| self.nodes[j].cmp(&self.nodes[i]))
+    }
+}
+
+/// The nodes by descending score, see [`Rendezvous::node_iter`].
+pub struct NodeIterator<'a, H = Crc32> {
+    order: std::vec::IntoIter<usize>,
+    rendezvous: &'a Rendezvous<H>,
+}
+
+impl<'a, H: Hasher> Iterator for NodeIterator<'a, H> {
+    type Item = &'a SocketAddr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.order.next().map(|node| &self.rendezvous.nodes[node])
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.order.size_hint()
+    }
+}
+
+/// A step of SplitMix64: spreads the key and node hashes over all bits.
+fn mix(x: u64) -> u64 {
+    let mut x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: usize = 20_000;
+
+    fn buckets(weights: &[u32]) -> Vec<Bucket> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| Bucket::new(([10, 0, 0, i as u8 + 1], 80).into(), weight))
+            .collect()
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("/objects/{i}").into_bytes()
+    }
+
+    #[test]
+    fn keys_are_shared_by_weight() {
+        let weights = [1, 1, 2, 4];
+        let nodes = buckets(&weights);
+        let rendezvous = Rendezvous::new(&nodes);
+        let mut keys = vec![0; nodes.len()];
+        for i in 0..KEYS {
+            let node = rendezvous.node(&key(i)).expect("node");
+            keys[nodes.iter().position(|b| b.node == node).unwrap()] += 1;
+        }
+        let total: u32 = weights.iter().sum();
+        for (held, weight) in keys.into_iter().zip(weights) {
+            let expected = KEYS as f64 * f64::from(weight) / f64::from(total);
+            assert!(
+                (f64::from(held) - expected).abs() < expected * 0.1,
+                "{held} keys for {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn removing_a_node_moves_only_its_keys() {
+        let all = buckets(&[1; 5]);
+        let before = Rendezvous::new(&all);
+        let after = Rendezvous::new(&[&all[..2], &all[3..]].concat());
+        let gone = all[2].node;
+        let mut moved = 0;
+        for i in 0..KEYS {
+            let key = key(i);
+            let was = before.nodes(&key, 2);
+            let is = after.node(&key).expect("node");
+            if was[0] == gone {
+                // to the node that scored next on it
+                assert_eq!(is, was[1]);
+                moved += 1;
+            } else {
+                assert_eq!(is, was[0]);
+            }
+        }
+        assert!(moved > KEYS / 6 && moved < KEYS / 4, "{moved} keys moved");
+    }
+}
@@ -0,0 +1,58 @@
+//! Reads served by the replicas of a key.
+//!
+//! For replicated caches and storage behind the proxy: the cluster is laid out
+//! on a [`Continuum`], a key's replicas are the first distinct nodes from the
+//! key on, and a `GET` is sent to all of them at once. The first successful
+//! response is served and the others are abandoned.
+
+use pingora::lb::{Backend, LoadBalancer, selection::RoundRobin};
+
+use crate::consistent_hash::{ClusterRing, HashFunction, Layout, PoolHint};
+
+pub struct FanOut {
+    replicas: usize,
+    max_body: usize,
+    ring: ClusterRing,
+}
+
+impl FanOut {
+    /// Read from `replicas` nodes, buffering responses of up to `max_body`
+    /// bytes; larger ones are proxied the regular way.
+    pub fn new(replicas: usize, max_body: usize) -> Self {
+        FanOut {
+            replicas,
+            max_body,
+            ring: ClusterRing::default(),
+        }
+    }
+
+    /// Hash keys, and the points of an nginx layout, by `hasher`.
+    pub fn with_hasher(mut self, hasher: HashFunction) -> Self {
+        self.ring = ClusterRing::new(self.ring.layout(), hasher);
+        self
+    }
+
+    /// Lay the cluster out by `layout`.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.ring = ClusterRing::new(layout, self.ring.hasher());
+        self
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    /// The replicas of `key` in `upstreams` with their pools, nearest first.
+    pub(crate) fn replicas(
+        &self,
+        upstreams: &LoadBalancer<RoundRobin>,
+        key: &[u8],
+    ) -> Vec<(Backend, PoolHint)> {
+        let ring = self.ring.get(upstreams);
+        ring.continuum
+            .lookups(key, self.replicas)
+            .iter()
+            .filter_map(|l| Some((ring.backend(&l.node)?.clone(), l.pool)))
+            .collect()
+    }
+}
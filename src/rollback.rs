@@ -0,0 +1,431 @@
+//! Versions of the route configuration, with rollback.
+//!
+//! [`RouterVersions`] numbers every router it installs in a [`SharedRouter`]
+//! and keeps the last known good one next to the live one. A router is made
+//! of the routes of each [`RouteSource`], and a reload replaces those of one
+//! source, the others staying as the live version has them; rolling back
+//! returns to the routes of every source of the version. A reload that
+//! fails [`Router::validate`] is refused. One that passes is watched for a
+//! while: if the share of 5xx responses spikes above the limit it is rolled
+//! back on the spot, otherwise it becomes the last known good version once
+//! the watch is over. Known good versions can be written to disk for
+//! operators to see what the proxy would return to. What matching costs
+//! for each route is logged as every version is installed.
+//!
+//! Responses are counted towards a watch on sharded counters, see
+//! [`crate::sharded`]; only 5xx responses, which may roll the version back,
+//! and the first response after the watch is over take the lock of the
+//! versions.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use arc_swap::ArcSwapOption;
+use log::{info, warn};
+use serde_json::{Value, json};
+
+use crate::route::{Route, Router, SharedRouter};
+use crate::sharded::Counter;
+
+/// When a reloaded version counts as broken.
+#[derive(Clone, Debug)]
+pub struct ReloadWatch {
+    /// How long a reloaded version is watched.
+    pub window: Duration,
+    /// Share of 5xx responses above which it is rolled back.
+    pub max_error_rate: f64,
+    /// Responses needed before the share is trusted.
+    pub min_requests: u64,
+}
+
+impl Default for ReloadWatch {
+    fn default() -> Self {
+        ReloadWatch {
+            window: Duration::from_secs(60),
+            max_error_rate: 0.2,
+            min_requests: 20,
+        }
+    }
+}
+
+/// Where the routes of a router come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RouteSource {
+    /// The configuration file and the command line.
+    Config,
+    /// Labelled containers, see [`crate::discovery::DockerWatcher`].
+    Docker,
+}
+
+impl RouteSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RouteSource::Config => "config",
+            RouteSource::Docker => "docker",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Version {
+    number: u64,
+    router: Arc<Router>,
+    /// the routes of `router`, by where they come from
+    sources: Arc<BTreeMap<RouteSource, Vec<Route>>>,
+    loaded: SystemTime,
+}
+
+struct Watch {
+    since: Instant,
+    requests: Counter,
+    errors: Counter,
+}
+
+struct State {
+    current: Version,
+    good: Version,
+    /// the good version before `good`, for rolling back a good version
+    prior_good: Option<Version>,
+    watch: Option<Arc<Watch>>,
+    next_number: u64,
+    rollbacks: u64,
+    last_rejected: Option<String>,
+}
+
+pub struct RouterVersions {
+    router: Arc<SharedRouter>,
+    watch: ReloadWatch,
+    snapshot: Option<PathBuf>,
+    /// the watch of `state`, read by responses without the lock
+    watching: ArcSwapOption<Watch>,
+    state: Mutex<State>,
+}
+
+impl RouterVersions {
+    /// Version the router `router` holds now as version 1, known good, its
+    /// routes those of [`RouteSource::Config`].
+    pub fn new(router: Arc<SharedRouter>, watch: ReloadWatch) -> Self {
+        let current = router.load();
+        let routes = current.routes().iter().map(|r| Route::clone(r)).collect();
+        let version = Version {
+            number: 1,
+            router: current,
+            sources: Arc::new(BTreeMap::from([(RouteSource::Config, routes)])),
+            loaded: SystemTime::now(),
+        };
+        log_match_costs(&version);
+        RouterVersions {
+            router,
+            watch,
+            snapshot: None,
+            watching: ArcSwapOption::empty(),
+            state: Mutex::new(State {
+                current: version.clone(),
+                good: version,
+                prior_good: None,
+                watch: None,
+                next_number: 2,
+                rollbacks: 0,
+                last_rejected: None,
+            }),
+        }
+    }
+
+    /// Write every known good version to `path`, as JSON.
+    pub fn with_snapshot(self, path: impl Into<PathBuf>) -> Self {
+        let versions = RouterVersions {
+            snapshot: Some(path.into()),
+            ..self
+        };
+        versions.write_snapshot(&versions.state.lock().unwrap().good);
+        versions
+    }
+
+    /// Install the live routes with those of `source` replaced by `routes`
+    /// if they are valid, returning the version number, and watch them.
+    pub fn reload(&self, source: RouteSource, routes: Vec<Route>) -> Result<u64, String> {
+        let mut state = self.state.lock().unwrap();
+        self.settle(&mut state);
+        let sources = with_source(&state.current.sources, source, routes);
+        let router = Router::new(sources.values().flatten().cloned().collect());
+        if let Err(e) = router.validate() {
+            warn!("refusing {} routes: {e}", source.as_str());
+            state.last_rejected = Some(e.clone());
+            return Err(e);
+        }
+        let version = Version {
+            number: state.next_number,
+            router: Arc::new(router),
+            sources: Arc::new(sources),
+            loaded: SystemTime::now(),
+        };
+        state.next_number += 1;
+        log_match_costs(&version);
+        self.router.store(version.router.clone());
+        info!(
+            "routes version {} loaded with new {} routes, watching it",
+            version.number,
+            source.as_str()
+        );
+        state.current = version;
+        let watch = Arc::new(Watch {
+            since: Instant::now(),
+            requests: Counter::default(),
+            errors: Counter::default(),
+        });
+        state.watch = Some(watch.clone());
+        self.watching.store(Some(watch));
+        Ok(state.current.number)
+    }
+
+    /// The router of the live routes with those of `source` replaced by
+    /// `routes`, as [`RouterVersions::reload`] would install it, validated or
+    /// not.
+    pub fn candidate(&self, source: RouteSource, routes: Vec<Route>) -> Router {
+        let state = self.state.lock().unwrap();
+        let sources = with_source(&state.current.sources, source, routes);
+        Router::new(sources.values().flatten().cloned().collect())
+    }
+
+    /// Count a response status towards the watch of a reloaded version.
+    pub fn observe(&self, status: u16) {
+        let watching = self.watching.load();
+        let Some(watch) = &*watching else {
+            return;
+        };
+        watch.requests.add(1);
+        if status >= 500 {
+            watch.errors.add(1);
+        } else if watch.since.elapsed() < self.watch.window {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        self.settle(&mut state);
+        // the watch may have ended, or another begun, since
+        if !state.watch.as_ref().is_some_and(|w| Arc::ptr_eq(w, watch)) {
+            return;
+        }
+        let requests = watch.requests.sum();
+        let rate = watch.errors.sum() as f64 / requests as f64;
+        if requests >= self.watch.min_requests && rate > self.watch.max_error_rate {
+            warn!(
+                "routes version {} answered {:.0}% of {requests} requests with 5xx, rolling back to version {}",
+                state.current.number,
+                rate * 100.0,
+                state.good.number
+            );
+            let good = state.good.clone();
+            self.install(&mut state, good);
+        }
+    }
+
+    /// Go back to the last known good version, or the one before it if the
+    /// live version is known good; the version number now live.
+    pub fn rollback(&self) -> Result<u64, &'static str> {
+        let mut state = self.state.lock().unwrap();
+        self.settle(&mut state);
+        let target = if state.current.number != state.good.number {
+            state.good.clone()
+        } else {
+            let prior = state
+                .prior_good
+                .take()
+                .ok_or("no earlier version to roll back to")?;
+            state.good = prior.clone();
+            self.write_snapshot(&prior);
+            prior
+        };
+        info!(
+            "rolling routes back from version {} to version {}",
+            state.current.number, target.number
+        );
+        self.install(&mut state, target);
+        Ok(state.current.number)
+    }
+
+    fn install(&self, state: &mut State, version: Version) {
+        self.router.store(version.router.clone());
+        state.current = version;
+        state.watch = None;
+        state.rollbacks += 1;
+        self.watching.store(None);
+    }
+
+    /// Make the watched version the last known good one once its watch is
+    /// over.
+    fn settle(&self, state: &mut State) {
+        if state
+            .watch
+            .as_ref()
+            .is_none_or(|w| w.since.elapsed() < self.watch.window)
+        {
+            return;
+        }
+        info!("routes version {} is known good", state.current.number);
+        state.watch = None;
+        self.watching.store(None);
+        let current = state.current.clone();
+        state.prior_good = Some(std::mem::replace(&mut state.good, current));
+        self.write_snapshot(&state.good);
+    }
+
+    fn write_snapshot(&self, version: &Version) {
+        let Some(path) = &self.snapshot else {
+            return;
+        };
+        let body = describe(version).to_string();
+        if let Err(e) = std::fs::write(path, body) {
+            warn!("writing the known good routes to {}: {e}", path.display());
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut state = self.state.lock().unwrap();
+        self.settle(&mut state);
+        let watch = state.watch.as_ref().map(|w| {
+            json!({
+                "remaining_ms": self.watch.window.saturating_sub(w.since.elapsed()).as_millis() as u64,
+                "requests": w.requests.sum(),
+                "errors": w.errors.sum(),
+            })
+        });
+        json!({
+            "current": describe(&state.current),
+            "match_costs": match_costs(&state.current.router),
+            "last_good": state.good.number,
+            "watch": watch,
+            "rollbacks_total": state.rollbacks,
+            "last_rejected": state.last_rejected,
+        })
+    }
+}
+
+/// The routes of `sources` with those of `source` replaced by `routes`.
+fn with_source(
+    sources: &BTreeMap<RouteSource, Vec<Route>>,
+    source: RouteSource,
+    routes: Vec<Route>,
+) -> BTreeMap<RouteSource, Vec<Route>> {
+    let mut sources = sources.clone();
+    sources.insert(source, routes);
+    sources
+}
+
+fn match_costs(router: &Router) -> Vec<Value> {
+    router
+        .match_costs()
+        .into_iter()
+        .map(|cost| {
+            json!({
+                "route": cost.route,
+                "checked_before": cost.checked_before,
+                "pattern": cost.pattern,
+                "compile_us": cost.compile.as_micros() as u64,
+            })
+        })
+        .collect()
+}
+
+/// Log the costliest route to match of `version`, and the pattern compile
+/// time of all.
+fn log_match_costs(version: &Version) {
+    let costs = version.router.match_costs();
+    let compile: Duration = costs.iter().map(|c| c.compile).sum();
+    let patterns = costs.iter().filter(|c| c.pattern).count();
+    let worst = costs.iter().max_by_key(|c| c.checked_before);
+    info!(
+        "routes version {}: {} routes, {patterns} path patterns compiled in {compile:?}{}",
+        version.number,
+        costs.len(),
+        worst.map_or(String::new(), |c| format!(
+            ", route {} is checked after {} others",
+            c.route, c.checked_before
+        )),
+    );
+}
+
+fn describe(version: &Version) -> Value {
+    let loaded = version
+        .loaded
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let routes: Vec<Value> = version
+        .router
+        .routes()
+        .iter()
+        .map(|r| describe_route(r))
+        .collect();
+    json!({
+        "version": version.number,
+        "loaded": loaded.as_secs(),
+        "routes": routes,
+    })
+}
+
+fn describe_route(route: &Route) -> Value {
+    let upstreams: Option<Vec<String>> = route.upstreams.as_ref().map(|lb| {
+        lb.backends()
+            .get_backend()
+            .iter()
+            .map(|b| b.addr.to_string())
+            .collect()
+    });
+    json!({
+        "name": route.name,
+        "host": route.host,
+        "path_prefix": route.path_prefix,
+        "path_pattern": route.path_pattern,
+        "upstreams": upstreams,
+        "maintenance": route.maintenance,
+        "redirect": route.redirect,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{ReloadWatch, RouteSource, RouterVersions};
+    use crate::route::{Route, Router, SharedRouter};
+
+    fn names(router: &SharedRouter) -> Vec<String> {
+        let mut names: Vec<String> = router
+            .load()
+            .routes()
+            .iter()
+            .map(|r| r.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn reloads_replace_the_routes_of_their_source() {
+        let router = Arc::new(SharedRouter::new(Router::new(vec![Route::new(
+            "site", "/",
+        )])));
+        let versions = RouterVersions::new(router.clone(), ReloadWatch::default());
+
+        let app = vec![Route::new("docker:/app/", "/app/")];
+        assert_eq!(versions.reload(RouteSource::Docker, app), Ok(2));
+        assert_eq!(names(&router), ["docker:/app/", "site"]);
+
+        let config = vec![Route::new("site", "/"), Route::new("api", "/api/")];
+        assert_eq!(versions.reload(RouteSource::Config, config), Ok(3));
+        assert_eq!(names(&router), ["api", "docker:/app/", "site"]);
+
+        // a config route clashing with a container's is refused
+        let clash = vec![Route::new("docker:/app/", "/other/")];
+        assert!(versions.reload(RouteSource::Config, clash).is_err());
+        assert_eq!(names(&router), ["api", "docker:/app/", "site"]);
+
+        // back to version 1, without the containers and the api
+        assert_eq!(versions.rollback(), Ok(1));
+        assert_eq!(names(&router), ["site"]);
+        let app = vec![Route::new("docker:/app/", "/app/")];
+        assert_eq!(versions.reload(RouteSource::Docker, app), Ok(4));
+        assert_eq!(names(&router), ["docker:/app/", "site"]);
+    }
+}
@@ -0,0 +1,509 @@
+//! Request routing.
+//!
+//! A route matches on an optional host, a path prefix and an optional path
+//! pattern, and carries the per-route feature settings. The most specific
+//! match wins: routes with a host beat host-less ones, then longer prefixes
+//! beat shorter ones, then routes matching the original destination of the
+//! connection, see [`crate::transparent`], beat those that do not, then
+//! routes with a [`crate::schedule::Schedule`], which only match while it is
+//! open, beat those without.
+//!
+//! Matching is compiled when a [`Router`] is built: the prefixes go into a
+//! byte trie per host, and the patterns of all routes into one `RegexSet`.
+//! A request walks the trie of its host and the host-less one once, along its
+//! path, and the set runs at most once, only if a candidate has a pattern;
+//! the cost does not grow with the number of routes that cannot match.
+//! [`Router::match_costs`] reports what matching each route takes.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use http::header;
+use pingora::http::RequestHeader;
+use pingora::lb::{LoadBalancer, selection::RoundRobin};
+use regex::{Regex, RegexSet};
+
+use crate::balancing::Balancer;
+use crate::body_route::BodyRouting;
+use crate::budget::Budget;
+use crate::cgi::CgiGateway;
+use crate::config::UpstreamPeer;
+use crate::content_sniff::ContentTypeGuard;
+use crate::doh::DohGateway;
+use crate::gateway::Gateway;
+use crate::graphql::GraphQl;
+use crate::hash_select::HashSelection;
+use crate::idempotency::Idempotency;
+use crate::image::ImageOptimizer;
+use crate::no_upstream::NoUpstream;
+use crate::openapi::OpenApi;
+use crate::quarantine::Quarantine;
+use crate::replica::FanOut;
+use crate::s3::S3Origin;
+use crate::scan::ContentScanner;
+use crate::schedule::Schedule;
+use crate::signing::ResponseSigner;
+use crate::sticky::StickySessions;
+use crate::stream::StreamConfig;
+use crate::subset::ClientSubsets;
+use crate::transparent::DstMatch;
+use crate::upload::UploadTarget;
+use crate::upstream_tcp::UpstreamTcp;
+use crate::xml::XmlGuard;
+
+#[derive(Clone, Default)]
+pub struct Route {
+    pub name: String,
+    /// Host to match, without port; `None` matches any host.
+    pub host: Option<String>,
+    pub path_prefix: String,
+    /// Regex the whole path must also match, e.g. `^/users/[0-9]+$`.
+    pub path_pattern: Option<String>,
+    /// Only match connections to these destinations, the addresses clients
+    /// dialed when the proxy is transparent.
+    pub original_dst: Option<DstMatch>,
+    /// Only match while the schedule has a window open.
+    pub schedule: Option<Arc<Schedule>>,
+    /// Paths as stats label them, e.g. `/users/:id`, see [`crate::labels`].
+    pub path_templates: Vec<String>,
+    /// The route's own cluster; `None` sends to the default upstreams.
+    pub upstreams: Option<Arc<LoadBalancer<RoundRobin>>>,
+    /// TLS, SNI and `Host` of the route's cluster; `None` speaks to it as
+    /// to the default upstreams.
+    pub peer: Option<Arc<UpstreamPeer>>,
+    /// The route's cluster in the secondary region, sent to instead while
+    /// the region is failed over, see [`crate::region`].
+    pub secondary_region: Option<Arc<LoadBalancer<RoundRobin>>>,
+    /// Send requests to the original destination of their connection, over
+    /// plain HTTP and with their own `Host`, instead of a cluster, see
+    /// [`crate::original_dst`].
+    pub original_dst_cluster: bool,
+    /// Talk HTTP/2 without TLS to the cluster, with prior knowledge rather
+    /// than an upgrade, as internal gRPC services expect.
+    pub h2c: bool,
+    /// The answer when no upstream of the cluster is usable.
+    pub no_upstream: NoUpstream,
+    /// Selects the tenant's templates for synthesized responses and its
+    /// cache partition.
+    pub tenant: Option<String>,
+    /// Answer every request with the maintenance page.
+    pub maintenance: bool,
+    /// Redirect every request to this location instead of proxying it.
+    pub redirect: Option<String>,
+    /// `Link` values sent in a `103 Early Hints` while the request is proxied.
+    pub early_hints: Vec<String>,
+    /// On-the-fly image transforms for this route.
+    pub image: Option<Arc<ImageOptimizer>>,
+    /// Serve `GET`s from the first replica of the URI to answer.
+    pub fan_out: Option<Arc<FanOut>>,
+    /// Spread each client over its own subset of the cluster.
+    pub subsets: Option<Arc<ClientSubsets>>,
+    /// Long-lived streaming responses, see [`crate::stream`].
+    pub stream: Option<StreamConfig>,
+    /// TCP keepalive, pool timeout and half-closes of the connections of the
+    /// route, instead of those of the default upstreams, see
+    /// [`crate::upstream_tcp`].
+    pub upstream_tcp: Option<UpstreamTcp>,
+    /// How the upstreams of the route's cluster are picked, instead of as
+    /// those of the default upstreams are, see [`crate::balancing`].
+    pub balancing: Option<Arc<Balancer>>,
+    /// Requests each upstream of the route's cluster may have queued or in
+    /// flight, see [`crate::in_flight`]; `None` for no cap.
+    pub max_in_flight: Option<usize>,
+    /// Keep each client session on one upstream of the cluster.
+    pub sticky: Option<StickySessions>,
+    /// Pick upstreams by consistent hashing of a request key, see
+    /// [`crate::hash_select`]. Sticky sessions take over from it, and it
+    /// takes over from subsets.
+    pub hash_selection: Option<Arc<HashSelection>>,
+    /// Answer DNS-over-HTTPS queries instead of proxying, see [`crate::doh`].
+    pub doh: Option<Arc<DohGateway>>,
+    /// Buffer and sign every response, see [`crate::signing`]. Takes over
+    /// from fan-out, image transforms, streaming and caching.
+    pub signing: Option<Arc<ResponseSigner>>,
+    /// Sniff the start of response bodies and correct or block those not of
+    /// their declared type, see [`crate::content_sniff`]. Takes over from
+    /// idempotency, fan-out, image transforms, streaming and caching.
+    pub content_type_guard: Option<Arc<ContentTypeGuard>>,
+    /// Have an external scanner check request and response bodies, see
+    /// [`crate::scan`]. Takes over from signing and everything it does.
+    pub scan: Option<Arc<ContentScanner>>,
+    /// Keep request bodies in a quarantine directory until they are scanned
+    /// clean, see [`crate::quarantine`]. Takes over from scanning and
+    /// everything it does.
+    pub quarantine: Option<Arc<Quarantine>>,
+    /// Send requests with an `Idempotency-Key` upstream once, see
+    /// [`crate::idempotency`].
+    pub idempotency: Option<Arc<Idempotency>>,
+    /// Hand requests over to other routes by their body or gRPC method, see
+    /// [`crate::body_route`].
+    pub body_routing: Option<Arc<BodyRouting>>,
+    /// Depth, complexity and per-operation rate limits for GraphQL, see
+    /// [`crate::graphql`].
+    pub graphql: Option<Arc<GraphQl>>,
+    /// Check request bodies as XML before proxying them, see [`crate::xml`].
+    pub xml_guard: Option<Arc<XmlGuard>>,
+    /// Store multipart uploads in a bucket instead of proxying them, see
+    /// [`crate::upload`].
+    pub upload: Option<Arc<UploadTarget>>,
+    /// Serve `GET`s and `HEAD`s from a private bucket instead of the
+    /// cluster, see [`crate::s3`].
+    pub s3: Option<Arc<S3Origin>>,
+    /// Speak FastCGI, uwsgi or SCGI to the cluster instead of HTTP, see
+    /// [`crate::cgi`].
+    pub cgi: Option<Arc<CgiGateway>>,
+    /// Handle idempotent requests sent in TLS early data rather than answer
+    /// them `425 Too Early`, see [`crate::early_data`].
+    pub early_data: bool,
+    /// JA3 hashes and JA4 fingerprints of TLS clients answered `403`, see
+    /// [`crate::fingerprint`].
+    pub blocked_fingerprints: Vec<String>,
+    /// Answer requests scoring higher `403`, see [`crate::anomaly`].
+    pub max_anomaly_score: Option<u32>,
+    /// Limits on the buffering and CPU of optional filters, see
+    /// [`crate::budget`].
+    pub budget: Option<Arc<Budget>>,
+    /// API gateway presets: prefix stripping, auth context headers, the
+    /// error envelope and CORS, see [`crate::gateway`].
+    pub gateway: Option<Arc<Gateway>>,
+    /// Refuse requests not of the operations of an OpenAPI spec, see
+    /// [`crate::openapi`].
+    pub openapi: Option<Arc<OpenApi>>,
+}
+
+impl Route {
+    pub fn new(name: impl Into<String>, path_prefix: impl Into<String>) -> Self {
+        Route {
+            name: name.into(),
+            path_prefix: path_prefix.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The cache partition of the route: its tenant's, or its own without
+    /// a tenant. Partitions without a quota share the default store.
+    pub fn cache_partition(&self) -> &str {
+        self.tenant.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// A byte trie of path prefixes, with the routes ending at each node in
+/// match order.
+#[derive(Default)]
+struct Trie {
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    routes: Vec<usize>,
+}
+
+impl Trie {
+    fn insert(&mut self, prefix: &str, route: usize) {
+        if self.nodes.is_empty() {
+            self.nodes.push(TrieNode::default());
+        }
+        let mut node = 0;
+        for &byte in prefix.as_bytes() {
+            node = match self.nodes[node].children.iter().find(|(b, _)| *b == byte) {
+                Some(&(_, child)) => child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(TrieNode::default());
+                    self.nodes[node].children.push((byte, child));
+                    child
+                }
+            };
+        }
+        self.nodes[node].routes.push(route);
+    }
+
+    /// The routes whose prefix starts `path`, longest prefix first.
+    fn candidates(&self, path: &str, out: &mut Vec<usize>) {
+        let Some(root) = self.nodes.first() else {
+            return;
+        };
+        let start = out.len();
+        let mut node = root;
+        out.extend(node.routes.iter().rev());
+        for &byte in path.as_bytes() {
+            match node.children.iter().find(|(b, _)| *b == byte) {
+                Some(&(_, child)) => node = &self.nodes[child],
+                None => break,
+            }
+            out.extend(node.routes.iter().rev());
+        }
+        out[start..].reverse();
+    }
+}
+
+/// What matching a route takes, from [`Router::match_costs`].
+#[derive(Clone, Debug)]
+pub struct MatchCost {
+    pub route: String,
+    /// Routes a request for exactly the route's prefix checks before it.
+    pub checked_before: usize,
+    /// Whether it needs the regex set to run.
+    pub pattern: bool,
+    /// Time its pattern took to compile.
+    pub compile: Duration,
+}
+
+/// What a route checks of the path beyond its prefix.
+enum PathCheck {
+    Prefix,
+    /// its pattern, by index in the set
+    Pattern(usize),
+    /// its pattern did not compile, it matches nothing
+    Broken,
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Arc<Route>>,
+    /// prefix tries of routes with a host, by lowercase host
+    hosts: HashMap<String, Trie>,
+    any_host: Trie,
+    /// the valid patterns of all routes
+    patterns: Option<RegexSet>,
+    checks: Vec<PathCheck>,
+    compile_times: Vec<Duration>,
+    /// patterns that failed to compile, by route
+    bad_patterns: Vec<(String, String)>,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>) -> Self {
+        let mut routes: Vec<_> = routes.into_iter().map(Arc::new).collect();
+        routes.sort_by_key(|r| {
+            (
+                r.host.is_none(),
+                std::cmp::Reverse(r.path_prefix.len()),
+                r.original_dst.is_none(),
+                r.schedule.is_none(),
+            )
+        });
+
+        let mut hosts: HashMap<String, Trie> = HashMap::new();
+        let mut any_host = Trie::default();
+        let mut valid = Vec::new();
+        let mut checks = Vec::new();
+        let mut compile_times = Vec::new();
+        let mut bad_patterns = Vec::new();
+        for (i, route) in routes.iter().enumerate() {
+            match &route.host {
+                Some(host) => hosts
+                    .entry(host.to_ascii_lowercase())
+                    .or_default()
+                    .insert(&route.path_prefix, i),
+                None => any_host.insert(&route.path_prefix, i),
+            }
+            let started = Instant::now();
+            let check = match route.path_pattern.as_deref().map(Regex::new) {
+                None => PathCheck::Prefix,
+                Some(Ok(_)) => {
+                    valid.push(route.path_pattern.as_deref().unwrap_or_default());
+                    PathCheck::Pattern(valid.len() - 1)
+                }
+                Some(Err(e)) => {
+                    bad_patterns.push((route.name.clone(), e.to_string()));
+                    PathCheck::Broken
+                }
+            };
+            compile_times.push(started.elapsed());
+            checks.push(check);
+        }
+        let patterns = (!valid.is_empty())
+            .then(|| RegexSet::new(&valid).expect("every pattern compiled on its own"));
+        Router {
+            routes,
+            hosts,
+            any_host,
+            patterns,
+            checks,
+            compile_times,
+            bad_patterns,
+        }
+    }
+
+    pub fn routes(&self) -> &[Arc<Route>] {
+        &self.routes
+    }
+
+    /// Why the routes cannot be used as they are: duplicate names, prefixes
+    /// not starting with `/`, patterns that are not valid regexes,
+    /// quarantined uploads to buckets, or requests handed to routes that do
+    /// not exist.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some((route, e)) = self.bad_patterns.first() {
+            return Err(format!("path pattern of route {route} is invalid: {e}"));
+        }
+        let mut names = HashSet::new();
+        for route in &self.routes {
+            if !names.insert(route.name.as_str()) {
+                return Err(format!("route {} is defined twice", route.name));
+            }
+            if route.quarantine.is_some() && route.upload.is_some() {
+                return Err(format!(
+                    "route {} stores uploads in a bucket, they cannot be quarantined",
+                    route.name
+                ));
+            }
+            if !route.path_prefix.starts_with('/') {
+                return Err(format!(
+                    "path prefix {:?} of route {} does not start with /",
+                    route.path_prefix, route.name
+                ));
+            }
+        }
+        for route in &self.routes {
+            let targets = route.body_routing.iter().flat_map(|b| b.targets());
+            for target in targets {
+                if !names.contains(target) {
+                    return Err(format!(
+                        "route {} hands requests to route {target}, which does not exist",
+                        route.name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The route named `name`.
+    pub fn route(&self, name: &str) -> Option<Arc<Route>> {
+        self.routes.iter().find(|r| r.name == name).cloned()
+    }
+
+    /// Whether a route names `host`, lowercase.
+    pub fn serves_host(&self, host: &str) -> bool {
+        self.hosts.contains_key(host)
+    }
+
+    /// The routes that could match `path` on `host`, in match order.
+    fn candidates(&self, host: Option<&str>, path: &str) -> Vec<usize> {
+        let mut candidates = Vec::new();
+        if let Some(host) = host {
+            let host = if host.bytes().any(|b| b.is_ascii_uppercase()) {
+                Cow::Owned(host.to_ascii_lowercase())
+            } else {
+                Cow::Borrowed(host)
+            };
+            if let Some(trie) = self.hosts.get(host.as_ref()) {
+                trie.candidates(path, &mut candidates);
+            }
+        }
+        self.any_host.candidates(path, &mut candidates);
+        candidates
+    }
+
+    /// The route of `req`, received on a connection to `dst`: where a
+    /// connection the proxy intercepted was headed if `redirected`, the
+    /// proxy's own listener otherwise. Routes sending to the original
+    /// destination only match redirected connections.
+    pub fn match_request(
+        &self,
+        req: &RequestHeader,
+        dst: Option<SocketAddr>,
+        redirected: bool,
+    ) -> Option<Arc<Route>> {
+        let path = req.uri.path();
+        let mut matched_patterns = None;
+        self.candidates(request_host(req), path)
+            .into_iter()
+            .find(|&i| {
+                let path_matches = match self.checks[i] {
+                    PathCheck::Prefix => true,
+                    PathCheck::Broken => false,
+                    PathCheck::Pattern(pattern) => {
+                        let matches = matched_patterns.get_or_insert_with(|| {
+                            self.patterns.as_ref().map(|set| set.matches(path))
+                        });
+                        matches.as_ref().is_some_and(|m| m.matched(pattern))
+                    }
+                };
+                let route = &self.routes[i];
+                path_matches
+                    && (redirected || !route.original_dst_cluster)
+                    && route
+                        .original_dst
+                        .as_ref()
+                        .is_none_or(|m| dst.is_some_and(|dst| m.matches(dst)))
+                    && route.schedule.as_ref().is_none_or(|s| s.is_open())
+            })
+            .map(|i| self.routes[i].clone())
+    }
+
+    /// What matching each route takes, in match order.
+    pub fn match_costs(&self) -> Vec<MatchCost> {
+        self.routes
+            .iter()
+            .enumerate()
+            .map(|(i, route)| {
+                let checked_before = self
+                    .candidates(route.host.as_deref(), &route.path_prefix)
+                    .iter()
+                    .position(|&c| c == i)
+                    .unwrap_or(0);
+                MatchCost {
+                    route: route.name.clone(),
+                    checked_before,
+                    pattern: route.path_pattern.is_some(),
+                    compile: self.compile_times[i],
+                }
+            })
+            .collect()
+    }
+}
+
+/// Host the request is for, without port.
+pub fn request_host(req: &RequestHeader) -> Option<&str> {
+    let host = req
+        .headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri.host())?;
+    if host.starts_with('[') {
+        // bracketed IPv6 literal, optionally followed by a port
+        return host.find(']').map(|end| &host[..=end]);
+    }
+    Some(host.split_once(':').map_or(host, |(name, _)| name))
+}
+
+/// The active [`Router`], replaceable while requests are being routed.
+///
+/// Requests read a snapshot of the router without taking a lock, and a reload
+/// swaps the snapshot in one step: it never waits for requests routing with
+/// the old router, nor they for it. The same holds for the other state
+/// requests read on every pick of an upstream: the cluster's backends, kept
+/// so by the load balancer, the rings and selections built from them, the
+/// draining upstreams and the feature flags. `tests/reload.rs` checks it
+/// under load.
+#[derive(Default)]
+pub struct SharedRouter {
+    current: ArcSwap<Router>,
+}
+
+impl SharedRouter {
+    pub fn new(router: Router) -> Self {
+        SharedRouter {
+            current: ArcSwap::from_pointee(router),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Router> {
+        self.current.load_full()
+    }
+
+    /// Route new requests with `router`; requests already routed keep their
+    /// route.
+    pub fn store(&self, router: impl Into<Arc<Router>>) {
+        self.current.store(router.into());
+    }
+}
@@ -0,0 +1,109 @@
+//! Upstreams added and removed at runtime through the admin API.
+//!
+//! [`RuntimeDiscovery`] wraps the discovery of a cluster. Upstreams added at
+//! runtime join the discovered ones. Removed ones are left out, even while
+//! discovery still finds them, until they are added again. After a change
+//! the admin API has the load balancer discover at once. The load balancer
+//! swaps its backends and their selection ring in one step, and rings built
+//! from the backends, as those of [`crate::replica::FanOut`], follow on their
+//! next use. Changes last until the proxy restarts.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr as InetAddr;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use http::Extensions;
+use log::info;
+use pingora::Result;
+use pingora::lb::Backend;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::protocols::l4::socket::SocketAddr;
+
+#[derive(Default)]
+struct Changes {
+    /// weights of the upstreams added
+    added: BTreeMap<InetAddr, usize>,
+    removed: BTreeSet<InetAddr>,
+}
+
+/// The upstreams added to and removed from a cluster.
+#[derive(Default)]
+pub struct RuntimeUpstreams {
+    changes: RwLock<Changes>,
+}
+
+impl RuntimeUpstreams {
+    /// Add `addr` with `weight`, or change the weight it was added with.
+    /// Whether it was not added before.
+    pub fn add(&self, addr: InetAddr, weight: usize) -> bool {
+        let mut changes = self.changes.write().unwrap();
+        changes.removed.remove(&addr);
+        let new = changes.added.insert(addr, weight).is_none();
+        info!("upstream {addr} added with weight {weight}");
+        new
+    }
+
+    /// Remove `addr`, whether it was added or discovered.
+    pub fn remove(&self, addr: InetAddr) {
+        let mut changes = self.changes.write().unwrap();
+        changes.added.remove(&addr);
+        changes.removed.insert(addr);
+        info!("upstream {addr} removed");
+    }
+
+    pub fn is_added(&self, addr: &SocketAddr) -> bool {
+        addr.as_inet()
+            .is_some_and(|addr| self.changes.read().unwrap().added.contains_key(addr))
+    }
+
+    /// The upstreams removed, in address order.
+    pub fn removed(&self) -> Vec<InetAddr> {
+        self.changes
+            .read()
+            .unwrap()
+            .removed
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    fn apply(&self, backends: &mut BTreeSet<Backend>) {
+        let changes = self.changes.read().unwrap();
+        // one also discovered takes the weight it was added with
+        backends.retain(|b| {
+            b.addr.as_inet().is_none_or(|addr| {
+                !changes.removed.contains(addr) && !changes.added.contains_key(addr)
+            })
+        });
+        backends.extend(changes.added.iter().map(|(addr, weight)| Backend {
+            addr: SocketAddr::Inet(*addr),
+            weight: *weight,
+            ext: Extensions::new(),
+        }));
+    }
+}
+
+/// Wraps a discovery to add and remove the [`RuntimeUpstreams`].
+pub struct RuntimeDiscovery {
+    inner: Box<dyn ServiceDiscovery + Send + Sync>,
+    upstreams: Arc<RuntimeUpstreams>,
+}
+
+impl RuntimeDiscovery {
+    pub fn new(
+        inner: Box<dyn ServiceDiscovery + Send + Sync>,
+        upstreams: Arc<RuntimeUpstreams>,
+    ) -> Box<Self> {
+        Box::new(RuntimeDiscovery { inner, upstreams })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for RuntimeDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let (mut backends, enablement) = self.inner.discover().await?;
+        self.upstreams.apply(&mut backends);
+        Ok((backends, enablement))
+    }
+}
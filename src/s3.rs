@@ -0,0 +1,478 @@
+//! S3-compatible object storage: buckets and AWS Signature Version 4.
+//!
+//! Requests are signed over the host, the `x-amz-*` headers and the payload
+//! hash, which callers give as the hex SHA-256 of the body. MinIO and other
+//! self-hosted stores usually want path-style addressing and accept any
+//! region name; GCS takes signed requests through its XML API with HMAC
+//! keys.
+//!
+//! A route with an [`S3Origin`] sends its `GET`s and `HEAD`s to a private
+//! bucket instead of its cluster: the path below the route prefix names the
+//! object, and the request is signed on its way out, so the cache and range
+//! handling work on it as on any other upstream.
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use http::{Method, header};
+use log::warn;
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::{Error, ErrorType, OrErr, Result};
+use ring::{digest, hmac};
+use serde_json::Value;
+
+use crate::subrequest;
+
+/// Payload hash of a request without a body.
+pub const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// For temporary credentials.
+    pub session_token: Option<String>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+const CREDENTIALS_ERROR: ErrorType = ErrorType::Custom("CredentialsError");
+
+/// The instance metadata service of EC2.
+const IMDS: &str = "169.254.169.254:80";
+
+/// How long credentials of a role are used before fetching them again; the
+/// service hands out new ones well before the old expire, after an hour.
+const ROLE_REFRESH: Duration = Duration::from_secs(10 * 60);
+
+/// How long credentials of a role are used if fetching new ones fails.
+const ROLE_STALE: Duration = Duration::from_secs(45 * 60);
+
+#[derive(Debug)]
+enum Source {
+    Fixed(Credentials),
+    Environment,
+    InstanceRole(Option<String>),
+}
+
+/// Where a bucket's credentials come from.
+pub struct CredentialProvider {
+    source: Source,
+    fetched: Mutex<Option<(Credentials, Instant)>>,
+    http: Connector,
+}
+
+impl std::fmt::Debug for CredentialProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl CredentialProvider {
+    fn new(source: Source) -> Arc<Self> {
+        Arc::new(CredentialProvider {
+            source,
+            fetched: Mutex::default(),
+            http: Connector::new(None),
+        })
+    }
+
+    pub fn fixed(credentials: Credentials) -> Arc<Self> {
+        Self::new(Source::Fixed(credentials))
+    }
+
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`,
+    /// read for every request so rotated values are picked up.
+    pub fn environment() -> Arc<Self> {
+        Self::new(Source::Environment)
+    }
+
+    /// The role of the EC2 instance, through the instance metadata service
+    /// (IMDSv2); `role` picks one by name instead of the first attached.
+    pub fn instance_role(role: Option<String>) -> Arc<Self> {
+        Self::new(Source::InstanceRole(role))
+    }
+
+    pub async fn credentials(&self) -> Result<Credentials> {
+        match &self.source {
+            Source::Fixed(credentials) => Ok(credentials.clone()),
+            Source::Environment => {
+                let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+                match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+                    (Some(access_key_id), Some(secret_access_key)) => Ok(Credentials {
+                        access_key_id,
+                        secret_access_key,
+                        session_token: var("AWS_SESSION_TOKEN"),
+                    }),
+                    _ => Error::e_explain(CREDENTIALS_ERROR, "AWS credentials not set"),
+                }
+            }
+            Source::InstanceRole(role) => {
+                let cached = self.fetched.lock().unwrap().clone();
+                if let Some((credentials, at)) = &cached
+                    && at.elapsed() < ROLE_REFRESH
+                {
+                    return Ok(credentials.clone());
+                }
+                match self.fetch_role(role.as_deref()).await {
+                    Ok(credentials) => {
+                        *self.fetched.lock().unwrap() = Some((credentials.clone(), Instant::now()));
+                        Ok(credentials)
+                    }
+                    Err(e) => match cached {
+                        Some((credentials, at)) if at.elapsed() < ROLE_STALE => {
+                            warn!("fetching role credentials, using the previous ones: {e}");
+                            Ok(credentials)
+                        }
+                        _ => Err(e),
+                    },
+                }
+            }
+        }
+    }
+
+    async fn fetch_role(&self, role: Option<&str>) -> Result<Credentials> {
+        let mut peer = HttpPeer::new(IMDS, false, String::new());
+        peer.options.total_connection_timeout = Some(Duration::from_secs(2));
+        peer.options.read_timeout = Some(Duration::from_secs(2));
+        let imds = |method: Method, path: &str, token: Option<&str>| {
+            let mut req = RequestHeader::build(method, path.as_bytes(), None)?;
+            req.insert_header(header::HOST, IMDS.trim_end_matches(":80"))?;
+            match token {
+                Some(token) => req.insert_header("X-aws-ec2-metadata-token", token)?,
+                None => {
+                    req.insert_header("X-aws-ec2-metadata-token-ttl-seconds", "300")?;
+                    req.insert_header(header::CONTENT_LENGTH, 0)?;
+                }
+            }
+            Ok::<_, Box<Error>>(req)
+        };
+        let get =
+            async |req: RequestHeader| match subrequest::fetch(&self.http, &peer, req, 64 * 1024)
+                .await?
+            {
+                Some(fetched) if fetched.header.status.is_success() => {
+                    String::from_utf8(fetched.body.to_vec())
+                        .or_err(CREDENTIALS_ERROR, "instance metadata is not UTF-8")
+                }
+                Some(fetched) => Error::e_explain(
+                    CREDENTIALS_ERROR,
+                    format!("instance metadata answered {}", fetched.header.status),
+                ),
+                None => Error::e_explain(CREDENTIALS_ERROR, "instance metadata too large"),
+            };
+
+        let token = get(imds(Method::PUT, "/latest/api/token", None)?).await?;
+        let base = "/latest/meta-data/iam/security-credentials/";
+        let role = match role {
+            Some(role) => role.to_string(),
+            None => {
+                let roles = get(imds(Method::GET, base, Some(&token))?).await?;
+                roles
+                    .lines()
+                    .next()
+                    .map(str::to_string)
+                    .filter(|r| !r.is_empty())
+                    .ok_or_else(|| Error::explain(CREDENTIALS_ERROR, "no role attached"))?
+            }
+        };
+        let path = format!("{base}{}", uri_encode(&role, true));
+        let body = get(imds(Method::GET, &path, Some(&token))?).await?;
+        let parsed: Value =
+            serde_json::from_str(&body).or_err(CREDENTIALS_ERROR, "parsing role credentials")?;
+        let field = |name: &str| parsed.get(name).and_then(Value::as_str).map(str::to_string);
+        match (field("AccessKeyId"), field("SecretAccessKey")) {
+            (Some(access_key_id), Some(secret_access_key)) => Ok(Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: field("Token"),
+            }),
+            _ => Error::e_explain(CREDENTIALS_ERROR, "incomplete role credentials"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Bucket {
+    pub name: String,
+    pub region: String,
+    pub addr: SocketAddr,
+    /// Host of the endpoint, `s3.eu-west-1.amazonaws.com`; virtual-hosted
+    /// requests go to the bucket's subdomain of it.
+    pub host: String,
+    pub tls: bool,
+    /// Address objects as `host/bucket/key` rather than `bucket.host/key`.
+    pub path_style: bool,
+    pub credentials: Arc<CredentialProvider>,
+}
+
+impl Bucket {
+    /// Host serving the bucket's objects, also the TLS SNI.
+    pub fn object_host(&self) -> String {
+        if self.path_style {
+            self.host.clone()
+        } else {
+            format!("{}.{}", self.name, self.host)
+        }
+    }
+
+    /// Request path of the object `key`, percent-encoded.
+    pub fn object_path(&self, key: &str) -> String {
+        let key = uri_encode(key.trim_start_matches('/'), false);
+        if self.path_style {
+            format!("/{}/{key}", self.name)
+        } else {
+            format!("/{key}")
+        }
+    }
+
+    pub fn object_url(&self, key: &str) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{scheme}://{}{}", self.object_host(), self.object_path(key))
+    }
+
+    pub fn peer(&self) -> HttpPeer {
+        HttpPeer::new(self.addr, self.tls, self.object_host())
+    }
+
+    /// Sign `req` for the bucket, setting its `Host`, `x-amz-*` and
+    /// `Authorization` headers.
+    pub(crate) async fn sign(&self, req: &mut RequestHeader, payload_sha256: &str) -> Result<()> {
+        let credentials = self.credentials.credentials().await?;
+        let (date, timestamp) = amz_date(SystemTime::now());
+        let host = self.object_host();
+        req.insert_header(header::HOST, &host)?;
+        req.insert_header("x-amz-date", &timestamp)?;
+        req.insert_header("x-amz-content-sha256", payload_sha256)?;
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_sha256.to_string()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            req.insert_header("x-amz-security-token", token)?;
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+
+        let signed_headers = headers
+            .iter()
+            .map(|(n, _)| *n)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(n, v)| format!("{n}:{}\n", v.trim()))
+            .collect();
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{canonical_headers}\n{signed_headers}\n{payload_sha256}",
+            req.method,
+            req.uri.path(),
+            canonical_query(req.uri.query().unwrap_or_default()),
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+        );
+
+        let mut key = format!("AWS4{}", credentials.secret_access_key).into_bytes();
+        for part in [date.as_str(), &self.region, "s3", "aws4_request"] {
+            key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), part.as_bytes())
+                .as_ref()
+                .to_vec();
+        }
+        let signature = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, &key),
+            string_to_sign.as_bytes(),
+        );
+        req.insert_header(
+            header::AUTHORIZATION,
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={}",
+                credentials.access_key_id,
+                hex(signature.as_ref())
+            ),
+        )
+    }
+}
+
+/// A private bucket serving a route, see the module docs.
+pub struct S3Origin {
+    bucket: Bucket,
+    prefix: String,
+    index: Option<String>,
+}
+
+impl S3Origin {
+    pub fn new(bucket: Bucket) -> Self {
+        S3Origin {
+            bucket,
+            prefix: String::new(),
+            index: None,
+        }
+    }
+
+    /// Prefix of the object keys, put in place of the route prefix.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Object served for paths ending in `/`, `index.html`.
+    pub fn with_index(mut self, index: impl Into<String>) -> Self {
+        self.index = Some(index.into());
+        self
+    }
+
+    pub fn bucket(&self) -> &Bucket {
+        &self.bucket
+    }
+
+    /// Turn the request for a path below `route_prefix` into a signed request
+    /// for its object. The query is dropped, it could reach sub-resources
+    /// (`?acl`, `?versions`) of the bucket.
+    pub(crate) async fn prepare(&self, route_prefix: &str, req: &mut RequestHeader) -> Result<()> {
+        let path = percent_decode(req.uri.path());
+        let mut key = format!(
+            "{}{}",
+            self.prefix,
+            path.strip_prefix(route_prefix)
+                .unwrap_or(&path)
+                .trim_start_matches('/')
+        );
+        if let Some(index) = &self.index
+            && (key.is_empty() || key.ends_with('/'))
+        {
+            key.push_str(index);
+        }
+        let uri = self
+            .bucket
+            .object_path(&key)
+            .parse()
+            .or_err(ErrorType::HTTPStatus(400), "object path")?;
+        req.set_uri(uri);
+
+        // the proxy's credentials stand in for whatever the client sent
+        let client_amz: Vec<_> = req
+            .headers
+            .keys()
+            .filter(|name| name.as_str().starts_with("x-amz-"))
+            .cloned()
+            .collect();
+        for name in client_amz {
+            req.remove_header(&name);
+        }
+        req.remove_header(&header::AUTHORIZATION);
+        req.remove_header(&header::COOKIE);
+        req.remove_header(&header::CONTENT_LENGTH);
+        self.bucket.sign(req, EMPTY_SHA256).await
+    }
+}
+
+/// Hex SHA-256 of `payload`, for [`Bucket::sign`].
+pub fn payload_sha256(payload: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, payload).as_ref())
+}
+
+/// Percent-encode all but unreserved characters, and `/` if `slash` is false.
+pub(crate) fn uri_encode(value: &str, slash: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) || (b == b'/' && !slash) {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{b:02X}");
+        }
+    }
+    out
+}
+
+/// The query with each name and value encoded once, sorted.
+fn canonical_query(query: &str) -> String {
+    let mut pairs: Vec<_> = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                uri_encode(&percent_decode(name), true),
+                uri_encode(&percent_decode(value), true),
+            )
+        })
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(n, v)| format!("{n}={v}"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+pub(crate) fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut out, b| {
+            let _ = write!(out, "{b:02x}");
+            out
+        })
+}
+
+/// `YYYYMMDD` and `YYYYMMDDTHHMMSSZ` of `time`, in UTC.
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rest) = ((secs / 86_400) as i64, secs % 86_400);
+    // days to civil date, after Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let date = format!("{year:04}{month:02}{day:02}");
+    let timestamp = format!(
+        "{date}T{:02}{:02}{:02}Z",
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    );
+    (date, timestamp)
+}
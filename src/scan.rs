@@ -0,0 +1,455 @@
+//! Content scanning by an external scanner, antivirus or DLP.
+//!
+//! On a route with a [`ContentScanner`] the proxy buffers each exchange, up
+//! to `max_body` each way, and has the scanner look at the request body
+//! before it goes upstream and the response body before it reaches the
+//! client. The scanner answers, for each body:
+//!
+//! - clean, and the body passes as it is,
+//! - cleaned, with the body to pass instead, e.g. without the infected
+//!   attachment,
+//! - blocked, and the client gets a `403`.
+//!
+//! Scanners are spoken to over ICAP (RFC 3507), `REQMOD` and `RESPMOD` with
+//! `Allow: 204`, or over plain HTTP: the body is `POST`ed to a URL, which
+//! answers `200` or `204` for clean and `403` for blocked. When the scanner
+//! cannot be reached, times out or answers anything else, the route's
+//! [`FailMode`] decides: open lets the body through unscanned, closed answers
+//! `503`.
+//!
+//! Bodies over `max_body` are refused, `413` for requests and `502` for
+//! responses, rather than passed unscanned.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{Uri, header};
+use log::warn;
+use pingora::connectors::http::Connector;
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::subrequest;
+
+pub const ICAP_PORT: u16 = 1344;
+
+/// The room ICAP and HTTP headers get in a scanner's answer, beyond the body.
+const MAX_HEADERS: usize = 64 * 1024;
+
+/// Headers of the plain HTTP callout saying what is scanned.
+pub const DIRECTION_HEADER: &str = "x-scan-direction";
+pub const URI_HEADER: &str = "x-scan-uri";
+
+/// Where the scanner is.
+#[derive(Clone, Debug)]
+pub enum Scanner {
+    /// An ICAP service, `icap://av.internal:1344/avscan`; the same service
+    /// takes `REQMOD` and `RESPMOD`.
+    Icap(Uri),
+    /// An HTTP endpoint bodies are `POST`ed to.
+    Http(Uri),
+}
+
+/// What happens to a body the scanner could not scan.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailMode {
+    /// Pass it unscanned.
+    Open,
+    /// Answer `503`.
+    #[default]
+    Closed,
+}
+
+/// The scanner's answer about a body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// Pass this body instead.
+    Cleaned(Bytes),
+    /// Refuse it, for the reason the scanner gave.
+    Blocked(String),
+    /// The scanner could not scan it and the route fails closed.
+    Unavailable,
+}
+
+#[derive(Default)]
+struct Counts {
+    clean: AtomicU64,
+    cleaned: AtomicU64,
+    blocked: AtomicU64,
+    failed: AtomicU64,
+}
+
+pub struct ContentScanner {
+    scanner: Scanner,
+    requests: bool,
+    responses: bool,
+    max_body: usize,
+    fail: FailMode,
+    timeout: Duration,
+    connector: Connector,
+    counts: Counts,
+}
+
+impl ContentScanner {
+    /// Scan request and response bodies of up to 10 MiB with `scanner`,
+    /// failing closed.
+    pub fn new(scanner: Scanner) -> Self {
+        ContentScanner {
+            scanner,
+            requests: true,
+            responses: true,
+            max_body: 10 * 1024 * 1024,
+            fail: FailMode::default(),
+            timeout: Duration::from_secs(30),
+            connector: Connector::new(None),
+            counts: Counts::default(),
+        }
+    }
+
+    /// Which bodies are scanned.
+    pub fn with_directions(mut self, requests: bool, responses: bool) -> Self {
+        self.requests = requests;
+        self.responses = responses;
+        self
+    }
+
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    pub fn with_fail_mode(mut self, fail: FailMode) -> Self {
+        self.fail = fail;
+        self
+    }
+
+    /// Time the scanner has for one body.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    pub fn scans_requests(&self) -> bool {
+        self.requests
+    }
+
+    pub fn scans_responses(&self) -> bool {
+        self.responses
+    }
+
+    /// The verdict on the body of `req`.
+    pub async fn check_request(&self, req: &RequestHeader, body: &Bytes) -> Verdict {
+        self.verdict(req, None, body).await
+    }
+
+    /// The verdict on the body of `resp`, the response to `req`.
+    pub async fn check_response(
+        &self,
+        req: &RequestHeader,
+        resp: &ResponseHeader,
+        body: &Bytes,
+    ) -> Verdict {
+        self.verdict(req, Some(resp), body).await
+    }
+
+    async fn verdict(
+        &self,
+        req: &RequestHeader,
+        resp: Option<&ResponseHeader>,
+        body: &Bytes,
+    ) -> Verdict {
+        let scanned = async {
+            match &self.scanner {
+                Scanner::Icap(uri) => self.icap(uri, req, resp, body).await,
+                Scanner::Http(url) => self.callout(url, req, resp, body).await,
+            }
+        };
+        let direction = if resp.is_some() {
+            "response"
+        } else {
+            "request"
+        };
+        let verdict = match tokio::time::timeout(self.timeout, scanned).await {
+            Ok(Ok(verdict)) => verdict,
+            Ok(Err(e)) => self.failed(direction, req, &e),
+            Err(_) => self.failed(direction, req, "timed out"),
+        };
+        let count = match &verdict {
+            Verdict::Clean => &self.counts.clean,
+            Verdict::Cleaned(_) => &self.counts.cleaned,
+            Verdict::Blocked(reason) => {
+                warn!("scanner blocked the {direction} of {}: {reason}", req.uri);
+                &self.counts.blocked
+            }
+            Verdict::Unavailable => return verdict,
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+        verdict
+    }
+
+    fn failed(&self, direction: &str, req: &RequestHeader, e: &str) -> Verdict {
+        self.counts.failed.fetch_add(1, Ordering::Relaxed);
+        match self.fail {
+            FailMode::Open => {
+                warn!(
+                    "could not scan the {direction} of {}, passing it: {e}",
+                    req.uri
+                );
+                Verdict::Clean
+            }
+            FailMode::Closed => {
+                warn!(
+                    "could not scan the {direction} of {}, refusing it: {e}",
+                    req.uri
+                );
+                Verdict::Unavailable
+            }
+        }
+    }
+
+    /// `REQMOD`, or `RESPMOD` with `resp`, of `body` to the service at `uri`.
+    async fn icap(
+        &self,
+        uri: &Uri,
+        req: &RequestHeader,
+        resp: Option<&ResponseHeader>,
+        body: &Bytes,
+    ) -> Result<Verdict, String> {
+        let host = uri.host().ok_or_else(|| format!("{uri} has no host"))?;
+        let port = uri.port_u16().unwrap_or(ICAP_PORT);
+        let addr = host.trim_start_matches('[').trim_end_matches(']');
+        let req_head = request_head(req);
+        let (method, encapsulated, heads) = match resp {
+            None => (
+                "REQMOD",
+                format!("req-hdr=0, req-body={}", req_head.len()),
+                req_head,
+            ),
+            Some(resp) => {
+                let resp_head = response_head(resp);
+                let encapsulated = format!(
+                    "req-hdr=0, res-hdr={}, res-body={}",
+                    req_head.len(),
+                    req_head.len() + resp_head.len()
+                );
+                ("RESPMOD", encapsulated, [req_head, resp_head].concat())
+            }
+        };
+        let mut message = format!(
+            "{method} {uri} ICAP/1.0\r\nHost: {host}\r\nAllow: 204\r\n\
+             Connection: close\r\nEncapsulated: {encapsulated}\r\n\r\n"
+        )
+        .into_bytes();
+        message.extend_from_slice(&heads);
+        if !body.is_empty() {
+            message.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            message.extend_from_slice(body);
+            message.extend_from_slice(b"\r\n");
+        }
+        message.extend_from_slice(b"0\r\n\r\n");
+
+        let mut stream = TcpStream::connect((addr, port))
+            .await
+            .map_err(|e| format!("connecting {host}:{port}: {e}"))?;
+        stream
+            .write_all(&message)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut answer = Vec::new();
+        stream
+            .take((self.max_body + MAX_HEADERS) as u64)
+            .read_to_end(&mut answer)
+            .await
+            .map_err(|e| e.to_string())?;
+        icap_verdict(&answer, resp.is_some())
+    }
+
+    /// `POST` of `body` to `url`.
+    async fn callout(
+        &self,
+        url: &Uri,
+        req: &RequestHeader,
+        resp: Option<&ResponseHeader>,
+        body: &Bytes,
+    ) -> Result<Verdict, String> {
+        let (peer, mut callout) = subrequest::for_url("POST", url, self.timeout)?;
+        let headers = match resp {
+            None => &req.headers,
+            Some(resp) => &resp.headers,
+        };
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let direction = if resp.is_some() {
+            "response"
+        } else {
+            "request"
+        };
+        let fields = [
+            (header::CONTENT_TYPE.as_str(), content_type),
+            (header::CONTENT_LENGTH.as_str(), body.len().to_string()),
+            (DIRECTION_HEADER, direction.to_string()),
+            (URI_HEADER, req.uri.to_string()),
+        ];
+        for (name, value) in fields {
+            callout
+                .insert_header(name, value)
+                .map_err(|e| e.to_string())?;
+        }
+        let fetched = subrequest::send(&self.connector, &peer, callout, body.clone(), 4096)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or("verdict too large")?;
+        match fetched.header.status.as_u16() {
+            200 | 204 => Ok(Verdict::Clean),
+            403 => Ok(Verdict::Blocked(
+                String::from_utf8_lossy(&fetched.body).trim().to_string(),
+            )),
+            status => Err(format!("scanner answered {status}")),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let count = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        json!({
+            "clean": count(&self.counts.clean),
+            "cleaned": count(&self.counts.cleaned),
+            "blocked": count(&self.counts.blocked),
+            "failed": count(&self.counts.failed),
+        })
+    }
+}
+
+fn request_head(req: &RequestHeader) -> Vec<u8> {
+    let mut head = BytesMut::new();
+    head.put_slice(format!("{} {} HTTP/1.1\r\n", req.method, req.uri).as_bytes());
+    for (name, value) in &req.headers {
+        put_field(&mut head, name.as_str(), value.as_bytes());
+    }
+    head.put_slice(b"\r\n");
+    head.to_vec()
+}
+
+fn response_head(resp: &ResponseHeader) -> Vec<u8> {
+    let mut head = BytesMut::new();
+    head.put_slice(format!("HTTP/1.1 {}\r\n", resp.status).as_bytes());
+    for (name, value) in &resp.headers {
+        put_field(&mut head, name.as_str(), value.as_bytes());
+    }
+    head.put_slice(b"\r\n");
+    head.to_vec()
+}
+
+fn put_field(head: &mut BytesMut, name: &str, value: &[u8]) {
+    head.put_slice(name.as_bytes());
+    head.put_slice(b": ");
+    head.put_slice(value);
+    head.put_slice(b"\r\n");
+}
+
+/// The verdict in an ICAP `answer` to a `REQMOD`, or a `RESPMOD` when
+/// `response`.
+fn icap_verdict(answer: &[u8], response: bool) -> Result<Verdict, String> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    // httparse reads `ICAP/1.0` as it would `HTTP/1.0`
+    let mut http = answer.to_vec();
+    if http.starts_with(b"ICAP/") {
+        http[..4].copy_from_slice(b"HTTP");
+    }
+    let start = match parsed.parse(&http) {
+        Ok(httparse::Status::Complete(len)) => len,
+        _ => return Err("bad ICAP response".to_string()),
+    };
+    let field = |name: &str| {
+        parsed
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    };
+    let reason = field("X-Infection-Found")
+        .or_else(|| field("X-Violations-Found"))
+        .map(str::to_string);
+    match parsed.code {
+        Some(204) => return Ok(Verdict::Clean),
+        Some(200) => {}
+        Some(code) => return Err(format!("ICAP server answered {code}")),
+        None => return Err("bad ICAP response".to_string()),
+    }
+    let encapsulated = field("Encapsulated").ok_or("ICAP response without Encapsulated")?;
+    let sections: Vec<(&str, usize)> = encapsulated
+        .split(',')
+        .filter_map(|s| {
+            let (name, offset) = s.trim().split_once('=')?;
+            Some((name, offset.parse().ok()?))
+        })
+        .collect();
+    let offset = |name: &str| sections.iter().find(|(n, _)| *n == name).map(|(_, o)| *o);
+    let encapsulated = &answer[start..];
+
+    // an HTTP response in the answer to a REQMOD is the scanner's refusal,
+    // one of another status than a success in the answer to a RESPMOD too
+    if let Some(at) = offset("res-hdr") {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut resp = httparse::Response::new(&mut headers);
+        let status = encapsulated
+            .get(at..)
+            .and_then(|head| resp.parse(head).ok())
+            .and(resp.code)
+            .ok_or("bad response in ICAP answer")?;
+        if !response || !(200..300).contains(&status) {
+            let reason = reason.unwrap_or_else(|| format!("scanner answered {status}"));
+            return Ok(Verdict::Blocked(reason));
+        }
+    }
+    if let Some(reason) = reason {
+        return Ok(Verdict::Blocked(reason));
+    }
+    let body_at = if response {
+        offset("res-body")
+    } else {
+        offset("req-body")
+    };
+    match body_at {
+        Some(at) => {
+            let chunked = encapsulated.get(at..).ok_or("short ICAP answer")?;
+            Ok(Verdict::Cleaned(dechunk(chunked)?))
+        }
+        None if offset("null-body").is_some() => Ok(Verdict::Cleaned(Bytes::new())),
+        None => Ok(Verdict::Clean),
+    }
+}
+
+/// The body of a chunked encoding, up to its last chunk.
+fn dechunk(mut chunked: &[u8]) -> Result<Bytes, String> {
+    let bad = || "bad chunked body in ICAP answer".to_string();
+    let mut body = BytesMut::new();
+    loop {
+        let line_end = chunked
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(bad)?;
+        let size = std::str::from_utf8(&chunked[..line_end]).map_err(|_| bad())?;
+        // chunk extensions, like `; ieof`, are not needed
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| bad())?;
+        chunked = &chunked[line_end + 2..];
+        if size == 0 {
+            return Ok(body.freeze());
+        }
+        let data = chunked.get(..size).ok_or_else(bad)?;
+        body.put_slice(data);
+        chunked = chunked.get(size + 2..).ok_or_else(bad)?;
+    }
+}
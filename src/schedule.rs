@@ -0,0 +1,180 @@
+//! Time windows for routes.
+//!
+//! A route with a [`Schedule`] only matches while one of its windows is open,
+//! and then beats the routes without one that match as specifically: a copy
+//! of a route with `maintenance` set, or another cluster, or stricter limits,
+//! takes over for the window and hands back when it closes, without anyone
+//! reloading the configuration at 2am.
+//!
+//! A window opens at the times a cron expression names, read in a time zone,
+//! and stays open for a duration:
+//!
+//! ```text
+//! minute hour day-of-month month day-of-week
+//! 0      2    *            *     SUN          for 2h, in Europe/Berlin
+//! ```
+//!
+//! Fields take `*`, numbers, `a-b` ranges, `/step`s and comma lists; months
+//! and days of the week also take their English abbreviations, Sunday is `0`
+//! or `7`. As in cron, when both day fields are restricted a day matching
+//! either one counts.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, Zoned};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Longest window, the opening times are searched back this far.
+const MAX_WINDOW: Duration = Duration::from_secs(31 * 24 * 60 * 60);
+
+/// The values one cron field matches, as bits.
+#[derive(Clone, Copy, Debug)]
+struct Field {
+    bits: u64,
+    /// whether the field is `*`, which matters for the day fields
+    any: bool,
+}
+
+impl Field {
+    fn parse(text: &str, min: u32, max: u32, names: &[&str]) -> Result<Field, String> {
+        let value = |v: &str| -> Result<u32, String> {
+            let lower = v.to_ascii_lowercase();
+            if let Some(i) = names.iter().position(|n| *n == lower) {
+                return Ok(i as u32 + min);
+            }
+            v.parse()
+                .ok()
+                .filter(|n| (min..=max).contains(n))
+                .ok_or_else(|| format!("{v} is not in {min}-{max}"))
+        };
+        let mut bits = 0;
+        for part in text.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (
+                    range,
+                    step.parse::<u32>()
+                        .ok()
+                        .filter(|s| *s > 0)
+                        .ok_or_else(|| format!("bad step {step}"))?,
+                ),
+                None => (part, 1),
+            };
+            let (first, last) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((first, last)) => (value(first)?, value(last)?),
+                    None if step > 1 => (value(range)?, max),
+                    None => (value(range)?, value(range)?),
+                },
+            };
+            if first > last {
+                return Err(format!("empty range {range}"));
+            }
+            for v in (first..=last).step_by(step as usize) {
+                bits |= 1 << v;
+            }
+        }
+        Ok(Field {
+            bits,
+            any: text == "*",
+        })
+    }
+
+    fn has(&self, value: u32) -> bool {
+        self.bits & (1 << value) != 0
+    }
+}
+
+pub struct Schedule {
+    minute: Field,
+    hour: Field,
+    day_of_month: Field,
+    month: Field,
+    day_of_week: Field,
+    duration: Duration,
+    tz: TimeZone,
+    /// whether a window is open, by epoch minute
+    open: Mutex<Option<(i64, bool)>>,
+}
+
+impl Schedule {
+    /// Windows of `duration` opening at the times of `cron`, read in the
+    /// IANA time zone `tz`.
+    pub fn new(cron: &str, duration: Duration, tz: &str) -> Result<Schedule, String> {
+        let fields: Vec<&str> = cron.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            return Err(format!("{cron:?} does not have five fields"));
+        };
+        if duration.is_zero() || duration > MAX_WINDOW {
+            return Err(format!("window of {duration:?} is not within 31 days"));
+        }
+        let mut day_of_week = Field::parse(day_of_week, 0, 7, &DAYS)?;
+        if day_of_week.has(7) {
+            day_of_week.bits |= 1;
+        }
+        Ok(Schedule {
+            minute: Field::parse(minute, 0, 59, &[])?,
+            hour: Field::parse(hour, 0, 23, &[])?,
+            day_of_month: Field::parse(day_of_month, 1, 31, &[])?,
+            month: Field::parse(month, 1, 12, &MONTHS)?,
+            day_of_week,
+            duration,
+            tz: TimeZone::get(tz).map_err(|e| format!("time zone {tz}: {e}"))?,
+            open: Mutex::new(None),
+        })
+    }
+
+    /// Whether a window opens at `time`, to the minute.
+    fn opens_at(&self, time: &Zoned) -> bool {
+        let days = match (self.day_of_month.any, self.day_of_week.any) {
+            (_, true) => self.day_of_month.has(time.day() as u32),
+            (true, false) => self
+                .day_of_week
+                .has(time.weekday().to_sunday_zero_offset() as u32),
+            (false, false) => {
+                self.day_of_month.has(time.day() as u32)
+                    || self
+                        .day_of_week
+                        .has(time.weekday().to_sunday_zero_offset() as u32)
+            }
+        };
+        self.minute.has(time.minute() as u32)
+            && self.hour.has(time.hour() as u32)
+            && self.month.has(time.month() as u32)
+            && days
+    }
+
+    /// Whether a window is open at `now`.
+    pub fn is_open_at(&self, now: Timestamp) -> bool {
+        let minute = now.as_second().div_euclid(60);
+        let window = self.duration.as_secs() as i64;
+        (0..window.div_euclid(60) + 1)
+            .map(|back| (minute - back) * 60)
+            .take_while(|start| now.as_second() - start < window)
+            .any(|start| {
+                Timestamp::from_second(start)
+                    .is_ok_and(|start| self.opens_at(&start.to_zoned(self.tz.clone())))
+            })
+    }
+
+    /// Whether a window is open now; evaluated once a minute.
+    pub fn is_open(&self) -> bool {
+        let now = Timestamp::now();
+        let minute = now.as_second().div_euclid(60);
+        let mut open = self.open.lock().unwrap();
+        match *open {
+            Some((at, is_open)) if at == minute => is_open,
+            _ => {
+                let is_open = self.is_open_at(now);
+                *open = Some((minute, is_open));
+                is_open
+            }
+        }
+    }
+}
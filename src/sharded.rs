@@ -0,0 +1,194 @@
+//! State sharded by worker thread.
+//!
+//! A counter every request updates, such as the requests in flight on an
+//! upstream, is one cache line that all worker threads write. Past about
+//! 100k requests a second, moving that line between cores, and waiting on
+//! the locks of shared maps, costs more than the updates themselves. State
+//! written on every request is split into one shard per core instead. A
+//! worker only writes its own shard, on a line of its own, and readers add
+//! the shards up.
+//!
+//! Readers on the request path cannot add up the shards of every upstream on
+//! every request. They read totals a [`ShardAggregator`] refreshes every few
+//! milliseconds, which may be behind by what the workers did since.
+//! `examples/contention.rs` compares the sharded counters with shared ones.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+
+/// Keeps each shard on cache lines of its own, two as adjacent lines are
+/// fetched together.
+#[derive(Default)]
+#[repr(align(128))]
+struct Padded<T>(T);
+
+/// The number of shards, one per core.
+pub fn shards() -> usize {
+    static SHARDS: OnceLock<usize> = OnceLock::new();
+    *SHARDS.get_or_init(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+/// The number of the current worker thread, given out on first use.
+pub(crate) fn worker() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    thread_local! {
+        static WORKER: Cell<Option<u64>> = const { Cell::new(None) };
+    }
+    WORKER.with(|worker| match worker.get() {
+        Some(n) => n,
+        None => {
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            worker.set(Some(n));
+            n
+        }
+    })
+}
+
+/// The shard of the current worker thread.
+fn shard() -> usize {
+    worker() as usize % shards()
+}
+
+fn padded<T: Default>() -> Box<[Padded<T>]> {
+    (0..shards()).map(|_| Padded::default()).collect()
+}
+
+/// A count that only goes up.
+pub struct Counter {
+    shards: Box<[Padded<AtomicU64>]>,
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Counter { shards: padded() }
+    }
+}
+
+impl Counter {
+    pub fn add(&self, n: u64) {
+        self.shards[shard()].0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|s| s.0.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+/// A count that goes up and down, with the total as last aggregated. One
+/// worker may add what another subtracts, so shards can go below zero; only
+/// their sum means anything.
+pub struct Gauge {
+    shards: Box<[Padded<AtomicI64>]>,
+    total: AtomicI64,
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Gauge {
+            shards: padded(),
+            total: AtomicI64::new(0),
+        }
+    }
+}
+
+impl Gauge {
+    pub fn add(&self, n: i64) {
+        self.shards[shard()].0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The sum of the shards now.
+    pub fn sum(&self) -> i64 {
+        self.shards
+            .iter()
+            .map(|s| s.0.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The sum as of the last [`Gauge::aggregate`].
+    pub fn total(&self) -> i64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub fn aggregate(&self) {
+        self.total.store(self.sum(), Ordering::Relaxed);
+    }
+}
+
+/// A value per shard, each behind a lock of its own that only contends with
+/// readers.
+pub struct Sharded<T> {
+    shards: Box<[Padded<Mutex<T>>]>,
+}
+
+impl<T: Default> Default for Sharded<T> {
+    fn default() -> Self {
+        Sharded { shards: padded() }
+    }
+}
+
+impl<T> Sharded<T> {
+    /// The value of the current worker's shard.
+    pub fn local(&self) -> MutexGuard<'_, T> {
+        self.shards[shard()].0.lock().unwrap()
+    }
+
+    /// The values of all shards, one after another.
+    pub fn each(&self, mut f: impl FnMut(&mut T)) {
+        for shard in &*self.shards {
+            f(&mut shard.0.lock().unwrap());
+        }
+    }
+}
+
+/// State whose aggregated totals a [`ShardAggregator`] refreshes.
+pub trait Aggregate: Send + Sync {
+    fn aggregate(&self);
+}
+
+/// Background service aggregating the shards of its targets every
+/// `interval`.
+pub struct ShardAggregator {
+    interval: Duration,
+    targets: Vec<Arc<dyn Aggregate>>,
+}
+
+impl ShardAggregator {
+    pub fn new(interval: Duration) -> Self {
+        ShardAggregator {
+            interval,
+            targets: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, target: Arc<dyn Aggregate>) -> Self {
+        self.targets.push(target);
+        self
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ShardAggregator {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = interval.tick() => {
+                    for target in &self.targets {
+                        target.aggregate();
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! One listening port for HTTP, TLS and plain TCP.
+//!
+//! For when a firewall only lets one port through: the listener peeks at the
+//! first bytes of each connection and hands it to
+//!
+//! - the TLS terminator when they open a TLS handshake, and then to the HTTP
+//!   application, with `h2` and `http/1.1` offered through ALPN,
+//! - the HTTP application when they start an HTTP/1 request line or the
+//!   HTTP/2 preface,
+//! - the raw TCP route otherwise, which relays the connection as it is.
+//!
+//! The ClientHello of a TLS connection is peeked at too, for its
+//! [`crate::fingerprint`].
+//!
+//! Protocols where the server speaks first send nothing to peek at, so a
+//! connection that stays silent for `sniff_timeout` goes to the TCP route too.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use pingora::apps::ServerApp;
+use pingora::connectors::TransportConnector;
+use pingora::protocols::Stream;
+use pingora::protocols::l4::stream::Stream as L4Stream;
+use pingora::protocols::tls::server::handshake_with_callback;
+use pingora::server::ShutdownWatch;
+use pingora::tls::ssl::{self, AlpnError, SslAcceptor, SslFiletype, SslMethod};
+use pingora::upstreams::peer::BasicPeer;
+use pingora::{ErrorType, OrErr, Result};
+
+use crate::fingerprint::{self, AttachFingerprint, TlsFingerprint};
+
+const TLS_CONFIG_ERROR: ErrorType = ErrorType::Custom("TLSConfigError");
+
+/// First byte of a TLS handshake record.
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// The request line starts of all methods proxied, and the HTTP/2 preface.
+const HTTP_STARTS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+    b"PRI ",
+];
+
+#[derive(Clone, Debug)]
+pub struct SniffConfig {
+    /// Certificate and key paths (PEM) of the TLS terminator; without them
+    /// TLS connections are refused.
+    pub tls: Option<(String, String)>,
+    /// Where to relay connections that are neither HTTP nor TLS; without it
+    /// they are closed.
+    pub tcp_upstream: Option<String>,
+    /// How long a new connection may stay silent before it counts as TCP.
+    pub sniff_timeout: Duration,
+}
+
+impl Default for SniffConfig {
+    fn default() -> Self {
+        SniffConfig {
+            tls: None,
+            tcp_upstream: None,
+            sniff_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Protocol {
+    Http,
+    Tls,
+    Tcp,
+}
+
+/// A [`ServerApp`] dispatching the connections of one port to `http` or its
+/// own TLS terminator and TCP relay.
+pub struct Sniffer<H> {
+    http: Arc<H>,
+    tls: Option<SslAcceptor>,
+    tcp_upstream: Option<BasicPeer>,
+    connector: TransportConnector,
+    sniff_timeout: Duration,
+}
+
+impl<H> Sniffer<H> {
+    pub fn new(http: H, config: SniffConfig) -> Result<Self> {
+        let tls = match &config.tls {
+            Some((cert, key)) => Some(acceptor(cert, key, b"\x02h2\x08http/1.1")?),
+            None => None,
+        };
+        Ok(Sniffer {
+            http: Arc::new(http),
+            tls,
+            tcp_upstream: config.tcp_upstream.as_deref().map(BasicPeer::new),
+            connector: TransportConnector::new(None),
+            sniff_timeout: config.sniff_timeout,
+        })
+    }
+}
+
+/// A TLS acceptor for `cert` and `key`, offering the ALPN protocols `alpn`
+/// in wire format.
+pub(crate) fn acceptor(cert: &str, key: &str, alpn: &'static [u8]) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+        .or_err(TLS_CONFIG_ERROR, "creating TLS acceptor")?;
+    builder
+        .set_certificate_chain_file(cert)
+        .or_err_with(TLS_CONFIG_ERROR, || format!("loading certificate {cert}"))?;
+    builder
+        .set_private_key_file(key, SslFiletype::PEM)
+        .or_err_with(TLS_CONFIG_ERROR, || format!("loading key {key}"))?;
+    builder.set_alpn_select_callback(move |_, client| {
+        ssl::select_next_proto(alpn, client).ok_or(AlpnError::NOACK)
+    });
+    Ok(builder.build())
+}
+
+/// Which protocol the connection speaks, peeking without consuming.
+async fn sniff(stream: &mut Stream, timeout: Duration) -> std::io::Result<Protocol> {
+    let mut first = [0u8; 1];
+    // a single byte is never read in part, so giving up on it loses nothing
+    match tokio::time::timeout(timeout, stream.try_peek(&mut first)).await {
+        Err(_) => return Ok(Protocol::Tcp),
+        // cannot peek, so not a plain TCP connection; the HTTP app decides
+        Ok(peeked) => {
+            if !peeked? {
+                return Ok(Protocol::Http);
+            }
+        }
+    }
+    if first[0] == TLS_HANDSHAKE {
+        return Ok(Protocol::Tls);
+    }
+
+    // grow the peek until it can only be a request line or cannot be one
+    let mut peeked = vec![first[0]];
+    loop {
+        if HTTP_STARTS.iter().any(|s| peeked.starts_with(s)) {
+            return Ok(Protocol::Http);
+        }
+        if !HTTP_STARTS.iter().any(|s| s.starts_with(&peeked)) {
+            return Ok(Protocol::Tcp);
+        }
+        peeked.push(0);
+        stream.try_peek(&mut peeked).await?;
+    }
+}
+
+/// The fingerprint of the ClientHello the TLS connection opens with, peeked
+/// at without consuming; `None` if it does not arrive within `timeout`.
+async fn client_hello(stream: &mut Stream, timeout: Duration) -> Option<TlsFingerprint> {
+    let peek = async {
+        let mut record = vec![0u8; 5];
+        if !stream.try_peek(&mut record).await.ok()? {
+            return None;
+        }
+        record.resize(fingerprint::record_len(&record)?, 0);
+        stream.try_peek(&mut record).await.ok()?;
+        fingerprint::from_client_hello(&record)
+    };
+    tokio::time::timeout(timeout, peek).await.ok().flatten()
+}
+
+impl<H: ServerApp + Send + Sync + 'static> Sniffer<H> {
+    /// Run the HTTP application on `stream` for as long as it keeps it.
+    async fn serve_http(&self, stream: Stream, shutdown: &ShutdownWatch) {
+        let mut stream = Some(stream);
+        while let Some(reused) = stream.take() {
+            stream = self.http.process_new(reused, shutdown).await;
+        }
+    }
+
+    async fn relay_tcp(&self, mut downstream: Stream) {
+        let Some(peer) = &self.tcp_upstream else {
+            debug!("no TCP route, closing connection");
+            return;
+        };
+        let mut upstream = match self.connector.new_stream(peer).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                warn!("connecting TCP route: {e}");
+                return;
+            }
+        };
+        if let Err(e) = tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await {
+            debug!("TCP route relay ended: {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl<H: ServerApp + Send + Sync + 'static> ServerApp for Sniffer<H> {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut session: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let protocol = match sniff(&mut session, self.sniff_timeout).await {
+            Ok(protocol) => protocol,
+            Err(e) => {
+                debug!("connection closed before it could be sniffed: {e}");
+                return None;
+            }
+        };
+        match protocol {
+            Protocol::Http => self.serve_http(session, shutdown).await,
+            Protocol::Tls => {
+                let Some(acceptor) = &self.tls else {
+                    debug!("TLS not configured, closing connection");
+                    return None;
+                };
+                let fingerprint = client_hello(&mut session, self.sniff_timeout).await;
+                // the handshake needs the TCP stream itself
+                let Ok(tcp) = session.into_any().downcast::<L4Stream>() else {
+                    debug!("TLS on a non-TCP stream, closing connection");
+                    return None;
+                };
+                let attach = AttachFingerprint(fingerprint.map(Arc::new));
+                match handshake_with_callback(acceptor, *tcp, &attach).await {
+                    Ok(tls) => self.serve_http(Box::new(tls), shutdown).await,
+                    Err(e) => debug!("TLS handshake failed: {e}"),
+                }
+            }
+            Protocol::Tcp => self.relay_tcp(session).await,
+        }
+        None
+    }
+}
@@ -0,0 +1,124 @@
+//! Write stalls: time spent waiting for a peer to take more data.
+//!
+//! Each request adds the time its writes were pending, blocked on socket
+//! backpressure, to two histograms of its route: writes to the client
+//! (slow clients) and writes of the request to the upstream (slow origins).
+//! Both come from the accumulated pending time of the HTTP/1.x connections;
+//! HTTP/2 streams share their connection and are not measured.
+//!
+//! Every worker records into histograms of its own, see [`crate::sharded`],
+//! which are added up when read.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde_json::{Value, json};
+
+use crate::sharded::Sharded;
+
+/// Upper bounds of the buckets, in milliseconds; the last bucket is open.
+const BOUNDS_MS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Clone, Default)]
+struct Histogram {
+    buckets: [u64; BOUNDS_MS.len() + 1],
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, stall: Duration) {
+        let ms = stall.as_millis() as u64;
+        let bucket = BOUNDS_MS
+            .iter()
+            .position(|&bound| ms <= bound)
+            .unwrap_or(BOUNDS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += stall;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (bucket, n) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += n;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    /// Cumulative counts, as Prometheus histograms have them.
+    fn to_json(&self) -> Value {
+        let mut cumulative = 0;
+        let buckets: Vec<Value> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                cumulative += count;
+                let le = BOUNDS_MS.get(i).map_or(json!("+Inf"), |b| json!(b));
+                json!({ "le_ms": le, "count": cumulative })
+            })
+            .collect();
+        json!({
+            "count": self.count,
+            "sum_ms": self.sum.as_secs_f64() * 1000.0,
+            "buckets": buckets,
+        })
+    }
+}
+
+#[derive(Clone, Default)]
+struct RouteStalls {
+    downstream: Histogram,
+    upstream: Histogram,
+}
+
+/// Write stall histograms of all routes, by route name; requests without a
+/// route count as `default`.
+#[derive(Default)]
+pub struct WriteStalls {
+    routes: Sharded<BTreeMap<String, RouteStalls>>,
+}
+
+impl WriteStalls {
+    /// Record the stalls of one request; `None` where they were not measured.
+    pub fn observe(&self, route: &str, downstream: Option<Duration>, upstream: Option<Duration>) {
+        if downstream.is_none() && upstream.is_none() {
+            return;
+        }
+        let route = if route.is_empty() { "default" } else { route };
+        let mut routes = self.routes.local();
+        if !routes.contains_key(route) {
+            routes.insert(route.to_string(), RouteStalls::default());
+        }
+        let stalls = routes.get_mut(route).unwrap();
+        if let Some(stall) = downstream {
+            stalls.downstream.observe(stall);
+        }
+        if let Some(stall) = upstream {
+            stalls.upstream.observe(stall);
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut total: BTreeMap<String, RouteStalls> = BTreeMap::new();
+        self.routes.each(|routes| {
+            for (name, stalls) in routes.iter() {
+                let sum = total.entry(name.clone()).or_default();
+                sum.downstream.merge(&stalls.downstream);
+                sum.upstream.merge(&stalls.upstream);
+            }
+        });
+        let routes: serde_json::Map<String, Value> = total
+            .iter()
+            .map(|(name, stalls)| {
+                let value = json!({
+                    "downstream": stalls.downstream.to_json(),
+                    "upstream": stalls.upstream.to_json(),
+                });
+                (name.clone(), value)
+            })
+            .collect();
+        json!({ "routes": routes })
+    }
+}
@@ -0,0 +1,190 @@
+//! Startup validation of the clusters.
+//!
+//! A background service the proxy depends on, so it only starts accepting
+//! traffic once every cluster has enough upstreams: reachable with a TCP
+//! connection, or for clusters whose upstreams may refuse this host, merely
+//! resolved by discovery. Clusters still short after the wait are logged and
+//! the proxy starts anyway, unless fail-fast is on and a required cluster
+//! has no upstream at all; then the process exits non-zero, so that a deploy
+//! with broken upstream configuration fails right away.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{error, info, warn};
+use pingora::connectors::TransportConnector;
+use pingora::lb::{Backend, LoadBalancer, selection::RoundRobin};
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::server::ShutdownWatch;
+use pingora::services::ServiceReadyNotifier;
+use pingora::services::background::BackgroundService;
+use pingora::upstreams::peer::BasicPeer;
+
+/// How a cluster's upstreams count as up.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Check {
+    /// They accept a TCP connection.
+    #[default]
+    Reachable,
+    /// Discovery found them.
+    Resolvable,
+}
+
+pub struct ClusterProbe {
+    pub name: String,
+    pub upstreams: Arc<LoadBalancer<RoundRobin>>,
+    pub check: Check,
+    /// Upstreams that must be up.
+    pub min_up: usize,
+    /// Whether fail-fast exits when none is up.
+    pub required: bool,
+}
+
+impl ClusterProbe {
+    /// A required cluster needing one reachable upstream.
+    pub fn new(name: impl Into<String>, upstreams: Arc<LoadBalancer<RoundRobin>>) -> Self {
+        ClusterProbe {
+            name: name.into(),
+            upstreams,
+            check: Check::Reachable,
+            min_up: 1,
+            required: true,
+        }
+    }
+}
+
+pub struct StartupProbe {
+    clusters: Vec<ClusterProbe>,
+    /// how long to keep probing clusters that are short
+    wait: Duration,
+    interval: Duration,
+    connect_timeout: Duration,
+    fail_fast: bool,
+    connector: TransportConnector,
+    done: AtomicBool,
+}
+
+impl StartupProbe {
+    pub fn new(clusters: Vec<ClusterProbe>, wait: Duration) -> Self {
+        StartupProbe {
+            clusters,
+            wait,
+            interval: Duration::from_secs(1),
+            connect_timeout: Duration::from_secs(2),
+            fail_fast: false,
+            connector: TransportConnector::new(None),
+            done: AtomicBool::new(false),
+        }
+    }
+
+    /// Exit the process when a required cluster is still entirely down after
+    /// the wait.
+    pub fn with_fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Whether the probe is over, the clusters up or the wait spent.
+    pub fn done(&self) -> bool {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    async fn reachable(&self, backend: &Backend) -> bool {
+        let mut peer = match &backend.addr {
+            SocketAddr::Inet(addr) => BasicPeer::new(&addr.to_string()),
+            SocketAddr::Unix(addr) => match addr.as_pathname().map(BasicPeer::new_uds) {
+                Some(Ok(peer)) => peer,
+                _ => return false,
+            },
+        };
+        peer.options.connection_timeout = Some(self.connect_timeout);
+        self.connector.new_stream(&peer).await.is_ok()
+    }
+
+    /// Upstreams of `cluster` that are up.
+    async fn up(&self, cluster: &ClusterProbe) -> usize {
+        let backends = cluster.upstreams.backends().get_backend();
+        match cluster.check {
+            Check::Resolvable => backends.len(),
+            Check::Reachable => join_all(backends.iter().map(|b| self.reachable(b)))
+                .await
+                .into_iter()
+                .filter(|&up| up)
+                .count(),
+        }
+    }
+
+    /// Probe until every cluster has its upstreams or the wait is over; the
+    /// clusters still short, with their upstreams up.
+    async fn wait_for_upstreams(
+        &self,
+        shutdown: &mut ShutdownWatch,
+    ) -> Vec<(&ClusterProbe, usize)> {
+        let started = Instant::now();
+        loop {
+            let up = join_all(self.clusters.iter().map(|c| self.up(c))).await;
+            let short: Vec<_> = self
+                .clusters
+                .iter()
+                .zip(up)
+                .filter(|(cluster, up)| *up < cluster.min_up)
+                .collect();
+            if short.is_empty() || started.elapsed() >= self.wait {
+                return short;
+            }
+            tokio::select! {
+                _ = shutdown.changed() => return short,
+                _ = tokio::time::sleep(self.interval) => {}
+            }
+        }
+    }
+
+    async fn run(&self, mut shutdown: ShutdownWatch) {
+        let short = self.wait_for_upstreams(&mut shutdown).await;
+        if short.is_empty() {
+            info!("every cluster has its upstreams up");
+            return;
+        }
+        for (cluster, up) in &short {
+            warn!(
+                "cluster {} has {up} of the {} upstreams it needs up",
+                cluster.name, cluster.min_up
+            );
+        }
+        let dead: Vec<&str> = short
+            .iter()
+            .filter(|(cluster, up)| cluster.required && *up == 0)
+            .map(|(cluster, _)| cluster.name.as_str())
+            .collect();
+        if self.fail_fast && !dead.is_empty() {
+            error!("required clusters have no upstream up: {}", dead.join(", "));
+            std::process::exit(1);
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for StartupProbe {
+    async fn start_with_ready_notifier(
+        &self,
+        shutdown: ShutdownWatch,
+        ready_notifier: ServiceReadyNotifier,
+    ) {
+        self.run(shutdown).await;
+        self.done.store(true, Ordering::Relaxed);
+        ready_notifier.notify_ready();
+    }
+
+    async fn start(&self, shutdown: ShutdownWatch) {
+        self.run(shutdown).await;
+        self.done.store(true, Ordering::Relaxed);
+    }
+}
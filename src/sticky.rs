@@ -0,0 +1,108 @@
+//! Cookie based session affinity.
+//!
+//! The first response of a session pins it to its upstream with a cookie, and
+//! later requests carrying the cookie go to the same upstream while it is
+//! healthy and in the cluster. The cookie names the upstream by an opaque id
+//! and records when the pin was made, so the pin lasts `ttl` whatever the
+//! client does with `Max-Age`.
+//!
+//! When the pinned upstream starts draining, [`DrainPolicy`] decides how the
+//! session moves off it. With either policy a session whose upstream has left
+//! the cluster is pinned anew, so a `ttl` beyond the drain grace only delays
+//! the move for honored sessions until the upstream is gone.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::header;
+use pingora::http::RequestHeader;
+use pingora::lb::Backend;
+
+/// What happens to sessions pinned to a draining upstream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DrainPolicy {
+    /// Keep sending them to it until their pin expires.
+    #[default]
+    HonorUntilExpiry,
+    /// Serve the current request from it, and pin the session to another
+    /// upstream with the response.
+    Repin,
+}
+
+#[derive(Clone, Debug)]
+pub struct StickySessions {
+    /// Name of the affinity cookie.
+    pub cookie: String,
+    /// How long a pin lasts.
+    pub ttl: Duration,
+    pub on_drain: DrainPolicy,
+}
+
+impl Default for StickySessions {
+    fn default() -> Self {
+        StickySessions {
+            cookie: "proxy-rs-upstream".to_string(),
+            ttl: Duration::from_secs(3600),
+            on_drain: DrainPolicy::default(),
+        }
+    }
+}
+
+/// A session's pin as read from its cookie.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Pin {
+    upstream: u64,
+    /// Unix time the pin was made, in seconds.
+    issued: u64,
+}
+
+impl Pin {
+    pub(crate) fn is_for(&self, backend: &Backend) -> bool {
+        self.upstream == upstream_id(backend)
+    }
+}
+
+impl StickySessions {
+    /// The unexpired pin of the request, if it has one.
+    pub(crate) fn pin(&self, req: &RequestHeader) -> Option<Pin> {
+        let value = req
+            .headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find_map(|(name, value)| (name == self.cookie).then_some(value))?;
+        let (upstream, issued) = value.split_once('.')?;
+        let pin = Pin {
+            upstream: u64::from_str_radix(upstream, 16).ok()?,
+            issued: u64::from_str_radix(issued, 16).ok()?,
+        };
+        (pin.issued.saturating_add(self.ttl.as_secs()) > now()).then_some(pin)
+    }
+
+    /// The `Set-Cookie` value pinning the session to `backend`.
+    pub(crate) fn set_cookie(&self, backend: &Backend) -> String {
+        format!(
+            "{}={:016x}.{:x}; Max-Age={}; Path=/; HttpOnly",
+            self.cookie,
+            upstream_id(backend),
+            now(),
+            self.ttl.as_secs()
+        )
+    }
+}
+
+/// Stable id of an upstream that does not reveal its address.
+fn upstream_id(backend: &Backend) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    backend.addr.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,128 @@
+//! Long-lived streaming responses: log tails, event streams, anything that
+//! keeps sending for as long as the client listens.
+//!
+//! A streaming route has no total response timeout, only an idle timeout
+//! between two pieces of the upstream response. Its responses bypass the
+//! cache and are sent on as they arrive, with `X-Accel-Buffering: no` telling
+//! downstream proxies to do the same.
+//!
+//! Server-sent events are relayed on their own path, so that while the
+//! upstream is quiet a comment line can be sent every `sse_keepalive` to keep
+//! idle-connection reapers between the proxy and the client from closing
+//! the stream.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::header;
+use pingora::connectors::http::Connector;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::proxy::Session;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::{Error, ErrorType, Result};
+
+/// Sent while an event stream is quiet; ignored by `EventSource` clients.
+const KEEPALIVE_COMMENT: &[u8] = b": keepalive\n\n";
+
+#[derive(Clone, Debug, Default)]
+pub struct StreamConfig {
+    /// Longest wait for the next piece of a response; `None` waits forever.
+    pub idle_timeout: Option<Duration>,
+    /// Comment interval for quiet `text/event-stream` responses; `None` sends
+    /// none and proxies event streams like any other stream.
+    pub sse_keepalive: Option<Duration>,
+}
+
+/// Whether the client asks for server-sent events.
+pub fn wants_events(req: &RequestHeader) -> bool {
+    req.headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("text/event-stream"))
+}
+
+fn is_event_stream(resp: &ResponseHeader) -> bool {
+    resp.headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().starts_with("text/event-stream"))
+}
+
+/// Mark a streaming response as not to be buffered.
+pub fn disable_buffering(resp: &mut ResponseHeader) -> Result<()> {
+    resp.insert_header("X-Accel-Buffering", "no")
+}
+
+/// Relay a body-less request to `peer` and its response to `session` as it
+/// comes, with keepalive comments while an event stream is quiet.
+///
+/// `fix_header` adjusts the response header before it is sent downstream.
+pub(crate) async fn relay_events(
+    connector: &Connector,
+    peer: &HttpPeer,
+    req: RequestHeader,
+    session: &mut Session,
+    config: &StreamConfig,
+    fix_header: impl FnOnce(&mut ResponseHeader) -> Result<()>,
+) -> Result<()> {
+    let (mut upstream, _reused) = connector.get_http_session(peer).await?;
+    upstream.set_read_timeout(None);
+    upstream.write_request_header(Box::new(req)).await?;
+    upstream.finish_request_body().await?;
+    read_with_idle_timeout(config, upstream.read_response_header()).await?;
+    let mut header = upstream
+        .response_header()
+        .expect("response header is read")
+        .clone();
+    let keepalive = config.sse_keepalive.filter(|_| is_event_stream(&header));
+    disable_buffering(&mut header)?;
+    fix_header(&mut header)?;
+    session
+        .write_response_header(Box::new(header), false)
+        .await?;
+
+    let mut quiet = Duration::ZERO;
+    loop {
+        let tick = keepalive.unwrap_or(Duration::MAX);
+        let wait = match config.idle_timeout {
+            Some(idle) => tick.min(idle.saturating_sub(quiet)),
+            None => tick,
+        };
+        tokio::select! {
+            body = upstream.read_response_body() => match body? {
+                Some(data) => {
+                    quiet = Duration::ZERO;
+                    session.write_response_body(Some(data), false).await?;
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep(wait) => {
+                quiet += wait;
+                if config.idle_timeout.is_some_and(|idle| quiet >= idle) {
+                    return Error::e_explain(ErrorType::ReadTimedout, "upstream stream idle");
+                }
+                if keepalive.is_some() {
+                    let comment = Bytes::from_static(KEEPALIVE_COMMENT);
+                    session.write_response_body(Some(comment), false).await?;
+                }
+            }
+        }
+    }
+    session.write_response_body(None, true).await?;
+    connector.release_http_session(upstream, peer, None).await;
+    Ok(())
+}
+
+async fn read_with_idle_timeout(
+    config: &StreamConfig,
+    read: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    match config.idle_timeout {
+        Some(idle) => match tokio::time::timeout(idle, read).await {
+            Ok(res) => res,
+            Err(_) => Error::e_explain(ErrorType::ReadTimedout, "waiting for response header"),
+        },
+        None => read.await,
+    }
+}
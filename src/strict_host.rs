@@ -0,0 +1,99 @@
+//! Strict-host mode.
+//!
+//! Without it, a request for any host that no route claims goes to the
+//! host-less routes and the default cluster, `Host` and all: an upstream that
+//! builds links or redirects from it, or a cache that keys on it, can be fed
+//! a host of the client's choosing. With [`StrictHosts`] on the listener the
+//! proxy serves only the hosts its routes name and those allowed explicitly;
+//! requests for any other get `421 Misdirected Request`, and requests without
+//! a usable host, with more than one, or whose target names another host than
+//! their `Host` header, `400 Bad Request`. Nothing is forwarded for them.
+
+use http::header;
+use pingora::http::RequestHeader;
+
+use crate::route::{Router, request_host};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostCheck {
+    Allowed,
+    /// No host, several, or an ambiguous one: answer 400.
+    Invalid,
+    /// A host the proxy does not serve: answer 421.
+    Unknown,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StrictHosts {
+    /// Lowercase hosts served besides those of the routes; `*.example.com`
+    /// allows the subdomains of `example.com`, not `example.com` itself.
+    allowed: Vec<String>,
+}
+
+impl StrictHosts {
+    pub fn new<S: AsRef<str>>(allowed: impl IntoIterator<Item = S>) -> Self {
+        StrictHosts {
+            allowed: allowed
+                .into_iter()
+                .map(|host| host.as_ref().to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Whether `req` may be served by the routes of `router`.
+    pub fn check(&self, req: &RequestHeader, router: &Router) -> HostCheck {
+        let mut hosts = req.headers.get_all(header::HOST).iter();
+        let header = hosts.next();
+        if hosts.next().is_some() {
+            return HostCheck::Invalid;
+        }
+        let Some(host) = request_host(req) else {
+            return HostCheck::Invalid;
+        };
+        // an absolute target wins over `Host` upstream, they must agree
+        if header.is_some()
+            && let Some(target) = req.uri.host()
+            && !target
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(host.trim_end_matches('.'))
+        {
+            return HostCheck::Invalid;
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if !valid_host(&host) {
+            return HostCheck::Invalid;
+        }
+        if router.serves_host(&host) || self.allowed.iter().any(|a| host_matches(a, &host)) {
+            HostCheck::Allowed
+        } else {
+            HostCheck::Unknown
+        }
+    }
+}
+
+/// A DNS name, an IPv4 address or a bracketed IPv6 one.
+fn valid_host(host: &str) -> bool {
+    if let Some(literal) = host.strip_prefix('[') {
+        return literal
+            .strip_suffix(']')
+            .is_some_and(|v6| v6.parse::<std::net::Ipv6Addr>().is_ok());
+    }
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+fn host_matches(allowed: &str, host: &str) -> bool {
+    match allowed.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => allowed == host,
+    }
+}
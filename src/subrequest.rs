@@ -0,0 +1,98 @@
+//! Requests the proxy sends to an upstream on its own behalf, outside the
+//! regular proxy pipeline, with the response buffered in memory.
+
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use http::{Uri, header};
+use pingora::Result;
+use pingora::connectors::http::Connector;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::upstreams::peer::HttpPeer;
+
+/// A fully buffered upstream response.
+pub struct Fetched {
+    pub header: ResponseHeader,
+    pub body: Bytes,
+}
+
+/// The peer serving `url`, an absolute `http` or `https` URL, and a request
+/// for it with `method` and its `Host` set.
+pub fn for_url(
+    method: &str,
+    url: &Uri,
+    connection_timeout: Duration,
+) -> std::result::Result<(HttpPeer, RequestHeader), String> {
+    let (Some(host), Some(authority)) = (url.host(), url.authority()) else {
+        return Err(format!("{url} has no host"));
+    };
+    let tls = match url.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => return Err(format!("{url} is not an http or https URL")),
+    };
+    let port = url.port_u16().unwrap_or(if tls { 443 } else { 80 });
+    let path = url.path_and_query().map_or("/", |p| p.as_str());
+    let mut req = RequestHeader::build(method, path.as_bytes(), None).map_err(|e| e.to_string())?;
+    req.insert_header(header::HOST, authority.as_str())
+        .map_err(|e| e.to_string())?;
+    let mut peer = HttpPeer::new((host, port), tls, host.to_string());
+    peer.options.connection_timeout = Some(connection_timeout);
+    Ok((peer, req))
+}
+
+/// Send a body-less request to `peer` and buffer the response.
+///
+/// Returns `Ok(None)` when the response body exceeds `max_body` bytes; the
+/// connection is dropped in that case instead of being drained.
+pub async fn fetch(
+    connector: &Connector,
+    peer: &HttpPeer,
+    req: RequestHeader,
+    max_body: usize,
+) -> Result<Option<Fetched>> {
+    send(connector, peer, req, Bytes::new(), max_body).await
+}
+
+/// Send a request with `body` to `peer` and buffer the response, like
+/// [`fetch`].
+pub async fn send(
+    connector: &Connector,
+    peer: &HttpPeer,
+    req: RequestHeader,
+    body: Bytes,
+    max_body: usize,
+) -> Result<Option<Fetched>> {
+    let (mut session, _reused) = connector.get_http_session(peer).await?;
+    session.write_request_header(Box::new(req)).await?;
+    if !body.is_empty() {
+        session.write_request_body(body, true).await?;
+    }
+    session.finish_request_body().await?;
+    session.read_response_header().await?;
+    let header = session
+        .response_header()
+        .expect("response header is read")
+        .clone();
+
+    let declared = header
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > max_body) {
+        return Ok(None);
+    }
+
+    let mut body = BytesMut::new();
+    while let Some(chunk) = session.read_response_body().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > max_body {
+            return Ok(None);
+        }
+    }
+    connector.release_http_session(session, peer, None).await;
+    Ok(Some(Fetched {
+        header,
+        body: body.freeze(),
+    }))
+}
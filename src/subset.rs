@@ -0,0 +1,125 @@
+//! Deterministic subsetting of large clusters.
+//!
+//! Instead of spreading over every upstream, each proxy instance or each
+//! client uses a fixed subset of `size` of them, which bounds the connections
+//! kept open when a cluster has hundreds of upstreams. Subsets are chosen by
+//! rendezvous hashing: of all upstreams, the ones ranking highest for the
+//! subset's seed. When upstreams join or leave, a subset only changes by
+//! the members that left and the newcomers that outrank its lowest member, so
+//! clients keep most of their connections.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use pingora::Result;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::{Backend, Backends};
+use pingora::protocols::l4::socket::SocketAddr;
+
+use crate::sharded::Sharded;
+
+/// Subsets remembered per cluster and worker; beyond this they are
+/// recomputed.
+const MAX_CACHED: usize = 10_000;
+
+/// The `size` backends ranking highest for `seed`.
+pub fn subset<'a>(
+    seed: &str,
+    backends: impl IntoIterator<Item = &'a Backend>,
+    size: usize,
+) -> Vec<&'a Backend> {
+    let mut ranked: Vec<_> = backends
+        .into_iter()
+        .map(|b| {
+            let mut hasher = DefaultHasher::new();
+            (seed, &b.addr).hash(&mut hasher);
+            (hasher.finish(), b)
+        })
+        .collect();
+    ranked.sort_unstable_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+    ranked.into_iter().take(size).map(|(_, b)| b).collect()
+}
+
+/// Wraps a discovery to keep only this instance's subset of its backends, so
+/// neither requests nor health checks go to the others.
+pub struct SubsetDiscovery {
+    inner: Box<dyn ServiceDiscovery + Send + Sync>,
+    /// e.g. the host name, distinct per proxy instance
+    instance: String,
+    size: usize,
+}
+
+impl SubsetDiscovery {
+    pub fn new(
+        inner: Box<dyn ServiceDiscovery + Send + Sync>,
+        instance: impl Into<String>,
+        size: usize,
+    ) -> Box<Self> {
+        Box::new(SubsetDiscovery {
+            inner,
+            instance: instance.into(),
+            size,
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for SubsetDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let (backends, enablement) = self.inner.discover().await?;
+        let subset = subset(&self.instance, &backends, self.size)
+            .into_iter()
+            .cloned()
+            .collect();
+        Ok((subset, enablement))
+    }
+}
+
+/// Per-client subsets of a route's cluster. Clients are told apart by their
+/// consumer when known, by their address otherwise. Each worker remembers
+/// the subsets it computed, so requests do not wait on each other for them.
+pub struct ClientSubsets {
+    size: usize,
+    cache: Sharded<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// the backends the subsets were taken from
+    backends: Option<Arc<BTreeSet<Backend>>>,
+    subsets: HashMap<String, Arc<HashSet<SocketAddr>>>,
+}
+
+impl ClientSubsets {
+    pub fn new(size: usize) -> Self {
+        ClientSubsets {
+            size,
+            cache: Sharded::default(),
+        }
+    }
+
+    /// The subset of `client` among `backends`, recomputed once they changed.
+    pub(crate) fn get(&self, backends: &Backends, client: &str) -> Arc<HashSet<SocketAddr>> {
+        let current = backends.get_backend();
+        let mut cache = self.cache.local();
+        let stale = !cache
+            .backends
+            .as_ref()
+            .is_some_and(|b| Arc::ptr_eq(b, &current));
+        if stale || cache.subsets.len() >= MAX_CACHED {
+            cache.subsets.clear();
+            cache.backends = Some(current.clone());
+        }
+        cache
+            .subsets
+            .entry(client.to_string())
+            .or_insert_with(|| {
+                let members = subset(client, current.iter(), self.size);
+                Arc::new(members.into_iter().map(|b| b.addr.clone()).collect())
+            })
+            .clone()
+    }
+}
@@ -0,0 +1,303 @@
+//! Synthetic monitoring through the proxy's own data path.
+//!
+//! A background service that sends each configured [`SyntheticCheck`] to a
+//! listener of the proxy over loopback on a fixed interval, so the request
+//! goes through routing, the filters and an upstream exactly as a client's
+//! would. A check passes when the response has an expected status and, if
+//! asked, contains a string. Runs, failures and latencies are kept per check
+//! for the admin API; the first failure after a pass is logged as a warning,
+//! as is the recovery.
+
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::join_all;
+use http::{Method, header};
+use log::{info, warn};
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::upstreams::peer::HttpPeer;
+use serde_json::{Value, json};
+
+use crate::subrequest;
+
+/// Header naming the check a synthetic request belongs to.
+pub const SYNTHETIC_HEADER: &str = "x-synthetic-check";
+
+/// Largest response body read for a check.
+const MAX_BODY: usize = 1 << 20;
+
+/// One synthetic request and what a passing response looks like.
+#[derive(Clone, Debug)]
+pub struct SyntheticCheck {
+    pub name: String,
+    /// The route the request is meant to hit, for reporting.
+    pub route: String,
+    pub method: Method,
+    /// Path and query of the request.
+    pub path: String,
+    /// `Host` of the request, the listener address if `None`.
+    pub host: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+    pub expect_status: RangeInclusive<u16>,
+    /// A string the response body must contain.
+    pub expect_body: Option<String>,
+    pub timeout: Duration,
+}
+
+impl SyntheticCheck {
+    /// A `GET` of `path` on `route` expecting a 2xx or 3xx within 5 seconds.
+    pub fn get(name: impl Into<String>, route: impl Into<String>, path: impl Into<String>) -> Self {
+        SyntheticCheck {
+            name: name.into(),
+            route: route.into(),
+            method: Method::GET,
+            path: path.into(),
+            host: None,
+            headers: Vec::new(),
+            body: Bytes::new(),
+            expect_status: 200..=399,
+            expect_body: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Send `method` with `body` instead of a body-less `GET`.
+    pub fn with_request(mut self, method: Method, body: impl Into<Bytes>) -> Self {
+        self.method = method;
+        self.body = body.into();
+        self
+    }
+
+    pub fn expect_status(mut self, status: RangeInclusive<u16>) -> Self {
+        self.expect_status = status;
+        self
+    }
+
+    pub fn expect_body(mut self, contains: impl Into<String>) -> Self {
+        self.expect_body = Some(contains.into());
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// The record of one check.
+#[derive(Clone, Debug, Default)]
+pub struct CheckStats {
+    pub runs: u64,
+    pub failures: u64,
+    pub consecutive_failures: u64,
+    /// Status of the last response, `None` if there was none.
+    pub last_status: Option<u16>,
+    /// Why the last run failed.
+    pub last_error: Option<String>,
+    pub last_latency: Option<Duration>,
+    pub max_latency: Duration,
+    /// Sum of the latencies of all runs, for the average.
+    pub total_latency: Duration,
+    pub last_run: Option<SystemTime>,
+}
+
+impl CheckStats {
+    fn to_json(&self, check: &SyntheticCheck) -> Value {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let last_run = self
+            .last_run
+            .map(|t| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+        json!({
+            "name": check.name,
+            "route": check.route,
+            "path": check.path,
+            "runs_total": self.runs,
+            "failures_total": self.failures,
+            "consecutive_failures": self.consecutive_failures,
+            "passing": self.runs > 0 && self.consecutive_failures == 0,
+            "last_status": self.last_status,
+            "last_error": self.last_error,
+            "last_run": last_run,
+            "latency_ms": {
+                "last": self.last_latency.map(ms),
+                "avg": (self.runs > 0).then(|| ms(self.total_latency) / self.runs as f64),
+                "max": ms(self.max_latency),
+            },
+        })
+    }
+}
+
+pub struct SyntheticProber {
+    /// the listener the checks are sent to, `127.0.0.1:6188`
+    target: String,
+    checks: Vec<SyntheticCheck>,
+    interval: Duration,
+    stats: RwLock<BTreeMap<String, CheckStats>>,
+    connector: Connector,
+}
+
+impl SyntheticProber {
+    pub fn new(target: impl Into<String>, checks: Vec<SyntheticCheck>, interval: Duration) -> Self {
+        SyntheticProber {
+            target: target.into(),
+            checks,
+            interval,
+            stats: RwLock::default(),
+            connector: Connector::new(None),
+        }
+    }
+
+    /// Run `check` once; the status of the response, or why it failed.
+    async fn run(&self, check: &SyntheticCheck) -> (Option<u16>, Result<(), String>) {
+        let mut req = match RequestHeader::build(check.method.clone(), check.path.as_bytes(), None)
+        {
+            Ok(req) => req,
+            Err(e) => return (None, Err(format!("bad request: {e}"))),
+        };
+        let host = check.host.as_deref().unwrap_or(&self.target);
+        let mut headers = vec![
+            (header::HOST.as_str(), host),
+            (SYNTHETIC_HEADER, check.name.as_str()),
+        ];
+        headers.extend(check.headers.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+        let length = check.body.len().to_string();
+        if !check.body.is_empty() {
+            headers.push((header::CONTENT_LENGTH.as_str(), &length));
+        }
+        for (name, value) in headers {
+            if let Err(e) = req.insert_header(name.to_string(), value) {
+                return (None, Err(format!("bad header {name}: {e}")));
+            }
+        }
+
+        let mut peer = HttpPeer::new(self.target.as_str(), false, String::new());
+        peer.options.connection_timeout = Some(check.timeout);
+        let sent = subrequest::send(&self.connector, &peer, req, check.body.clone(), MAX_BODY);
+        let fetched = match tokio::time::timeout(check.timeout, sent).await {
+            Err(_) => return (None, Err("timed out".to_string())),
+            Ok(Err(e)) => return (None, Err(e.to_string())),
+            Ok(Ok(None)) => return (None, Err("response body too large".to_string())),
+            Ok(Ok(Some(fetched))) => fetched,
+        };
+        let status = fetched.header.status.as_u16();
+        if !check.expect_status.contains(&status) {
+            return (Some(status), Err(format!("unexpected status {status}")));
+        }
+        if let Some(wanted) = &check.expect_body
+            && !String::from_utf8_lossy(&fetched.body).contains(wanted.as_str())
+        {
+            return (Some(status), Err(format!("body without {wanted:?}")));
+        }
+        (Some(status), Ok(()))
+    }
+
+    fn record(
+        &self,
+        check: &SyntheticCheck,
+        latency: Duration,
+        outcome: (Option<u16>, Result<(), String>),
+    ) {
+        let (status, result) = outcome;
+        let mut stats = self.stats.write().unwrap();
+        let stats = stats.entry(check.name.clone()).or_default();
+        stats.runs += 1;
+        stats.last_status = status;
+        stats.last_latency = Some(latency);
+        stats.max_latency = stats.max_latency.max(latency);
+        stats.total_latency += latency;
+        stats.last_run = Some(SystemTime::now());
+        match result {
+            Ok(()) => {
+                if stats.consecutive_failures > 0 {
+                    info!(
+                        "synthetic check {} passes again after {} failures",
+                        check.name, stats.consecutive_failures
+                    );
+                }
+                stats.consecutive_failures = 0;
+                stats.last_error = None;
+            }
+            Err(e) => {
+                if stats.consecutive_failures == 0 {
+                    warn!(
+                        "synthetic check {} on route {} failed: {e}",
+                        check.name, check.route
+                    );
+                }
+                stats.failures += 1;
+                stats.consecutive_failures += 1;
+                stats.last_error = Some(e);
+            }
+        }
+    }
+
+    async fn run_all(&self) {
+        join_all(self.checks.iter().map(|check| async move {
+            let started = Instant::now();
+            let outcome = self.run(check).await;
+            self.record(check, started.elapsed(), outcome);
+        }))
+        .await;
+    }
+
+    /// The stats of a check, `None` before its first run.
+    pub fn stats(&self, name: &str) -> Option<CheckStats> {
+        self.stats.read().unwrap().get(name).cloned()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let stats = self.stats.read().unwrap();
+        let checks: Vec<Value> = self
+            .checks
+            .iter()
+            .map(|check| {
+                stats
+                    .get(&check.name)
+                    .cloned()
+                    .unwrap_or_default()
+                    .to_json(check)
+            })
+            .collect();
+        json!({
+            "target": self.target,
+            "interval_ms": self.interval.as_millis() as u64,
+            "checks": checks,
+        })
+    }
+}
+
+#[async_trait]
+impl BackgroundService for SyntheticProber {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        info!(
+            "sending {} synthetic checks to {}",
+            self.checks.len(),
+            self.target
+        );
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(self.interval) => {}
+            }
+            self.run_all().await;
+        }
+    }
+}
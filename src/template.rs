@@ -0,0 +1,303 @@
+//! Templates for responses the proxy synthesizes itself: error pages,
+//! maintenance pages, pages for clusters without a live upstream and
+//! redirect interstitials.
+//!
+//! Every page exists as HTML and as JSON, the JSON flavour is served to clients
+//! preferring `application/json`. The built-in pages can be overridden from a
+//! directory: `<dir>/<page>.<html|json>` replaces a page for every tenant and
+//! `<dir>/<tenant>/<page>.<html|json>` for a single tenant only.
+//!
+//! Variables are written `{{name}}` and escaped for the output format; unknown
+//! variables render as the empty string.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Page {
+    /// The request could not be proxied.
+    Error,
+    /// The route is switched off for maintenance.
+    Maintenance,
+    /// No upstream of the route's cluster is up.
+    Unavailable,
+    /// The route redirects elsewhere; shown by clients not following redirects.
+    Redirect,
+}
+
+impl Page {
+    const ALL: [Page; 4] = [
+        Page::Error,
+        Page::Maintenance,
+        Page::Unavailable,
+        Page::Redirect,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Page::Error => "error",
+            Page::Maintenance => "maintenance",
+            Page::Unavailable => "unavailable",
+            Page::Redirect => "redirect",
+        }
+    }
+
+    fn builtin(self, format: Format) -> &'static str {
+        match (self, format) {
+            (Page::Error, Format::Html) => ERROR_HTML,
+            (Page::Error, Format::Json) => ERROR_JSON,
+            (Page::Maintenance, Format::Html) => MAINTENANCE_HTML,
+            (Page::Maintenance, Format::Json) => MAINTENANCE_JSON,
+            (Page::Unavailable, Format::Html) => UNAVAILABLE_HTML,
+            (Page::Unavailable, Format::Json) => UNAVAILABLE_JSON,
+            (Page::Redirect, Format::Html) => REDIRECT_HTML,
+            (Page::Redirect, Format::Json) => REDIRECT_JSON,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    const ALL: [Format; 2] = [Format::Html, Format::Json];
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Html => "html",
+            Format::Json => "json",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+
+    /// JSON if the client ranks it above HTML, HTML otherwise.
+    pub fn negotiate(accept: &str) -> Format {
+        if quality(accept, "application/json") > quality(accept, "text/html") {
+            Format::Json
+        } else {
+            Format::Html
+        }
+    }
+}
+
+type Key = (Option<String>, Page, Format);
+
+/// The page templates, built-in ones plus any overrides loaded from disk.
+#[derive(Default)]
+pub struct Templates {
+    overrides: HashMap<Key, String>,
+}
+
+impl Templates {
+    /// Load the overrides found in `dir`, see the module docs for the layout.
+    /// Files not named after a page and format are ignored.
+    pub fn load(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut overrides = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                let tenant = entry.file_name().to_string_lossy().into_owned();
+                for page in fs::read_dir(&path)? {
+                    let page = page?.path();
+                    load_page(&mut overrides, Some(&tenant), &page)?;
+                }
+            } else {
+                load_page(&mut overrides, None, &path)?;
+            }
+        }
+        Ok(Templates { overrides })
+    }
+
+    /// Render `page` for `tenant`, falling back from the tenant's override to
+    /// the global override to the built-in page.
+    pub fn render(
+        &self,
+        tenant: Option<&str>,
+        page: Page,
+        format: Format,
+        vars: &[(&str, &str)],
+    ) -> String {
+        let template = tenant
+            .and_then(|t| self.overrides.get(&(Some(t.to_string()), page, format)))
+            .or_else(|| self.overrides.get(&(None, page, format)))
+            .map_or(page.builtin(format), String::as_str);
+        substitute(template, format, vars)
+    }
+}
+
+fn load_page(
+    overrides: &mut HashMap<Key, String>,
+    tenant: Option<&str>,
+    path: &Path,
+) -> io::Result<()> {
+    let (Some(stem), Some(ext)) = (
+        path.file_stem().and_then(|s| s.to_str()),
+        path.extension().and_then(|s| s.to_str()),
+    ) else {
+        return Ok(());
+    };
+    let page = Page::ALL.into_iter().find(|p| p.name() == stem);
+    let format = Format::ALL.into_iter().find(|f| f.extension() == ext);
+    if let (Some(page), Some(format)) = (page, format) {
+        let template = fs::read_to_string(path)?;
+        overrides.insert((tenant.map(str::to_string), page, format), template);
+    }
+    Ok(())
+}
+
+fn substitute(template: &str, format: Format, vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find("{{") {
+        let Some(close) = rest[open + 2..].find("}}") else {
+            break;
+        };
+        out.push_str(&rest[..open]);
+        let name = rest[open + 2..open + 2 + close].trim();
+        if let Some((_, value)) = vars.iter().find(|(n, _)| *n == name) {
+            match format {
+                Format::Html => escape_html(value, &mut out),
+                Format::Json => escape_json(value, &mut out),
+            }
+        }
+        rest = &rest[open + 2 + close + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn escape_html(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Escape for use inside a JSON string literal.
+fn escape_json(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+}
+
+/// The highest quality `accept` gives to exactly `mime`, 0 if not listed.
+fn quality(accept: &str, mime: &str) -> f32 {
+    accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            if !parts.next()?.eq_ignore_ascii_case(mime) {
+                return None;
+            }
+            Some(
+                parts
+                    .find_map(|p| p.strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0),
+            )
+        })
+        .fold(0.0, f32::max)
+}
+
+/// `time` as an RFC 3339 timestamp in UTC, e.g. `2024-01-31T12:00:00Z`.
+pub fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+const ERROR_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+<p>Request ID: {{request_id}}<br>Time: {{timestamp}}</p>
+</body>
+</html>
+"#;
+
+const ERROR_JSON: &str = r#"{"status": {{status}}, "error": "{{reason}}", "route": "{{route}}", "request_id": "{{request_id}}", "timestamp": "{{timestamp}}"}
+"#;
+
+const MAINTENANCE_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Down for maintenance</title></head>
+<body>
+<h1>Down for maintenance</h1>
+<p>This service is undergoing maintenance, please try again later.</p>
+<p>Request ID: {{request_id}}<br>Time: {{timestamp}}</p>
+</body>
+</html>
+"#;
+
+const MAINTENANCE_JSON: &str = r#"{"status": {{status}}, "error": "maintenance", "route": "{{route}}", "request_id": "{{request_id}}", "timestamp": "{{timestamp}}"}
+"#;
+
+const UNAVAILABLE_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Service unavailable</title></head>
+<body>
+<h1>Service unavailable</h1>
+<p>No server is available to handle this request, please try again later.</p>
+<p>Request ID: {{request_id}}<br>Time: {{timestamp}}</p>
+</body>
+</html>
+"#;
+
+const UNAVAILABLE_JSON: &str = r#"{"status": {{status}}, "error": "unavailable", "route": "{{route}}", "request_id": "{{request_id}}", "timestamp": "{{timestamp}}"}
+"#;
+
+const REDIRECT_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Redirecting</title><meta http-equiv="refresh" content="0; url={{location}}"></head>
+<body>
+<p>Redirecting to <a href="{{location}}">{{location}}</a>.</p>
+</body>
+</html>
+"#;
+
+const REDIRECT_JSON: &str = r#"{"status": {{status}}, "location": "{{location}}", "request_id": "{{request_id}}"}
+"#;
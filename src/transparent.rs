@@ -0,0 +1,217 @@
+//! Transparent proxying with Linux TPROXY.
+//!
+//! With `IP_TRANSPARENT` on its listening socket, the proxy accepts the
+//! connections an iptables `TPROXY` rule diverts to it although they are
+//! addressed to other hosts, so it can be put in the path of clients that
+//! know nothing about it:
+//!
+//! ```text
+//! iptables -t mangle -A PREROUTING -p tcp --dport 80 \
+//!     -j TPROXY --on-port 6188 --tproxy-mark 1
+//! ip rule add fwmark 1 lookup 100
+//! ip route add local 0.0.0.0/0 dev lo table 100
+//! ```
+//!
+//! The local address of such a connection is the one the client dialed, and
+//! routes with a [`DstMatch`] match on it. Setting the option takes
+//! `CAP_NET_ADMIN`.
+//!
+//! pingora binds its listeners itself, so [`TransparentListener`] finds the
+//! listening socket of an address among the process's descriptors once it
+//! is bound and sets the option then; connections accepted from then on
+//! inherit it.
+
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::RawFd;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+
+use crate::family::Network;
+
+/// How long [`TransparentListener`] waits for the listener to be bound.
+const BIND_WAIT: Duration = Duration::from_secs(10);
+
+/// `IPV6_TRANSPARENT` from `linux/in6.h`, which libc does not export on
+/// every target.
+const IPV6_TRANSPARENT: libc::c_int = 75;
+
+/// The original destinations a route matches: any of the networks, on any of
+/// the ports. An empty list matches any.
+#[derive(Clone, Debug, Default)]
+pub struct DstMatch {
+    pub networks: Vec<Network>,
+    pub ports: Vec<u16>,
+}
+
+impl DstMatch {
+    /// Destinations in `networks`, as `10.20.0.0/16`, on any port.
+    pub fn networks<S: AsRef<str>>(networks: impl IntoIterator<Item = S>) -> Result<Self, String> {
+        let networks = networks
+            .into_iter()
+            .map(|n| Network::parse(n.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(DstMatch {
+            networks,
+            ports: Vec::new(),
+        })
+    }
+
+    /// Destinations on `ports`, on any address.
+    pub fn ports(ports: impl IntoIterator<Item = u16>) -> Self {
+        DstMatch {
+            networks: Vec::new(),
+            ports: ports.into_iter().collect(),
+        }
+    }
+
+    /// Also require one of `ports`.
+    pub fn with_ports(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+        self.ports = ports.into_iter().collect();
+        self
+    }
+
+    pub fn matches(&self, dst: SocketAddr) -> bool {
+        (self.networks.is_empty() || self.networks.iter().any(|n| n.contains(dst.ip())))
+            && (self.ports.is_empty() || self.ports.contains(&dst.port()))
+    }
+}
+
+/// Sets `IP_TRANSPARENT`, or `IPV6_TRANSPARENT`, on the listening socket of
+/// an address once pingora has bound it.
+pub struct TransparentListener {
+    addr: SocketAddr,
+}
+
+impl TransparentListener {
+    /// The listener bound to `addr`, as `0.0.0.0:6188` or `[::]:6188`.
+    pub fn new(addr: &str) -> Result<Self, String> {
+        let addr = addr
+            .parse()
+            .map_err(|e| format!("transparent listener {addr}: {e}"))?;
+        Ok(TransparentListener { addr })
+    }
+
+    /// Set the option on the listening socket, `Ok(false)` while there is
+    /// none yet.
+    fn apply(&self) -> Result<bool, String> {
+        let Some(fd) = listening_socket(self.addr) else {
+            return Ok(false);
+        };
+        let (level, name) = match self.addr.ip() {
+            IpAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+            IpAddr::V6(_) => (libc::SOL_IPV6, IPV6_TRANSPARENT),
+        };
+        let on: libc::c_int = 1;
+        // SAFETY: fd is an open socket of the process and `on` outlives the call
+        let set = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &on as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if set != 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+        Ok(true)
+    }
+}
+
+/// The descriptor of the TCP socket listening on `addr`, if any.
+fn listening_socket(addr: SocketAddr) -> Option<RawFd> {
+    std::fs::read_dir("/proc/self/fd")
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+        .find(|&fd| is_listening(fd) && local_addr(fd) == Some(addr))
+}
+
+fn is_listening(fd: RawFd) -> bool {
+    let mut listening: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: getsockopt writes at most `len` bytes to `listening`, and fails
+    // on descriptors that are not sockets
+    let got = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    got == 0 && listening != 0
+}
+
+fn local_addr(fd: RawFd) -> Option<SocketAddr> {
+    // SAFETY: sockaddr_storage is plain data, all zeroes is a valid value
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: getsockname writes at most `len` bytes to `storage`
+    let got = unsafe {
+        libc::getsockname(
+            fd,
+            &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if got != 0 {
+        return None;
+    }
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says storage holds a sockaddr_in
+            let v4 = unsafe { *(&storage as *const _ as *const libc::sockaddr_in) };
+            let ip = u32::from_be(v4.sin_addr.s_addr);
+            Some(SocketAddr::from((
+                ip.to_be_bytes(),
+                u16::from_be(v4.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says storage holds a sockaddr_in6
+            let v6 = unsafe { *(&storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::from((
+                v6.sin6_addr.s6_addr,
+                u16::from_be(v6.sin6_port),
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl BackgroundService for TransparentListener {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let started = Instant::now();
+        loop {
+            match self.apply() {
+                Ok(true) => {
+                    info!("listener {} accepts transparent connections", self.addr);
+                    return;
+                }
+                Ok(false) if started.elapsed() < BIND_WAIT => {}
+                Ok(false) => {
+                    warn!("no listener on {}, not transparent", self.addr);
+                    return;
+                }
+                Err(e) => {
+                    warn!(
+                        "could not make listener {} transparent, which takes CAP_NET_ADMIN: {e}",
+                        self.addr
+                    );
+                    return;
+                }
+            }
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,574 @@
+//! Uploads streamed straight to object storage.
+//!
+//! A route with an [`UploadTarget`] takes `multipart/form-data` `POST`s
+//! itself. Each file part is stored in the bucket as the object
+//! `<prefix><id>/<n>/<filename>`, `n` counting the files of the request:
+//! parts up to `part_size` are sent in a single `PUT`, larger ones as an S3
+//! multipart upload of `part_size` pieces, so no more than a piece per
+//! request is held in memory and no upload reaches the application backends.
+//! Form fields without a filename are skipped.
+//!
+//! The request is answered `201 Created` with the stored objects:
+//!
+//! ```json
+//! {"objects": [{"field": "avatar", "filename": "me.png", "key": "uploads/1f..e2/0/me.png",
+//!   "url": "https://bucket.s3.eu-west-1.amazonaws.com/uploads/1f..e2/0/me.png", "size": 48213}]}
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use http::{Method, StatusCode, header};
+use log::warn;
+use pingora::connectors::http::Connector;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::proxy::Session;
+use pingora::{Error, ErrorType, Result};
+use serde_json::json;
+
+use crate::s3::{self, Bucket};
+use crate::subrequest;
+
+/// Smallest piece S3 accepts in a multipart upload, but for the last one.
+const MIN_PART: usize = 5 * 1024 * 1024;
+
+/// Largest header block accepted for a part.
+const MAX_PART_HEADER: usize = 8 * 1024;
+
+/// Largest storage response read, an error or a multipart upload id.
+const MAX_STORAGE_RESPONSE: usize = 64 * 1024;
+
+pub struct UploadTarget {
+    bucket: Bucket,
+    prefix: String,
+    part_size: usize,
+    max_size: u64,
+    max_files: usize,
+    timeout: Duration,
+    http: Connector,
+}
+
+impl UploadTarget {
+    pub fn new(bucket: Bucket) -> Self {
+        UploadTarget {
+            bucket,
+            prefix: String::new(),
+            part_size: 8 * 1024 * 1024,
+            max_size: 100 * 1024 * 1024,
+            max_files: 16,
+            timeout: Duration::from_secs(30),
+            http: Connector::new(None),
+        }
+    }
+
+    /// Prefix of the stored object keys, `uploads/`.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Size of the pieces of large files, at least the 5 MiB S3 requires.
+    pub fn with_part_size(mut self, part_size: usize) -> Self {
+        self.part_size = part_size.max(MIN_PART);
+        self
+    }
+
+    /// Largest request body accepted, all parts together; larger ones are
+    /// refused with a 413.
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Most files stored per request.
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    /// Timeout of each request to the storage.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn bucket(&self) -> &Bucket {
+        &self.bucket
+    }
+
+    /// Why `req` is refused before its body is read, if it is.
+    pub(crate) fn refusal(&self, req: &RequestHeader) -> Option<StatusCode> {
+        if req.method != Method::POST {
+            return Some(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        if boundary(req).is_none() {
+            return Some(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+        let declared = req
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+        declared
+            .is_some_and(|len| len > self.max_size)
+            .then_some(StatusCode::PAYLOAD_TOO_LARGE)
+    }
+
+    /// Store the files of the upload of `session`, once [`Self::refusal`]
+    /// let it through.
+    pub(crate) async fn handle(&self, session: &mut Session) -> Result<(ResponseHeader, Bytes)> {
+        let boundary = boundary(session.req_header())
+            .ok_or_else(|| Error::explain(ErrorType::HTTPStatus(415), "not multipart/form-data"))?;
+        let mut form = Multipart::new(session, &boundary, self.max_size);
+        let id = upload_id();
+        let mut objects = Vec::new();
+        while let Some(part) = form.next_part().await? {
+            let Some(filename) = part.filename else {
+                continue;
+            };
+            if objects.len() >= self.max_files {
+                return Error::e_explain(ErrorType::HTTPStatus(413), "too many files");
+            }
+            let filename = sanitize(&filename);
+            // a directory per file keeps same-named files of one request apart
+            let key = format!("{}{id}/{}/{filename}", self.prefix, objects.len());
+            let size = self
+                .store(&mut form, &key, part.content_type.as_deref())
+                .await?;
+            objects.push(json!({
+                "field": part.name,
+                "filename": filename,
+                "key": key,
+                "url": self.bucket.object_url(&key),
+                "size": size,
+            }));
+        }
+
+        let body = Bytes::from(json!({ "objects": objects }).to_string());
+        let mut header = ResponseHeader::build(StatusCode::CREATED, Some(3))?;
+        header.insert_header(header::CONTENT_TYPE, "application/json")?;
+        header.insert_header(header::CONTENT_LENGTH, body.len())?;
+        header.insert_header(header::CACHE_CONTROL, "no-store")?;
+        Ok((header, body))
+    }
+
+    /// Stream the body of the current part of `form` to the object `key`,
+    /// returning its size.
+    async fn store(
+        &self,
+        form: &mut Multipart<'_>,
+        key: &str,
+        content_type: Option<&str>,
+    ) -> Result<u64> {
+        let mut upload = None;
+        let stored = self.stream(form, key, content_type, &mut upload).await;
+        if stored.is_err()
+            && let Some(upload) = upload
+        {
+            // best effort, the bucket's lifecycle rules catch what is left
+            if let Err(e) = self.abort(key, &upload).await {
+                warn!("aborting upload of {key}: {e}");
+            }
+        }
+        stored
+    }
+
+    async fn stream(
+        &self,
+        form: &mut Multipart<'_>,
+        key: &str,
+        content_type: Option<&str>,
+        upload: &mut Option<MultipartUpload>,
+    ) -> Result<u64> {
+        let mut piece = BytesMut::new();
+        let mut size = 0u64;
+        while let Some(chunk) = form.read().await? {
+            size += chunk.len() as u64;
+            piece.extend_from_slice(&chunk);
+            if piece.len() >= self.part_size {
+                let full = piece.split_to(self.part_size).freeze();
+                if upload.is_none() {
+                    *upload = Some(self.create(key, content_type).await?);
+                }
+                let upload = upload.as_mut().expect("multipart upload created");
+                self.upload_part(key, upload, full).await?;
+            }
+        }
+        match upload {
+            None => self.put(key, content_type, piece.freeze()).await?,
+            Some(upload) => {
+                if !piece.is_empty() {
+                    self.upload_part(key, upload, piece.freeze()).await?;
+                }
+                self.complete(key, upload).await?;
+            }
+        }
+        Ok(size)
+    }
+
+    async fn put(&self, key: &str, content_type: Option<&str>, body: Bytes) -> Result<()> {
+        let mut req = self.request(Method::PUT, &self.bucket.object_path(key), body.len())?;
+        if let Some(content_type) = content_type {
+            req.insert_header(header::CONTENT_TYPE, content_type)?;
+        }
+        self.send(req, body).await.map(drop)
+    }
+
+    async fn create(&self, key: &str, content_type: Option<&str>) -> Result<MultipartUpload> {
+        let path = format!("{}?uploads", self.bucket.object_path(key));
+        let mut req = self.request(Method::POST, &path, 0)?;
+        if let Some(content_type) = content_type {
+            req.insert_header(header::CONTENT_TYPE, content_type)?;
+        }
+        let (_, body) = self.send(req, Bytes::new()).await?;
+        let id = xml_text(&body, "UploadId")
+            .ok_or_else(|| Error::explain(UPLOAD_ERROR, "no UploadId in storage response"))?;
+        Ok(MultipartUpload {
+            id,
+            etags: Vec::new(),
+        })
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload: &mut MultipartUpload,
+        body: Bytes,
+    ) -> Result<()> {
+        let path = format!(
+            "{}?partNumber={}&uploadId={}",
+            self.bucket.object_path(key),
+            upload.etags.len() + 1,
+            s3::uri_encode(&upload.id, true)
+        );
+        let req = self.request(Method::PUT, &path, body.len())?;
+        let (header, _) = self.send(req, body).await?;
+        let etag = header
+            .headers
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| Error::explain(UPLOAD_ERROR, "no ETag for uploaded part"))?;
+        upload.etags.push(etag.to_string());
+        Ok(())
+    }
+
+    async fn complete(&self, key: &str, upload: &MultipartUpload) -> Result<()> {
+        let path = format!(
+            "{}?uploadId={}",
+            self.bucket.object_path(key),
+            s3::uri_encode(&upload.id, true)
+        );
+        let parts: String = upload
+            .etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| {
+                let etag = etag.replace('&', "&amp;").replace('"', "&quot;");
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{etag}</ETag></Part>",
+                    i + 1
+                )
+            })
+            .collect();
+        let body = Bytes::from(format!(
+            "<CompleteMultipartUpload>{parts}</CompleteMultipartUpload>"
+        ));
+        let req = self.request(Method::POST, &path, body.len())?;
+        let (_, response) = self.send(req, body).await?;
+        // errors of a completion may come with a 200
+        if xml_text(&response, "Code").is_some() {
+            return Error::e_explain(UPLOAD_ERROR, "storage failed to complete the upload");
+        }
+        Ok(())
+    }
+
+    async fn abort(&self, key: &str, upload: &MultipartUpload) -> Result<()> {
+        let path = format!(
+            "{}?uploadId={}",
+            self.bucket.object_path(key),
+            s3::uri_encode(&upload.id, true)
+        );
+        let req = self.request(Method::DELETE, &path, 0)?;
+        self.send(req, Bytes::new()).await.map(drop)
+    }
+
+    fn request(&self, method: Method, path: &str, len: usize) -> Result<RequestHeader> {
+        let mut req = RequestHeader::build(method, path.as_bytes(), None)?;
+        req.insert_header(header::CONTENT_LENGTH, len)?;
+        Ok(req)
+    }
+
+    /// Sign and send `req` to the bucket, expecting a success.
+    async fn send(&self, mut req: RequestHeader, body: Bytes) -> Result<(ResponseHeader, Bytes)> {
+        self.bucket
+            .sign(&mut req, &s3::payload_sha256(&body))
+            .await?;
+        let mut peer = self.bucket.peer();
+        peer.options.total_connection_timeout = Some(self.timeout);
+        peer.options.read_timeout = Some(self.timeout);
+        peer.options.write_timeout = Some(self.timeout);
+        let path = req.uri.path().to_string();
+        let fetched = subrequest::send(&self.http, &peer, req, body, MAX_STORAGE_RESPONSE)
+            .await?
+            .ok_or_else(|| Error::explain(UPLOAD_ERROR, "storage response too large"))?;
+        if !fetched.header.status.is_success() {
+            let code = xml_text(&fetched.body, "Code").unwrap_or_default();
+            return Error::e_explain(
+                UPLOAD_ERROR,
+                format!(
+                    "storage answered {} {code} for {path}",
+                    fetched.header.status
+                ),
+            );
+        }
+        Ok((fetched.header, fetched.body))
+    }
+}
+
+/// Storage failures, answered with a 502.
+const UPLOAD_ERROR: ErrorType = ErrorType::HTTPStatus(502);
+
+struct MultipartUpload {
+    id: String,
+    /// of the parts uploaded so far, in order
+    etags: Vec<String>,
+}
+
+struct Part {
+    name: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+#[derive(PartialEq)]
+enum State {
+    /// before the first delimiter, in the preamble
+    Start,
+    Body,
+    /// right after a delimiter
+    Delimited,
+    End,
+}
+
+/// A `multipart/form-data` request body, read as it arrives.
+struct Multipart<'s> {
+    session: &'s mut Session,
+    /// `\r\n--<boundary>`
+    delimiter: Vec<u8>,
+    buf: BytesMut,
+    state: State,
+    received: u64,
+    max_size: u64,
+}
+
+impl<'s> Multipart<'s> {
+    fn new(session: &'s mut Session, boundary: &str, max_size: u64) -> Self {
+        Multipart {
+            session,
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // so the first delimiter looks like the others
+            buf: BytesMut::from(&b"\r\n"[..]),
+            state: State::Start,
+            received: 0,
+            max_size,
+        }
+    }
+
+    /// Read more of the body, failing at its end.
+    async fn fill(&mut self) -> Result<()> {
+        let Some(chunk) = self.session.read_request_body().await? else {
+            return Error::e_explain(ErrorType::HTTPStatus(400), "truncated multipart body");
+        };
+        self.received += chunk.len() as u64;
+        if self.received > self.max_size {
+            return Error::e_explain(ErrorType::HTTPStatus(413), "upload too large");
+        }
+        self.buf.extend_from_slice(&chunk);
+        Ok(())
+    }
+
+    /// The headers of the next part, `None` after the last one.
+    async fn next_part(&mut self) -> Result<Option<Part>> {
+        loop {
+            match self.state {
+                State::End => return Ok(None),
+                State::Body => while self.read().await?.is_some() {},
+                State::Start => match find(&self.buf, &self.delimiter) {
+                    Some(at) => {
+                        let _ = self.buf.split_to(at + self.delimiter.len());
+                        self.state = State::Delimited;
+                    }
+                    None => {
+                        // keep what may be the start of the delimiter
+                        let keep = self.delimiter.len().min(self.buf.len());
+                        let _ = self.buf.split_to(self.buf.len() - keep);
+                        self.fill().await?;
+                    }
+                },
+                State::Delimited => {
+                    while self.buf.len() < 2 {
+                        self.fill().await?;
+                    }
+                    if self.buf.starts_with(b"--") {
+                        self.state = State::End;
+                        return Ok(None);
+                    }
+                    let block = loop {
+                        // transport padding may follow the delimiter
+                        if let Some(eol) = find(&self.buf, b"\r\n") {
+                            let first = &self.buf[eol + 2..];
+                            if first.starts_with(b"\r\n") {
+                                break (eol, eol + 4);
+                            }
+                            if let Some(end) = find(first, b"\r\n\r\n") {
+                                break (eol, eol + 2 + end + 4);
+                            }
+                        }
+                        if self.buf.len() > MAX_PART_HEADER {
+                            return Error::e_explain(
+                                ErrorType::HTTPStatus(400),
+                                "multipart header too large",
+                            );
+                        }
+                        self.fill().await?;
+                    };
+                    let (eol, end) = block;
+                    let head = self.buf.split_to(end);
+                    self.state = State::Body;
+                    return parse_part(&head[eol + 2..]).map(Some);
+                }
+            }
+        }
+    }
+
+    /// The next piece of the current part's body, `None` at its end.
+    async fn read(&mut self) -> Result<Option<Bytes>> {
+        if self.state != State::Body {
+            return Ok(None);
+        }
+        loop {
+            match find(&self.buf, &self.delimiter) {
+                Some(0) => {
+                    let _ = self.buf.split_to(self.delimiter.len());
+                    self.state = State::Delimited;
+                    return Ok(None);
+                }
+                Some(at) => return Ok(Some(self.buf.split_to(at).freeze())),
+                None => {
+                    let keep = self.delimiter.len() - 1;
+                    if self.buf.len() > keep {
+                        let len = self.buf.len() - keep;
+                        return Ok(Some(self.buf.split_to(len).freeze()));
+                    }
+                    self.fill().await?;
+                }
+            }
+        }
+    }
+}
+
+fn parse_part(block: &[u8]) -> Result<Part> {
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let parsed = httparse::parse_headers(block, &mut headers);
+    let Ok(httparse::Status::Complete((_, headers))) = parsed else {
+        return Error::e_explain(ErrorType::HTTPStatus(400), "malformed multipart header");
+    };
+    let value = |name: &str| {
+        headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    };
+    let disposition = value("content-disposition").unwrap_or_default();
+    Ok(Part {
+        name: parameter(disposition, "name"),
+        filename: parameter(disposition, "filename").filter(|f| !f.is_empty()),
+        content_type: value("content-type").map(str::to_string),
+    })
+}
+
+/// The value of parameter `name` of a header value, unquoted.
+fn parameter(value: &str, name: &str) -> Option<String> {
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        let (param, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let (param_value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut out = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (i, '"') => break i + 1,
+                        (_, '\\') => out.push(chars.next()?.1),
+                        (_, c) => out.push(c),
+                    }
+                };
+                let next = quoted[end..].split_once(';').map_or("", |(_, n)| n);
+                (out, next)
+            }
+            None => {
+                let (token, next) = after.split_once(';').unwrap_or((after, ""));
+                (token.trim().to_string(), next)
+            }
+        };
+        if param.trim().eq_ignore_ascii_case(name) {
+            return Some(param_value);
+        }
+        rest = next;
+    }
+}
+
+fn boundary(req: &RequestHeader) -> Option<String> {
+    let content_type = req.headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let essence = content_type.split(';').next()?.trim();
+    if !essence.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    parameter(content_type, "boundary").filter(|b| !b.is_empty() && b.len() <= 70)
+}
+
+/// The last path segment of `filename`, with characters that could confuse
+/// a consumer replaced.
+fn sanitize(filename: &str) -> String {
+    let base = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let clean: String = base
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "._-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(128)
+        .collect();
+    if clean.trim_matches('.').is_empty() {
+        "file".to_string()
+    } else {
+        clean
+    }
+}
+
+/// An id for the objects of one upload, unlikely to collide.
+fn upload_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let state = RandomState::new();
+    let a = state.hash_one((Instant::now(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    let b = state.hash_one(a);
+    format!("{a:016x}{b:016x}")
+}
+
+/// The text of the first `<tag>` element of a storage response.
+fn xml_text(body: &[u8], tag: &str) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    let start = body.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let len = body[start..].find(&format!("</{tag}>"))?;
+    Some(body[start..start + len].to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
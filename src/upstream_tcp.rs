@@ -0,0 +1,147 @@
+//! TCP settings of the connections to a cluster.
+//!
+//! NATs and firewalls between the proxy and its upstreams forget connections
+//! that stay quiet for longer than their idle timeout, often a few minutes,
+//! without telling either end. The next request on a pooled connection, or
+//! the next message of a WebSocket, then gets a reset. [`UpstreamTcp`] has
+//! the kernel send keepalive probes on quiet connections, so the middleboxes
+//! keep them, and drops pooled connections idle for longer than the
+//! middleboxes would keep them anyway.
+//!
+//! Upgraded connections, WebSockets and other protocols an `Upgrade` switches
+//! to, are otherwise closed as soon as either side finishes sending, cutting
+//! off what the other side still had to send. With `half_close` the end of
+//! one direction is passed on as a TCP half-close, `FIN`, and the other
+//! direction is relayed until it ends too.
+//!
+//! The idle connections to an upstream are pooled for all workers of the
+//! proxy together, and at high request rates the workers taking and returning
+//! them contend on the lock of that one pool. With `partition_pools` the
+//! connections to the nodes of a consistent hashing ring, whose lookups give
+//! the [`PoolHint`] of a node, are pooled by worker thread and node instead.
+//! A worker then only reuses the connections it opened, and only contends
+//! with itself.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::Duration;
+
+use http::StatusCode;
+use log::debug;
+use pingora::Result;
+use pingora::protocols::http::client::HttpSession;
+use pingora::protocols::l4::ext::TcpKeepalive;
+use pingora::proxy::Session;
+use pingora::upstreams::peer::HttpPeer;
+
+use crate::consistent_hash::PoolHint;
+use crate::sharded::worker;
+
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamTcp {
+    /// Probes sent on connections quiet for `idle`; `None` sends none.
+    pub keepalive: Option<TcpKeepalive>,
+    /// Close pooled connections unused for this long; `None` keeps them
+    /// until the upstream closes them.
+    pub pool_idle_timeout: Option<Duration>,
+    /// Pass the end of one direction of upgraded connections on as a
+    /// half-close, rather than closing both.
+    pub half_close: bool,
+    /// Pool the connections to nodes of a consistent hashing ring by worker.
+    pub partition_pools: bool,
+}
+
+impl UpstreamTcp {
+    /// Probes every `interval` after `idle`, giving up on the connection
+    /// after `count` unanswered ones.
+    pub fn probe(idle: Duration, interval: Duration, count: usize) -> TcpKeepalive {
+        TcpKeepalive {
+            idle,
+            interval,
+            count,
+            // the system default
+            #[cfg(target_os = "linux")]
+            user_timeout: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn apply(&self, peer: &mut HttpPeer) {
+        if let Some(keepalive) = &self.keepalive {
+            peer.options.tcp_keepalive = Some(keepalive.clone());
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            peer.options.idle_timeout = Some(timeout);
+        }
+    }
+
+    /// Reuse the connections of `peer`, the node of `pool` on a ring, from
+    /// the pool of this worker.
+    pub(crate) fn pool(&self, peer: &mut HttpPeer, pool: PoolHint) {
+        if self.partition_pools {
+            let mut hasher = DefaultHasher::new();
+            (pool, worker()).hash(&mut hasher);
+            peer.group_key = hasher.finish();
+        }
+    }
+}
+
+/// Relay the upgrade request of `session`, whose header `exchange` sent
+/// upstream already, and then both directions of the upgraded connection,
+/// each until it ends. Returns the bytes relayed from the client and from
+/// the upstream.
+pub(crate) async fn relay_upgraded(
+    session: &mut Session,
+    exchange: &mut HttpSession,
+) -> Result<(u64, u64)> {
+    exchange.read_response_header().await?;
+    let header = exchange
+        .response_header()
+        .expect("response header is read")
+        .clone();
+    let switched = header.status == StatusCode::SWITCHING_PROTOCOLS;
+    session
+        .write_response_header(Box::new(header), false)
+        .await?;
+    if !switched {
+        // refused, the response is a regular one
+        exchange.finish_request_body().await?;
+        while let Some(chunk) = exchange.read_response_body().await? {
+            session.write_response_body(Some(chunk), false).await?;
+        }
+        session.write_response_body(None, true).await?;
+        return Ok((0, 0));
+    }
+    if let HttpSession::H1(h1) = exchange {
+        // the request body is passed through as it is from here on
+        h1.maybe_upgrade_body_writer();
+    }
+
+    let (mut sent, mut received) = (0, 0);
+    let (mut client_open, mut upstream_open) = (true, true);
+    while client_open || upstream_open {
+        tokio::select! {
+            chunk = session.read_request_body(), if client_open => match chunk? {
+                Some(chunk) => {
+                    sent += chunk.len() as u64;
+                    exchange.write_request_body(chunk, false).await?;
+                }
+                None => {
+                    debug!("client half-closed an upgraded connection");
+                    client_open = false;
+                    exchange.shutdown().await;
+                }
+            },
+            chunk = exchange.read_response_body(), if upstream_open => match chunk? {
+                Some(chunk) => {
+                    received += chunk.len() as u64;
+                    session.write_response_body(Some(chunk), false).await?;
+                }
+                None => {
+                    debug!("upstream half-closed an upgraded connection");
+                    upstream_open = false;
+                    session.shutdown().await;
+                }
+            },
+        }
+    }
+    Ok((sent, received))
+}
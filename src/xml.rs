@@ -0,0 +1,340 @@
+//! Guards for routes fronting XML and SOAP services.
+//!
+//! On a route with an [`XmlGuard`] every request body is read, up to
+//! `max_body` and at most 64 KiB, and checked before it is proxied. Refused
+//! are bodies that:
+//!
+//! - have no `Content-Length` (411) or a larger one (413),
+//! - are not declared XML, `text/xml`, `application/xml` or `*+xml` (415),
+//! - carry a document type declaration or reference entities other than the
+//!   predefined and numeric ones (400): external entities (XXE) and entity
+//!   expansion ("billion laughs") both need a DTD,
+//! - nest elements deeper than `max_depth` or give one more than
+//!   `max_attributes` attributes (400), or are not well-formed enough to
+//!   check.
+//!
+//! The scan also finds the operation of a request, used by
+//! [`crate::body_route`]: the first element of the `Body` of a SOAP envelope,
+//! the root element of other documents.
+
+use http::{Method, header};
+use pingora::http::RequestHeader;
+use pingora::{Error, ErrorType, Result};
+
+use crate::body_route::MAX_BODY;
+
+pub struct XmlGuard {
+    max_body: usize,
+    max_depth: usize,
+    max_attributes: usize,
+}
+
+/// What bounds a scan.
+#[derive(Clone, Copy)]
+struct Limits {
+    max_depth: usize,
+    max_attributes: usize,
+}
+
+/// Used when scanning only for the operation.
+const SCAN_LIMITS: Limits = Limits {
+    max_depth: 256,
+    max_attributes: 256,
+};
+
+impl Default for XmlGuard {
+    fn default() -> Self {
+        XmlGuard {
+            max_body: MAX_BODY,
+            max_depth: 32,
+            max_attributes: 64,
+        }
+    }
+}
+
+impl XmlGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body.min(MAX_BODY);
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Most attributes, namespace declarations included, on one element.
+    pub fn with_max_attributes(mut self, max_attributes: usize) -> Self {
+        self.max_attributes = max_attributes;
+        self
+    }
+
+    /// Whether `req` has a body to read and [`Self::check`]; refuses bodies
+    /// that cannot be checked.
+    pub(crate) fn wants_body(&self, req: &RequestHeader) -> Result<bool> {
+        let len = req
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .map(|v| v.to_str().ok().and_then(|v| v.parse::<usize>().ok()));
+        match len {
+            Some(Some(0)) => Ok(false),
+            Some(Some(len)) if len <= self.max_body => {
+                if !is_xml(req) {
+                    return Error::e_explain(ErrorType::HTTPStatus(415), "request body is not XML");
+                }
+                Ok(true)
+            }
+            Some(Some(_)) => Error::e_explain(
+                ErrorType::HTTPStatus(413),
+                "request body too large to check",
+            ),
+            Some(None) => Error::e_explain(ErrorType::HTTPStatus(400), "invalid Content-Length"),
+            None if req.headers.contains_key(header::TRANSFER_ENCODING) => Error::e_explain(
+                ErrorType::HTTPStatus(411),
+                "request body needs a Content-Length",
+            ),
+            // bodies without framing only end with the connection
+            None if req.method == Method::POST || req.method == Method::PUT => Error::e_explain(
+                ErrorType::HTTPStatus(411),
+                "request body needs a Content-Length",
+            ),
+            None => Ok(false),
+        }
+    }
+
+    pub(crate) fn check(&self, body: &[u8]) -> Result<()> {
+        let limits = Limits {
+            max_depth: self.max_depth,
+            max_attributes: self.max_attributes,
+        };
+        match scan(body, limits) {
+            Ok(_) => Ok(()),
+            Err(why) => Error::e_explain(ErrorType::HTTPStatus(400), why),
+        }
+    }
+}
+
+/// The SOAP action of `req`: the `SOAPAction` header of SOAP 1.1 or the
+/// `action` parameter of a SOAP 1.2 `Content-Type`, unquoted.
+pub(crate) fn soap_action(req: &RequestHeader) -> Option<&str> {
+    if let Some(action) = req.headers.get("soapaction") {
+        return action.to_str().ok().map(unquote);
+    }
+    let content_type = req.headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("action")
+            .then(|| unquote(value))
+    })
+}
+
+/// Local name of the operation element of `body`, see the module docs.
+pub(crate) fn operation(body: &[u8]) -> Option<String> {
+    scan(body, SCAN_LIMITS).ok()?.operation
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+fn is_xml(req: &RequestHeader) -> bool {
+    let Some(content_type) = req
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "text/xml" || essence == "application/xml" || essence.ends_with("+xml")
+}
+
+struct Scanned {
+    operation: Option<String>,
+}
+
+/// Walk the markup of `body` without resolving anything.
+fn scan(body: &[u8], limits: Limits) -> Result<Scanned, &'static str> {
+    let mut open: Vec<&[u8]> = Vec::new();
+    let mut root: Option<&[u8]> = None;
+    let mut operation: Option<&[u8]> = None;
+    let mut soap_body_at = None;
+    let mut i = 0;
+    while i < body.len() {
+        let Some(lt) = memchr(b'<', &body[i..]) else {
+            check_text(&body[i..])?;
+            break;
+        };
+        check_text(&body[i..i + lt])?;
+        i += lt;
+        let rest = &body[i..];
+        if rest.starts_with(b"<?") {
+            i += find(rest, b"?>").ok_or("unterminated processing instruction")? + 2;
+        } else if rest.starts_with(b"<!--") {
+            i += find(rest, b"-->").ok_or("unterminated comment")? + 3;
+        } else if rest.starts_with(b"<![CDATA[") {
+            if open.is_empty() {
+                return Err("character data outside the root element");
+            }
+            i += find(rest, b"]]>").ok_or("unterminated CDATA section")? + 3;
+        } else if rest.starts_with(b"<!") {
+            return Err("document type declarations are not allowed");
+        } else if rest.starts_with(b"</") {
+            let end = memchr(b'>', rest).ok_or("unterminated end tag")?;
+            let name = rest[2..end].trim_ascii();
+            if open.pop() != Some(name) {
+                return Err("mismatched end tag");
+            }
+            if soap_body_at == Some(open.len() + 1) {
+                soap_body_at = None;
+            }
+            i += end + 1;
+        } else {
+            let (name, len, self_closing) = start_tag(rest, limits)?;
+            if open.is_empty() {
+                if root.is_some() {
+                    return Err("more than one root element");
+                }
+                root = Some(name);
+            }
+            let depth = open.len();
+            if depth == 1
+                && soap_body_at.is_none()
+                && local_name(name) == b"Body"
+                && is_envelope(root)
+            {
+                soap_body_at = Some(depth + 1);
+            } else if Some(depth) == soap_body_at && operation.is_none() {
+                operation = Some(name);
+            }
+            if !self_closing {
+                open.push(name);
+                if open.len() > limits.max_depth {
+                    return Err("elements nested too deep");
+                }
+            }
+            i += len;
+        }
+    }
+    if !open.is_empty() {
+        return Err("unclosed element");
+    }
+    let root = root.ok_or("no root element")?;
+    let operation = if is_envelope(Some(root)) {
+        operation
+    } else {
+        Some(root)
+    };
+    Ok(Scanned {
+        operation: operation.map(|name| String::from_utf8_lossy(local_name(name)).into_owned()),
+    })
+}
+
+fn is_envelope(root: Option<&[u8]>) -> bool {
+    root.is_some_and(|r| local_name(r) == b"Envelope")
+}
+
+/// The name of the start tag at the beginning of `tag`, its length, and
+/// whether it closes itself.
+fn start_tag(tag: &[u8], limits: Limits) -> Result<(&[u8], usize, bool), &'static str> {
+    let name_end = tag[1..]
+        .iter()
+        .position(|&b| b.is_ascii_whitespace() || b == b'/' || b == b'>')
+        .ok_or("unterminated start tag")?
+        + 1;
+    let name = &tag[1..name_end];
+    if name.is_empty() {
+        return Err("start tag without a name");
+    }
+    let mut attributes = 0;
+    let mut i = name_end;
+    loop {
+        match tag.get(i).ok_or("unterminated start tag")? {
+            b'>' => return Ok((name, i + 1, false)),
+            b'/' if tag.get(i + 1) == Some(&b'>') => return Ok((name, i + 2, true)),
+            b if b.is_ascii_whitespace() => i += 1,
+            _ => {
+                let name_len = tag[i..]
+                    .iter()
+                    .position(|&b| b.is_ascii_whitespace() || matches!(b, b'=' | b'>' | b'/'))
+                    .ok_or("unterminated start tag")?;
+                i = skip_whitespace(tag, i + name_len);
+                if tag.get(i) != Some(&b'=') {
+                    return Err("attribute without a value");
+                }
+                i = skip_whitespace(tag, i + 1);
+                let quote = *tag.get(i).ok_or("unterminated start tag")?;
+                if quote != b'"' && quote != b'\'' {
+                    return Err("unquoted attribute value");
+                }
+                let len = memchr(quote, &tag[i + 1..]).ok_or("unterminated attribute value")?;
+                let value = &tag[i + 1..i + 1 + len];
+                if value.contains(&b'<') {
+                    return Err("'<' in attribute value");
+                }
+                check_text(value)?;
+                i += len + 2;
+                attributes += 1;
+                if attributes > limits.max_attributes {
+                    return Err("too many attributes");
+                }
+            }
+        }
+    }
+}
+
+/// Only the predefined and numeric character references may appear.
+fn check_text(text: &[u8]) -> Result<(), &'static str> {
+    let mut rest = text;
+    while let Some(amp) = memchr(b'&', rest) {
+        rest = &rest[amp + 1..];
+        let end = memchr(b';', rest).ok_or("unterminated reference")?;
+        let reference = &rest[..end];
+        let known = matches!(reference, b"lt" | b"gt" | b"amp" | b"quot" | b"apos")
+            || match reference {
+                [b'#', b'x', hex @ ..] => !hex.is_empty() && hex.iter().all(u8::is_ascii_hexdigit),
+                [b'#', dec @ ..] => !dec.is_empty() && dec.iter().all(u8::is_ascii_digit),
+                _ => false,
+            };
+        if !known {
+            return Err("entity references are not allowed");
+        }
+        rest = &rest[end + 1..];
+    }
+    Ok(())
+}
+
+fn local_name(name: &[u8]) -> &[u8] {
+    name.iter()
+        .rposition(|&b| b == b':')
+        .map_or(name, |colon| &name[colon + 1..])
+}
+
+fn skip_whitespace(bytes: &[u8], mut i: usize) -> usize {
+    while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+        i += 1;
+    }
+    i
+}
+
+fn memchr(needle: u8, haystack: &[u8]) -> Option<usize> {
+    haystack.iter().position(|&b| b == needle)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
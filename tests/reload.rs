@@ -0,0 +1,206 @@
+//! Reloads under load never block requests.
+//!
+//! Reader threads do what a request does to the shared state: route a
+//! request on the current router, check whether its upstream drains and
+//! whether its circuit is open, and read its latency estimate. A writer keeps
+//! replacing the router with a freshly built one, draining and releasing the
+//! upstream, failing it and hearing back from it, while the test holds on to
+//! the first router throughout, as a slow request routed before the reloads
+//! would.
+//!
+//! It fails if the writer gets stuck behind the router still in use, if any
+//! reader routes nothing over ten reloads, or if reads get slow while it
+//! reloads: every read is timed, and 99% of them must take well under a
+//! millisecond. The slowest percent is not held against the readers, a
+//! reader descheduled in the middle of a read takes as long as the scheduler
+//! keeps it out, so how long the slowest reads take says more about the
+//! machine than about locks.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
+
+use pingora::http::RequestHeader;
+use pingora::protocols::l4::socket::SocketAddr;
+use proxy_rs::balancing::Latencies;
+use proxy_rs::circuit::{CircuitBreakers, CircuitConfig};
+use proxy_rs::drain::{DrainRegistry, DrainSource};
+use proxy_rs::route::{Route, Router, SharedRouter};
+
+/// Routes of every router built, so building one takes a while.
+const ROUTES: usize = 2_000;
+
+const RUN: Duration = Duration::from_secs(2);
+
+const READERS: usize = 4;
+
+/// The slowest of the fastest 99% of reads allowed. Routing takes
+/// microseconds, building a router milliseconds.
+const MAX_P99: Duration = Duration::from_millis(1);
+
+/// Routers the writer keeps after replacing them, long after the readers
+/// are done with them. Freeing memory contends on the allocator and the
+/// kernel's lock of the address space, so a reader that drops the last
+/// reference to a router may wait where the router never made it.
+const KEPT: usize = 8;
+
+fn router(generation: usize) -> Router {
+    let routes = (0..ROUTES)
+        .map(|i| Route::new(format!("r{generation}-{i}"), format!("/v{i}/")))
+        .collect();
+    Router::new(routes)
+}
+
+/// Read times by power of two nanoseconds: reads in bucket `i` took less
+/// than `2^i` ns.
+struct Histogram([u64; 64]);
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram([0; 64])
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, took: Duration) {
+        let nanos = u64::try_from(took.as_nanos()).unwrap_or(u64::MAX);
+        self.0[(64 - nanos.leading_zeros() as usize).min(63)] += 1;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.0.iter_mut().zip(other.0) {
+            *count += other;
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    /// The time under which the share `q` of the reads took.
+    fn quantile(&self, q: f64) -> Duration {
+        let rank = (self.count() as f64 * q).ceil() as u64;
+        let mut seen = 0;
+        for (i, count) in self.0.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(1 << i);
+            }
+        }
+        Duration::MAX
+    }
+}
+
+#[test]
+fn reloads_never_block_requests() {
+    let shared = Arc::new(SharedRouter::new(router(0)));
+    let drain = Arc::new(DrainRegistry::default());
+    let circuits = Arc::new(CircuitBreakers::new(CircuitConfig::default()));
+    let latencies = Arc::new(Latencies::default());
+    let upstream: SocketAddr = "127.0.0.1:8000".parse().expect("address");
+    // a request routed before the reloads, still going
+    let held = shared.load();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    // reads of every reader so far
+    let reads: Arc<Vec<AtomicU64>> = Arc::new((0..READERS).map(|_| AtomicU64::new(0)).collect());
+    let readers: Vec<_> = (0..READERS)
+        .map(|reader| {
+            let (shared, drain, upstream) = (shared.clone(), drain.clone(), upstream.clone());
+            let (circuits, latencies) = (circuits.clone(), latencies.clone());
+            let (stop, reads) = (stop.clone(), reads.clone());
+            std::thread::spawn(move || {
+                let req = RequestHeader::build("GET", b"/v7/users", None).expect("request");
+                let mut took = Histogram::default();
+                while !stop.load(Ordering::Relaxed) {
+                    let start = Instant::now();
+                    let route = shared.load().match_request(&req, None, false);
+                    std::hint::black_box((
+                        route,
+                        drain.is_draining(&upstream),
+                        circuits.allows(&upstream),
+                        latencies.estimate(&upstream),
+                    ));
+                    took.record(start.elapsed());
+                    reads[reader].fetch_add(1, Ordering::Relaxed);
+                }
+                took
+            })
+        })
+        .collect();
+
+    let (done, reloads) = mpsc::channel();
+    let writer = {
+        let (shared, drain, stop) = (shared.clone(), drain.clone(), stop.clone());
+        let (circuits, latencies) = (circuits.clone(), latencies.clone());
+        std::thread::spawn(move || {
+            let mut generation = 0;
+            let mut kept = VecDeque::new();
+            while !stop.load(Ordering::Relaxed) {
+                generation += 1;
+                let router = Arc::new(router(generation));
+                shared.store(router.clone());
+                kept.push_back(router);
+                if kept.len() > KEPT {
+                    kept.pop_front();
+                }
+                drain.drain(upstream.clone(), DrainSource::Admin);
+                drain.undrain(&upstream);
+                circuits.failed(&upstream);
+                circuits.succeeded(&upstream);
+                latencies.observe(&upstream, Duration::from_millis(generation as u64 % 50));
+                if done.send(()).is_err() {
+                    return;
+                }
+            }
+        })
+    };
+
+    let started = Instant::now();
+    let mut failure = None;
+    let mut count = 0;
+    let progress = || -> Vec<u64> { reads.iter().map(|r| r.load(Ordering::Relaxed)).collect() };
+    let mut reads_before = progress();
+    while started.elapsed() < RUN {
+        if reloads.recv_timeout(Duration::from_secs(2)).is_err() {
+            failure = Some(format!(
+                "reload {} stuck while a request holds the router",
+                count + 1
+            ));
+            break;
+        }
+        count += 1;
+        // a reload builds its router for long enough that readers got turns
+        if count % 10 == 0 {
+            let reads_now = progress();
+            if let Some(reader) = (0..READERS).find(|&i| reads_now[i] == reads_before[i]) {
+                failure = Some(format!(
+                    "reader {reader} routed no request during reloads {}..{count}",
+                    count - 9
+                ));
+                break;
+            }
+            reads_before = reads_now;
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+    drop(reloads);
+    writer.join().expect("writer");
+    let mut took = Histogram::default();
+    for reader in readers {
+        took.merge(&reader.join().expect("reader"));
+    }
+    drop(held);
+
+    if let Some(failure) = failure {
+        panic!("{failure}");
+    }
+    assert!(count > 0, "no reloads");
+    let (p50, p99) = (took.quantile(0.5), took.quantile(0.99));
+    assert!(
+        p99 <= MAX_P99,
+        "99% of {} reads in {count} reloads took under {p99:?}, the median under {p50:?}",
+        took.count()
+    );
+}
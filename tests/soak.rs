@@ -0,0 +1,284 @@
+//! The proxy does not leak under sustained load.
+//!
+//! Load is sent to the proxy in windows, reusing connections in one and
+//! opening one per request in the next, and after every window the proxy's
+//! resident memory, open file descriptors, threads and tokio tasks are read
+//! from its admin API, once the requests of the window are done.
+//!
+//! ```text
+//! SOAK_MINUTES=5 cargo test --release --test soak -- --ignored --nocapture
+//! ```
+//!
+//! The proxy binary is started in front of an origin of the test's own, on
+//! free ports for its listener and admin API, unless `SOAK_PROXY` and
+//! `SOAK_ADMIN` give the URLs of one already running. `SOAK_MINUTES` is how
+//! long the load lasts, 5 minutes without it.
+//!
+//! The first window only warms up pools and caches. It fails if any of the
+//! figures grows over the rest of the run: its average over each third of
+//! the windows above the one before, and the last third above the first by
+//! more than the slack of the figure. Failed requests are reported but not
+//! held against the proxy, whose upstreams may well be down.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use proxy_rs::loadgen::{self, LoadConfig, MixEntry, Stop};
+use serde_json::Value;
+
+/// Load between two readings.
+const WINDOW: Duration = Duration::from_secs(15);
+
+/// Growth of a figure that is not taken for a leak: memory the allocator
+/// keeps around, descriptors and tasks of connections being closed.
+const SLACK: [(&str, f64); 4] = [
+    ("rss_mib", 16.0),
+    ("fds", 16.0),
+    ("threads", 4.0),
+    ("tasks", 64.0),
+];
+
+/// The figures of one reading, in the order of [`SLACK`].
+type Reading = [f64; 4];
+
+/// `GET` of an admin URL, its body as JSON.
+fn admin(url: &str, path: &str) -> Result<Value, String> {
+    let authority = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("admin URL {url} is not http://"))?
+        .trim_end_matches('/');
+    let mut stream = TcpStream::connect(authority).map_err(|e| format!("admin {url}: {e}"))?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .map_err(|e| e.to_string())?;
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: {authority}\r\nConnection: close\r\n\r\n"
+    )
+    .map_err(|e| format!("admin {url}: {e}"))?;
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .map_err(|e| format!("admin {url}: {e}"))?;
+    let response = String::from_utf8_lossy(&response);
+    let (_, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| format!("admin {url}: no response"))?;
+    serde_json::from_str(body).map_err(|e| format!("admin {url}: {e}"))
+}
+
+fn reading(url: &str) -> Result<Reading, String> {
+    let debug = admin(url, "/admin/debug")?;
+    let figure = |v: &Value, what: &str| {
+        v.as_f64()
+            .ok_or_else(|| format!("admin {url}: no {what} in /admin/debug"))
+    };
+    let tasks: f64 = debug["runtimes"]
+        .as_array()
+        .map(|runtimes| {
+            runtimes
+                .iter()
+                .filter_map(|r| r["alive_tasks"].as_f64())
+                .sum()
+        })
+        .unwrap_or_default();
+    Ok([
+        figure(&debug["memory"]["rss"], "memory.rss")? / (1024.0 * 1024.0),
+        figure(&debug["open_fds"], "open_fds")?,
+        figure(&debug["memory"]["threads"], "memory.threads")?,
+        tasks,
+    ])
+}
+
+/// Whether the figure at `i` grows through `readings`, and by how much.
+fn growth(readings: &[Reading], i: usize) -> (bool, f64) {
+    let third = readings.len() / 3;
+    let mean = |part: &[Reading]| part.iter().map(|r| r[i]).sum::<f64>() / part.len() as f64;
+    let parts = [
+        mean(&readings[..third]),
+        mean(&readings[third..readings.len() - third]),
+        mean(&readings[readings.len() - third..]),
+    ];
+    let grew = parts[2] - parts[0];
+    (
+        parts[0] < parts[1] && parts[1] < parts[2] && grew > SLACK[i].1,
+        grew,
+    )
+}
+
+/// An origin answering every request with a short `200`, on a port of its
+/// own.
+fn origin() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("origin");
+    let port = listener.local_addr().expect("origin address").port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            std::thread::spawn(move || serve(stream));
+        }
+    });
+    port
+}
+
+/// Answer the requests of a connection until the proxy closes it.
+fn serve(stream: TcpStream) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut length = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) if line == "\r\n" => break,
+                Ok(_) => {}
+            }
+            if let Some((name, value)) = line.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                length = value.trim().parse().unwrap_or(0);
+            }
+        }
+        let mut body = vec![0; length];
+        if reader.read_exact(&mut body).is_err()
+            || writer
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// The proxy binary, killed and its config removed when dropped.
+struct Proxy {
+    child: Child,
+    config: PathBuf,
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.config);
+    }
+}
+
+/// A port nothing listens on.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("free port")
+        .port()
+}
+
+/// Start the proxy on free ports in front of `origin`, once its admin API
+/// answers; the proxy, its URL and the URL of its admin API.
+fn proxy(origin: u16) -> (Proxy, String, String) {
+    let (port, admin_port) = (free_port(), free_port());
+    let config = std::env::temp_dir().join(format!("proxy-rs-soak-{}.yaml", std::process::id()));
+    std::fs::write(
+        &config,
+        format!(
+            "listeners: [\"127.0.0.1:{port}\"]\n\
+             admin: 127.0.0.1:{admin_port}\n\
+             pools:\n  - name: default\n    tls: false\n    upstreams: [\"127.0.0.1:{origin}\"]\n"
+        ),
+    )
+    .expect("proxy config");
+    let child = Command::new(env!("CARGO_BIN_EXE_proxy-rs"))
+        .arg("--config")
+        .arg(&config)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("proxy binary");
+    let proxy = Proxy { child, config };
+    let admin_url = format!("http://127.0.0.1:{admin_port}");
+    let started = Instant::now();
+    while admin(&admin_url, "/admin/debug").is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(30),
+            "proxy admin API not up after 30s"
+        );
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    (proxy, format!("http://127.0.0.1:{port}"), admin_url)
+}
+
+#[test]
+#[ignore = "runs for minutes; SOAK_MINUTES sets how many"]
+fn no_growth_under_load() {
+    let minutes: f64 =
+        std::env::var("SOAK_MINUTES").map_or(5.0, |m| m.parse().expect("SOAK_MINUTES"));
+    let windows = (minutes * 60.0 / WINDOW.as_secs_f64()).round() as usize;
+    assert!(
+        windows >= 4,
+        "a soak of {minutes} minutes has too few windows, run for a minute at least"
+    );
+    let (_proxy, target, admin_url) =
+        match (std::env::var("SOAK_PROXY"), std::env::var("SOAK_ADMIN")) {
+            (Ok(target), Ok(admin_url)) => (None, target, admin_url),
+            _ => {
+                let (proxy, target, admin_url) = proxy(origin());
+                (Some(proxy), target, admin_url)
+            }
+        };
+    let mix: Vec<MixEntry> = ["GET / 4", "GET /assets/soak.css 2", "POST /api/soak 1"]
+        .iter()
+        .map(|m| m.parse().expect("mix entry"))
+        .collect();
+
+    println!(
+        "{:>6} {:>8} {:>10} {:>8} {:>6} {:>8} {:>7}",
+        "window", "requests", "failed", "rss_mib", "fds", "threads", "tasks"
+    );
+    let mut readings = Vec::with_capacity(windows);
+    for window in 0..windows {
+        let config = LoadConfig {
+            target: target.parse().expect("proxy URL"),
+            mix: mix.clone(),
+            concurrency: 32,
+            threads: 2,
+            stop: Stop::After(WINDOW),
+            rate: None,
+            seed: window as u64,
+            h2: false,
+            close: window % 2 == 1,
+            body_size: 1024,
+            timeout: Duration::from_secs(10),
+        };
+        let report = loadgen::run(&config).unwrap_or_else(|e| panic!("load: {e}"));
+        let reading = reading(&admin_url).unwrap_or_else(|e| panic!("{e}"));
+        println!(
+            "{window:>6} {:>8} {:>10} {:>8.1} {:>6} {:>8} {:>7}",
+            report.answered(),
+            report.failed(),
+            reading[0],
+            reading[1],
+            reading[2],
+            reading[3]
+        );
+        // the first window fills pools and caches
+        if window > 0 {
+            readings.push(reading);
+        }
+    }
+
+    let grown: Vec<String> = SLACK
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (name, slack))| {
+            let (grew, by) = growth(&readings, i);
+            grew.then(|| format!("{name} grew by {by:.1} over the run, over a slack of {slack}"))
+        })
+        .collect();
+    assert!(grown.is_empty(), "{}", grown.join("; "));
+    println!("no growth over {} windows", readings.len());
+}